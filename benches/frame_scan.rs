@@ -0,0 +1,80 @@
+//! Time and allocation count of analyze/apply_gain over a large file
+//!
+//! Run with `cargo bench --bench frame_scan`. The input is built by repeating
+//! the frames of `tests/fixtures/test_stereo.mp3`.
+
+use mp3rgain::{analyze, apply_gain};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Number of copies of the fixture's frames (about 17 MB per 1000)
+const REPEAT: usize = 5000;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn build_input() -> PathBuf {
+    let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/test_stereo.mp3");
+    let data = fs::read(fixture).expect("fixture");
+
+    // Strip the leading ID3v2 tag so the copies form one continuous stream
+    let frames = if data.starts_with(b"ID3") {
+        let size = data[6..10]
+            .iter()
+            .fold(0usize, |acc, &b| (acc << 7) | (b & 0x7F) as usize);
+        &data[10 + size..]
+    } else {
+        &data[..]
+    };
+
+    let path = std::env::temp_dir().join(format!("mp3rgain_bench_{}.mp3", std::process::id()));
+    fs::write(&path, frames.repeat(REPEAT)).expect("write input");
+    path
+}
+
+fn measure<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<12} {:>8.1} ms {:>10} allocations",
+        name,
+        elapsed.as_secs_f64() * 1000.0,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations
+    );
+    result
+}
+
+fn main() {
+    let path = build_input();
+    let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+
+    let analysis = measure("analyze", || analyze(&path).expect("analyze"));
+    println!(
+        "{} frames, {:.1} MB",
+        analysis.frame_count,
+        size as f64 / 1_000_000.0
+    );
+    measure("apply_gain", || apply_gain(&path, 1).expect("apply_gain"));
+
+    let _ = fs::remove_file(&path);
+}
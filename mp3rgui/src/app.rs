@@ -1,5 +1,10 @@
-use mp3rgain::replaygain::{self, REPLAYGAIN_REFERENCE_DB};
+use mp3rgain::replaygain::{
+    self, AlbumGainResult, AudioFileType, ReplayGainResult, REPLAYGAIN_REFERENCE_DB,
+};
+use mp3rgain::{ApeTag, CancellationToken, Interrupted, TagFormat};
 use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
 
 #[derive(Default, Clone, PartialEq)]
 pub enum FileStatus {
@@ -32,21 +37,86 @@ pub struct FileEntry {
     pub volume: Option<f64>,
     pub clipping: bool,
     pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
     pub track_clip: bool,
     pub album_volume: Option<f64>,
     pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
     pub album_clip: bool,
     pub status: FileStatus,
 }
 
+/// Progress of the background job for one file
+pub struct ProgressEvent {
+    pub file_index: usize,
+    /// Fraction of the current file done (0.0 to 1.0)
+    pub file_fraction: f32,
+    /// Fraction of the whole job done (0.0 to 1.0)
+    pub total_fraction: f32,
+    pub status: FileStatus,
+}
+
+/// Album analysis of the files at `paths`, in the order of `result.tracks`
+///
+/// Kept so files added to the list later are folded in without decoding the
+/// others again.
+#[derive(Clone)]
+pub struct AnalyzedAlbum {
+    pub paths: Vec<PathBuf>,
+    pub result: AlbumGainResult,
+}
+
+/// Message from the background job to the UI thread
+pub enum WorkerEvent {
+    Progress(ProgressEvent),
+    TrackAnalyzed(usize, ReplayGainResult),
+    /// Loudness and peak only, without a gain for the target volume
+    TrackMeasured(usize, ReplayGainResult),
+    AlbumAnalyzed(AnalyzedAlbum),
+    /// The job ended; the text goes to the status bar
+    Finished(String),
+}
+
+/// Sending side of a background job
+struct Worker {
+    tx: Sender<WorkerEvent>,
+    cancel: CancellationToken,
+    total: usize,
+}
+
+impl Worker {
+    fn send(&self, event: WorkerEvent) {
+        // The receiver is only gone if the window was closed
+        let _ = self.tx.send(event);
+    }
+
+    fn progress(&self, file_index: usize, file_fraction: f32, status: FileStatus) {
+        let total_fraction = (file_index as f32 + file_fraction) / self.total.max(1) as f32;
+        self.send(WorkerEvent::Progress(ProgressEvent {
+            file_index,
+            file_fraction,
+            total_fraction,
+            status,
+        }));
+    }
+}
+
 pub struct Mp3rgainApp {
     pub files: Vec<FileEntry>,
     pub target_volume: f64,
+    /// Edit the target in whole 1.5 dB steps from the 89 dB reference
+    pub target_in_steps: bool,
+    /// Write ReplayGain tags to MP3 files after applying gain
+    pub write_tags: bool,
+    pub tag_format: TagFormat,
     pub selected_indices: Vec<usize>,
     pub file_progress: f32,
     pub total_progress: f32,
     pub is_processing: bool,
     pub status_message: String,
+    events: Option<Receiver<WorkerEvent>>,
+    cancel: CancellationToken,
+    album: Option<AnalyzedAlbum>,
 }
 
 impl Mp3rgainApp {
@@ -54,11 +124,17 @@ impl Mp3rgainApp {
         Self {
             files: Vec::new(),
             target_volume: 89.0,
+            target_in_steps: false,
+            write_tags: false,
+            tag_format: TagFormat::Ape,
             selected_indices: Vec::new(),
             file_progress: 0.0,
             total_progress: 0.0,
             is_processing: false,
             status_message: String::new(),
+            events: None,
+            cancel: CancellationToken::new(),
+            album: None,
         }
     }
 
@@ -100,11 +176,7 @@ impl Mp3rgainApp {
                 return false;
             }
         }
-        path.extension().map_or(false, |ext| {
-            ext.eq_ignore_ascii_case("mp3")
-                || ext.eq_ignore_ascii_case("m4a")
-                || ext.eq_ignore_ascii_case("aac")
-        })
+        mp3rgain::has_supported_extension(path)
     }
 
     fn is_duplicate(&self, path: &PathBuf) -> bool {
@@ -141,196 +213,411 @@ impl Mp3rgainApp {
         self.selected_indices.clear();
     }
 
+    /// Reorder the file list, keeping the same files selected
+    ///
+    /// Ignored while a job runs, since it refers to files by position.
+    fn reorder(&mut self, f: impl FnOnce(&mut Vec<FileEntry>)) {
+        if self.is_processing {
+            return;
+        }
+        let selected: Vec<PathBuf> = self
+            .selected_indices
+            .iter()
+            .filter_map(|&i| self.files.get(i).map(|f| f.path.clone()))
+            .collect();
+
+        f(&mut self.files);
+
+        self.selected_indices = self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| selected.contains(&f.path))
+            .map(|(i, _)| i)
+            .collect();
+    }
+
+    /// Move the file at `from` to position `to` (drag and drop in the table)
+    pub fn move_file(&mut self, from: usize, to: usize) {
+        if from == to || from >= self.files.len() || to >= self.files.len() {
+            return;
+        }
+        self.reorder(|files| {
+            let file = files.remove(from);
+            files.insert(to, file);
+        });
+    }
+
+    pub fn sort_by_filename(&mut self) {
+        self.reorder(|files| files.sort_by(|a, b| a.filename.cmp(&b.filename)));
+    }
+
+    pub fn sort_by_path(&mut self) {
+        self.reorder(|files| files.sort_by(|a, b| a.path.cmp(&b.path)));
+    }
+
     pub fn clear_files(&mut self) {
         self.files.clear();
         self.selected_indices.clear();
+        self.album = None;
     }
 
-    pub fn analyze_tracks(&mut self) {
-        if self.files.is_empty() || !replaygain::is_available() {
-            if !replaygain::is_available() {
-                self.status_message = "ReplayGain feature not available".to_string();
-            }
-            return;
-        }
+    /// Run `job` on a background thread; its events are applied in `update`
+    fn start_job(&mut self, total: usize, job: impl FnOnce(&Worker) -> String + Send + 'static) {
+        let (tx, rx) = mpsc::channel();
+        self.cancel = CancellationToken::new();
+        let worker = Worker {
+            tx,
+            cancel: self.cancel.clone(),
+            total,
+        };
 
         self.is_processing = true;
         self.file_progress = 0.0;
         self.total_progress = 0.0;
+        self.events = Some(rx);
 
-        let total = self.files.len();
-        let mut analyzed = 0;
-        let mut errors = 0;
+        thread::spawn(move || {
+            let message = job(&worker);
+            worker.send(WorkerEvent::Finished(message));
+        });
+    }
 
-        for (i, file) in self.files.iter_mut().enumerate() {
-            file.status = FileStatus::Analyzing;
-            self.total_progress = i as f32 / total as f32;
+    /// Ask the running job to stop after the current step
+    pub fn cancel_processing(&mut self) {
+        self.cancel.cancel();
+    }
 
-            match replaygain::analyze_track(&file.path) {
-                Ok(result) => {
-                    // Display volume relative to ReplayGain reference (89 dB) for MP3Gain compatibility
-                    file.volume = Some(REPLAYGAIN_REFERENCE_DB - result.gain_db);
-                    file.clipping = result.peak >= 1.0;
-                    let gain = self.target_volume - REPLAYGAIN_REFERENCE_DB + result.gain_db;
-                    file.track_gain = Some(gain);
-                    file.track_clip = Self::would_clip(result.peak, gain);
-                    file.status = FileStatus::Analyzed;
-                    analyzed += 1;
+    /// Apply all events the background job sent since the last frame
+    fn poll_events(&mut self) {
+        let Some(rx) = self.events.take() else {
+            return;
+        };
+
+        loop {
+            match rx.try_recv() {
+                Ok(WorkerEvent::Finished(message)) => {
+                    self.status_message = message;
+                    self.total_progress = 1.0;
+                    self.is_processing = false;
+                    return;
                 }
-                Err(e) => {
-                    file.status = FileStatus::Error(e.to_string());
-                    errors += 1;
+                Ok(event) => self.handle_event(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // The job panicked before finishing
+                    self.status_message = "Processing stopped unexpectedly".to_string();
+                    self.is_processing = false;
+                    return;
                 }
             }
         }
 
-        self.total_progress = 1.0;
-        self.is_processing = false;
-        self.status_message = if errors > 0 {
-            format!("Analyzed {} file(s), {} error(s)", analyzed, errors)
-        } else {
-            format!("Analyzed {} file(s)", analyzed)
-        };
+        self.events = Some(rx);
     }
 
-    pub fn analyze_album(&mut self) {
-        if self.files.is_empty() || !replaygain::is_available() {
-            if !replaygain::is_available() {
-                self.status_message = "ReplayGain feature not available".to_string();
+    fn handle_event(&mut self, event: WorkerEvent) {
+        match event {
+            WorkerEvent::Progress(progress) => {
+                self.file_progress = progress.file_fraction;
+                self.total_progress = progress.total_fraction;
+                if let Some(file) = self.files.get_mut(progress.file_index) {
+                    file.status = progress.status;
+                }
             }
-            return;
-        }
-
-        self.is_processing = true;
-        self.total_progress = 0.0;
-
-        let paths: Vec<&std::path::Path> = self.files.iter().map(|f| f.path.as_path()).collect();
-
-        match replaygain::analyze_album(&paths) {
-            Ok(result) => {
-                let album_gain =
-                    self.target_volume - REPLAYGAIN_REFERENCE_DB + result.album_gain_db;
-
-                for (i, file) in self.files.iter_mut().enumerate() {
-                    if let Some(track_result) = result.tracks.get(i) {
-                        // Display volume relative to ReplayGain reference (89 dB) for MP3Gain compatibility
-                        file.volume = Some(REPLAYGAIN_REFERENCE_DB - track_result.gain_db);
-                        file.clipping = track_result.peak >= 1.0;
-                        let track_gain =
-                            self.target_volume - REPLAYGAIN_REFERENCE_DB + track_result.gain_db;
-                        file.track_gain = Some(track_gain);
-                        file.track_clip = Self::would_clip(track_result.peak, track_gain);
-                    }
+            WorkerEvent::TrackAnalyzed(index, result) => {
+                if let Some(file) = self.files.get_mut(index) {
+                    Self::set_track_result(file, &result, self.target_volume);
+                }
+            }
+            WorkerEvent::TrackMeasured(index, result) => {
+                if let Some(file) = self.files.get_mut(index) {
+                    file.volume = Some(REPLAYGAIN_REFERENCE_DB - result.gain_db);
+                    file.clipping = result.peak >= 1.0;
+                    file.track_gain = None;
+                    file.track_peak = None;
+                    file.track_clip = false;
+                }
+            }
+            WorkerEvent::AlbumAnalyzed(album) => {
+                let result = &album.result;
+                let album_gain = result.album_gain_for_target(self.target_volume);
+                for (path, track_result) in album.paths.iter().zip(&result.tracks) {
+                    let Some(file) = self.files.iter_mut().find(|f| f.path == *path) else {
+                        continue;
+                    };
+                    Self::set_track_result(file, track_result, self.target_volume);
                     // Display album volume relative to ReplayGain reference (89 dB) for MP3Gain compatibility
                     file.album_volume = Some(REPLAYGAIN_REFERENCE_DB - result.album_gain_db);
                     file.album_gain = Some(album_gain);
-                    file.album_clip = Self::would_clip(result.album_peak, album_gain);
+                    file.album_peak = Some(result.album_peak);
+                    file.album_clip = replaygain::would_clip(result.album_peak, album_gain);
                     file.status = FileStatus::Analyzed;
                 }
-                self.status_message =
-                    format!("Album analysis complete ({} tracks)", self.files.len());
-            }
-            Err(e) => {
-                self.status_message = format!("Album analysis failed: {}", e);
+                self.album = Some(album);
             }
+            WorkerEvent::Finished(_) => {}
         }
+    }
+
+    fn set_track_result(file: &mut FileEntry, result: &ReplayGainResult, target_volume: f64) {
+        // Display volume relative to ReplayGain reference (89 dB) for MP3Gain compatibility
+        file.volume = Some(REPLAYGAIN_REFERENCE_DB - result.gain_db);
+        file.clipping = result.peak >= 1.0;
+        let gain = result.gain_for_target(target_volume);
+        file.track_gain = Some(gain);
+        file.track_peak = Some(result.peak);
+        file.track_clip = replaygain::would_clip(result.peak, gain);
+    }
 
-        self.total_progress = 1.0;
-        self.is_processing = false;
+    pub fn analyze_tracks(&mut self) {
+        self.run_track_analysis(false);
     }
 
-    fn would_clip(peak: f64, gain_db: f64) -> bool {
-        let gain_linear = 10.0_f64.powf(gain_db / 20.0);
-        peak * gain_linear > 1.0
+    /// Measure loudness and peak of each file, without suggesting a gain
+    pub fn measure_tracks(&mut self) {
+        self.run_track_analysis(true);
     }
 
-    pub fn apply_track_gain(&mut self) {
-        if self.files.is_empty() {
+    fn run_track_analysis(&mut self, measure_only: bool) {
+        if self.files.is_empty() || !replaygain::is_available() {
+            if !replaygain::is_available() {
+                self.status_message = "ReplayGain feature not available".to_string();
+            }
             return;
         }
 
-        self.is_processing = true;
-        self.total_progress = 0.0;
-
-        let total = self.files.len();
-        let mut applied = 0;
-        let mut errors = 0;
-
-        for (i, file) in self.files.iter_mut().enumerate() {
-            self.total_progress = i as f32 / total as f32;
-
-            if let Some(gain_db) = file.track_gain {
-                file.status = FileStatus::Applying;
-                match mp3rgain::apply_gain_db(&file.path, gain_db) {
-                    Ok(_) => {
-                        file.status = FileStatus::Done;
-                        applied += 1;
+        let paths: Vec<PathBuf> = self.files.iter().map(|f| f.path.clone()).collect();
+        self.start_job(paths.len(), move |worker| {
+            let verb = if measure_only {
+                "measuring"
+            } else {
+                "analyzing"
+            };
+            let mut analyzed = 0;
+            let mut errors = 0;
+
+            for (i, path) in paths.iter().enumerate() {
+                worker.progress(i, 0.0, FileStatus::Analyzing);
+
+                // Decoding reports progress per packet; only send whole percents
+                let mut last_percent = 0;
+                let result =
+                    replaygain::analyze_track_cancellable(path, None, &worker.cancel, |fraction| {
+                        let percent = (fraction * 100.0) as u32;
+                        if percent > last_percent {
+                            last_percent = percent;
+                            worker.progress(i, fraction, FileStatus::Analyzing);
+                        }
+                    });
+
+                match result {
+                    Ok(result) => {
+                        worker.send(if measure_only {
+                            WorkerEvent::TrackMeasured(i, result)
+                        } else {
+                            WorkerEvent::TrackAnalyzed(i, result)
+                        });
+                        worker.progress(i, 1.0, FileStatus::Analyzed);
+                        analyzed += 1;
+                    }
+                    Err(e) if e.downcast_ref::<Interrupted>().is_some() => {
+                        worker.progress(i, 0.0, FileStatus::Pending);
+                        return format!("Cancelled after {} {} file(s)", verb, analyzed);
                     }
                     Err(e) => {
-                        file.status = FileStatus::Error(e.to_string());
+                        worker.progress(i, 1.0, FileStatus::Error(e.to_string()));
                         errors += 1;
                     }
                 }
             }
-        }
 
-        self.total_progress = 1.0;
-        self.is_processing = false;
-        self.status_message = if errors > 0 {
-            format!(
-                "Applied track gain to {} file(s), {} error(s)",
-                applied, errors
-            )
-        } else {
-            format!("Applied track gain to {} file(s)", applied)
-        };
+            let done = if measure_only { "Measured" } else { "Analyzed" };
+            if errors > 0 {
+                format!("{} {} file(s), {} error(s)", done, analyzed, errors)
+            } else {
+                format!("{} {} file(s)", done, analyzed)
+            }
+        });
     }
 
-    pub fn apply_album_gain(&mut self) {
-        if self.files.is_empty() {
+    pub fn analyze_album(&mut self) {
+        if self.files.is_empty() || !replaygain::is_available() {
+            if !replaygain::is_available() {
+                self.status_message = "ReplayGain feature not available".to_string();
+            }
             return;
         }
 
-        self.is_processing = true;
-        self.total_progress = 0.0;
+        // Keep the previous analysis if all its files are still listed (and
+        // unchanged, see apply_gains); only files added since are decoded
+        let album = self
+            .album
+            .clone()
+            .filter(|album| {
+                album
+                    .paths
+                    .iter()
+                    .all(|p| self.files.iter().any(|f| f.path == *p))
+            })
+            .unwrap_or_else(|| AnalyzedAlbum {
+                paths: Vec::new(),
+                result: AlbumGainResult::default(),
+            });
+        let new_files: Vec<(usize, PathBuf)> = self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !album.paths.contains(&f.path))
+            .map(|(i, f)| (i, f.path.clone()))
+            .collect();
+
+        for &(i, _) in &new_files {
+            self.files[i].status = FileStatus::Analyzing;
+        }
 
-        let total = self.files.len();
-        let mut applied = 0;
-        let mut errors = 0;
+        self.start_job(self.files.len(), move |worker| {
+            let mut album = album;
+            let refs: Vec<&std::path::Path> = new_files.iter().map(|(_, p)| p.as_path()).collect();
+            let result = replaygain::add_to_album_cancellable(
+                &mut album.result,
+                &refs,
+                None,
+                &worker.cancel,
+                |done, _| {
+                    worker.progress(new_files[done - 1].0, 1.0, FileStatus::Analyzing);
+                },
+            );
+
+            match result {
+                Ok(()) => {
+                    album.paths.extend(new_files.into_iter().map(|(_, p)| p));
+                    let tracks = album.result.tracks.len();
+                    worker.send(WorkerEvent::AlbumAnalyzed(album));
+                    format!("Album analysis complete ({} tracks)", tracks)
+                }
+                Err(e) => {
+                    for &(i, _) in &new_files {
+                        worker.progress(i, 0.0, FileStatus::Pending);
+                    }
+                    if e.downcast_ref::<Interrupted>().is_some() {
+                        "Album analysis cancelled".to_string()
+                    } else {
+                        format!("Album analysis failed: {}", e)
+                    }
+                }
+            }
+        });
+    }
 
-        for (i, file) in self.files.iter_mut().enumerate() {
-            self.total_progress = i as f32 / total as f32;
+    /// Apply the gain `gain` picks for each file that has one, in the background
+    ///
+    /// With "Write ReplayGain tags", MP3 files then get tags describing their
+    /// new level.
+    fn apply_gains(&mut self, label: &'static str, gain: fn(&FileEntry) -> Option<f64>) {
+        if self.files.is_empty() {
+            return;
+        }
+        // Loudness changes, so the next album analysis starts over
+        self.album = None;
+
+        let files: Vec<(usize, FileEntry)> = self.files.iter().cloned().enumerate().collect();
+        let tags = self
+            .write_tags
+            .then_some((self.tag_format, self.target_volume));
+        self.start_job(files.len(), move |worker| {
+            let mut applied = 0;
+            let mut errors = 0;
+
+            for (i, file) in files {
+                let Some(gain_db) = gain(&file) else {
+                    continue;
+                };
+                if worker.cancel.is_cancelled() {
+                    return format!("Cancelled after applying {} to {} file(s)", label, applied);
+                }
 
-            if let Some(gain_db) = file.album_gain {
-                file.status = FileStatus::Applying;
-                match mp3rgain::apply_gain_db(&file.path, gain_db) {
-                    Ok(_) => {
-                        file.status = FileStatus::Done;
+                worker.progress(i, 0.0, FileStatus::Applying);
+                let path = &file.path;
+                let result = mp3rgain::apply_gain_db(path, gain_db).and_then(|_| match tags {
+                    Some((format, target_db))
+                        if mp3rgain::detect_audio_type(path) == Some(AudioFileType::Mp3) =>
+                    {
+                        // apply_gain_db rounds to whole steps
+                        let applied_db = mp3rgain::quantized_db(gain_db);
+                        mp3rgain::read_gain_tag(path).and_then(|tag| {
+                            let tag = replaygain_tag_after(
+                                tag.unwrap_or_default(),
+                                &file,
+                                applied_db,
+                                target_db,
+                            );
+                            mp3rgain::write_gain_tag(path, &tag, format)
+                        })
+                    }
+                    _ => Ok(()),
+                });
+                match result {
+                    Ok(()) => {
+                        worker.progress(i, 1.0, FileStatus::Done);
                         applied += 1;
                     }
                     Err(e) => {
-                        file.status = FileStatus::Error(e.to_string());
+                        worker.progress(i, 1.0, FileStatus::Error(e.to_string()));
                         errors += 1;
                     }
                 }
             }
-        }
 
-        self.total_progress = 1.0;
-        self.is_processing = false;
-        self.status_message = if errors > 0 {
-            format!(
-                "Applied album gain to {} file(s), {} error(s)",
-                applied, errors
-            )
-        } else {
-            format!("Applied album gain to {} file(s)", applied)
-        };
+            if errors > 0 {
+                format!(
+                    "Applied {} to {} file(s), {} error(s)",
+                    label, applied, errors
+                )
+            } else {
+                format!("Applied {} to {} file(s)", label, applied)
+            }
+        });
+    }
+
+    pub fn apply_track_gain(&mut self) {
+        self.apply_gains("track gain", |f| f.track_gain);
+    }
+
+    pub fn apply_album_gain(&mut self) {
+        self.apply_gains("album gain", |f| f.album_gain);
     }
 }
 
+/// `tag` with the ReplayGain values of `file` once `applied_db` of gain is in
+/// its audio
+///
+/// The gains left to reach `target_db` shrink, and the peaks grow, by what
+/// was applied; the reference loudness is the target.
+fn replaygain_tag_after(
+    mut tag: ApeTag,
+    file: &FileEntry,
+    applied_db: f64,
+    target_db: f64,
+) -> ApeTag {
+    let scale = 10.0_f64.powf(applied_db / 20.0);
+    if let (Some(gain), Some(peak)) = (file.track_gain, file.track_peak) {
+        tag.set_replaygain_track(gain - applied_db, peak * scale);
+    }
+    if let (Some(gain), Some(peak)) = (file.album_gain, file.album_peak) {
+        tag.set_replaygain_album(gain - applied_db, peak * scale);
+    }
+    tag.set_reference_loudness(target_db);
+    tag
+}
+
 impl eframe::App for Mp3rgainApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_events();
+        if self.is_processing {
+            // Keep repainting so progress from the worker thread shows up
+            ctx.request_repaint();
+        }
         crate::ui::render(self, ctx);
     }
 }
@@ -1,4 +1,5 @@
 use crate::app::Mp3rgainApp;
+use mp3rgain::TagFormat;
 
 pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
     egui::TopBottomPanel::top("menu_bar").show(ctx, |ui| {
@@ -6,7 +7,7 @@ pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
             file_menu(app, ui, ctx);
             analysis_menu(app, ui);
             modify_menu(app, ui);
-            options_menu(ui);
+            options_menu(app, ui);
             help_menu(ui);
         });
     });
@@ -14,29 +15,55 @@ pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
 
 fn file_menu(app: &mut Mp3rgainApp, ui: &mut egui::Ui, ctx: &egui::Context) {
     ui.menu_button("File", |ui| {
-        if ui.button("Add Files...").clicked() {
+        if ui
+            .add_enabled(!app.is_processing, egui::Button::new("Add Files..."))
+            .clicked()
+        {
             if let Some(paths) = rfd::FileDialog::new()
-                .add_filter("Audio files", &["mp3", "m4a", "aac"])
+                .add_filter("Audio files", mp3rgain::supported_extensions())
                 .pick_files()
             {
                 app.add_files(paths);
             }
             ui.close_menu();
         }
-        if ui.button("Add Folder...").clicked() {
+        if ui
+            .add_enabled(!app.is_processing, egui::Button::new("Add Folder..."))
+            .clicked()
+        {
             if let Some(folder) = rfd::FileDialog::new().pick_folder() {
                 app.add_folder(folder, false);
             }
             ui.close_menu();
         }
-        if ui.button("Add Folder (with subfolders)...").clicked() {
+        if ui
+            .add_enabled(
+                !app.is_processing,
+                egui::Button::new("Add Folder (with subfolders)..."),
+            )
+            .clicked()
+        {
             if let Some(folder) = rfd::FileDialog::new().pick_folder() {
                 app.add_folder(folder, true);
             }
             ui.close_menu();
         }
         ui.separator();
-        if ui.button("Clear File List").clicked() {
+        ui.add_enabled_ui(app.files.len() > 1 && !app.is_processing, |ui| {
+            if ui.button("Sort by Filename").clicked() {
+                app.sort_by_filename();
+                ui.close_menu();
+            }
+            if ui.button("Sort by Path").clicked() {
+                app.sort_by_path();
+                ui.close_menu();
+            }
+        });
+        ui.separator();
+        if ui
+            .add_enabled(!app.is_processing, egui::Button::new("Clear File List"))
+            .clicked()
+        {
             app.clear_files();
             ui.close_menu();
         }
@@ -54,6 +81,10 @@ fn analysis_menu(app: &mut Mp3rgainApp, ui: &mut egui::Ui) {
                 app.analyze_tracks();
                 ui.close_menu();
             }
+            if ui.button("Measure Loudness").clicked() {
+                app.measure_tracks();
+                ui.close_menu();
+            }
             if ui.button("Album Analysis").clicked() {
                 app.analyze_album();
                 ui.close_menu();
@@ -87,8 +118,26 @@ fn modify_menu(app: &mut Mp3rgainApp, ui: &mut egui::Ui) {
     });
 }
 
-fn options_menu(ui: &mut egui::Ui) {
+fn options_menu(app: &mut Mp3rgainApp, ui: &mut egui::Ui) {
     ui.menu_button("Options", |ui| {
+        ui.checkbox(&mut app.write_tags, "Write ReplayGain Tags")
+            .on_hover_text(
+                "After applying gain, tag MP3 files with their new track and album gain",
+            );
+        ui.add_enabled_ui(app.write_tags, |ui| {
+            ui.menu_button("Tag Format", |ui| {
+                for (format, label) in [
+                    (TagFormat::Ape, "APEv2"),
+                    (TagFormat::Id3v2, "ID3v2"),
+                    (TagFormat::Both, "APEv2 and ID3v2"),
+                ] {
+                    if ui.radio_value(&mut app.tag_format, format, label).clicked() {
+                        ui.close_menu();
+                    }
+                }
+            });
+        });
+        ui.separator();
         if ui.button("Settings...").clicked() {
             // TODO: Implement settings dialog
             ui.close_menu();
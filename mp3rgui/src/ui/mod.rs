@@ -15,7 +15,7 @@ pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
 
 fn handle_dropped_files(app: &mut Mp3rgainApp, ctx: &egui::Context) {
     ctx.input(|i| {
-        if !i.raw.dropped_files.is_empty() {
+        if !i.raw.dropped_files.is_empty() && !app.is_processing {
             let paths: Vec<std::path::PathBuf> = i
                 .raw
                 .dropped_files
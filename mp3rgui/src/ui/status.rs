@@ -24,7 +24,7 @@ pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
                 }
                 ui.add_enabled_ui(app.is_processing, |ui| {
                     if ui.button("Cancel").clicked() {
-                        // TODO: Implement cancel
+                        app.cancel_processing();
                     }
                 });
             });
@@ -1,6 +1,20 @@
 use crate::app::Mp3rgainApp;
+use mp3rgain::{db_to_steps, quantization_error_db, quantized_db};
+
+/// Tooltip of a gain cell: what is applied once the gain is rounded to steps
+fn gain_tooltip(gain_db: f64) -> String {
+    format!(
+        "Applied as {:+.1} dB ({:+} steps of 1.5 dB)\nQuantization error: {:+.2} dB",
+        quantized_db(gain_db),
+        db_to_steps(gain_db),
+        quantization_error_db(gain_db)
+    )
+}
 
 pub fn render(app: &mut Mp3rgainApp, ui: &mut egui::Ui) {
+    // (from, to) of a row dropped this frame, applied after the table is drawn
+    let mut dropped: Option<(usize, usize)> = None;
+
     egui::ScrollArea::both().show(ui, |ui| {
         egui_extras::TableBuilder::new(ui)
             .striped(true)
@@ -51,7 +65,14 @@ pub fn render(app: &mut Mp3rgainApp, ui: &mut egui::Ui) {
                         row.set_selected(is_selected);
 
                         row.col(|ui| {
-                            if ui.selectable_label(is_selected, &file.filename).clicked() {
+                            // Rows are reordered by dragging the file name
+                            let response = ui
+                                .selectable_label(is_selected, &file.filename)
+                                .interact(egui::Sense::drag());
+                            if !app.is_processing {
+                                response.dnd_set_drag_payload(idx);
+                            }
+                            if response.clicked() {
                                 if ui.input(|i| i.modifiers.ctrl || i.modifiers.command) {
                                     if is_selected {
                                         app.selected_indices.retain(|&i| i != idx);
@@ -81,7 +102,8 @@ pub fn render(app: &mut Mp3rgainApp, ui: &mut egui::Ui) {
                                 } else {
                                     ui.style().visuals.text_color()
                                 };
-                                ui.colored_label(color, format!("{:+.1} dB", g));
+                                ui.colored_label(color, format!("{:+.1} dB", g))
+                                    .on_hover_text(gain_tooltip(g));
                             }
                         });
                         row.col(|ui| {
@@ -101,7 +123,8 @@ pub fn render(app: &mut Mp3rgainApp, ui: &mut egui::Ui) {
                                 } else {
                                     ui.style().visuals.text_color()
                                 };
-                                ui.colored_label(color, format!("{:+.1} dB", g));
+                                ui.colored_label(color, format!("{:+.1} dB", g))
+                                    .on_hover_text(gain_tooltip(g));
                             }
                         });
                         row.col(|ui| {
@@ -112,8 +135,16 @@ pub fn render(app: &mut Mp3rgainApp, ui: &mut egui::Ui) {
                         row.col(|ui| {
                             ui.label(file.status.as_str());
                         });
+
+                        if let Some(from) = row.response().dnd_release_payload::<usize>() {
+                            dropped = Some((*from, idx));
+                        }
                     });
                 }
             });
     });
+
+    if let Some((from, to)) = dropped {
+        app.move_file(from, to);
+    }
 }
@@ -1,4 +1,6 @@
 use crate::app::Mp3rgainApp;
+use mp3rgain::replaygain::REPLAYGAIN_REFERENCE_DB;
+use mp3rgain::{db_to_steps, quantized_db, steps_to_db};
 
 pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
     egui::TopBottomPanel::top("toolbar").show(ctx, |ui| {
@@ -6,9 +8,12 @@ pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
             ui.spacing_mut().button_padding = egui::vec2(8.0, 4.0);
 
             // Add Files button
-            if ui.button("Add Files").clicked() {
+            if ui
+                .add_enabled(!app.is_processing, egui::Button::new("Add Files"))
+                .clicked()
+            {
                 if let Some(paths) = rfd::FileDialog::new()
-                    .add_filter("Audio files", &["mp3", "m4a", "aac"])
+                    .add_filter("Audio files", mp3rgain::supported_extensions())
                     .pick_files()
                 {
                     app.add_files(paths);
@@ -16,7 +21,10 @@ pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
             }
 
             // Add Folder button
-            if ui.button("Add Folder").clicked() {
+            if ui
+                .add_enabled(!app.is_processing, egui::Button::new("Add Folder"))
+                .clicked()
+            {
                 if let Some(folder) = rfd::FileDialog::new().pick_folder() {
                     app.add_folder(folder, true);
                 }
@@ -32,6 +40,9 @@ pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
                 if ui.button("Album Analysis").clicked() {
                     app.analyze_album();
                 }
+                if ui.button("Measure").clicked() {
+                    app.measure_tracks();
+                }
             });
 
             ui.separator();
@@ -66,14 +77,49 @@ pub fn render(app: &mut Mp3rgainApp, ctx: &egui::Context) {
 
             ui.separator();
 
-            // Target volume
+            // Target volume; gain is applied in 1.5 dB steps, so also show
+            // (or edit) it as steps from the 89 dB reference
             ui.label("Target:");
-            ui.add(
-                egui::DragValue::new(&mut app.target_volume)
-                    .speed(0.1)
-                    .range(75.0..=100.0)
-                    .suffix(" dB"),
-            );
+            if app.target_in_steps {
+                let mut steps = db_to_steps(app.target_volume - REPLAYGAIN_REFERENCE_DB);
+                let response = ui.add(
+                    egui::DragValue::new(&mut steps)
+                        .speed(0.1)
+                        .range(
+                            db_to_steps(75.0 - REPLAYGAIN_REFERENCE_DB)
+                                ..=db_to_steps(100.0 - REPLAYGAIN_REFERENCE_DB),
+                        )
+                        .custom_formatter(|n, _| format!("{:+}", n))
+                        .suffix(" steps"),
+                );
+                if response.changed() {
+                    app.target_volume = REPLAYGAIN_REFERENCE_DB + steps_to_db(steps);
+                }
+                ui.label(format!("= {:.1} dB", app.target_volume));
+            } else {
+                ui.add(
+                    egui::DragValue::new(&mut app.target_volume)
+                        .speed(0.1)
+                        .range(75.0..=100.0)
+                        .suffix(" dB"),
+                );
+                let offset = app.target_volume - REPLAYGAIN_REFERENCE_DB;
+                ui.label(format!("≈ {:+} steps", db_to_steps(offset)))
+                    .on_hover_text(
+                        "Gain is applied in whole 1.5 dB steps, so each file's gain is \
+                         rounded; hover over a gain to see what is applied",
+                    );
+            }
+            if ui
+                .checkbox(&mut app.target_in_steps, "Steps")
+                .on_hover_text("Edit the target in 1.5 dB steps")
+                .changed()
+                && app.target_in_steps
+            {
+                // Snap to the step the target was closest to
+                app.target_volume = REPLAYGAIN_REFERENCE_DB
+                    + quantized_db(app.target_volume - REPLAYGAIN_REFERENCE_DB);
+            }
         });
     });
 }
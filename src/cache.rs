@@ -0,0 +1,227 @@
+//! On-disk cache of ReplayGain track analyses
+//!
+//! Decoding dominates ReplayGain analysis, yet album gain only needs each
+//! track's loudness histogram and peak. [`AnalysisCache`] stores those per
+//! file, one JSON entry each, and hands them back while the file's size and
+//! modification time are unchanged, so re-running `-a` on a large album only
+//! decodes the files that changed. On Unix the inode change time is checked
+//! too: it moves on every write, even when `-p` puts the modification time
+//! back and the size stays the same.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::replaygain::{
+    self, AlbumGainResult, AnalysisMethod, AudioFileType, ChannelLayout, LoudnessHistogram,
+    ReplayGainResult, TrackAnalysis,
+};
+
+/// Entry format version; entries written with another version are ignored
+const CACHE_VERSION: u32 = 1;
+
+/// One cached track analysis
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    version: u32,
+    /// Canonical path of the analyzed file, to rule out key collisions
+    path: String,
+    track_index: Option<u32>,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    /// Inode change time (Unix only, 0 elsewhere)
+    ctime_secs: i64,
+    ctime_nanos: i64,
+    loudness_db: f64,
+    gain_db: f64,
+    peak: f64,
+    sample_rate: u32,
+    file_type: Option<AudioFileType>,
+    channel_layout: ChannelLayout,
+    /// Non-empty histogram buckets as (bucket index, window count)
+    histogram: Vec<(usize, u32)>,
+}
+
+/// What identifies a file's contents: its path, size and modification time
+/// (plus change time on Unix)
+struct FileKey {
+    path: String,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    ctime_secs: i64,
+    ctime_nanos: i64,
+}
+
+impl FileKey {
+    fn of(file: &Path) -> Option<Self> {
+        let path = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+        let meta = fs::metadata(&path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        #[cfg(unix)]
+        let (ctime_secs, ctime_nanos) = {
+            use std::os::unix::fs::MetadataExt;
+            (meta.ctime(), meta.ctime_nsec())
+        };
+        #[cfg(not(unix))]
+        let (ctime_secs, ctime_nanos) = (0, 0);
+
+        Some(Self {
+            path: path.to_string_lossy().into_owned(),
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            ctime_secs,
+            ctime_nanos,
+        })
+    }
+
+    fn matches(&self, entry: &CacheEntry) -> bool {
+        entry.path == self.path
+            && entry.size == self.size
+            && entry.mtime_secs == self.mtime_secs
+            && entry.mtime_nanos == self.mtime_nanos
+            && entry.ctime_secs == self.ctime_secs
+            && entry.ctime_nanos == self.ctime_nanos
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds (unlike `DefaultHasher`)
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Directory of cached track analyses (`--cache <dir>`)
+///
+/// Only complete decoded analyses are cached; frame-domain estimates, partial
+/// results and failures are always redone. Reading and writing entries is best effort: a missing,
+/// stale or unreadable entry just means the file is decoded again.
+#[derive(Debug, Clone)]
+pub struct AnalysisCache {
+    dir: PathBuf,
+}
+
+impl AnalysisCache {
+    /// Use `dir` for cache entries; it is created when the first entry is stored
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Directory the entries are stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, key: &FileKey, track_index: Option<u32>) -> PathBuf {
+        let id = format!("{}\0{:?}", key.path, track_index);
+        self.dir.join(format!("{:016x}.json", fnv1a(id.as_bytes())))
+    }
+
+    /// Cached analysis of `file`, if there is one and the file is unchanged
+    pub fn get(&self, file: &Path, track_index: Option<u32>) -> Option<TrackAnalysis> {
+        let key = FileKey::of(file)?;
+        let json = fs::read(self.entry_path(&key, track_index)).ok()?;
+        let entry: CacheEntry = serde_json::from_slice(&json).ok()?;
+        if entry.version != CACHE_VERSION
+            || entry.track_index != track_index
+            || !key.matches(&entry)
+        {
+            return None;
+        }
+
+        Some(TrackAnalysis {
+            result: ReplayGainResult {
+                loudness_db: entry.loudness_db,
+                gain_db: entry.gain_db,
+                peak: entry.peak,
+                sample_rate: entry.sample_rate,
+                file_type: entry.file_type,
+                channel_layout: entry.channel_layout,
+                method: AnalysisMethod::Decoded,
+                partial: false,
+            },
+            histogram: LoudnessHistogram::from_buckets(entry.histogram)?,
+        })
+    }
+
+    /// Store the analysis of `file` as it is now
+    ///
+    /// Frame-domain estimates ([`AnalysisMethod::Heuristic`]) and partial
+    /// analyses of damaged files are not stored.
+    pub fn put(&self, file: &Path, track_index: Option<u32>, track: &TrackAnalysis) -> Result<()> {
+        if track.result.method != AnalysisMethod::Decoded || track.result.partial {
+            return Ok(());
+        }
+        let key = FileKey::of(file)
+            .with_context(|| format!("Cannot read modification time: {}", file.display()))?;
+        let result = &track.result;
+        let entry = CacheEntry {
+            version: CACHE_VERSION,
+            path: key.path.clone(),
+            track_index,
+            size: key.size,
+            mtime_secs: key.mtime_secs,
+            mtime_nanos: key.mtime_nanos,
+            ctime_secs: key.ctime_secs,
+            ctime_nanos: key.ctime_nanos,
+            loudness_db: result.loudness_db,
+            gain_db: result.gain_db,
+            peak: result.peak,
+            sample_rate: result.sample_rate,
+            file_type: result.file_type,
+            channel_layout: result.channel_layout,
+            histogram: track.histogram.buckets().collect(),
+        };
+
+        // Write to a temporary name first so a concurrent reader never sees
+        // a partial entry
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache directory: {}", self.dir.display()))?;
+        let path = self.entry_path(&key, track_index);
+        let temp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&temp, serde_json::to_vec(&entry)?)?;
+        fs::rename(&temp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })?;
+        Ok(())
+    }
+
+    /// Cached analysis of `file`, or decode it and cache the result
+    pub fn analyze_track(&self, file: &Path, track_index: Option<u32>) -> Result<TrackAnalysis> {
+        if let Some(track) = self.get(file, track_index) {
+            return Ok(track);
+        }
+        let track = replaygain::analyze_track_histogram(file, track_index)?;
+        let _ = self.put(file, track_index, &track);
+        Ok(track)
+    }
+
+    /// Decode `file` even if a cached analysis exists, and cache the new result
+    pub fn refresh_track(&self, file: &Path, track_index: Option<u32>) -> Result<TrackAnalysis> {
+        let track = replaygain::analyze_track_histogram(file, track_index)?;
+        let _ = self.put(file, track_index, &track);
+        Ok(track)
+    }
+
+    /// Album analysis that only decodes tracks without a valid cache entry
+    ///
+    /// Gives the same result as [`replaygain::analyze_album_with_index`].
+    pub fn analyze_album(
+        &self,
+        files: &[&Path],
+        track_index: Option<u32>,
+    ) -> Result<AlbumGainResult> {
+        let mut album = AlbumGainResult::default();
+        for file in files {
+            album.add_track(self.analyze_track(file, track_index)?);
+        }
+        Ok(album)
+    }
+}
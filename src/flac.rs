@@ -0,0 +1,292 @@
+//! FLAC metadata handling for ReplayGain tags
+//!
+//! FLAC is lossless and has nothing like MP3's global_gain, so gain is never
+//! applied to the audio. ReplayGain is stored as Vorbis comments
+//! (`REPLAYGAIN_TRACK_GAIN=-6.50 dB`) in the VORBIS_COMMENT metadata block,
+//! where players look for it.
+//!
+//! FLAC file structure:
+//! ```text
+//! "fLaC"
+//! STREAMINFO (always first)
+//! SEEKTABLE, VORBIS_COMMENT, PICTURE, PADDING, ... (any order)
+//! audio frames
+//! ```
+//!
+//! Each metadata block starts with a 4-byte header: a last-block flag, the
+//! 7-bit block type and a 24-bit big-endian length. Vorbis comments use
+//! little-endian lengths. Seek points are relative to the first audio frame,
+//! so metadata can grow or shrink without touching the audio.
+
+use anyhow::Result;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use crate::tag_keys;
+use crate::write_error;
+use crate::ApeTag;
+
+/// Stream marker at the start of every FLAC file
+const FLAC_MARKER: &[u8; 4] = b"fLaC";
+
+/// Metadata block types
+const STREAMINFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+
+/// Vendor string for a VORBIS_COMMENT block created from scratch
+const VENDOR: &str = concat!("mp3rgain ", env!("CARGO_PKG_VERSION"));
+
+/// A metadata block of a FLAC file
+struct MetadataBlock {
+    block_type: u8,
+    body: Vec<u8>,
+}
+
+/// Split FLAC data into its metadata blocks and the offset of the first frame
+fn parse_metadata(data: &[u8]) -> Result<(Vec<MetadataBlock>, usize)> {
+    if !is_flac_data(data) {
+        anyhow::bail!("Not a FLAC file");
+    }
+
+    let mut blocks = Vec::new();
+    let mut pos = FLAC_MARKER.len();
+    loop {
+        let header = data
+            .get(pos..pos + 4)
+            .ok_or_else(|| anyhow::anyhow!("Truncated FLAC metadata block header"))?;
+        let last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7F;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let body = data
+            .get(pos + 4..pos + 4 + len)
+            .ok_or_else(|| anyhow::anyhow!("Truncated FLAC metadata block"))?;
+        blocks.push(MetadataBlock {
+            block_type,
+            body: body.to_vec(),
+        });
+        pos += 4 + len;
+        if last {
+            break;
+        }
+    }
+
+    if blocks.first().map(|b| b.block_type) != Some(STREAMINFO) {
+        anyhow::bail!("FLAC file does not start with a STREAMINFO block");
+    }
+
+    Ok((blocks, pos))
+}
+
+/// Serialize metadata blocks, setting the last-block flag on the final one
+fn serialize_metadata(blocks: &[MetadataBlock]) -> Result<Vec<u8>> {
+    let mut out = FLAC_MARKER.to_vec();
+    for (i, block) in blocks.iter().enumerate() {
+        let len = block.body.len();
+        if len >= 1 << 24 {
+            anyhow::bail!("FLAC metadata block too large");
+        }
+        let last = if i + 1 == blocks.len() { 0x80 } else { 0 };
+        out.push(last | block.block_type);
+        out.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(&block.body);
+    }
+    Ok(out)
+}
+
+/// Parse a VORBIS_COMMENT block body into the vendor string and comments
+fn parse_vorbis_comments(body: &[u8]) -> Result<(String, Vec<(String, String)>)> {
+    let mut pos = 0;
+    let read_string = |pos: &mut usize| -> Result<String> {
+        let len = body
+            .get(*pos..*pos + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(|| anyhow::anyhow!("Invalid FLAC Vorbis comment block"))?;
+        let bytes = body
+            .get(*pos + 4..*pos + 4 + len)
+            .ok_or_else(|| anyhow::anyhow!("Invalid FLAC Vorbis comment block"))?;
+        *pos += 4 + len;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    };
+
+    let vendor = read_string(&mut pos)?;
+    let count = body
+        .get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| anyhow::anyhow!("Invalid FLAC Vorbis comment block"))?;
+    pos += 4;
+
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let comment = read_string(&mut pos)?;
+        // A comment without '=' is invalid; keep it as a key with no value
+        let (key, value) = comment.split_once('=').unwrap_or((&comment, ""));
+        comments.push((key.to_string(), value.to_string()));
+    }
+
+    Ok((vendor, comments))
+}
+
+/// Serialize a VORBIS_COMMENT block body (no framing bit, as FLAC requires)
+fn serialize_vorbis_comments(vendor: &str, comments: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    out.extend_from_slice(vendor.as_bytes());
+    out.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let comment = format!("{}={}", key, value);
+        out.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        out.extend_from_slice(comment.as_bytes());
+    }
+    out
+}
+
+/// Read all Vorbis comments of a FLAC file, in file order
+pub fn read_vorbis_comments(file_path: &Path) -> Result<Vec<(String, String)>> {
+    let data = crate::read_file(file_path)?;
+    let (blocks, _) = parse_metadata(&data)?;
+
+    match blocks.iter().find(|b| b.block_type == VORBIS_COMMENT) {
+        Some(block) => Ok(parse_vorbis_comments(&block.body)?.1),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Read the ReplayGain comments of a FLAC file
+///
+/// The values are returned as an [`ApeTag`] with the keys of
+/// [`tag_keys::REPLAYGAIN_KEYS`], so they can be planned and compared like the
+/// APEv2 tags of MP3 files.
+///
+/// # Returns
+/// * `None` if the file has no ReplayGain comments
+pub fn read_replaygain_tag(file_path: &Path) -> Result<Option<ApeTag>> {
+    let mut tag = ApeTag::new();
+    for (key, value) in read_vorbis_comments(file_path)? {
+        if let Some(name) = tag_keys::REPLAYGAIN_KEYS
+            .iter()
+            .find(|k| k.eq_ignore_ascii_case(&key))
+        {
+            tag.set(name, &value);
+        }
+    }
+
+    Ok(if tag.is_empty() { None } else { Some(tag) })
+}
+
+/// Replace the ReplayGain comments of a FLAC file with those in `tag`
+///
+/// Other comments (artist, title, ...) and metadata blocks are kept. Items of
+/// `tag` that aren't ReplayGain keys (such as MP3GAIN_UNDO) are ignored. A
+/// VORBIS_COMMENT block is added after STREAMINFO if the file has none.
+pub fn write_replaygain_tag(file_path: &Path, tag: &ApeTag) -> Result<()> {
+    let data = crate::read_file(file_path)?;
+    let (mut blocks, audio_start) = parse_metadata(&data)?;
+
+    let index = match blocks.iter().position(|b| b.block_type == VORBIS_COMMENT) {
+        Some(index) => index,
+        None => {
+            blocks.insert(
+                1,
+                MetadataBlock {
+                    block_type: VORBIS_COMMENT,
+                    body: serialize_vorbis_comments(VENDOR, &[]),
+                },
+            );
+            1
+        }
+    };
+
+    let (vendor, mut comments) = parse_vorbis_comments(&blocks[index].body)?;
+    comments.retain(|(key, _)| !tag_keys::is_replaygain_key(key));
+    comments.extend(
+        tag.items()
+            .iter()
+            .filter(|item| tag_keys::is_replaygain_key(&item.key))
+            .map(|item| (item.key.clone(), item.value.clone())),
+    );
+    blocks[index].body = serialize_vorbis_comments(&vendor, &comments);
+
+    let mut output = serialize_metadata(&blocks)?;
+    output.extend_from_slice(&data[audio_start..]);
+
+    fs::write(file_path, &output).map_err(|e| write_error(e, file_path))?;
+
+    Ok(())
+}
+
+/// Delete ReplayGain comments from a FLAC file
+pub fn delete_replaygain_tags(file_path: &Path) -> Result<()> {
+    write_replaygain_tag(file_path, &ApeTag::new())
+}
+
+/// Check if file is a FLAC file
+pub fn is_flac_file(file_path: &Path) -> bool {
+    let mut head = Vec::with_capacity(FLAC_MARKER.len());
+    if let Ok(file) = fs::File::open(file_path) {
+        let _ = file.take(FLAC_MARKER.len() as u64).read_to_end(&mut head);
+    }
+    is_flac_data(&head)
+}
+
+/// Check if data starts with the FLAC stream marker
+pub(crate) fn is_flac_data(data: &[u8]) -> bool {
+    data.starts_with(FLAC_MARKER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flac_data(blocks: &[MetadataBlock]) -> Vec<u8> {
+        let mut data = serialize_metadata(blocks).unwrap();
+        data.extend_from_slice(&[0xFF, 0xF8, 0x69, 0x18]);
+        data
+    }
+
+    #[test]
+    fn test_vorbis_comment_roundtrip() {
+        let comments = vec![
+            ("TITLE".to_string(), "Song".to_string()),
+            ("REPLAYGAIN_TRACK_GAIN".to_string(), "-6.50 dB".to_string()),
+        ];
+        let body = serialize_vorbis_comments("vendor", &comments);
+        let (vendor, parsed) = parse_vorbis_comments(&body).unwrap();
+        assert_eq!(vendor, "vendor");
+        assert_eq!(parsed, comments);
+
+        assert!(parse_vorbis_comments(&body[..body.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_metadata_blocks() {
+        let data = flac_data(&[
+            MetadataBlock {
+                block_type: STREAMINFO,
+                body: vec![0; 34],
+            },
+            MetadataBlock {
+                block_type: 1,
+                body: vec![0; 10],
+            },
+        ]);
+        assert!(is_flac_data(&data));
+
+        let (blocks, audio_start) = parse_metadata(&data).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].body.len(), 10);
+        assert_eq!(&data[audio_start..], &[0xFF, 0xF8, 0x69, 0x18]);
+        // Only the final block carries the last-block flag
+        assert_eq!(data[4], STREAMINFO);
+        assert_eq!(data[4 + 4 + 34], 0x80 | 1);
+
+        // STREAMINFO must come first
+        let data = flac_data(&[MetadataBlock {
+            block_type: VORBIS_COMMENT,
+            body: serialize_vorbis_comments("", &[]),
+        }]);
+        assert!(parse_metadata(&data).is_err());
+        assert!(parse_metadata(b"fLaC\x00\x00\x00\x22").is_err());
+        assert!(!is_flac_data(b"ID3\x04"));
+    }
+}
@@ -0,0 +1,899 @@
+//! ID3v2 RVA2 (relative volume adjustment) and TXXX frames
+//!
+//! Some players and taggers store a volume adjustment in ID3v2.3/2.4 `RVA2`
+//! frames instead of mp3gain's APEv2 tags. This module reads and writes
+//! those frames in the ID3v2 tag at the start of a file, and can rewrite an
+//! adjustment in place (the field has a fixed size, so the tag never moves).
+//!
+//! ReplayGain and mp3gain undo values can also be stored as user-defined
+//! text (`TXXX`) frames whose description is the APEv2 key, e.g.
+//! `REPLAYGAIN_TRACK_GAIN`; see [`read_txxx`] and [`with_txxx`].
+//!
+//! RVA2 frame body:
+//! ```text
+//! identification    Latin-1 text, NUL terminated ("track", "album", ...)
+//! then per channel:
+//!   channel type    1 byte (1 = master volume)
+//!   adjustment      signed 16-bit big-endian, in 1/512 dB
+//!   bits of peak    1 byte (0 = no peak stored)
+//!   peak            ceil(bits / 8) bytes, big-endian
+//! ```
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::write_error;
+
+/// RVA2 channel type of the master volume
+pub const CHANNEL_MASTER: u8 = 1;
+
+/// One channel entry of an RVA2 frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rva2Channel {
+    /// Channel type (0 = other, 1 = master, 2 = front right, ...)
+    pub channel_type: u8,
+    /// Volume adjustment in dB
+    pub adjustment_db: f64,
+    /// Peak as a fraction of full scale, if stored
+    pub peak: Option<f64>,
+    /// File offset of the adjustment field, if it is stored verbatim
+    /// (not unsynchronised) and can be rewritten in place
+    adjustment_offset: Option<usize>,
+}
+
+/// An RVA2 frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rva2Frame {
+    /// Identification string, usually "track" or "album"
+    pub identification: String,
+    pub channels: Vec<Rva2Channel>,
+}
+
+impl Rva2Channel {
+    /// Channel entry to write
+    pub fn new(channel_type: u8, adjustment_db: f64, peak: Option<f64>) -> Self {
+        Rva2Channel {
+            channel_type,
+            adjustment_db,
+            peak,
+            adjustment_offset: None,
+        }
+    }
+}
+
+impl Rva2Frame {
+    /// Frame with a single master volume channel
+    pub fn master_only(identification: &str, adjustment_db: f64, peak: Option<f64>) -> Self {
+        Rva2Frame {
+            identification: identification.to_string(),
+            channels: vec![Rva2Channel::new(CHANNEL_MASTER, adjustment_db, peak)],
+        }
+    }
+
+    /// The master volume channel, if present
+    pub fn master(&self) -> Option<&Rva2Channel> {
+        self.channels
+            .iter()
+            .find(|c| c.channel_type == CHANNEL_MASTER)
+    }
+}
+
+/// Master channel of the frame describing the track
+///
+/// Prefers the frame identified as "track", falling back to the first frame
+/// with a master channel.
+pub fn track_master(frames: &[Rva2Frame]) -> Option<&Rva2Channel> {
+    frames
+        .iter()
+        .filter(|f| f.identification.eq_ignore_ascii_case("track"))
+        .chain(frames)
+        .find_map(Rva2Frame::master)
+}
+
+/// ID3v2.3/2.4 tag at the start of a file
+struct Tag {
+    v4: bool,
+    flags: u8,
+    /// Declared size (excluding header and footer)
+    size: usize,
+    /// Tag body, with whole-tag unsynchronisation (ID3v2.3) removed
+    body: Vec<u8>,
+    /// File offset of `body`, if it is stored verbatim
+    base: Option<usize>,
+    /// Start of the first frame in `body` (after any extended header)
+    frames_start: usize,
+}
+
+/// A frame within [`Tag::body`]
+struct RawFrame {
+    id: [u8; 4],
+    format_flags: u8,
+    /// Header start and content range in the tag body
+    start: usize,
+    content: std::ops::Range<usize>,
+}
+
+impl Tag {
+    fn parse(data: &[u8]) -> Option<Tag> {
+        if data.len() < 10 || &data[0..3] != b"ID3" || !(3..=4).contains(&data[3]) {
+            return None;
+        }
+        let v4 = data[3] == 4;
+        let flags = data[5];
+        let size = syncsafe(&data[6..10]);
+        let end = (10 + size).min(data.len());
+
+        // ID3v2.3 unsynchronises the whole tag, so offsets into the decoded
+        // body no longer match the file
+        let (body, base) = if !v4 && flags & 0x80 != 0 {
+            (remove_unsync(&data[10..end]), None)
+        } else {
+            (data[10..end].to_vec(), Some(10))
+        };
+
+        let mut frames_start = 0;
+        if flags & 0x40 != 0 && body.len() >= 4 {
+            // Extended header: v2.4 size includes itself, v2.3 size doesn't
+            frames_start = if v4 {
+                syncsafe(&body[0..4])
+            } else {
+                4 + u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize
+            };
+        }
+
+        Some(Tag {
+            v4,
+            flags,
+            size,
+            body,
+            base,
+            frames_start,
+        })
+    }
+
+    /// Frames up to the padding (or the first malformed frame)
+    fn frames(&self) -> Vec<RawFrame> {
+        let mut frames = Vec::new();
+        let mut pos = self.frames_start;
+        while pos + 10 <= self.body.len() && self.body[pos] != 0 {
+            let header = &self.body[pos..pos + 10];
+            let size = if self.v4 {
+                syncsafe(&header[4..8])
+            } else {
+                u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize
+            };
+            let content = pos + 10..pos + 10 + size;
+            if content.end > self.body.len() {
+                break;
+            }
+            frames.push(RawFrame {
+                id: [header[0], header[1], header[2], header[3]],
+                format_flags: header[9],
+                start: pos,
+                content: content.clone(),
+            });
+            pos = content.end;
+        }
+        frames
+    }
+
+    /// Decode an RVA2 frame, skipping compressed and encrypted ones
+    fn rva2(&self, frame: &RawFrame) -> Option<Rva2Frame> {
+        let (content, offset) = self.content(frame)?;
+        parse_rva2_body(&content, offset)
+    }
+
+    /// Decode a TXXX frame into (description, value)
+    fn txxx(&self, frame: &RawFrame) -> Option<(String, String)> {
+        let (content, _) = self.content(frame)?;
+        parse_txxx_body(&content)
+    }
+
+    /// Frame data and its file offset (if stored verbatim), or `None` for
+    /// compressed and encrypted frames
+    fn content(&self, frame: &RawFrame) -> Option<(Vec<u8>, Option<usize>)> {
+        let flags = frame.format_flags;
+        let mut content = self.body[frame.content.clone()].to_vec();
+
+        // Skip the flag-dependent bytes that precede the frame data
+        let (unsynchronised, extra) = if self.v4 {
+            if flags & 0x0C != 0 {
+                return None;
+            }
+            let extra = usize::from(flags & 0x40 != 0) + usize::from(flags & 0x01 != 0) * 4;
+            (flags & 0x02 != 0, extra)
+        } else {
+            if flags & 0xC0 != 0 {
+                return None;
+            }
+            (false, usize::from(flags & 0x20 != 0))
+        };
+        content.drain(..extra.min(content.len()));
+        let mut offset = self.base.map(|b| b + frame.content.start + extra);
+        if unsynchronised {
+            content = remove_unsync(&content);
+            offset = None;
+        }
+
+        Some((content, offset))
+    }
+}
+
+/// Read all RVA2 frames from the ID3v2 tag at the start of `data`
+///
+/// ID3v2.2 (which has `RVA` instead), compressed and encrypted frames are
+/// ignored.
+pub fn read_rva2(data: &[u8]) -> Vec<Rva2Frame> {
+    let Some(tag) = Tag::parse(data) else {
+        return Vec::new();
+    };
+    tag.frames()
+        .iter()
+        .filter(|f| &f.id == b"RVA2")
+        .filter_map(|f| tag.rva2(f))
+        .collect()
+}
+
+/// Insert RVA2 frames into the ID3v2 tag at the start of `data`
+///
+/// Existing RVA2 frames with the same identification are replaced and all
+/// other frames are kept. The tag's padding is reused when the new frames
+/// fit, otherwise the tag grows; a new ID3v2.4 tag is created if there is
+/// none. Returns the complete new file contents.
+pub fn with_rva2(data: &[u8], frames: &[Rva2Frame]) -> Result<Vec<u8>> {
+    let replaces = |id: &str| {
+        frames
+            .iter()
+            .any(|f| f.identification.eq_ignore_ascii_case(id))
+    };
+    rewrite_frames(
+        data,
+        |tag, frame| {
+            &frame.id == b"RVA2" && tag.rva2(frame).is_some_and(|f| replaces(&f.identification))
+        },
+        |_| {
+            frames
+                .iter()
+                .map(|f| (*b"RVA2", serialize_rva2_body(f)))
+                .collect()
+        },
+    )
+}
+
+/// Replace the TXXX frames for `keys` in the ID3v2 tag at the start of `data`
+///
+/// Every TXXX frame whose description matches one of `keys`
+/// (case-insensitive) is removed, then one frame per item is added. Other
+/// frames, padding and the tag version are kept as in [`with_rva2`]. If
+/// there is no ID3v2 tag and nothing to add, `data` is returned unchanged.
+pub fn with_txxx(data: &[u8], keys: &[&str], items: &[(&str, &str)]) -> Result<Vec<u8>> {
+    if items.is_empty() && Tag::parse(data).is_none() {
+        return Ok(data.to_vec());
+    }
+    rewrite_frames(
+        data,
+        |tag, frame| {
+            &frame.id == b"TXXX"
+                && tag.txxx(frame).is_some_and(|(description, _)| {
+                    keys.iter().any(|k| k.eq_ignore_ascii_case(&description))
+                })
+        },
+        |v4| {
+            items
+                .iter()
+                .map(|(key, value)| (*b"TXXX", serialize_txxx_body(key, value, v4)))
+                .collect()
+        },
+    )
+}
+
+/// Rebuild the ID3v2 tag at the start of `data` without the frames `remove`
+/// selects, followed by the frames `add` returns for the tag version (true
+/// for ID3v2.4)
+fn rewrite_frames(
+    data: &[u8],
+    remove: impl Fn(&Tag, &RawFrame) -> bool,
+    add: impl Fn(bool) -> Vec<([u8; 4], Vec<u8>)>,
+) -> Result<Vec<u8>> {
+    let (v4, flags, old_size, mut body, rest) = match Tag::parse(data) {
+        Some(tag) => {
+            if tag.flags & 0x80 != 0 {
+                anyhow::bail!("unsynchronised ID3v2 tags are not supported");
+            }
+            let mut body = tag.body[..tag.frames_start.min(tag.body.len())].to_vec();
+            for frame in tag.frames() {
+                if !remove(&tag, &frame) {
+                    body.extend_from_slice(&tag.body[frame.start..frame.content.end]);
+                }
+            }
+            let rest = &data[crate::skip_id3v2(data).min(data.len())..];
+            (tag.v4, tag.flags, tag.size, body, rest)
+        }
+        None if data.len() >= 3 && &data[0..3] == b"ID3" => {
+            anyhow::bail!("unsupported ID3v2 version 2.{}", data.get(3).unwrap_or(&0));
+        }
+        None => (true, 0, 0, Vec::new(), data),
+    };
+
+    for (id, content) in add(v4) {
+        let size = if v4 {
+            to_syncsafe(content.len())
+        } else {
+            (content.len() as u32).to_be_bytes()
+        };
+        body.extend_from_slice(&id);
+        body.extend_from_slice(&size);
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(&content);
+    }
+    if body.len() < old_size {
+        body.resize(old_size, 0);
+    }
+
+    // The footer is optional and not allowed together with padding, so
+    // drop it rather than rewrite it
+    let mut out = Vec::with_capacity(10 + body.len() + rest.len());
+    out.extend_from_slice(b"ID3");
+    out.extend_from_slice(&[if v4 { 4 } else { 3 }, 0, flags & !0x10]);
+    out.extend_from_slice(&to_syncsafe(body.len()));
+    out.extend_from_slice(&body);
+    out.extend_from_slice(rest);
+    Ok(out)
+}
+
+/// Write RVA2 frames to a file (see [`with_rva2`])
+pub fn write_rva2(file_path: &Path, frames: &[Rva2Frame]) -> Result<()> {
+    let data = crate::read_file(file_path)?;
+    let new_data = with_rva2(&data, frames)?;
+    fs::write(file_path, new_data).map_err(|e| write_error(e, file_path))
+}
+
+/// Read all RVA2 frames from a file
+pub fn read_rva2_from_file(file_path: &Path) -> Result<Vec<Rva2Frame>> {
+    Ok(read_rva2(&read_tag_from_file(file_path)?))
+}
+
+/// Read the ID3v2 tag at the start of a file, and nothing after it
+fn read_tag_from_file(file_path: &Path) -> Result<Vec<u8>> {
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    read_tag_bytes(&mut file)
+}
+
+/// Read the ID3v2 tag (header, frames and any footer) at the start of `file`
+///
+/// Returns no bytes if the file doesn't start with an ID3v2 tag.
+pub(crate) fn read_tag_bytes(file: &mut fs::File) -> Result<Vec<u8>> {
+    let header = crate::read_range(file, 0, 10)?;
+    if header.len() < 10 || &header[0..3] != b"ID3" {
+        return Ok(Vec::new());
+    }
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    let len = 10 + syncsafe(&header[6..10]) + footer;
+    crate::read_range(file, 0, len as u64)
+}
+
+/// Read the ID3v2 tag at the start of `file`, keeping only its TXXX frames
+///
+/// The frame headers are read one by one and other frames (album art in
+/// particular) skipped, so [`read_txxx`] on the result sees every TXXX
+/// frame without the whole tag being read. An ID3v2.3 tag unsynchronised as
+/// a whole can't be walked this way and is read in full.
+///
+/// Returns the bytes and the length of the tag in the file (0 without one).
+pub(crate) fn read_txxx_bytes(file: &mut fs::File) -> Result<(Vec<u8>, u64)> {
+    let mut header = crate::read_range(file, 0, 10)?;
+    if header.len() < 10 || &header[0..3] != b"ID3" {
+        return Ok((Vec::new(), 0));
+    }
+    let v4 = header[3] == 4;
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    let end = 10 + syncsafe(&header[6..10]) as u64;
+    let tag_len = end + footer;
+    if !(3..=4).contains(&header[3]) || (!v4 && header[5] & 0x80 != 0) {
+        return Ok((crate::read_range(file, 0, tag_len)?, tag_len));
+    }
+    let frame_size = |bytes: &[u8]| {
+        if v4 {
+            syncsafe(bytes) as u64
+        } else {
+            u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64
+        }
+    };
+
+    let mut body = Vec::new();
+    let mut pos = 10;
+    if header[5] & 0x40 != 0 {
+        // Extended header, kept as is: v2.4 size includes itself, v2.3 size doesn't
+        let size = crate::read_range(file, pos, 4)?;
+        if size.len() == 4 {
+            let len = frame_size(&size) + if v4 { 0 } else { 4 };
+            body = crate::read_range(file, pos, len.min(end - pos))?;
+            pos += len;
+        }
+    }
+    while pos + 10 <= end {
+        let frame_header = crate::read_range(file, pos, 10)?;
+        if frame_header.len() < 10 || frame_header[0] == 0 {
+            break;
+        }
+        let next = pos + 10 + frame_size(&frame_header[4..8]);
+        if next > end {
+            break;
+        }
+        if &frame_header[0..4] == b"TXXX" {
+            body.extend(crate::read_range(file, pos, next - pos)?);
+        }
+        pos = next;
+    }
+
+    header[5] &= !0x10;
+    header[6..10].copy_from_slice(&to_syncsafe(body.len()));
+    header.extend(body);
+    Ok((header, tag_len))
+}
+
+/// Read all TXXX frames as (description, value) from the ID3v2 tag at the
+/// start of `data`
+pub fn read_txxx(data: &[u8]) -> Vec<(String, String)> {
+    let Some(tag) = Tag::parse(data) else {
+        return Vec::new();
+    };
+    tag.frames()
+        .iter()
+        .filter(|f| &f.id == b"TXXX")
+        .filter_map(|f| tag.txxx(f))
+        .collect()
+}
+
+/// Read all TXXX frames from a file (see [`read_txxx`])
+pub fn read_txxx_from_file(file_path: &Path) -> Result<Vec<(String, String)>> {
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    Ok(read_txxx(&read_txxx_bytes(&mut file)?.0))
+}
+
+/// Replace TXXX frames in a file (see [`with_txxx`])
+///
+/// The file is only rewritten if its contents change.
+pub fn write_txxx(file_path: &Path, keys: &[&str], items: &[(&str, &str)]) -> Result<()> {
+    let data = crate::read_file(file_path)?;
+    let new_data = with_txxx(&data, keys, items)?;
+    if new_data != data {
+        fs::write(file_path, new_data).map_err(|e| write_error(e, file_path))?;
+    }
+    Ok(())
+}
+
+/// Version, size and frame IDs of an ID3v2 tag
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagSummary {
+    /// Major version (3 or 4)
+    pub major_version: u8,
+    /// Declared tag size, excluding the 10-byte header and any footer
+    pub size: usize,
+    /// Frame IDs in tag order, e.g. "TIT2" or "RVA2"
+    pub frame_ids: Vec<String>,
+}
+
+/// Summarize the ID3v2.3/2.4 tag at the start of `data`, if any
+pub fn tag_summary(data: &[u8]) -> Option<TagSummary> {
+    let tag = Tag::parse(data)?;
+    Some(TagSummary {
+        major_version: if tag.v4 { 4 } else { 3 },
+        size: tag.size,
+        frame_ids: tag
+            .frames()
+            .iter()
+            .map(|frame| String::from_utf8_lossy(&frame.id).into_owned())
+            .collect(),
+    })
+}
+
+/// Summarize the ID3v2 tag of a file (see [`tag_summary`])
+pub fn read_tag_summary(file_path: &Path) -> Result<Option<TagSummary>> {
+    Ok(tag_summary(&read_tag_from_file(file_path)?))
+}
+
+/// Rewrite the track's master adjustment (see [`track_master`]) in place
+///
+/// The value is rounded to the nearest 1/512 dB. Returns `false` if there is
+/// no master adjustment or it can't be rewritten in place (unsynchronised
+/// tag or frame).
+pub fn set_track_master_adjustment(file_path: &Path, adjustment_db: f64) -> Result<bool> {
+    let frames = read_rva2_from_file(file_path)?;
+    let Some(offset) = track_master(&frames).and_then(|c| c.adjustment_offset) else {
+        return Ok(false);
+    };
+
+    let raw = adjustment_to_raw(adjustment_db);
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(file_path)
+        .map_err(|e| write_error(e, file_path))?;
+    file.seek(SeekFrom::Start(offset as u64))
+        .and_then(|_| file.write_all(&raw.to_be_bytes()))
+        .map_err(|e| write_error(e, file_path))?;
+    Ok(true)
+}
+
+/// Parse an RVA2 frame body; `offset` is its file offset, if writable
+fn parse_rva2_body(content: &[u8], offset: Option<usize>) -> Option<Rva2Frame> {
+    let nul = content.iter().position(|&b| b == 0)?;
+    let identification = content[..nul].iter().map(|&b| b as char).collect();
+
+    let mut channels = Vec::new();
+    let mut i = nul + 1;
+    while i + 4 <= content.len() {
+        let channel_type = content[i];
+        let raw = i16::from_be_bytes([content[i + 1], content[i + 2]]);
+        let bits = content[i + 3] as usize;
+        let peak_len = bits.div_ceil(8);
+        if i + 4 + peak_len > content.len() {
+            break;
+        }
+
+        // Peak is an unsigned integer where 2^(bits-1) is full scale
+        let peak = (bits > 0).then(|| {
+            let value = content[i + 4..i + 4 + peak_len]
+                .iter()
+                .fold(0.0, |acc, &b| acc * 256.0 + b as f64);
+            value / 2f64.powi(bits as i32 - 1)
+        });
+
+        channels.push(Rva2Channel {
+            channel_type,
+            adjustment_db: raw as f64 / 512.0,
+            peak,
+            adjustment_offset: offset.map(|o| o + i + 1),
+        });
+        i += 4 + peak_len;
+    }
+
+    Some(Rva2Frame {
+        identification,
+        channels,
+    })
+}
+
+/// Encode an RVA2 frame body; peaks are stored with 16 bits
+fn serialize_rva2_body(frame: &Rva2Frame) -> Vec<u8> {
+    let mut out: Vec<u8> = frame
+        .identification
+        .chars()
+        .map(|c| u8::try_from(c).unwrap_or(b'?'))
+        .collect();
+    out.push(0);
+    for channel in &frame.channels {
+        out.push(channel.channel_type);
+        out.extend_from_slice(&adjustment_to_raw(channel.adjustment_db).to_be_bytes());
+        match channel.peak {
+            Some(peak) => {
+                out.push(16);
+                let raw = (peak * 32768.0).round().clamp(0.0, 65535.0) as u16;
+                out.extend_from_slice(&raw.to_be_bytes());
+            }
+            None => out.push(0),
+        }
+    }
+    out
+}
+
+/// Parse a TXXX frame body: text encoding, description, NUL, value
+fn parse_txxx_body(content: &[u8]) -> Option<(String, String)> {
+    let (&encoding, text) = content.split_first()?;
+    let wide = encoding == 1 || encoding == 2;
+    let terminator = if wide {
+        (0..text.len().saturating_sub(1))
+            .step_by(2)
+            .find(|&i| text[i] == 0 && text[i + 1] == 0)?
+    } else {
+        text.iter().position(|&b| b == 0)?
+    };
+    let value_start = terminator + if wide { 2 } else { 1 };
+    let description = decode_text(encoding, &text[..terminator])?;
+    let value = decode_text(encoding, &text[value_start..])?;
+    Some((description, value.trim_end_matches('\0').to_string()))
+}
+
+/// Decode ID3v2 text in the given encoding
+///
+/// 0 = Latin-1, 1 = UTF-16 with BOM, 2 = UTF-16BE, 3 = UTF-8.
+fn decode_text(encoding: u8, bytes: &[u8]) -> Option<String> {
+    match encoding {
+        0 => Some(bytes.iter().map(|&b| b as char).collect()),
+        1 | 2 => {
+            let (big_endian, bytes) = match bytes {
+                [0xFF, 0xFE, rest @ ..] => (false, rest),
+                [0xFE, 0xFF, rest @ ..] => (true, rest),
+                _ => (encoding == 2, bytes),
+            };
+            let units = bytes.chunks_exact(2).map(|c| {
+                if big_endian {
+                    u16::from_be_bytes([c[0], c[1]])
+                } else {
+                    u16::from_le_bytes([c[0], c[1]])
+                }
+            });
+            Some(
+                char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            )
+        }
+        3 => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
+    }
+}
+
+/// Encode a TXXX frame body
+///
+/// Latin-1 text is stored as such; anything else as UTF-8 in ID3v2.4 and
+/// UTF-16 with BOM in ID3v2.3, which has no UTF-8.
+fn serialize_txxx_body(description: &str, value: &str, v4: bool) -> Vec<u8> {
+    let text = format!("{}\0{}", description, value);
+    if text.chars().all(|c| (c as u32) < 0x100) {
+        let mut out = vec![0];
+        out.extend(text.chars().map(|c| c as u8));
+        out
+    } else if v4 {
+        let mut out = vec![3];
+        out.extend_from_slice(text.as_bytes());
+        out
+    } else {
+        let mut out = vec![1];
+        for part in text.split('\0') {
+            if out.len() > 1 {
+                out.extend_from_slice(&[0, 0]);
+            }
+            out.extend_from_slice(&[0xFF, 0xFE]);
+            out.extend(part.encode_utf16().flat_map(u16::to_le_bytes));
+        }
+        out
+    }
+}
+
+/// Convert dB to the 1/512 dB fixed-point adjustment field
+fn adjustment_to_raw(adjustment_db: f64) -> i16 {
+    (adjustment_db * 512.0)
+        .round()
+        .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+/// Encode a 28-bit syncsafe integer
+fn to_syncsafe(value: usize) -> [u8; 4] {
+    [
+        ((value >> 21) & 0x7F) as u8,
+        ((value >> 14) & 0x7F) as u8,
+        ((value >> 7) & 0x7F) as u8,
+        (value & 0x7F) as u8,
+    ]
+}
+
+/// Decode a 28-bit syncsafe integer
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes[..4]
+        .iter()
+        .fold(0, |acc, &b| (acc << 7) | (b as usize & 0x7F))
+}
+
+/// Undo ID3v2 unsynchronisation ($FF $00 -> $FF)
+fn remove_unsync(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut prev = 0u8;
+    for &b in data {
+        if !(prev == 0xFF && b == 0x00) {
+            out.push(b);
+        }
+        prev = b;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ID3v2.4 tag holding one RVA2 frame with the given body
+    fn tag_with_rva2(body: &[u8]) -> Vec<u8> {
+        let mut frame = b"RVA2".to_vec();
+        frame.extend_from_slice(&[0, 0, 0, body.len() as u8, 0, 0]);
+        frame.extend_from_slice(body);
+
+        let mut tag = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+        tag.push(frame.len() as u8);
+        tag.extend_from_slice(&frame);
+        tag
+    }
+
+    #[test]
+    fn test_parse_rva2() {
+        // "album": master -3.5 dB with a 16-bit peak of 0.75, then
+        // front right +1.0 dB without peak
+        let mut body = b"album\0".to_vec();
+        body.extend_from_slice(&[1, 0xF9, 0x00, 16, 0x60, 0x00]);
+        body.extend_from_slice(&[2, 0x02, 0x00, 0]);
+        let tag = tag_with_rva2(&body);
+
+        let frames = read_rva2(&tag);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].identification, "album");
+        assert_eq!(frames[0].channels.len(), 2);
+
+        let master = frames[0].master().unwrap();
+        assert_eq!(master.adjustment_db, -3.5);
+        assert_eq!(master.peak, Some(0.75));
+        assert_eq!(master.adjustment_offset, Some(10 + 10 + 7));
+
+        let right = &frames[0].channels[1];
+        assert_eq!(right.channel_type, 2);
+        assert_eq!(right.adjustment_db, 1.0);
+        assert_eq!(right.peak, None);
+    }
+
+    #[test]
+    fn test_track_master_prefers_track_frame() {
+        let channel = |db: f64| Rva2Channel {
+            channel_type: CHANNEL_MASTER,
+            adjustment_db: db,
+            peak: None,
+            adjustment_offset: None,
+        };
+        let frames = vec![
+            Rva2Frame {
+                identification: "album".to_string(),
+                channels: vec![channel(-1.0)],
+            },
+            Rva2Frame {
+                identification: "Track".to_string(),
+                channels: vec![channel(-2.0)],
+            },
+        ];
+        assert_eq!(track_master(&frames).unwrap().adjustment_db, -2.0);
+        assert_eq!(track_master(&frames[..1]).unwrap().adjustment_db, -1.0);
+        assert!(track_master(&[]).is_none());
+    }
+
+    #[test]
+    fn test_unsynchronised_frame_is_read_only() {
+        // v2.4 frame with the unsynchronisation flag: $FF $00 decodes to $FF
+        let mut tag = tag_with_rva2(b"track\0\x01\xFF\x00\x00\x00");
+        tag[10 + 9] = 0x02;
+
+        let frames = read_rva2(&tag);
+        let master = frames[0].master().unwrap();
+        assert_eq!(master.adjustment_db, -0.5);
+        assert_eq!(master.adjustment_offset, None);
+    }
+
+    #[test]
+    fn test_write_rva2_round_trip() {
+        let audio = b"\xFF\xFB\x90\x64audio".to_vec();
+
+        // No tag yet: a new ID3v2.4 tag is prepended
+        let written = with_rva2(
+            &audio,
+            &[
+                Rva2Frame::master_only("track", -6.27, Some(0.75)),
+                Rva2Frame::master_only("album", 2.0, None),
+            ],
+        )
+        .unwrap();
+        assert!(written.ends_with(&audio));
+
+        let frames = read_rva2(&written);
+        assert_eq!(frames.len(), 2);
+        let track = frames[0].master().unwrap();
+        assert_eq!(frames[0].identification, "track");
+        // -6.27 dB is stored as round(-6.27 * 512) = -3210 in 1/512 dB
+        assert_eq!(track.adjustment_db, -3210.0 / 512.0);
+        assert_eq!(track.peak, Some(0.75));
+        assert_eq!(frames[1].master().unwrap().adjustment_db, 2.0);
+        assert_eq!(frames[1].master().unwrap().peak, None);
+
+        // Rewriting "track" replaces it, keeps "album", and reuses the
+        // existing tag size
+        let rewritten = with_rva2(&written, &[Rva2Frame::master_only("track", 1.5, None)]).unwrap();
+        let frames = read_rva2(&rewritten);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].identification, "album");
+        assert_eq!(track_master(&frames).unwrap().adjustment_db, 1.5);
+        assert_eq!(rewritten.len(), written.len());
+    }
+
+    #[test]
+    fn test_write_rva2_keeps_other_frames_and_padding() {
+        // ID3v2.3 tag with a TIT2 frame and 100 bytes of padding
+        let mut tag = b"ID3\x03\x00\x00\x00\x00\x00\x7F".to_vec();
+        tag.extend_from_slice(b"TIT2\x00\x00\x00\x05\x00\x00\x00Song");
+        tag.resize(10 + 127, 0);
+        let mut data = tag.clone();
+        data.extend_from_slice(b"\xFF\xFB\x90\x64");
+
+        let written = with_rva2(&data, &[Rva2Frame::master_only("track", -3.5, None)]).unwrap();
+        assert_eq!(written.len(), data.len());
+        assert_eq!(&written[3..4], &[3]);
+        assert_eq!(&written[10..25], &tag[10..25]);
+        assert_eq!(
+            track_master(&read_rva2(&written)).unwrap().adjustment_db,
+            -3.5
+        );
+    }
+
+    #[test]
+    fn test_txxx_round_trip() {
+        let audio = b"\xFF\xFB\x90\x64audio".to_vec();
+        let keys = ["REPLAYGAIN_TRACK_GAIN", "MP3GAIN_UNDO"];
+
+        // Nothing to write and no tag: the data is left alone
+        assert_eq!(with_txxx(&audio, &keys, &[]).unwrap(), audio);
+
+        let written = with_txxx(
+            &audio,
+            &keys,
+            &[
+                ("REPLAYGAIN_TRACK_GAIN", "-6.50 dB"),
+                ("MP3GAIN_UNDO", "+004,+004,N"),
+            ],
+        )
+        .unwrap();
+        assert!(written.ends_with(&audio));
+        assert_eq!(
+            read_txxx(&written),
+            vec![
+                ("REPLAYGAIN_TRACK_GAIN".to_string(), "-6.50 dB".to_string()),
+                ("MP3GAIN_UNDO".to_string(), "+004,+004,N".to_string()),
+            ]
+        );
+
+        // Keys match case-insensitively; other TXXX frames and RVA2 are kept
+        let mut other = with_rva2(&written, &[Rva2Frame::master_only("track", 1.0, None)]).unwrap();
+        other = with_txxx(&other, &["ARTIST_NOTE"], &[("ARTIST_NOTE", "x")]).unwrap();
+        let rewritten = with_txxx(&other, &["mp3gain_undo"], &[]).unwrap();
+        assert_eq!(
+            read_txxx(&rewritten),
+            vec![
+                ("REPLAYGAIN_TRACK_GAIN".to_string(), "-6.50 dB".to_string()),
+                ("ARTIST_NOTE".to_string(), "x".to_string()),
+            ]
+        );
+        assert_eq!(read_rva2(&rewritten).len(), 1);
+        assert_eq!(rewritten.len(), other.len(), "padding is reused");
+    }
+
+    #[test]
+    fn test_txxx_text_encodings() {
+        // UTF-16 with BOM, as ID3v2.3 taggers commonly write it
+        let mut body = vec![1, 0xFF, 0xFE];
+        body.extend("KEY".encode_utf16().flat_map(u16::to_le_bytes));
+        body.extend_from_slice(&[0, 0, 0xFF, 0xFE]);
+        body.extend("1.5 dB".encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(
+            parse_txxx_body(&body),
+            Some(("KEY".to_string(), "1.5 dB".to_string()))
+        );
+
+        // UTF-8 with a trailing NUL
+        assert_eq!(
+            parse_txxx_body(b"\x03K\xC3\xA9Y\0v\0"),
+            Some(("K\u{e9}Y".to_string(), "v".to_string()))
+        );
+
+        for v4 in [false, true] {
+            let body = serialize_txxx_body("KEY", "\u{2603}", v4);
+            assert_eq!(
+                parse_txxx_body(&body),
+                Some(("KEY".to_string(), "\u{2603}".to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_id3v2_tag() {
+        assert!(read_rva2(b"\xFF\xFB\x90\x64").is_empty());
+        assert!(read_rva2(b"ID3\x02\x00\x00\x00\x00\x00\x00").is_empty());
+    }
+}
@@ -0,0 +1,357 @@
+//! Append-only audit journal of processed files (`--journal`)
+//!
+//! Each processed file adds one JSON line (NDJSON) to the journal, including
+//! the file's size and SHA-256 before and after the operation. Unlike the
+//! per-file `--sidecar` reports, lines are only ever appended, so the journal
+//! keeps the whole history of a library. The record layout is up to the
+//! caller; this module supplies the file states and the appending.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::{audio_region, read_range, write_error};
+
+/// Size and SHA-256 of a file's contents at one point in time
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileState {
+    pub size: u64,
+    /// Lowercase hex digest
+    pub sha256: String,
+}
+
+impl FileState {
+    /// State of `data`, the contents of a file already in memory
+    pub fn of(data: &[u8]) -> Self {
+        Self::of_parts(&[data])
+    }
+
+    /// State of a file made of `parts` in order
+    fn of_parts(parts: &[&[u8]]) -> Self {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        FileState {
+            size: hasher.len,
+            sha256: hasher
+                .finish()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        }
+    }
+}
+
+/// Tags around the audio data of a file, split off the way a gain pass does
+struct Tags {
+    prefix: Vec<u8>,
+    suffix: Vec<u8>,
+    audio_len: u64,
+}
+
+impl Tags {
+    /// Read the tags of `file_path` and return them with its audio data range
+    fn read(file_path: &Path) -> Result<(Self, fs::File, u64)> {
+        let mut file = fs::File::open(file_path)
+            .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+        let len = file.metadata()?.len();
+        let (start, end) = audio_region(&mut file, len)?;
+        let tags = Tags {
+            prefix: read_range(&mut file, 0, start)?,
+            suffix: read_range(&mut file, end, len - end)?,
+            audio_len: end - start,
+        };
+        Ok((tags, file, start))
+    }
+
+    fn state(&self, audio: &[u8]) -> FileState {
+        FileState::of_parts(&[&self.prefix, audio, &self.suffix])
+    }
+}
+
+/// Audio data seen by gain passes while a [`Recording`] is running
+struct Capture {
+    tags: Tags,
+    /// State before the first pass, hashed from the data it read
+    before: Option<FileState>,
+    /// Audio data as the last pass wrote it
+    audio: Option<Vec<u8>>,
+}
+
+thread_local! {
+    static CAPTURE: RefCell<Option<Capture>> = const { RefCell::new(None) };
+}
+
+/// Note the audio data a gain pass read, before it changes anything
+pub(crate) fn capture_pass_input(audio: &[u8]) {
+    CAPTURE.with(|capture| {
+        if let Some(capture) = capture.borrow_mut().as_mut() {
+            if capture.audio.is_none()
+                && capture.before.is_none()
+                && audio.len() as u64 == capture.tags.audio_len
+            {
+                capture.before = Some(capture.tags.state(audio));
+            }
+        }
+    });
+}
+
+/// Note the audio data a gain pass wrote back
+pub(crate) fn capture_pass_output(audio: &[u8]) {
+    CAPTURE.with(|capture| {
+        if let Some(capture) = capture.borrow_mut().as_mut() {
+            capture.audio = Some(audio.to_vec());
+        }
+    });
+}
+
+/// States of one file before and after an operation on this thread
+///
+/// The audio data is the bulk of a file and is already in memory while a
+/// gain pass rewrites it, so it is hashed from there and only the tags are
+/// read from disk. A file whose audio data no pass rewrote (a tag-only
+/// operation, or one that failed) has its audio data read once, in
+/// [`Recording::finish`], and it counts for both states.
+pub struct Recording {
+    _private: (),
+}
+
+impl Recording {
+    /// Read the tags of `file_path` and start capturing gain passes
+    pub fn start(file_path: &Path) -> Result<Self> {
+        let (tags, _, _) = Tags::read(file_path)?;
+        CAPTURE.with(|capture| {
+            *capture.borrow_mut() = Some(Capture {
+                tags,
+                before: None,
+                audio: None,
+            })
+        });
+        Ok(Recording { _private: () })
+    }
+
+    /// States of `file_path` before and after the operation
+    pub fn finish(self, file_path: &Path) -> Result<(FileState, FileState)> {
+        let Some(capture) = CAPTURE.with(|capture| capture.borrow_mut().take()) else {
+            anyhow::bail!("No recording in progress");
+        };
+        let (tags, mut file, start) = Tags::read(file_path)?;
+        let audio = match capture.audio {
+            Some(audio) if audio.len() as u64 == tags.audio_len => audio,
+            _ => read_range(&mut file, start, tags.audio_len)?,
+        };
+        let after = tags.state(&audio);
+        let before = match capture.before {
+            Some(before) => before,
+            None => capture.tags.state(&audio),
+        };
+        Ok((before, after))
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        CAPTURE.with(|capture| capture.borrow_mut().take());
+    }
+}
+
+/// Append `record` as one JSON line to the journal at `journal_path`
+///
+/// The file is created if needed. The line goes out in a single write, so
+/// records of concurrent runs don't interleave.
+pub fn append<T: Serialize>(journal_path: &Path, record: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal_path)
+        .and_then(|mut journal| journal.write_all(&line))
+        .map_err(|e| write_error(e, journal_path))
+}
+
+/// SHA-256 round constants (FIPS 180-4)
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Incremental SHA-256 (FIPS 180-4)
+struct Sha256 {
+    state: [u32; 8],
+    /// Bytes not yet making up a whole block
+    pending: Vec<u8>,
+    /// Message length in bytes
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            pending: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.pending.is_empty() {
+            let take = (64 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+        let full = data.len() / 64 * 64;
+        for block in data[..full].chunks_exact(64) {
+            self.compress(block);
+        }
+        self.pending.extend_from_slice(&data[full..]);
+    }
+
+    /// Pad the message (a 1 bit, zeros, then its length in bits, to a
+    /// multiple of 64 bytes) and return the digest
+    fn finish(mut self) -> [u8; 32] {
+        let mut tail = std::mem::take(&mut self.pending);
+        tail.push(0x80);
+        while tail.len() % 64 != 56 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&(self.len * 8).to_be_bytes());
+        for block in tail.chunks_exact(64) {
+            self.compress(block);
+        }
+
+        let mut digest = [0u8; 32];
+        for (out, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testgen, ApeTag, ChannelMode, MpegVersion, TAG_REPLAYGAIN_TRACK_GAIN};
+
+    #[test]
+    fn test_recording_hashes_the_pass_buffers() {
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_journal_recording_{}.mp3",
+            std::process::id()
+        ));
+        let audio = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 1000, 170);
+        let mut ape = ApeTag::new();
+        ape.set(TAG_REPLAYGAIN_TRACK_GAIN, "-3.00 dB");
+        let original = crate::with_ape_tag(&audio, &ape);
+        fs::write(&path, &original).unwrap();
+        let bytes_read = || crate::BYTES_READ.with(|bytes| bytes.get());
+
+        // The audio data is hashed as the gain pass holds it; only the tags
+        // are read again
+        let recording = Recording::start(&path).unwrap();
+        crate::apply_gain(&path, 2).unwrap();
+        let read = bytes_read();
+        let (before, after) = recording.finish(&path).unwrap();
+        assert!(bytes_read() - read < 1024, "{} bytes", bytes_read() - read);
+        assert_eq!(before, FileState::of(&original));
+        assert_eq!(after, FileState::of(&fs::read(&path).unwrap()));
+        assert_ne!(before, after);
+
+        // Without a pass the audio data is read once for both states
+        let recording = Recording::start(&path).unwrap();
+        let (before, after) = recording.finish(&path).unwrap();
+        assert_eq!(before, FileState::of(&fs::read(&path).unwrap()));
+        assert_eq!(before, after);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sha256_vectors() {
+        // FIPS 180-4 examples, plus messages around the padding boundaries
+        let hex = |data: &[u8]| FileState::of(data).sha256;
+        assert_eq!(
+            hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            hex(&[b'a'; 1000]),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+        assert_eq!(FileState::of(&[b'a'; 1000]).size, 1000);
+
+        // Split at any point, the parts hash like the whole message
+        let data: Vec<u8> = (0..=255).cycle().take(300).collect();
+        for split in [0, 1, 55, 64, 100, 299, 300] {
+            let (head, tail) = data.split_at(split);
+            assert_eq!(FileState::of_parts(&[head, tail]), FileState::of(&data));
+        }
+    }
+}
@@ -17,6 +17,7 @@
 //! - **replaygain**: Enable ReplayGain analysis (requires symphonia)
 //!   - Track gain calculation (`-r` flag)
 //!   - Album gain calculation (`-a` flag)
+//!   - Loudness of already-decoded PCM ([`replaygain::PcmAnalyzer`])
 //!
 //! ## Example
 //!
@@ -37,12 +38,23 @@
 //! Each gain step equals 1.5 dB (fixed by MP3 specification).
 //! The global_gain field is 8 bits, allowing values 0-255.
 
+pub mod cache;
+pub mod flac;
+pub mod id3v2;
+pub mod journal;
 pub mod mp4meta;
 pub mod replaygain;
+pub mod tag_keys;
+#[cfg(test)]
+mod testgen;
 
 use anyhow::{Context, Result};
+use replaygain::AudioFileType;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tag_keys::is_gain_key;
 
 /// MP3 gain step size in dB (fixed by format specification)
 pub const GAIN_STEP_DB: f64 = 1.5;
@@ -53,15 +65,276 @@ pub const MAX_GAIN: u8 = 255;
 /// Minimum global_gain value
 pub const MIN_GAIN: u8 = 0;
 
+/// Features compiled into this build of the library
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// ReplayGain analysis (track/album gain, decoded peak)
+    pub replaygain: bool,
+    /// AAC/M4A decoding for ReplayGain analysis
+    pub aac: bool,
+    /// FLAC decoding for ReplayGain analysis (tags only, audio is not changed)
+    pub flac: bool,
+    /// Memory-mapped file I/O
+    pub mmap: bool,
+    /// Parallel processing of multiple files
+    pub parallel: bool,
+    /// Reading/writing gain information in ID3v2 tags (TXXX and RVA2, see
+    /// `--tag-format`)
+    pub id3v2: bool,
+    /// MPEG audio versions that can be adjusted (Layer III only)
+    pub mpeg_versions: &'static [&'static str],
+    /// Sample rates supported by ReplayGain analysis
+    pub sample_rates: &'static [u32],
+}
+
+/// Report which optional features and formats this build supports
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        replaygain: replaygain::is_available(),
+        aac: replaygain::is_available(),
+        flac: replaygain::is_available(),
+        mmap: false,
+        parallel: false,
+        id3v2: true,
+        mpeg_versions: &["MPEG1", "MPEG2", "MPEG2.5"],
+        sample_rates: replaygain::supported_sample_rates(),
+    }
+}
+
+/// What mp3rgain can do with one audio format
+#[derive(Debug, Clone)]
+pub struct FormatSupport {
+    pub file_type: AudioFileType,
+    /// Display name ("MP3", "M4A/AAC", ...)
+    pub name: &'static str,
+    /// File extensions the format is usually found under
+    pub extensions: &'static [&'static str],
+    /// Lossless gain changes to the audio itself (MP3 global_gain)
+    pub frame_gain: bool,
+    /// Tag format ReplayGain gains are written to
+    pub tag_gain: &'static str,
+    /// ReplayGain analysis by decoding (needs the `replaygain` feature)
+    pub replaygain_analysis: bool,
+    /// Undo of frame gain changes from the stored MP3GAIN_UNDO
+    pub undo: bool,
+    /// Gain changes to the left or right channel alone
+    pub channel_gain: bool,
+}
+
+/// Operations supported for each audio format, in this build
+///
+/// Formats without global_gain ([`AudioFileType::has_global_gain`]) are
+/// tag-only: gains go into ReplayGain tags and the audio is never changed.
+pub fn format_support() -> Vec<FormatSupport> {
+    let formats: [(AudioFileType, &str, &[&str], &str); 4] = [
+        (AudioFileType::Mp3, "MP3", &["mp3"], "APEv2 or ID3v2"),
+        (
+            AudioFileType::Aac,
+            "M4A/AAC",
+            &["m4a", "mp4"],
+            "MP4 freeform (iTunes)",
+        ),
+        (AudioFileType::AacAdts, "AAC (ADTS)", &["aac"], "APEv2"),
+        (AudioFileType::Flac, "FLAC", &["flac"], "Vorbis comments"),
+    ];
+    formats
+        .into_iter()
+        .map(|(file_type, name, extensions, tag_gain)| FormatSupport {
+            file_type,
+            name,
+            extensions,
+            frame_gain: file_type.has_global_gain(),
+            tag_gain,
+            replaygain_analysis: replaygain::is_available(),
+            undo: file_type.has_global_gain(),
+            channel_gain: file_type.has_global_gain(),
+        })
+        .collect()
+}
+
+/// Cooperative cancellation flag for long-running batch operations
+///
+/// Clones share the same flag, so a token can be handed to a worker thread
+/// and cancelled from a GUI button or a request timeout. Cancellation is
+/// checked between files (and between packets during analysis); files that
+/// were already written are not rolled back.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation of all operations using this token
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Return an [`Interrupted`] error if cancellation was requested
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Interrupted.into());
+        }
+        Ok(())
+    }
+}
+
+/// Error returned when an operation was stopped via [`CancellationToken`]
+///
+/// Use `err.downcast_ref::<Interrupted>()` to distinguish it from I/O errors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Operation interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Error returned when a file changed between being analyzed and written
+///
+/// Use `err.downcast_ref::<FileModifiedDuringOperation>()` to tell it apart
+/// from I/O errors; the file was left as the other process wrote it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileModifiedDuringOperation;
+
+impl std::fmt::Display for FileModifiedDuringOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "File was modified by another process after it was analyzed; not written"
+        )
+    }
+}
+
+impl std::error::Error for FileModifiedDuringOperation {}
+
+/// Size and modification time of a file, taken before analyzing it
+///
+/// [`verify`](Self::verify) guards the write that follows the analysis
+/// against another process having changed the file in between (say, on a
+/// shared media server). A change keeping both the size and the timestamp,
+/// within the file system's resolution, goes unnoticed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSnapshot {
+    len: u64,
+    modified: Option<std::time::SystemTime>,
+}
+
+impl FileSnapshot {
+    pub fn capture(file_path: &Path) -> Result<Self> {
+        let meta = fs::metadata(file_path)
+            .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+        Ok(FileSnapshot {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+
+    /// Return a [`FileModifiedDuringOperation`] error if the file no longer
+    /// matches the snapshot (or is gone)
+    pub fn verify(&self, file_path: &Path) -> Result<()> {
+        match Self::capture(file_path) {
+            Ok(now) if now == *self => Ok(()),
+            _ => Err(FileModifiedDuringOperation.into()),
+        }
+    }
+}
+
+/// Whether an I/O error usually clears up on its own, such as a file held
+/// open by a player, sync client or virus scanner
+///
+/// On Windows this includes sharing and lock violations, which is how a
+/// file opened elsewhere without sharing shows up.
+pub fn is_transient_io_error(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    if matches!(
+        err.kind(),
+        ErrorKind::ResourceBusy
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+    ) {
+        return true;
+    }
+    // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION
+    cfg!(windows) && matches!(err.raw_os_error(), Some(32) | Some(33))
+}
+
+/// Longest wait between two attempts of [`retry_io`]
+pub const MAX_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Run an I/O operation, retrying up to `retries` more times while it fails
+/// with a transient error
+///
+/// The delay starts at `backoff` and doubles after each attempt, up to
+/// [`MAX_RETRY_BACKOFF`]. Other errors,
+/// and the last transient one, are returned as is. Only pass operations that
+/// are safe to repeat after a failure, such as opening or renaming a file.
+pub fn retry_io<T>(
+    retries: u32,
+    backoff: std::time::Duration,
+    mut op: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut delay = backoff.min(MAX_RETRY_BACKOFF);
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient_io_error(&e) => {
+                std::thread::sleep(delay);
+                delay = next_backoff(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The delay after `delay`: twice as long, but at most [`MAX_RETRY_BACKOFF`]
+fn next_backoff(delay: std::time::Duration) -> std::time::Duration {
+    delay
+        .checked_mul(2)
+        .map_or(MAX_RETRY_BACKOFF, |next| next.min(MAX_RETRY_BACKOFF))
+}
+
+/// Error for a failed write to `file_path`
+///
+/// A read-only file and a full disk are the usual causes, so those get a
+/// message saying what to do about them; anything else keeps the plain
+/// "Failed to write" context over the OS error.
+pub fn write_error(err: std::io::Error, file_path: &Path) -> anyhow::Error {
+    use std::io::ErrorKind;
+
+    let hint = match err.kind() {
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => {
+            " (file is read-only; make it writable with chmod u+w, or attrib -r on Windows)"
+        }
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => " (disk full)",
+        _ => "",
+    };
+    anyhow::Error::new(err).context(format!("Failed to write: {}{}", file_path.display(), hint))
+}
+
 /// Result of MP3 file analysis
 #[derive(Debug, Clone)]
 pub struct Mp3Analysis {
     /// Number of audio frames in the file
     pub frame_count: usize,
-    /// MPEG version detected (1, 2, or 2.5)
+    /// MPEG version of the first frame (1, 2, or 2.5); a stream is assumed
+    /// not to change version, which fixes its granules per frame
     pub mpeg_version: String,
     /// Channel mode (Stereo, Joint Stereo, Dual Channel, Mono)
     pub channel_mode: String,
+    /// Sample rate of the first frame in Hz
+    pub sample_rate: u32,
     /// Minimum global_gain value found across all granules
     pub min_gain: u8,
     /// Maximum global_gain value found across all granules
@@ -72,6 +345,42 @@ pub struct Mp3Analysis {
     pub headroom_steps: i32,
     /// Maximum safe positive adjustment in dB
     pub headroom_db: f64,
+    /// Junk bytes skipped before the ID3v2 tag or first frame
+    pub leading_bytes: usize,
+    /// Bytes between the end of the ID3v2 tag (or the start of the audio
+    /// data) and the first frame, such as encoder padding
+    pub id3v2_gap: usize,
+    /// Non-frame bytes after the last valid frame (before any trailing tags)
+    pub trailing_bytes: usize,
+    /// Number of global_gain fields visited (granules x channels, summed over frames)
+    pub gain_locations: usize,
+    /// Number of global_gain fields holding each value, indexed by value;
+    /// the counts add up to `gain_locations`
+    pub gain_histogram: [u64; 256],
+    /// Number of places between frames where the stream lost sync (corrupt
+    /// or truncated frames) and was re-synchronized on the next valid frame
+    pub bad_frames: usize,
+    /// Byte offsets at which those invalid stretches start
+    pub bad_frame_offsets: Vec<usize>,
+}
+
+/// Xing/Info header in the first frame, with the encoder's LAME tag if present
+///
+/// LAME (and ffmpeg) can store a ReplayGain estimate made while encoding;
+/// compare it with mp3rgain's own analysis to spot files that were already
+/// adjusted or mis-tagged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoHeader {
+    /// "Xing" (VBR) or "Info" (CBR)
+    pub marker: String,
+    /// Encoder string from the LAME tag, e.g. "LAME3.100" or "Lavc62.11"
+    pub encoder: Option<String>,
+    /// Stored track ("radio") gain in dB
+    pub track_gain_db: Option<f64>,
+    /// Stored album ("audiophile") gain in dB
+    pub album_gain_db: Option<f64>,
+    /// Stored peak amplitude (1.0 = full scale)
+    pub peak: Option<f64>,
 }
 
 /// MPEG version
@@ -147,6 +456,16 @@ impl FrameHeader {
             4
         }
     }
+
+    /// Length of the side information following the header (and CRC)
+    fn side_info_len(&self) -> usize {
+        match (self.version, self.channel_mode) {
+            (MpegVersion::Mpeg1, ChannelMode::Mono) => 17,
+            (MpegVersion::Mpeg1, _) => 32,
+            (_, ChannelMode::Mono) => 9,
+            (_, _) => 17,
+        }
+    }
 }
 
 /// Bitrate table for MPEG1 Layer III
@@ -252,15 +571,32 @@ fn parse_header(header: &[u8]) -> Option<FrameHeader> {
 }
 
 /// Location of a global_gain field within the file
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default)]
 struct GainLocation {
     byte_offset: usize,
     bit_offset: u8,
 }
 
+/// global_gain locations of one frame (at most 2 granules × 2 channels)
+///
+/// Kept on the stack so scanning a file does not allocate per frame.
+#[derive(Debug, Clone, Copy, Default)]
+struct GainLocations {
+    locations: [GainLocation; 4],
+    len: usize,
+}
+
+impl std::ops::Deref for GainLocations {
+    type Target = [GainLocation];
+
+    fn deref(&self) -> &[GainLocation] {
+        &self.locations[..self.len]
+    }
+}
+
 /// Calculate global_gain locations within a frame's side information
-fn calculate_gain_locations(frame_offset: usize, header: &FrameHeader) -> Vec<GainLocation> {
-    let mut locations = Vec::new();
+fn calculate_gain_locations(frame_offset: usize, header: &FrameHeader) -> GainLocations {
+    let mut locations = GainLocations::default();
     let side_info_start = frame_offset + header.side_info_offset();
 
     let num_channels = header.channel_mode.channel_count();
@@ -287,10 +623,11 @@ fn calculate_gain_locations(frame_offset: usize, header: &FrameHeader) -> Vec<Ga
             let byte_offset = side_info_start + global_gain_bit / 8;
             let bit_offset = (global_gain_bit % 8) as u8;
 
-            locations.push(GainLocation {
+            locations.locations[locations.len] = GainLocation {
                 byte_offset,
                 bit_offset,
-            });
+            };
+            locations.len += 1;
         }
     }
 
@@ -350,52 +687,125 @@ fn skip_id3v2(data: &[u8]) -> usize {
         | ((data[8] as usize & 0x7F) << 7)
         | (data[9] as usize & 0x7F);
 
-    10 + size
+    // ID3v2.4 may append a 10-byte footer (flags bit 4) that is not
+    // included in the declared size
+    let has_footer = data[3] == 4 && data[5] & 0x10 != 0;
+
+    if has_footer {
+        10 + size + 10
+    } else {
+        10 + size
+    }
 }
 
-/// Find the end of audio data (before trailing tags)
-/// Returns the position where audio data ends (before APE tag, ID3v1 tag, or end of file)
-fn find_audio_end(data: &[u8]) -> usize {
-    let mut audio_end = data.len();
+/// Maximum number of leading bytes searched for an ID3v2 tag or the first frame
+const MAX_PREFIX_SCAN: usize = 4096;
+
+/// Check for a plausible ID3v2 header ("ID3", version 2-4, syncsafe size)
+fn is_id3v2_header(data: &[u8]) -> bool {
+    data.len() >= 10
+        && &data[0..3] == b"ID3"
+        && (2..=4).contains(&data[3])
+        && data[4] != 0xFF
+        && data[6..10].iter().all(|&b| b < 0x80)
+}
 
-    // Check for ID3v1 tag at end (128 bytes, starts with "TAG")
-    if audio_end >= 128 && &data[audio_end - 128..audio_end - 125] == b"TAG" {
-        audio_end -= 128;
+/// Find where audio data starts
+///
+/// Normally the file starts with an ID3v2 tag or directly with a frame, but
+/// some files carry a few junk bytes first. A bounded prefix is scanned for
+/// whichever of an ID3v2 tag or a valid frame comes first, so the tag body is
+/// never mistaken for audio.
+///
+/// Returns the start position and the number of junk bytes skipped before the
+/// tag or frame. Without a match the start is 0 and frames are resynced from
+/// the beginning as usual.
+fn find_audio_start(data: &[u8], audio_end: usize) -> (usize, usize) {
+    if data.len() >= 3 && &data[0..3] == b"ID3" {
+        return (skip_id3v2(data), 0);
     }
 
-    // Check for APE tag before ID3v1 (or at end if no ID3v1)
-    // APE footer is 32 bytes, starts with "APETAGEX"
-    if audio_end >= 32 && &data[audio_end - 32..audio_end - 24] == APE_PREAMBLE {
-        let footer_start = audio_end - 32;
-        // Read tag size from footer (includes items + footer, not header)
-        let tag_size = read_u32_le(&data[footer_start + 12..]) as usize;
-        let flags = read_u32_le(&data[footer_start + 20..]);
-        let has_header = (flags & APE_FLAG_HEADER_PRESENT) != 0;
-        let header_size = if has_header { 32 } else { 0 };
+    for pos in 0..audio_end.min(MAX_PREFIX_SCAN) {
+        if is_id3v2_header(&data[pos..]) {
+            return (pos + skip_id3v2(&data[pos..]), pos);
+        }
+        if frame_at(data, pos, audio_end, pos == 0).is_some() {
+            return (pos, pos);
+        }
+    }
+
+    (0, 0)
+}
 
-        // Move audio_end before the APE tag
-        if footer_start + 32 >= tag_size + header_size {
-            audio_end = footer_start + 32 - tag_size - header_size;
+/// Find the end of audio data (before trailing tags)
+///
+/// Returns the position where audio data ends: before an ID3v1 tag at the
+/// end of the file and the APEv2 tags and Lyrics3 blocks in front of it, in
+/// whichever order taggers left them.
+fn find_audio_end(data: &[u8]) -> usize {
+    let mut audio_end = without_id3v1(data);
+
+    loop {
+        let tail = &data[..audio_end];
+        let tag_start = find_ape_footer(tail)
+            .filter(|&footer_start| footer_start + 32 == audio_end)
+            .and_then(|footer_start| ape_tag_start(tail, footer_start))
+            .or_else(|| lyrics3_start(tail));
+        match tag_start {
+            Some(start) if start < audio_end => audio_end = start,
+            _ => return audio_end,
         }
     }
+}
+
+/// Largest Lyrics3 v1 block, from `LYRICSBEGIN` to `LYRICSEND`
+const LYRICS3_V1_MAX_SIZE: usize = 11 + 5100 + 9;
+
+/// Start of a Lyrics3 block (v1 or v2) that ends at the end of `data`
+fn lyrics3_start(data: &[u8]) -> Option<usize> {
+    if data.ends_with(b"LYRICS200") {
+        // v2: a six-digit size of everything from LYRICSBEGIN up to the size
+        let size_start = data.len().checked_sub(15)?;
+        let size: usize = std::str::from_utf8(&data[size_start..size_start + 6])
+            .ok()?
+            .parse()
+            .ok()?;
+        let start = size_start.checked_sub(size)?;
+        return data[start..].starts_with(b"LYRICSBEGIN").then_some(start);
+    }
+    if data.ends_with(b"LYRICSEND") {
+        let search_start = data.len().saturating_sub(LYRICS3_V1_MAX_SIZE);
+        return data[search_start..]
+            .windows(11)
+            .position(|w| w == b"LYRICSBEGIN")
+            .map(|pos| search_start + pos);
+    }
+    None
+}
 
-    audio_end
+/// Whether a tag that only ever follows the audio starts at `data`
+///
+/// Once frames have been found, the scan stops at an APEv2 header or
+/// footer, a Lyrics3 block or (where the next frame should start) an ID3v1
+/// tag, so bytes inside the tag are never taken for a frame. This covers
+/// tags that [`find_audio_end`] can't strip, such as ones followed by junk
+/// or left incomplete by an interrupted write.
+fn is_trailing_tag(data: &[u8], frame_expected: bool) -> bool {
+    data.starts_with(APE_PREAMBLE)
+        || data.starts_with(b"LYRICSBEGIN")
+        || (frame_expected && data.len() >= 128 && data.starts_with(b"TAG"))
+}
+
+/// Where a Xing/Info header would start: right after the side information
+fn xing_offset(frame_offset: usize, header: &FrameHeader) -> usize {
+    frame_offset + header.side_info_offset() + header.side_info_len()
 }
 
 /// Check if a frame contains a Xing or Info VBR header
 /// These frames should be skipped when applying gain adjustments
 /// to match the behavior of the original mp3gain
 fn is_xing_frame(data: &[u8], frame_offset: usize, header: &FrameHeader) -> bool {
-    // Calculate where the Xing/Info header would be located
-    // It appears after the side information
-    let side_info_len = match (header.version, header.channel_mode) {
-        (MpegVersion::Mpeg1, ChannelMode::Mono) => 17,
-        (MpegVersion::Mpeg1, _) => 32,
-        (_, ChannelMode::Mono) => 9,
-        (_, _) => 17,
-    };
-
-    let xing_offset = frame_offset + header.side_info_offset() + side_info_len;
+    let xing_offset = xing_offset(frame_offset, header);
 
     // Check if we have enough data
     if xing_offset + 4 > data.len() {
@@ -407,41 +817,159 @@ fn is_xing_frame(data: &[u8], frame_offset: usize, header: &FrameHeader) -> bool
     marker == b"Xing" || marker == b"Info"
 }
 
+/// Number of bytes scanned for the next frame once sync is lost before a
+/// candidate has to prove itself
+///
+/// Comfortably larger than any Layer III frame. Past this window a frame is
+/// only accepted when [`RESYNC_CONFIRM_FRAMES`] more frames follow it back to
+/// back, so a long corrupt stretch in the middle of the file is crossed while
+/// false syncs in trailing junk (partial download, appended garbage) are not.
+/// If no such run turns up, the rest of the audio data is trailing junk.
+const MAX_RESYNC_BYTES: usize = 8192;
+
+/// Frames that must follow a frame found past [`MAX_RESYNC_BYTES`]
+const RESYNC_CONFIRM_FRAMES: usize = 3;
+
+/// Parse the frame at `pos` if it is valid
+///
+/// A frame is valid if it lies entirely within the audio data (side
+/// information included) and either the next frame starts with a sync word or
+/// the frame ends at/near the audio data boundary.
+///
+/// `expected` marks a position where a frame should start: the beginning of
+/// the audio data or right after the previous frame. A header there is
+/// trusted without the next sync word, so the last frame is kept when junk
+/// follows it and a lone frame is still found. Positions reached by scanning
+/// always need the next sync word to rule out false syncs.
+fn frame_at(data: &[u8], pos: usize, audio_end: usize, expected: bool) -> Option<FrameHeader> {
+    if pos + 4 > audio_end {
+        return None;
+    }
+
+    let header = parse_header(&data[pos..])?;
+    if header.side_info_offset() + header.side_info_len() > header.frame_size {
+        return None;
+    }
+
+    let next_pos = pos + header.frame_size;
+    let valid_frame = if next_pos + 2 <= audio_end {
+        // Check if next position has a valid frame sync
+        expected || (data[next_pos] == 0xFF && (data[next_pos + 1] & 0xE0) == 0xE0)
+    } else {
+        // Last frame: valid if it ends at or before audio_end
+        next_pos <= audio_end
+    };
+
+    valid_frame.then_some(header)
+}
+
+/// Find the next valid frame at or after `pos`
+///
+/// `synced` indicates a frame has already been found: the search then stops
+/// at trailing tags, and candidates past `MAX_RESYNC_BYTES` must start a run
+/// of frames (see [`is_frame_run`]). A frame is expected exactly at `pos`
+/// (see [`frame_at`]); later positions are scanned candidates.
+fn find_next_frame(
+    data: &[u8],
+    mut pos: usize,
+    audio_end: usize,
+    synced: bool,
+) -> Option<(usize, FrameHeader)> {
+    let scan_limit = if synced {
+        pos.saturating_add(MAX_RESYNC_BYTES)
+    } else {
+        usize::MAX
+    };
+
+    let start = pos;
+    while pos + 4 <= audio_end {
+        if synced && is_trailing_tag(&data[pos..audio_end], pos == start) {
+            return None;
+        }
+        if let Some(header) = frame_at(data, pos, audio_end, pos == start) {
+            if pos <= scan_limit || is_frame_run(data, pos, &header, audio_end) {
+                return Some((pos, header));
+            }
+        }
+        pos += 1;
+        if !synced {
+            // Before the first frame nothing but a sync byte can start one,
+            // so padding between the ID3v2 tag and the audio is skipped whole
+            pos = data[pos..audio_end]
+                .iter()
+                .position(|&b| b == 0xFF)
+                .map_or(audio_end, |offset| pos + offset);
+        }
+    }
+
+    None
+}
+
+/// Whether [`RESYNC_CONFIRM_FRAMES`] frames of the same stream follow the
+/// frame at `pos` back to back (or the audio data ends cleanly before that)
+fn is_frame_run(data: &[u8], pos: usize, header: &FrameHeader, audio_end: usize) -> bool {
+    let mut next = pos + header.frame_size;
+    for _ in 0..RESYNC_CONFIRM_FRAMES {
+        match frame_at(data, next, audio_end, false) {
+            Some(h) if h.version == header.version && h.sample_rate == header.sample_rate => {
+                next += h.frame_size;
+            }
+            _ => return next == audio_end,
+        }
+    }
+    true
+}
+
+/// Summary of a frame scan
+struct FrameScan {
+    /// Number of audio frames visited (Xing/Info frames excluded)
+    frame_count: usize,
+    /// Junk bytes skipped before the ID3v2 tag or first frame
+    leading_bytes: usize,
+    /// Bytes skipped between the audio start and the first frame
+    id3v2_gap: usize,
+    /// Bytes between the end of the last frame and the end of the audio data
+    trailing_bytes: usize,
+    /// Position right after the last frame (0 if no frame was found)
+    frames_end: usize,
+    /// Number of global_gain fields visited
+    gain_locations: usize,
+    /// Offsets where a frame was expected but sync had to be searched for
+    bad_frame_offsets: Vec<usize>,
+}
+
 /// Internal function to iterate over frames
 /// Skips Xing/Info VBR header frames to match mp3gain behavior
-fn iterate_frames<F>(data: &[u8], mut callback: F) -> Result<usize>
+///
+/// Each frame's gain locations follow from its own header, so a stream is
+/// assumed to keep one MPEG version (granule count) and channel mode
+/// throughout. Analysis and gain passes parse the same bytes identically, so
+/// their gain location totals must agree; see [`check_gain_locations`].
+fn iterate_frames<F>(data: &[u8], mut callback: F) -> Result<FrameScan>
 where
     F: FnMut(usize, &FrameHeader, &[GainLocation]),
 {
     let audio_end = find_audio_end(data);
-    let mut pos = skip_id3v2(data);
+    let (mut pos, leading_bytes) = find_audio_start(data, audio_end);
+    let audio_start = pos;
+    let mut id3v2_gap = 0;
     let mut frame_count = 0;
-
-    while pos + 4 <= audio_end {
-        let header = match parse_header(&data[pos..]) {
-            Some(h) => h,
-            None => {
-                pos += 1;
-                continue;
-            }
-        };
-
-        let next_pos = pos + header.frame_size;
-
-        // Validate frame: either next frame starts with sync word,
-        // or this frame ends at/near the audio data boundary
-        let valid_frame = if next_pos + 2 <= audio_end {
-            // Check if next position has a valid frame sync
-            data[next_pos] == 0xFF && (data[next_pos + 1] & 0xE0) == 0xE0
-        } else {
-            // Last frame: valid if it ends at or before audio_end
-            next_pos <= audio_end
-        };
-
-        if !valid_frame {
-            pos += 1;
-            continue;
+    let mut gain_locations = 0;
+    let mut bad_frame_offsets = Vec::new();
+
+    let mut synced = false;
+
+    while let Some((frame_pos, header)) = find_next_frame(data, pos, audio_end, synced) {
+        if !synced {
+            id3v2_gap = frame_pos - audio_start;
+        } else if frame_pos > pos {
+            // The previous frame wasn't followed by another one: the bytes up
+            // to the next sync are a corrupt frame, left untouched
+            bad_frame_offsets.push(pos);
         }
+        synced = true;
+        pos = frame_pos;
+        let next_pos = pos + header.frame_size;
 
         // Skip Xing/Info header frames (VBR metadata)
         // This matches the behavior of the original mp3gain
@@ -454,10 +982,29 @@ where
         callback(pos, &header, &locations);
 
         frame_count += 1;
+        gain_locations += locations.len();
         pos = next_pos;
     }
 
-    Ok(frame_count)
+    if !synced {
+        check_other_format(data)?;
+    }
+
+    let trailing_bytes = if synced {
+        audio_end.saturating_sub(pos)
+    } else {
+        0
+    };
+
+    Ok(FrameScan {
+        frame_count,
+        leading_bytes,
+        id3v2_gap,
+        trailing_bytes,
+        frames_end: if synced { pos } else { 0 },
+        gain_locations,
+        bad_frame_offsets,
+    })
 }
 
 /// Analyze an MP3 file and return gain statistics
@@ -468,32 +1015,72 @@ where
 /// # Returns
 /// * Analysis results including frame count, gain range, and headroom
 pub fn analyze(file_path: &Path) -> Result<Mp3Analysis> {
-    let data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    #[cfg(test)]
+    ANALYZE_CALLS.with(|calls| calls.set(calls.get() + 1));
+
+    analyze_data(&read_file(file_path)?)
+}
+
+/// [`analyze`] reading only the audio region between the tags
+///
+/// The gain statistics, channel mode and frame count are those of
+/// [`analyze`]; byte offsets (such as `bad_frame_offsets`) are relative to
+/// the region. Used before applying gain, which touches only that region
+/// too (see [`apply_pass_to_file`]).
+fn analyze_audio(file_path: &Path) -> Result<Mp3Analysis> {
+    #[cfg(test)]
+    ANALYZE_CALLS.with(|calls| calls.set(calls.get() + 1));
+
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    let len = file.metadata()?.len();
+    let (start, end) = audio_region(&mut file, len)?;
+    analyze_data(&read_range(&mut file, start, end - start)?)
+}
+
+/// Analyze an MP3 file that is already open
+///
+/// Like [`analyze`], for callers that manage their own files (see
+/// [`apply_gain_file`]). The whole file is read from the start, whatever the
+/// handle's position.
+pub fn analyze_file(file: &mut fs::File) -> Result<Mp3Analysis> {
+    let len = file
+        .metadata()
+        .context("Failed to read the open file")?
+        .len();
+    let data = read_range(file, 0, len).context("Failed to read the open file")?;
+    analyze_data(&data)
+}
 
+/// [`analyze`] for MP3 data in memory
+pub(crate) fn analyze_data(data: &[u8]) -> Result<Mp3Analysis> {
     let mut min_gain = 255u8;
     let mut max_gain = 0u8;
     let mut total_gain: u64 = 0;
     let mut gain_count: u64 = 0;
+    let mut gain_histogram = [0u64; 256];
     let mut first_version = None;
     let mut first_channel_mode = None;
+    let mut sample_rate = 0;
 
-    let frame_count = iterate_frames(&data, |_pos, header, locations| {
+    let scan = iterate_frames(data, |_pos, header, locations| {
         if first_version.is_none() {
             first_version = Some(header.version);
             first_channel_mode = Some(header.channel_mode);
+            sample_rate = header.sample_rate;
         }
 
         for loc in locations {
-            let gain = read_gain_at(&data, loc);
+            let gain = read_gain_at(data, loc);
             min_gain = min_gain.min(gain);
             max_gain = max_gain.max(gain);
             total_gain += gain as u64;
             gain_count += 1;
+            gain_histogram[gain as usize] += 1;
         }
     })?;
 
-    if frame_count == 0 {
+    if scan.frame_count == 0 {
         anyhow::bail!("No valid MP3 frames found");
     }
 
@@ -502,17 +1089,168 @@ pub fn analyze(file_path: &Path) -> Result<Mp3Analysis> {
     let headroom_db = headroom_steps as f64 * GAIN_STEP_DB;
 
     Ok(Mp3Analysis {
-        frame_count,
+        frame_count: scan.frame_count,
         mpeg_version: first_version.unwrap().as_str().to_string(),
         channel_mode: first_channel_mode.unwrap().as_str().to_string(),
+        sample_rate,
         min_gain,
         max_gain,
         avg_gain,
         headroom_steps,
         headroom_db,
+        leading_bytes: scan.leading_bytes,
+        id3v2_gap: scan.id3v2_gap,
+        trailing_bytes: scan.trailing_bytes,
+        gain_locations: scan.gain_locations,
+        gain_histogram,
+        bad_frames: scan.bad_frame_offsets.len(),
+        bad_frame_offsets: scan.bad_frame_offsets,
     })
 }
 
+// Number of `analyze` scans on this thread, so tests can check that a full
+// scan is skipped when it isn't needed
+#[cfg(test)]
+thread_local! {
+    static ANALYZE_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// Bytes read through `read_range` (which every file read goes through) on
+// this thread, so tests can check what tag inspection and gain passes read
+#[cfg(test)]
+thread_local! {
+    static BYTES_READ: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// Read a whole file, through [`read_range`] so tests see the bytes read
+pub(crate) fn read_file(file_path: &Path) -> Result<Vec<u8>> {
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    let len = file.metadata()?.len();
+    read_range(&mut file, 0, len)
+}
+
+/// Read up to `len` bytes of `file` starting at `start` (fewer at end of file)
+pub(crate) fn read_range(file: &mut fs::File, start: u64, len: u64) -> Result<Vec<u8>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::new();
+    file.take(len).read_to_end(&mut data)?;
+
+    #[cfg(test)]
+    BYTES_READ.with(|bytes| bytes.set(bytes.get() + data.len() as u64));
+
+    Ok(data)
+}
+
+/// Read the Xing/Info header and LAME tag of an MP3 file
+///
+/// # Returns
+/// * `None` if the first frame carries no Xing/Info header
+pub fn read_info_header(file_path: &Path) -> Result<Option<InfoHeader>> {
+    let data = read_file(file_path)?;
+    Ok(parse_info_header(&data))
+}
+
+/// Parse the Xing/Info header of the first frame
+fn parse_info_header(data: &[u8]) -> Option<InfoHeader> {
+    let audio_end = find_audio_end(data);
+    let (start, _) = find_audio_start(data, audio_end);
+    let (frame_pos, header) = find_next_frame(data, start, audio_end, false)?;
+    if !is_xing_frame(data, frame_pos, &header) {
+        return None;
+    }
+
+    let frame_end = (frame_pos + header.frame_size).min(data.len());
+    let xing = xing_offset(frame_pos, &header);
+    let marker = String::from_utf8_lossy(&data[xing..xing + 4]).into_owned();
+
+    // Optional fields, each present if its flag bit is set:
+    // frames (4), bytes (4), TOC (100), quality (4)
+    let mut info = InfoHeader {
+        marker,
+        encoder: None,
+        track_gain_db: None,
+        album_gain_db: None,
+        peak: None,
+    };
+    if xing + 8 > frame_end {
+        return Some(info);
+    }
+    let flags = u32::from_be_bytes([
+        data[xing + 4],
+        data[xing + 5],
+        data[xing + 6],
+        data[xing + 7],
+    ]);
+    let mut lame = xing + 8;
+    for (bit, len) in [(0x1, 4), (0x2, 4), (0x4, 100), (0x8, 4)] {
+        if flags & bit != 0 {
+            lame += len;
+        }
+    }
+
+    // LAME tag: 9-byte encoder string, ..., peak at +11, track gain at +15,
+    // album gain at +17 (36 bytes in total)
+    if lame + 36 > frame_end || !data[lame..lame + 4].iter().all(u8::is_ascii_alphanumeric) {
+        return Some(info);
+    }
+    let tag = &data[lame..lame + 36];
+
+    let encoder = String::from_utf8_lossy(&tag[..9]);
+    info.encoder = Some(encoder.trim_end_matches(['\0', ' ']).to_string());
+
+    // Peak is 8.23 fixed point; 0 means not stored
+    let peak = u32::from_be_bytes([tag[11], tag[12], tag[13], tag[14]]);
+    if peak != 0 {
+        info.peak = Some(peak as f64 / (1u32 << 23) as f64);
+    }
+    info.track_gain_db = parse_lame_gain(u16::from_be_bytes([tag[15], tag[16]]), 1);
+    info.album_gain_db = parse_lame_gain(u16::from_be_bytes([tag[17], tag[18]]), 2);
+
+    Some(info)
+}
+
+/// Decode a LAME tag gain field: name (3 bits), originator (3 bits), sign, 0.1 dB units (9 bits)
+///
+/// Returns `None` unless the name matches `name` (1 = radio, 2 = audiophile)
+/// and an originator is set.
+fn parse_lame_gain(raw: u16, name: u16) -> Option<f64> {
+    let originator = (raw >> 10) & 0x7;
+    if raw >> 13 != name || originator == 0 {
+        return None;
+    }
+    let value = (raw & 0x1FF) as f64 / 10.0;
+    Some(if raw & 0x200 != 0 { -value } else { value })
+}
+
+/// Analyze several MP3 files, keeping each file's result separate
+///
+/// A file that cannot be read or parsed yields an `Err` in its own entry and
+/// does not stop the batch; nothing is written. `cancel` is checked before
+/// each file; once set, an [`Interrupted`] error is returned instead of the
+/// partial results. `progress` is called after each file with the number of
+/// completed files and the total.
+///
+/// # Returns
+/// * One `(path, analysis)` pair per input, in input order
+pub fn analyze_many(
+    files: &[&Path],
+    cancel: &CancellationToken,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<Vec<(PathBuf, Result<Mp3Analysis>)>> {
+    let mut results = Vec::with_capacity(files.len());
+
+    for (i, file) in files.iter().enumerate() {
+        cancel.check()?;
+        results.push((file.to_path_buf(), analyze(file)));
+        progress(i + 1, files.len());
+    }
+
+    Ok(results)
+}
+
 /// Gain adjustment mode
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum GainMode {
@@ -520,6 +1258,8 @@ enum GainMode {
     Saturating,
     /// Wrapping mode: wrap around 0-255 range
     Wrapping,
+    /// Saturating, but negative gain stops at this floor instead of 0
+    Floor(u8),
 }
 
 /// Apply the gain adjustment to a single gain location
@@ -536,40 +1276,65 @@ fn adjust_gain_value(current: u8, steps: i32, mode: GainMode) -> u8 {
             let new_gain = (current as i32 + steps) % 256;
             ((new_gain + 256) % 256) as u8
         }
+        GainMode::Floor(floor) => {
+            let new_gain = adjust_gain_value(current, steps, GainMode::Saturating);
+            if steps < 0 {
+                // Values already at or below the floor are left alone
+                new_gain.max(floor.min(current))
+            } else {
+                new_gain
+            }
+        }
     }
 }
 
+/// Frames and global_gain fields touched by a gain pass
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct GainPass {
+    frames: usize,
+    gain_locations: usize,
+    /// Frames in which a left/right value stopped short at 0 or 255
+    saturated: [usize; 2],
+    /// Whether any value was held at the 255 ceiling
+    clipped: bool,
+    /// Invalid stretches skipped between frames (see [`Mp3Analysis::bad_frames`])
+    bad_frames: usize,
+    /// Whether any value moved by less than the requested steps
+    held: bool,
+}
+
+/// Check that a gain pass visited as many global_gain fields as the analysis
+///
+/// Both passes derive the locations from the same frame headers, so a
+/// mismatch means the frame layout was parsed inconsistently and the undo
+/// information (recorded from the analysis) would not match what was applied.
+/// Callers check inside the pass, so that nothing is written on a mismatch.
+fn check_gain_locations(analyzed: usize, applied: usize) -> Result<()> {
+    if analyzed != applied {
+        anyhow::bail!(
+            "Analysis found {} global_gain fields but the gain pass found {}; \
+             undo information would not match",
+            analyzed,
+            applied
+        );
+    }
+    Ok(())
+}
+
 /// Internal function to apply gain to all frames in data
-/// Returns the number of modified frames
-fn apply_gain_to_data(data: &mut [u8], gain_steps: i32, mode: GainMode) -> usize {
+fn apply_gain_to_data(data: &mut [u8], gain_steps: i32, mode: GainMode) -> GainPass {
     let audio_end = find_audio_end(data);
-    let mut pos = skip_id3v2(data);
-    let mut modified_frames = 0;
+    let (mut pos, _) = find_audio_start(data, audio_end);
+    let mut pass = GainPass::default();
 
-    while pos + 4 <= audio_end {
-        let header = match parse_header(&data[pos..]) {
-            Some(h) => h,
-            None => {
-                pos += 1;
-                continue;
-            }
-        };
+    let mut synced = false;
 
+    while let Some((frame_pos, header)) = find_next_frame(data, pos, audio_end, synced) {
+        pass.bad_frames += usize::from(synced && frame_pos > pos);
+        synced = true;
+        pos = frame_pos;
         let next_pos = pos + header.frame_size;
 
-        // Validate frame: either next frame starts with sync word,
-        // or this frame ends at/near the audio data boundary
-        let valid_frame = if next_pos + 2 <= audio_end {
-            data[next_pos] == 0xFF && (data[next_pos + 1] & 0xE0) == 0xE0
-        } else {
-            next_pos <= audio_end
-        };
-
-        if !valid_frame {
-            pos += 1;
-            continue;
-        }
-
         // Skip Xing/Info header frames (VBR metadata)
         if is_xing_frame(data, pos, &header) {
             pos = next_pos;
@@ -578,41 +1343,345 @@ fn apply_gain_to_data(data: &mut [u8], gain_steps: i32, mode: GainMode) -> usize
 
         let locations = calculate_gain_locations(pos, &header);
 
-        for loc in &locations {
+        for loc in locations.iter() {
             let current_gain = read_gain_at(data, loc);
             let new_gain = adjust_gain_value(current_gain, gain_steps, mode);
             write_gain_at(data, loc, new_gain);
+            pass.held |= i32::from(new_gain) != i32::from(current_gain) + gain_steps;
         }
 
-        modified_frames += 1;
+        pass.frames += 1;
+        pass.gain_locations += locations.len();
         pos = next_pos;
     }
 
-    modified_frames
+    pass
 }
 
-/// Apply gain adjustment to MP3 file (lossless)
+/// Current and projected global_gain values of a single frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameGains {
+    /// Byte offset of the frame header within the file
+    pub offset: usize,
+    /// Current values, granule by granule: [gr0_ch0, gr0_ch1, gr1_ch0, gr1_ch1]
+    pub current: Vec<u8>,
+    /// Values after applying the requested gain, in the same order
+    pub projected: Vec<u8>,
+}
+
+/// List the global_gain values of every frame and what applying gain would change them to
+///
+/// The file is not modified. Frames are visited exactly as [`apply_gain`] visits
+/// them (Xing/Info frames skipped), so this is suitable for frame-level
+/// comparison against other tools.
 ///
 /// # Arguments
 /// * `file_path` - Path to MP3 file
-/// * `gain_steps` - Number of 1.5dB steps to apply (positive = louder)
-///
+/// * `gain_steps` - Number of 1.5dB steps to project (0 = current values only)
+/// * `wrap` - Project with wrapping instead of clamping, as [`apply_gain_wrap`]
+pub fn frame_gains(file_path: &Path, gain_steps: i32, wrap: bool) -> Result<Vec<FrameGains>> {
+    let data = read_file(file_path)?;
+    let mode = if wrap {
+        GainMode::Wrapping
+    } else {
+        GainMode::Saturating
+    };
+
+    let mut frames = Vec::new();
+    iterate_frames(&data, |pos, _header, locations| {
+        let current: Vec<u8> = locations
+            .iter()
+            .map(|loc| read_gain_at(&data, loc))
+            .collect();
+        let projected = current
+            .iter()
+            .map(|&gain| adjust_gain_value(gain, gain_steps, mode))
+            .collect();
+        frames.push(FrameGains {
+            offset: pos,
+            current,
+            projected,
+        });
+    })?;
+
+    if frames.is_empty() {
+        anyhow::bail!("No valid MP3 frames found");
+    }
+
+    Ok(frames)
+}
+
+/// Header fields of a single frame, as listed by [`frame_headers`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameHeaderInfo {
+    /// Byte offset of the frame header within the file
+    pub offset: usize,
+    /// MPEG version ("MPEG1", "MPEG2" or "MPEG2.5")
+    pub mpeg_version: String,
+    /// Channel mode (Stereo, Joint Stereo, Dual Channel, Mono)
+    pub channel_mode: String,
+    /// Bitrate in kbps
+    pub bitrate_kbps: u32,
+    /// Sample rate in Hz
+    pub sample_rate: u32,
+    /// Frame length in bytes, including the header
+    pub frame_size: usize,
+    /// Whether the frame is protected by a CRC
+    pub has_crc: bool,
+    /// Whether this is a Xing/Info header frame (left alone by gain changes)
+    pub is_info_frame: bool,
+}
+
+/// List the headers of the first `limit` frames, Xing/Info frames included
+///
+/// Frames are found the same way as by [`analyze`] and [`apply_gain`], so
+/// this shows where they start parsing a file.
+pub fn frame_headers(file_path: &Path, limit: usize) -> Result<Vec<FrameHeaderInfo>> {
+    let data = read_file(file_path)?;
+    let audio_end = find_audio_end(&data);
+    let (mut pos, _) = find_audio_start(&data, audio_end);
+
+    let mut frames = Vec::new();
+    let mut synced = false;
+
+    while frames.len() < limit {
+        let Some((frame_pos, header)) = find_next_frame(&data, pos, audio_end, synced) else {
+            break;
+        };
+        synced = true;
+        frames.push(FrameHeaderInfo {
+            offset: frame_pos,
+            mpeg_version: header.version.as_str().to_string(),
+            channel_mode: header.channel_mode.as_str().to_string(),
+            bitrate_kbps: header.bitrate_kbps,
+            sample_rate: header.sample_rate,
+            frame_size: header.frame_size,
+            has_crc: header.has_crc,
+            is_info_frame: is_xing_frame(&data, frame_pos, &header),
+        });
+        pos = frame_pos + header.frame_size;
+    }
+
+    Ok(frames)
+}
+
+/// 4-byte header and length of every frame, Xing/Info frames included
+fn frame_layout(data: &[u8]) -> Vec<([u8; 4], usize)> {
+    let audio_end = find_audio_end(data);
+    let (mut pos, _) = find_audio_start(data, audio_end);
+
+    let mut frames = Vec::new();
+    let mut synced = false;
+    while let Some((frame_pos, header)) = find_next_frame(data, pos, audio_end, synced) {
+        synced = true;
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[frame_pos..frame_pos + 4]);
+        frames.push((bytes, header.frame_size));
+        pos = frame_pos + header.frame_size;
+    }
+    frames
+}
+
+/// Check that `after` still holds the frames of `before`
+///
+/// A gain change only rewrites side information, so both must parse into
+/// the same frames with the same headers, in the same order; tags around
+/// them may differ. A lost frame or a broken sync word fails the check.
+///
+/// # Returns
+/// * Number of frames compared
+pub fn verify_frame_layout(before: &[u8], after: &[u8]) -> Result<usize> {
+    let expected = frame_layout(before);
+    let found = frame_layout(after);
+
+    if let Some(index) = expected
+        .iter()
+        .zip(&found)
+        .position(|(expected, found)| expected != found)
+    {
+        anyhow::bail!(
+            "verification failed: frame {} of {} changed its header or length",
+            index + 1,
+            expected.len()
+        );
+    }
+    if expected.len() != found.len() {
+        anyhow::bail!(
+            "verification failed: {} frames before the write, {} after",
+            expected.len(),
+            found.len()
+        );
+    }
+    Ok(found.len())
+}
+
+/// Apply gain adjustment to MP3 file (lossless)
+///
+/// # Arguments
+/// * `file_path` - Path to MP3 file
+/// * `gain_steps` - Number of 1.5dB steps to apply (positive = louder)
+///
 /// # Returns
 /// * Number of frames modified
 pub fn apply_gain(file_path: &Path, gain_steps: i32) -> Result<usize> {
+    apply_gain_mode(file_path, gain_steps, GainMode::Saturating)
+}
+
+/// Apply gain without letting negative gain push global_gain below `floor`
+///
+/// Frames at or below `floor` stay where they are, keeping a minimum signal
+/// level that later positive gain can recover. Positive gain is unaffected,
+/// and a floor of 0 is the same as [`apply_gain`].
+pub fn apply_gain_floor(file_path: &Path, gain_steps: i32, floor: u8) -> Result<usize> {
+    apply_gain_mode(file_path, gain_steps, GainMode::Floor(floor))
+}
+
+fn apply_gain_mode(file_path: &Path, gain_steps: i32, mode: GainMode) -> Result<usize> {
     if gain_steps == 0 {
         return Ok(0);
     }
 
-    let mut data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    apply_pass_to_file(file_path, |data| {
+        apply_gain_bytes_mode(data, gain_steps, mode)
+    })
+}
+
+/// Byte range of a file that can hold audio frames
+///
+/// Leaves out an ID3v2 tag at the start and an APEv2 tag (with any ID3v1
+/// after it) at the end. Without a recognizable tag the range extends to
+/// that end of the file.
+fn audio_region(file: &mut fs::File, len: u64) -> Result<(u64, u64)> {
+    let header = read_range(file, 0, 10)?;
+    let start = (skip_id3v2(&header) as u64).min(len);
+
+    let tail_start = len.saturating_sub(32 + 128).max(start);
+    let tail = read_range(file, tail_start, len - tail_start)?;
+    let end = find_ape_footer(&tail)
+        .and_then(|footer| {
+            let tag_size = read_u32_le(&tail[footer + 12..]) as u64;
+            let flags = read_u32_le(&tail[footer + 20..]);
+            let header_size = if flags & APE_FLAG_HEADER_PRESENT != 0 {
+                32
+            } else {
+                0
+            };
+            (tail_start + footer as u64 + 32).checked_sub(tag_size + header_size)
+        })
+        .filter(|&end| end >= start)
+        .unwrap_or(len);
+
+    Ok((start, end))
+}
+
+/// Run `pass` over the audio region of a file and write the region back in place
+///
+/// Only the bytes between the tags are read (see [`audio_region`]), so
+/// memory stays proportional to the audio rather than to embedded album
+/// art. A gain pass never changes the length of the data, so the tags are
+/// left on disk as they are. Nothing is written if `pass` fails.
+fn apply_pass_to_file<T>(file_path: &Path, pass: impl FnOnce(&mut [u8]) -> Result<T>) -> Result<T> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(file_path)
+        .map_err(|e| write_error(e, file_path))?;
+    apply_pass_to_open_file(&mut file, pass, |e| write_error(e, file_path))
+}
+
+/// [`apply_pass_to_file`] for a handle open for reading and writing
+fn apply_pass_to_open_file<T>(
+    file: &mut fs::File,
+    pass: impl FnOnce(&mut [u8]) -> Result<T>,
+    write_error: impl FnOnce(std::io::Error) -> anyhow::Error,
+) -> Result<T> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let len = file.metadata()?.len();
+
+    let (start, end) = audio_region(file, len)?;
+    let mut data = read_range(file, start, end - start)?;
+    journal::capture_pass_input(&data);
+    let result = pass(&mut data)?;
+
+    file.seek(SeekFrom::Start(start))
+        .and_then(|_| file.write_all(&data))
+        .map_err(write_error)?;
+    journal::capture_pass_output(&data);
+
+    Ok(result)
+}
+
+/// Apply gain adjustment to an MP3 file that is already open (lossless)
+///
+/// Like [`apply_gain`], for callers that manage their own files: one that is
+/// locked, a memfd, or opened with particular flags. `file` must be open for
+/// reading and writing; only its audio frames are rewritten, in place, and
+/// its position afterwards is unspecified.
+///
+/// # Returns
+/// * Number of frames modified
+pub fn apply_gain_file(file: &mut fs::File, gain_steps: i32) -> Result<usize> {
+    if gain_steps == 0 {
+        return Ok(0);
+    }
 
-    let modified_frames = apply_gain_to_data(&mut data, gain_steps, GainMode::Saturating);
+    apply_pass_to_open_file(
+        file,
+        |data| apply_gain_bytes_mode(data, gain_steps, GainMode::Saturating),
+        |e| anyhow::Error::new(e).context("Failed to write to the open file"),
+    )
+}
+
+/// Apply gain adjustment to MP3 data in memory (lossless)
+///
+/// Like [`apply_gain`], for a buffer that never touches the filesystem, such
+/// as an upload. Tags are left alone; see [`apply_gain_with_undo_bytes`] to
+/// record the change.
+///
+/// # Returns
+/// * Number of frames modified
+pub fn apply_gain_bytes(data: &mut [u8], gain_steps: i32) -> Result<usize> {
+    apply_gain_bytes_mode(data, gain_steps, GainMode::Saturating)
+}
+
+fn apply_gain_bytes_mode(data: &mut [u8], gain_steps: i32, mode: GainMode) -> Result<usize> {
+    if gain_steps == 0 {
+        return Ok(0);
+    }
+
+    let pass = apply_gain_to_data(data, gain_steps, mode);
+    if pass.frames == 0 {
+        check_other_format(data)?;
+    }
+
+    Ok(pass.frames)
+}
 
-    fs::write(file_path, &data)
-        .with_context(|| format!("Failed to write: {}", file_path.display()))?;
+/// Apply the same gain adjustment to several MP3 files
+///
+/// `cancel` is checked before each file; once set, an [`Interrupted`] error is
+/// returned and the remaining files are left untouched. Files processed
+/// before cancellation keep their new gain. `progress` is called after each
+/// file with the number of completed files and the total.
+///
+/// # Returns
+/// * Per-file results (frames modified or the error for that file)
+pub fn apply_gain_many(
+    files: &[&Path],
+    gain_steps: i32,
+    cancel: &CancellationToken,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<Vec<Result<usize>>> {
+    let mut results = Vec::with_capacity(files.len());
+
+    for (i, file) in files.iter().enumerate() {
+        cancel.check()?;
+        results.push(apply_gain(file, gain_steps));
+        progress(i + 1, files.len());
+    }
 
-    Ok(modified_frames)
+    Ok(results)
 }
 
 /// Apply gain adjustment in dB (converted to nearest step)
@@ -628,16 +1697,111 @@ pub fn apply_gain_db(file_path: &Path, gain_db: f64) -> Result<usize> {
     apply_gain(file_path, steps)
 }
 
+/// Number of gain steps needed to move a file's average global_gain to `target_avg`
+///
+/// This is a rough, frame-domain leveling: no audio is decoded and no
+/// equal-loudness weighting is applied (unlike ReplayGain).
+pub fn avg_target_steps(analysis: &Mp3Analysis, target_avg: u8) -> i32 {
+    (target_avg as f64 - analysis.avg_gain).round() as i32
+}
+
+/// Number of gain steps that bring a file's estimated peak to `target_dbfs`
+///
+/// The peak is estimated from the largest global_gain, as mp3gain does when
+/// it can't decode: each step below 255 counts as 1.5 dB of headroom. This is
+/// approximate, since the real peak also depends on the scalefactors and the
+/// spectral data, but needs no decoding. The steps round down, so the
+/// estimate never ends up above the target.
+pub fn peak_target_steps_heuristic(analysis: &Mp3Analysis, target_dbfs: f64) -> i32 {
+    let estimated_dbfs = (analysis.max_gain as f64 - MAX_GAIN as f64) * GAIN_STEP_DB;
+    ((target_dbfs - estimated_dbfs) / GAIN_STEP_DB + 1e-9).floor() as i32
+}
+
+/// Whether applying `gain_steps` would change any global_gain value of the analyzed file
+///
+/// Saturating gain leaves a file untouched when every value is already at
+/// the limit in the requested direction (255 or 0); wrapping by a multiple
+/// of 256 is a no-op as well.
+pub fn gain_would_change(analysis: &Mp3Analysis, gain_steps: i32, wrap: bool) -> bool {
+    if analysis.frame_count == 0 {
+        return false;
+    }
+    if wrap {
+        return gain_steps % 256 != 0;
+    }
+    match gain_steps.signum() {
+        1 => analysis.min_gain < 255,
+        -1 => analysis.max_gain > 0,
+        _ => false,
+    }
+}
+
+/// Shift all frames so the average global_gain reaches `target_avg`
+///
+/// # Arguments
+/// * `file_path` - Path to MP3 file
+/// * `target_avg` - Desired average global_gain (0-255)
+///
+/// # Returns
+/// * Number of frames modified
+pub fn apply_gain_to_avg_target(file_path: &Path, target_avg: u8) -> Result<usize> {
+    let analysis = analyze(file_path)?;
+    apply_gain(file_path, avg_target_steps(&analysis, target_avg))
+}
+
 /// Convert dB gain to MP3 gain steps
 pub fn db_to_steps(db: f64) -> i32 {
     (db / GAIN_STEP_DB).round() as i32
 }
 
+/// Convert dB gain to MP3 gain steps, rounding down where nearest would clip
+///
+/// Like [`db_to_steps`], except that when rounding up takes audio peaking at
+/// `peak` (0.0 to 1.0) above full scale while rounding down doesn't, the
+/// lower step is used: 4.4 dB with 4.2 dB of headroom becomes 2 steps
+/// (+3.0 dB) rather than 3 (+4.5 dB). A gain that clips either way rounds to
+/// nearest, leaving the clipping to be handled by the caller.
+pub fn db_to_steps_clip_safe(db: f64, peak: f64) -> i32 {
+    let nearest = db_to_steps(db);
+    let down = (db / GAIN_STEP_DB).floor() as i32;
+    let clips = |steps: i32| replaygain::would_clip(peak, steps_to_db(steps));
+    if nearest > down && clips(nearest) && !clips(down) {
+        down
+    } else {
+        nearest
+    }
+}
+
 /// Convert MP3 gain steps to dB
 pub fn steps_to_db(steps: i32) -> f64 {
     steps as f64 * GAIN_STEP_DB
 }
 
+/// Exact change in level from shifting every global_gain by `steps`, in dB
+///
+/// This is what `-g steps` does to the decoded audio: every sample is scaled
+/// by `steps` x 1.5 dB, loud and quiet passages alike. It is not a loudness
+/// measurement or normalization; files that differ in loudness still differ
+/// by as much afterwards (ReplayGain analysis, see [`replaygain`], is what
+/// brings them to a common level).
+pub fn global_gain_shift_db(steps: i32) -> f64 {
+    steps_to_db(steps)
+}
+
+/// The gain [`apply_gain_db`] actually applies for `db`: the nearest whole
+/// number of steps, in dB
+pub fn quantized_db(db: f64) -> f64 {
+    steps_to_db(db_to_steps(db))
+}
+
+/// How far the whole steps a dB gain rounds to are from it
+///
+/// Positive when the applied gain is louder than requested: +4.5 dB is
+/// exactly 3 steps, while +4.0 dB also becomes 3 steps = +4.5 dB (+0.5).
+pub fn quantization_error_db(db: f64) -> f64 {
+    quantized_db(db) - db
+}
+
 /// Channel selection for independent gain adjustment
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Channel {
@@ -666,44 +1830,173 @@ impl Channel {
     }
 }
 
+/// Check if data starts with a raw AAC (ADTS) stream
+///
+/// ADTS shares the 0xFFF sync word with MPEG audio but uses layer bits 00,
+/// which is never valid for MP3. The declared frame length is also checked
+/// against the next sync word when enough data is available.
+fn is_adts_data(data: &[u8]) -> bool {
+    if data.len() < 7 || data[0] != 0xFF || (data[1] & 0xF6) != 0xF0 {
+        return false;
+    }
+
+    let frame_length =
+        ((data[3] as usize & 0x03) << 11) | ((data[4] as usize) << 3) | (data[5] as usize >> 5);
+    if frame_length < 7 {
+        return false;
+    }
+
+    if frame_length + 2 <= data.len() {
+        data[frame_length] == 0xFF && (data[frame_length + 1] & 0xF6) == 0xF0
+    } else {
+        true
+    }
+}
+
+/// Fail if the data is FLAC, or the audio after any ID3v2 tag is a raw AAC
+/// (ADTS) stream
+///
+/// Used when a scan finds no MP3 frames, so other formats (ADTS as written by
+/// some rippers is often named `.mp3`) are told apart from a damaged MP3.
+fn check_other_format(data: &[u8]) -> Result<()> {
+    if flac::is_flac_data(data) {
+        anyhow::bail!("FLAC stream, not MP3: no global_gain to adjust");
+    }
+    let audio_end = find_audio_end(data);
+    let (start, _) = find_audio_start(data, audio_end);
+    if is_adts_data(data.get(start..audio_end).unwrap_or_default()) {
+        anyhow::bail!("raw AAC (ADTS) stream, not MP3: no global_gain to adjust");
+    }
+    Ok(())
+}
+
+/// Check if file is a raw AAC (ADTS) stream rather than an MP3
+///
+/// An ID3v2 tag in front of the stream is skipped, as with
+/// [`detect_audio_type`].
+pub fn is_adts_file(file_path: &Path) -> bool {
+    detect_audio_type(file_path) == Some(AudioFileType::AacAdts)
+}
+
+/// File extensions (lowercase, without the dot) of the supported formats
+const SUPPORTED_EXTENSIONS: &[&str] = &["mp3", "m4a", "mp4", "aac", "flac"];
+
+/// File extensions (lowercase, without the dot) handled by mp3rgain
+///
+/// Used to pick files out of directories. The format itself is decided by
+/// [`detect_audio_type`] from the file contents.
+pub fn supported_extensions() -> &'static [&'static str] {
+    SUPPORTED_EXTENSIONS
+}
+
+/// Check if the path has one of the [`supported_extensions`] (any case)
+pub fn has_supported_extension(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        SUPPORTED_EXTENSIONS
+            .iter()
+            .any(|supported| ext.eq_ignore_ascii_case(supported))
+    })
+}
+
+/// Bytes read from the start of the audio data to detect its format
+const SNIFF_BYTES: u64 = 8192;
+
+/// Detect the audio format of a file from its contents
+///
+/// The extension is not consulted, so an M4A file named `.mp3` (or an MP3
+/// named `.m4a`) is still recognized for what it is. An MP4 `ftyp` box means
+/// M4A/AAC and a `fLaC` marker FLAC; otherwise any leading ID3v2 tag is
+/// skipped the same way the frame scanner does and the audio must start with
+/// an ADTS or MPEG Layer III frame.
+///
+/// # Returns
+/// * `None` if the file can't be read or holds none of the supported formats
+pub fn detect_audio_type(file_path: &Path) -> Option<AudioFileType> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = fs::File::open(file_path).ok()?;
+    let mut head = Vec::with_capacity(SNIFF_BYTES as usize);
+    (&mut file).take(SNIFF_BYTES).read_to_end(&mut head).ok()?;
+    if let Some(container) = container_type(&head) {
+        return Some(container);
+    }
+
+    // The ID3v2 tag may be larger than the bytes read so far
+    let (start, _) = find_audio_start(&head, head.len());
+    if start > 0 {
+        file.seek(SeekFrom::Start(start as u64)).ok()?;
+        head.clear();
+        file.take(SNIFF_BYTES).read_to_end(&mut head).ok()?;
+    }
+    frame_type(&head)
+}
+
+/// [`detect_audio_type`] for a whole file in memory
+#[cfg(feature = "replaygain")]
+pub(crate) fn detect_audio_type_data(data: &[u8]) -> Option<AudioFileType> {
+    container_type(data).or_else(|| {
+        let (start, _) = find_audio_start(data, data.len());
+        frame_type(&data[start.min(data.len())..])
+    })
+}
+
+/// M4A/AAC or FLAC, from the start of a file
+fn container_type(head: &[u8]) -> Option<AudioFileType> {
+    if mp4meta::is_mp4_data(head) {
+        Some(AudioFileType::Aac)
+    } else if flac::is_flac_data(head) {
+        Some(AudioFileType::Flac)
+    } else {
+        None
+    }
+}
+
+/// ADTS or MP3, from the start of the audio data
+fn frame_type(audio: &[u8]) -> Option<AudioFileType> {
+    if is_adts_data(audio) {
+        Some(AudioFileType::AacAdts)
+    } else if frame_at(audio, 0, audio.len(), true).is_some() {
+        Some(AudioFileType::Mp3)
+    } else {
+        None
+    }
+}
+
 /// Check if an MP3 file is mono
 pub fn is_mono(file_path: &Path) -> Result<bool> {
     let analysis = analyze(file_path)?;
     Ok(analysis.channel_mode == "Mono")
 }
 
-/// Internal function to apply gain to a specific channel in data
-/// Returns the number of modified frames
-fn apply_gain_to_channel_data(data: &mut [u8], channel: Channel, gain_steps: i32) -> usize {
+/// Internal function to apply separate gains to the left and right channels in data
+fn apply_gain_to_channels_data(data: &mut [u8], left_steps: i32, right_steps: i32) -> GainPass {
+    apply_gain_to_locations_data(data, |_, _, channel| match channel {
+        0 => left_steps,
+        _ => right_steps,
+    })
+}
+
+/// Internal function to apply gain per (granule, channel) location in data
+///
+/// `steps_for` is called with each location's frame index (counting the
+/// frames gain applies to, as [`frame_gains`] lists them), granule and
+/// channel index.
+fn apply_gain_to_locations_data(
+    data: &mut [u8],
+    steps_for: impl Fn(usize, usize, usize) -> i32,
+) -> GainPass {
     let audio_end = find_audio_end(data);
-    let mut pos = skip_id3v2(data);
-    let mut modified_frames = 0;
-    let target_channel = channel.index();
+    let (mut pos, _) = find_audio_start(data, audio_end);
+    let mut pass = GainPass::default();
 
-    while pos + 4 <= audio_end {
-        let header = match parse_header(&data[pos..]) {
-            Some(h) => h,
-            None => {
-                pos += 1;
-                continue;
-            }
-        };
+    let mut synced = false;
 
+    while let Some((frame_pos, header)) = find_next_frame(data, pos, audio_end, synced) {
+        pass.bad_frames += usize::from(synced && frame_pos > pos);
+        synced = true;
+        pos = frame_pos;
         let next_pos = pos + header.frame_size;
 
-        // Validate frame: either next frame starts with sync word,
-        // or this frame ends at/near the audio data boundary
-        let valid_frame = if next_pos + 2 <= audio_end {
-            data[next_pos] == 0xFF && (data[next_pos + 1] & 0xE0) == 0xE0
-        } else {
-            next_pos <= audio_end
-        };
-
-        if !valid_frame {
-            pos += 1;
-            continue;
-        }
-
         // Skip Xing/Info header frames (VBR metadata)
         if is_xing_frame(data, pos, &header) {
             pos = next_pos;
@@ -712,25 +2005,32 @@ fn apply_gain_to_channel_data(data: &mut [u8], channel: Channel, gain_steps: i32
 
         let locations = calculate_gain_locations(pos, &header);
         let num_channels = header.channel_mode.channel_count();
-        let num_granules = header.granule_count();
 
-        // Apply gain only to the target channel
         // Locations are ordered: [gr0_ch0, gr0_ch1, gr1_ch0, gr1_ch1] for stereo MPEG1
-        for gr in 0..num_granules {
-            let loc_index = gr * num_channels + target_channel;
-            if loc_index < locations.len() {
-                let loc = &locations[loc_index];
+        let mut saturated = [false; 2];
+        for (index, loc) in locations.iter().enumerate() {
+            let channel = index % num_channels;
+            let steps = steps_for(pass.frames, index / num_channels, channel);
+            if steps != 0 {
                 let current_gain = read_gain_at(data, loc);
-                let new_gain = adjust_gain_value(current_gain, gain_steps, GainMode::Saturating);
+                let new_gain = adjust_gain_value(current_gain, steps, GainMode::Saturating);
                 write_gain_at(data, loc, new_gain);
+                if i32::from(new_gain) - i32::from(current_gain) != steps {
+                    saturated[channel] = true;
+                    pass.clipped |= steps > 0;
+                }
             }
         }
 
-        modified_frames += 1;
+        for (count, hit) in pass.saturated.iter_mut().zip(saturated) {
+            *count += usize::from(hit);
+        }
+        pass.frames += 1;
+        pass.gain_locations += locations.len();
         pos = next_pos;
     }
 
-    modified_frames
+    pass
 }
 
 /// Apply gain adjustment to a specific channel only (lossless)
@@ -745,26 +2045,278 @@ fn apply_gain_to_channel_data(data: &mut [u8], channel: Channel, gain_steps: i32
 ///
 /// # Errors
 /// * Returns error if file is mono (no separate channels)
+///
+/// On joint stereo files, frames coded as mid/side or intensity stereo store
+/// mid and side (or a shared spectrum) rather than left and right, so the
+/// gain doesn't cleanly apply to one speaker.
 pub fn apply_gain_channel(file_path: &Path, channel: Channel, gain_steps: i32) -> Result<usize> {
-    if gain_steps == 0 {
+    match channel {
+        Channel::Left => apply_gain_channels(file_path, gain_steps, 0),
+        Channel::Right => apply_gain_channels(file_path, 0, gain_steps),
+    }
+}
+
+/// Apply separate gain adjustments to the left and right channels in one pass (lossless)
+///
+/// # Arguments
+/// * `file_path` - Path to MP3 file
+/// * `left_steps` - Number of 1.5dB steps to apply to the left channel
+/// * `right_steps` - Number of 1.5dB steps to apply to the right channel
+///
+/// # Returns
+/// * Number of frames modified
+///
+/// # Errors
+/// * Returns error if file is mono (no separate channels)
+pub fn apply_gain_channels(file_path: &Path, left_steps: i32, right_steps: i32) -> Result<usize> {
+    if left_steps == 0 && right_steps == 0 {
         return Ok(0);
     }
 
     // Check if file is mono
-    let analysis = analyze(file_path)?;
+    let analysis = analyze_audio(file_path)?;
     if analysis.channel_mode == "Mono" {
         anyhow::bail!("Cannot apply channel-specific gain to mono file. Use -g for mono files.");
     }
 
-    let mut data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    let pass = apply_pass_to_file(file_path, |data| {
+        Ok(apply_gain_to_channels_data(data, left_steps, right_steps))
+    })?;
 
-    let modified_frames = apply_gain_to_channel_data(&mut data, channel, gain_steps);
+    Ok(pass.frames)
+}
 
-    fs::write(file_path, &data)
-        .with_context(|| format!("Failed to write: {}", file_path.display()))?;
+/// Apply a gain adjustment to one granule of every frame (lossless)
+///
+/// MPEG1 frames hold two granules (0 and 1); MPEG2 and MPEG2.5 frames hold
+/// only granule 0. With `channel` set, only that channel's global_gain in the
+/// granule changes; otherwise every channel's does.
+///
+/// # Arguments
+/// * `file_path` - Path to MP3 file
+/// * `granule` - Granule index within each frame
+/// * `channel` - Channel to adjust, or `None` for all channels
+/// * `gain_steps` - Number of 1.5dB steps to apply (positive = louder)
+///
+/// # Returns
+/// * Number of frames modified
+///
+/// # Errors
+/// * Returns error if the granule doesn't exist in the file's MPEG version,
+///   or a channel is given for a mono file
+pub fn apply_gain_granule(
+    file_path: &Path,
+    granule: usize,
+    channel: Option<Channel>,
+    gain_steps: i32,
+) -> Result<usize> {
+    let analysis = analyze_audio(file_path)?;
+    let granules = if analysis.mpeg_version == "MPEG1" {
+        2
+    } else {
+        1
+    };
+    if granule >= granules {
+        anyhow::bail!(
+            "Granule {} out of range: {} frames have {} granule{}",
+            granule,
+            analysis.mpeg_version,
+            granules,
+            if granules == 1 { "" } else { "s" }
+        );
+    }
+    if channel.is_some() && analysis.channel_mode == "Mono" {
+        anyhow::bail!("Cannot apply channel-specific gain to mono file. Use -g for mono files.");
+    }
+    if gain_steps == 0 {
+        return Ok(0);
+    }
+
+    let channel = channel.map(|c| c.index());
+    let pass = apply_pass_to_file(file_path, |data| {
+        Ok(apply_gain_to_locations_data(data, |_, gr, ch| {
+            if gr == granule && channel.is_none_or(|c| c == ch) {
+                gain_steps
+            } else {
+                0
+            }
+        }))
+    })?;
 
-    Ok(modified_frames)
+    Ok(pass.frames)
+}
+
+/// Apply a gain that changes linearly across a range of frames (lossless)
+///
+/// Frame `start_frame` gets `start_steps`, frame `end_frame` gets
+/// `end_steps` and the frames between get the steps on the straight line
+/// between them, rounded to the nearest whole step; frames outside the
+/// range are left alone. Frames are numbered from 0 as [`frame_gains`] lists
+/// them (Xing/Info frames not counted). At 1.5 dB per step a ramp is audibly
+/// stepped unless it is long compared to its height.
+///
+/// A ramp can't be expressed in MP3GAIN_UNDO, so no undo information is
+/// stored; applying the opposite ramp reverses it unless global_gain hit
+/// 0 or 255.
+///
+/// # Returns
+/// * Number of frames in the range
+///
+/// # Errors
+/// * Returns error if `start_frame` is after `end_frame`, or `end_frame` is
+///   past the last frame
+pub fn apply_gain_ramp(
+    file_path: &Path,
+    start_frame: usize,
+    end_frame: usize,
+    start_steps: i32,
+    end_steps: i32,
+) -> Result<usize> {
+    if start_frame > end_frame {
+        anyhow::bail!(
+            "Ramp starts at frame {} after it ends at frame {}",
+            start_frame,
+            end_frame
+        );
+    }
+    let analysis = analyze_audio(file_path)?;
+    if end_frame >= analysis.frame_count {
+        anyhow::bail!(
+            "Ramp end frame {} out of range: the file has {} frames",
+            end_frame,
+            analysis.frame_count
+        );
+    }
+    if start_steps == 0 && end_steps == 0 {
+        return Ok(0);
+    }
+
+    apply_pass_to_file(file_path, |data| {
+        Ok(apply_gain_to_locations_data(data, |frame, _, _| {
+            ramp_steps(frame, start_frame, end_frame, start_steps, end_steps)
+        }))
+    })?;
+
+    Ok(end_frame - start_frame + 1)
+}
+
+/// Steps a ramp from `start_steps` at `start_frame` to `end_steps` at
+/// `end_frame` applies to `frame`; 0 outside the range
+fn ramp_steps(
+    frame: usize,
+    start_frame: usize,
+    end_frame: usize,
+    start_steps: i32,
+    end_steps: i32,
+) -> i32 {
+    if frame < start_frame || frame > end_frame {
+        return 0;
+    }
+    if end_frame == start_frame {
+        return start_steps;
+    }
+    let position = (frame - start_frame) as f64 / (end_frame - start_frame) as f64;
+    start_steps + (f64::from(end_steps - start_steps) * position).round() as i32
+}
+
+/// Outcome of [`apply_gain_checked`]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ApplyReport {
+    /// Number of frames modified
+    pub frames: usize,
+    /// Frames in which a left-channel (or mono) global_gain stopped at 0 or 255
+    /// short of the requested adjustment
+    pub left_saturated: usize,
+    /// Frames in which a right-channel global_gain stopped at 0 or 255
+    pub right_saturated: usize,
+    /// Whether positive gain pushed any global_gain against the 255 ceiling
+    pub clipped: bool,
+    /// Corrupt stretches between frames that were skipped; gain was still
+    /// applied to every valid frame around them
+    pub bad_frames: usize,
+    /// Requested (left, right) steps
+    pub steps_applied: (i32, i32),
+}
+
+/// Apply left/right gains in one pass and report how they met the 0-255 limits (lossless)
+///
+/// Like [`apply_gain_channels`], except that equal steps are also accepted
+/// for mono files, where the left steps apply to the single channel.
+///
+/// # Errors
+/// * Returns error if the steps differ and the file is mono
+pub fn apply_gain_checked(
+    file_path: &Path,
+    left_steps: i32,
+    right_steps: i32,
+) -> Result<ApplyReport> {
+    let mut report = ApplyReport {
+        steps_applied: (left_steps, right_steps),
+        ..Default::default()
+    };
+    if left_steps == 0 && right_steps == 0 {
+        return Ok(report);
+    }
+
+    if left_steps != right_steps && analyze_audio(file_path)?.channel_mode == "Mono" {
+        anyhow::bail!("Cannot apply channel-specific gain to mono file. Use -g for mono files.");
+    }
+
+    let pass = apply_pass_to_file(file_path, |data| {
+        let pass = apply_gain_to_channels_data(data, left_steps, right_steps);
+        if pass.frames == 0 {
+            check_other_format(data)?;
+        }
+        Ok(pass)
+    })?;
+
+    report.frames = pass.frames;
+    report.left_saturated = pass.saturated[0];
+    report.right_saturated = pass.saturated[1];
+    report.clipped = pass.clipped;
+    report.bad_frames = pass.bad_frames;
+    Ok(report)
+}
+
+/// Outcome of [`apply_gain_safe`]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SafeApplyReport {
+    /// Number of frames modified
+    pub frames: usize,
+    /// Byte offsets of the frames that applying the opposite gain would not
+    /// restore, because a global_gain stopped at 0 or 255
+    pub irreversible_frames: Vec<usize>,
+}
+
+impl SafeApplyReport {
+    /// Whether undoing the gain restores every frame exactly
+    pub fn is_reversible(&self) -> bool {
+        self.irreversible_frames.is_empty()
+    }
+}
+
+/// Apply gain, then check that undoing it would restore the file exactly (lossless)
+///
+/// After [`apply_gain`], the written frames are read back and the opposite
+/// gain is projected onto them; frames that wouldn't come back to their
+/// original global_gain values are listed in the report. The gain stays
+/// applied either way, so the caller decides whether to undo it.
+pub fn apply_gain_safe(file_path: &Path, gain_steps: i32) -> Result<SafeApplyReport> {
+    let before = frame_gains(file_path, 0, false)?;
+    let frames = apply_gain(file_path, gain_steps)?;
+    let undone = frame_gains(file_path, -gain_steps, false)?;
+
+    let irreversible_frames = before
+        .iter()
+        .zip(&undone)
+        .filter(|(before, undone)| before.current != undone.projected)
+        .map(|(before, _)| before.offset)
+        .collect();
+
+    Ok(SafeApplyReport {
+        frames,
+        irreversible_frames,
+    })
 }
 
 /// Apply channel-specific gain and store undo information in APEv2 tag
@@ -773,63 +2325,255 @@ pub fn apply_gain_channel_with_undo(
     channel: Channel,
     gain_steps: i32,
 ) -> Result<usize> {
-    if gain_steps == 0 {
+    match channel {
+        Channel::Left => apply_gain_channels_with_undo(file_path, gain_steps, 0),
+        Channel::Right => apply_gain_channels_with_undo(file_path, 0, gain_steps),
+    }
+}
+
+/// Apply separate left/right gains in one pass and store undo information in APEv2 tag
+///
+/// Both adjustments are recorded in the left and right fields of `MP3GAIN_UNDO`.
+pub fn apply_gain_channels_with_undo(
+    file_path: &Path,
+    left_steps: i32,
+    right_steps: i32,
+) -> Result<usize> {
+    if left_steps == 0 && right_steps == 0 {
         return Ok(0);
     }
 
     // Check if file is mono before doing anything
-    let analysis = analyze(file_path)?;
+    let analysis = analyze_audio(file_path)?;
     if analysis.channel_mode == "Mono" {
         anyhow::bail!("Cannot apply channel-specific gain to mono file. Use -g for mono files.");
     }
 
-    // Read existing APE tag or create new one
-    let mut tag = read_ape_tag_from_file(file_path)?.unwrap_or_else(ApeTag::new);
+    let tag = channel_undo_tag(file_path, &analysis, left_steps, right_steps)?;
 
-    // Get existing undo values (left, right)
-    let (existing_left, existing_right) = parse_undo_values(tag.get(TAG_MP3GAIN_UNDO));
+    // Apply the gain
+    let pass = apply_pass_to_file(file_path, |data| {
+        let pass = apply_gain_to_channels_data(data, left_steps, right_steps);
+        check_gain_locations(analysis.gain_locations, pass.gain_locations)?;
+        Ok(pass)
+    })?;
 
-    // Update the appropriate channel
-    let (new_left, new_right) = match channel {
-        Channel::Left => (existing_left + gain_steps, existing_right),
-        Channel::Right => (existing_left, existing_right + gain_steps),
-    };
+    // Write APE tag
+    write_ape_tag(file_path, &tag)?;
 
-    tag.set_undo_gain(new_left, new_right, false);
+    Ok(pass.frames)
+}
+
+/// The undo tag for separate left/right adjustments
+///
+/// `analysis` is the file before the adjustment, recorded as MP3GAIN_MINMAX
+/// unless a baseline is already stored.
+fn channel_undo_tag(
+    file_path: &Path,
+    analysis: &Mp3Analysis,
+    left_steps: i32,
+    right_steps: i32,
+) -> Result<ApeTag> {
+    // Read existing tags (APEv2 or ID3v2) or create new one
+    let mut tag = read_gain_tag(file_path)?.unwrap_or_else(ApeTag::new);
+
+    // Add this adjustment to the existing undo values (left, right)
+    tag.push_undo(left_steps, right_steps, false);
+    tag.remove(TAG_MP3GAIN_REDO);
 
     // Store original min/max if not already stored
     if tag.get(TAG_MP3GAIN_MINMAX).is_none() {
         tag.set_minmax(analysis.min_gain, analysis.max_gain);
     }
 
-    // Apply the gain
-    let frames = apply_gain_channel(file_path, channel, gain_steps)?;
+    Ok(tag)
+}
 
-    // Write APE tag
-    write_ape_tag(file_path, &tag)?;
+/// How [`GainOp`] treats positive gain beyond a file's headroom
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ClipPolicy {
+    /// Apply the requested gain; global_gain stops at 255 (or wraps)
+    #[default]
+    Allow,
+    /// Limit positive gain to the file's headroom, like `-k`
+    Prevent,
+}
 
-    Ok(frames)
+/// Builder for a gain adjustment, combining the `apply_gain*` variants
+///
+/// ```no_run
+/// use mp3rgain::{Channel, ClipPolicy, GainOp};
+/// use std::path::Path;
+///
+/// let report = GainOp::new()
+///     .steps(2)
+///     .channel(Channel::Left)
+///     .with_undo(true)
+///     .clip_policy(ClipPolicy::Prevent)
+///     .apply(Path::new("song.mp3"))?;
+/// println!("{} frames", report.frames);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// The simple functions such as [`apply_gain`] and [`apply_gain_with_undo`]
+/// remain as shortcuts for the common cases.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GainOp {
+    steps: i32,
+    wrap: bool,
+    channel: Option<Channel>,
+    with_undo: bool,
+    clip_policy: ClipPolicy,
 }
 
-/// Parse MP3GAIN_UNDO tag value into (left_gain, right_gain)
-fn parse_undo_values(undo_str: Option<&str>) -> (i32, i32) {
-    match undo_str {
-        Some(v) => {
-            let parts: Vec<&str> = v.split(',').collect();
-            let left = parts
-                .first()
-                .and_then(|s| s.trim().parse::<i32>().ok())
-                .unwrap_or(0);
-            let right = parts
-                .get(1)
-                .and_then(|s| s.trim().parse::<i32>().ok())
-                .unwrap_or(left);
-            (left, right)
+impl GainOp {
+    /// A gain of 0 steps on both channels, saturating, without undo information
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of 1.5dB steps to apply (positive = louder)
+    pub fn steps(mut self, steps: i32) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// Gain in dB, rounded to the nearest step
+    pub fn db(self, gain_db: f64) -> Self {
+        self.steps(db_to_steps(gain_db))
+    }
+
+    /// Wrap global_gain around 0-255 instead of clamping
+    pub fn wrap(mut self, wrap: bool) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Adjust only this channel (stereo files only)
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Record the adjustment in MP3GAIN_UNDO (and MP3GAIN_MINMAX) for [`undo_gain`]
+    pub fn with_undo(mut self, with_undo: bool) -> Self {
+        self.with_undo = with_undo;
+        self
+    }
+
+    /// How to treat positive gain beyond the file's headroom
+    pub fn clip_policy(mut self, policy: ClipPolicy) -> Self {
+        self.clip_policy = policy;
+        self
+    }
+
+    /// Apply the adjustment to an MP3 file (lossless)
+    ///
+    /// `steps_applied` in the report holds the (left, right) steps after
+    /// [`ClipPolicy::Prevent`] limited them. Saturation is not counted for
+    /// wrapping gain, which never saturates.
+    ///
+    /// # Errors
+    /// * Returns error if a channel is selected and the file is mono, or
+    ///   if a channel is combined with wrapping
+    pub fn apply(&self, file_path: &Path) -> Result<ApplyReport> {
+        if self.wrap && self.channel.is_some() {
+            anyhow::bail!("Wrapping gain cannot be applied to a single channel");
+        }
+
+        let mut steps = self.steps;
+        if self.clip_policy == ClipPolicy::Prevent && steps > 0 {
+            steps = steps.min(analyze_audio(file_path)?.headroom_steps);
+        }
+        let (left, right) = match self.channel {
+            None => (steps, steps),
+            Some(Channel::Left) => (steps, 0),
+            Some(Channel::Right) => (0, steps),
+        };
+        if steps == 0 {
+            return Ok(ApplyReport {
+                steps_applied: (left, right),
+                ..Default::default()
+            });
         }
-        None => (0, 0),
+
+        let undo_tag = match (self.with_undo, self.channel) {
+            (false, _) => None,
+            (true, None) => Some(undo_tag_for(file_path, steps, self.wrap)?.0),
+            (true, Some(_)) => {
+                let analysis = analyze_audio(file_path)?;
+                if analysis.channel_mode == "Mono" {
+                    anyhow::bail!(
+                        "Cannot apply channel-specific gain to mono file. Use -g for mono files."
+                    );
+                }
+                Some(channel_undo_tag(file_path, &analysis, left, right)?)
+            }
+        };
+
+        let report = if self.wrap {
+            ApplyReport {
+                frames: apply_gain_mode(file_path, steps, GainMode::Wrapping)?,
+                steps_applied: (left, right),
+                ..Default::default()
+            }
+        } else {
+            apply_gain_checked(file_path, left, right)?
+        };
+
+        if let Some(tag) = undo_tag {
+            write_ape_tag(file_path, &tag)?;
+        }
+
+        Ok(report)
     }
 }
 
+/// Parse an MP3GAIN_UNDO (or MP3GAIN_REDO) value into (left, right, wrap)
+///
+/// mp3gain writes `+002,+002,N`. Other taggers drop the sign or the zero
+/// padding, pad fields with spaces or NULs, leave out the right channel or
+/// the wrap flag, or write the flag in lowercase. A missing right channel is
+/// taken to be the same as the left one. Returns `None` if the left channel
+/// isn't a number.
+fn parse_undo_value(value: &str) -> Option<(i32, i32, bool)> {
+    let mut fields = value.split(',').map(|field| {
+        field
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '\0')
+            .collect::<String>()
+    });
+    let left = fields.next()?.parse::<i32>().ok()?;
+    let right = fields
+        .next()
+        .and_then(|field| field.parse::<i32>().ok())
+        .unwrap_or(left);
+    let wrap = fields
+        .next()
+        .is_some_and(|flag| flag.eq_ignore_ascii_case("W"));
+    Some((left, right, wrap))
+}
+
+/// Net (left, right) gain of an undo stack
+fn undo_stack_sum(stack: &[(i32, i32, bool)]) -> (i32, i32) {
+    stack.iter().fold((0, 0), |(left, right), entry| {
+        (left + entry.0, right + entry.1)
+    })
+}
+
+/// Format an MP3GAIN_UNDO value the way mp3gain does (`+002,+002,N`)
+/// Parse a `min,max` global_gain pair as in MP3GAIN_MINMAX, allowing
+/// spaces around the numbers
+fn parse_minmax_value(value: &str) -> Option<(u8, u8)> {
+    let (min, max) = value.split_once(',')?;
+    Some((min.trim().parse().ok()?, max.trim().parse().ok()?))
+}
+
+fn format_undo_value(left: i32, right: i32, wrap: bool) -> String {
+    let wrap_flag = if wrap { "W" } else { "N" };
+    format!("{:+04},{:+04},{}", left, right, wrap_flag)
+}
+
 // =============================================================================
 // APEv2 Tag Support
 // =============================================================================
@@ -844,22 +2588,81 @@ const APE_VERSION: u32 = 2000;
 const APE_FLAG_HEADER_PRESENT: u32 = 1 << 31;
 const APE_FLAG_IS_HEADER: u32 = 1 << 29;
 
-/// MP3Gain specific tag keys
-pub const TAG_MP3GAIN_UNDO: &str = "MP3GAIN_UNDO";
-pub const TAG_MP3GAIN_MINMAX: &str = "MP3GAIN_MINMAX";
-pub const TAG_MP3GAIN_ALBUM_MINMAX: &str = "MP3GAIN_ALBUM_MINMAX";
-
-/// ReplayGain tag keys
-pub const TAG_REPLAYGAIN_TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
-pub const TAG_REPLAYGAIN_TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
-pub const TAG_REPLAYGAIN_ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
-pub const TAG_REPLAYGAIN_ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
+/// APEv2 item flags: bit 0 marks the item read-only, bits 1-2 hold its
+/// value type
+const APE_ITEM_READ_ONLY: u32 = 1;
+const APE_ITEM_TYPE_MASK: u32 = 0b110;
+
+/// Smallest APEv2 item: value size and flags, a one-byte key and its
+/// terminator
+const APE_MIN_ITEM_SIZE: usize = 8 + 2;
+
+/// MP3Gain specific tag keys (see [`tag_keys`])
+pub use tag_keys::{
+    MP3GAIN_ALBUM_MINMAX as TAG_MP3GAIN_ALBUM_MINMAX, MP3GAIN_MINMAX as TAG_MP3GAIN_MINMAX,
+    MP3GAIN_REDO as TAG_MP3GAIN_REDO, MP3GAIN_UNDO as TAG_MP3GAIN_UNDO,
+    MP3GAIN_UNDO_STACK as TAG_MP3GAIN_UNDO_STACK,
+};
+
+/// ReplayGain tag keys (see [`tag_keys`])
+pub use tag_keys::{
+    REPLAYGAIN_ALBUM_GAIN as TAG_REPLAYGAIN_ALBUM_GAIN,
+    REPLAYGAIN_ALBUM_PEAK as TAG_REPLAYGAIN_ALBUM_PEAK,
+    REPLAYGAIN_REFERENCE_LOUDNESS as TAG_REPLAYGAIN_REFERENCE_LOUDNESS,
+    REPLAYGAIN_TRACK_GAIN as TAG_REPLAYGAIN_TRACK_GAIN,
+    REPLAYGAIN_TRACK_PEAK as TAG_REPLAYGAIN_TRACK_PEAK,
+};
 
 /// APEv2 tag item
 #[derive(Debug, Clone)]
 pub struct ApeItem {
     pub key: String,
+    /// Value decoded as UTF-8 (invalid sequences replaced); empty for
+    /// binary items
     pub value: String,
+    /// Value bytes exactly as stored, written back unchanged
+    pub raw: Vec<u8>,
+    /// Item flags as stored (item type, read-only bit)
+    pub flags: u32,
+}
+
+/// Value type of an APEv2 item, from bits 1-2 of its flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApeItemType {
+    /// UTF-8 text
+    Text,
+    /// Binary data, such as cover art
+    Binary,
+    /// UTF-8 locator of external information (a URL)
+    External,
+    /// Type 3, reserved by the APEv2 specification
+    Reserved,
+}
+
+impl ApeItem {
+    /// Value type from the item flags
+    pub fn item_type(&self) -> ApeItemType {
+        match (self.flags & APE_ITEM_TYPE_MASK) >> 1 {
+            0 => ApeItemType::Text,
+            1 => ApeItemType::Binary,
+            2 => ApeItemType::External,
+            _ => ApeItemType::Reserved,
+        }
+    }
+
+    /// Whether the item is marked read-only
+    ///
+    /// The flag is kept when the tag is rewritten; it doesn't stop
+    /// [`ApeTag::set`] from replacing the item.
+    pub fn is_read_only(&self) -> bool {
+        self.flags & APE_ITEM_READ_ONLY != 0
+    }
+
+    /// Whether the value is text ([`ApeItemType::Text`] or
+    /// [`ApeItemType::External`])
+    pub fn is_text(&self) -> bool {
+        matches!(self.item_type(), ApeItemType::Text | ApeItemType::External)
+    }
 }
 
 /// APEv2 tag collection
@@ -875,15 +2678,20 @@ impl ApeTag {
     }
 
     /// Get a tag value by key (case-insensitive)
+    ///
+    /// Binary items have no text value, so their keys give `None`.
     pub fn get(&self, key: &str) -> Option<&str> {
         let key_upper = key.to_uppercase();
         self.items
             .iter()
             .find(|item| item.key.to_uppercase() == key_upper)
+            .filter(|item| item.is_text())
             .map(|item| item.value.as_str())
     }
 
     /// Set a tag value (replaces existing if present)
+    ///
+    /// The item becomes a writable UTF-8 text item, whatever its flags were.
     pub fn set(&mut self, key: &str, value: &str) {
         let key_upper = key.to_uppercase();
         if let Some(item) = self
@@ -892,10 +2700,14 @@ impl ApeTag {
             .find(|item| item.key.to_uppercase() == key_upper)
         {
             item.value = value.to_string();
+            item.raw = value.as_bytes().to_vec();
+            item.flags = 0;
         } else {
             self.items.push(ApeItem {
                 key: key_upper,
                 value: value.to_string(),
+                raw: value.as_bytes().to_vec(),
+                flags: 0,
             });
         }
     }
@@ -907,30 +2719,158 @@ impl ApeTag {
             .retain(|item| item.key.to_uppercase() != key_upper);
     }
 
-    /// Check if tag is empty
-    pub fn is_empty(&self) -> bool {
-        self.items.is_empty()
+    /// Check if tag is empty
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// All items in tag order
+    pub fn items(&self) -> &[ApeItem] {
+        &self.items
+    }
+
+    /// Compute the per-key changes needed to turn this tag into `new`
+    pub fn diff(&self, new: &ApeTag) -> Vec<TagChange> {
+        let pairs = |tag: &ApeTag| -> Vec<(String, String)> {
+            tag.items
+                .iter()
+                .map(|item| {
+                    let value = if item.is_text() {
+                        item.value.clone()
+                    } else {
+                        format!("<binary, {} bytes>", item.raw.len())
+                    };
+                    (item.key.clone(), value)
+                })
+                .collect()
+        };
+        diff_tag_items(&pairs(self), &pairs(new))
+    }
+
+    /// Get MP3GAIN_UNDO value as gain steps (the left channel's adjustment)
+    pub fn get_undo_gain(&self) -> Option<i32> {
+        self.get_undo_gains().map(|(left, _)| left)
+    }
+
+    /// Get MP3GAIN_UNDO value as (left, right) gain steps
+    ///
+    /// Besides mp3gain's own `+002,+002,N`, variants written by other
+    /// taggers (`2,2,N`, ` +2 , +2 `, `+002`) are understood.
+    pub fn get_undo_gains(&self) -> Option<(i32, i32)> {
+        let (left, right, _) = parse_undo_value(self.get(TAG_MP3GAIN_UNDO)?)?;
+        Some((left, right))
+    }
+
+    /// Set MP3GAIN_UNDO value
+    pub fn set_undo_gain(&mut self, left_gain: i32, right_gain: i32, wrap: bool) {
+        self.set(
+            TAG_MP3GAIN_UNDO,
+            &format_undo_value(left_gain, right_gain, wrap),
+        );
+    }
+
+    /// The adjustments that add up to MP3GAIN_UNDO, oldest first
+    ///
+    /// Each is `(left, right, wrap)` as in MP3GAIN_UNDO. Tags without
+    /// MP3GAIN_UNDO_STACK (from mp3gain or older versions of mp3rgain) hold
+    /// a single adjustment, the net gain. Gain that another tool added to
+    /// MP3GAIN_UNDO without updating the stack becomes one more adjustment.
+    pub fn get_undo_stack(&self) -> Vec<(i32, i32, bool)> {
+        let Some((net_left, net_right, net_wrap)) =
+            self.get(TAG_MP3GAIN_UNDO).and_then(parse_undo_value)
+        else {
+            return Vec::new();
+        };
+        let mut stack: Vec<(i32, i32, bool)> = self
+            .get(TAG_MP3GAIN_UNDO_STACK)
+            .map(|v| v.split(';').filter_map(parse_undo_value).collect())
+            .unwrap_or_default();
+        let (left, right) = undo_stack_sum(&stack);
+        if (left, right) != (net_left, net_right) {
+            stack.push((net_left - left, net_right - right, net_wrap));
+        }
+        stack
+    }
+
+    /// Record one more adjustment in MP3GAIN_UNDO and MP3GAIN_UNDO_STACK
+    pub fn push_undo(&mut self, left_gain: i32, right_gain: i32, wrap: bool) {
+        let mut stack = self.get_undo_stack();
+        stack.push((left_gain, right_gain, wrap));
+        self.set_undo_stack(&stack);
+    }
+
+    /// Remove the last adjustment from MP3GAIN_UNDO and MP3GAIN_UNDO_STACK
+    ///
+    /// Removing the only adjustment removes both items.
+    pub fn pop_undo(&mut self) -> Option<(i32, i32, bool)> {
+        let mut stack = self.get_undo_stack();
+        let last = stack.pop()?;
+        self.set_undo_stack(&stack);
+        Some(last)
     }
 
-    /// Get MP3GAIN_UNDO value as gain steps
-    pub fn get_undo_gain(&self) -> Option<i32> {
-        self.get(TAG_MP3GAIN_UNDO).and_then(|v| {
-            // Format: "+002,+002,N" or similar
-            // First field is the left channel adjustment, second is right
-            let parts: Vec<&str> = v.split(',').collect();
-            if !parts.is_empty() {
-                parts[0].trim().parse::<i32>().ok()
-            } else {
-                None
+    /// Store `stack` as MP3GAIN_UNDO_STACK and its sum as MP3GAIN_UNDO
+    ///
+    /// The net gain takes the wrap flag of the last adjustment. A single
+    /// adjustment needs no stack, which keeps the tag as mp3gain writes it.
+    fn set_undo_stack(&mut self, stack: &[(i32, i32, bool)]) {
+        let Some(&(_, _, wrap)) = stack.last() else {
+            self.remove(TAG_MP3GAIN_UNDO);
+            self.remove(TAG_MP3GAIN_UNDO_STACK);
+            return;
+        };
+        let (left, right) = undo_stack_sum(stack);
+        self.set_undo_gain(left, right, wrap);
+        if stack.len() == 1 {
+            self.remove(TAG_MP3GAIN_UNDO_STACK);
+            return;
+        }
+        let entries: Vec<String> = stack
+            .iter()
+            .map(|&(left, right, wrap)| format_undo_value(left, right, wrap))
+            .collect();
+        self.set(TAG_MP3GAIN_UNDO_STACK, &entries.join(";"));
+    }
+
+    /// Rewrite MP3GAIN_UNDO and MP3GAIN_REDO in mp3gain's `+002,+002,N`
+    /// format, and MP3GAIN_MINMAX and MP3GAIN_ALBUM_MINMAX as `min,max`
+    ///
+    /// Only the formatting changes, never the values. Min/max values with or
+    /// without mp3gain's zero padding (`084,210`) are both canonical. Values
+    /// that can't be parsed are left alone. Returns `true` if any value
+    /// changed.
+    pub fn normalize_undo(&mut self) -> bool {
+        let mut changed = false;
+        for key in [TAG_MP3GAIN_UNDO, TAG_MP3GAIN_REDO] {
+            let Some(value) = self.get(key) else {
+                continue;
+            };
+            if let Some((left, right, wrap)) = parse_undo_value(value) {
+                let canonical = format_undo_value(left, right, wrap);
+                if canonical != value {
+                    self.set(key, &canonical);
+                    changed = true;
+                }
             }
-        })
+        }
+        for key in [TAG_MP3GAIN_MINMAX, TAG_MP3GAIN_ALBUM_MINMAX] {
+            let Some(value) = self.get(key) else {
+                continue;
+            };
+            if let Some((min, max)) = parse_minmax_value(value) {
+                if value != format!("{},{}", min, max) && value != format!("{:03},{:03}", min, max)
+                {
+                    self.set(key, &format!("{},{}", min, max));
+                    changed = true;
+                }
+            }
+        }
+        changed
     }
 
-    /// Set MP3GAIN_UNDO value
-    pub fn set_undo_gain(&mut self, left_gain: i32, right_gain: i32, wrap: bool) {
-        let wrap_flag = if wrap { "W" } else { "N" };
-        let value = format!("{:+04},{:+04},{}", left_gain, right_gain, wrap_flag);
-        self.set(TAG_MP3GAIN_UNDO, &value);
+    /// Get MP3GAIN_MINMAX value as (min, max) global_gain
+    pub fn get_minmax(&self) -> Option<(u8, u8)> {
+        parse_minmax_value(self.get(TAG_MP3GAIN_MINMAX)?)
     }
 
     /// Set MP3GAIN_MINMAX value
@@ -938,6 +2878,116 @@ impl ApeTag {
         let value = format!("{},{}", min, max);
         self.set(TAG_MP3GAIN_MINMAX, &value);
     }
+
+    /// REPLAYGAIN_TRACK_GAIN in dB, if present and it parses (e.g. "-6.50 dB")
+    pub fn replaygain_track_gain_db(&self) -> Option<f64> {
+        parse_db(self.get(TAG_REPLAYGAIN_TRACK_GAIN)?)
+    }
+
+    /// REPLAYGAIN_ALBUM_GAIN in dB, if present and it parses
+    pub fn replaygain_album_gain_db(&self) -> Option<f64> {
+        parse_db(self.get(TAG_REPLAYGAIN_ALBUM_GAIN)?)
+    }
+
+    /// Set REPLAYGAIN_TRACK_GAIN/PEAK along with the reference loudness
+    pub fn set_replaygain_track(&mut self, gain_db: f64, peak: f64) {
+        self.set(TAG_REPLAYGAIN_TRACK_GAIN, &format!("{:+.2} dB", gain_db));
+        self.set(TAG_REPLAYGAIN_TRACK_PEAK, &format!("{:.6}", peak));
+        self.set_reference_loudness(replaygain::REPLAYGAIN_REFERENCE_DB);
+    }
+
+    /// Set REPLAYGAIN_ALBUM_GAIN/PEAK
+    pub fn set_replaygain_album(&mut self, gain_db: f64, peak: f64) {
+        self.set(TAG_REPLAYGAIN_ALBUM_GAIN, &format!("{:+.2} dB", gain_db));
+        self.set(TAG_REPLAYGAIN_ALBUM_PEAK, &format!("{:.6}", peak));
+    }
+
+    /// Set REPLAYGAIN_REFERENCE_LOUDNESS (e.g. "89.0 dB")
+    pub fn set_reference_loudness(&mut self, reference_db: f64) {
+        self.set(
+            TAG_REPLAYGAIN_REFERENCE_LOUDNESS,
+            &format!("{:.1} dB", reference_db),
+        );
+    }
+}
+
+/// A dB value as stored in ReplayGain tags ("+3.50 dB", "-6.5dB", "2.0")
+fn parse_db(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let number = value
+        .get(value.len().saturating_sub(2)..)
+        .filter(|unit| unit.eq_ignore_ascii_case("db"))
+        .map_or(value, |_| &value[..value.len() - 2]);
+    number.trim().parse().ok()
+}
+
+/// A single tag key change, as shown by dry-run previews
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagChange {
+    Added {
+        key: String,
+        value: String,
+    },
+    Changed {
+        key: String,
+        old: String,
+        new: String,
+    },
+    Removed {
+        key: String,
+        value: String,
+    },
+}
+
+impl TagChange {
+    pub fn key(&self) -> &str {
+        match self {
+            TagChange::Added { key, .. }
+            | TagChange::Changed { key, .. }
+            | TagChange::Removed { key, .. } => key,
+        }
+    }
+}
+
+/// Compare two lists of tag key/value pairs (keys are case-insensitive)
+///
+/// Changed and removed keys are reported in the order of `old`, followed
+/// by added keys in the order of `new`. Unchanged keys are omitted.
+pub fn diff_tag_items(old: &[(String, String)], new: &[(String, String)]) -> Vec<TagChange> {
+    let find = |items: &[(String, String)], key: &str| {
+        items
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.clone())
+    };
+
+    let mut changes = Vec::new();
+
+    for (key, old_value) in old {
+        match find(new, key) {
+            Some(new_value) if new_value != *old_value => changes.push(TagChange::Changed {
+                key: key.clone(),
+                old: old_value.clone(),
+                new: new_value,
+            }),
+            Some(_) => {}
+            None => changes.push(TagChange::Removed {
+                key: key.clone(),
+                value: old_value.clone(),
+            }),
+        }
+    }
+
+    for (key, new_value) in new {
+        if find(old, key).is_none() {
+            changes.push(TagChange::Added {
+                key: key.clone(),
+                value: new_value.clone(),
+            });
+        }
+    }
+
+    changes
 }
 
 /// Find APEv2 tag footer position in file data
@@ -966,6 +3016,8 @@ fn find_ape_footer(data: &[u8]) -> Option<usize> {
 }
 
 /// Read u32 little-endian from slice
+///
+/// All APEv2 numbers are little-endian, unlike MP4 box sizes (big-endian).
 fn read_u32_le(data: &[u8]) -> u32 {
     u32::from_le_bytes([data[0], data[1], data[2], data[3]])
 }
@@ -983,30 +3035,51 @@ pub fn read_ape_tag(data: &[u8]) -> Option<ApeTag> {
     let tag_size = read_u32_le(&data[footer_start + 12..]) as usize;
     let item_count = read_u32_le(&data[footer_start + 16..]) as usize;
 
-    // Calculate items start (tag_size includes items + footer, not header)
-    if footer_start + 32 < tag_size {
+    // Calculate items start (tag_size includes items + footer, not header).
+    // A footer whose size or item count can't fit is garbage, not a tag;
+    // items that merely overrun it are dropped by parse_ape_items.
+    if tag_size < 32 || footer_start + 32 < tag_size {
+        return None;
+    }
+    if item_count > (tag_size - 32) / APE_MIN_ITEM_SIZE {
         return None;
     }
     let items_start = footer_start + 32 - tag_size;
 
-    // Parse items
     let mut tag = ApeTag::new();
-    let mut pos = items_start;
+    tag.items = parse_ape_items(data, items_start, footer_start, item_count).0;
+
+    Some(tag)
+}
+
+/// Parse up to `item_count` APE items from `data[start..end]`
+///
+/// Also returns whether exactly `item_count` items filled the range, as they
+/// do in a consistent tag.
+fn parse_ape_items(
+    data: &[u8],
+    start: usize,
+    end: usize,
+    item_count: usize,
+) -> (Vec<ApeItem>, bool) {
+    let mut items = Vec::new();
+    let mut pos = start;
 
     for _ in 0..item_count {
-        if pos + 8 > footer_start {
+        if end.saturating_sub(pos) < 8 {
             break;
         }
 
         let value_size = read_u32_le(&data[pos..]) as usize;
-        pos += 8; // skip value_size + flags
+        let flags = read_u32_le(&data[pos + 4..]);
+        pos += 8;
 
         // Find null-terminated key
         let key_start = pos;
-        while pos < footer_start && data[pos] != 0 {
+        while pos < end && data[pos] != 0 {
             pos += 1;
         }
-        if pos >= footer_start {
+        if pos >= end {
             break;
         }
 
@@ -1014,23 +3087,80 @@ pub fn read_ape_tag(data: &[u8]) -> Option<ApeTag> {
         pos += 1; // skip null terminator
 
         // Read value
-        if pos + value_size > footer_start {
+        if pos
+            .checked_add(value_size)
+            .is_none_or(|value_end| value_end > end)
+        {
             break;
         }
-        let value = String::from_utf8_lossy(&data[pos..pos + value_size]).to_string();
+        // Keep the bytes: older taggers wrote Latin-1, and binary items
+        // are not text at all
+        let mut item = ApeItem {
+            key,
+            value: String::new(),
+            raw: data[pos..pos + value_size].to_vec(),
+            flags,
+        };
+        if item.is_text() {
+            item.value = String::from_utf8_lossy(&item.raw).to_string();
+        }
         pos += value_size;
 
-        tag.items.push(ApeItem { key, value });
+        items.push(item);
     }
 
-    Some(tag)
+    let complete = items.len() == item_count && pos == end;
+    (items, complete)
 }
 
 /// Read APEv2 tag from file
+///
+/// Only the end of the file is read: the footer, then the tag it points to.
 pub fn read_ape_tag_from_file(file_path: &Path) -> Result<Option<ApeTag>> {
-    let data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
-    Ok(read_ape_tag(&data))
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    let len = file.metadata()?.len();
+    let start = ape_region_start(&mut file, len)?;
+    Ok(read_ape_tag(&read_range(&mut file, start, len - start)?))
+}
+
+/// Offset where the APEv2 tag items of a file start, or of its last 160
+/// bytes (room for a footer and ID3v1) if it has no APEv2 footer
+///
+/// Reading from here to the end gives [`read_ape_tag`] all it looks at.
+fn ape_region_start(file: &mut fs::File, len: u64) -> Result<u64> {
+    let tail_start = len.saturating_sub(32 + 128);
+    let tail = read_range(file, tail_start, len - tail_start)?;
+    let Some(footer) = find_ape_footer(&tail) else {
+        return Ok(tail_start);
+    };
+    let tag_size = read_u32_le(&tail[footer + 12..]) as u64;
+    Ok((tail_start + footer as u64 + 32)
+        .checked_sub(tag_size)
+        .unwrap_or(tail_start)
+        .min(tail_start))
+}
+
+/// The TXXX frames of the ID3v2 tag at the start of a file joined to the
+/// APEv2 region at its end
+///
+/// [`id3v2::read_txxx`] only looks at the start of file data and
+/// [`read_ape_tag`] at the end, so both give the same result on this as on
+/// the whole file, without reading the audio in between or the other ID3v2
+/// frames (see [`id3v2::read_txxx_bytes`]).
+fn read_tag_regions(file_path: &Path) -> Result<Vec<u8>> {
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    let len = file.metadata()?.len();
+
+    let (mut data, id3v2_len) = id3v2::read_txxx_bytes(&mut file)?;
+    let tail_start = ape_region_start(&mut file, len)?;
+    if tail_start <= id3v2_len {
+        // The regions overlap in a small file
+        return read_range(&mut file, 0, len);
+    }
+    data.extend(read_range(&mut file, tail_start, len - tail_start)?);
+    Ok(data)
 }
 
 /// Serialize APE tag to bytes
@@ -1043,13 +3173,13 @@ fn serialize_ape_tag(tag: &ApeTag) -> Vec<u8> {
 
     // Serialize items
     for item in &tag.items {
-        let value_bytes = item.value.as_bytes();
+        let value_bytes = &item.raw;
         let key_bytes = item.key.as_bytes();
 
         // Value size (4 bytes)
         items_data.extend_from_slice(&(value_bytes.len() as u32).to_le_bytes());
-        // Item flags (4 bytes) - 0 for UTF-8 text
-        items_data.extend_from_slice(&0u32.to_le_bytes());
+        // Item flags (4 bytes), as read or 0 for text set by us
+        items_data.extend_from_slice(&item.flags.to_le_bytes());
         // Key (null-terminated)
         items_data.extend_from_slice(key_bytes);
         items_data.push(0);
@@ -1084,47 +3214,163 @@ fn serialize_ape_tag(tag: &ApeTag) -> Vec<u8> {
     result
 }
 
-/// Remove existing APE tag from file data, returning the audio data portion
-fn remove_ape_tag(data: &[u8]) -> Vec<u8> {
-    let footer_start = match find_ape_footer(data) {
-        Some(pos) => pos,
-        None => return data.to_vec(),
-    };
+/// Length of the data without a trailing ID3v1 tag
+fn without_id3v1(data: &[u8]) -> usize {
+    if data.len() >= 128 && &data[data.len() - 128..data.len() - 125] == b"TAG" {
+        data.len() - 128
+    } else {
+        data.len()
+    }
+}
 
-    // Get tag size from footer
+/// Start of the complete APE tag (including its header) whose footer is at `footer_start`
+fn ape_tag_start(data: &[u8], footer_start: usize) -> Option<usize> {
+    if &data[footer_start..footer_start + 8] != APE_PREAMBLE {
+        return None;
+    }
     let tag_size = read_u32_le(&data[footer_start + 12..]) as usize;
     let flags = read_u32_le(&data[footer_start + 20..]);
-    let has_header = (flags & APE_FLAG_HEADER_PRESENT) != 0;
-    let header_size = if has_header { 32 } else { 0 };
-
-    // Calculate where audio ends
-    let audio_end = if footer_start + 32 >= tag_size + header_size {
-        footer_start + 32 - tag_size - header_size
+    let header_size = if flags & APE_FLAG_HEADER_PRESENT != 0 {
+        32
     } else {
         0
     };
+    if tag_size < 32 || footer_start + 32 < tag_size + header_size {
+        return None;
+    }
+    Some(footer_start + 32 - tag_size - header_size)
+}
 
-    // Check for ID3v1 after APE
-    let id3v1_start = footer_start + 32;
-    let has_id3v1 = data.len() > id3v1_start + 3 && &data[id3v1_start..id3v1_start + 3] == b"TAG";
+/// Start of all APE data that ends at `end`
+///
+/// Besides the tag itself this covers duplicate tags appended by earlier
+/// writes and leftovers of an interrupted write (a header or footer without
+/// its counterpart) after the last MP3 frame.
+fn ape_data_start(data: &[u8], mut end: usize) -> usize {
+    while end >= 32 {
+        match ape_tag_start(data, end - 32) {
+            Some(start) => end = start,
+            None => break,
+        }
+    }
 
-    if has_id3v1 {
-        // Keep audio + ID3v1
-        let mut result = data[..audio_end].to_vec();
-        result.extend_from_slice(&data[id3v1_start..]);
-        result
-    } else {
-        data[..audio_end].to_vec()
+    let frames_end = iterate_frames(&data[..end], |_, _, _| {})
+        .map(|scan| scan.frames_end)
+        .unwrap_or(0);
+    if frames_end > 0 && frames_end < end {
+        if let Some(pos) = data[frames_end..end]
+            .windows(APE_PREAMBLE.len())
+            .position(|w| w == APE_PREAMBLE)
+        {
+            end = frames_end + pos;
+        }
+    }
+
+    end
+}
+
+/// Remove existing APE tag from file data, returning the audio data portion
+///
+/// Duplicate and partial APE tags are removed as well.
+fn remove_ape_tag(data: &[u8]) -> Vec<u8> {
+    let end = without_id3v1(data);
+    let start = ape_data_start(data, end);
+
+    // Keep audio + ID3v1
+    let mut result = data[..start].to_vec();
+    result.extend_from_slice(&data[end..]);
+    result
+}
+
+/// Detect an inconsistent APEv2 tag and rewrite it as a single clean tag
+///
+/// A tag is inconsistent if its items do not match the footer's size and
+/// item count, if it was written more than once, or if an interrupted write
+/// left a header without a footer. Items are recovered from the last
+/// complete tag or, failing that, from the stray header.
+///
+/// # Returns
+/// * `true` if the file was rewritten, `false` if it was already consistent
+///   or has no APE tag
+pub fn repair_ape_tag(file_path: &Path) -> Result<bool> {
+    let data = read_file(file_path)?;
+
+    let end = without_id3v1(&data);
+    let start = ape_data_start(&data, end);
+    if start == end {
+        return Ok(false);
     }
+
+    // One complete tag whose items fill it exactly
+    if let Some(tag_start) = end.checked_sub(32).and_then(|f| ape_tag_start(&data, f)) {
+        let footer_start = end - 32;
+        let tag_size = read_u32_le(&data[footer_start + 12..]) as usize;
+        let item_count = read_u32_le(&data[footer_start + 16..]) as usize;
+        let (_, complete) = parse_ape_items(
+            &data,
+            footer_start + 32 - tag_size,
+            footer_start,
+            item_count,
+        );
+        if tag_start == start && complete {
+            return Ok(false);
+        }
+    }
+
+    let mut tag = read_ape_tag(&data[..end]).unwrap_or_default();
+    let is_stray_header = start + 32 <= end
+        && &data[start..start + 8] == APE_PREAMBLE
+        && read_u32_le(&data[start + 20..]) & APE_FLAG_IS_HEADER != 0;
+    if tag.is_empty() && is_stray_header {
+        let item_count = read_u32_le(&data[start + 16..]) as usize;
+        tag.items = parse_ape_items(&data, start + 32, end, item_count).0;
+    }
+
+    let mut repaired = data[..start].to_vec();
+    repaired.extend_from_slice(&serialize_ape_tag(&tag));
+    repaired.extend_from_slice(&data[end..]);
+
+    fs::write(file_path, &repaired).map_err(|e| write_error(e, file_path))?;
+
+    Ok(true)
 }
 
 /// Write APEv2 tag to file
+///
+/// Only the end of the file is rewritten, from where its APE data starts:
+/// the ID3v2 tag at the start is neither read nor written, so embedded album
+/// art costs nothing. An empty `tag` removes the APE data.
 pub fn write_ape_tag(file_path: &Path, tag: &ApeTag) -> Result<()> {
-    let data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    use std::io::{Seek, SeekFrom, Write};
+
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(file_path)
+        .map_err(|e| write_error(e, file_path))?;
+    let len = file.metadata()?.len();
+    let header = read_range(&mut file, 0, 10)?;
+    let start = (skip_id3v2(&header) as u64).min(len);
+    let data = read_range(&mut file, start, len - start)?;
+
+    // Same as `with_ape_tag`, from the start of the APE data on
+    let end = without_id3v1(&data);
+    let ape_start = start + ape_data_start(&data, end) as u64;
+    let mut tail = serialize_ape_tag(tag);
+    tail.extend_from_slice(&data[end..]);
+
+    file.seek(SeekFrom::Start(ape_start))
+        .and_then(|_| file.write_all(&tail))
+        .and_then(|_| file.set_len(ape_start + tail.len() as u64))
+        .map_err(|e| write_error(e, file_path))?;
+
+    Ok(())
+}
 
+/// File data with its APEv2 tag replaced by `tag`
+fn with_ape_tag(data: &[u8], tag: &ApeTag) -> Vec<u8> {
     // Remove existing APE tag
-    let mut audio_data = remove_ape_tag(&data);
+    let mut audio_data = remove_ape_tag(data);
 
     // Check for ID3v1 at end
     let has_id3v1 = audio_data.len() >= 128
@@ -1143,195 +3389,743 @@ pub fn write_ape_tag(file_path: &Path, tag: &ApeTag) -> Result<()> {
         audio_data.extend_from_slice(&tag_data);
     }
 
-    fs::write(file_path, &audio_data)
-        .with_context(|| format!("Failed to write: {}", file_path.display()))?;
+    audio_data
+}
+
+/// Delete APEv2 tag from file
+pub fn delete_ape_tag(file_path: &Path) -> Result<()> {
+    write_ape_tag(file_path, &ApeTag::new())
+}
+
+// =============================================================================
+// Gain Tag Formats
+// =============================================================================
+
+/// Keys of the gain, undo and ReplayGain metadata that [`TagFormat`] places
+pub use tag_keys::GAIN_KEYS as GAIN_TAG_KEYS;
+
+/// Where gain metadata ([`GAIN_TAG_KEYS`]) is stored in an MP3 file
+///
+/// ID3v2 storage uses TXXX frames named after the APEv2 keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TagFormat {
+    /// APEv2 tag at the end of the file (mp3gain's format)
+    #[default]
+    Ape,
+    /// TXXX frames in the ID3v2 tag at the start of the file
+    Id3v2,
+    /// Both of the above, with the same values
+    Both,
+}
+
+impl TagFormat {
+    /// Name as used on the command line ("ape", "id3v2", "both")
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagFormat::Ape => "ape",
+            TagFormat::Id3v2 => "id3v2",
+            TagFormat::Both => "both",
+        }
+    }
+}
+
+/// Read the file's APEv2 tag merged with gain metadata stored in ID3v2
+///
+/// All APEv2 items are kept. Gain keys found only in ID3v2 TXXX frames are
+/// added; when both formats hold a key, the APEv2 value wins, as mp3gain
+/// would read it. Returns `None` if neither format holds anything.
+pub fn read_gain_tag(file_path: &Path) -> Result<Option<ApeTag>> {
+    Ok(gain_tag_from_data(&read_tag_regions(file_path)?))
+}
+
+/// [`read_gain_tag`] for file data in memory
+fn gain_tag_from_data(data: &[u8]) -> Option<ApeTag> {
+    let ape = read_ape_tag(data);
+    let id3v2_items: Vec<(String, String)> = id3v2::read_txxx(data)
+        .into_iter()
+        .filter(|(key, _)| is_gain_key(key))
+        .collect();
+    if ape.is_none() && id3v2_items.is_empty() {
+        return None;
+    }
+
+    let mut tag = ape.unwrap_or_default();
+    for (key, value) in id3v2_items {
+        if tag.get(&key).is_none() {
+            tag.set(&key, &value);
+        }
+    }
+    Some(tag)
+}
+
+/// Which formats hold gain metadata, or `None` if neither does
+pub fn stored_gain_tag_format(file_path: &Path) -> Result<Option<TagFormat>> {
+    let data = read_tag_regions(file_path)?;
+    let in_ape = read_ape_tag(&data)
+        .is_some_and(|tag| tag.items().iter().any(|item| is_gain_key(&item.key)));
+    let in_id3v2 = id3v2::read_txxx(&data)
+        .iter()
+        .any(|(key, _)| is_gain_key(key));
+    Ok(match (in_ape, in_id3v2) {
+        (true, true) => Some(TagFormat::Both),
+        (true, false) => Some(TagFormat::Ape),
+        (false, true) => Some(TagFormat::Id3v2),
+        (false, false) => None,
+    })
+}
+
+/// Kind of tool that left gain metadata in a file (see [`gain_provenance`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GainSource {
+    /// mp3gain tags with undo history only mp3rgain keeps
+    /// ([`tag_keys::MP3GAIN_UNDO_STACK`] or [`tag_keys::MP3GAIN_REDO`])
+    Mp3rgain,
+    /// `MP3GAIN_UNDO` or `MP3GAIN_MINMAX`, as mp3gain writes them; a file
+    /// adjusted once by mp3rgain looks the same
+    Mp3gain,
+    /// ReplayGain tags only, which any ReplayGain tool may have written
+    ReplayGain,
+    /// No gain metadata
+    Untagged,
+}
+
+impl GainSource {
+    /// Name as used in reports ("mp3rgain", "mp3gain", "replaygain", "untagged")
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GainSource::Mp3rgain => "mp3rgain",
+            GainSource::Mp3gain => "mp3gain",
+            GainSource::ReplayGain => "replaygain",
+            GainSource::Untagged => "untagged",
+        }
+    }
+}
+
+/// Tag container holding a file's gain metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagContainer {
+    /// MP3 (or ADTS) gain tags, in APEv2 and/or ID3v2
+    Mp3(TagFormat),
+    /// iTunes freeform tags of an MP4/M4A file
+    Mp4,
+    /// Vorbis comments of a FLAC file
+    Vorbis,
+}
+
+impl TagContainer {
+    /// Name as used in reports ("ape", "id3v2", "both", "mp4", "vorbis")
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagContainer::Mp3(format) => format.as_str(),
+            TagContainer::Mp4 => "mp4",
+            TagContainer::Vorbis => "vorbis",
+        }
+    }
+}
+
+/// Which tool family gained a file, and where it left its tags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Provenance {
+    pub source: GainSource,
+    /// `None` when [`GainSource::Untagged`]
+    pub container: Option<TagContainer>,
+}
+
+/// Tell from its tags whether a file was adjusted by mp3rgain or mp3gain,
+/// tagged by some ReplayGain tool, or left alone
+///
+/// Useful for library audits before re-tagging. Only tags are read; the
+/// audio itself isn't compared against anything, so a file changed without
+/// leaving tags reads as untagged. MP4 and FLAC files can only hold
+/// ReplayGain tags here.
+pub fn gain_provenance(file_path: &Path) -> Result<Provenance> {
+    let (source, container) = match detect_audio_type(file_path) {
+        Some(AudioFileType::Aac) => {
+            let tagged = !mp4meta::read_replaygain_tags(file_path)?.is_empty();
+            (tagged.then_some(GainSource::ReplayGain), TagContainer::Mp4)
+        }
+        Some(AudioFileType::Flac) => {
+            let tagged = flac::read_replaygain_tag(file_path)?.is_some();
+            (
+                tagged.then_some(GainSource::ReplayGain),
+                TagContainer::Vorbis,
+            )
+        }
+        _ => {
+            let source = read_gain_tag(file_path)?.and_then(|tag| {
+                let has = |keys: &[&str]| keys.iter().any(|key| tag.get(key).is_some());
+                if has(&[tag_keys::MP3GAIN_UNDO_STACK, tag_keys::MP3GAIN_REDO]) {
+                    Some(GainSource::Mp3rgain)
+                } else if has(&[tag_keys::MP3GAIN_UNDO, tag_keys::MP3GAIN_MINMAX]) {
+                    Some(GainSource::Mp3gain)
+                } else if has(&tag_keys::REPLAYGAIN_KEYS) {
+                    Some(GainSource::ReplayGain)
+                } else {
+                    None
+                }
+            });
+            let format = stored_gain_tag_format(file_path)?.unwrap_or_default();
+            (source, TagContainer::Mp3(format))
+        }
+    };
+
+    Ok(match source {
+        Some(source) => Provenance {
+            source,
+            container: Some(container),
+        },
+        None => Provenance {
+            source: GainSource::Untagged,
+            container: None,
+        },
+    })
+}
+
+/// Write `tag` with its gain keys stored in `format`
+///
+/// * [`TagFormat::Ape`] writes the APEv2 tag and leaves ID3v2 untouched, as
+///   mp3gain does. Stale ID3v2 values are harmless since APEv2 takes
+///   precedence in [`read_gain_tag`].
+/// * [`TagFormat::Id3v2`] writes the gain keys as TXXX frames and removes
+///   them from the APEv2 tag, which would otherwise shadow them. Other APEv2
+///   items are kept; an APEv2 tag left empty is deleted.
+/// * [`TagFormat::Both`] writes both.
+pub fn write_gain_tag(file_path: &Path, tag: &ApeTag, format: TagFormat) -> Result<()> {
+    if format == TagFormat::Id3v2 {
+        let mut rest = tag.clone();
+        rest.items.retain(|item| !is_gain_key(&item.key));
+        if !rest.is_empty() {
+            write_ape_tag(file_path, &rest)?;
+        } else if read_ape_tag_from_file(file_path)?.is_some() {
+            delete_ape_tag(file_path)?;
+        }
+    } else {
+        write_ape_tag(file_path, tag)?;
+    }
+
+    if format != TagFormat::Ape {
+        let items: Vec<(&str, &str)> = tag
+            .items()
+            .iter()
+            .filter(|item| is_gain_key(&item.key))
+            .map(|item| (item.key.as_str(), item.value.as_str()))
+            .collect();
+        id3v2::write_txxx(file_path, &GAIN_TAG_KEYS, &items)?;
+    }
+    Ok(())
+}
+
+/// Rewrite the gain metadata already in the file in `format`
+///
+/// Functions such as [`apply_gain_with_undo`] write APEv2; this moves their
+/// result to another format (see [`write_gain_tag`]).
+pub fn convert_gain_tag(file_path: &Path, format: TagFormat) -> Result<()> {
+    match read_gain_tag(file_path)? {
+        Some(tag) => write_gain_tag(file_path, &tag, format),
+        None => Ok(()),
+    }
+}
+
+/// Maximum amplitude of an MP3 file and how it was measured
+#[derive(Debug, Clone)]
+pub struct MaxAmplitude {
+    /// Peak amplitude, normalized (values > 1.0 indicate clipping)
+    pub peak: f64,
+    pub max_gain: u8,
+    pub min_gain: u8,
+    pub method: replaygain::AnalysisMethod,
+}
+
+/// Find maximum amplitude in an MP3 file, decoding the audio when possible
+///
+/// The decoded sample peak is exact. If decoding fails (or the replaygain
+/// feature is disabled), the peak is estimated from the maximum
+/// global_gain instead, unless `require_decode` is set, in which case the
+/// decoding error is returned.
+pub fn measure_max_amplitude(file_path: &Path, require_decode: bool) -> Result<MaxAmplitude> {
+    // Get global_gain range from frame analysis (now skips Xing frames)
+    let data = read_file(file_path)?;
+
+    let mut min_gain = 255u8;
+    let mut max_gain = 0u8;
+
+    let scan = iterate_frames(&data, |_pos, _header, locations| {
+        for loc in locations {
+            let gain = read_gain_at(&data, loc);
+            min_gain = min_gain.min(gain);
+            max_gain = max_gain.max(gain);
+        }
+    })?;
+
+    if scan.frame_count == 0 {
+        anyhow::bail!("No valid MP3 frames found");
+    }
+
+    // Get actual peak amplitude by decoding audio
+    let (peak, method) = match replaygain::find_peak_amplitude(file_path) {
+        Ok(result) => (result.peak, replaygain::AnalysisMethod::Decoded),
+        Err(e) if require_decode => return Err(e),
+        // Fallback: estimate amplitude from global_gain (less accurate)
+        Err(_) => (
+            estimate_peak_from_gain(max_gain),
+            replaygain::AnalysisMethod::Heuristic,
+        ),
+    };
+
+    Ok(MaxAmplitude {
+        peak,
+        max_gain,
+        min_gain,
+        method,
+    })
+}
+
+/// Find maximum amplitude in an MP3 file.
+/// Returns (max_amplitude, max_global_gain, min_global_gain)
+///
+/// When the replaygain feature is enabled, this decodes the audio to measure
+/// actual PCM sample values. Otherwise, it falls back to estimation from global_gain.
+///
+/// Note: The max_amplitude is normalized (0.0 to 1.0+), where values > 1.0 indicate clipping.
+/// To get the value in 16-bit PCM scale (like mp3gain), multiply by 32768.
+pub fn find_max_amplitude(file_path: &Path) -> Result<(f64, u8, u8)> {
+    let result = measure_max_amplitude(file_path, replaygain::is_available())?;
+    Ok((result.peak, result.max_gain, result.min_gain))
+}
+
+/// Rough peak amplitude estimate from the maximum global_gain, for when audio
+/// can't be decoded: each step of headroom below 255 counts as 1.5 dB
+pub(crate) fn estimate_peak_from_gain(max_gain: u8) -> f64 {
+    let headroom_db = (MAX_GAIN - max_gain) as f64 * GAIN_STEP_DB;
+    10.0_f64.powf(-headroom_db / 20.0)
+}
+
+/// Apply gain with wrapping (values wrap around instead of clamping)
+pub fn apply_gain_wrap(file_path: &Path, gain_steps: i32) -> Result<usize> {
+    apply_gain_mode(file_path, gain_steps, GainMode::Wrapping)
+}
+
+/// Analyze the file as it would be after applying `gain_steps`
+///
+/// The file is not modified: the gain is applied to a copy in memory, exactly
+/// as [`apply_gain_wrap`] (`wrap`) or [`apply_gain_floor`] (otherwise) would
+/// apply it, so the projected min/max/average global_gain and headroom match
+/// what an actual apply produces.
+pub fn preview_gain(
+    file_path: &Path,
+    gain_steps: i32,
+    wrap: bool,
+    floor: u8,
+) -> Result<Mp3Analysis> {
+    let mut data = read_file(file_path)?;
+    let mode = if wrap {
+        GainMode::Wrapping
+    } else {
+        GainMode::Floor(floor)
+    };
+    apply_gain_bytes_mode(&mut data, gain_steps, mode)?;
+    analyze_data(&data)
+}
+
+/// Build the APEv2 tag that applying `gain_steps` with undo would write
+///
+/// The file is not modified. Compare against [`read_gain_tag`] with
+/// [`ApeTag::diff`] to preview tag changes.
+pub fn preview_undo_tag(file_path: &Path, gain_steps: i32, wrap: bool) -> Result<ApeTag> {
+    Ok(undo_tag_for(file_path, gain_steps, wrap)?.0)
+}
+
+/// The undo tag for `gain_steps`, plus the analysis used for its min/max
+///
+/// The file is only analyzed when it has no valid MP3GAIN_MINMAX yet: the
+/// stored one is the baseline from before the first adjustment and must not
+/// change, so repeated adjustments skip the scan and return `None`.
+fn undo_tag_for(
+    file_path: &Path,
+    gain_steps: i32,
+    wrap: bool,
+) -> Result<(ApeTag, Option<Mp3Analysis>)> {
+    // Read existing tags (APEv2 or ID3v2) or create new one
+    let existing = read_gain_tag(file_path)?;
+    build_undo_tag(existing, gain_steps, wrap, || analyze_audio(file_path))
+}
+
+/// [`undo_tag_for`] from the existing gain tag, analyzing only if needed
+fn build_undo_tag(
+    existing: Option<ApeTag>,
+    gain_steps: i32,
+    wrap: bool,
+    analyze: impl FnOnce() -> Result<Mp3Analysis>,
+) -> Result<(ApeTag, Option<Mp3Analysis>)> {
+    let mut tag = existing.unwrap_or_default();
+
+    // Store or update undo information
+    tag.push_undo(gain_steps, gain_steps, wrap);
+    // A new adjustment leaves nothing to redo
+    tag.remove(TAG_MP3GAIN_REDO);
+
+    // Get current min/max before modification, unless already stored
+    let analysis = if tag.get_minmax().is_some() {
+        None
+    } else {
+        Some(analyze()?)
+    };
+    if let Some(analysis) = &analysis {
+        if tag.get(TAG_MP3GAIN_MINMAX).is_none() {
+            tag.set_minmax(analysis.min_gain, analysis.max_gain);
+        }
+    }
+
+    Ok((tag, analysis))
+}
 
-    Ok(())
+/// Build the APEv2 tag that [`write_minmax_tag`] would write
+///
+/// The file is not modified. An existing MP3GAIN_MINMAX is kept.
+pub fn preview_minmax_tag(file_path: &Path) -> Result<ApeTag> {
+    let analysis = analyze(file_path)?;
+    let mut tag = read_gain_tag(file_path)?.unwrap_or_else(ApeTag::new);
+    if tag.get(TAG_MP3GAIN_MINMAX).is_none() {
+        tag.set_minmax(analysis.min_gain, analysis.max_gain);
+    }
+    Ok(tag)
 }
 
-/// Delete APEv2 tag from file
-pub fn delete_ape_tag(file_path: &Path) -> Result<()> {
-    let data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
+/// Record the current min/max global_gain as MP3GAIN_MINMAX without touching the audio
+///
+/// This seeds the baseline that [`estimate_applied_gain`] compares against
+/// before the file is adjusted by a tool that doesn't write it. No
+/// MP3GAIN_UNDO is written, and an existing MP3GAIN_MINMAX is kept since it
+/// already holds the baseline from before the first adjustment.
+///
+/// # Returns
+/// * `true` if the tag was written, `false` if MP3GAIN_MINMAX was already present
+pub fn write_minmax_tag(file_path: &Path) -> Result<bool> {
+    let existing = read_gain_tag(file_path)?;
+    if existing.is_some_and(|tag| tag.get(TAG_MP3GAIN_MINMAX).is_some()) {
+        return Ok(false);
+    }
+    let tag = preview_minmax_tag(file_path)?;
+    write_ape_tag(file_path, &tag)?;
+    Ok(true)
+}
 
-    let audio_data = remove_ape_tag(&data);
+/// Rewrite MP3GAIN_UNDO, MP3GAIN_REDO and the min/max tags in mp3gain's
+/// own format
+///
+/// See [`ApeTag::normalize_undo`]. The audio is not touched.
+///
+/// # Returns
+/// * `true` if the tag was written, `false` if there was nothing to rewrite
+pub fn normalize_undo_tag(file_path: &Path) -> Result<bool> {
+    let Some(mut tag) = read_gain_tag(file_path)? else {
+        return Ok(false);
+    };
+    if !tag.normalize_undo() {
+        return Ok(false);
+    }
+    write_ape_tag(file_path, &tag)?;
+    Ok(true)
+}
 
-    fs::write(file_path, &audio_data)
-        .with_context(|| format!("Failed to write: {}", file_path.display()))?;
+/// Apply gain with wrapping and store undo information in APEv2 tag
+pub fn apply_gain_with_undo_wrap(file_path: &Path, gain_steps: i32) -> Result<usize> {
+    apply_gain_with_undo_mode(file_path, gain_steps, GainMode::Wrapping)
+}
 
-    Ok(())
+/// Apply gain and store undo information in APEv2 tag
+pub fn apply_gain_with_undo(file_path: &Path, gain_steps: i32) -> Result<usize> {
+    apply_gain_with_undo_mode(file_path, gain_steps, GainMode::Saturating)
 }
 
-/// Find maximum amplitude in an MP3 file by decoding the audio.
-/// Returns (max_amplitude, max_global_gain, min_global_gain)
-///
-/// When the replaygain feature is enabled, this decodes the audio to measure
-/// actual PCM sample values. Otherwise, it falls back to estimation from global_gain.
+/// [`apply_gain_floor`] and store undo information in APEv2 tag
 ///
-/// Note: The max_amplitude is normalized (0.0 to 1.0+), where values > 1.0 indicate clipping.
-/// To get the value in 16-bit PCM scale (like mp3gain), multiply by 32768.
-#[cfg(feature = "replaygain")]
-pub fn find_max_amplitude(file_path: &Path) -> Result<(f64, u8, u8)> {
-    // Get global_gain range from frame analysis (now skips Xing frames)
-    let data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
+/// MP3GAIN_UNDO can only shift every frame back by the same amount, so the
+/// file is left unchanged with an error if the floor would hold any value
+/// back.
+pub fn apply_gain_with_undo_floor(file_path: &Path, gain_steps: i32, floor: u8) -> Result<usize> {
+    apply_gain_with_undo_mode(file_path, gain_steps, GainMode::Floor(floor))
+}
 
-    let mut min_gain = 255u8;
-    let mut max_gain = 0u8;
+fn apply_gain_with_undo_mode(file_path: &Path, gain_steps: i32, mode: GainMode) -> Result<usize> {
+    if gain_steps == 0 {
+        return Ok(0);
+    }
 
-    let frame_count = iterate_frames(&data, |_pos, _header, locations| {
-        for loc in locations {
-            let gain = read_gain_at(&data, loc);
-            min_gain = min_gain.min(gain);
-            max_gain = max_gain.max(gain);
+    let (tag, analysis) = undo_tag_for(file_path, gain_steps, mode == GainMode::Wrapping)?;
+
+    // Apply the gain
+    let pass = apply_pass_to_file(file_path, |data| {
+        let pass = apply_gain_to_data(data, gain_steps, mode);
+        if analysis.is_none() && pass.frames == 0 {
+            check_other_format(data)?;
+            anyhow::bail!("No valid MP3 frames found");
+        }
+        if let GainMode::Floor(floor @ 1..) = mode {
+            if gain_steps < 0 && pass.held {
+                anyhow::bail!(
+                    "Some frames would stop at global_gain {}, which the undo information can't restore",
+                    floor
+                );
+            }
+        }
+        if let Some(analysis) = &analysis {
+            check_gain_locations(analysis.gain_locations, pass.gain_locations)?;
         }
+        Ok(pass)
     })?;
 
-    if frame_count == 0 {
-        anyhow::bail!("No valid MP3 frames found");
-    }
-
-    // Get actual peak amplitude by decoding audio
-    let peak_result = replaygain::find_peak_amplitude(file_path)?;
-    let max_amplitude = peak_result.peak;
+    // Write APE tag
+    write_ape_tag(file_path, &tag)?;
 
-    Ok((max_amplitude, max_gain, min_gain))
+    Ok(pass.frames)
 }
 
-/// Find maximum amplitude in an MP3 file (fallback without replaygain feature)
-/// Returns (max_amplitude, max_global_gain, min_global_gain)
-#[cfg(not(feature = "replaygain"))]
-pub fn find_max_amplitude(file_path: &Path) -> Result<(f64, u8, u8)> {
-    let data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
-
-    let mut min_gain = 255u8;
-    let mut max_gain = 0u8;
+/// Apply gain to MP3 data in memory and store undo information in its APEv2 tag
+///
+/// Like [`apply_gain_with_undo`] for a buffer that never touches the
+/// filesystem: `data` holds the whole file (tags included) and is updated in
+/// place, its APEv2 tag replaced by one with the new MP3GAIN_UNDO (and
+/// MP3GAIN_MINMAX on the first adjustment). Gain keys stored in ID3v2 are
+/// carried over as [`read_gain_tag`] would.
+///
+/// # Returns
+/// * Number of frames modified
+pub fn apply_gain_with_undo_bytes(data: &mut Vec<u8>, gain_steps: i32) -> Result<usize> {
+    if gain_steps == 0 {
+        return Ok(0);
+    }
 
-    let frame_count = iterate_frames(&data, |_pos, _header, locations| {
-        for loc in locations {
-            let gain = read_gain_at(&data, loc);
-            min_gain = min_gain.min(gain);
-            max_gain = max_gain.max(gain);
-        }
+    let (tag, analysis) = build_undo_tag(gain_tag_from_data(data), gain_steps, false, || {
+        analyze_data(data)
     })?;
 
-    if frame_count == 0 {
-        anyhow::bail!("No valid MP3 frames found");
+    // Work on the copy with the new tag, so `data` is untouched on error
+    let mut updated = with_ape_tag(data, &tag);
+    let pass = apply_gain_to_data(&mut updated, gain_steps, GainMode::Saturating);
+    match analysis {
+        Some(analysis) => check_gain_locations(analysis.gain_locations, pass.gain_locations)?,
+        None if pass.frames == 0 => {
+            check_other_format(data)?;
+            anyhow::bail!("No valid MP3 frames found");
+        }
+        None => {}
     }
 
-    // Fallback: estimate amplitude from global_gain (less accurate)
-    let headroom_steps = (MAX_GAIN - max_gain) as i32;
-    let headroom_db = headroom_steps as f64 * GAIN_STEP_DB;
-    let max_amplitude = 10.0_f64.powf(-headroom_db / 20.0);
+    *data = updated;
 
-    Ok((max_amplitude, max_gain, min_gain))
+    Ok(pass.frames)
 }
 
-/// Apply gain with wrapping (values wrap around instead of clamping)
-pub fn apply_gain_wrap(file_path: &Path, gain_steps: i32) -> Result<usize> {
-    if gain_steps == 0 {
-        return Ok(0);
+/// Difference in global_gain between two MP3 files, from [`compare_gains`]
+///
+/// Each value is `b` minus `a`, in 1.5 dB steps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GainDiff {
+    /// Difference in average global_gain
+    pub avg_steps: f64,
+    /// Difference in minimum global_gain
+    pub min_steps: i32,
+    /// Difference in maximum global_gain
+    pub max_steps: i32,
+    /// Number of audio frames in `a`
+    pub frames_a: usize,
+    /// Number of audio frames in `b`
+    pub frames_b: usize,
+}
+
+impl GainDiff {
+    /// Whether both files have the same number of frames
+    ///
+    /// If not, they don't hold the same stream (or one was cut), and the
+    /// differences compare different audio.
+    pub fn frame_counts_match(&self) -> bool {
+        self.frames_a == self.frames_b
     }
+}
 
-    let mut data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
+/// Compare the global_gain values of two MP3 files (frame domain)
+///
+/// Meant for checking that a re-gained copy is at the expected level: a
+/// copy of `a` with 3 steps applied gives differences of +3, unless values
+/// were held at 0 or 255. Differing frame counts are reported through
+/// [`GainDiff::frame_counts_match`] rather than as an error.
+pub fn compare_gains(a: &Path, b: &Path) -> Result<GainDiff> {
+    let a = analyze(a)?;
+    let b = analyze(b)?;
+    Ok(GainDiff {
+        avg_steps: b.avg_gain - a.avg_gain,
+        min_steps: i32::from(b.min_gain) - i32::from(a.min_gain),
+        max_steps: i32::from(b.max_gain) - i32::from(a.max_gain),
+        frames_a: a.frame_count,
+        frames_b: b.frame_count,
+    })
+}
 
-    let modified_frames = apply_gain_to_data(&mut data, gain_steps, GainMode::Wrapping);
+/// Estimate the net gain (in steps) applied since MP3GAIN_MINMAX was stored
+///
+/// This is an estimate: it compares the original min/max global_gain in the
+/// tag with the current values, so it still works when MP3GAIN_UNDO has been
+/// lost. Values clamped at 0 or 255 move less than the applied gain, so the
+/// larger of the two shifts is used. Returns `None` if the file can't be read
+/// or has no MP3GAIN_MINMAX tag.
+pub fn estimate_applied_gain(file_path: &Path) -> Option<i32> {
+    let (orig_min, orig_max) = read_gain_tag(file_path).ok()??.get_minmax()?;
+    let analysis = analyze(file_path).ok()?;
+
+    let min_shift = analysis.min_gain as i32 - orig_min as i32;
+    let max_shift = analysis.max_gain as i32 - orig_max as i32;
+    if min_shift.abs() >= max_shift.abs() {
+        Some(min_shift)
+    } else {
+        Some(max_shift)
+    }
+}
 
-    fs::write(file_path, &data)
-        .with_context(|| format!("Failed to write: {}", file_path.display()))?;
+/// Undo gain changes based on APEv2 (or ID3v2, see [`read_gain_tag`]) tag information
+///
+/// Returns `Ok(0)` if there is nothing to undo (no tag, or no MP3GAIN_UNDO
+/// item); errors are reserved for I/O and parse failures. The undo items
+/// are removed from both formats, and an APEv2 tag left empty is removed.
+pub fn undo_gain(file_path: &Path) -> Result<usize> {
+    undo_gain_with_redo(file_path, false)
+}
 
-    Ok(modified_frames)
+/// [`undo_gain`], keeping the undone gain as MP3GAIN_REDO for [`redo_gain`]
+///
+/// The redo item is stored in the tag format(s) the undo information was
+/// read from.
+pub fn undo_gain_keep_redo(file_path: &Path) -> Result<usize> {
+    undo_gain_with_redo(file_path, true)
 }
 
-/// Apply gain with wrapping and store undo information in APEv2 tag
-pub fn apply_gain_with_undo_wrap(file_path: &Path, gain_steps: i32) -> Result<usize> {
-    if gain_steps == 0 {
+fn undo_gain_with_redo(file_path: &Path, keep_redo: bool) -> Result<usize> {
+    let data = read_tag_regions(file_path)?;
+    let Some(tag) = gain_tag_from_data(&data) else {
         return Ok(0);
-    }
-
-    // First, get current min/max before modification
-    let analysis = analyze(file_path)?;
-
-    // Read existing APE tag or create new one
-    let mut tag = read_ape_tag_from_file(file_path)?.unwrap_or_else(ApeTag::new);
+    };
 
-    // Store or update undo information
-    let existing_undo = tag.get_undo_gain().unwrap_or(0);
-    let new_undo = existing_undo + gain_steps;
-    tag.set_undo_gain(new_undo, new_undo, true); // true = wrap mode
+    let Some((undo_left, undo_right)) = tag.get_undo_gains() else {
+        return Ok(0);
+    };
+    let undo_value = tag.get(TAG_MP3GAIN_UNDO).unwrap_or_default().to_string();
 
-    // Store original min/max if not already stored
-    if tag.get(TAG_MP3GAIN_MINMAX).is_none() {
-        tag.set_minmax(analysis.min_gain, analysis.max_gain);
+    if undo_left == 0 && undo_right == 0 {
+        return Ok(0);
     }
 
-    // Apply the gain with wrapping
-    let frames = apply_gain_wrap(file_path, gain_steps)?;
+    // Apply inverse gain, per channel if the channels were adjusted differently
+    let frames = if undo_left == undo_right {
+        apply_gain(file_path, -undo_left)?
+    } else {
+        apply_gain_channels(file_path, -undo_left, -undo_right)?
+    };
 
-    // Write APE tag
-    write_ape_tag(file_path, &tag)?;
+    // Remove the undo information, moving it to the redo slot if asked to
+    let redo = [(TAG_MP3GAIN_REDO, undo_value.as_str())];
+    let redo: &[(&str, &str)] = if keep_redo { &redo } else { &[] };
+    let in_id3v2 = id3v2::read_txxx(&data)
+        .iter()
+        .any(|(key, _)| key == TAG_MP3GAIN_UNDO);
+    if let Some(mut new_tag) = read_ape_tag_from_file(file_path)? {
+        let in_ape = new_tag.get(TAG_MP3GAIN_UNDO).is_some();
+        new_tag.remove(TAG_MP3GAIN_UNDO);
+        new_tag.remove(TAG_MP3GAIN_UNDO_STACK);
+        new_tag.remove(TAG_MP3GAIN_MINMAX);
+        if in_ape {
+            for (key, value) in redo {
+                new_tag.set(key, value);
+            }
+        }
+
+        if new_tag.is_empty() {
+            delete_ape_tag(file_path)?;
+        } else {
+            write_ape_tag(file_path, &new_tag)?;
+        }
+    }
+    id3v2::write_txxx(
+        file_path,
+        &[TAG_MP3GAIN_UNDO, TAG_MP3GAIN_UNDO_STACK, TAG_MP3GAIN_MINMAX],
+        if in_id3v2 { redo } else { &[] },
+    )?;
 
     Ok(frames)
 }
 
-/// Apply gain and store undo information in APEv2 tag
-pub fn apply_gain_with_undo(file_path: &Path, gain_steps: i32) -> Result<usize> {
-    if gain_steps == 0 {
+/// Undo only the most recent adjustment recorded in MP3GAIN_UNDO_STACK
+///
+/// Earlier adjustments stay applied and recorded, so [`undo_gain`] still
+/// reverses them; see [`ApeTag::get_undo_stack`] for tags written without a
+/// stack. When the last adjustment is the only one, this is the same as
+/// [`undo_gain`]. The undone adjustment is kept as MP3GAIN_REDO.
+pub fn undo_last_gain(file_path: &Path) -> Result<usize> {
+    let Some(mut tag) = read_gain_tag(file_path)? else {
+        return Ok(0);
+    };
+    let Some((last_left, last_right, last_wrap)) = tag.pop_undo() else {
         return Ok(0);
+    };
+    if tag.get(TAG_MP3GAIN_UNDO).is_none() {
+        return undo_gain_keep_redo(file_path);
     }
-
-    // First, get current min/max before modification
-    let analysis = analyze(file_path)?;
-
-    // Read existing APE tag or create new one
-    let mut tag = read_ape_tag_from_file(file_path)?.unwrap_or_else(ApeTag::new);
-
-    // Store or update undo information
-    let existing_undo = tag.get_undo_gain().unwrap_or(0);
-    let new_undo = existing_undo + gain_steps;
-    tag.set_undo_gain(new_undo, new_undo, false);
-
-    // Store original min/max if not already stored
-    if tag.get(TAG_MP3GAIN_MINMAX).is_none() {
-        tag.set_minmax(analysis.min_gain, analysis.max_gain);
+    if last_left == 0 && last_right == 0 {
+        return Ok(0);
     }
 
-    // Apply the gain
-    let frames = apply_gain(file_path, gain_steps)?;
+    let frames = if last_left == last_right {
+        apply_gain(file_path, -last_left)?
+    } else {
+        apply_gain_channels(file_path, -last_left, -last_right)?
+    };
 
-    // Write APE tag
+    tag.set(
+        TAG_MP3GAIN_REDO,
+        &format_undo_value(last_left, last_right, last_wrap),
+    );
     write_ape_tag(file_path, &tag)?;
 
     Ok(frames)
 }
 
-/// Undo gain changes based on APEv2 tag information
-pub fn undo_gain(file_path: &Path) -> Result<usize> {
-    let tag = read_ape_tag_from_file(file_path)?
-        .ok_or_else(|| anyhow::anyhow!("No APE tag found - cannot undo"))?;
-
-    let undo_gain = tag
-        .get_undo_gain()
-        .ok_or_else(|| anyhow::anyhow!("No MP3GAIN_UNDO tag found - cannot undo"))?;
+/// Apply again the gain that the last [`undo_gain_keep_redo`] took back
+///
+/// The MP3GAIN_REDO item becomes MP3GAIN_UNDO (with a fresh MP3GAIN_MINMAX)
+/// again, in the tag format(s) it was stored in, so the file and its tags
+/// end up as they were before the undo. Returns `Ok(0)` if there is nothing
+/// to redo; any adjustment made after the undo discards the redo information.
+pub fn redo_gain(file_path: &Path) -> Result<usize> {
+    let data = read_tag_regions(file_path)?;
+    let Some(redo_value) =
+        gain_tag_from_data(&data).and_then(|tag| tag.get(TAG_MP3GAIN_REDO).map(String::from))
+    else {
+        return Ok(0);
+    };
+    let in_ape = read_ape_tag(&data).is_some_and(|tag| tag.get(TAG_MP3GAIN_REDO).is_some());
+    let in_id3v2 = id3v2::read_txxx(&data)
+        .iter()
+        .any(|(key, _)| key == TAG_MP3GAIN_REDO);
+    let Some((redo_left, redo_right, wrap)) = parse_undo_value(&redo_value) else {
+        return Ok(0);
+    };
 
-    if undo_gain == 0 {
+    if redo_left == 0 && redo_right == 0 {
         return Ok(0);
     }
 
-    // Apply inverse gain
-    let frames = apply_gain(file_path, -undo_gain)?;
-
-    // Update or remove undo tag
-    let mut new_tag = tag.clone();
-    new_tag.remove(TAG_MP3GAIN_UNDO);
-    new_tag.remove(TAG_MP3GAIN_MINMAX);
-
-    if new_tag.is_empty() {
-        delete_ape_tag(file_path)?;
+    // These rewrite the APEv2 tag without MP3GAIN_REDO
+    let frames = if redo_left != redo_right {
+        apply_gain_channels_with_undo(file_path, redo_left, redo_right)?
+    } else if wrap {
+        apply_gain_with_undo_wrap(file_path, redo_left)?
     } else {
-        write_ape_tag(file_path, &new_tag)?;
+        apply_gain_with_undo(file_path, redo_left)?
+    };
+    id3v2::write_txxx(file_path, &[TAG_MP3GAIN_REDO], &[])?;
+    match (in_ape, in_id3v2) {
+        (false, true) => convert_gain_tag(file_path, TagFormat::Id3v2)?,
+        (true, true) => convert_gain_tag(file_path, TagFormat::Both)?,
+        _ => {}
     }
 
     Ok(frames)
@@ -1350,6 +4144,18 @@ mod tests {
         assert_eq!(db_to_steps(2.25), 2);
     }
 
+    #[test]
+    fn test_db_to_steps_clip_safe() {
+        // 4.2 dB of headroom: +4.5 dB would clip, +3.0 dB doesn't
+        let peak = 10f64.powf(-4.2 / 20.0);
+        assert_eq!(db_to_steps(4.4), 3);
+        assert_eq!(db_to_steps_clip_safe(4.4, peak), 2);
+        // No clipping either way, or clipping either way: nearest
+        assert_eq!(db_to_steps_clip_safe(4.4, 0.5), 3);
+        assert_eq!(db_to_steps_clip_safe(4.4, 1.0), 3);
+        assert_eq!(db_to_steps_clip_safe(2.9, peak), 2);
+    }
+
     #[test]
     fn test_steps_to_db() {
         assert_eq!(steps_to_db(0), 0.0);
@@ -1357,6 +4163,318 @@ mod tests {
         assert_eq!(steps_to_db(-2), -3.0);
     }
 
+    #[test]
+    fn test_peak_target_steps_heuristic() {
+        let data = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 4, 240);
+        let analysis = analyze_data(&data).unwrap();
+        // Estimated peak: 15 steps below 255, so -22.5 dBFS
+        assert_eq!(peak_target_steps_heuristic(&analysis, -1.5), 14);
+        assert_eq!(peak_target_steps_heuristic(&analysis, -22.5), 0);
+        // Rounds down rather than overshoot the target
+        assert_eq!(peak_target_steps_heuristic(&analysis, -1.0), 14);
+        assert_eq!(peak_target_steps_heuristic(&analysis, -25.0), -2);
+    }
+
+    #[test]
+    fn test_quantization_error_db() {
+        assert_eq!(quantization_error_db(4.5), 0.0);
+        assert_eq!(quantization_error_db(4.0), 0.5);
+        assert_eq!(quantization_error_db(-1.0), -0.5);
+        assert!((quantization_error_db(2.2) + 0.7).abs() < 1e-9);
+        assert_eq!(quantized_db(4.0), 4.5);
+        assert_eq!(quantized_db(-0.7), 0.0);
+        // Never more than half a step either way
+        for tenths in -200..=200 {
+            assert!(quantization_error_db(tenths as f64 / 10.0).abs() <= GAIN_STEP_DB / 2.0);
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = capabilities();
+        assert_eq!(caps.replaygain, cfg!(feature = "replaygain"));
+        // AAC and FLAC decoding come with the symphonia dependency of `replaygain`
+        assert_eq!(caps.aac, caps.replaygain);
+        assert_eq!(caps.flac, caps.replaygain);
+        assert_eq!(caps.mpeg_versions.len(), 3);
+        if caps.replaygain {
+            assert!(caps.sample_rates.contains(&44100));
+            assert!(caps.sample_rates.contains(&8000));
+        } else {
+            assert!(caps.sample_rates.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_retry_io_recovers_from_transient_error() {
+        use std::io::{Error, ErrorKind};
+        use std::time::Duration;
+
+        // Locked for the first two attempts, then free
+        let mut attempts = 0;
+        let result = retry_io(3, Duration::from_millis(1), || {
+            attempts += 1;
+            if attempts <= 2 {
+                Err(Error::from(ErrorKind::ResourceBusy))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // Gives up once the retries are used up
+        let mut attempts = 0;
+        let result: std::io::Result<()> = retry_io(1, Duration::from_millis(1), || {
+            attempts += 1;
+            Err(Error::from(ErrorKind::ResourceBusy))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::ResourceBusy);
+        assert_eq!(attempts, 2);
+
+        // Permanent errors are not retried
+        let mut attempts = 0;
+        let result: std::io::Result<()> = retry_io(3, Duration::from_millis(1), || {
+            attempts += 1;
+            Err(Error::from(ErrorKind::PermissionDenied))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        // The backoff doubles up to the cap and never overflows
+        assert_eq!(
+            next_backoff(Duration::from_millis(200)),
+            Duration::from_millis(400)
+        );
+        assert_eq!(next_backoff(Duration::from_millis(1500)), MAX_RETRY_BACKOFF);
+        assert_eq!(next_backoff(Duration::MAX), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn test_write_error_explains_common_causes() {
+        use std::io::{Error, ErrorKind};
+
+        let path = Path::new("song.mp3");
+        let message = |kind| write_error(Error::from(kind), path).to_string();
+        assert!(message(ErrorKind::PermissionDenied).contains("song.mp3 (file is read-only;"));
+        assert!(message(ErrorKind::StorageFull).ends_with("(disk full)"));
+        assert_eq!(message(ErrorKind::NotFound), "Failed to write: song.mp3");
+    }
+
+    #[test]
+    fn test_replaygain_ape_tags_round_trip() {
+        let mut tag = ApeTag::new();
+        tag.set_replaygain_track(-6.5, 0.75);
+        tag.set_replaygain_album(-5.0, 0.9);
+
+        let mut data = vec![0xFF, 0xFB, 0x90, 0x00];
+        data.extend_from_slice(&serialize_ape_tag(&tag));
+
+        let read = read_ape_tag(&data).unwrap();
+        assert_eq!(read.get(TAG_REPLAYGAIN_TRACK_GAIN), Some("-6.50 dB"));
+        assert_eq!(read.get(TAG_REPLAYGAIN_TRACK_PEAK), Some("0.750000"));
+        assert_eq!(read.get(TAG_REPLAYGAIN_ALBUM_GAIN), Some("-5.00 dB"));
+        assert_eq!(read.get(TAG_REPLAYGAIN_REFERENCE_LOUDNESS), Some("89.0 dB"));
+    }
+
+    #[test]
+    fn test_undo_value_variants() {
+        let cases = [
+            ("+002,+002,N", Some((2, 2, false))),
+            ("-003,-001,W", Some((-3, -1, true))),
+            ("2,2,N", Some((2, 2, false))),
+            (" +2 , +2 , n ", Some((2, 2, false))),
+            ("+ 002,+ 002,w", Some((2, 2, true))),
+            ("+004", Some((4, 4, false))),
+            ("+004,,N", Some((4, 4, false))),
+            ("-001,+001", Some((-1, 1, false))),
+            ("+002,+002,N\0", Some((2, 2, false))),
+            ("", None),
+            ("N", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_undo_value(value), expected, "{:?}", value);
+        }
+
+        let mut tag = ApeTag::new();
+        tag.set(TAG_MP3GAIN_UNDO, " 2, 3 ,w");
+        tag.set(TAG_MP3GAIN_REDO, "-1");
+        assert_eq!(tag.get_undo_gain(), Some(2));
+        assert_eq!(tag.get_undo_gains(), Some((2, 3)));
+        assert!(tag.normalize_undo());
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+002,+003,W"));
+        assert_eq!(tag.get(TAG_MP3GAIN_REDO), Some("-001,-001,N"));
+        assert!(!tag.normalize_undo());
+
+        // Unparseable values are left for the user to look at
+        tag.set(TAG_MP3GAIN_UNDO, "garbage");
+        assert!(!tag.normalize_undo());
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("garbage"));
+
+        tag.set(TAG_MP3GAIN_MINMAX, "084,210");
+        assert!(!tag.normalize_undo());
+        tag.set(TAG_MP3GAIN_ALBUM_MINMAX, " 84 , +210");
+        assert!(tag.normalize_undo());
+        assert_eq!(tag.get(TAG_MP3GAIN_ALBUM_MINMAX), Some("84,210"));
+    }
+
+    #[test]
+    fn test_undo_stack() {
+        let mut tag = ApeTag::new();
+        assert!(tag.get_undo_stack().is_empty());
+        assert_eq!(tag.pop_undo(), None);
+
+        // mp3gain's net value is a single adjustment
+        tag.set(TAG_MP3GAIN_UNDO, "+002,+002,N");
+        assert_eq!(tag.get_undo_stack(), [(2, 2, false)]);
+
+        tag.push_undo(3, 3, false);
+        tag.push_undo(-1, 0, true);
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+004,+005,W"));
+        assert_eq!(
+            tag.get(TAG_MP3GAIN_UNDO_STACK),
+            Some("+002,+002,N;+003,+003,N;-001,+000,W")
+        );
+
+        // Gain added to the net value alone shows up as its own adjustment
+        tag.set(TAG_MP3GAIN_UNDO, "+006,+007,N");
+        assert_eq!(tag.get_undo_stack().last(), Some(&(2, 2, false)));
+
+        assert_eq!(tag.pop_undo(), Some((2, 2, false)));
+        assert_eq!(tag.pop_undo(), Some((-1, 0, true)));
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+005,+005,N"));
+        assert_eq!(tag.pop_undo(), Some((3, 3, false)));
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+002,+002,N"));
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO_STACK), None);
+        assert_eq!(tag.pop_undo(), Some((2, 2, false)));
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO), None);
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO_STACK), None);
+    }
+
+    #[test]
+    fn test_ape_numbers_are_little_endian() {
+        assert_eq!(read_u32_le(&[0x01, 0x00, 0x00, 0x00]), 1);
+        assert_eq!(read_u32_le(&[0xD0, 0x07, 0x00, 0x00]), APE_VERSION);
+
+        let mut tag = ApeTag::new();
+        tag.set(TAG_MP3GAIN_UNDO, "+002,+002,N");
+        let data = serialize_ape_tag(&tag);
+        let footer = &data[data.len() - 32..];
+        assert_eq!(&footer[8..12], &2000u32.to_le_bytes());
+        // One item: 8 + "MP3GAIN_UNDO\0" + "+002,+002,N", plus the footer
+        assert_eq!(&footer[12..16], &(8 + 13 + 11 + 32u32).to_le_bytes());
+        assert_eq!(&footer[16..20], &1u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_read_ape_tag_rejects_malformed_sizes() {
+        let mut tag = ApeTag::new();
+        tag.set(TAG_MP3GAIN_UNDO, "+002,+002,N");
+        tag.set(TAG_MP3GAIN_MINMAX, "100,200");
+        let mut data = vec![0xFF, 0xFB, 0x90, 0x00];
+        data.extend_from_slice(&serialize_ape_tag(&tag));
+        assert_eq!(read_ape_tag(&data).unwrap().items.len(), 2);
+
+        let footer = data.len() - 32;
+        let header = 4;
+        let first_item = header + 32;
+        let sizes = [0, 1, 8, 31, 32, 33, 0x7FFF_FFFF, u32::MAX];
+
+        // Footer tag size too small for the footer or larger than the file
+        for size in [0, 1, 31, data.len() as u32 + 1, 0x7FFF_FFFF, u32::MAX] {
+            let mut bad = data.clone();
+            bad[footer + 12..footer + 16].copy_from_slice(&size.to_le_bytes());
+            assert!(read_ape_tag(&bad).is_none(), "tag size {size}");
+        }
+
+        // More items than the tag has room for (62 bytes hold at most 6)
+        for count in [7, 1000, u32::MAX] {
+            let mut bad = data.clone();
+            bad[footer + 16..footer + 20].copy_from_slice(&count.to_le_bytes());
+            assert!(read_ape_tag(&bad).is_none(), "item count {count}");
+        }
+
+        // Item value sizes running past the tag lose that item, not the tag
+        for size in sizes {
+            let mut bad = data.clone();
+            bad[first_item..first_item + 4].copy_from_slice(&size.to_le_bytes());
+            let read = read_ape_tag(&bad).unwrap();
+            assert!(read.items.len() <= 2, "value size {size}");
+        }
+
+        // Any of the fields set to any size, and any truncation, never panics
+        for field in [footer + 12, footer + 16, header + 12, first_item] {
+            for size in sizes {
+                let mut bad = data.clone();
+                bad[field..field + 4].copy_from_slice(&size.to_le_bytes());
+                let _ = read_ape_tag(&bad);
+            }
+        }
+        for len in 0..data.len() {
+            let _ = read_ape_tag(&data[..len]);
+            let _ = read_ape_tag(&data[len..]);
+        }
+    }
+
+    #[test]
+    fn test_is_adts_data() {
+        // Two silent AAC-LC stereo frames (16 bytes each)
+        let frame = [
+            0xFF, 0xF1, 0x50, 0x80, 0x02, 0x1F, 0xFC, 0x21, 0x00, 0x49, 0x90, 0x02, 0x19, 0x00,
+            0x23, 0x80,
+        ];
+        let mut data = frame.to_vec();
+        data.extend_from_slice(&frame);
+        assert!(is_adts_data(&data));
+
+        // MPEG1 Layer III header is not ADTS
+        assert!(!is_adts_data(&[
+            0xFF, 0xFB, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00
+        ]));
+
+        // Wrong frame length (no sync at declared end)
+        data[16] = 0x00;
+        assert!(!is_adts_data(&data));
+    }
+
+    #[test]
+    fn test_tag_diff() {
+        let mut old = ApeTag::new();
+        old.set("MP3GAIN_UNDO", "+002,+002,N");
+        old.set("MP3GAIN_MINMAX", "100,200");
+        old.set("ARTIST", "Someone");
+
+        let mut new = old.clone();
+        new.set("MP3GAIN_UNDO", "+005,+005,N");
+        new.remove("ARTIST");
+        new.set(TAG_REPLAYGAIN_TRACK_GAIN, "-3.00 dB");
+
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            vec![
+                TagChange::Changed {
+                    key: "MP3GAIN_UNDO".to_string(),
+                    old: "+002,+002,N".to_string(),
+                    new: "+005,+005,N".to_string(),
+                },
+                TagChange::Removed {
+                    key: "ARTIST".to_string(),
+                    value: "Someone".to_string(),
+                },
+                TagChange::Added {
+                    key: TAG_REPLAYGAIN_TRACK_GAIN.to_string(),
+                    value: "-3.00 dB".to_string(),
+                },
+            ]
+        );
+
+        // Identical tags and case-only key differences produce no changes
+        assert!(old.diff(&old.clone()).is_empty());
+        let lower = vec![("mp3gain_minmax".to_string(), "100,200".to_string())];
+        let upper = vec![("MP3GAIN_MINMAX".to_string(), "100,200".to_string())];
+        assert!(diff_tag_items(&lower, &upper).is_empty());
+    }
+
     #[test]
     fn test_parse_valid_header() {
         let header = [0xFF, 0xFB, 0x90, 0x00];
@@ -1399,6 +4517,360 @@ mod tests {
         assert_eq!(data[2], 0x9F);
     }
 
+    const ALL_LAYOUTS: [(MpegVersion, ChannelMode); 6] = [
+        (MpegVersion::Mpeg1, ChannelMode::Stereo),
+        (MpegVersion::Mpeg1, ChannelMode::Mono),
+        (MpegVersion::Mpeg2, ChannelMode::JointStereo),
+        (MpegVersion::Mpeg2, ChannelMode::Mono),
+        (MpegVersion::Mpeg25, ChannelMode::DualChannel),
+        (MpegVersion::Mpeg25, ChannelMode::Mono),
+    ];
+
+    #[test]
+    fn test_generated_frames_gain_locations() {
+        for (version, mode) in ALL_LAYOUTS {
+            let data = testgen::mp3_frames(version, mode, 10, 170);
+            let mut gains = Vec::new();
+            let scan = iterate_frames(&data, |_pos, header, locations| {
+                assert_eq!(header.version, version);
+                assert_eq!(header.channel_mode, mode);
+                gains.extend(locations.iter().map(|loc| read_gain_at(&data, loc)));
+            })
+            .unwrap();
+
+            let per_frame =
+                mode.channel_count() * if version == MpegVersion::Mpeg1 { 2 } else { 1 };
+            assert_eq!(scan.frame_count, 10, "{:?} {:?}", version, mode);
+            assert_eq!(scan.trailing_bytes, 0);
+            assert_eq!(gains, vec![170; 10 * per_frame], "{:?} {:?}", version, mode);
+        }
+    }
+
+    #[test]
+    fn test_generated_frames_apply_gain_touches_only_gain_bits() {
+        for (version, mode) in ALL_LAYOUTS {
+            let mut data = testgen::mp3_frames(version, mode, 5, 170);
+            assert_eq!(
+                apply_gain_to_data(&mut data, 5, GainMode::Saturating).frames,
+                5
+            );
+            assert_eq!(data, testgen::mp3_frames(version, mode, 5, 175));
+
+            let mut data = testgen::mp3_frames(version, mode, 5, 250);
+            apply_gain_to_data(&mut data, 10, GainMode::Wrapping);
+            assert_eq!(data, testgen::mp3_frames(version, mode, 5, 4));
+        }
+    }
+
+    #[test]
+    fn test_gain_location_counts_match_across_passes() {
+        for (version, mode) in ALL_LAYOUTS {
+            let per_frame =
+                mode.channel_count() * if version == MpegVersion::Mpeg1 { 2 } else { 1 };
+            let mut data = testgen::mp3_frames(version, mode, 7, 170);
+            let scan = iterate_frames(&data, |_, _, _| {}).unwrap();
+            assert_eq!(
+                scan.gain_locations,
+                7 * per_frame,
+                "{:?} {:?}",
+                version,
+                mode
+            );
+
+            let pass = apply_gain_to_data(&mut data, 2, GainMode::Saturating);
+            assert_eq!(pass.gain_locations, scan.gain_locations);
+            let pass = apply_gain_to_channels_data(&mut data, 1, -1);
+            assert_eq!(pass.gain_locations, scan.gain_locations);
+            assert!(check_gain_locations(scan.gain_locations, pass.gain_locations).is_ok());
+        }
+        assert!(check_gain_locations(14, 28).is_err());
+    }
+
+    #[test]
+    fn test_resync_across_long_corrupt_stretch() {
+        let audio = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 20, 170);
+        let gap = vec![0x55; MAX_RESYNC_BYTES + 2000];
+        let mut data = [&audio[..], &gap, &audio].concat();
+
+        let scan = iterate_frames(&data, |_, _, _| {}).unwrap();
+        assert_eq!(scan.frame_count, 40);
+        assert_eq!(scan.bad_frame_offsets, vec![audio.len()]);
+        assert_eq!(scan.trailing_bytes, 0);
+
+        let pass = apply_gain_to_data(&mut data, 2, GainMode::Saturating);
+        assert_eq!(pass.frames, 40);
+        assert_eq!(pass.bad_frames, 1);
+        assert_eq!(data[audio.len()..audio.len() + gap.len()], gap[..]);
+
+        // A short false run that far out is still trailing junk
+        let fake = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 2, 200);
+        let data = [&audio[..], &gap, &fake, &[0x55; 64]].concat();
+        let scan = iterate_frames(&data, |_, _, _| {}).unwrap();
+        assert_eq!(scan.frame_count, 20);
+        assert_eq!(scan.trailing_bytes, gap.len() + fake.len() + 64);
+    }
+
+    #[test]
+    fn test_scan_stops_at_trailing_tags() {
+        let audio = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 5, 170);
+        let fake = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 2, 200);
+
+        // An APEv2 tag whose value holds two frames, at the end of the file
+        // (stripped as a tag) or followed by junk (found by the scan)
+        let mut tag = ApeTag::new();
+        tag.set("Cover", &"x".repeat(fake.len()));
+        let mut ape = serialize_ape_tag(&tag);
+        let value = ape
+            .windows(fake.len())
+            .position(|w| w.iter().all(|&b| b == b'x'))
+            .unwrap();
+        ape[value..value + fake.len()].copy_from_slice(&fake);
+
+        // A Lyrics3 v2 block holding the same frames, before an ID3v1 tag
+        let mut lyrics = b"LYRICSBEGIN".to_vec();
+        lyrics.extend_from_slice(format!("LYR{:05}", fake.len()).as_bytes());
+        lyrics.extend_from_slice(&fake);
+        lyrics.extend_from_slice(format!("{:06}LYRICS200", lyrics.len()).as_bytes());
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0);
+
+        for (trailer, trailing_bytes) in [
+            ([&ape[..]].concat(), 0),
+            ([&ape[..], b"junk"].concat(), ape.len() + 4),
+            ([&lyrics[..], &id3v1].concat(), 0),
+            ([&ape[..], &lyrics, &id3v1].concat(), 0),
+            ([&lyrics[..], &ape, &id3v1].concat(), 0),
+        ] {
+            let mut data = [&audio[..], &trailer].concat();
+            let scan = iterate_frames(&data, |_, _, _| {}).unwrap();
+            assert_eq!(scan.frame_count, 5);
+            assert_eq!(scan.trailing_bytes, trailing_bytes);
+
+            // The frames inside the tags are left alone
+            assert_eq!(
+                apply_gain_to_data(&mut data, 2, GainMode::Saturating).frames,
+                5
+            );
+            assert_eq!(data[audio.len()..], trailer[..]);
+        }
+    }
+
+    #[test]
+    fn test_verify_frame_layout() {
+        let before = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 5, 170);
+        let mut after = before.clone();
+        apply_gain_to_data(&mut after, 2, GainMode::Saturating);
+        assert_ne!(after, before);
+        assert_eq!(verify_frame_layout(&before, &after).unwrap(), 5);
+
+        // A tag added after the frames is fine
+        let tagged = with_ape_tag(&after, &ApeTag::new());
+        assert_eq!(verify_frame_layout(&before, &tagged).unwrap(), 5);
+
+        // A write that breaks the sync word of the third frame is caught
+        let frame_size = parse_header(&before).unwrap().frame_size;
+        let mut corrupted = after.clone();
+        corrupted[2 * frame_size] = 0;
+        assert!(verify_frame_layout(&before, &corrupted).is_err());
+
+        // So is one that drops the last frame
+        assert!(verify_frame_layout(&before, &after[..4 * frame_size]).is_err());
+    }
+
+    #[test]
+    fn test_single_frame_streams() {
+        for (version, mode) in ALL_LAYOUTS {
+            let single = testgen::mp3_frames(version, mode, 1, 170);
+            let header = parse_header(&single).unwrap();
+            let side_info_end = header.side_info_offset() + header.side_info_len();
+
+            // A lone frame, with or without junk after it
+            for padding in [0, 2, 100] {
+                let mut data = single.clone();
+                data.resize(single.len() + padding, 0);
+                let scan = iterate_frames(&data, |_, _, _| {}).unwrap();
+                assert_eq!(scan.frame_count, 1, "{:?} {:?}", version, mode);
+                assert_eq!(scan.trailing_bytes, padding);
+
+                assert_eq!(
+                    apply_gain_to_data(&mut data, 2, GainMode::Saturating).frames,
+                    1
+                );
+                assert_eq!(
+                    data[..single.len()],
+                    testgen::mp3_frames(version, mode, 1, 172)
+                );
+                assert!(data[single.len()..].iter().all(|&b| b == 0));
+            }
+
+            // The last of several frames is kept when junk follows it
+            let mut data = testgen::mp3_frames(version, mode, 2, 170);
+            data.resize(data.len() + 100, 0);
+            assert_eq!(iterate_frames(&data, |_, _, _| {}).unwrap().frame_count, 2);
+
+            // A frame cut short is rejected, even inside its side information
+            for len in [side_info_end - 1, side_info_end, single.len() - 1] {
+                let scan = iterate_frames(&single[..len], |_, _, _| {}).unwrap();
+                assert_eq!(scan.frame_count, 0, "{:?} {:?} len {}", version, mode, len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_repeated_undo_apply_keeps_minmax_without_rescanning() {
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_lib_undo_minmax_{}.mp3",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 10, 170),
+        )
+        .unwrap();
+        let scans = || ANALYZE_CALLS.with(|calls| calls.get());
+
+        // The first application records the baseline and has to scan for it
+        let before = scans();
+        assert_eq!(apply_gain_with_undo(&path, 2).unwrap(), 10);
+        assert_eq!(scans(), before + 1);
+        let minmax = read_ape_tag_from_file(&path).unwrap().unwrap().get_minmax();
+        assert_eq!(minmax, Some((170, 170)));
+
+        // Later ones keep it and only make the gain pass
+        let before = scans();
+        assert_eq!(apply_gain_with_undo(&path, 3).unwrap(), 10);
+        preview_undo_tag(&path, 1, false).unwrap();
+        assert_eq!(scans(), before);
+
+        let tag = read_ape_tag_from_file(&path).unwrap().unwrap();
+        assert_eq!(tag.get_minmax(), minmax);
+        assert_eq!(tag.get_undo_gain(), Some(5));
+        assert_eq!(analyze(&path).unwrap().max_gain, 175);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_tag_inspection_skips_audio() {
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_lib_tag_regions_{}.mp3",
+            std::process::id()
+        ));
+        let audio = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 3000, 170);
+        let mut data = id3v2::with_txxx(&audio, &[], &[(TAG_MP3GAIN_UNDO, "+001,+001,N")]).unwrap();
+        let mut ape = ApeTag::new();
+        ape.set(TAG_REPLAYGAIN_TRACK_GAIN, "-3.00 dB");
+        data = with_ape_tag(&data, &ape);
+        data.extend_from_slice(b"TAG"); // ID3v1 after APEv2
+        data.resize(data.len() + 125, 0);
+        fs::write(&path, &data).unwrap();
+        let bytes_read = || BYTES_READ.with(|bytes| bytes.get());
+
+        let before = bytes_read();
+        let tag = read_gain_tag(&path).unwrap().unwrap();
+        assert!(
+            bytes_read() - before < 1024,
+            "{} bytes",
+            bytes_read() - before
+        );
+        assert_eq!(tag.get(TAG_REPLAYGAIN_TRACK_GAIN), Some("-3.00 dB"));
+        assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+001,+001,N"));
+        assert_eq!(
+            tag.items().len(),
+            gain_tag_from_data(&data).unwrap().items().len()
+        );
+        assert_eq!(
+            stored_gain_tag_format(&path).unwrap(),
+            Some(TagFormat::Both)
+        );
+
+        let before = bytes_read();
+        let ape = read_ape_tag_from_file(&path).unwrap().unwrap();
+        assert!(bytes_read() - before < 512);
+        assert_eq!(ape.items().len(), 1);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_apply_gain_leaves_large_id3v2_tag_on_disk() {
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_lib_large_apic_{}.mp3",
+            std::process::id()
+        ));
+
+        // ID3v2.3 tag with a 5 MB APIC frame followed by a TXXX frame
+        let mut apic = b"\0image/jpeg\0\x03\0".to_vec();
+        apic.extend((0..5 * 1024 * 1024).map(|i| (i * 7) as u8));
+        let mut frame = b"APIC".to_vec();
+        frame.extend_from_slice(&(apic.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&apic);
+        let txxx = b"\0REPLAYGAIN_ALBUM_GAIN\0-1.00 dB";
+        frame.extend_from_slice(b"TXXX");
+        frame.extend_from_slice(&(txxx.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(txxx);
+        let size = frame.len();
+        let mut tag = b"ID3\x03\0\0".to_vec();
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8));
+        tag.extend_from_slice(&frame);
+
+        let audio = testgen::mp3_frames(MpegVersion::Mpeg1, ChannelMode::Stereo, 100, 170);
+        let mut ape = ApeTag::new();
+        ape.set(TAG_REPLAYGAIN_TRACK_GAIN, "-3.00 dB");
+        let data = with_ape_tag(&[tag.as_slice(), &audio].concat(), &ape);
+        fs::write(&path, &data).unwrap();
+        let bytes_read = || BYTES_READ.with(|bytes| bytes.get());
+
+        // The TXXX frame after the album art is found without reading the art
+        let before = bytes_read();
+        let gain_tag = read_gain_tag(&path).unwrap().unwrap();
+        assert_eq!(gain_tag.get(TAG_REPLAYGAIN_ALBUM_GAIN), Some("-1.00 dB"));
+        assert!(bytes_read() - before < 1024);
+
+        // Two gain passes and the mono check of apply_gain_channels
+        let before = bytes_read();
+        assert_eq!(apply_gain(&path, -2).unwrap(), 100);
+        assert_eq!(apply_gain_channels(&path, 1, -1).unwrap(), 100);
+        assert!(
+            bytes_read() - before < 3 * audio.len() as u64 + 1024,
+            "{} bytes",
+            bytes_read() - before
+        );
+
+        let result = fs::read(&path).unwrap();
+        assert_eq!(result.len(), data.len());
+        assert_eq!(result[..tag.len()], tag[..]);
+        assert_eq!(
+            result[tag.len() + audio.len()..],
+            data[tag.len() + audio.len()..]
+        );
+        let analysis = analyze(&path).unwrap();
+        assert_eq!((analysis.min_gain, analysis.max_gain), (167, 169));
+
+        // Recording undo information reads the audio and the APEv2 tag, and
+        // rewrites only the latter
+        let before = bytes_read();
+        assert_eq!(apply_gain_with_undo(&path, 1).unwrap(), 100);
+        assert_eq!(apply_gain_channels_with_undo(&path, -1, 1).unwrap(), 100);
+        assert!(
+            bytes_read() - before < 6 * audio.len() as u64 + 4096,
+            "{} bytes",
+            bytes_read() - before
+        );
+
+        let result = fs::read(&path).unwrap();
+        assert_eq!(result[..tag.len()], tag[..]);
+        let ape = read_ape_tag_from_file(&path).unwrap().unwrap();
+        assert_eq!(ape.get(TAG_REPLAYGAIN_TRACK_GAIN), Some("-3.00 dB"));
+        assert_eq!(ape.get_undo_gains(), Some((0, 2)));
+        let analysis = analyze(&path).unwrap();
+        assert_eq!((analysis.min_gain, analysis.max_gain), (169, 169));
+
+        let _ = fs::remove_file(&path);
+    }
+
     #[test]
     fn test_skip_id3v2() {
         let data_no_tag = vec![0xFF, 0xFB, 0x90, 0x00];
@@ -1408,6 +4880,22 @@ mod tests {
         assert_eq!(skip_id3v2(&data_with_tag), 10);
     }
 
+    #[test]
+    fn test_skip_id3v2_footer() {
+        // ID3v2.4 with footer flag: header + 20 bytes of frames + "3DI" footer
+        let mut data = vec![b'I', b'D', b'3', 0x04, 0x00, 0x10, 0x00, 0x00, 0x00, 20];
+        data.extend_from_slice(&[0u8; 20]);
+        data.extend_from_slice(&[b'3', b'D', b'I', 0x04, 0x00, 0x10, 0x00, 0x00, 0x00, 20]);
+        data.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        assert_eq!(skip_id3v2(&data), 40);
+        assert!(parse_header(&data[skip_id3v2(&data)..]).is_some());
+
+        // The footer flag is only defined for ID3v2.4
+        let mut v3 = data.clone();
+        v3[3] = 0x03;
+        assert_eq!(skip_id3v2(&v3), 30);
+    }
+
     #[test]
     fn test_is_xing_frame() {
         // Create a minimal frame with Xing header for MPEG1 stereo
@@ -3,32 +3,113 @@
 //!
 //! Command-line interface compatible with the original mp3gain.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use colored::*;
 use indicatif::{ProgressBar, ProgressStyle};
+use mp3rgain::cache::AnalysisCache;
+use mp3rgain::flac;
+use mp3rgain::id3v2;
+use mp3rgain::journal::{self, FileState, Recording};
 use mp3rgain::mp4meta;
-use mp3rgain::replaygain::{self, AudioFileType, ReplayGainResult, REPLAYGAIN_REFERENCE_DB};
+use mp3rgain::replaygain::{
+    self, AlbumGainResult, AnalysisMethod, AudioFileType, Downmix, ReplayGainResult, SkipRegions,
+    TrackAnalysis, REPLAYGAIN_REFERENCE_DB,
+};
 use mp3rgain::{
-    analyze, apply_gain, apply_gain_channel_with_undo, apply_gain_with_undo,
-    apply_gain_with_undo_wrap, apply_gain_wrap, db_to_steps, delete_ape_tag, find_max_amplitude,
-    read_ape_tag_from_file, steps_to_db, undo_gain, Channel, GAIN_STEP_DB, TAG_MP3GAIN_MINMAX,
-    TAG_MP3GAIN_UNDO, TAG_REPLAYGAIN_ALBUM_GAIN, TAG_REPLAYGAIN_ALBUM_PEAK,
+    analyze, apply_gain_channels, apply_gain_channels_with_undo, apply_gain_floor, apply_gain_ramp,
+    apply_gain_with_undo_floor, apply_gain_with_undo_wrap, apply_gain_wrap, avg_target_steps,
+    convert_gain_tag, db_to_steps, delete_ape_tag, detect_audio_type, diff_tag_items,
+    find_max_amplitude, frame_gains, frame_headers, gain_provenance, gain_would_change,
+    global_gain_shift_db, has_supported_extension, is_adts_file, is_transient_io_error,
+    measure_max_amplitude, normalize_undo_tag, peak_target_steps_heuristic, preview_gain,
+    preview_minmax_tag, preview_undo_tag, quantization_error_db, read_ape_tag_from_file,
+    read_gain_tag, read_info_header, redo_gain, retry_io, steps_to_db, stored_gain_tag_format,
+    tag_keys::is_gain_key, undo_gain, undo_gain_keep_redo, undo_last_gain, verify_frame_layout,
+    write_ape_tag, write_error, write_gain_tag, write_minmax_tag, ApeTag, CancellationToken,
+    Channel, FileSnapshot, FrameHeaderInfo, InfoHeader, Interrupted, Mp3Analysis, Provenance,
+    TagChange, TagFormat, GAIN_STEP_DB, GAIN_TAG_KEYS, TAG_MP3GAIN_ALBUM_MINMAX,
+    TAG_MP3GAIN_MINMAX, TAG_MP3GAIN_REDO, TAG_MP3GAIN_UNDO, TAG_MP3GAIN_UNDO_STACK,
+    TAG_REPLAYGAIN_ALBUM_GAIN, TAG_REPLAYGAIN_ALBUM_PEAK, TAG_REPLAYGAIN_REFERENCE_LOUDNESS,
     TAG_REPLAYGAIN_TRACK_GAIN, TAG_REPLAYGAIN_TRACK_PEAK,
 };
 use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Default --progress-threshold: fewer files than this show no progress bar
 const PROGRESS_THRESHOLD: usize = 5;
+/// Default --max-steps limit for fixed gains (96 dB)
+const DEFAULT_MAX_STEPS: i32 = 64;
+/// First --retry delay; doubles after each attempt, up to 2 seconds
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+/// Largest --retry count (about half a minute of waiting per file)
+const MAX_RETRIES: u32 = 20;
+
+/// --path-style, set once the options are parsed; unset, text and TSV show
+/// file names and JSON shows paths as given
+static PATH_STYLE: OnceLock<PathStyle> = OnceLock::new();
+
+/// A file as shown in text and TSV output: its name, returning "unknown" if
+/// extraction fails, unless --path-style says otherwise
+fn get_filename(path: &Path) -> Cow<'_, str> {
+    render_path(
+        path,
+        PATH_STYLE.get().copied().unwrap_or(PathStyle::Basename),
+    )
+}
+
+/// A file as shown in JSON output and reports: its path as given, unless
+/// --path-style says otherwise
+fn get_path(path: &Path) -> String {
+    render_path(path, PATH_STYLE.get().copied().unwrap_or(PathStyle::Full)).into_owned()
+}
 
-/// Extract filename from path, returning "unknown" if extraction fails
-fn get_filename(path: &Path) -> &str {
-    path.file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("unknown")
+fn render_path(path: &Path, style: PathStyle) -> Cow<'_, str> {
+    match style {
+        PathStyle::Full => path.to_string_lossy(),
+        PathStyle::Relative => Cow::Owned(relative_path(path).display().to_string()),
+        PathStyle::Basename => Cow::Borrowed(
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("unknown"),
+        ),
+    }
+}
+
+/// `path` relative to the current directory, with `..` where it lies
+/// outside; paths on another root (Windows drive) are returned absolute
+fn relative_path(path: &Path) -> PathBuf {
+    let Ok(cwd) = env::current_dir() else {
+        return path.to_path_buf();
+    };
+    let Ok(path) = fs::canonicalize(path).or_else(|_| std::path::absolute(path)) else {
+        return path.to_path_buf();
+    };
+    let common = path
+        .components()
+        .zip(cwd.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path;
+    }
+    let mut relative: PathBuf = cwd
+        .components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .collect();
+    relative.extend(path.components().skip(common));
+    if relative.as_os_str().is_empty() {
+        relative.push(Component::CurDir);
+    }
+    relative
 }
 
 // =============================================================================
@@ -49,10 +130,54 @@ enum StoredTagMode {
     None, // Default behavior
     Check,    // -s c: Check/show stored tag info
     Delete,   // -s d: Delete stored tag info
-    Skip,     // -s s: Skip (ignore) stored tag info
-    Recalc,   // -s r: Force recalculation
-    UseId3v2, // -s i: Use ID3v2 tags (not fully implemented, show warning)
-    UseApev2, // -s a: Use APEv2 tags (default)
+    Skip,     // -s s: Skip (ignore) stored tag info and analyses; write no tags
+    Recalc,   // -s r: Force recalculation (ignore cached analyses, refresh them)
+    UseId3v2, // -s i: Use ID3v2 tags (same as --tag-format id3v2)
+    UseApev2, // -s a: Use APEv2 tags (default, same as --tag-format ape)
+}
+
+/// When to show the progress bar (--progress)
+#[derive(Default, Clone, Copy, PartialEq)]
+enum ProgressMode {
+    #[default]
+    Auto, // Text output with at least --progress-threshold files
+    Always, // Any output and number of files, unless -q
+    Never,
+}
+
+/// How files are named in output (--path-style)
+#[derive(Clone, Copy, PartialEq)]
+enum PathStyle {
+    Full,     // As given on the command line (JSON default)
+    Relative, // Relative to the current directory
+    Basename, // File name only (text and TSV default)
+}
+
+/// Order files are processed in (--sort)
+#[derive(Clone, Copy, PartialEq)]
+enum SortOrder {
+    Name,  // File name, then path
+    Path,  // Whole path
+    Mtime, // Modification time, oldest first
+    None,  // As given, and as found in directories with -R
+}
+
+/// Which track's loudness --match levels the others to
+#[derive(Clone, Copy, PartialEq)]
+enum MatchMode {
+    Quietest,
+    Loudest,
+    Median,
+}
+
+impl MatchMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MatchMode::Quietest => "quietest",
+            MatchMode::Loudest => "loudest",
+            MatchMode::Median => "median",
+        }
+    }
 }
 
 /// Album gain info for AAC files
@@ -64,34 +189,111 @@ struct AacAlbumInfo {
 #[derive(Default)]
 struct Options {
     // Gain options
-    gain_steps: Option<i32>,              // -g <i>
-    gain_modifier_db: f64,                // -d <n>: modify suggested dB gain (mp3gain compatible)
-    channel_gain: Option<(Channel, i32)>, // -l <channel> <gain>
-    gain_modifier: i32,                   // -m <i>: modify suggested gain by integer steps
+    gain_steps: Option<i32>,            // -g <i>
+    gain_modifier_db: f64,              // -d <n>: modify suggested dB gain (mp3gain compatible)
+    channel_gain: Option<(i32, i32)>,   // -l <channel> <gain>, repeatable: (left, right)
+    ramp: Option<GainRamp>,             // --ramp <startf>:<endf>:<startsteps>:<endsteps>
+    gain_modifier: i32,                 // -m <i>: modify suggested gain by integer steps
+    avg_target: Option<u8>,             // --avg-target <n>: shift average global_gain to n
+    peak_target_heuristic: Option<f64>, // --peak-normalize-heuristic <dbfs>: estimated peak target
+    apply_rva2: bool,                   // --apply-rva2: apply the ID3v2 RVA2 track adjustment
+    match_mode: Option<MatchMode>,      // --match <mode>: level all files to one track's loudness
+    /// Apply the stored REPLAYGAIN_TRACK_GAIN (--apply-replaygain-tag)
+    apply_rg_tag: bool,
 
     // Mode options
-    undo: bool,                     // -u
-    stored_tag_mode: StoredTagMode, // -s <mode>
-    track_gain: bool,               // -r (apply track gain)
-    album_gain: bool,               // -a (apply album gain)
-    skip_album: bool,               // -e: skip album analysis
-    max_amplitude_only: bool,       // -x: only find max amplitude
-    track_index: Option<u32>,       // -i <index>: track index for multi-track files
+    undo: bool,                   // -u
+    undo_last: bool,              // --last: -u reverses only the most recent adjustment
+    keep_redo: bool,              // --keep-redo: -u keeps the undone gain as MP3GAIN_REDO
+    redo: bool,                   // --redo: re-apply the gain the last -u took back
+    write_minmax: bool,           // --write-minmax: record MP3GAIN_MINMAX only
+    album_gain_db: Option<f64>,   // --album-gain-db <n>: write this album gain, no analysis
+    album_peak: Option<f64>,      // --album-peak <p>: album peak to write with it
+    tag_format: TagFormat,        // --tag-format <ape|id3v2|both>: where MP3 gain tags go
+    track_gain: bool,             // -r (apply track gain)
+    album_gain: bool,             // -a (apply album gain)
+    skip_album: bool,             // -e: skip album analysis
+    album_clip_safe: bool,        // --album-clip-safe: lower only tracks the album gain clips
+    album_clip_check: bool,       // --album-clip-check: lower the album gain until no track clips
+    max_amplitude_only: bool,     // -x: only find max amplitude
+    decode_peak: bool,            // --decode: with -x, require a decoded (exact) peak
+    track_index: Option<u32>,     // -i <index>: track index for multi-track files
+    tags_only: bool,              // --tags-only: write ReplayGain tags, leave MP3 frames alone
+    true_peak_tags: bool,         // --true-peak-tags: true peaks in ReplayGain peak tags
+    tag_even_if_zero: bool,       // --tag-even-if-zero: write tags when the gain is 0 steps
+    rva2: bool,                   // --rva2: like --tags-only, but as ID3v2 RVA2 frames for MP3
+    trim_silence: bool,           // --trim-silence: leave silent windows out of the loudness
+    skip: SkipRegions,            // --skip-intro/--skip-outro <s>: regions left out of the loudness
+    best_effort: bool,            // --best-effort: analyze the decodable part of damaged files
+    show_continuous: bool,        // --show-continuous: exact dB next to the quantized steps
+    dump_frames: bool,            // --dump-frames: print per-frame gain values (debug)
+    list_frames_json: bool,       // --list-frames-json: describe every frame of one file as JSON
+    list_formats: bool,           // --list-formats: print the operations supported per format
+    explain: bool,                // --explain: describe what -g/-d do instead of applying them
+    frame_limit: Option<usize>,   // --limit <n>: frames listed by --list-frames-json
+    gain_histogram: bool,         // --gain-histogram: print the distribution of global_gain values
+    compare_tools: bool,          // --compare-tools: frame estimate against decoded analysis
+    check_clipping: bool,         // --check-clipping: exit 2 if any track gain would clip
+    rg_debug: bool,               // --rg-debug: print the ReplayGain analysis parameters
+    doctor: bool,                 // --doctor: run diagnostic checks on each file
+    measure: bool,                // --measure: only print loudness and peak
+    cache: Option<AnalysisCache>, // --cache <dir>: reuse ReplayGain analyses of unchanged files
+    /// Rewrite gain tags canonically (--normalize-tags/--canonicalize-tags)
+    normalize_tags: bool,
+    /// What to do with stored tags and cached analyses (-s <mode>)
+    stored_tag_mode: StoredTagMode,
+    /// Channel layout the loudness is measured in (--downmix <mono|stereo|none>)
+    downmix: Downmix,
+    /// With -r, analyze all files as one stream, one gain for all (--as-one)
+    as_one: bool,
 
     // Behavior options
-    preserve_timestamp: bool,    // -p
-    ignore_clipping: bool,       // -c
-    prevent_clipping: bool,      // -k
-    quiet: bool,                 // -q
-    recursive: bool,             // -R
-    dry_run: bool,               // -n or --dry-run
-    output_format: OutputFormat, // -o <format>
-    wrap_gain: bool,             // -w: wrap gain values
-    use_temp_file: bool,         // -t: use temp file for writing
-    assume_mpeg2: bool,          // -f: assume MPEG 2 Layer III
+    preserve_timestamp: bool,      // -p
+    preserve_all: bool,            // --preserve-all: -p plus file mode and owner
+    ignore_clipping: bool,         // -c
+    prevent_clipping: bool,        // -k
+    quiet: bool,                   // -q
+    quiet_errors: bool,            // --quiet-errors: count per-file errors without printing them
+    progress: ProgressMode,        // --progress <auto|always|never>
+    retries: u32,                  // --retry <n>: retry opening/replacing a busy or locked file
+    skip_locked: bool,             // --skip-locked: skip files that stay locked instead of failing
+    recursive: bool,               // -R
+    dry_run: bool,                 // -n or --dry-run
+    estimate: bool,                // --estimate: project the batch's run time from a few files
+    output_format: OutputFormat,   // -o <format>
+    json_compact: bool,            // --json-compact or -o json-compact: minified JSON
+    path_style: Option<PathStyle>, // --path-style <full|relative|basename>
+    legacy_output: bool,           // --legacy-output: analysis in original mp3gain's text layout
+    wrap_gain: bool,               // -w: wrap gain values
+    min_floor: u8,                 // --min-floor <n>: negative gain stops at this global_gain
+    use_temp_file: bool,           // -t: use temp file for writing
+    in_place_truncate: bool,       // --in-place-truncate: with -t, copy back instead of rename
+    temp_dir: Option<PathBuf>,     // --temp-dir <dir>: where -t puts temp files (implies -t)
+    verify: bool,                  // --verify: re-parse the frames after writing
+    follow_symlinks: bool,         // --follow-symlinks: follow symlinked directories when recursing
+    since: Option<SystemTime>,     // --since <time>: with -R, only files modified after it
+    sort: Option<SortOrder>,       // --sort <name|path|mtime|none>: order files are processed in
+    natural_sort: bool,            // --natural-sort: track2 before track10 in --sort name/path
+    max_file_size: Option<u64>,    // --max-file-size <size>: skip larger files
+    assume_mpeg2: bool,            // -f: assume MPEG 2 Layer III
+    max_steps: Option<i32>,        // --max-steps <n>: refuse larger fixed gains (default 64)
+    force: bool,                   // --force: allow gains beyond --max-steps
+    stats: bool,                   // --stats: report frames, bytes, time and throughput
+    sidecar: bool,                 // --sidecar: write <file>.rgain.json after processing each file
+    journal: Option<PathBuf>,      // --journal <path>: append an NDJSON audit record per file
+    hash: bool,                    // --hash: report each file's SHA-256 before and after
+    /// Files needed for an auto bar (--progress-threshold <n>)
+    progress_threshold: Option<usize>,
+    /// Per-file maximum gain steps (--ceiling-from <file>)
+    ceilings: Option<BTreeMap<PathBuf, i32>>,
+    /// Don't write files the operation would not alter (--skip-unchanged)
+    skip_unchanged: bool,
 
     // Files
     files: Vec<PathBuf>,
+    groups: Vec<Vec<PathBuf>>, // --group <files...>: album boundaries for -a
+    /// With -a -R, one album per directory n levels down (--album-depth <n>)
+    album_depth: Option<usize>,
 }
 
 // =============================================================================
@@ -104,8 +306,13 @@ struct JsonOutput {
     files: Option<Vec<JsonFileResult>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     album: Option<JsonAlbumResult>,
+    /// One entry per --group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    albums: Option<Vec<JsonAlbumResult>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     summary: Option<JsonSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<JsonStats>,
 }
 
 #[derive(Serialize, Clone, Default)]
@@ -116,35 +323,180 @@ struct JsonFileResult {
     #[serde(skip_serializing_if = "Option::is_none")]
     frames: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     mpeg_version: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     channel_mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channels: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaygain_track_gain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaygain_track_peak: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaygain_album_gain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaygain_album_peak: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     min_gain: Option<u8>,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_gain: Option<u8>,
     #[serde(skip_serializing_if = "Option::is_none")]
     avg_gain: Option<f64>,
+    /// --gain-histogram: global_gain fields counted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gain_locations: Option<usize>,
+    /// --gain-histogram: count of each global_gain value, indexed by value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gain_histogram: Option<Vec<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compare_tools: Option<JsonToolComparison>,
+    /// --check-clipping: the track gain would push the peak over full scale
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clips: Option<bool>,
+    /// --rg-debug: filter, window and percentile behind the loudness
+    #[serde(skip_serializing_if = "Option::is_none")]
+    analysis_params: Option<replaygain::AnalysisParams>,
+    /// --rg-debug: algorithm, constants and version the loudness comes from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaygain_meta: Option<replaygain::AlgorithmInfo>,
+    /// Where -s c found gain tags: "ape", "id3v2" or "both"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag_format: Option<String>,
+    /// -s c: "mp3rgain", "mp3gain", "replaygain" or "untagged"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance: Option<String>,
+    /// -s c: where those tags are: "ape", "id3v2", "both", "mp4" or "vorbis"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance_container: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rva2_gain_db: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rva2_peak: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lame_track_gain_db: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lame_album_gain_db: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lame_peak: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     headroom_steps: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     headroom_db: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    leading_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id3v2_gap: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trailing_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bad_frames: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bad_frame_offsets: Option<Vec<usize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     gain_applied_steps: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     gain_applied_db: Option<f64>,
+    /// -n: global_gain statistics the file would have after the gain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projected_min_gain: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projected_max_gain: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projected_avg_gain: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projected_headroom: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    left_gain_steps: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    right_gain_steps: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggested_gain_steps: Option<i32>,
+    /// --show-continuous: exact ReplayGain dB before rounding to steps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requested_gain_db: Option<f64>,
+    /// --show-continuous: gain_applied_db - requested_gain_db
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quantization_error_db: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     loudness_db: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partial: Option<bool>, // --best-effort left damaged packets out
+    #[serde(skip_serializing_if = "Option::is_none")]
     peak: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_amplitude: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    warning: Option<String>,
+    #[serde(flatten)]
+    warnings: Warnings,
     #[serde(skip_serializing_if = "Option::is_none")]
     dry_run: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag_changes: Option<Vec<JsonTagChange>>,
+    /// -s d removes the whole APEv2 tag (nothing but gain keys in it)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ape_tag_removed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<JsonStats>,
+    /// --hash: SHA-256 of the file before the operation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256_before: Option<String>,
+    /// --hash: SHA-256 afterwards (not in dry-run mode)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256_after: Option<String>,
+}
+
+/// Warnings of one file, serialized as a `warnings` array plus the older
+/// `warning` string holding them all joined by "; "
+#[derive(Clone, Default)]
+struct Warnings(Vec<String>);
+
+impl Warnings {
+    fn push(&mut self, msg: impl Into<String>) {
+        self.0.push(msg.into());
+    }
+}
+
+impl From<String> for Warnings {
+    fn from(msg: String) -> Self {
+        Warnings(vec![msg])
+    }
+}
+
+impl From<Option<String>> for Warnings {
+    fn from(msg: Option<String>) -> Self {
+        Warnings(msg.into_iter().collect())
+    }
+}
+
+impl Serialize for Warnings {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(None)?;
+        if !self.0.is_empty() {
+            map.serialize_entry("warning", &self.0.join("; "))?;
+            map.serialize_entry("warnings", &self.0)?;
+        }
+        map.end()
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct JsonTagChange {
+    action: &'static str,
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -153,6 +505,240 @@ struct JsonAlbumResult {
     gain_db: f64,
     gain_steps: i32,
     peak: f64,
+    /// --show-continuous: gain_steps in dB - gain_db
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quantization_error_db: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    /// Dry run: tracks the album gain would clip
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clipping: Option<JsonClippingReport>,
+    /// --album-clip-check: how the album gain was verified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clip_check: Option<JsonAlbumClipCheck>,
+    /// Per-file results of this album (with --group)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<JsonFileResult>>,
+}
+
+/// --album-clip-check result: the album gain after re-checking every track
+#[derive(Serialize)]
+struct JsonAlbumClipCheck {
+    iterations: u32,
+    gain_steps: i32,
+    gain_db: f64,
+    clip_free: bool,
+}
+
+/// Dry-run clipping pre-check of an album gain
+#[derive(Serialize)]
+struct JsonClippingReport {
+    tracks: Vec<JsonClippingTrack>,
+    /// Largest album gain that clips no track (what -k would apply)
+    safe_gain_steps: i32,
+    safe_gain_db: f64,
+}
+
+#[derive(Serialize)]
+struct JsonClippingTrack {
+    file: String,
+    peak: f64,
+    /// How far the peak would go above full scale
+    over_db: f64,
+}
+
+/// --match output
+#[derive(Serialize)]
+struct JsonMatchOutput {
+    target: JsonMatchTarget,
+    files: Vec<JsonFileResult>,
+    summary: JsonSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<JsonStats>,
+}
+
+#[derive(Serialize)]
+struct JsonMatchTarget {
+    /// "quietest", "loudest" or "median"
+    mode: &'static str,
+    loudness_db: f64,
+}
+
+/// --doctor output
+#[derive(Serialize)]
+struct JsonDoctorOutput {
+    version: &'static str,
+    files: Vec<JsonDoctorReport>,
+}
+
+/// --doctor report for one file
+#[derive(Serialize)]
+struct JsonDoctorReport {
+    file: String,
+    checks: Vec<JsonDoctorCheck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames: Option<Vec<JsonDoctorFrame>>,
+}
+
+#[derive(Serialize)]
+struct JsonDoctorCheck {
+    name: &'static str,
+    /// "ok", "warning", "failed" or "skipped"
+    status: &'static str,
+    detail: String,
+}
+
+#[derive(Serialize)]
+struct JsonDoctorFrame {
+    offset: usize,
+    mpeg_version: String,
+    channel_mode: String,
+    bitrate_kbps: u32,
+    sample_rate: u32,
+    frame_size: usize,
+    crc: bool,
+    info_frame: bool,
+}
+
+impl From<FrameHeaderInfo> for JsonDoctorFrame {
+    fn from(h: FrameHeaderInfo) -> Self {
+        JsonDoctorFrame {
+            offset: h.offset,
+            mpeg_version: h.mpeg_version,
+            channel_mode: h.channel_mode,
+            bitrate_kbps: h.bitrate_kbps,
+            sample_rate: h.sample_rate,
+            frame_size: h.frame_size,
+            crc: h.has_crc,
+            info_frame: h.is_info_frame,
+        }
+    }
+}
+
+/// --list-frames-json output
+#[derive(Serialize)]
+struct JsonFrameList {
+    file: String,
+    /// Frames in the file, Xing/Info frames included
+    total_frames: usize,
+    /// The first --limit of them
+    frames: Vec<JsonFrame>,
+}
+
+#[derive(Serialize)]
+struct JsonFrame {
+    #[serde(flatten)]
+    header: JsonDoctorFrame,
+    /// global_gain per granule, each holding one value per channel; `None`
+    /// for frames gain changes leave alone (Xing/Info)
+    global_gain: Option<Vec<Vec<u8>>>,
+}
+
+/// --estimate output
+#[derive(Serialize)]
+struct JsonEstimateOutput {
+    /// The option selecting the estimated action ("" for analysis)
+    action: &'static str,
+    /// Whether the action decodes audio (slow) or only touches MP3 frames
+    decodes: bool,
+    sampled_files: usize,
+    mb_per_sec: f64,
+    total_bytes: u64,
+    total_seconds: f64,
+    files: Vec<JsonEstimateFile>,
+}
+
+#[derive(Serialize)]
+struct JsonEstimateFile {
+    file: String,
+    bytes: u64,
+    seconds: f64,
+    sampled: bool,
+}
+
+/// --sidecar report: one file's result with version and time of the run
+#[derive(Serialize)]
+struct JsonSidecar<'a> {
+    schema_version: u32,
+    tool_version: &'static str,
+    timestamp: String,
+    #[serde(flatten)]
+    result: &'a JsonFileResult,
+}
+
+/// --journal record: one processed file, with its state before and after
+#[derive(Serialize)]
+struct JsonJournalRecord<'a> {
+    timestamp: String,
+    tool_version: &'static str,
+    operation: &'static str,
+    file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gain_applied_steps: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gain_applied_db: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    /// None if the file could not be read
+    before: Option<FileState>,
+    after: Option<FileState>,
+}
+
+/// --stats figures for one file (`files` is omitted) or the whole run
+#[derive(Serialize, Clone)]
+struct JsonStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<usize>,
+    frames: usize,
+    bytes_read: u64,
+    bytes_written: u64,
+    seconds: f64,
+    mb_per_sec: f64,
+}
+
+/// --compare-tools: the frame-domain estimate, the decoded analysis and how
+/// far apart they are
+#[derive(Serialize, Clone)]
+struct JsonToolComparison {
+    heuristic: JsonLevels,
+    decoded: JsonLevels,
+    /// Heuristic minus decoded
+    delta: JsonLevels,
+}
+
+#[derive(Serialize, Clone)]
+struct JsonLevels {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peak: Option<f64>,
+    peak_dbfs: f64,
+    loudness_db: f64,
+    headroom_steps: i32,
+}
+
+impl JsonLevels {
+    fn of(result: &ReplayGainResult) -> Self {
+        let peak_dbfs = 20.0 * result.peak.log10();
+        JsonLevels {
+            peak: Some(result.peak),
+            peak_dbfs,
+            loudness_db: result.loudness_db,
+            // Whole steps before the peak reaches full scale
+            headroom_steps: (-peak_dbfs / GAIN_STEP_DB + 1e-9)
+                .floor()
+                .clamp(-255.0, 255.0) as i32,
+        }
+    }
+
+    fn minus(&self, other: &Self) -> Self {
+        JsonLevels {
+            peak: None,
+            peak_dbfs: self.peak_dbfs - other.peak_dbfs,
+            loudness_db: self.loudness_db - other.loudness_db,
+            headroom_steps: self.headroom_steps - other.headroom_steps,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -161,9 +747,59 @@ struct JsonSummary {
     successful: usize,
     failed: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
+    locked: Option<usize>, // files skipped with --skip-locked
+    #[serde(skip_serializing_if = "Option::is_none")]
     dry_run: Option<bool>,
 }
 
+#[derive(Serialize)]
+struct JsonVersion {
+    version: &'static str,
+    features: JsonFeatures,
+    step_db: f64,
+    mpeg_versions: &'static [&'static str],
+    supported_sample_rates: &'static [u32],
+}
+
+#[derive(Serialize)]
+struct JsonFormatList {
+    formats: Vec<JsonFormat>,
+}
+
+#[derive(Serialize)]
+struct JsonFormat {
+    format: &'static str,
+    extensions: &'static [&'static str],
+    frame_gain: bool,
+    /// Gains only go into tags; the audio is never changed
+    tag_only: bool,
+    tag_gain: &'static str,
+    replaygain_analysis: bool,
+    undo: bool,
+    channel_gain: bool,
+}
+
+#[derive(Serialize)]
+struct JsonExplanation {
+    option: String,
+    /// Exact dB -g applies; absent for -d, whose gain depends on analysis
+    #[serde(skip_serializing_if = "Option::is_none")]
+    applied_db: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_db: Option<f64>,
+    explanation: String,
+}
+
+#[derive(Serialize)]
+struct JsonFeatures {
+    replaygain: bool,
+    aac: bool,
+    flac: bool,
+    mmap: bool,
+    parallel: bool,
+    id3v2: bool,
+}
+
 // =============================================================================
 // Main
 // =============================================================================
@@ -176,8 +812,40 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let opts = parse_args(&args[1..])?;
-    run(opts)
+    if args[1] == "help" {
+        print_usage();
+        return Ok(());
+    }
+
+    let opts = parse_command(&args[1..])?;
+    let quiet_errors = opts.quiet_errors;
+    // JSON output already reports every file's status and error
+    let json = opts.output_format == OutputFormat::Json;
+    run(opts)?;
+
+    let locked = LOCKED_FILES.load(Ordering::Relaxed);
+    if locked > 0 && !json {
+        eprintln!(
+            "{}: {} locked file(s) skipped",
+            "warning".yellow().bold(),
+            locked
+        );
+    }
+
+    // Errors weren't shown, so at least say how many there were
+    let errors = FILE_ERRORS.load(Ordering::Relaxed);
+    if quiet_errors && errors > 0 {
+        if !json {
+            eprintln!("{}: {} file(s) failed", "error".red().bold(), errors);
+        }
+        std::process::exit(1);
+    }
+
+    // --check-clipping: fail the build when any file would clip
+    if CLIPPING_FILES.load(Ordering::Relaxed) > 0 {
+        std::process::exit(2);
+    }
+    Ok(())
 }
 
 fn parse_args(args: &[String]) -> Result<Options> {
@@ -187,281 +855,4901 @@ fn parse_args(args: &[String]) -> Result<Options> {
     while i < args.len() {
         let arg = &args[i];
 
+        // Everything after `--` is a file, even if it looks like an option
+        if arg == "--" {
+            for file in &args[i + 1..] {
+                match opts.groups.last_mut() {
+                    Some(group) => group.push(PathBuf::from(file)),
+                    None => opts.files.push(PathBuf::from(file)),
+                }
+            }
+            break;
+        }
+
         if arg == "--dry-run" {
             opts.dry_run = true;
             i += 1;
             continue;
         }
 
-        if arg == "--help" {
-            print_usage();
-            std::process::exit(0);
+        if arg == "--follow-symlinks" {
+            opts.follow_symlinks = true;
+            i += 1;
+            continue;
         }
 
-        if arg == "--version" {
-            print_version();
-            std::process::exit(0);
+        if arg == "--since" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --since requires a time", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.since = Some(parse_since(&args[i], SystemTime::now())?);
+            i += 1;
+            continue;
         }
 
-        if arg.starts_with('-') && arg.len() > 1 && !arg.starts_with("--") {
-            let flag = &arg[1..];
+        if arg == "--max-file-size" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --max-file-size requires a size", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.max_file_size = Some(parse_size(&args[i])?);
+            i += 1;
+            continue;
+        }
 
-            match flag {
-                "g" => {
-                    i += 1;
-                    if i >= args.len() {
-                        eprintln!("{}: -g requires an argument", "error".red().bold());
-                        std::process::exit(1);
-                    }
-                    opts.gain_steps = Some(
-                        args[i]
-                            .parse()
-                            .map_err(|_| anyhow::anyhow!("invalid gain value: {}", args[i]))?,
-                    );
-                }
-                "d" => {
-                    // mp3gain compatible: -d modifies the suggested dB gain
-                    // (adjusts target level relative to 89 dB reference)
-                    i += 1;
-                    if i >= args.len() {
-                        eprintln!("{}: -d requires an argument", "error".red().bold());
-                        std::process::exit(1);
+        if arg == "--group" {
+            // Files after --group (up to the next one) form one album
+            opts.groups.push(Vec::new());
+            i += 1;
+            continue;
+        }
+
+        if arg == "--album-depth" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --album-depth requires an argument",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            opts.album_depth = Some(
+                args[i]
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid album depth: {}", args[i]))?,
+            );
+            i += 1;
+            continue;
+        }
+
+        if arg == "--album-gain-db" || arg == "--album-peak" {
+            let name = arg.as_str();
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: {} requires an argument", "error".red().bold(), name);
+                std::process::exit(1);
+            }
+            let value: f64 = args[i]
+                .parse()
+                .ok()
+                .filter(|v: &f64| v.is_finite())
+                .ok_or_else(|| anyhow::anyhow!("invalid value for {}: {}", name, args[i]))?;
+            if name == "--album-gain-db" {
+                opts.album_gain_db = Some(value);
+            } else {
+                opts.album_peak = Some(value);
+            }
+            i += 1;
+            continue;
+        }
+
+        if arg == "--album-clip-safe" {
+            opts.album_clip_safe = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--album-clip-check" {
+            opts.album_clip_check = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--estimate" {
+            opts.estimate = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--write-minmax" {
+            opts.write_minmax = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--normalize-tags" || arg == "--canonicalize-tags" {
+            opts.normalize_tags = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--last" {
+            opts.undo_last = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--keep-redo" {
+            opts.keep_redo = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--redo" {
+            opts.redo = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--sidecar" {
+            opts.sidecar = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--journal" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --journal requires a file", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.journal = Some(PathBuf::from(&args[i]));
+            i += 1;
+            continue;
+        }
+
+        if arg == "--skip-unchanged" {
+            opts.skip_unchanged = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--preserve-all" {
+            opts.preserve_timestamp = true;
+            opts.preserve_all = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--temp-dir" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --temp-dir requires a directory", "error".red().bold());
+                std::process::exit(1);
+            }
+            let dir = PathBuf::from(&args[i]);
+            if !dir.is_dir() {
+                anyhow::bail!("invalid --temp-dir: {} is not a directory", dir.display());
+            }
+            opts.temp_dir = Some(dir);
+            opts.use_temp_file = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--in-place-truncate" {
+            opts.in_place_truncate = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--verify" {
+            opts.verify = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--doctor" {
+            opts.doctor = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--dump-frames" {
+            opts.dump_frames = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--explain" {
+            opts.explain = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--list-formats" {
+            opts.list_formats = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--list-frames-json" {
+            opts.list_frames_json = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--limit" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --limit requires a frame count", "error".red().bold());
+                std::process::exit(1);
+            }
+            let limit = args[i]
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("invalid --limit: {}", args[i]))?;
+            opts.frame_limit = Some(limit);
+            i += 1;
+            continue;
+        }
+
+        if arg == "--gain-histogram" {
+            opts.gain_histogram = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--compare-tools" {
+            opts.compare_tools = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--check-clipping" {
+            opts.check_clipping = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--rg-debug" {
+            opts.rg_debug = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--measure" || arg == "--preview-loudness" {
+            opts.measure = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--skip-intro" || arg == "--skip-outro" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: {} requires seconds", "error".red().bold(), arg);
+                std::process::exit(1);
+            }
+            let secs = args[i]
+                .parse::<f64>()
+                .ok()
+                .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                .ok_or_else(|| anyhow::anyhow!("invalid duration for {}: {}", arg, args[i]))?;
+            if arg == "--skip-intro" {
+                opts.skip.intro_secs = secs;
+            } else {
+                opts.skip.outro_secs = secs;
+            }
+            i += 1;
+            continue;
+        }
+
+        if arg == "--downmix" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --downmix requires mono, stereo or none",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            opts.downmix = Downmix::parse(&args[i])
+                .ok_or_else(|| anyhow::anyhow!("invalid --downmix: {}", args[i]))?;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--as-one" {
+            opts.as_one = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--trim-silence" {
+            opts.trim_silence = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--best-effort" {
+            opts.best_effort = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--json-compact" {
+            opts.output_format = OutputFormat::Json;
+            opts.json_compact = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--legacy-output" {
+            opts.legacy_output = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--show-continuous" {
+            opts.show_continuous = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--tags-only" {
+            opts.tags_only = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--true-peak-tags" {
+            opts.true_peak_tags = true;
+            i += 1;
+            continue;
+        }
+        if arg == "--tag-even-if-zero" {
+            opts.tag_even_if_zero = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--no-undo-tag" {
+            // Same as -s s: shift global_gain without writing an APEv2 tag
+            opts.stored_tag_mode = StoredTagMode::Skip;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--max-steps" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --max-steps requires an argument", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.max_steps = Some(
+                args[i]
+                    .parse::<u8>()
+                    .map_err(|_| anyhow::anyhow!("invalid step limit: {} (use 0-255)", args[i]))?
+                    as i32,
+            );
+            i += 1;
+            continue;
+        }
+
+        if arg == "--quiet-errors" {
+            opts.quiet_errors = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--retry" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --retry requires an argument", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.retries = args[i]
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid retry count: {}", args[i]))?;
+            if opts.retries > MAX_RETRIES {
+                anyhow::bail!(
+                    "invalid retry count: {} (at most {})",
+                    opts.retries,
+                    MAX_RETRIES
+                );
+            }
+            i += 1;
+            continue;
+        }
+
+        if arg == "--progress" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --progress requires an argument", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.progress = match args[i].as_str() {
+                "auto" => ProgressMode::Auto,
+                "always" => ProgressMode::Always,
+                "never" => ProgressMode::Never,
+                other => {
+                    eprintln!(
+                        "{}: unknown progress mode '{}', use auto, always or never",
+                        "error".red().bold(),
+                        other
+                    );
+                    std::process::exit(1);
+                }
+            };
+            i += 1;
+            continue;
+        }
+
+        if arg == "--progress-threshold" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --progress-threshold requires an argument",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            opts.progress_threshold = Some(
+                args[i]
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid progress threshold: {}", args[i]))?,
+            );
+            i += 1;
+            continue;
+        }
+
+        if arg == "--skip-locked" {
+            opts.skip_locked = true;
+            i += 1;
+            continue;
+        }
+        if arg == "--stats" {
+            opts.stats = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--hash" {
+            opts.hash = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--force" {
+            opts.force = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--decode" {
+            opts.decode_peak = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--rva2" {
+            opts.rva2 = true;
+            opts.tags_only = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--ramp" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --ramp requires <startf>:<endf>:<startsteps>:<endsteps>",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            opts.ramp = Some(
+                GainRamp::parse(&args[i])
+                    .ok_or_else(|| anyhow::anyhow!("invalid --ramp: {}", args[i]))?,
+            );
+            i += 1;
+            continue;
+        }
+
+        if arg == "--apply-replaygain-tag" {
+            opts.apply_rg_tag = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--apply-rva2" {
+            opts.apply_rva2 = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--sort" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --sort requires name, path, mtime or none",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            opts.sort = Some(match args[i].as_str() {
+                "name" => SortOrder::Name,
+                "path" => SortOrder::Path,
+                "mtime" => SortOrder::Mtime,
+                "none" => SortOrder::None,
+                other => anyhow::bail!("invalid --sort: {}", other),
+            });
+            i += 1;
+            continue;
+        }
+
+        if arg == "--natural-sort" {
+            opts.natural_sort = true;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--path-style" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --path-style requires an argument",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            opts.path_style = Some(match args[i].as_str() {
+                "full" => PathStyle::Full,
+                "relative" => PathStyle::Relative,
+                "basename" => PathStyle::Basename,
+                other => {
+                    eprintln!(
+                        "{}: unknown path style '{}', use full, relative or basename",
+                        "error".red().bold(),
+                        other
+                    );
+                    std::process::exit(1);
+                }
+            });
+            i += 1;
+            continue;
+        }
+
+        if arg == "--tag-format" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --tag-format requires an argument",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            opts.tag_format = match args[i].as_str() {
+                "ape" => TagFormat::Ape,
+                "id3v2" => TagFormat::Id3v2,
+                "both" => TagFormat::Both,
+                other => {
+                    eprintln!(
+                        "{}: unknown tag format '{}', use ape, id3v2 or both",
+                        "error".red().bold(),
+                        other
+                    );
+                    std::process::exit(1);
+                }
+            };
+            i += 1;
+            continue;
+        }
+
+        if arg == "--match" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --match requires an argument", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.match_mode = Some(match args[i].as_str() {
+                "quietest" => MatchMode::Quietest,
+                "loudest" => MatchMode::Loudest,
+                "median" => MatchMode::Median,
+                other => {
+                    eprintln!(
+                        "{}: unknown match mode '{}', use quietest, loudest or median",
+                        "error".red().bold(),
+                        other
+                    );
+                    std::process::exit(1);
+                }
+            });
+            i += 1;
+            continue;
+        }
+
+        if arg == "--cache" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --cache requires a directory", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.cache = Some(AnalysisCache::new(Path::new(&args[i])));
+            i += 1;
+            continue;
+        }
+
+        if arg == "--ceiling-from" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --ceiling-from requires a file", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.ceilings = Some(read_ceilings(Path::new(&args[i]))?);
+            i += 1;
+            continue;
+        }
+
+        if arg == "--min-floor" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("{}: --min-floor requires an argument", "error".red().bold());
+                std::process::exit(1);
+            }
+            opts.min_floor = args[i]
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid gain floor: {} (use 0-255)", args[i]))?;
+            i += 1;
+            continue;
+        }
+
+        if arg == "--avg-target" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --avg-target requires an argument",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            opts.avg_target =
+                Some(args[i].parse().map_err(|_| {
+                    anyhow::anyhow!("invalid average target: {} (use 0-255)", args[i])
+                })?);
+            i += 1;
+            continue;
+        }
+
+        if arg == "--peak-normalize-heuristic" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!(
+                    "{}: --peak-normalize-heuristic requires an argument",
+                    "error".red().bold()
+                );
+                std::process::exit(1);
+            }
+            let invalid =
+                || anyhow::anyhow!("invalid peak target: {} (use dBFS, 0 or below)", args[i]);
+            let dbfs: f64 = args[i].parse().map_err(|_| invalid())?;
+            if dbfs > 0.0 || !dbfs.is_finite() {
+                return Err(invalid());
+            }
+            opts.peak_target_heuristic = Some(dbfs);
+            i += 1;
+            continue;
+        }
+
+        if arg == "--help" {
+            print_usage();
+            std::process::exit(0);
+        }
+
+        if arg == "--version-json" {
+            print_version_json()?;
+            std::process::exit(0);
+        }
+
+        if arg == "--version" {
+            print_version(args.iter().any(|a| a == "--verbose"));
+            std::process::exit(0);
+        }
+
+        if arg.starts_with('-') && arg.len() > 1 && !arg.starts_with("--") {
+            let flag = &arg[1..];
+
+            match flag {
+                "g" => {
+                    i += 1;
+                    if i >= args.len() {
+                        eprintln!("{}: -g requires an argument", "error".red().bold());
+                        std::process::exit(1);
+                    }
+                    opts.gain_steps = Some(
+                        args[i]
+                            .parse()
+                            .map_err(|_| anyhow::anyhow!("invalid gain value: {}", args[i]))?,
+                    );
+                }
+                "d" => {
+                    // mp3gain compatible: -d modifies the suggested dB gain
+                    // (adjusts target level relative to 89 dB reference)
+                    i += 1;
+                    if i >= args.len() {
+                        eprintln!("{}: -d requires an argument", "error".red().bold());
+                        std::process::exit(1);
+                    }
+                    opts.gain_modifier_db = args[i]
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid dB value: {}", args[i]))?;
+                }
+                "m" => {
+                    i += 1;
+                    if i >= args.len() {
+                        eprintln!("{}: -m requires an argument", "error".red().bold());
+                        std::process::exit(1);
+                    }
+                    opts.gain_modifier = args[i]
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid modifier value: {}", args[i]))?;
+                }
+                "s" => {
+                    i += 1;
+                    if i >= args.len() {
+                        eprintln!("{}: -s requires an argument", "error".red().bold());
+                        std::process::exit(1);
+                    }
+                    match args[i].as_str() {
+                        "c" => opts.stored_tag_mode = StoredTagMode::Check,
+                        "d" => opts.stored_tag_mode = StoredTagMode::Delete,
+                        "s" => opts.stored_tag_mode = StoredTagMode::Skip,
+                        "r" => opts.stored_tag_mode = StoredTagMode::Recalc,
+                        "i" => {
+                            opts.stored_tag_mode = StoredTagMode::UseId3v2;
+                            opts.tag_format = TagFormat::Id3v2;
+                        }
+                        "a" => {
+                            opts.stored_tag_mode = StoredTagMode::UseApev2;
+                            opts.tag_format = TagFormat::Ape;
+                        }
+                        other => {
+                            eprintln!(
+                                "{}: unknown -s mode '{}', use c/d/s/r/i/a",
+                                "error".red().bold(),
+                                other
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                "o" => {
+                    // mp3gain compatibility: -o without argument means TSV output
+                    // Check if next arg is a valid format specifier
+                    let next_is_format = if i + 1 < args.len() {
+                        matches!(
+                            args[i + 1].to_lowercase().as_str(),
+                            "json" | "json-compact" | "text" | "tsv" | "db"
+                        )
+                    } else {
+                        false
+                    };
+
+                    if next_is_format {
+                        i += 1;
+                        match args[i].to_lowercase().as_str() {
+                            "json" => opts.output_format = OutputFormat::Json,
+                            "json-compact" => {
+                                opts.output_format = OutputFormat::Json;
+                                opts.json_compact = true;
+                            }
+                            "text" => opts.output_format = OutputFormat::Text,
+                            "tsv" | "db" => opts.output_format = OutputFormat::Tsv,
+                            _ => unreachable!(),
+                        }
+                    } else {
+                        // mp3gain compatible: -o alone means TSV
+                        opts.output_format = OutputFormat::Tsv;
+                    }
+                }
+                "l" => {
+                    // -l <channel> <gain> : apply gain to specific channel
+                    i += 1;
+                    if i >= args.len() {
+                        eprintln!(
+                            "{}: -l requires two arguments: <channel> <gain>",
+                            "error".red().bold()
+                        );
+                        std::process::exit(1);
+                    }
+                    let channel_arg: usize = args[i].parse().map_err(|_| {
+                        anyhow::anyhow!(
+                            "invalid channel number: {} (use 0 for left, 1 for right)",
+                            args[i]
+                        )
+                    })?;
+                    let channel = Channel::from_index(channel_arg).ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "invalid channel: {} (use 0 for left, 1 for right)",
+                            channel_arg
+                        )
+                    })?;
+
+                    i += 1;
+                    if i >= args.len() {
+                        eprintln!(
+                            "{}: -l requires two arguments: <channel> <gain>",
+                            "error".red().bold()
+                        );
+                        std::process::exit(1);
+                    }
+                    let gain: i32 = args[i]
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid gain value: {}", args[i]))?;
+
+                    // Repeated -l options accumulate per channel
+                    let (left, right) = opts.channel_gain.get_or_insert((0, 0));
+                    match channel {
+                        Channel::Left => *left += gain,
+                        Channel::Right => *right += gain,
+                    }
+                }
+                "r" => opts.track_gain = true,
+                "a" => opts.album_gain = true,
+                "e" => opts.skip_album = true,
+                "x" => opts.max_amplitude_only = true,
+                "i" => {
+                    i += 1;
+                    if i >= args.len() {
+                        eprintln!("{}: -i requires an argument", "error".red().bold());
+                        std::process::exit(1);
+                    }
+                    opts.track_index = Some(
+                        args[i]
+                            .parse()
+                            .map_err(|_| anyhow::anyhow!("invalid track index: {}", args[i]))?,
+                    );
+                }
+                "u" => opts.undo = true,
+                "p" => opts.preserve_timestamp = true,
+                "c" => opts.ignore_clipping = true,
+                "k" => opts.prevent_clipping = true,
+                "q" => opts.quiet = true,
+                "R" => opts.recursive = true,
+                "n" => opts.dry_run = true,
+                "w" => opts.wrap_gain = true,
+                "t" => opts.use_temp_file = true,
+                "f" => opts.assume_mpeg2 = true,
+                "v" | "-version" => {
+                    print_version(args.iter().any(|a| a == "--verbose"));
+                    std::process::exit(0);
+                }
+                "h" | "-help" => {
+                    print_usage();
+                    std::process::exit(0);
+                }
+                // Handle combined short flags like -qp, -kc, etc.
+                _ if flag.chars().all(|c| "pqckuranRewxtf".contains(c)) => {
+                    for c in flag.chars() {
+                        match c {
+                            'p' => opts.preserve_timestamp = true,
+                            'q' => opts.quiet = true,
+                            'c' => opts.ignore_clipping = true,
+                            'k' => opts.prevent_clipping = true,
+                            'u' => opts.undo = true,
+                            'r' => opts.track_gain = true,
+                            'a' => opts.album_gain = true,
+                            'n' => opts.dry_run = true,
+                            'R' => opts.recursive = true,
+                            'e' => opts.skip_album = true,
+                            'w' => opts.wrap_gain = true,
+                            'x' => opts.max_amplitude_only = true,
+                            't' => opts.use_temp_file = true,
+                            'f' => opts.assume_mpeg2 = true,
+                            _ => {}
+                        }
+                    }
+                }
+                // Handle -g with attached value (e.g., -g2)
+                _ if flag.starts_with('g') => {
+                    let val = &flag[1..];
+                    opts.gain_steps = Some(
+                        val.parse()
+                            .map_err(|_| anyhow::anyhow!("invalid gain value: {}", val))?,
+                    );
+                }
+                // Handle -d with attached value (e.g., -d4.5)
+                _ if flag.starts_with('d') => {
+                    let val = &flag[1..];
+                    opts.gain_modifier_db = val
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid dB value: {}", val))?;
+                }
+                // Handle -m with attached value (e.g., -m2)
+                _ if flag.starts_with('m') => {
+                    let val = &flag[1..];
+                    opts.gain_modifier = val
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid modifier value: {}", val))?;
+                }
+                // Handle -i with attached value (e.g., -i1)
+                _ if flag.starts_with('i') => {
+                    let val = &flag[1..];
+                    opts.track_index = Some(
+                        val.parse()
+                            .map_err(|_| anyhow::anyhow!("invalid track index: {}", val))?,
+                    );
+                }
+                _ => {
+                    eprintln!("{}: unknown option: -{}", "warning".yellow().bold(), flag);
+                }
+            }
+        } else if !arg.starts_with("--") {
+            // It's a file
+            match opts.groups.last_mut() {
+                Some(group) => group.push(PathBuf::from(arg)),
+                None => opts.files.push(PathBuf::from(arg)),
+            }
+        }
+
+        i += 1;
+    }
+
+    Ok(opts)
+}
+
+/// Read a --ceiling-from map of per-file gain ceilings
+///
+/// Each line holds a whole number of gain steps and a file, separated by
+/// whitespace. Files are resolved relative to the map's directory, like
+/// playlist entries. Blank lines and `#` comments are skipped.
+fn read_ceilings(path: &Path) -> Result<BTreeMap<PathBuf, i32>> {
+    let data = fs::read(path)
+        .map_err(|e| anyhow::anyhow!("failed to read ceiling map {}: {}", path.display(), e))?;
+    let text = String::from_utf8_lossy(&data);
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    let mut ceilings = BTreeMap::new();
+    for (n, line) in text.trim_start_matches('\u{feff}').lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = line
+            .split_once(char::is_whitespace)
+            .and_then(|(steps, file)| Some((steps.parse::<i32>().ok()?, file.trim_start())));
+        let Some((steps, file)) = entry else {
+            anyhow::bail!(
+                "{}:{}: expected <steps> <file>, got: {}",
+                path.display(),
+                n + 1,
+                line
+            );
+        };
+        ceilings.insert(ceiling_key(&base.join(file)), steps);
+    }
+    Ok(ceilings)
+}
+
+/// A file's key in the --ceiling-from map: its canonical path, so an entry
+/// matches however the file is named on the command line
+fn ceiling_key(file: &Path) -> PathBuf {
+    fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf())
+}
+
+/// Clamp a gain to the file's --ceiling-from entry
+///
+/// Returns the gain to apply and, when the ceiling lowered it, a warning.
+fn cap_to_ceiling(file: &Path, steps: i32, opts: &Options) -> (i32, Option<String>) {
+    let ceiling = opts
+        .ceilings
+        .as_ref()
+        .and_then(|ceilings| ceilings.get(&ceiling_key(file)));
+    let Some(&ceiling) = ceiling.filter(|&&ceiling| steps > ceiling) else {
+        return (steps, None);
+    };
+
+    let msg = format!(
+        "gain capped from {} to {} steps by --ceiling-from",
+        steps, ceiling
+    );
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+        eprintln!(
+            "  {} {}{} - {}",
+            "!".yellow(),
+            dry_run_prefix,
+            get_filename(file),
+            msg
+        );
+    }
+    (ceiling, Some(msg))
+}
+
+/// Check for an .m3u/.m3u8 playlist by extension
+fn is_playlist(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u") || ext.eq_ignore_ascii_case("m3u8"))
+}
+
+/// Replace playlist files among `paths` with the files they list
+///
+/// Entries are resolved relative to the playlist's directory. Comment lines
+/// (including `#EXTM3U`/`#EXTINF`), URLs and missing files are skipped, the
+/// latter two with a warning.
+fn expand_playlists(paths: &[PathBuf], opts: &Options) -> Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+
+    for path in paths {
+        if !is_playlist(path) {
+            result.push(path.clone());
+            continue;
+        }
+
+        let data = fs::read(path)
+            .map_err(|e| anyhow::anyhow!("failed to read playlist {}: {}", path.display(), e))?;
+        let text = String::from_utf8_lossy(&data);
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        for line in text.trim_start_matches('\u{feff}').lines() {
+            let entry = line.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            let warn = |msg: &str| {
+                if !opts.quiet {
+                    eprintln!(
+                        "{}: {}: {} ({})",
+                        "warning".yellow().bold(),
+                        path.display(),
+                        msg,
+                        entry
+                    );
+                }
+            };
+
+            if entry.contains("://") {
+                warn("skipping URL entry");
+                continue;
+            }
+            let file = base.join(entry);
+            if file.is_file() {
+                result.push(file);
+            } else {
+                warn("skipping missing entry");
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn expand_files_recursive(
+    paths: &[PathBuf],
+    follow_symlinks: bool,
+    since: Option<SystemTime>,
+) -> Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    let mut visited = HashSet::new();
+
+    for path in paths {
+        if path.is_dir() {
+            collect_audio_files(path, follow_symlinks, since, &mut visited, &mut result)?;
+        } else {
+            result.push(path.clone());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Put `files` in the order chosen with --sort
+///
+/// Without --sort, files are sorted by path with -R or -a and otherwise kept
+/// in the order given. Names and paths compare naturally (`track2` before
+/// `track10`) with --natural-sort, and by default with -a, where the order
+/// is the album's track order.
+fn sort_files(files: &mut [PathBuf], opts: &Options) {
+    let order = opts.sort.unwrap_or(if opts.recursive || opts.album_gain {
+        SortOrder::Path
+    } else {
+        SortOrder::None
+    });
+    let natural = opts.natural_sort || opts.album_gain;
+    let compare = |a: &Path, b: &Path| {
+        if natural {
+            natural_cmp(&a.to_string_lossy(), &b.to_string_lossy())
+        } else {
+            a.cmp(b)
+        }
+    };
+
+    match order {
+        SortOrder::Name => files.sort_by(|a, b| {
+            compare(
+                Path::new(a.file_name().unwrap_or_default()),
+                Path::new(b.file_name().unwrap_or_default()),
+            )
+            .then_with(|| compare(a, b))
+        }),
+        SortOrder::Path => files.sort_by(|a, b| compare(a, b)),
+        SortOrder::Mtime => files.sort_by_cached_key(|file| {
+            let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+            (modified, file.clone())
+        }),
+        SortOrder::None => {}
+    }
+}
+
+/// Compare strings with runs of digits taken as numbers, so `track2` comes
+/// before `track10`
+///
+/// Equal numbers with different zero padding (`01`, `1`) order by length.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let split = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            let (num_a, rest_a) = a.split_at(split(a));
+            let (num_b, rest_b) = b.split_at(split(b));
+            let (trim_a, trim_b) = (num_a.trim_start_matches('0'), num_b.trim_start_matches('0'));
+            let ordering = trim_a
+                .len()
+                .cmp(&trim_b.len())
+                .then_with(|| trim_a.cmp(trim_b))
+                .then_with(|| num_a.len().cmp(&num_b.len()));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            (a, b) = (rest_a, rest_b);
+        } else {
+            if x != y {
+                return x.cmp(&y);
+            }
+            (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+        }
+    }
+}
+
+/// Drop repeated files, keeping the first occurrence of each
+///
+/// Files are compared by canonical path, so a track named twice (by a glob
+/// and explicitly, through `./`, or by two playlist entries) is processed
+/// only once and never gets its gain applied twice. `seen` carries across
+/// calls, so a file in one --group isn't processed again in another.
+fn dedup_files(files: &mut Vec<PathBuf>, seen: &mut HashSet<PathBuf>) {
+    files.retain(|file| seen.insert(fs::canonicalize(file).unwrap_or_else(|_| file.clone())));
+}
+
+/// Drop files larger than `limit` bytes (--max-file-size), with a warning
+/// for each if `warn` is set
+///
+/// Every file is read into memory whole, so a mislabeled video in a `-R` run
+/// would otherwise be loaded in full. Files whose size can't be read are
+/// kept and fail later with the usual error.
+fn skip_large_files(files: &mut Vec<PathBuf>, limit: u64, warn: bool) {
+    files.retain(|file| {
+        let Ok(size) = fs::metadata(file).map(|m| m.len()) else {
+            return true;
+        };
+        if size <= limit {
+            return true;
+        }
+        if warn {
+            eprintln!(
+                "{}: {}: skipped, {} bytes is over --max-file-size ({} bytes)",
+                "warning".yellow().bold(),
+                get_filename(file),
+                size,
+                limit
+            );
+        }
+        false
+    });
+}
+
+/// Split the files under each directory into albums (--album-depth)
+///
+/// A file belongs to the album named by the first `depth` directories of its
+/// path below the directory it was found in, so with depth 1 both
+/// `Music/Album/Disc 1` and `Music/Album/Disc 2` go into `Music/Album`. Files
+/// higher up than that form an album with the others in their directory.
+/// Depth 0 makes each directory argument a single album. With `since`, an
+/// album is kept whole if any of its files was modified after it, so its
+/// album gain still covers every track, and dropped otherwise.
+///
+/// # Returns
+/// * Files given directly (not from a directory) and the albums, by path
+fn group_by_album_dir(
+    paths: &[PathBuf],
+    depth: usize,
+    follow_symlinks: bool,
+    since: Option<SystemTime>,
+) -> Result<(Vec<PathBuf>, Vec<Vec<PathBuf>>)> {
+    let mut files = Vec::new();
+    let mut albums: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        for file in expand_files_recursive(std::slice::from_ref(path), follow_symlinks, None)? {
+            let subdir = file
+                .parent()
+                .and_then(|dir| dir.strip_prefix(path).ok())
+                .unwrap_or(Path::new(""));
+            let album: PathBuf = subdir.components().take(depth).collect();
+            albums.entry(path.join(album)).or_default().push(file);
+        }
+    }
+    albums.retain(|_, files| files.iter().any(|f| modified_since(f, since)));
+
+    Ok((files, albums.into_values().collect()))
+}
+
+/// Recursively collect audio files under `dir`
+///
+/// Symlinked directories found while recursing are skipped unless
+/// `follow_symlinks` is set; symlinked files are collected either way.
+/// Directories and files are tracked by canonical path in `visited`, so
+/// symlink loops terminate and nothing is processed twice. With `since`,
+/// files last modified at or before that time are left out.
+fn collect_audio_files(
+    dir: &Path,
+    follow_symlinks: bool,
+    since: Option<SystemTime>,
+    visited: &mut HashSet<PathBuf>,
+    result: &mut Vec<PathBuf>,
+) -> Result<()> {
+    if !visited.insert(dir.canonicalize()?) {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if !follow_symlinks && entry.file_type()?.is_symlink() && path.is_dir() {
+            continue;
+        }
+
+        if path.is_dir() {
+            collect_audio_files(&path, follow_symlinks, since, visited, result)?;
+        } else if has_supported_extension(&path)
+            && modified_since(&path, since)
+            && path.canonicalize().is_ok_and(|p| visited.insert(p))
+        {
+            result.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `path` was modified after `since` (always true without a time)
+fn modified_since(path: &Path, since: Option<SystemTime>) -> bool {
+    since.is_none_or(|since| {
+        fs::metadata(path)
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified > since)
+    })
+}
+
+/// Refuse fixed gains (-g, -d, -l, -m, --ramp) larger than --max-steps unless --force
+/// is given, so a typo like `-g 200` doesn't flatten a whole library
+fn check_max_steps(opts: &Options) -> Result<()> {
+    if opts.force {
+        return Ok(());
+    }
+    let limit = opts.max_steps.unwrap_or(DEFAULT_MAX_STEPS);
+
+    let (left, right) = opts.channel_gain.unwrap_or((0, 0));
+    let (ramp_start, ramp_end) = opts
+        .ramp
+        .map_or((0, 0), |ramp| (ramp.start_steps, ramp.end_steps));
+    let requested = [
+        opts.gain_steps.unwrap_or(0),
+        db_to_steps(opts.gain_modifier_db),
+        opts.gain_modifier,
+        left,
+        right,
+        ramp_start,
+        ramp_end,
+    ];
+    if let Some(steps) = requested.into_iter().find(|s| s.abs() > limit) {
+        anyhow::bail!(
+            "gain of {:+} steps ({:+.1} dB) exceeds the limit of {} steps; \
+             use --force to apply it anyway or --max-steps to raise the limit",
+            steps,
+            steps_to_db(steps),
+            limit
+        );
+    }
+    Ok(())
+}
+
+/// Reject options that contradict each other instead of silently letting
+/// the order of checks in `run` pick one of them
+fn check_conflicts(opts: &Options) -> Result<()> {
+    let fixed_gain = opts.gain_steps.is_some();
+    let gain_modifier_db = opts.gain_modifier_db != 0.0;
+    let match_mode = opts.match_mode.is_some();
+    let ceilings = opts.ceilings.is_some();
+    let peak_target = opts.peak_target_heuristic.is_some();
+    let skip_tags = opts.stored_tag_mode == StoredTagMode::Skip;
+    let conflicts = [
+        (opts.track_gain && opts.album_gain, "-r", "-a"),
+        (opts.as_one && opts.album_gain, "--as-one", "-a"),
+        (fixed_gain && gain_modifier_db, "-g", "-d"),
+        (fixed_gain && opts.track_gain, "-g", "-r"),
+        (fixed_gain && opts.album_gain, "-g", "-a"),
+        (fixed_gain && opts.channel_gain.is_some(), "-g", "-l"),
+        (fixed_gain && opts.ramp.is_some(), "-g", "--ramp"),
+        (opts.undo && opts.ramp.is_some(), "-u", "--ramp"),
+        (opts.undo && fixed_gain, "-u", "-g"),
+        (opts.undo && gain_modifier_db, "-u", "-d"),
+        (opts.undo && opts.gain_modifier != 0, "-u", "-m"),
+        (opts.undo && opts.channel_gain.is_some(), "-u", "-l"),
+        (opts.undo && opts.track_gain, "-u", "-r"),
+        (opts.undo && opts.album_gain, "-u", "-a"),
+        (match_mode && opts.track_gain, "--match", "-r"),
+        (match_mode && opts.album_gain, "--match", "-a"),
+        (match_mode && fixed_gain, "--match", "-g"),
+        (opts.undo && match_mode, "-u", "--match"),
+        (opts.redo && opts.undo, "--redo", "-u"),
+        (opts.redo && fixed_gain, "--redo", "-g"),
+        (opts.redo && gain_modifier_db, "--redo", "-d"),
+        (opts.redo && opts.gain_modifier != 0, "--redo", "-m"),
+        (opts.redo && opts.channel_gain.is_some(), "--redo", "-l"),
+        (opts.redo && opts.track_gain, "--redo", "-r"),
+        (opts.redo && opts.album_gain, "--redo", "-a"),
+        (opts.redo && match_mode, "--redo", "--match"),
+        (opts.wrap_gain && opts.prevent_clipping, "-w", "-k"),
+        (skip_tags && opts.undo, "-s s", "-u"),
+        (skip_tags && opts.redo, "-s s", "--redo"),
+        (skip_tags && opts.rva2, "-s s", "--rva2"),
+        (skip_tags && opts.tags_only, "-s s", "--tags-only"),
+        (
+            peak_target && fixed_gain,
+            "--peak-normalize-heuristic",
+            "-g",
+        ),
+        (
+            peak_target && opts.track_gain,
+            "--peak-normalize-heuristic",
+            "-r",
+        ),
+        (
+            peak_target && opts.album_gain,
+            "--peak-normalize-heuristic",
+            "-a",
+        ),
+        (
+            peak_target && opts.avg_target.is_some(),
+            "--peak-normalize-heuristic",
+            "--avg-target",
+        ),
+        (
+            opts.album_clip_safe && opts.prevent_clipping,
+            "--album-clip-safe",
+            "-k",
+        ),
+        (
+            opts.album_clip_safe && opts.wrap_gain,
+            "--album-clip-safe",
+            "-w",
+        ),
+        (
+            opts.album_clip_check && opts.album_clip_safe,
+            "--album-clip-check",
+            "--album-clip-safe",
+        ),
+        (
+            opts.album_clip_check && opts.wrap_gain,
+            "--album-clip-check",
+            "-w",
+        ),
+        (ceilings && opts.undo, "--ceiling-from", "-u"),
+        (ceilings && opts.redo, "--ceiling-from", "--redo"),
+        (
+            ceilings && opts.channel_gain.is_some(),
+            "--ceiling-from",
+            "-l",
+        ),
+    ];
+    if let Some((_, first, second)) = conflicts.into_iter().find(|(set, _, _)| *set) {
+        anyhow::bail!(
+            "{} and {} are mutually exclusive; use only one of them",
+            first,
+            second
+        );
+    }
+    Ok(())
+}
+
+/// Expand playlists (and directories with -R) within each --group
+fn expand_groups(opts: &mut Options) -> Result<()> {
+    let groups = std::mem::take(&mut opts.groups);
+    for mut files in groups {
+        if files.iter().any(|f| is_playlist(f)) {
+            files = expand_playlists(&files, opts)?;
+        }
+        if opts.recursive {
+            files = expand_files_recursive(&files, opts.follow_symlinks, None)?;
+        }
+        opts.groups.push(files);
+    }
+    Ok(())
+}
+
+fn run(mut opts: Options) -> Result<()> {
+    if let Some(style) = opts.path_style {
+        let _ = PATH_STYLE.set(style);
+    }
+
+    // Need no files
+    if opts.list_formats {
+        return cmd_list_formats(&opts);
+    }
+    if opts.explain {
+        return cmd_explain(&opts);
+    }
+
+    // Validate options
+    if opts.files.is_empty() && opts.groups.is_empty() {
+        eprintln!("{}: no files specified", "error".red().bold());
+        std::process::exit(1);
+    }
+
+    if let Err(e) = check_conflicts(&opts) {
+        eprintln!("{}: {}", "error".red().bold(), e);
+        std::process::exit(1);
+    }
+
+    if opts.min_floor > 0 && (opts.wrap_gain || opts.channel_gain.is_some()) {
+        eprintln!(
+            "{}: --min-floor cannot be combined with -w or -l",
+            "error".red().bold()
+        );
+        std::process::exit(1);
+    }
+
+    if !opts.groups.is_empty() && (!opts.album_gain || opts.skip_album) {
+        eprintln!("{}: --group requires -a", "error".red().bold());
+        std::process::exit(1);
+    }
+
+    if opts.undo_last && !opts.undo {
+        eprintln!("{}: --last requires -u", "error".red().bold());
+        std::process::exit(1);
+    }
+
+    if opts.keep_redo && !opts.undo {
+        eprintln!("{}: --keep-redo requires -u", "error".red().bold());
+        std::process::exit(1);
+    }
+
+    if opts.album_clip_safe && (!opts.album_gain || opts.skip_album) {
+        eprintln!("{}: --album-clip-safe requires -a", "error".red().bold());
+        std::process::exit(1);
+    }
+
+    if opts.album_clip_check && (!opts.album_gain || opts.skip_album) {
+        eprintln!("{}: --album-clip-check requires -a", "error".red().bold());
+        std::process::exit(1);
+    }
+
+    if opts.album_peak.is_some() && opts.album_gain_db.is_none() {
+        eprintln!(
+            "{}: --album-peak requires --album-gain-db",
+            "error".red().bold()
+        );
+        std::process::exit(1);
+    }
+
+    if opts.album_gain_db.is_some() && (opts.album_gain || opts.track_gain) {
+        eprintln!(
+            "{}: --album-gain-db writes known values and cannot be combined with -r or -a",
+            "error".red().bold()
+        );
+        std::process::exit(1);
+    }
+
+    if opts.album_depth.is_some() {
+        if !opts.album_gain || opts.skip_album || !opts.recursive {
+            eprintln!("{}: --album-depth requires -a and -R", "error".red().bold());
+            std::process::exit(1);
+        }
+        if !opts.groups.is_empty() {
+            eprintln!(
+                "{}: --album-depth cannot be combined with --group",
+                "error".red().bold()
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // Replace playlists with the tracks they list
+    if opts.files.iter().any(|f| is_playlist(f)) {
+        opts.files = expand_playlists(&opts.files, &opts)?;
+        if opts.files.is_empty() {
+            eprintln!("{}: no files found in playlist(s)", "error".red().bold());
+            std::process::exit(1);
+        }
+    }
+
+    // Expand files if recursive mode; with --album-depth, directories
+    // become one album per subdirectory at that depth
+    if let Some(depth) = opts.album_depth {
+        let (files, albums) =
+            group_by_album_dir(&opts.files, depth, opts.follow_symlinks, opts.since)?;
+        if files.is_empty() && albums.is_empty() {
+            if nothing_modified_since(&opts) {
+                return Ok(());
+            }
+            eprintln!(
+                "{}: no audio files found (MP3/M4A/FLAC)",
+                "error".red().bold()
+            );
+            std::process::exit(1);
+        }
+        opts.files = files;
+        opts.groups = albums;
+    } else if opts.recursive && !opts.files.is_empty() {
+        opts.files = expand_files_recursive(&opts.files, opts.follow_symlinks, opts.since)?;
+        if opts.files.is_empty() {
+            if nothing_modified_since(&opts) {
+                return Ok(());
+            }
+            eprintln!(
+                "{}: no audio files found (MP3/M4A/FLAC)",
+                "error".red().bold()
+            );
+            std::process::exit(1);
+        }
+    }
+
+    if !opts.groups.is_empty() {
+        expand_groups(&mut opts)?;
+        if let Some(n) = opts.groups.iter().position(|g| g.is_empty()) {
+            eprintln!("{}: --group {} has no files", "error".red().bold(), n + 1);
+            std::process::exit(1);
+        }
+    }
+
+    // Files (and each album) in the --sort order before anything is analyzed
+    let mut files = std::mem::take(&mut opts.files);
+    let mut groups = std::mem::take(&mut opts.groups);
+    sort_files(&mut files, &opts);
+    for group in &mut groups {
+        sort_files(group, &opts);
+    }
+    (opts.files, opts.groups) = (files, groups);
+
+    // Never process (and gain) the same file twice
+    let mut seen = HashSet::new();
+    for group in &mut opts.groups {
+        dedup_files(group, &mut seen);
+    }
+    opts.groups.retain(|group| !group.is_empty());
+    dedup_files(&mut opts.files, &mut seen);
+
+    if let Some(limit) = opts.max_file_size {
+        let warn = !opts.quiet && opts.output_format != OutputFormat::Json;
+        for group in &mut opts.groups {
+            skip_large_files(group, limit, warn);
+        }
+        opts.groups.retain(|group| !group.is_empty());
+        skip_large_files(&mut opts.files, limit, warn);
+    }
+
+    if let Err(e) = check_max_steps(&opts) {
+        eprintln!("{}: {}", "error".red().bold(), e);
+        std::process::exit(1);
+    }
+
+    // -f option warning (assume MPEG2)
+    if opts.assume_mpeg2 && !opts.quiet && opts.output_format == OutputFormat::Text {
+        eprintln!(
+            "{}: -f (assume MPEG2) is accepted for compatibility but has no effect",
+            "note".cyan()
+        );
+    }
+
+    if opts.estimate {
+        return cmd_estimate(&opts, select_action(&opts));
+    }
+
+    match select_action(&opts) {
+        Action::Doctor => cmd_doctor(&opts.files, &opts),
+        Action::DumpFrames => cmd_dump_frames(&opts.files, &opts),
+        Action::ListFramesJson => cmd_list_frames_json(&opts.files, &opts),
+        Action::GainHistogram => cmd_gain_histogram(&opts.files, &opts),
+        Action::CompareTools => cmd_compare_tools(&opts.files, &opts),
+        Action::CheckClipping => cmd_check_clipping(&opts.files, &opts),
+        Action::RgDebug => cmd_rg_debug(&opts.files, &opts),
+        Action::Measure => cmd_measure(&opts.files, &opts),
+        Action::MaxAmplitude => cmd_max_amplitude(&opts.files, &opts),
+        Action::DeleteTags => cmd_delete_tags(&opts.files, &opts),
+        Action::CheckTags => cmd_check_tags(&opts.files, &opts),
+        Action::Undo | Action::Redo => cmd_undo(&opts.files, &opts),
+        Action::WriteMinmax => cmd_write_minmax(&opts.files, &opts),
+        Action::NormalizeTags => cmd_normalize_tags(&opts.files, &opts),
+        Action::SetAlbumTags(gain_db) => {
+            cmd_set_album_tags(&opts.files, gain_db, opts.album_peak, &opts)
+        }
+        Action::Match(mode) => cmd_match(&opts.files, mode, &opts),
+        Action::AlbumGain => cmd_album_gain(&opts.files, &opts),
+        Action::TrackGain => cmd_track_gain(&opts.files, &opts),
+        Action::ApplyChannel(left, right) => cmd_apply_channel(&opts.files, left, right, &opts),
+        Action::Ramp(ramp) => cmd_ramp(&opts.files, ramp, &opts),
+        Action::ApplyRva2 => cmd_apply_stored(&opts.files, StoredAdjustment::Rva2, &opts),
+        Action::ApplyRgTag => cmd_apply_stored(&opts.files, StoredAdjustment::ReplayGainTag, &opts),
+        Action::AvgTarget(target) => {
+            cmd_frame_target(&opts.files, FrameTarget::AvgGain(target), &opts)
+        }
+        Action::PeakHeuristic(dbfs) => {
+            cmd_frame_target(&opts.files, FrameTarget::PeakDbfs(dbfs), &opts)
+        }
+        Action::Apply(steps) => cmd_apply(&opts.files, steps, &opts),
+        Action::Info => cmd_info(&opts.files, &opts),
+    }
+}
+
+/// What a run does, picked from the options in order of precedence
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Doctor,
+    DumpFrames,
+    ListFramesJson,
+    GainHistogram,
+    CompareTools,
+    CheckClipping,
+    RgDebug,
+    Measure,
+    MaxAmplitude,
+    DeleteTags,
+    CheckTags,
+    Undo,
+    Redo,
+    WriteMinmax,
+    NormalizeTags,
+    SetAlbumTags(f64),
+    Match(MatchMode),
+    AlbumGain,
+    TrackGain,
+    ApplyChannel(i32, i32),
+    Ramp(GainRamp),
+    ApplyRva2,
+    ApplyRgTag,
+    AvgTarget(u8),
+    PeakHeuristic(f64),
+    Apply(i32),
+    /// No action option: analyze files (mp3gain compatible)
+    Info,
+}
+
+impl Action {
+    /// The option that selects this action, for error messages
+    fn option(&self) -> &'static str {
+        match self {
+            Action::Doctor => "--doctor",
+            Action::DumpFrames => "--dump-frames",
+            Action::ListFramesJson => "--list-frames-json",
+            Action::GainHistogram => "--gain-histogram",
+            Action::CompareTools => "--compare-tools",
+            Action::CheckClipping => "--check-clipping",
+            Action::RgDebug => "--rg-debug",
+            Action::Measure => "--measure",
+            Action::MaxAmplitude => "-x",
+            Action::DeleteTags => "-s d",
+            Action::CheckTags => "-s c",
+            Action::Undo => "-u",
+            Action::Redo => "--redo",
+            Action::WriteMinmax => "--write-minmax",
+            Action::NormalizeTags => "--normalize-tags",
+            Action::SetAlbumTags(_) => "--album-gain-db",
+            Action::Match(_) => "--match",
+            Action::AlbumGain => "-a",
+            Action::TrackGain => "-r",
+            Action::ApplyChannel(..) => "-l",
+            Action::Ramp(_) => "--ramp",
+            Action::ApplyRva2 => "--apply-rva2",
+            Action::ApplyRgTag => "--apply-replaygain-tag",
+            Action::AvgTarget(_) => "--avg-target",
+            Action::PeakHeuristic(_) => "--peak-normalize-heuristic",
+            Action::Apply(_) => "-g",
+            Action::Info => "",
+        }
+    }
+}
+
+fn select_action(opts: &Options) -> Action {
+    if opts.doctor {
+        // --doctor: diagnostics for bug reports
+        Action::Doctor
+    } else if opts.dump_frames {
+        // --dump-frames: per-frame current/projected global_gain (debug)
+        Action::DumpFrames
+    } else if opts.list_frames_json {
+        // --list-frames-json: frame headers and global_gain as JSON (debug)
+        Action::ListFramesJson
+    } else if opts.gain_histogram {
+        // --gain-histogram: count of each global_gain value
+        Action::GainHistogram
+    } else if opts.compare_tools {
+        // --compare-tools: frame heuristic next to decoded analysis
+        Action::CompareTools
+    } else if opts.check_clipping {
+        // --check-clipping: read-only clipping gate for CI
+        Action::CheckClipping
+    } else if opts.rg_debug {
+        Action::RgDebug
+    } else if opts.measure {
+        Action::Measure
+    } else if opts.max_amplitude_only {
+        // -x: only find max amplitude
+        Action::MaxAmplitude
+    } else if opts.stored_tag_mode == StoredTagMode::Delete {
+        // -s d: delete stored tag info
+        Action::DeleteTags
+    } else if opts.stored_tag_mode == StoredTagMode::Check {
+        // -s c: check/show stored tag info
+        Action::CheckTags
+    } else if opts.undo {
+        // -u: undo from APEv2 tags
+        Action::Undo
+    } else if opts.redo {
+        // --redo: apply the undone gain again
+        Action::Redo
+    } else if opts.write_minmax {
+        // --write-minmax: record the undo baseline without changing audio
+        Action::WriteMinmax
+    } else if opts.normalize_tags {
+        // --normalize-tags: rewrite undo and min/max tags from other taggers
+        Action::NormalizeTags
+    } else if let Some(gain_db) = opts.album_gain_db {
+        // --album-gain-db: write known album values without analysis
+        Action::SetAlbumTags(gain_db)
+    } else if let Some(mode) = opts.match_mode {
+        // --match: level every file to the quietest, loudest or median one
+        Action::Match(mode)
+    } else if opts.album_gain && !opts.skip_album {
+        // -a: apply album gain (ReplayGain)
+        Action::AlbumGain
+    } else if opts.track_gain || opts.skip_album {
+        // -r or -e: apply track gain (ReplayGain)
+        Action::TrackGain
+    } else if let Some((left, right)) = opts.channel_gain {
+        // -l: apply channel-specific gain
+        Action::ApplyChannel(left, right)
+    } else if let Some(ramp) = opts.ramp {
+        // --ramp: fade the gain in or out across a range of frames
+        Action::Ramp(ramp)
+    } else if opts.apply_rva2 {
+        // --apply-rva2: apply each file's RVA2 track adjustment
+        Action::ApplyRva2
+    } else if opts.apply_rg_tag {
+        // --apply-replaygain-tag: apply each file's stored track gain
+        Action::ApplyRgTag
+    } else if let Some(target) = opts.avg_target {
+        // --avg-target: shift each file's average global_gain to the target
+        Action::AvgTarget(target)
+    } else if let Some(dbfs) = opts.peak_target_heuristic {
+        // --peak-normalize-heuristic: bring the estimated peak to the target
+        Action::PeakHeuristic(dbfs)
+    } else if let Some(steps) = opts.gain_steps {
+        // -g: apply fixed gain steps
+        Action::Apply(steps)
+    } else {
+        // With -d modifier, perform ReplayGain analysis
+        Action::Info
+    }
+}
+
+/// Subcommands that may come before the options, naming the action instead
+/// of leaving it to mp3gain-style flags
+#[derive(Clone, Copy)]
+enum Subcommand {
+    Apply,
+    Analyze,
+    Undo,
+    Redo,
+    Measure,
+    Strip,
+}
+
+impl Subcommand {
+    fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "apply" => Some(Subcommand::Apply),
+            "analyze" => Some(Subcommand::Analyze),
+            "undo" => Some(Subcommand::Undo),
+            "redo" => Some(Subcommand::Redo),
+            "measure" => Some(Subcommand::Measure),
+            "strip" => Some(Subcommand::Strip),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Subcommand::Apply => "apply",
+            Subcommand::Analyze => "analyze",
+            Subcommand::Undo => "undo",
+            Subcommand::Redo => "redo",
+            Subcommand::Measure => "measure",
+            Subcommand::Strip => "strip",
+        }
+    }
+
+    /// Set the options this subcommand stands for
+    ///
+    /// Fails if the other options pick an action that doesn't belong to it.
+    fn apply_to(self, opts: &mut Options) -> Result<()> {
+        let action = select_action(opts);
+        let fits = match self {
+            Subcommand::Apply => !matches!(
+                action,
+                Action::Doctor
+                    | Action::DumpFrames
+                    | Action::ListFramesJson
+                    | Action::GainHistogram
+                    | Action::CompareTools
+                    | Action::CheckClipping
+                    | Action::RgDebug
+                    | Action::Measure
+                    | Action::MaxAmplitude
+                    | Action::DeleteTags
+                    | Action::CheckTags
+                    | Action::Undo
+                    | Action::Redo
+                    | Action::Info
+            ),
+            Subcommand::Analyze => matches!(
+                action,
+                Action::Doctor
+                    | Action::DumpFrames
+                    | Action::ListFramesJson
+                    | Action::GainHistogram
+                    | Action::CompareTools
+                    | Action::CheckClipping
+                    | Action::RgDebug
+                    | Action::MaxAmplitude
+                    | Action::CheckTags
+                    | Action::Info
+            ),
+            Subcommand::Undo => matches!(action, Action::Undo | Action::Info),
+            Subcommand::Redo => matches!(action, Action::Redo | Action::Info),
+            Subcommand::Measure => matches!(action, Action::Measure | Action::Info),
+            Subcommand::Strip => matches!(action, Action::DeleteTags | Action::Info),
+        };
+        if !fits {
+            if action == Action::Info {
+                anyhow::bail!(
+                    "`mp3rgain apply` needs a gain option such as -g, -l, -r, -a or --match"
+                );
+            }
+            anyhow::bail!(
+                "`mp3rgain {}` cannot be combined with {}",
+                self.name(),
+                action.option()
+            );
+        }
+
+        match self {
+            Subcommand::Apply | Subcommand::Analyze => {}
+            Subcommand::Undo => opts.undo = true,
+            Subcommand::Redo => opts.redo = true,
+            Subcommand::Measure => opts.measure = true,
+            Subcommand::Strip => opts.stored_tag_mode = StoredTagMode::Delete,
+        }
+        Ok(())
+    }
+}
+
+/// Parse the command line, with or without a leading subcommand
+///
+/// Only the very first argument can be a subcommand, so a file named like
+/// one is still a file after any option or after `--`.
+fn parse_command(args: &[String]) -> Result<Options> {
+    let Some(subcommand) = args.first().and_then(|arg| Subcommand::parse(arg)) else {
+        return parse_args(args);
+    };
+    let mut opts = parse_args(&args[1..])?;
+    if let Err(e) = subcommand.apply_to(&mut opts) {
+        eprintln!("{}: {}", "error".red().bold(), e);
+        std::process::exit(1);
+    }
+    Ok(opts)
+}
+
+// =============================================================================
+// Progress Bar
+// =============================================================================
+
+/// Whether a batch of `total` files gets a progress bar (-q, --progress)
+fn shows_progress(total: usize, opts: &Options) -> bool {
+    match opts.progress {
+        _ if opts.quiet => false,
+        ProgressMode::Never => false,
+        ProgressMode::Always => true,
+        ProgressMode::Auto => {
+            opts.output_format == OutputFormat::Text
+                && total >= opts.progress_threshold.unwrap_or(PROGRESS_THRESHOLD)
+        }
+    }
+}
+
+fn create_progress_bar(total: usize, opts: &Options) -> Option<ProgressBar> {
+    if !shows_progress(total, opts) {
+        return None;
+    }
+
+    let pb = ProgressBar::new(total as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.cyan} [{bar:40.cyan/blue}] {pos}/{len} {msg}")
+            .unwrap()
+            .progress_chars("=>-"),
+    );
+    Some(pb)
+}
+
+fn progress_set_message(pb: &Option<ProgressBar>, msg: &str) {
+    if let Some(ref pb) = pb {
+        pb.set_message(msg.to_string());
+    }
+}
+
+fn progress_inc(pb: &Option<ProgressBar>) {
+    if let Some(ref pb) = pb {
+        pb.inc(1);
+    }
+}
+
+fn progress_finish(pb: Option<ProgressBar>) {
+    if let Some(pb) = pb {
+        pb.finish_and_clear();
+    }
+}
+
+// =============================================================================
+// Commands
+// =============================================================================
+
+fn cmd_max_amplitude(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{} Finding maximum amplitude for {} file(s)",
+            "mp3rgain".green().bold(),
+            files.len()
+        );
+        println!();
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        match measure_max_amplitude(file, opts.decode_peak) {
+            Ok(measured) => {
+                let (max_amp, max_gain, min_gain) =
+                    (measured.peak, measured.max_gain, measured.min_gain);
+                let decoded = measured.method == AnalysisMethod::Decoded;
+                // Convert to PCM scale (like mp3gain: 0-32768+)
+                let max_pcm_sample = max_amp * 32768.0;
+                let headroom_db = replaygain::clip_margin_db(max_amp, 0.0);
+
+                // Check if peak is at clipping threshold (Symphonia MP3 decoder limitation)
+                // Only applies to MP3 files - AAC/M4A decoder doesn't have this issue
+                let is_mp3 = file
+                    .extension()
+                    .map(|e| e.eq_ignore_ascii_case("mp3"))
+                    .unwrap_or(false);
+                let may_clip = decoded && is_mp3 && max_amp >= 0.9999;
+
+                match opts.output_format {
+                    OutputFormat::Text => {
+                        if !opts.quiet {
+                            println!("{}", filename.cyan().bold());
+                            println!("  Max PCM sample: {:.6}", max_pcm_sample);
+                            if may_clip {
+                                println!(
+                                    "  {}",
+                                    "  (may be clipped - actual peak could be higher)".yellow()
+                                );
+                            }
+                            if !decoded {
+                                println!(
+                                    "  {}",
+                                    "  (estimated from global_gain - audio could not be decoded)"
+                                        .yellow()
+                                );
+                            }
+                            println!("  Headroom:       {:+.2} dB", headroom_db);
+                            println!("  Max global_gain: {}", max_gain);
+                            println!("  Min global_gain: {}", min_gain);
+                            println!();
+                        } else {
+                            println!("{}\t{:.6}\t{:.2}", filename, max_pcm_sample, headroom_db);
+                        }
+                    }
+                    OutputFormat::Tsv => {
+                        println!(
+                            "{}\t{:.6}\t{:.2}\t{}\t{}",
+                            filename, max_pcm_sample, headroom_db, max_gain, min_gain
+                        );
+                    }
+                    OutputFormat::Json => {
+                        let mut result = JsonFileResult {
+                            file: get_path(file),
+                            max_amplitude: Some(max_pcm_sample),
+                            headroom_db: Some(headroom_db),
+                            max_gain: Some(max_gain),
+                            min_gain: Some(min_gain),
+                            method: Some(measured.method.as_str()),
+                            ..Default::default()
+                        };
+                        if may_clip {
+                            result
+                                .warnings
+                                .push("peak may be clipped - actual value could be higher");
+                        }
+                        json_results.push(result);
+                    }
+                }
+            }
+            Err(e) => {
+                if file_error(
+                    opts,
+                    opts.output_format != OutputFormat::Json && !opts.quiet,
+                ) {
+                    eprintln!("{} - {}", filename.red(), e);
+                }
+                if opts.output_format == OutputFormat::Json {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("error".to_string()),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        progress_inc(&pb);
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: None,
+            stats: None,
+        };
+        print_json(&output, opts)?;
+    }
+
+    Ok(())
+}
+
+/// --measure: loudness, peak and sample rate of each file, with no target or gain
+fn cmd_measure(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if !replaygain::is_available() {
+        eprintln!(
+            "{}: Loudness measurement requires the 'replaygain' feature",
+            "error".red().bold()
+        );
+        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
+        std::process::exit(1);
+    }
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{} Measuring loudness of {} file(s)",
+            "mp3rgain".green().bold(),
+            files.len()
+        );
+        println!();
+    }
+    if opts.output_format == OutputFormat::Tsv {
+        println!("File\tLoudness dB\tPeak\tSample rate");
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        match measure_track(file, opts) {
+            Ok(result) => match opts.output_format {
+                OutputFormat::Text if opts.quiet => {
+                    println!(
+                        "{}\t{:.2}\t{:.6}\t{}",
+                        filename, result.loudness_db, result.peak, result.sample_rate
+                    );
+                }
+                OutputFormat::Text => {
+                    println!("{}", filename.cyan().bold());
+                    println!("  Loudness:    {:.2} dB", result.loudness_db);
+                    println!("  Peak:        {:.6}", result.peak);
+                    println!("  Sample rate: {} Hz", result.sample_rate);
+                    if result.method == AnalysisMethod::Heuristic {
+                        println!(
+                            "  {}",
+                            "  (estimated from global_gain - audio could not be decoded)".yellow()
+                        );
+                    }
+                    if result.partial {
+                        println!(
+                            "  {}",
+                            "  (partial - some audio could not be decoded)".yellow()
+                        );
+                    }
+                    println!();
+                }
+                OutputFormat::Tsv => {
+                    println!(
+                        "{}\t{:.2}\t{:.6}\t{}",
+                        filename, result.loudness_db, result.peak, result.sample_rate
+                    );
+                }
+                OutputFormat::Json => {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        loudness_db: Some(result.loudness_db),
+                        peak: Some(result.peak),
+                        sample_rate: Some(result.sample_rate),
+                        method: Some(result.method.as_str()),
+                        partial: result.partial.then_some(true),
+                        ..Default::default()
+                    });
+                }
+            },
+            Err(e) => {
+                if file_error(
+                    opts,
+                    opts.output_format != OutputFormat::Json && !opts.quiet,
+                ) {
+                    eprintln!("{} - {}", filename.red(), e);
+                }
+                if opts.output_format == OutputFormat::Json {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("error".to_string()),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        progress_inc(&pb);
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: None,
+            stats: None,
+        };
+        print_json(&output, opts)?;
+    }
+
+    Ok(())
+}
+
+/// Analyze a file for --measure; `-` reads standard input
+///
+/// Decoding needs to seek, so standard input is read in full into memory
+/// before the analysis starts.
+fn measure_track(file: &Path, opts: &Options) -> Result<ReplayGainResult> {
+    if file != Path::new("-") {
+        return analyze_track(file, opts);
+    }
+    let mut data = Vec::new();
+    std::io::Read::read_to_end(&mut std::io::stdin().lock(), &mut data)
+        .context("Failed to read standard input")?;
+    replaygain::analyze_track_data(data, opts.track_index)
+}
+
+fn cmd_delete_tags(files: &[PathBuf], opts: &Options) -> Result<()> {
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} {} ReplayGain tags from {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            if opts.dry_run {
+                "Would delete"
+            } else {
+                "Deleting"
+            },
+            files.len()
+        );
+        println!();
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let timer = stats.begin(file);
+        let mut result = process_delete_tags(file, opts);
+        stats.finish(timer, file, opts, &mut result);
+        update_counters(&result, &mut successful, &mut failed);
+        json_results.push(result);
+
+        progress_inc(&pb);
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        if opts.dry_run && !opts.quiet {
+            println!();
+            println!("{}", "No files were modified.".yellow());
+        }
+    }
+
+    Ok(())
+}
+
+/// -s d on one file: remove the gain metadata listed by [`plan_delete_gain_tags`]
+fn process_delete_tags(file: &Path, opts: &Options) -> JsonFileResult {
+    let filename = get_filename(file);
+    let text = opts.output_format == OutputFormat::Text && !opts.quiet;
+
+    let error_result = |e: anyhow::Error| {
+        if file_error(opts, text) {
+            eprintln!("  {} {} - {}", "x".red(), filename, e);
+        }
+        JsonFileResult {
+            file: get_path(file),
+            status: Some("error".to_string()),
+            error: Some(e.to_string()),
+            ..Default::default()
+        }
+    };
+
+    let plan = match plan_delete_gain_tags(file, opts) {
+        Ok(plan) => plan,
+        Err(e) => return error_result(e),
+    };
+
+    if plan.changes.is_empty() && !plan.drops_ape_tag {
+        if text {
+            println!("  {} {} (no gain tags)", ".".cyan(), filename);
+        }
+        return JsonFileResult {
+            file: get_path(file),
+            status: no_adjustment_status(opts),
+            dry_run: if opts.dry_run { Some(true) } else { None },
+            ..Default::default()
+        };
+    }
+
+    let ape_tag_removed = if plan.drops_ape_tag { Some(true) } else { None };
+
+    if opts.dry_run {
+        if text {
+            println!(
+                "  {} [DRY RUN] {} (would delete {} tag(s))",
+                "~".cyan(),
+                filename,
+                plan.changes.len()
+            );
+            print_tag_changes(&plan.changes);
+            if plan.drops_ape_tag {
+                println!("      (APEv2 tag would be left empty and removed)");
+            }
+        }
+        return JsonFileResult {
+            file: get_path(file),
+            status: Some("dry_run".to_string()),
+            dry_run: Some(true),
+            tag_changes: json_tag_changes(&plan.changes),
+            ape_tag_removed,
+            ..Default::default()
+        };
+    }
+
+    // Save original timestamp (and mode/owner) if needed
+    let preserved = PreservedMetadata::capture(file, opts);
+
+    match delete_gain_tags(file, opts) {
+        Ok(()) => {
+            preserved.restore(file);
+            if text {
+                println!("  {} {} (tags deleted)", "v".green(), filename);
+            }
+            JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                tag_changes: json_tag_changes(&plan.changes),
+                ape_tag_removed,
+                ..Default::default()
+            }
+        }
+        Err(e) => error_result(e),
+    }
+}
+
+fn cmd_check_tags(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{} Checking stored tag info for {} file(s)",
+            "mp3rgain".green().bold(),
+            files.len()
+        );
+        println!();
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        // Adjustments some players store in ID3v2 instead of APEv2
+        let rva2 = id3v2::read_rva2_from_file(file).unwrap_or_default();
+        let rva2_master = id3v2::track_master(&rva2);
+        // ReplayGain the encoder stored in the LAME tag
+        let lame = read_info_header(file).ok().flatten();
+
+        // Where the gain tags below were found (APEv2 wins if both have one)
+        let stored_format = stored_gain_tag_format(file).ok().flatten();
+        // Which tool family left them
+        let provenance = gain_provenance(file).ok();
+        let provenance_source = provenance.map(|p| p.source.as_str().to_string());
+        let provenance_container = provenance
+            .and_then(|p| p.container)
+            .map(|c| c.as_str().to_string());
+
+        match read_stored_gain_tag(file) {
+            Ok(Some(tag)) => {
+                let undo = tag.get(TAG_MP3GAIN_UNDO);
+                let undo_stack = tag.get(TAG_MP3GAIN_UNDO_STACK);
+                let redo = tag.get(TAG_MP3GAIN_REDO);
+                let minmax = tag.get(TAG_MP3GAIN_MINMAX);
+                let album_minmax = tag.get(TAG_MP3GAIN_ALBUM_MINMAX);
+                let track_gain = tag.get(TAG_REPLAYGAIN_TRACK_GAIN);
+                let track_peak = tag.get(TAG_REPLAYGAIN_TRACK_PEAK);
+                let album_gain = tag.get(TAG_REPLAYGAIN_ALBUM_GAIN);
+                let album_peak = tag.get(TAG_REPLAYGAIN_ALBUM_PEAK);
+                let reference = tag.get(TAG_REPLAYGAIN_REFERENCE_LOUDNESS);
+                // Undo tags another tagger wrote in its own format
+                let nonstandard_undo = tag.clone().normalize_undo();
+
+                match opts.output_format {
+                    OutputFormat::Text => {
+                        println!("{}", filename.cyan().bold());
+                        if let Some(v) = undo {
+                            println!("  MP3GAIN_UNDO:         {}", v);
+                        }
+                        if let Some(v) = undo_stack {
+                            println!("  MP3GAIN_UNDO_STACK:   {}", v);
+                        }
+                        if let Some(v) = redo {
+                            println!("  MP3GAIN_REDO:         {}", v);
+                        }
+                        if let Some(v) = minmax {
+                            println!("  MP3GAIN_MINMAX:       {}", v);
+                        }
+                        if let Some(v) = album_minmax {
+                            println!("  MP3GAIN_ALBUM_MINMAX: {}", v);
+                        }
+                        if let Some(v) = track_gain {
+                            println!("  REPLAYGAIN_TRACK_GAIN: {}", v);
+                        }
+                        if let Some(v) = track_peak {
+                            println!("  REPLAYGAIN_TRACK_PEAK: {}", v);
+                        }
+                        if let Some(v) = album_gain {
+                            println!("  REPLAYGAIN_ALBUM_GAIN: {}", v);
+                        }
+                        if let Some(v) = album_peak {
+                            println!("  REPLAYGAIN_ALBUM_PEAK: {}", v);
+                        }
+                        if let Some(v) = reference {
+                            println!("  REPLAYGAIN_REFERENCE_LOUDNESS: {}", v);
+                        }
+                        if nonstandard_undo {
+                            println!(
+                                "  (gain tags not in mp3gain format; --normalize-tags rewrites them)"
+                            );
+                        }
+                        match stored_format {
+                            Some(TagFormat::Id3v2) => println!("  (stored in ID3v2)"),
+                            Some(TagFormat::Both) => println!("  (stored in APEv2 and ID3v2)"),
+                            _ => {}
+                        }
+                        print_provenance(provenance);
+                        print_rva2(&rva2);
+                        print_lame_replaygain(lame.as_ref());
+                        if undo.is_none()
+                            && redo.is_none()
+                            && minmax.is_none()
+                            && track_gain.is_none()
+                            && rva2.is_empty()
+                        {
+                            println!("  (no mp3gain tags found)");
+                        }
+                        println!();
                     }
-                    opts.gain_modifier_db = args[i]
-                        .parse()
-                        .map_err(|_| anyhow::anyhow!("invalid dB value: {}", args[i]))?;
+                    OutputFormat::Tsv => {
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                            filename,
+                            undo.unwrap_or("-"),
+                            minmax.unwrap_or("-"),
+                            track_gain.unwrap_or("-"),
+                            track_peak.unwrap_or("-"),
+                            album_gain.unwrap_or("-"),
+                            album_peak.unwrap_or("-"),
+                            reference.unwrap_or("-")
+                        );
+                    }
+                    OutputFormat::Json => {
+                        let result = JsonFileResult {
+                            file: get_path(file),
+                            status: Some("success".to_string()),
+                            tag_format: stored_format.map(|f| f.as_str().to_string()),
+                            provenance: provenance_source,
+                            provenance_container,
+                            replaygain_track_gain: track_gain.map(String::from),
+                            replaygain_track_peak: track_peak.map(String::from),
+                            replaygain_album_gain: album_gain.map(String::from),
+                            replaygain_album_peak: album_peak.map(String::from),
+                            rva2_gain_db: rva2_master.map(|c| c.adjustment_db),
+                            rva2_peak: rva2_master.and_then(|c| c.peak),
+                            lame_track_gain_db: lame.as_ref().and_then(|i| i.track_gain_db),
+                            lame_album_gain_db: lame.as_ref().and_then(|i| i.album_gain_db),
+                            lame_peak: lame.as_ref().and_then(|i| i.peak),
+                            warnings: nonstandard_undo
+                                .then(|| {
+                                    "gain tags not in mp3gain format; --normalize-tags rewrites them"
+                                        .to_string()
+                                })
+                                .into(),
+                            ..Default::default()
+                        };
+                        // Note: we can add tag info to JSON if needed
+                        json_results.push(result);
+                    }
+                }
+            }
+            Ok(None) => match opts.output_format {
+                OutputFormat::Text => {
+                    println!("{}", filename.cyan().bold());
+                    println!("  (no APE or ID3v2 gain tags found)");
+                    print_provenance(provenance);
+                    print_rva2(&rva2);
+                    print_lame_replaygain(lame.as_ref());
+                    println!();
+                }
+                OutputFormat::Tsv => {
+                    println!("{}\t-\t-\t-\t-\t-\t-\t-", filename);
+                }
+                OutputFormat::Json => {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("no_tag".to_string()),
+                        provenance: provenance_source,
+                        provenance_container,
+                        rva2_gain_db: rva2_master.map(|c| c.adjustment_db),
+                        rva2_peak: rva2_master.and_then(|c| c.peak),
+                        lame_track_gain_db: lame.as_ref().and_then(|i| i.track_gain_db),
+                        lame_album_gain_db: lame.as_ref().and_then(|i| i.album_gain_db),
+                        lame_peak: lame.as_ref().and_then(|i| i.peak),
+                        ..Default::default()
+                    });
+                }
+            },
+            Err(e) => {
+                if file_error(opts, opts.output_format != OutputFormat::Json) {
+                    eprintln!("{} - {}", filename.red(), e);
+                }
+                if opts.output_format == OutputFormat::Json {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("error".to_string()),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        progress_inc(&pb);
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: None,
+            stats: None,
+        };
+        print_json(&output, opts)?;
+    }
+
+    Ok(())
+}
+
+/// Print which tool family gained the file (for -s c)
+fn print_provenance(provenance: Option<Provenance>) {
+    let Some(provenance) = provenance else {
+        return;
+    };
+    match provenance.container {
+        Some(container) => println!(
+            "  Provenance:           {} ({})",
+            provenance.source.as_str(),
+            container.as_str()
+        ),
+        None => println!("  Provenance:           {}", provenance.source.as_str()),
+    }
+}
+
+/// Print the master adjustment of each RVA2 frame (for -s c)
+fn print_rva2(frames: &[id3v2::Rva2Frame]) {
+    for frame in frames {
+        if let Some(master) = frame.master() {
+            print!(
+                "  RVA2 ({}):{:width$}{:+.2} dB",
+                frame.identification,
+                "",
+                master.adjustment_db,
+                width = 14usize.saturating_sub(frame.identification.len())
+            );
+            if let Some(peak) = master.peak {
+                print!(", peak {:.6}", peak);
+            }
+            println!();
+        }
+    }
+}
+
+/// Print the ReplayGain values stored in the LAME tag, if any
+fn print_lame_replaygain(info: Option<&InfoHeader>) {
+    let Some(info) = info else {
+        return;
+    };
+    if let Some(gain) = info.track_gain_db {
+        println!("  LAME track gain:      {:+.1} dB", gain);
+    }
+    if let Some(gain) = info.album_gain_db {
+        println!("  LAME album gain:      {:+.1} dB", gain);
+    }
+    if let Some(peak) = info.peak {
+        println!("  LAME peak:            {:.6}", peak);
+    }
+}
+
+/// Per-file measurement started by [`Stats::begin`]
+struct FileTimer {
+    start: Instant,
+    bytes_read: u64,
+    /// --journal, --hash: the file's states around processing
+    recording: Option<Recording>,
+}
+
+/// Frame, byte and timing totals for --stats, the --journal records and the
+/// --hash digests
+struct Stats {
+    enabled: bool,
+    /// --journal file (None in dry-run mode, which modifies nothing)
+    journal: Option<PathBuf>,
+    hash: bool,
+    operation: &'static str,
+    start: Instant,
+    files: usize,
+    frames: usize,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl Stats {
+    fn new(opts: &Options) -> Self {
+        let operation = match select_action(opts).option() {
+            "" => "analyze",
+            option => option,
+        };
+        Stats {
+            enabled: opts.stats,
+            journal: opts.journal.clone().filter(|_| !opts.dry_run),
+            hash: opts.hash,
+            operation,
+            start: Instant::now(),
+            files: 0,
+            frames: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Start measuring one file (None without --stats, --journal and --hash)
+    ///
+    /// For the journal and --hash the file's tags are read up front; its
+    /// audio data is hashed as the gain pass holds it (see [`Recording`]).
+    fn begin(&self, file: &Path) -> Option<FileTimer> {
+        let hashed = self.journal.is_some() || self.hash;
+        (self.enabled || hashed).then(|| FileTimer {
+            start: Instant::now(),
+            bytes_read: fs::metadata(file).map(|m| m.len()).unwrap_or(0),
+            recording: hashed.then(|| Recording::start(file).ok()).flatten(),
+        })
+    }
+
+    /// Record a file's frames, bytes and time in the totals and in `result`
+    ///
+    /// The file counts as read in full, and as written in full if it was
+    /// successfully modified.
+    fn finish(
+        &mut self,
+        timer: Option<FileTimer>,
+        file: &Path,
+        opts: &Options,
+        result: &mut JsonFileResult,
+    ) {
+        let Some(timer) = timer else {
+            return;
+        };
+        let states = timer.recording.and_then(|r| r.finish(file).ok());
+        let before = states.as_ref().map(|(before, _)| before.clone());
+        // A file left alone keeps its state
+        let after = states.map(|(before, after)| {
+            if result.status.as_deref() == Some("success") {
+                after
+            } else {
+                before
+            }
+        });
+        let after = after.filter(|_| self.journal.is_some() || (self.hash && !opts.dry_run));
+        if let Some(journal) = &self.journal {
+            if let Err(e) = self.write_journal(journal, file, before.clone(), after.clone(), result)
+            {
+                let msg = format!("failed to write journal record: {}", e);
+                side_warning(file, msg, result, opts);
+            }
+        }
+        if self.hash {
+            result.sha256_before = before.map(|s| s.sha256);
+            result.sha256_after = after.map(|s| s.sha256);
+            if opts.output_format == OutputFormat::Text && !opts.quiet {
+                print_hashes(result);
+            }
+        }
+        if !self.enabled {
+            return;
+        }
+        let seconds = timer.start.elapsed().as_secs_f64();
+
+        let modified = result.status.as_deref() == Some("success") && !opts.dry_run;
+        let bytes_written = if modified {
+            fs::metadata(file).map(|m| m.len()).unwrap_or(0)
+        } else {
+            0
+        };
+        let frames = result.frames.unwrap_or(0);
+
+        self.files += 1;
+        self.frames += frames;
+        self.bytes_read += timer.bytes_read;
+        self.bytes_written += bytes_written;
+
+        let stats = JsonStats {
+            files: None,
+            frames,
+            bytes_read: timer.bytes_read,
+            bytes_written,
+            seconds,
+            mb_per_sec: throughput(timer.bytes_read, seconds),
+        };
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!("      {}", format_stats(&stats).dimmed());
+        }
+        result.stats = Some(stats);
+    }
+
+    /// Append the --journal record of a processed file
+    fn write_journal(
+        &self,
+        journal: &Path,
+        file: &Path,
+        before: Option<FileState>,
+        after: Option<FileState>,
+        result: &JsonFileResult,
+    ) -> Result<()> {
+        let record = JsonJournalRecord {
+            timestamp: iso8601_utc(SystemTime::now()),
+            tool_version: VERSION,
+            operation: self.operation,
+            file: get_path(file),
+            status: result.status.as_deref(),
+            gain_applied_steps: result.gain_applied_steps,
+            gain_applied_db: result.gain_applied_db,
+            error: result.error.as_deref(),
+            before,
+            after,
+        };
+        journal::append(journal, &record)
+    }
+
+    /// Totals for JSON output (None without --stats)
+    fn json(&self) -> Option<JsonStats> {
+        if !self.enabled {
+            return None;
+        }
+        let seconds = self.start.elapsed().as_secs_f64();
+        Some(JsonStats {
+            files: Some(self.files),
+            frames: self.frames,
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+            seconds,
+            mb_per_sec: throughput(self.bytes_read, seconds),
+        })
+    }
+
+    /// Print totals in text mode
+    fn print(&self, opts: &Options) {
+        if opts.output_format != OutputFormat::Text {
+            return;
+        }
+        if let Some(stats) = self.json() {
+            println!();
+            println!(
+                "{} {} file(s), {}",
+                "Stats:".cyan().bold(),
+                self.files,
+                format_stats(&stats)
+            );
+        }
+    }
+}
+
+/// --hash in text mode: the digests of one file, dry runs only "before"
+fn print_hashes(result: &JsonFileResult) {
+    let digest = |hash: &Option<String>| hash.clone().unwrap_or_else(|| "-".to_string());
+    if result.sha256_after.is_some() {
+        println!(
+            "      SHA-256 before: {}",
+            digest(&result.sha256_before).dimmed()
+        );
+        println!(
+            "      SHA-256 after:  {}",
+            digest(&result.sha256_after).dimmed()
+        );
+    } else {
+        println!("      SHA-256: {}", digest(&result.sha256_before).dimmed());
+    }
+}
+
+/// MB (10^6 bytes) per second
+fn throughput(bytes: u64, seconds: f64) -> f64 {
+    if seconds > 0.0 {
+        bytes as f64 / 1_000_000.0 / seconds
+    } else {
+        0.0
+    }
+}
+
+fn format_stats(stats: &JsonStats) -> String {
+    format!(
+        "{} frames, {} bytes read, {} bytes written, {:.3} s, {:.1} MB/s",
+        stats.frames, stats.bytes_read, stats.bytes_written, stats.seconds, stats.mb_per_sec
+    )
+}
+
+/// Version of the --sidecar JSON layout
+const SIDECAR_SCHEMA_VERSION: u32 = 1;
+
+/// With --sidecar, write `result` to `<file>.rgain.json` (nothing in dry-run mode)
+fn write_sidecar(file: &Path, result: &mut JsonFileResult, opts: &Options) {
+    if !opts.sidecar || opts.dry_run {
+        return;
+    }
+    let mut path = file.as_os_str().to_owned();
+    path.push(".rgain.json");
+    let sidecar = JsonSidecar {
+        schema_version: SIDECAR_SCHEMA_VERSION,
+        tool_version: VERSION,
+        timestamp: iso8601_utc(SystemTime::now()),
+        result,
+    };
+    let written = serde_json::to_string_pretty(&sidecar)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(fs::write(&path, json + "\n")?));
+    if let Err(e) = written {
+        side_warning(
+            file,
+            format!("failed to write sidecar: {}", e),
+            result,
+            opts,
+        );
+    }
+}
+
+/// Report a problem with an extra output (--journal, --sidecar) of a file
+/// that was itself processed: on stderr in text and TSV mode, and among the
+/// file's warnings in JSON mode so stderr stays empty for JSON consumers
+fn side_warning(file: &Path, msg: String, result: &mut JsonFileResult, opts: &Options) {
+    if opts.output_format == OutputFormat::Json {
+        result.warnings.push(msg);
+    } else {
+        eprintln!(
+            "{}: {}: {}",
+            "warning".yellow().bold(),
+            get_filename(file),
+            msg
+        );
+    }
+}
+
+/// With --since, a recursive run that found no new files has nothing to do
+/// rather than failing; say so and tell the caller to stop
+fn nothing_modified_since(opts: &Options) -> bool {
+    let Some(since) = opts.since else {
+        return false;
+    };
+    if !opts.quiet && opts.output_format != OutputFormat::Json {
+        eprintln!(
+            "{}: no audio files modified since {}, nothing to do",
+            "info".cyan(),
+            iso8601_utc(since)
+        );
+    }
+    true
+}
+
+/// Parse a --max-file-size limit: bytes, or a number with a `K`, `M` or `G`
+/// suffix (decimal units, an optional trailing `B`, any case), e.g. `500MB`
+fn parse_size(s: &str) -> Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid size: {} (use e.g. 500000000, 500MB or 2G)", s);
+    let upper = s.trim().to_ascii_uppercase();
+    let number = upper.strip_suffix('B').unwrap_or(&upper);
+    let (digits, unit) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1_000),
+        Some('M') => (&number[..number.len() - 1], 1_000_000),
+        Some('G') => (&number[..number.len() - 1], 1_000_000_000),
+        _ => (number, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .filter(|&n| n > 0)
+        .ok_or_else(invalid)
+}
+
+/// Parse a --since time
+///
+/// Accepts an RFC 3339 timestamp (`2024-05-01T12:00:00Z`,
+/// `2024-05-01T14:00:00+02:00`), a date (`2024-05-01`, midnight UTC), or an
+/// age counted back from `now`: a number followed by `s`, `m`, `h`, `d` or
+/// `w`, e.g. `36h` or `7d`.
+fn parse_since(s: &str, now: SystemTime) -> Result<SystemTime> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "invalid time: {} (use e.g. 2024-05-01T12:00:00Z, 2024-05-01 or 7d)",
+            s
+        )
+    };
+    let number = |field: Option<&str>| {
+        field
+            .filter(|f| !f.is_empty() && f.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|f| f.parse::<u64>().ok())
+    };
+
+    if let Some(unit) = s.chars().last().filter(|c| !c.is_ascii_digit()) {
+        if let Some(count) = number(s.get(..s.len() - unit.len_utf8())) {
+            let unit_secs = match unit {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                'w' => 7 * 86400,
+                _ => return Err(invalid()),
+            };
+            return count
+                .checked_mul(unit_secs)
+                .and_then(|secs| now.checked_sub(Duration::from_secs(secs)))
+                .ok_or_else(invalid);
+        }
+    }
+
+    // Date, then optionally the time of day and its UTC offset
+    let (year, month, day) = match (
+        number(s.get(..4)),
+        number(s.get(5..7)),
+        number(s.get(8..10)),
+    ) {
+        (Some(y), Some(m), Some(d)) if &s[4..5] == "-" && &s[7..8] == "-" => (y, m, d),
+        _ => return Err(invalid()),
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    let mut secs = days_from_civil(year, month, day) * 86400;
+
+    let rest = &s[10..];
+    if !rest.is_empty() {
+        let time = rest
+            .strip_prefix(['T', 't', ' '])
+            .filter(|t| t.get(2..3) == Some(":") && t.get(5..6) == Some(":"))
+            .ok_or_else(invalid)?;
+        let (hour, minute, second) = match (
+            number(time.get(..2)),
+            number(time.get(3..5)),
+            number(time.get(6..8)),
+        ) {
+            (Some(h), Some(m), Some(s)) if h < 24 && m < 60 && s < 61 => (h, m, s),
+            _ => return Err(invalid()),
+        };
+        secs += hour as i64 * 3600 + minute as i64 * 60 + second as i64;
+
+        // Fractional seconds don't matter for mtimes
+        let offset = time[8..].strip_prefix('.').map_or(&time[8..], |frac| {
+            frac.trim_start_matches(|c: char| c.is_ascii_digit())
+        });
+        secs -= match offset {
+            "Z" | "z" => 0,
+            _ => {
+                let sign = match offset.get(..1) {
+                    Some("+") => 1,
+                    Some("-") => -1,
+                    _ => return Err(invalid()),
+                };
+                match (number(offset.get(1..3)), number(offset.get(4..6))) {
+                    (Some(h), Some(m)) if offset.len() == 6 && &offset[3..4] == ":" => {
+                        sign * (h as i64 * 3600 + m as i64 * 60)
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+        };
+    }
+
+    u64::try_from(secs)
+        .ok()
+        .and_then(|secs| std::time::UNIX_EPOCH.checked_add(Duration::from_secs(secs)))
+        .ok_or_else(invalid)
+}
+
+/// Days from 1970-01-01 to a civil date (Howard Hinnant's days_from_civil)
+fn days_from_civil(year: u64, month: u64, day: u64) -> i64 {
+    let (year, month, day) = (year as i64, month as i64, day as i64);
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// `time` as an ISO-8601 UTC timestamp, e.g. 2024-05-01T12:00:00Z
+fn iso8601_utc(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, time_of_day) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's days_from_civil inverse)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    )
+}
+
+/// Per-file errors reported so far, for the --quiet-errors summary
+static FILE_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Count a per-file error and tell whether to print it
+///
+/// `shown` says whether the caller prints errors in the current output mode;
+/// with --quiet-errors the message is dropped but the error still counts.
+fn file_error(opts: &Options, shown: bool) -> bool {
+    FILE_ERRORS.fetch_add(1, Ordering::Relaxed);
+    shown && !opts.quiet_errors
+}
+
+/// Files --check-clipping found would clip at the target, for the exit status
+static CLIPPING_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Files skipped with --skip-locked, reported separately from errors
+static LOCKED_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether `e` was caused by the file staying busy or locked through all
+/// --retry attempts
+fn is_locked_error(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(is_transient_io_error)
+}
+
+/// Result for a failed write; with --skip-locked a locked file is skipped
+/// and counted on its own instead of as an error
+fn apply_error_result(file: &Path, e: anyhow::Error, opts: &Options) -> JsonFileResult {
+    let filename = get_filename(file);
+    if opts.skip_locked && is_locked_error(&e) {
+        LOCKED_FILES.fetch_add(1, Ordering::Relaxed);
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!("  {} {} - locked, skipped", "-".yellow(), filename);
+        }
+        return JsonFileResult {
+            file: get_path(file),
+            status: Some("locked".to_string()),
+            error: Some(e.to_string()),
+            ..Default::default()
+        };
+    }
+
+    if file_error(
+        opts,
+        opts.output_format == OutputFormat::Text && !opts.quiet,
+    ) {
+        eprintln!("  {} {} - {}", "x".red(), filename, e);
+    }
+    JsonFileResult {
+        file: get_path(file),
+        status: Some("error".to_string()),
+        error: Some(e.to_string()),
+        ..Default::default()
+    }
+}
+
+fn update_counters(result: &JsonFileResult, successful: &mut usize, failed: &mut usize) {
+    match result.status.as_deref() {
+        Some("success") => *successful += 1,
+        Some("error") => *failed += 1,
+        _ => {}
+    }
+}
+
+/// Print one JSON document: pretty by default, on a single line with --json-compact
+fn print_json<T: Serialize>(output: &T, opts: &Options) -> Result<()> {
+    let json = if opts.json_compact {
+        serde_json::to_string(output)?
+    } else {
+        serde_json::to_string_pretty(output)?
+    };
+    println!("{}", json);
+    Ok(())
+}
+
+fn create_json_summary(
+    total_files: usize,
+    successful: usize,
+    failed: usize,
+    dry_run: bool,
+) -> JsonSummary {
+    JsonSummary {
+        total_files,
+        successful,
+        failed,
+        locked: match LOCKED_FILES.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n),
+        },
+        dry_run: if dry_run { Some(true) } else { None },
+    }
+}
+
+fn print_dry_run_notice(opts: &Options) {
+    if opts.dry_run && !opts.quiet && opts.output_format == OutputFormat::Text {
+        println!();
+        println!("{}", "No files were modified.".yellow());
+    }
+}
+
+fn cmd_apply(files: &[PathBuf], steps: i32, opts: &Options) -> Result<()> {
+    // With --skip-unchanged each file is still reported as unchanged
+    if steps == 0 && !opts.skip_unchanged {
+        if opts.output_format == OutputFormat::Json {
+            let output = JsonOutput {
+                files: Some(vec![]),
+                album: None,
+                albums: None,
+                summary: Some(create_json_summary(files.len(), 0, 0, opts.dry_run)),
+                stats: None,
+            };
+            print_json(&output, opts)?;
+        } else if !opts.quiet {
+            println!("{}: gain is 0, nothing to do", "info".cyan());
+        }
+        return Ok(());
+    }
+
+    let db_value = steps_to_db(steps);
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} {} {} step(s) ({:+.1} dB) to {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            if opts.dry_run {
+                "Would apply"
+            } else {
+                "Applying"
+            },
+            steps,
+            db_value,
+            files.len()
+        );
+        if opts.wrap_gain {
+            println!("  {} Wrap mode enabled", "!".yellow());
+        }
+        println!();
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let timer = stats.begin(file);
+        let mut result = process_apply(file, steps, opts)?;
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
+
+        if opts.output_format == OutputFormat::Tsv {
+            if let Ok(info) = analyze(file) {
+                println!(
+                    "{}\t{}\t{:.1}\t{:.6}\t{}\t{}",
+                    filename, steps, db_value, 1.0, info.max_gain, info.min_gain
+                );
+            }
+        }
+
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
+        }
+
+        progress_inc(&pb);
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
+    }
+
+    Ok(())
+}
+
+/// Print each frame's offset and current/projected global_gain values as TSV
+///
+/// One `# <file>` line per file, then one line per frame:
+/// `offset<TAB>current<TAB>projected`, with the granule/channel values
+/// comma-separated in side-info order. Projection uses `-g` (default 0) and `-w`.
+fn cmd_dump_frames(files: &[PathBuf], opts: &Options) -> Result<()> {
+    let steps = opts.gain_steps.unwrap_or(0);
+
+    for file in files {
+        match frame_gains(file, steps, opts.wrap_gain) {
+            Ok(frames) => {
+                println!("# {}", get_path(file));
+                for frame in frames {
+                    println!(
+                        "{}\t{}\t{}",
+                        frame.offset,
+                        join_gains(&frame.current),
+                        join_gains(&frame.projected)
+                    );
+                }
+            }
+            Err(e) => {
+                if file_error(opts, true) {
+                    eprintln!("{} - {}", get_filename(file).red(), e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// --list-frames-json: header fields and global_gain of each frame of one
+/// file, for bug reports and external tools
+fn cmd_list_frames_json(files: &[PathBuf], opts: &Options) -> Result<()> {
+    let [file] = files else {
+        anyhow::bail!("--list-frames-json takes a single file");
+    };
+
+    let headers = frame_headers(file, usize::MAX)?;
+    let mut gains: HashMap<usize, Vec<u8>> = frame_gains(file, 0, false)?
+        .into_iter()
+        .map(|frame| (frame.offset, frame.current))
+        .collect();
+
+    let total_frames = headers.len();
+    let frames = headers
+        .into_iter()
+        .take(opts.frame_limit.unwrap_or(usize::MAX))
+        .map(|header| {
+            let channels = if header.channel_mode == "Mono" { 1 } else { 2 };
+            let global_gain = gains
+                .remove(&header.offset)
+                .map(|values| values.chunks(channels).map(<[u8]>::to_vec).collect());
+            JsonFrame {
+                header: header.into(),
+                global_gain,
+            }
+        })
+        .collect();
+
+    print_json(
+        &JsonFrameList {
+            file: get_path(file),
+            total_frames,
+            frames,
+        },
+        opts,
+    )
+}
+
+/// Files --estimate times before projecting the whole batch
+const ESTIMATE_SAMPLES: usize = 3;
+
+/// How long --estimate decodes a sampled file before extrapolating
+const ESTIMATE_DECODE_BUDGET: Duration = Duration::from_millis(500);
+
+/// --estimate: time `action` on a few files and project the whole batch
+///
+/// Frame-domain actions are timed with a frame scan of each sampled file;
+/// decoding ones (ReplayGain analysis) with a decode that stops after
+/// [`ESTIMATE_DECODE_BUDGET`] and is extrapolated to the whole file. The
+/// throughput measured is applied to the size of every file. Nothing is
+/// modified.
+fn cmd_estimate(opts: &Options, action: Action) -> Result<()> {
+    let files: Vec<&PathBuf> = opts
+        .files
+        .iter()
+        .chain(opts.groups.iter().flatten())
+        .collect();
+    let decodes = action_decodes(action, opts);
+    let text = opts.output_format != OutputFormat::Json && !opts.quiet;
+    let operation = match action {
+        Action::Info => "analysis",
+        _ => action.option(),
+    };
+
+    if text {
+        println!(
+            "{} Estimating {} for {} file(s) ({})",
+            "mp3rgain".green().bold(),
+            operation,
+            files.len(),
+            if decodes {
+                "decodes audio"
+            } else {
+                "MP3 frames only"
+            }
+        );
+        println!();
+    }
+
+    // Evenly spread over the batch, so one folder of huge files doesn't
+    // decide the estimate alone
+    let samples = files.len().min(ESTIMATE_SAMPLES);
+    let sampled: Vec<usize> = (0..samples).map(|k| k * files.len() / samples).collect();
+    let mut rates = Vec::with_capacity(samples);
+    for &i in &sampled {
+        match sample_seconds_per_byte(files[i], decodes, opts) {
+            Ok(rate) => rates.push(rate),
+            Err(e) => {
+                if file_error(opts, text) {
+                    eprintln!("  {} {} - {}", "x".red(), get_filename(files[i]), e);
+                }
+            }
+        }
+    }
+    if rates.is_empty() {
+        anyhow::bail!("no file could be sampled for --estimate");
+    }
+    let seconds_per_byte = rates.iter().sum::<f64>() / rates.len() as f64;
+
+    let estimates: Vec<JsonEstimateFile> = files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let bytes = fs::metadata(file).map(|m| m.len()).unwrap_or(0);
+            JsonEstimateFile {
+                file: get_path(file),
+                bytes,
+                seconds: bytes as f64 * seconds_per_byte,
+                sampled: sampled.contains(&i),
+            }
+        })
+        .collect();
+    let total_bytes: u64 = estimates.iter().map(|e| e.bytes).sum();
+    let total_seconds: f64 = estimates.iter().map(|e| e.seconds).sum();
+    let mb_per_sec = 1.0 / seconds_per_byte / 1_000_000.0;
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonEstimateOutput {
+            action: action.option(),
+            decodes,
+            sampled_files: rates.len(),
+            mb_per_sec,
+            total_bytes,
+            total_seconds,
+            files: estimates,
+        };
+        return print_json(&output, opts);
+    }
+
+    if !opts.quiet {
+        println!("  Sampled {} file(s): {:.1} MB/s", rates.len(), mb_per_sec);
+        println!();
+        for estimate in &estimates {
+            println!(
+                "  {:<40} {:>8.1} MB {:>10}{}",
+                get_filename(Path::new(&estimate.file)),
+                estimate.bytes as f64 / 1_000_000.0,
+                format_eta(estimate.seconds),
+                if estimate.sampled { "  (sampled)" } else { "" }
+            );
+        }
+        println!();
+    }
+    println!(
+        "{} {} for {:.1} MB",
+        "Estimated total:".cyan().bold(),
+        format_eta(total_seconds),
+        total_bytes as f64 / 1_000_000.0
+    );
+
+    Ok(())
+}
+
+/// Whether `action` decodes audio, which is far slower than frame access
+fn action_decodes(action: Action, opts: &Options) -> bool {
+    match action {
+        Action::Doctor
+        | Action::CompareTools
+        | Action::CheckClipping
+        | Action::RgDebug
+        | Action::Measure
+        | Action::Match(_)
+        | Action::AlbumGain
+        | Action::TrackGain => true,
+        Action::MaxAmplitude => opts.decode_peak,
+        // The mp3gain-style TSV and text layouts include ReplayGain analysis
+        Action::Info => opts.legacy_output || opts.output_format == OutputFormat::Tsv,
+        _ => false,
+    }
+}
+
+/// Seconds per byte of input for one sampled file
+fn sample_seconds_per_byte(file: &Path, decodes: bool, opts: &Options) -> Result<f64> {
+    let bytes = fs::metadata(file)?.len().max(1) as f64;
+    let start = Instant::now();
+    if !decodes {
+        analyze(file)?;
+        return Ok(start.elapsed().as_secs_f64() / bytes);
+    }
+
+    // Without a known track length there is no progress to stop at, and the
+    // whole file is decoded
+    let cancel = CancellationToken::new();
+    let mut done = 1.0_f32;
+    let result = replaygain::analyze_track_cancellable(file, opts.track_index, &cancel, |f| {
+        done = f;
+        if start.elapsed() >= ESTIMATE_DECODE_BUDGET {
+            cancel.cancel();
+        }
+    });
+    match result {
+        Ok(_) => {}
+        Err(e) if e.downcast_ref::<Interrupted>().is_some() => {}
+        Err(e) => return Err(e),
+    }
+    Ok(start.elapsed().as_secs_f64() / (bytes * done.max(1e-3) as f64))
+}
+
+/// A duration for --estimate, e.g. `12.3 s`, `4 min 05 s` or `2 h 07 min`
+fn format_eta(seconds: f64) -> String {
+    let whole = seconds.round() as u64;
+    if seconds < 60.0 {
+        format!("{:.1} s", seconds)
+    } else if whole < 3600 {
+        format!("{} min {:02} s", whole / 60, whole % 60)
+    } else {
+        format!("{} h {:02} min", whole / 3600, whole % 3600 / 60)
+    }
+}
+
+/// Number of frame headers listed by --doctor
+const DOCTOR_FRAMES: usize = 5;
+
+/// --doctor: run parsing, tag, analysis, gain and decoding checks on each file
+///
+/// Nothing is modified; the gain check only previews +1 step.
+fn cmd_doctor(files: &[PathBuf], opts: &Options) -> Result<()> {
+    let reports: Vec<JsonDoctorReport> = files.iter().map(|file| diagnose(file)).collect();
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonDoctorOutput {
+            version: VERSION,
+            files: reports,
+        };
+        print_json(&output, opts)?;
+        return Ok(());
+    }
+
+    for report in &reports {
+        println!(
+            "{} {} {}",
+            "mp3rgain".green().bold(),
+            VERSION,
+            format!("doctor: {}", report.file).bold()
+        );
+        for check in &report.checks {
+            let status = match check.status {
+                "ok" => "ok".green(),
+                "warning" => "warning".yellow(),
+                "failed" => "failed".red(),
+                _ => "skipped".dimmed(),
+            };
+            println!("  {:<9} {:<14} {}", status, check.name, check.detail);
+        }
+        if let Some(frames) = &report.frames {
+            println!();
+            println!("  First {} frame(s):", frames.len());
+            for frame in frames {
+                println!(
+                    "    @{:<8} {} {}, {} kbps, {} Hz, {} bytes{}{}",
+                    frame.offset,
+                    frame.mpeg_version,
+                    frame.channel_mode,
+                    frame.bitrate_kbps,
+                    frame.sample_rate,
+                    frame.frame_size,
+                    if frame.crc { ", CRC" } else { "" },
+                    if frame.info_frame { " (Xing/Info)" } else { "" }
+                );
+            }
+        }
+        let count = |status| report.checks.iter().filter(|c| c.status == status).count();
+        println!();
+        println!(
+            "  {} {} ok, {} warning(s), {} failed, {} skipped",
+            "Summary:".cyan().bold(),
+            count("ok"),
+            count("warning"),
+            count("failed"),
+            count("skipped")
+        );
+        println!();
+    }
+
+    Ok(())
+}
+
+/// Run the --doctor checks on one file
+fn diagnose(file: &Path) -> JsonDoctorReport {
+    let mut report = JsonDoctorReport {
+        file: get_path(file),
+        checks: Vec::new(),
+        frames: None,
+    };
+    let mut check = |name, status, detail: String| {
+        report.checks.push(JsonDoctorCheck {
+            name,
+            status,
+            detail,
+        })
+    };
+
+    match fs::metadata(file) {
+        Ok(meta) => check("read", "ok", format!("{} bytes", meta.len())),
+        Err(e) => {
+            check("read", "failed", e.to_string());
+            return report;
+        }
+    }
+
+    let file_type = detect_audio_type(file);
+    let is_mp4 = file_type == Some(AudioFileType::Aac);
+    let is_adts = file_type == Some(AudioFileType::AacAdts);
+    let is_flac = file_type == Some(AudioFileType::Flac);
+    match file_type {
+        Some(AudioFileType::Aac) => check("format", "ok", "MP4/M4A container".to_string()),
+        Some(AudioFileType::AacAdts) => check("format", "ok", "raw AAC (ADTS)".to_string()),
+        Some(AudioFileType::Flac) => check("format", "ok", "FLAC stream".to_string()),
+        Some(AudioFileType::Mp3) => check("format", "ok", "MPEG audio".to_string()),
+        None => check(
+            "format",
+            "warning",
+            "no MP4, FLAC, ADTS or MPEG audio header found".to_string(),
+        ),
+    }
+
+    match id3v2::read_tag_summary(file) {
+        Ok(Some(tag)) => check(
+            "id3v2",
+            "ok",
+            format!(
+                "ID3v2.{} tag, {} bytes, frames: {}",
+                tag.major_version,
+                tag.size,
+                tag.frame_ids.join(" ")
+            ),
+        ),
+        Ok(None) => check("id3v2", "skipped", "no ID3v2.3/2.4 tag".to_string()),
+        Err(e) => check("id3v2", "failed", e.to_string()),
+    }
+
+    match read_ape_tag_from_file(file) {
+        Ok(Some(tag)) => {
+            let keys: Vec<&str> = tag.items().iter().map(|item| item.key.as_str()).collect();
+            check(
+                "apev2",
+                "ok",
+                format!("{} item(s): {}", keys.len(), keys.join(" ")),
+            )
+        }
+        Ok(None) => check("apev2", "skipped", "no APEv2 tag".to_string()),
+        Err(e) => check("apev2", "failed", e.to_string()),
+    }
+
+    if is_mp4 {
+        match mp4meta::read_replaygain_tags(file) {
+            Ok(tags) if tags.is_empty() => {
+                check("mp4_tags", "skipped", "no ReplayGain tags".to_string())
+            }
+            Ok(tags) => check(
+                "mp4_tags",
+                "ok",
+                format!(
+                    "track gain {}, album gain {}",
+                    tags.track_gain.as_deref().unwrap_or("-"),
+                    tags.album_gain.as_deref().unwrap_or("-")
+                ),
+            ),
+            Err(e) => check("mp4_tags", "failed", e.to_string()),
+        }
+    }
+
+    if is_flac {
+        match flac::read_replaygain_tag(file) {
+            Ok(None) => check(
+                "vorbis_comments",
+                "skipped",
+                "no ReplayGain tags".to_string(),
+            ),
+            Ok(Some(tag)) => check(
+                "vorbis_comments",
+                "ok",
+                format!(
+                    "track gain {}, album gain {}",
+                    tag.get(TAG_REPLAYGAIN_TRACK_GAIN).unwrap_or("-"),
+                    tag.get(TAG_REPLAYGAIN_ALBUM_GAIN).unwrap_or("-")
+                ),
+            ),
+            Err(e) => check("vorbis_comments", "failed", e.to_string()),
+        }
+    }
+
+    if replaygain::is_available() {
+        check("replaygain", "ok", "feature compiled in".to_string());
+    } else {
+        check(
+            "replaygain",
+            "warning",
+            "feature not compiled in; -r, -a and decoding are unavailable".to_string(),
+        );
+    }
+
+    if is_mp4 || is_adts || is_flac {
+        for name in ["frames", "analysis", "info_header", "gain_preview"] {
+            check(name, "skipped", "not an MP3 file".to_string());
+        }
+    } else {
+        let mut frames = None;
+        match frame_headers(file, DOCTOR_FRAMES) {
+            Ok(headers) if headers.is_empty() => check(
+                "frames",
+                "failed",
+                "no MPEG Layer III frame found".to_string(),
+            ),
+            Ok(headers) => {
+                check(
+                    "frames",
+                    "ok",
+                    format!("first {} frame(s) parsed", headers.len()),
+                );
+                frames = Some(headers.into_iter().map(JsonDoctorFrame::from).collect());
+            }
+            Err(e) => check("frames", "failed", e.to_string()),
+        }
+
+        match analyze(file) {
+            Ok(info) => {
+                let mut detail = format!(
+                    "{} frames, {} {}, {} Hz, global_gain {}-{}",
+                    info.frame_count,
+                    info.mpeg_version,
+                    info.channel_mode,
+                    info.sample_rate,
+                    info.min_gain,
+                    info.max_gain
+                );
+                let junk = info.leading_bytes + info.trailing_bytes;
+                if junk > 0 {
+                    detail += &format!(
+                        "; {} junk byte(s) before and {} after the audio",
+                        info.leading_bytes, info.trailing_bytes
+                    );
+                }
+                if info.id3v2_gap > 0 {
+                    detail += &format!(
+                        "; {} byte(s) of padding before the first frame",
+                        info.id3v2_gap
+                    );
+                }
+                if info.bad_frames > 0 {
+                    detail += &format!(
+                        "; {} corrupt frame(s) skipped, the first at byte {}",
+                        info.bad_frames, info.bad_frame_offsets[0]
+                    );
+                }
+                let status = if junk > 0 || info.bad_frames > 0 {
+                    "warning"
+                } else {
+                    "ok"
+                };
+                check("analysis", status, detail);
+            }
+            Err(e) => check("analysis", "failed", e.to_string()),
+        }
+
+        match read_info_header(file) {
+            Ok(Some(info)) => check(
+                "info_header",
+                "ok",
+                format!(
+                    "{} header, encoder {}",
+                    info.marker,
+                    info.encoder.as_deref().unwrap_or("unknown")
+                ),
+            ),
+            Ok(None) => check("info_header", "skipped", "no Xing/Info header".to_string()),
+            Err(e) => check("info_header", "failed", e.to_string()),
+        }
+
+        let preview = frame_gains(file, 1, false)
+            .and_then(|gains| Ok((gains, preview_undo_tag(file, 1, false)?)));
+        match preview {
+            Ok((gains, tag)) => {
+                let changed = gains.iter().filter(|f| f.projected != f.current).count();
+                let detail = format!(
+                    "+1 step would change {} of {} frames, recording {}={}",
+                    changed,
+                    gains.len(),
+                    TAG_MP3GAIN_UNDO,
+                    tag.get(TAG_MP3GAIN_UNDO).unwrap_or("")
+                );
+                let status = if changed == 0 { "warning" } else { "ok" };
+                check("gain_preview", status, detail);
+            }
+            Err(e) => check("gain_preview", "failed", e.to_string()),
+        }
+
+        report.frames = frames;
+    }
+
+    if !replaygain::is_available() {
+        check(
+            "decode",
+            "skipped",
+            "replaygain feature not compiled in".to_string(),
+        );
+    } else {
+        match replaygain::analyze_track(file) {
+            Ok(result) if result.method == AnalysisMethod::Decoded => check(
+                "decode",
+                "ok",
+                format!(
+                    "loudness {:.1} dB, peak {:.4}, track gain {:+.1} dB",
+                    result.loudness_db, result.peak, result.gain_db
+                ),
+            ),
+            Ok(result) => check(
+                "decode",
+                "warning",
+                format!(
+                    "could not decode; heuristic estimate {:+.1} dB",
+                    result.gain_db
+                ),
+            ),
+            Err(e) => check("decode", "failed", e.to_string()),
+        }
+    }
+
+    report
+}
+
+/// Widest bar drawn by --gain-histogram in text mode
+const HISTOGRAM_BAR_WIDTH: u64 = 40;
+
+/// Print how many global_gain fields of each file hold each value
+///
+/// JSON gives every file's 256 counts as `gain_histogram`, indexed by value,
+/// and TSV one `file<TAB>value<TAB>count` line per value, ready to plot. Text
+/// output draws a bar for each value from the file's lowest to its highest.
+fn cmd_gain_histogram(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if opts.output_format == OutputFormat::Tsv {
+        println!("File\tglobal_gain\tCount");
+    }
+
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+
+    for file in files {
+        let filename = get_filename(file);
+        let info = match analyze(file) {
+            Ok(info) => info,
+            Err(e) => {
+                if file_error(opts, opts.output_format != OutputFormat::Json) {
+                    eprintln!("{} - {}", filename.red(), e);
+                }
+                if opts.output_format == OutputFormat::Json {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("error".to_string()),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    });
+                }
+                continue;
+            }
+        };
+        let histogram = &info.gain_histogram;
+
+        match opts.output_format {
+            OutputFormat::Text => {
+                println!("{}", filename.cyan().bold());
+                let largest = histogram.iter().copied().max().unwrap_or(0).max(1);
+                for value in info.min_gain..=info.max_gain {
+                    let count = histogram[value as usize];
+                    let bar = (count * HISTOGRAM_BAR_WIDTH).div_ceil(largest) as usize;
+                    println!(
+                        "  {:>3} {:<width$} {}",
+                        value,
+                        "#".repeat(bar),
+                        count,
+                        width = HISTOGRAM_BAR_WIDTH as usize
+                    );
+                }
+                println!();
+            }
+            OutputFormat::Tsv => {
+                for (value, count) in histogram.iter().enumerate() {
+                    println!("{}\t{}\t{}", get_filename(file), value, count);
+                }
+            }
+            OutputFormat::Json => json_results.push(JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                min_gain: Some(info.min_gain),
+                max_gain: Some(info.max_gain),
+                gain_locations: Some(info.gain_locations),
+                gain_histogram: Some(histogram.to_vec()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: None,
+            stats: None,
+        };
+        print_json(&output, opts)?;
+    }
+
+    Ok(())
+}
+
+/// Print the frame-domain estimate of each MP3's peak and loudness next to
+/// the decoded analysis, and the difference, to show how far the heuristic
+/// used for undecodable files (and by --peak-normalize-heuristic) is off
+fn cmd_compare_tools(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if !replaygain::is_available() {
+        eprintln!(
+            "{}: --compare-tools requires the 'replaygain' feature for the decoded analysis",
+            "error".red().bold()
+        );
+        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
+        std::process::exit(1);
+    }
+
+    if opts.output_format == OutputFormat::Tsv {
+        println!(
+            "File\tHeuristic peak dBFS\tDecoded peak dBFS\tPeak delta dB\t\
+             Heuristic loudness dB\tDecoded loudness dB\tLoudness delta dB\t\
+             Heuristic headroom\tDecoded headroom"
+        );
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let compared = replaygain::estimate_track(file).and_then(|heuristic| {
+            let decoded = replaygain::analyze_track_with_index(file, opts.track_index)?;
+            let (heuristic, decoded) = (JsonLevels::of(&heuristic), JsonLevels::of(&decoded));
+            let delta = heuristic.minus(&decoded);
+            Ok(JsonToolComparison {
+                heuristic,
+                decoded,
+                delta,
+            })
+        });
+        progress_inc(&pb);
+
+        let comparison = match compared {
+            Ok(comparison) => comparison,
+            Err(e) => {
+                if file_error(opts, opts.output_format != OutputFormat::Json) {
+                    eprintln!("{} - {}", filename.red(), e);
+                }
+                if opts.output_format == OutputFormat::Json {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("error".to_string()),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    });
+                }
+                continue;
+            }
+        };
+        let (heuristic, decoded, delta) = (
+            &comparison.heuristic,
+            &comparison.decoded,
+            &comparison.delta,
+        );
+
+        match opts.output_format {
+            OutputFormat::Text => {
+                println!("{}", filename.cyan().bold());
+                println!(
+                    "               {:>12} {:>12} {:>10}",
+                    "Heuristic", "Decoded", "Delta"
+                );
+                println!(
+                    "  Peak:        {:>7.2} dBFS {:>7.2} dBFS {:>+7.2} dB",
+                    heuristic.peak_dbfs, decoded.peak_dbfs, delta.peak_dbfs
+                );
+                println!(
+                    "  Loudness:    {:>9.2} dB {:>9.2} dB {:>+7.2} dB",
+                    heuristic.loudness_db, decoded.loudness_db, delta.loudness_db
+                );
+                println!(
+                    "  Headroom:    {:>6} steps {:>6} steps {:>+4} steps",
+                    heuristic.headroom_steps, decoded.headroom_steps, delta.headroom_steps
+                );
+                println!();
+            }
+            OutputFormat::Tsv => {
+                println!(
+                    "{}\t{:.2}\t{:.2}\t{:.2}\t{:.2}\t{:.2}\t{:.2}\t{}\t{}",
+                    filename,
+                    heuristic.peak_dbfs,
+                    decoded.peak_dbfs,
+                    delta.peak_dbfs,
+                    heuristic.loudness_db,
+                    decoded.loudness_db,
+                    delta.loudness_db,
+                    heuristic.headroom_steps,
+                    decoded.headroom_steps
+                );
+            }
+            OutputFormat::Json => json_results.push(JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                compare_tools: Some(comparison),
+                ..Default::default()
+            }),
+        }
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: None,
+            stats: None,
+        };
+        print_json(&output, opts)?;
+    }
+
+    Ok(())
+}
+
+/// --check-clipping: analyze every file and list those whose track gain
+/// would clip at the target (-d, -m), without writing anything
+///
+/// The exit status is 2 if any would (see `main`), for CI gates.
+fn cmd_check_clipping(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if !replaygain::is_available() {
+        eprintln!(
+            "{}: --check-clipping requires the 'replaygain' feature",
+            "error".red().bold()
+        );
+        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
+        std::process::exit(1);
+    }
+
+    let target_db = replaygain_target_db(opts);
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{} Checking {} file(s) for clipping at {} dB",
+            "mp3rgain".green().bold(),
+            files.len(),
+            target_db
+        );
+        if opts.gain_modifier != 0 {
+            println!("  Gain modifier: {:+} steps", opts.gain_modifier);
+        }
+        println!();
+    } else if opts.output_format == OutputFormat::Tsv {
+        println!("File\tPeak\tGain steps\tClips");
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let (mut checked, mut clipping, mut failed) = (0, 0, 0);
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let analyzed = analyze_track(file, opts);
+        progress_inc(&pb);
+
+        let result = match analyzed {
+            Ok(result) => result,
+            Err(e) => {
+                failed += 1;
+                if file_error(opts, opts.output_format != OutputFormat::Json) {
+                    eprintln!("{} - {}", filename.red(), e);
+                }
+                if opts.output_format == OutputFormat::Json {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("error".to_string()),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    });
+                }
+                continue;
+            }
+        };
+        checked += 1;
+
+        let steps = result.gain_steps_for_target(target_db) + opts.gain_modifier;
+        let gain_db = steps_to_db(steps);
+        let clips = replaygain::would_clip(result.peak, gain_db);
+        if clips {
+            clipping += 1;
+            CLIPPING_FILES.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match opts.output_format {
+            OutputFormat::Text => {
+                if clips && !opts.quiet {
+                    println!(
+                        "  {} {} ({:+} steps, peak {:.4}, {:.2} dB over full scale)",
+                        "!".yellow(),
+                        filename,
+                        steps,
+                        result.peak,
+                        -replaygain::clip_margin_db(result.peak, gain_db)
+                    );
+                }
+            }
+            OutputFormat::Tsv => {
+                println!(
+                    "{}\t{:.6}\t{}\t{}",
+                    filename,
+                    result.peak,
+                    steps,
+                    if clips { "yes" } else { "no" }
+                );
+            }
+            OutputFormat::Json => json_results.push(JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                suggested_gain_steps: Some(steps),
+                method: Some(result.method.as_str()),
+                peak: Some(result.peak),
+                clips: Some(clips),
+                ..Default::default()
+            }),
+        }
+    }
+
+    progress_finish(pb);
+
+    match opts.output_format {
+        OutputFormat::Json => {
+            let output = JsonOutput {
+                files: Some(json_results),
+                album: None,
+                albums: None,
+                summary: Some(JsonSummary {
+                    total_files: files.len(),
+                    successful: checked,
+                    failed,
+                    locked: None,
+                    dry_run: None,
+                }),
+                stats: None,
+            };
+            print_json(&output, opts)?;
+        }
+        OutputFormat::Text if !opts.quiet => {
+            if clipping == 0 {
+                println!("  No file would clip");
+            } else {
+                println!();
+                println!("{} of {} file(s) would clip", clipping, checked);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// --rg-debug: print how each file's loudness was measured, to find out why
+/// two ReplayGain tools disagree
+fn cmd_rg_debug(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if !replaygain::is_available() {
+        eprintln!(
+            "{}: --rg-debug requires the 'replaygain' feature",
+            "error".red().bold()
+        );
+        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
+        std::process::exit(1);
+    }
+
+    if opts.output_format == OutputFormat::Tsv {
+        println!("File\tFilter rate\tWindow samples\tWindows\tPercentile bucket\tLoudness");
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let (mut successful, mut failed) = (0, 0);
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let analyzed = replaygain::analyze_track_downmixed(
+            file,
+            opts.track_index,
+            opts.skip,
+            opts.downmix,
+            opts.best_effort,
+        );
+        progress_inc(&pb);
+
+        let track = match analyzed {
+            Ok(track) => track,
+            Err(e) => {
+                failed += 1;
+                if file_error(opts, opts.output_format != OutputFormat::Json) {
+                    eprintln!("{} - {}", filename.red(), e);
                 }
-                "m" => {
-                    i += 1;
-                    if i >= args.len() {
-                        eprintln!("{}: -m requires an argument", "error".red().bold());
-                        std::process::exit(1);
-                    }
-                    opts.gain_modifier = args[i]
-                        .parse()
-                        .map_err(|_| anyhow::anyhow!("invalid modifier value: {}", args[i]))?;
+                if opts.output_format == OutputFormat::Json {
+                    json_results.push(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("error".to_string()),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    });
                 }
-                "s" => {
-                    i += 1;
-                    if i >= args.len() {
-                        eprintln!("{}: -s requires an argument", "error".red().bold());
-                        std::process::exit(1);
-                    }
-                    match args[i].as_str() {
-                        "c" => opts.stored_tag_mode = StoredTagMode::Check,
-                        "d" => opts.stored_tag_mode = StoredTagMode::Delete,
-                        "s" => opts.stored_tag_mode = StoredTagMode::Skip,
-                        "r" => opts.stored_tag_mode = StoredTagMode::Recalc,
-                        "i" => {
-                            opts.stored_tag_mode = StoredTagMode::UseId3v2;
-                            eprintln!(
-                                "{}: -s i (ID3v2 tags) not fully supported, using APEv2",
-                                "warning".yellow().bold()
-                            );
-                        }
-                        "a" => opts.stored_tag_mode = StoredTagMode::UseApev2,
-                        other => {
-                            eprintln!(
-                                "{}: unknown -s mode '{}', use c/d/s/r/i/a",
-                                "error".red().bold(),
-                                other
-                            );
-                            std::process::exit(1);
-                        }
-                    }
+                continue;
+            }
+        };
+        successful += 1;
+
+        let params = track.params(silence_gate(opts));
+        let loudness_db = match silence_gate(opts) {
+            Some(gate) => track.with_silence_gate(gate).result.loudness_db,
+            None => track.result.loudness_db,
+        };
+        let bucket = params
+            .percentile_bucket
+            .map_or("-".to_string(), |bucket| bucket.to_string());
+        let meta = replaygain::AlgorithmInfo::new(replaygain_target_db(opts));
+
+        match opts.output_format {
+            OutputFormat::Text => {
+                println!("{}", filename.cyan().bold());
+                println!(
+                    "  Filter:      {} Hz coefficients",
+                    params.filter_sample_rate
+                );
+                println!("  Window:      {} samples (50 ms)", params.window_samples);
+                println!("  Windows:     {}", params.windows);
+                println!("  Percentile:  bucket {} (95th)", bucket);
+                println!("  Loudness:    {:.2} dB", loudness_db);
+                println!(
+                    "  Algorithm:   {} (pink reference {} dB, target {} dB, mp3rgain {})",
+                    meta.algorithm, meta.pink_ref, meta.target_db, meta.version
+                );
+                println!();
+            }
+            OutputFormat::Tsv => println!(
+                "{}\t{}\t{}\t{}\t{}\t{:.2}",
+                filename,
+                params.filter_sample_rate,
+                params.window_samples,
+                params.windows,
+                bucket,
+                loudness_db
+            ),
+            OutputFormat::Json => json_results.push(JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                loudness_db: Some(loudness_db),
+                analysis_params: Some(params),
+                replaygain_meta: Some(meta),
+                ..Default::default()
+            }),
+        }
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(files.len(), successful, failed, false)),
+            stats: None,
+        };
+        print_json(&output, opts)?;
+    }
+
+    Ok(())
+}
+
+fn join_gains(gains: &[u8]) -> String {
+    gains
+        .iter()
+        .map(|g| g.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A gain computed from each file's global_gain values alone, without decoding
+#[derive(Clone, Copy)]
+enum FrameTarget {
+    /// --avg-target: shift the average global_gain to this value
+    AvgGain(u8),
+    /// --peak-normalize-heuristic: bring the peak estimated from the largest
+    /// global_gain to this level in dBFS
+    PeakDbfs(f64),
+}
+
+impl FrameTarget {
+    fn steps(&self, info: &Mp3Analysis) -> i32 {
+        match *self {
+            FrameTarget::AvgGain(target) => avg_target_steps(info, target),
+            FrameTarget::PeakDbfs(dbfs) => peak_target_steps_heuristic(info, dbfs),
+        }
+    }
+
+    /// Where the file starts and where it should end up, for text output
+    fn describe(&self, info: &Mp3Analysis) -> String {
+        match *self {
+            FrameTarget::AvgGain(target) => format!("average {:.1} -> {}", info.avg_gain, target),
+            FrameTarget::PeakDbfs(dbfs) => format!(
+                "estimated peak {:.1} dBFS -> {:.1} dBFS",
+                (f64::from(info.max_gain) - 255.0) * GAIN_STEP_DB,
+                dbfs
+            ),
+        }
+    }
+}
+
+/// --avg-target and --peak-normalize-heuristic: apply the gain `target` asks
+/// of each file
+fn cmd_frame_target(files: &[PathBuf], target: FrameTarget, opts: &Options) -> Result<()> {
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        match target {
+            FrameTarget::AvgGain(avg) => println!(
+                "{}{} Leveling {} file(s) to average global_gain {}",
+                dry_run_prefix,
+                "mp3rgain".green().bold(),
+                files.len(),
+                avg
+            ),
+            FrameTarget::PeakDbfs(dbfs) => println!(
+                "{}{} Normalizing {} file(s) to an estimated peak of {:.1} dBFS (approximate, from global_gain)",
+                dry_run_prefix,
+                "mp3rgain".green().bold(),
+                files.len(),
+                dbfs
+            ),
+        }
+        println!();
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let timer = stats.begin(file);
+        let mut result = match analyze(file) {
+            Ok(info) => {
+                let steps = target.steps(&info);
+
+                if opts.output_format == OutputFormat::Text && !opts.quiet {
+                    println!(
+                        "  {} {}: {} ({:+} steps)",
+                        "->".cyan(),
+                        filename,
+                        target.describe(&info),
+                        steps
+                    );
                 }
-                "o" => {
-                    // mp3gain compatibility: -o without argument means TSV output
-                    // Check if next arg is a valid format specifier
-                    let next_is_format = if i + 1 < args.len() {
-                        matches!(
-                            args[i + 1].to_lowercase().as_str(),
-                            "json" | "text" | "tsv" | "db"
-                        )
-                    } else {
-                        false
-                    };
 
-                    if next_is_format {
-                        i += 1;
-                        match args[i].to_lowercase().as_str() {
-                            "json" => opts.output_format = OutputFormat::Json,
-                            "text" => opts.output_format = OutputFormat::Text,
-                            "tsv" | "db" => opts.output_format = OutputFormat::Tsv,
-                            _ => unreachable!(),
-                        }
-                    } else {
-                        // mp3gain compatible: -o alone means TSV
-                        opts.output_format = OutputFormat::Tsv;
+                if steps == 0 {
+                    if opts.output_format == OutputFormat::Text && !opts.quiet {
+                        println!("  {} {} (no adjustment needed)", ".".cyan(), filename);
                     }
-                }
-                "l" => {
-                    // -l <channel> <gain> : apply gain to specific channel
-                    i += 1;
-                    if i >= args.len() {
-                        eprintln!(
-                            "{}: -l requires two arguments: <channel> <gain>",
-                            "error".red().bold()
-                        );
-                        std::process::exit(1);
+                    JsonFileResult {
+                        file: get_path(file),
+                        status: no_adjustment_status(opts),
+                        avg_gain: Some(info.avg_gain),
+                        max_gain: Some(info.max_gain),
+                        gain_applied_steps: Some(0),
+                        gain_applied_db: Some(0.0),
+                        ..Default::default()
                     }
-                    let channel_arg: usize = args[i].parse().map_err(|_| {
-                        anyhow::anyhow!(
-                            "invalid channel number: {} (use 0 for left, 1 for right)",
-                            args[i]
-                        )
-                    })?;
-                    let channel = Channel::from_index(channel_arg).ok_or_else(|| {
-                        anyhow::anyhow!(
-                            "invalid channel: {} (use 0 for left, 1 for right)",
-                            channel_arg
-                        )
-                    })?;
+                } else {
+                    let mut result = process_apply(file, steps, opts)?;
+                    result.avg_gain = Some(info.avg_gain);
+                    result.max_gain = Some(info.max_gain);
+                    result
+                }
+            }
+            Err(e) => {
+                if file_error(
+                    opts,
+                    opts.output_format == OutputFormat::Text && !opts.quiet,
+                ) {
+                    eprintln!("  {} {} - {}", "x".red(), filename, e);
+                }
+                JsonFileResult {
+                    file: get_path(file),
+                    status: Some("error".to_string()),
+                    error: Some(e.to_string()),
+                    ..Default::default()
+                }
+            }
+        };
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
 
-                    i += 1;
-                    if i >= args.len() {
-                        eprintln!(
-                            "{}: -l requires two arguments: <channel> <gain>",
-                            "error".red().bold()
-                        );
-                        std::process::exit(1);
-                    }
-                    let gain: i32 = args[i]
-                        .parse()
-                        .map_err(|_| anyhow::anyhow!("invalid gain value: {}", args[i]))?;
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
+        }
+
+        progress_inc(&pb);
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
+    }
+
+    Ok(())
+}
+
+/// Adjustment stored in a file that --apply-rva2 or --apply-replaygain-tag
+/// applies to global_gain
+#[derive(Clone, Copy, PartialEq)]
+enum StoredAdjustment {
+    /// The ID3v2 RVA2 master adjustment of the track
+    Rva2,
+    /// REPLAYGAIN_TRACK_GAIN in the APEv2 or ID3v2 gain tag
+    ReplayGainTag,
+}
+
+impl StoredAdjustment {
+    fn label(&self) -> &'static str {
+        match self {
+            StoredAdjustment::Rva2 => "RVA2",
+            StoredAdjustment::ReplayGainTag => "ReplayGain",
+        }
+    }
 
-                    opts.channel_gain = Some((channel, gain));
+    /// The adjustment in dB and the peak stored with it, if the file has one
+    fn read(&self, file: &Path) -> Result<Option<(f64, Option<f64>)>> {
+        match self {
+            StoredAdjustment::Rva2 => id3v2::read_rva2_from_file(file)
+                .map(|frames| id3v2::track_master(&frames).map(|c| (c.adjustment_db, c.peak))),
+            StoredAdjustment::ReplayGainTag => Ok(read_gain_tag(file)?.and_then(|tag| {
+                let peak = tag
+                    .get(TAG_REPLAYGAIN_TRACK_PEAK)
+                    .and_then(|p| p.trim().parse().ok());
+                Some((tag.replaygain_track_gain_db()?, peak))
+            })),
+        }
+    }
+
+    /// Leave `remaining_db` of the adjustment in the file, now that
+    /// `applied_db` of it is in global_gain, so players don't apply it twice
+    ///
+    /// Returns `false` if it couldn't be rewritten.
+    fn write_remaining(
+        &self,
+        file: &Path,
+        remaining_db: f64,
+        applied_db: f64,
+        opts: &Options,
+    ) -> Result<bool> {
+        match self {
+            StoredAdjustment::Rva2 => id3v2::set_track_master_adjustment(file, remaining_db),
+            StoredAdjustment::ReplayGainTag => {
+                let Some(mut tag) = read_gain_tag(file)? else {
+                    return Ok(false);
+                };
+                // Peaks and the album gain move with the audio
+                let scale = 10.0_f64.powf(applied_db / 20.0);
+                let scaled = |tag: &ApeTag, key: &str| {
+                    tag.get(key)
+                        .and_then(|p| p.trim().parse::<f64>().ok())
+                        .map(|peak| format!("{:.6}", peak * scale))
+                };
+                tag.set(
+                    TAG_REPLAYGAIN_TRACK_GAIN,
+                    &format!("{:+.2} dB", remaining_db),
+                );
+                if let Some(peak) = scaled(&tag, TAG_REPLAYGAIN_TRACK_PEAK) {
+                    tag.set(TAG_REPLAYGAIN_TRACK_PEAK, &peak);
                 }
-                "r" => opts.track_gain = true,
-                "a" => opts.album_gain = true,
-                "e" => opts.skip_album = true,
-                "x" => opts.max_amplitude_only = true,
-                "i" => {
-                    i += 1;
-                    if i >= args.len() {
-                        eprintln!("{}: -i requires an argument", "error".red().bold());
-                        std::process::exit(1);
-                    }
-                    opts.track_index = Some(
-                        args[i]
-                            .parse()
-                            .map_err(|_| anyhow::anyhow!("invalid track index: {}", args[i]))?,
+                if let Some(album_db) = tag.replaygain_album_gain_db() {
+                    tag.set(
+                        TAG_REPLAYGAIN_ALBUM_GAIN,
+                        &format!("{:+.2} dB", album_db - applied_db),
+                    );
+                }
+                if let Some(peak) = scaled(&tag, TAG_REPLAYGAIN_ALBUM_PEAK) {
+                    tag.set(TAG_REPLAYGAIN_ALBUM_PEAK, &peak);
+                }
+                // Back wherever the tags are, so no stale copy is left
+                let format = stored_gain_tag_format(file)?.unwrap_or(opts.tag_format);
+                write_gain_tag(file, &tag, format)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Record the stored adjustment in a file's JSON result
+    fn report(&self, result: &mut JsonFileResult, adjustment_db: f64, peak: Option<f64>) {
+        match self {
+            StoredAdjustment::Rva2 => {
+                result.rva2_gain_db = Some(adjustment_db);
+                result.rva2_peak = peak;
+            }
+            StoredAdjustment::ReplayGainTag => {
+                result.replaygain_track_gain = Some(format!("{:+.2} dB", adjustment_db));
+                result.replaygain_track_peak = peak.map(|p| format!("{:.6}", p));
+            }
+        }
+    }
+}
+
+/// Apply each file's stored adjustment (RVA2 master adjustment or
+/// ReplayGain track gain) to global_gain
+///
+/// Whatever isn't applied (the sub-step remainder, or a -k reduction) is
+/// written back so players don't apply it a second time. A file without an
+/// RVA2 adjustment is skipped; one without a ReplayGain track gain is an
+/// error, since baking in that tag was asked for.
+fn cmd_apply_stored(files: &[PathBuf], source: StoredAdjustment, opts: &Options) -> Result<()> {
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+    let label = source.label();
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} Applying {} adjustment to {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            label,
+            files.len()
+        );
+        println!();
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let stored = source.read(file).and_then(|stored| match stored {
+            None if source == StoredAdjustment::ReplayGainTag => {
+                anyhow::bail!("no {} tag to apply", TAG_REPLAYGAIN_TRACK_GAIN)
+            }
+            stored => Ok(stored),
+        });
+
+        let timer = stats.begin(file);
+        let mut result = match stored {
+            Ok(Some((adjustment_db, peak))) => {
+                let steps = db_to_steps(adjustment_db);
+
+                if opts.output_format == OutputFormat::Text && !opts.quiet {
+                    println!(
+                        "  {} {}: {} {:+.2} dB ({:+} steps)",
+                        "->".cyan(),
+                        filename,
+                        label,
+                        adjustment_db,
+                        steps
                     );
                 }
-                "u" => opts.undo = true,
-                "p" => opts.preserve_timestamp = true,
-                "c" => opts.ignore_clipping = true,
-                "k" => opts.prevent_clipping = true,
-                "q" => opts.quiet = true,
-                "R" => opts.recursive = true,
-                "n" => opts.dry_run = true,
-                "w" => opts.wrap_gain = true,
-                "t" => opts.use_temp_file = true,
-                "f" => opts.assume_mpeg2 = true,
-                "v" | "-version" => {
-                    print_version();
-                    std::process::exit(0);
-                }
-                "h" | "-help" => {
-                    print_usage();
-                    std::process::exit(0);
-                }
-                // Handle combined short flags like -qp, -kc, etc.
-                _ if flag.chars().all(|c| "pqckuranRewxtf".contains(c)) => {
-                    for c in flag.chars() {
-                        match c {
-                            'p' => opts.preserve_timestamp = true,
-                            'q' => opts.quiet = true,
-                            'c' => opts.ignore_clipping = true,
-                            'k' => opts.prevent_clipping = true,
-                            'u' => opts.undo = true,
-                            'r' => opts.track_gain = true,
-                            'a' => opts.album_gain = true,
-                            'n' => opts.dry_run = true,
-                            'R' => opts.recursive = true,
-                            'e' => opts.skip_album = true,
-                            'w' => opts.wrap_gain = true,
-                            'x' => opts.max_amplitude_only = true,
-                            't' => opts.use_temp_file = true,
-                            'f' => opts.assume_mpeg2 = true,
-                            _ => {}
+
+                let mut result = if steps == 0 {
+                    if opts.output_format == OutputFormat::Text && !opts.quiet {
+                        println!("  {} {} (no adjustment needed)", ".".cyan(), filename);
+                    }
+                    JsonFileResult {
+                        file: get_path(file),
+                        status: Some("skipped".to_string()),
+                        gain_applied_steps: Some(0),
+                        gain_applied_db: Some(0.0),
+                        ..Default::default()
+                    }
+                } else {
+                    let preserved = PreservedMetadata::capture(file, opts);
+
+                    let mut result = process_apply(file, steps, opts)?;
+                    if result.status.as_deref() == Some("success") {
+                        let applied_db = steps_to_db(result.gain_applied_steps.unwrap_or(0));
+                        let remaining_db = adjustment_db - applied_db;
+                        match source.write_remaining(file, remaining_db, applied_db, opts) {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                let msg = format!(
+                                    "{} adjustment could not be updated in place; \
+                                     players may apply it again",
+                                    label
+                                );
+                                if opts.output_format == OutputFormat::Text && !opts.quiet {
+                                    eprintln!("  {} {} - {}", "!".yellow(), filename, msg);
+                                }
+                                result.warnings.push(msg);
+                            }
+                            Err(e) => {
+                                let msg = format!("failed to update {} adjustment: {}", label, e);
+                                if file_error(
+                                    opts,
+                                    opts.output_format == OutputFormat::Text && !opts.quiet,
+                                ) {
+                                    eprintln!("  {} {} - {}", "x".red(), filename, msg);
+                                }
+                                result.status = Some("error".to_string());
+                                result.error = Some(msg);
+                            }
                         }
+                        preserved.restore(file);
                     }
+                    result
+                };
+                source.report(&mut result, adjustment_db, peak);
+                result
+            }
+            Ok(None) => {
+                let msg = "no RVA2 master volume adjustment";
+                if opts.output_format == OutputFormat::Text && !opts.quiet {
+                    println!("  {} {} ({})", ".".cyan(), filename, msg);
                 }
-                // Handle -g with attached value (e.g., -g2)
-                _ if flag.starts_with('g') => {
-                    let val = &flag[1..];
-                    opts.gain_steps = Some(
-                        val.parse()
-                            .map_err(|_| anyhow::anyhow!("invalid gain value: {}", val))?,
-                    );
-                }
-                // Handle -d with attached value (e.g., -d4.5)
-                _ if flag.starts_with('d') => {
-                    let val = &flag[1..];
-                    opts.gain_modifier_db = val
-                        .parse()
-                        .map_err(|_| anyhow::anyhow!("invalid dB value: {}", val))?;
-                }
-                // Handle -m with attached value (e.g., -m2)
-                _ if flag.starts_with('m') => {
-                    let val = &flag[1..];
-                    opts.gain_modifier = val
-                        .parse()
-                        .map_err(|_| anyhow::anyhow!("invalid modifier value: {}", val))?;
+                JsonFileResult {
+                    file: get_path(file),
+                    status: Some("skipped".to_string()),
+                    warnings: Warnings::from(msg.to_string()),
+                    ..Default::default()
                 }
-                // Handle -i with attached value (e.g., -i1)
-                _ if flag.starts_with('i') => {
-                    let val = &flag[1..];
-                    opts.track_index = Some(
-                        val.parse()
-                            .map_err(|_| anyhow::anyhow!("invalid track index: {}", val))?,
-                    );
+            }
+            Err(e) => {
+                if file_error(
+                    opts,
+                    opts.output_format == OutputFormat::Text && !opts.quiet,
+                ) {
+                    eprintln!("  {} {} - {}", "x".red(), filename, e);
                 }
-                _ => {
-                    eprintln!("{}: unknown option: -{}", "warning".yellow().bold(), flag);
+                JsonFileResult {
+                    file: get_path(file),
+                    status: Some("error".to_string()),
+                    error: Some(e.to_string()),
+                    ..Default::default()
                 }
             }
-        } else if !arg.starts_with("--") {
-            // It's a file
-            opts.files.push(PathBuf::from(arg));
+        };
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
+
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
         }
 
-        i += 1;
+        progress_inc(&pb);
     }
 
-    Ok(opts)
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
+    }
+
+    Ok(())
 }
 
-fn expand_files_recursive(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
-    let mut result = Vec::new();
+/// Describe left/right channel gains, e.g. "+3 step(s) (+4.5 dB) to left channel"
+fn describe_channel_gains(left: i32, right: i32) -> String {
+    let describe = |steps: i32| format!("{:+} step(s) ({:+.1} dB)", steps, steps_to_db(steps));
+    match (left, right) {
+        (l, 0) => format!("{} to left channel", describe(l)),
+        (0, r) => format!("{} to right channel", describe(r)),
+        (l, r) => format!(
+            "{} to left and {} to right channel",
+            describe(l),
+            describe(r)
+        ),
+    }
+}
 
-    for path in paths {
-        if path.is_dir() {
-            collect_audio_files(path, &mut result)?;
-        } else {
-            result.push(path.clone());
+fn cmd_apply_channel(files: &[PathBuf], left: i32, right: i32, opts: &Options) -> Result<()> {
+    if left == 0 && right == 0 {
+        if opts.output_format == OutputFormat::Json {
+            let output = JsonOutput {
+                files: Some(vec![]),
+                album: None,
+                albums: None,
+                summary: Some(create_json_summary(files.len(), 0, 0, opts.dry_run)),
+                stats: None,
+            };
+            print_json(&output, opts)?;
+        } else if !opts.quiet {
+            println!("{}: gain is 0, nothing to do", "info".cyan());
         }
+        return Ok(());
     }
 
-    result.sort();
-    Ok(result)
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} {} {} of {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            if opts.dry_run {
+                "Would apply"
+            } else {
+                "Applying"
+            },
+            describe_channel_gains(left, right),
+            files.len()
+        );
+        println!();
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let timer = stats.begin(file);
+        let mut result = process_apply_channel(file, left, right, opts)?;
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
+
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
+        }
+
+        progress_inc(&pb);
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
+    }
+
+    Ok(())
 }
 
-fn collect_audio_files(dir: &Path, result: &mut Vec<PathBuf>) -> Result<()> {
-    for entry in std::fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
+fn cmd_info(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if opts.legacy_output {
+        return cmd_legacy_info(files, opts);
+    }
+
+    // Print mp3gain-compatible TSV header
+    if opts.output_format == OutputFormat::Tsv {
+        println!("File\tMP3 gain\tdB gain\tMax Amplitude\tMax global_gain\tMin global_gain");
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let result = process_info(file, opts)?;
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
+        }
+
+        progress_inc(&pb);
+    }
+
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: None,
+            stats: None,
+        };
+        print_json(&output, opts)?;
+    }
+
+    Ok(())
+}
+
+/// --legacy-output: analysis in the text layout of the original mp3gain
+///
+/// Lines, field names and `%f`/`%d` formatting follow mp3gain 1.6 so that
+/// scripts scraping its interactive output keep working: a block per file,
+/// then the album recommendation when more than one file is analyzed.
+/// Nothing is colored and no progress bar is shown.
+fn cmd_legacy_info(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if !replaygain::is_available() {
+        eprintln!(
+            "{}: --legacy-output requires the 'replaygain' feature",
+            "error".red().bold()
+        );
+        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
+        std::process::exit(1);
+    }
+
+    // mp3gain compatible: -d moves the target, -m adds whole steps
+    let target_db = replaygain_target_db(opts);
+    // mp3gain's clipping check, on its 16-bit sample scale
+    let would_clip =
+        |peak: f64, steps: i32| peak * 32768.0 * 2.0_f64.powf(steps as f64 / 4.0) > 32767.0;
+
+    let mut analyzed = Vec::with_capacity(files.len());
+    for file in files {
+        let result = match replaygain::analyze_track_with_index(file, opts.track_index) {
+            Ok(result) => result,
+            Err(e) => {
+                if file_error(opts, true) {
+                    eprintln!("{} - {}", get_path(file), e);
+                }
+                continue;
+            }
+        };
+        let (_, max_gain, min_gain) = find_max_amplitude(file).unwrap_or((1.0, 255, 0));
+        let gain_db = result.gain_for_target(target_db);
+        let steps = result.gain_steps_for_target(target_db) + opts.gain_modifier;
+
+        println!("{}", get_path(file));
+        println!("Recommended \"Track\" dB change: {:.6}", gain_db);
+        println!("Recommended \"Track\" mp3 gain change: {}", steps);
+        if would_clip(result.peak, steps) {
+            println!("WARNING: some clipping may occur with this gain change!");
+        }
+        println!(
+            "Max PCM sample at current gain: {:.6}",
+            result.peak * 32768.0
+        );
+        println!("Max mp3 global gain field: {}", max_gain);
+        println!("Min mp3 global gain field: {}", min_gain);
+        println!();
+
+        analyzed.push((file, result.peak));
+    }
+
+    if analyzed.len() > 1 {
+        let file_refs: Vec<&Path> = analyzed.iter().map(|(file, _)| file.as_path()).collect();
+        let album = analyze_album(&file_refs, opts)?;
+        let steps = album.album_gain_steps_for_target(target_db) + opts.gain_modifier;
 
-        if path.is_dir() {
-            collect_audio_files(&path, result)?;
-        } else if let Some(ext) = path.extension() {
-            if ext.eq_ignore_ascii_case("mp3")
-                || ext.eq_ignore_ascii_case("m4a")
-                || ext.eq_ignore_ascii_case("aac")
-                || ext.eq_ignore_ascii_case("mp4")
-            {
-                result.push(path);
+        println!(
+            "Recommended \"Album\" dB change for all files: {:.6}",
+            album.album_gain_for_target(target_db)
+        );
+        println!(
+            "Recommended \"Album\" mp3 gain change for all files: {}",
+            steps
+        );
+        for (file, peak) in &analyzed {
+            if would_clip(*peak, steps) {
+                println!(
+                    "WARNING: with this global gain change, some clipping may occur in file {}",
+                    get_path(file)
+                );
             }
         }
     }
@@ -469,206 +5757,235 @@ fn collect_audio_files(dir: &Path, result: &mut Vec<PathBuf>) -> Result<()> {
     Ok(())
 }
 
-fn run(mut opts: Options) -> Result<()> {
-    // Validate options
-    if opts.files.is_empty() {
-        eprintln!("{}: no files specified", "error".red().bold());
-        std::process::exit(1);
+/// -u, or --redo to apply the undone gain again
+fn cmd_undo(files: &[PathBuf], opts: &Options) -> Result<()> {
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} {} gain changes on {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            match (opts.redo, opts.dry_run) {
+                (false, true) => "Would undo",
+                (false, false) => "Undoing",
+                (true, true) => "Would redo",
+                (true, false) => "Redoing",
+            },
+            files.len()
+        );
+        println!();
     }
 
-    // Expand files if recursive mode
-    if opts.recursive {
-        opts.files = expand_files_recursive(&opts.files)?;
-        if opts.files.is_empty() {
-            eprintln!("{}: no audio files found (MP3/M4A)", "error".red().bold());
-            std::process::exit(1);
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let timer = stats.begin(file);
+        let mut result = process_undo(file, opts)?;
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
+
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
         }
-    }
 
-    // -f option warning (assume MPEG2)
-    if opts.assume_mpeg2 && !opts.quiet && opts.output_format == OutputFormat::Text {
-        eprintln!(
-            "{}: -f (assume MPEG2) is accepted for compatibility but has no effect",
-            "note".cyan()
-        );
+        progress_inc(&pb);
     }
 
-    // Determine action based on options
-    if opts.max_amplitude_only {
-        // -x: only find max amplitude
-        return cmd_max_amplitude(&opts.files, &opts);
-    }
+    progress_finish(pb);
 
-    if opts.stored_tag_mode == StoredTagMode::Delete {
-        // -s d: delete stored tag info
-        return cmd_delete_tags(&opts.files, &opts);
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
     }
 
-    if opts.stored_tag_mode == StoredTagMode::Check {
-        // -s c: check/show stored tag info
-        return cmd_check_tags(&opts.files, &opts);
-    }
+    Ok(())
+}
 
-    if opts.undo {
-        // -u: undo from APEv2 tags
-        return cmd_undo(&opts.files, &opts);
-    }
+fn cmd_write_minmax(files: &[PathBuf], opts: &Options) -> Result<()> {
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
-    if opts.album_gain && !opts.skip_album {
-        // -a: apply album gain (ReplayGain)
-        return cmd_album_gain(&opts.files, &opts);
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} Recording {} for {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            TAG_MP3GAIN_MINMAX,
+            files.len()
+        );
+        println!();
     }
 
-    if opts.track_gain || opts.skip_album {
-        // -r or -e: apply track gain (ReplayGain)
-        return cmd_track_gain(&opts.files, &opts);
-    }
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
 
-    if let Some((channel, steps)) = opts.channel_gain {
-        // -l: apply channel-specific gain
-        return cmd_apply_channel(&opts.files, channel, steps, &opts);
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
+
+        let timer = stats.begin(file);
+        let mut result = process_write_minmax(file, opts);
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
+
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
+        }
+
+        progress_inc(&pb);
     }
 
-    if let Some(steps) = opts.gain_steps {
-        // -g: apply fixed gain steps
-        cmd_apply(&opts.files, steps, &opts)
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
     } else {
-        // Default: analyze files (mp3gain compatible)
-        // With -d modifier, perform ReplayGain analysis
-        cmd_info(&opts.files, &opts)
+        stats.print(opts);
+        print_dry_run_notice(opts);
     }
+
+    Ok(())
 }
 
-// =============================================================================
-// Progress Bar
-// =============================================================================
+/// --normalize-tags: rewrite MP3GAIN_UNDO/REDO/MINMAX written by other taggers
+fn cmd_normalize_tags(files: &[PathBuf], opts: &Options) -> Result<()> {
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
-fn create_progress_bar(total: usize, opts: &Options) -> Option<ProgressBar> {
-    if opts.quiet || opts.output_format != OutputFormat::Text || total < PROGRESS_THRESHOLD {
-        return None;
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} Normalizing {} for {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            TAG_MP3GAIN_UNDO,
+            files.len()
+        );
+        println!();
     }
 
-    let pb = ProgressBar::new(total as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.cyan} [{bar:40.cyan/blue}] {pos}/{len} {msg}")
-            .unwrap()
-            .progress_chars("=>-"),
-    );
-    Some(pb)
-}
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
 
-fn progress_set_message(pb: &Option<ProgressBar>, msg: &str) {
-    if let Some(ref pb) = pb {
-        pb.set_message(msg.to_string());
-    }
-}
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
 
-fn progress_inc(pb: &Option<ProgressBar>) {
-    if let Some(ref pb) = pb {
-        pb.inc(1);
+        let timer = stats.begin(file);
+        let mut result = process_normalize_tags(file, opts);
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
+
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
+        }
+
+        progress_inc(&pb);
     }
-}
 
-fn progress_finish(pb: Option<ProgressBar>) {
-    if let Some(pb) = pb {
-        pb.finish_and_clear();
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
     }
+
+    Ok(())
 }
 
-// =============================================================================
-// Commands
-// =============================================================================
+/// --album-gain-db: write a known album gain (and peak) into each file's tags
+fn cmd_set_album_tags(
+    files: &[PathBuf],
+    gain_db: f64,
+    peak: Option<f64>,
+    opts: &Options,
+) -> Result<()> {
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
-fn cmd_max_amplitude(files: &[PathBuf], opts: &Options) -> Result<()> {
     if opts.output_format == OutputFormat::Text && !opts.quiet {
         println!(
-            "{} Finding maximum amplitude for {} file(s)",
+            "{}{} Writing album gain {:+.2} dB{} to {} file(s)",
+            dry_run_prefix,
             "mp3rgain".green().bold(),
+            gain_db,
+            peak.map(|p| format!(", peak {:.6}", p)).unwrap_or_default(),
             files.len()
         );
         println!();
     }
 
     let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
     let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
 
     for file in files {
         let filename = get_filename(file);
-        progress_set_message(&pb, filename);
+        progress_set_message(&pb, &filename);
 
-        match find_max_amplitude(file) {
-            Ok((max_amp, max_gain, min_gain)) => {
-                // Convert to PCM scale (like mp3gain: 0-32768+)
-                let max_pcm_sample = max_amp * 32768.0;
-                let headroom_db = if max_amp > 0.0 {
-                    -20.0 * max_amp.log10()
-                } else {
-                    f64::INFINITY
-                };
-
-                // Check if peak is at clipping threshold (Symphonia MP3 decoder limitation)
-                // Only applies to MP3 files - AAC/M4A decoder doesn't have this issue
-                let is_mp3 = file
-                    .extension()
-                    .map(|e| e.eq_ignore_ascii_case("mp3"))
-                    .unwrap_or(false);
-                let may_clip = is_mp3 && max_amp >= 0.9999;
+        let timer = stats.begin(file);
+        let mut result = process_set_album_tags(file, gain_db, peak, opts);
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
 
-                match opts.output_format {
-                    OutputFormat::Text => {
-                        if !opts.quiet {
-                            println!("{}", filename.cyan().bold());
-                            println!("  Max PCM sample: {:.6}", max_pcm_sample);
-                            if may_clip {
-                                println!(
-                                    "  {}",
-                                    "  (may be clipped - actual peak could be higher)".yellow()
-                                );
-                            }
-                            println!("  Headroom:       {:+.2} dB", headroom_db);
-                            println!("  Max global_gain: {}", max_gain);
-                            println!("  Min global_gain: {}", min_gain);
-                            println!();
-                        } else {
-                            println!("{}\t{:.6}\t{:.2}", filename, max_pcm_sample, headroom_db);
-                        }
-                    }
-                    OutputFormat::Tsv => {
-                        println!(
-                            "{}\t{:.6}\t{:.2}\t{}\t{}",
-                            filename, max_pcm_sample, headroom_db, max_gain, min_gain
-                        );
-                    }
-                    OutputFormat::Json => {
-                        let mut result = JsonFileResult {
-                            file: file.display().to_string(),
-                            max_amplitude: Some(max_pcm_sample),
-                            headroom_db: Some(headroom_db),
-                            max_gain: Some(max_gain),
-                            min_gain: Some(min_gain),
-                            ..Default::default()
-                        };
-                        if may_clip {
-                            result.warning =
-                                Some("peak may be clipped - actual value could be higher".into());
-                        }
-                        json_results.push(result);
-                    }
-                }
-            }
-            Err(e) => {
-                if opts.output_format == OutputFormat::Json {
-                    json_results.push(JsonFileResult {
-                        file: file.display().to_string(),
-                        status: Some("error".to_string()),
-                        error: Some(e.to_string()),
-                        ..Default::default()
-                    });
-                } else if !opts.quiet {
-                    eprintln!("{} - {}", filename.red(), e);
-                }
-            }
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
         }
 
         progress_inc(&pb);
@@ -680,92 +5997,82 @@ fn cmd_max_amplitude(files: &[PathBuf], opts: &Options) -> Result<()> {
         let output = JsonOutput {
             files: Some(json_results),
             album: None,
-            summary: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
         };
-        println!("{}", serde_json::to_string_pretty(&output)?);
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
     }
 
     Ok(())
 }
 
-fn cmd_delete_tags(files: &[PathBuf], opts: &Options) -> Result<()> {
+fn cmd_track_gain(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if !replaygain::is_available() {
+        eprintln!(
+            "{}: ReplayGain analysis requires the 'replaygain' feature",
+            "error".red().bold()
+        );
+        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
+        std::process::exit(1);
+    }
+
     let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
     if opts.output_format == OutputFormat::Text && !opts.quiet {
         println!(
-            "{}{} {} ReplayGain tags from {} file(s)",
+            "{}{} Analyzing and {} track gain to {} file(s)",
             dry_run_prefix,
             "mp3rgain".green().bold(),
             if opts.dry_run {
-                "Would delete"
+                "would apply"
             } else {
-                "Deleting"
+                "applying"
             },
             files.len()
         );
+        println!("  Target: {} dB (ReplayGain 1.0)", REPLAYGAIN_REFERENCE_DB);
+        if opts.gain_modifier != 0 {
+            println!("  Gain modifier: {:+} steps", opts.gain_modifier);
+        }
         println!();
     }
 
+    // --as-one: a single analysis of all files, applied to each of them
+    let stream = opts
+        .as_one
+        .then(|| analyze_as_one(files, opts))
+        .transpose()?;
+
     let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
     let mut json_results: Vec<JsonFileResult> = Vec::new();
     let mut successful = 0;
     let mut failed = 0;
 
     for file in files {
         let filename = get_filename(file);
-        progress_set_message(&pb, filename);
-
-        if opts.dry_run {
-            if opts.output_format == OutputFormat::Text && !opts.quiet {
-                println!(
-                    "  {} [DRY RUN] {} (would delete tags)",
-                    "~".cyan(),
-                    filename
-                );
-            }
-            json_results.push(JsonFileResult {
-                file: file.display().to_string(),
-                status: Some("dry_run".to_string()),
-                dry_run: Some(true),
-                ..Default::default()
-            });
-        } else {
-            // Save original timestamp if needed
-            let original_mtime = if opts.preserve_timestamp {
-                std::fs::metadata(file).ok().and_then(|m| m.modified().ok())
-            } else {
-                None
-            };
+        progress_set_message(&pb, &filename);
 
-            match delete_ape_tag(file) {
-                Ok(()) => {
-                    if let Some(mtime) = original_mtime {
-                        restore_timestamp(file, mtime);
-                    }
+        let timer = stats.begin(file);
+        let mut result = match &stream {
+            Some(stream) => process_stream_gain(file, stream, opts)?,
+            None => process_track_gain(file, opts)?,
+        };
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
 
-                    if opts.output_format == OutputFormat::Text && !opts.quiet {
-                        println!("  {} {} (tags deleted)", "v".green(), filename);
-                    }
-                    successful += 1;
-                    json_results.push(JsonFileResult {
-                        file: file.display().to_string(),
-                        status: Some("success".to_string()),
-                        ..Default::default()
-                    });
-                }
-                Err(e) => {
-                    if opts.output_format == OutputFormat::Text && !opts.quiet {
-                        eprintln!("  {} {} - {}", "x".red(), filename, e);
-                    }
-                    failed += 1;
-                    json_results.push(JsonFileResult {
-                        file: file.display().to_string(),
-                        status: Some("error".to_string()),
-                        error: Some(e.to_string()),
-                        ..Default::default()
-                    });
-                }
-            }
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
         }
 
         progress_inc(&pb);
@@ -777,126 +6084,314 @@ fn cmd_delete_tags(files: &[PathBuf], opts: &Options) -> Result<()> {
         let output = JsonOutput {
             files: Some(json_results),
             album: None,
+            albums: None,
             summary: Some(create_json_summary(
                 files.len(),
                 successful,
                 failed,
                 opts.dry_run,
             )),
+            stats: stats.json(),
         };
-        println!("{}", serde_json::to_string_pretty(&output)?);
-    } else if opts.dry_run && !opts.quiet {
-        println!();
-        println!("{}", "No files were modified.".yellow());
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
     }
 
     Ok(())
 }
 
-fn cmd_check_tags(files: &[PathBuf], opts: &Options) -> Result<()> {
+/// Track analysis for -r, reusing a cached analysis with --cache
+///
+/// Files the cache can't analyze go through the normal path, which falls back
+/// to a frame-domain estimate for undecodable MP3s.
+fn analyze_track(file: &Path, opts: &Options) -> Result<ReplayGainResult> {
+    let gated = |track: TrackAnalysis| match silence_gate(opts) {
+        Some(gate) => track.with_silence_gate(gate).result,
+        None => track.result,
+    };
+    // Cached analyses cover whole tracks as decoded
+    if custom_analysis(opts) {
+        return replaygain::analyze_track_downmixed(
+            file,
+            opts.track_index,
+            opts.skip,
+            opts.downmix,
+            opts.best_effort,
+        )
+        .map(gated);
+    }
+    if let Some(cache) = analysis_cache(opts) {
+        if let Ok(track) = cached_track(cache, file, opts) {
+            return Ok(gated(track));
+        }
+    } else if opts.trim_silence {
+        if let Ok(track) = replaygain::analyze_track_histogram(file, opts.track_index) {
+            return Ok(gated(track));
+        }
+    }
+    if opts.best_effort {
+        if let Ok(track) = replaygain::analyze_track_best_effort(file, opts.track_index) {
+            return Ok(gated(track));
+        }
+    }
+    replaygain::analyze_track_or_estimate(file, opts.track_index)
+}
+
+/// --as-one: analyze `files` in order as one continuous stream
+///
+/// The filter state carries over from file to file, as through the files
+/// joined end to end (see [`replaygain::analyze_stream`]).
+fn analyze_as_one(files: &[PathBuf], opts: &Options) -> Result<ReplayGainResult> {
+    let paths: Vec<&Path> = files.iter().map(|f| f.as_path()).collect();
+    let track = replaygain::analyze_stream(&paths, opts.track_index, opts.skip, opts.downmix)
+        .context("--as-one analysis failed")?;
+    let result = match silence_gate(opts) {
+        Some(gate) => track.with_silence_gate(gate).result,
+        None => track.result,
+    };
+
     if opts.output_format == OutputFormat::Text && !opts.quiet {
         println!(
-            "{} Checking stored tag info for {} file(s)",
+            "  As one stream: Loudness: {:.1} dB, Gain: {:+.1} dB, Peak: {:.4}",
+            result.loudness_db,
+            result.gain_for_target(replaygain_target_db(opts)),
+            result.peak
+        );
+        println!();
+    }
+    Ok(result)
+}
+
+/// Apply the --as-one gain to one of the files
+///
+/// Tags written with the gain record the stream's loudness and peak.
+fn process_stream_gain(
+    file: &Path,
+    stream: &ReplayGainResult,
+    opts: &Options,
+) -> Result<JsonFileResult> {
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+        println!(
+            "  {} {}Applying stream gain to {}...",
+            "->".cyan(),
+            dry_run_prefix,
+            get_filename(file)
+        );
+    }
+    let result = ReplayGainResult {
+        file_type: Some(detect_audio_type(file).unwrap_or(AudioFileType::Mp3)),
+        ..stream.clone()
+    };
+    process_track_result(file, &result, opts)
+}
+
+/// Whether --skip-intro/--skip-outro or --downmix change what is analyzed,
+/// so files are decoded again rather than read from --cache
+fn custom_analysis(opts: &Options) -> bool {
+    !opts.skip.is_empty() || opts.downmix != Downmix::None
+}
+
+/// The --cache to use, unless -s s says to ignore stored analyses
+fn analysis_cache(opts: &Options) -> Option<&AnalysisCache> {
+    opts.cache
+        .as_ref()
+        .filter(|_| opts.stored_tag_mode != StoredTagMode::Skip)
+}
+
+/// Analysis of `file` from `cache`; -s r decodes it again and refreshes the entry
+fn cached_track(cache: &AnalysisCache, file: &Path, opts: &Options) -> Result<TrackAnalysis> {
+    if opts.stored_tag_mode == StoredTagMode::Recalc {
+        cache.refresh_track(file, opts.track_index)
+    } else {
+        cache.analyze_track(file, opts.track_index)
+    }
+}
+
+/// Album analysis for -a, decoding only files without a cached analysis with --cache
+fn analyze_album(files: &[&Path], opts: &Options) -> Result<AlbumGainResult> {
+    let mut album = AlbumGainResult {
+        silence_gate_dbfs: silence_gate(opts),
+        ..Default::default()
+    };
+    // With --best-effort a damaged track joins the album with what decodes
+    let best_effort = |file: &Path, e: anyhow::Error| {
+        if opts.best_effort {
+            replaygain::analyze_track_best_effort(file, opts.track_index)
+        } else {
+            Err(e)
+        }
+    };
+    match analysis_cache(opts) {
+        _ if custom_analysis(opts) => {
+            for file in files {
+                album.add_track(replaygain::analyze_track_downmixed(
+                    file,
+                    opts.track_index,
+                    opts.skip,
+                    opts.downmix,
+                    opts.best_effort,
+                )?);
+            }
+        }
+        Some(cache) => {
+            for file in files {
+                let track = cached_track(cache, file, opts).or_else(|e| best_effort(file, e))?;
+                album.add_track(track);
+            }
+        }
+        None if opts.best_effort => {
+            for file in files {
+                album.add_track(replaygain::analyze_track_best_effort(
+                    file,
+                    opts.track_index,
+                )?);
+            }
+        }
+        None => replaygain::add_to_album(&mut album, files, opts.track_index)?,
+    }
+    Ok(album)
+}
+
+/// Warn that --best-effort left damaged packets out of a file's analysis
+fn print_partial_warning(file: &Path, result: &ReplayGainResult, opts: &Options) {
+    if result.partial && opts.output_format == OutputFormat::Text && !opts.quiet {
+        eprintln!(
+            "  {} {} - partial analysis: some audio could not be decoded and was left out",
+            "!".yellow(),
+            get_filename(file)
+        );
+    }
+}
+
+/// Loudness gate for --trim-silence
+fn silence_gate(opts: &Options) -> Option<f64> {
+    opts.trim_silence.then_some(replaygain::SILENCE_GATE_DBFS)
+}
+
+/// Loudness that --match levels every file to
+///
+/// Frame-domain estimates are never applied, so they don't count either.
+fn match_target(results: &[&ReplayGainResult], mode: MatchMode) -> Option<f64> {
+    let mut loudness: Vec<f64> = results
+        .iter()
+        .filter(|r| r.method == AnalysisMethod::Decoded)
+        .map(|r| r.loudness_db)
+        .collect();
+    loudness.sort_by(f64::total_cmp);
+
+    let n = loudness.len();
+    match mode {
+        _ if n == 0 => None,
+        MatchMode::Quietest => Some(loudness[0]),
+        MatchMode::Loudest => Some(loudness[n - 1]),
+        MatchMode::Median if n.is_multiple_of(2) => {
+            Some((loudness[n / 2 - 1] + loudness[n / 2]) / 2.0)
+        }
+        MatchMode::Median => Some(loudness[n / 2]),
+    }
+}
+
+/// --match: analyze all files, then bring each to the loudness of the
+/// quietest, loudest or median one
+///
+/// Unlike album gain, relative levels are not kept: every file ends up at
+/// the same loudness (within a 1.5 dB step for MP3).
+fn cmd_match(files: &[PathBuf], mode: MatchMode, opts: &Options) -> Result<()> {
+    if !replaygain::is_available() {
+        eprintln!(
+            "{}: ReplayGain analysis requires the 'replaygain' feature",
+            "error".red().bold()
+        );
+        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
+        std::process::exit(1);
+    }
+
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} Analyzing {} file(s) to match the {} track",
+            dry_run_prefix,
             "mp3rgain".green().bold(),
-            files.len()
+            files.len(),
+            mode.as_str()
+        );
+    }
+
+    let pb = create_progress_bar(files.len(), opts);
+    let mut analyses = Vec::with_capacity(files.len());
+    for file in files {
+        progress_set_message(&pb, &get_filename(file));
+        analyses.push(analyze_track(file, opts));
+        progress_inc(&pb);
+    }
+    progress_finish(pb);
+
+    let analyzed: Vec<&ReplayGainResult> =
+        analyses.iter().filter_map(|a| a.as_ref().ok()).collect();
+    let Some(target_db) = match_target(&analyzed, mode) else {
+        eprintln!(
+            "{}: --match needs at least one file that can be decoded",
+            "error".red().bold()
+        );
+        std::process::exit(1);
+    };
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "  Target: {:.1} dB loudness ({} track)",
+            target_db,
+            mode.as_str()
         );
+        if opts.gain_modifier != 0 {
+            println!("  Gain modifier: {:+} steps", opts.gain_modifier);
+        }
         println!();
     }
 
     let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
     let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
 
-    for file in files {
+    for (file, analysis) in files.iter().zip(analyses) {
         let filename = get_filename(file);
-        progress_set_message(&pb, filename);
-
-        match read_ape_tag_from_file(file) {
-            Ok(Some(tag)) => {
-                let undo = tag.get(TAG_MP3GAIN_UNDO);
-                let minmax = tag.get(TAG_MP3GAIN_MINMAX);
-                let track_gain = tag.get(TAG_REPLAYGAIN_TRACK_GAIN);
-                let track_peak = tag.get(TAG_REPLAYGAIN_TRACK_PEAK);
-                let album_gain = tag.get(TAG_REPLAYGAIN_ALBUM_GAIN);
-                let album_peak = tag.get(TAG_REPLAYGAIN_ALBUM_PEAK);
+        progress_set_message(&pb, &filename);
 
-                match opts.output_format {
-                    OutputFormat::Text => {
-                        println!("{}", filename.cyan().bold());
-                        if let Some(v) = undo {
-                            println!("  MP3GAIN_UNDO:         {}", v);
-                        }
-                        if let Some(v) = minmax {
-                            println!("  MP3GAIN_MINMAX:       {}", v);
-                        }
-                        if let Some(v) = track_gain {
-                            println!("  REPLAYGAIN_TRACK_GAIN: {}", v);
-                        }
-                        if let Some(v) = track_peak {
-                            println!("  REPLAYGAIN_TRACK_PEAK: {}", v);
-                        }
-                        if let Some(v) = album_gain {
-                            println!("  REPLAYGAIN_ALBUM_GAIN: {}", v);
-                        }
-                        if let Some(v) = album_peak {
-                            println!("  REPLAYGAIN_ALBUM_PEAK: {}", v);
-                        }
-                        if undo.is_none() && minmax.is_none() && track_gain.is_none() {
-                            println!("  (no mp3gain tags found)");
-                        }
-                        println!();
-                    }
-                    OutputFormat::Tsv => {
-                        println!(
-                            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                            filename,
-                            undo.unwrap_or("-"),
-                            minmax.unwrap_or("-"),
-                            track_gain.unwrap_or("-"),
-                            track_peak.unwrap_or("-"),
-                            album_gain.unwrap_or("-"),
-                            album_peak.unwrap_or("-")
-                        );
-                    }
-                    OutputFormat::Json => {
-                        let result = JsonFileResult {
-                            file: file.display().to_string(),
-                            status: Some("success".to_string()),
-                            ..Default::default()
-                        };
-                        // Note: we can add tag info to JSON if needed
-                        json_results.push(result);
-                    }
+        let timer = stats.begin(file);
+        let mut result = match analysis {
+            Ok(mut track) => {
+                if opts.output_format == OutputFormat::Text && !opts.quiet {
+                    println!("  {} {}{}", "->".cyan(), dry_run_prefix, filename);
                 }
+                track.gain_db = target_db - track.loudness_db;
+                process_track_result(file, &track, opts)?
             }
-            Ok(None) => match opts.output_format {
-                OutputFormat::Text => {
-                    println!("{}", filename.cyan().bold());
-                    println!("  (no APE tag found)");
-                    println!();
-                }
-                OutputFormat::Tsv => {
-                    println!("{}\t-\t-\t-\t-\t-\t-", filename);
-                }
-                OutputFormat::Json => {
-                    json_results.push(JsonFileResult {
-                        file: file.display().to_string(),
-                        status: Some("no_tag".to_string()),
-                        ..Default::default()
-                    });
-                }
-            },
             Err(e) => {
-                if opts.output_format != OutputFormat::Json {
-                    eprintln!("{} - {}", filename.red(), e);
-                } else {
-                    json_results.push(JsonFileResult {
-                        file: file.display().to_string(),
-                        status: Some("error".to_string()),
-                        error: Some(e.to_string()),
-                        ..Default::default()
-                    });
+                if file_error(
+                    opts,
+                    opts.output_format == OutputFormat::Text && !opts.quiet,
+                ) {
+                    eprintln!("  {} {} - {}", "x".red(), filename, e);
+                }
+                JsonFileResult {
+                    file: get_path(file),
+                    status: Some("error".to_string()),
+                    error: Some(e.to_string()),
+                    ..Default::default()
                 }
             }
+        };
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
+
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
         }
 
         progress_inc(&pb);
@@ -905,105 +6400,306 @@ fn cmd_check_tags(files: &[PathBuf], opts: &Options) -> Result<()> {
     progress_finish(pb);
 
     if opts.output_format == OutputFormat::Json {
-        let output = JsonOutput {
-            files: Some(json_results),
-            album: None,
-            summary: None,
+        let output = JsonMatchOutput {
+            target: JsonMatchTarget {
+                mode: mode.as_str(),
+                loudness_db: target_db,
+            },
+            files: json_results,
+            summary: create_json_summary(files.len(), successful, failed, opts.dry_run),
+            stats: stats.json(),
         };
-        println!("{}", serde_json::to_string_pretty(&output)?);
+        print_json(&output, opts)?;
+    } else {
+        stats.print(opts);
+        print_dry_run_notice(opts);
     }
 
     Ok(())
 }
 
-fn update_counters(result: &JsonFileResult, successful: &mut usize, failed: &mut usize) {
-    match result.status.as_deref() {
-        Some("success") => *successful += 1,
-        Some("error") => *failed += 1,
-        _ => {}
+fn cmd_album_gain(files: &[PathBuf], opts: &Options) -> Result<()> {
+    if !replaygain::is_available() {
+        eprintln!(
+            "{}: ReplayGain analysis requires the 'replaygain' feature",
+            "error".red().bold()
+        );
+        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
+        std::process::exit(1);
     }
-}
 
-fn create_json_summary(
-    total_files: usize,
-    successful: usize,
-    failed: usize,
-    dry_run: bool,
-) -> JsonSummary {
-    JsonSummary {
-        total_files,
-        successful,
-        failed,
-        dry_run: if dry_run { Some(true) } else { None },
+    if !opts.groups.is_empty() {
+        return cmd_album_groups(files, opts);
     }
-}
 
-fn print_dry_run_notice(opts: &Options) {
-    if opts.dry_run && !opts.quiet && opts.output_format == OutputFormat::Text {
+    let mut stats = Stats::new(opts);
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} Analyzing album gain for {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            files.len()
+        );
+        println!("  Target: {} dB (ReplayGain 1.0)", REPLAYGAIN_REFERENCE_DB);
+        if opts.gain_modifier != 0 {
+            println!("  Gain modifier: {:+} steps", opts.gain_modifier);
+        }
         println!();
-        println!("{}", "No files were modified.".yellow());
     }
-}
 
-fn cmd_apply(files: &[PathBuf], steps: i32, opts: &Options) -> Result<()> {
-    if steps == 0 {
-        if opts.output_format == OutputFormat::Json {
-            let output = JsonOutput {
-                files: Some(vec![]),
-                album: None,
-                summary: Some(create_json_summary(files.len(), 0, 0, opts.dry_run)),
-            };
-            println!("{}", serde_json::to_string_pretty(&output)?);
-        } else if !opts.quiet {
-            println!("{}: gain is 0, nothing to do", "info".cyan());
+    // First, analyze all tracks
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!("  {} Analyzing tracks...", "->".cyan());
+    }
+
+    let file_refs: Vec<&std::path::Path> = files.iter().map(|p| p.as_path()).collect();
+
+    match analyze_album(&file_refs, opts) {
+        Ok(album_result) => {
+            let run = apply_album_gain(files, &album_result, opts, &mut stats)?;
+
+            if opts.output_format == OutputFormat::Json {
+                let output = JsonOutput {
+                    files: Some(run.files),
+                    album: Some(run.album),
+                    albums: None,
+                    summary: Some(create_json_summary(
+                        files.len(),
+                        run.successful,
+                        run.failed,
+                        opts.dry_run,
+                    )),
+                    stats: stats.json(),
+                };
+                print_json(&output, opts)?;
+            } else if !run.skipped {
+                stats.print(opts);
+                print_dry_run_notice(opts);
+            }
+        }
+        Err(e) => {
+            if opts.output_format == OutputFormat::Json {
+                let output = JsonOutput {
+                    files: None,
+                    album: None,
+                    albums: None,
+                    summary: Some(create_json_summary(
+                        files.len(),
+                        0,
+                        files.len(),
+                        opts.dry_run,
+                    )),
+                    stats: None,
+                };
+                print_json(&output, opts)?;
+            } else {
+                eprintln!("{}: Failed to analyze album: {}", "error".red().bold(), e);
+            }
+            std::process::exit(1);
         }
-        return Ok(());
     }
 
-    let db_value = steps_to_db(steps);
+    Ok(())
+}
+
+/// Per-file results and album summary of applying one album's gain
+struct AlbumRun {
+    files: Vec<JsonFileResult>,
+    album: JsonAlbumResult,
+    successful: usize,
+    failed: usize,
+    /// No adjustment was needed, so no file was processed
+    skipped: bool,
+}
+
+/// Apply the analyzed album gain (with -m and -k) to every file of the album
+fn apply_album_gain(
+    files: &[PathBuf],
+    album_result: &AlbumGainResult,
+    opts: &Options,
+    stats: &mut Stats,
+) -> Result<AlbumRun> {
     let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
+    // Apply gain modifiers: -d moves the target, -m adds whole steps
+    let target_db = replaygain_target_db(opts);
+    let requested_db = album_result.album_gain_for_target(target_db);
+    let base_steps = album_result.album_gain_steps_for_target(target_db);
+    let modified_gain_steps = base_steps + opts.gain_modifier;
+
     if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!();
+        println!("  Album loudness: {:.1} dB", album_result.album_loudness_db);
         println!(
-            "{}{} {} {} step(s) ({:+.1} dB) to {} file(s)",
-            dry_run_prefix,
-            "mp3rgain".green().bold(),
-            if opts.dry_run {
-                "Would apply"
+            "  Album gain:     {:+.1} dB ({} steps{})",
+            requested_db,
+            base_steps,
+            if opts.gain_modifier != 0 {
+                format!(" + {} = {}", opts.gain_modifier, modified_gain_steps)
             } else {
-                "Applying"
-            },
-            steps,
-            db_value,
-            files.len()
+                String::new()
+            }
         );
-        if opts.wrap_gain {
-            println!("  {} Wrap mode enabled", "!".yellow());
-        }
+        print_continuous_gain("  Exact album gain:", requested_db, opts);
+        println!("  Album peak:     {:.4}", album_result.album_peak);
         println!();
     }
 
+    // Apply album gain to all files
+    let mut steps = modified_gain_steps;
+
+    // -k: limit the album gain so the loudest track doesn't clip,
+    // keeping the same adjustment on every track
+    let mut album_warning = None;
+    if opts.prevent_clipping && steps > 0 && !opts.wrap_gain {
+        let max_safe_db = replaygain::clip_margin_db(album_result.album_peak, 0.0);
+        let max_safe_steps = ((max_safe_db / GAIN_STEP_DB).floor() as i32).max(0);
+        if steps > max_safe_steps {
+            let msg = format!(
+                "album gain reduced from {} to {} steps to prevent clipping (album peak: {:.4})",
+                steps, max_safe_steps, album_result.album_peak
+            );
+            if opts.output_format == OutputFormat::Text && !opts.quiet {
+                eprintln!("  {} {}{}", "!".yellow(), dry_run_prefix, msg);
+                eprintln!();
+            }
+            steps = max_safe_steps;
+            album_warning = Some(msg);
+        }
+    }
+
+    // --album-clip-check: re-check every track's peak at the album gain and
+    // lower it a step at a time until none clips (never below 0 steps)
+    let clip_check = (opts.album_clip_check && steps > 0).then(|| {
+        let peaks: Vec<f64> = album_result.tracks.iter().map(|t| t.peak).collect();
+        let check = replaygain::clip_free_album_steps(&peaks, steps, 0);
+        let msg = if !check.clip_free {
+            format!(
+                "album gain lowered from {} to {} steps, but a track still clips \
+                 ({} checks)",
+                steps, check.steps, check.iterations
+            )
+        } else if check.steps < steps {
+            format!(
+                "album gain reduced from {} to {} steps after {} clipping checks",
+                steps, check.steps, check.iterations
+            )
+        } else {
+            format!("album gain of {} steps clips no track", steps)
+        };
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            let mark = if check.clip_free && check.steps == steps {
+                "v".green()
+            } else {
+                "!".yellow()
+            };
+            eprintln!("  {} {}{}", mark, dry_run_prefix, msg);
+            eprintln!();
+        }
+        if check.steps < steps || !check.clip_free {
+            album_warning = Some(match album_warning.take() {
+                Some(warning) => format!("{}; {}", warning, msg),
+                None => msg,
+            });
+        }
+        steps = check.steps;
+        JsonAlbumClipCheck {
+            iterations: check.iterations,
+            gain_steps: check.steps,
+            gain_db: steps_to_db(check.steps),
+            clip_free: check.clip_free,
+        }
+    });
+
+    let clipping = opts
+        .dry_run
+        .then(|| album_clipping_report(files, album_result, steps, opts));
+
+    let mut album = JsonAlbumResult {
+        loudness_db: album_result.album_loudness_db,
+        gain_db: album_result.album_gain_db,
+        gain_steps: steps,
+        peak: album_result.album_peak,
+        quantization_error_db: opts
+            .show_continuous
+            .then(|| steps_to_db(steps) - requested_db),
+        warning: album_warning,
+        clipping,
+        clip_check,
+        files: None,
+    };
+
+    if steps == 0 && !opts.tags_only && !opts.tag_even_if_zero {
+        let mut json_results: Vec<JsonFileResult> = Vec::new();
+        if opts.output_format == OutputFormat::Json {
+            json_results = files
+                .iter()
+                .enumerate()
+                .map(|(i, file)| {
+                    let track = &album_result.tracks[i];
+                    JsonFileResult {
+                        file: get_path(file),
+                        status: no_adjustment_status(opts),
+                        loudness_db: Some(track.loudness_db),
+                        peak: Some(track.peak),
+                        gain_applied_steps: Some(0),
+                        gain_applied_db: Some(0.0),
+                        partial: track.partial.then_some(true),
+                        ..Default::default()
+                    }
+                })
+                .collect();
+        } else if !opts.quiet {
+            println!("  {} No adjustment needed", ".".cyan());
+        }
+        return Ok(AlbumRun {
+            files: json_results,
+            album,
+            successful: 0,
+            failed: 0,
+            skipped: true,
+        });
+    }
+
     let pb = create_progress_bar(files.len(), opts);
     let mut json_results: Vec<JsonFileResult> = Vec::new();
     let mut successful = 0;
     let mut failed = 0;
 
-    for file in files {
+    // --true-peak-tags: the album peak tag is the loudest track's true peak
+    let album_peak = if opts.true_peak_tags {
+        files
+            .iter()
+            .filter_map(|file| replaygain::find_true_peak(file, opts.track_index).ok())
+            .fold(album_result.album_peak, f64::max)
+    } else {
+        album_result.album_peak
+    };
+
+    for (i, file) in files.iter().enumerate() {
         let filename = get_filename(file);
-        progress_set_message(&pb, filename);
+        progress_set_message(&pb, &filename);
 
-        let result = process_apply(file, steps, opts)?;
+        let track_result = &album_result.tracks[i];
+        print_partial_warning(file, track_result, opts);
+        let album_info = AacAlbumInfo {
+            album_gain_db: album_result.album_gain_db,
+            album_peak,
+        };
+        let timer = stats.begin(file);
+        // The album gain is already known, so a file that can't be written
+        // (disk full, read-only) doesn't keep it from the rest
+        let mut result =
+            process_apply_replaygain_with_album(file, steps, track_result, opts, Some(&album_info))
+                .unwrap_or_else(|e| apply_error_result(file, e, opts));
+        result.partial = track_result.partial.then_some(true);
+        add_continuous_gain(&mut result, requested_db, opts);
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
         update_counters(&result, &mut successful, &mut failed);
 
-        if opts.output_format == OutputFormat::Tsv {
-            if let Ok(info) = analyze(file) {
-                println!(
-                    "{}\t{}\t{:.1}\t{:.6}\t{}\t{}",
-                    filename, steps, db_value, 1.0, info.max_gain, info.min_gain
-                );
-            }
-        }
-
         if opts.output_format == OutputFormat::Json {
             json_results.push(result);
         }
@@ -1013,681 +6709,1328 @@ fn cmd_apply(files: &[PathBuf], steps: i32, opts: &Options) -> Result<()> {
 
     progress_finish(pb);
 
-    if opts.output_format == OutputFormat::Json {
-        let output = JsonOutput {
-            files: Some(json_results),
-            album: None,
-            summary: Some(create_json_summary(
-                files.len(),
-                successful,
-                failed,
-                opts.dry_run,
-            )),
-        };
-        println!("{}", serde_json::to_string_pretty(&output)?);
-    } else {
-        print_dry_run_notice(opts);
+    if failed > 0 {
+        let msg = format!(
+            "{} of {} file(s) failed; the album gain was applied to the others",
+            failed,
+            files.len()
+        );
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            eprintln!();
+            eprintln!("  {} {}", "!".yellow(), msg);
+        }
+        album.warning = Some(match album.warning.take() {
+            Some(warning) => format!("{}; {}", warning, msg),
+            None => msg,
+        });
     }
 
-    Ok(())
+    Ok(AlbumRun {
+        files: json_results,
+        album,
+        successful,
+        failed,
+        skipped: false,
+    })
 }
 
-fn cmd_apply_channel(
+/// Dry run: list the tracks that `steps` of album gain would clip
+///
+/// Also reports the largest album gain that clips none of them, to compare
+/// with -k or track gain before writing anything.
+fn album_clipping_report(
     files: &[PathBuf],
-    channel: Channel,
+    album_result: &AlbumGainResult,
     steps: i32,
     opts: &Options,
-) -> Result<()> {
-    if steps == 0 {
-        if opts.output_format == OutputFormat::Json {
-            let output = JsonOutput {
-                files: Some(vec![]),
-                album: None,
-                summary: Some(create_json_summary(files.len(), 0, 0, opts.dry_run)),
-            };
-            println!("{}", serde_json::to_string_pretty(&output)?);
-        } else if !opts.quiet {
-            println!("{}: gain is 0, nothing to do", "info".cyan());
+) -> JsonClippingReport {
+    let gain_db = steps_to_db(steps);
+    let tracks: Vec<JsonClippingTrack> = files
+        .iter()
+        .zip(&album_result.tracks)
+        .filter(|(_, track)| replaygain::would_clip(track.peak, gain_db))
+        .map(|(file, track)| JsonClippingTrack {
+            file: get_path(file),
+            peak: track.peak,
+            over_db: -replaygain::clip_margin_db(track.peak, gain_db),
+        })
+        .collect();
+    let max_safe_db = replaygain::clip_margin_db(album_result.album_peak, 0.0);
+    let safe_gain_steps = (max_safe_db / GAIN_STEP_DB).floor().min(i32::MAX as f64) as i32;
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        if tracks.is_empty() {
+            println!("  No track would clip at {:+.1} dB", gain_db);
+        } else {
+            println!(
+                "  {} track(s) would clip at {:+.1} dB:",
+                tracks.len(),
+                gain_db
+            );
+            for track in &tracks {
+                println!(
+                    "    {} {} (peak {:.4}, {:.2} dB over full scale)",
+                    "!".yellow(),
+                    get_filename(Path::new(&track.file)),
+                    track.peak,
+                    track.over_db
+                );
+            }
+            println!(
+                "  Largest album gain without clipping: {:+.1} dB ({} steps, as with -k)",
+                steps_to_db(safe_gain_steps),
+                safe_gain_steps
+            );
         }
-        return Ok(());
+        println!();
     }
 
-    let db_value = steps_to_db(steps);
+    JsonClippingReport {
+        tracks,
+        safe_gain_steps,
+        safe_gain_db: steps_to_db(safe_gain_steps),
+    }
+}
+
+/// -a with --group: album gain for each group, track gain for the other files
+///
+/// Every group is analyzed before any file is written, so a group that fails
+/// to analyze leaves all files untouched.
+fn cmd_album_groups(ungrouped: &[PathBuf], opts: &Options) -> Result<()> {
+    let mut stats = Stats::new(opts);
     let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
-    let channel_name = match channel {
-        Channel::Left => "left",
-        Channel::Right => "right",
-    };
+    let total_files = ungrouped.len() + opts.groups.iter().map(Vec::len).sum::<usize>();
 
     if opts.output_format == OutputFormat::Text && !opts.quiet {
         println!(
-            "{}{} {} {} step(s) ({:+.1} dB) to {} channel of {} file(s)",
+            "{}{} Analyzing album gain for {} group(s) of {} file(s)",
             dry_run_prefix,
             "mp3rgain".green().bold(),
-            if opts.dry_run {
-                "Would apply"
-            } else {
-                "Applying"
-            },
-            steps,
-            db_value,
-            channel_name,
-            files.len()
+            opts.groups.len(),
+            total_files
         );
+        println!("  Target: {} dB (ReplayGain 1.0)", REPLAYGAIN_REFERENCE_DB);
+        if opts.gain_modifier != 0 {
+            println!("  Gain modifier: {:+} steps", opts.gain_modifier);
+        }
         println!();
+        println!("  {} Analyzing tracks...", "->".cyan());
     }
 
-    let pb = create_progress_bar(files.len(), opts);
-    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut album_results = Vec::with_capacity(opts.groups.len());
+    for (n, group) in opts.groups.iter().enumerate() {
+        let file_refs: Vec<&Path> = group.iter().map(|p| p.as_path()).collect();
+        match analyze_album(&file_refs, opts) {
+            Ok(album_result) => album_results.push(album_result),
+            Err(e) => {
+                if opts.output_format == OutputFormat::Json {
+                    let output = JsonOutput {
+                        files: None,
+                        album: None,
+                        albums: None,
+                        summary: Some(create_json_summary(
+                            total_files,
+                            0,
+                            total_files,
+                            opts.dry_run,
+                        )),
+                        stats: None,
+                    };
+                    print_json(&output, opts)?;
+                } else {
+                    eprintln!(
+                        "{}: Failed to analyze album {}: {}",
+                        "error".red().bold(),
+                        n + 1,
+                        e
+                    );
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut albums = Vec::with_capacity(opts.groups.len());
     let mut successful = 0;
     let mut failed = 0;
 
-    for file in files {
-        let filename = get_filename(file);
-        progress_set_message(&pb, filename);
+    for (n, (group, album_result)) in opts.groups.iter().zip(&album_results).enumerate() {
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!();
+            println!(
+                "  {} Album {} of {} ({} file(s))",
+                "->".cyan(),
+                n + 1,
+                opts.groups.len(),
+                group.len()
+            );
+        }
 
-        let result = process_apply_channel(file, channel, steps, opts)?;
-        update_counters(&result, &mut successful, &mut failed);
+        let run = apply_album_gain(group, album_result, opts, &mut stats)?;
+        successful += run.successful;
+        failed += run.failed;
+        albums.push(JsonAlbumResult {
+            files: Some(run.files),
+            ..run.album
+        });
+    }
 
-        if opts.output_format == OutputFormat::Json {
-            json_results.push(result);
+    // Files outside every group get their own track gain
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    if !ungrouped.is_empty() {
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!();
+            println!(
+                "  {} Track gain for {} ungrouped file(s)",
+                "->".cyan(),
+                ungrouped.len()
+            );
         }
 
-        progress_inc(&pb);
-    }
+        let pb = create_progress_bar(ungrouped.len(), opts);
+        for file in ungrouped {
+            progress_set_message(&pb, &get_filename(file));
 
-    progress_finish(pb);
+            let timer = stats.begin(file);
+            let mut result = process_track_gain(file, opts)?;
+            stats.finish(timer, file, opts, &mut result);
+            write_sidecar(file, &mut result, opts);
+            update_counters(&result, &mut successful, &mut failed);
+
+            if opts.output_format == OutputFormat::Json {
+                json_results.push(result);
+            }
+
+            progress_inc(&pb);
+        }
+        progress_finish(pb);
+    }
 
     if opts.output_format == OutputFormat::Json {
         let output = JsonOutput {
-            files: Some(json_results),
+            files: if ungrouped.is_empty() {
+                None
+            } else {
+                Some(json_results)
+            },
             album: None,
+            albums: Some(albums),
             summary: Some(create_json_summary(
-                files.len(),
+                total_files,
                 successful,
                 failed,
                 opts.dry_run,
             )),
+            stats: stats.json(),
         };
-        println!("{}", serde_json::to_string_pretty(&output)?);
+        print_json(&output, opts)?;
     } else {
+        stats.print(opts);
         print_dry_run_notice(opts);
     }
 
     Ok(())
 }
 
-fn cmd_info(files: &[PathBuf], opts: &Options) -> Result<()> {
-    // Print mp3gain-compatible TSV header
-    if opts.output_format == OutputFormat::Tsv {
-        println!("File\tMP3 gain\tdB gain\tMax Amplitude\tMax global_gain\tMin global_gain");
+// =============================================================================
+// File processing
+// =============================================================================
+
+/// Counter that keeps temp file names unique within this process
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Temp file for `file`, removed on drop unless renamed away or kept
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Temp file in `dir`, or next to `file` without one
+    fn new(file: &Path, dir: Option<&Path>) -> Self {
+        let parent = dir.unwrap_or_else(|| file.parent().unwrap_or(Path::new(".")));
+        let name = file
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let id = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = parent.join(format!(
+            ".mp3rgain_temp_{}_{}_{}",
+            std::process::id(),
+            id,
+            name
+        ));
+        TempFile { path }
     }
 
-    let pb = create_progress_bar(files.len(), opts);
-    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    /// Leave the temp file on disk and return its path
+    fn keep(mut self) -> PathBuf {
+        std::mem::take(&mut self.path)
+    }
+}
 
-    for file in files {
-        let filename = get_filename(file);
-        progress_set_message(&pb, filename);
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
 
-        let result = process_info(file, opts)?;
-        if opts.output_format == OutputFormat::Json {
-            json_results.push(result);
+/// Copy a processed temp file over `file` in place
+///
+/// A failed copy can leave `file` half written, so the temp file is then
+/// kept as the only complete copy of the new data and named in the error.
+fn copy_back(temp: TempFile, file: &Path, retries: u32) -> Result<()> {
+    retry_io(retries, RETRY_BACKOFF, || {
+        fs::copy(&temp.path, file).map(drop)
+    })
+    .map_err(|e| {
+        let err = write_error(e, file);
+        let message = format!("{}; processed copy kept at {}", err, temp.keep().display());
+        err.context(message)
+    })
+}
+
+/// Replace `file` with a temp file from another directory, possibly on
+/// another filesystem
+///
+/// A rename is atomic but only works within one filesystem. Otherwise the
+/// data is copied next to `file` and renamed from there, which is just as
+/// atomic, and only if that directory can't take a copy is it copied over
+/// `file` in place (a crash then can leave it half written).
+fn replace_from_elsewhere(temp: TempFile, file: &Path, retries: u32) -> Result<()> {
+    if fs::rename(&temp.path, file).is_ok() {
+        return Ok(());
+    }
+    let sibling = TempFile::new(file, None);
+    if fs::copy(&temp.path, &sibling.path).is_ok() && fs::rename(&sibling.path, file).is_ok() {
+        return Ok(());
+    }
+    copy_back(temp, file, retries)
+}
+
+/// Make a read-only file writable by its owner, returning its permissions
+/// to restore afterwards (`None` if it was already writable)
+#[cfg_attr(not(unix), allow(clippy::permissions_set_readonly_false))]
+fn make_writable(path: &Path) -> Option<fs::Permissions> {
+    let permissions = fs::metadata(path).ok()?.permissions();
+    if !permissions.readonly() {
+        return None;
+    }
+    let mut writable = permissions.clone();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        writable.set_mode(writable.mode() | 0o200);
+    }
+    #[cfg(not(unix))]
+    writable.set_readonly(false);
+    fs::set_permissions(path, writable).ok()?;
+    Some(permissions)
+}
+
+fn apply_with_temp_file<F>(file: &Path, operation: F, opts: &Options) -> Result<usize>
+where
+    F: FnOnce(&Path) -> Result<usize>,
+{
+    let retry = |op: &mut dyn FnMut() -> std::io::Result<()>| {
+        retry_io(opts.retries, RETRY_BACKOFF, op).map_err(|e| write_error(e, file))
+    };
+
+    // Fail before reading or copying anything if the file can't be written,
+    // waiting for it while it's transiently busy. The operation itself isn't
+    // safe to repeat halfway through, so it only starts once this succeeds.
+    // A temp file renamed over the original only needs the directory to be
+    // writable, not the file.
+    if !opts.use_temp_file || opts.in_place_truncate {
+        retry(&mut || fs::OpenOptions::new().write(true).open(file).map(drop))?;
+    }
+
+    // --verify: keep the original to compare the written frames against
+    let original = if opts.verify {
+        Some(fs::read(file).with_context(|| format!("Failed to read: {}", file.display()))?)
+    } else {
+        None
+    };
+    let verify = |written: &Path| -> Result<()> {
+        if let Some(original) = &original {
+            let data = fs::read(written)
+                .with_context(|| format!("Failed to read: {}", written.display()))?;
+            verify_frame_layout(original, &data)?;
         }
+        Ok(())
+    };
 
-        progress_inc(&pb);
+    if opts.use_temp_file {
+        // Copy original to a temp file in --temp-dir, or else in the same
+        // directory; one that can't take it (read-only, full) leaves the
+        // system temp dir
+        let mut temp = TempFile::new(file, opts.temp_dir.as_deref());
+        let mut copied = false;
+        if opts.temp_dir.is_none() {
+            copied = fs::copy(file, &temp.path).is_ok();
+            if !copied {
+                temp = TempFile::new(file, Some(&env::temp_dir()));
+            }
+        }
+        if !copied {
+            retry(&mut || fs::copy(file, &temp.path).map(drop))?;
+        }
+
+        // Apply operation to temp file; a failed check leaves the original alone.
+        // A copy of a read-only file gets its mode back before replacing it.
+        let read_only = make_writable(&temp.path);
+        let frames = operation(&temp.path)?;
+        verify(&temp.path)?;
+        if let Some(permissions) = read_only {
+            retry(&mut || fs::set_permissions(&temp.path, permissions.clone()))?;
+        }
+        if opts.in_place_truncate {
+            // Write back through the original inode so hardlinks
+            // (and a symlinked path) keep pointing at the new data
+            copy_back(temp, file, opts.retries)?;
+        } else if temp.path.parent() == file.parent() {
+            // Replace original with temp (detaches hardlinks)
+            retry(&mut || fs::rename(&temp.path, file))?;
+        } else {
+            replace_from_elsewhere(temp, file, opts.retries)?;
+        }
+        Ok(frames)
+    } else {
+        let frames = operation(file)?;
+        if let Err(e) = verify(file) {
+            // Put the original bytes back rather than leave a damaged file
+            if let Some(original) = &original {
+                retry(&mut || fs::write(file, original))?;
+            }
+            return Err(e.context("original restored"));
+        }
+        Ok(frames)
     }
+}
 
-    progress_finish(pb);
+/// Show stream properties and stored ReplayGain tags of an AAC/M4A file
+fn process_aac_info(file: &Path, format: &str, opts: &Options) -> JsonFileResult {
+    let filename = get_filename(file);
+    let stream = replaygain::stream_info(file, opts.track_index);
 
-    if opts.output_format == OutputFormat::Json {
-        let output = JsonOutput {
-            files: Some(json_results),
-            album: None,
-            summary: None,
-        };
-        println!("{}", serde_json::to_string_pretty(&output)?);
+    // M4A keeps ReplayGain in iTunes freeform tags, FLAC in Vorbis comments,
+    // raw ADTS in an APEv2 tag
+    let tags = if mp4meta::is_mp4_file(file) {
+        mp4meta::read_replaygain_tags(file).unwrap_or_default()
+    } else {
+        let tag = if flac::is_flac_file(file) {
+            flac::read_replaygain_tag(file)
+        } else {
+            read_ape_tag_from_file(file)
+        }
+        .ok()
+        .flatten();
+        let get = |key: &str| tag.as_ref().and_then(|t| t.get(key)).map(String::from);
+        mp4meta::ReplayGainTags {
+            track_gain: get(TAG_REPLAYGAIN_TRACK_GAIN),
+            track_peak: get(TAG_REPLAYGAIN_TRACK_PEAK),
+            album_gain: get(TAG_REPLAYGAIN_ALBUM_GAIN),
+            album_peak: get(TAG_REPLAYGAIN_ALBUM_PEAK),
+            reference_loudness: get(TAG_REPLAYGAIN_REFERENCE_LOUDNESS),
+            ..Default::default()
+        }
+    };
+
+    match opts.output_format {
+        OutputFormat::Text => {
+            if opts.quiet {
+                println!("{}\t{}\t-\t-\t-\t-\t-", filename, format);
+            } else {
+                println!("{}", filename.cyan().bold());
+                match &stream {
+                    Ok(info) => {
+                        println!(
+                            "  Format:      {}, {} Hz, {} channel(s)",
+                            format, info.sample_rate, info.channels
+                        );
+                        if let Some(secs) = info.duration_secs {
+                            println!(
+                                "  Duration:    {}:{:04.1}",
+                                (secs / 60.0) as u64,
+                                secs % 60.0
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        println!("  Format:      {}", format);
+                        println!("  {}", format!("Stream info unavailable: {}", e).yellow());
+                    }
+                }
+                let describe = |gain: &Option<String>, peak: &Option<String>| match gain {
+                    Some(g) => format!("{} (peak {})", g, peak.as_deref().unwrap_or("-")),
+                    None => "(none)".to_string(),
+                };
+                println!(
+                    "  Track gain:  {}",
+                    describe(&tags.track_gain, &tags.track_peak)
+                );
+                println!(
+                    "  Album gain:  {}",
+                    describe(&tags.album_gain, &tags.album_peak)
+                );
+                println!(
+                    "  {}",
+                    "Note: Use -r or -a for ReplayGain analysis".yellow()
+                );
+                println!();
+            }
+        }
+        OutputFormat::Tsv => {
+            println!("{}\t-\t-\t-\t-\t-", filename);
+        }
+        OutputFormat::Json => {}
     }
 
-    Ok(())
+    let stream = stream.ok();
+    JsonFileResult {
+        file: get_path(file),
+        status: Some("info".to_string()),
+        format: Some(format.to_string()),
+        sample_rate: stream.as_ref().map(|s| s.sample_rate),
+        channels: stream.as_ref().map(|s| s.channels),
+        duration_secs: stream.as_ref().and_then(|s| s.duration_secs),
+        replaygain_track_gain: tags.track_gain,
+        replaygain_track_peak: tags.track_peak,
+        replaygain_album_gain: tags.album_gain,
+        replaygain_album_peak: tags.album_peak,
+        ..Default::default()
+    }
 }
 
-fn cmd_undo(files: &[PathBuf], opts: &Options) -> Result<()> {
-    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+/// Status of a file that needs no adjustment ("unchanged" with --skip-unchanged)
+fn no_adjustment_status(opts: &Options) -> Option<String> {
+    let status = if opts.skip_unchanged {
+        "unchanged"
+    } else {
+        "skipped"
+    };
+    Some(status.to_string())
+}
 
+/// Result for a file that --skip-unchanged leaves alone
+fn unchanged_result(file: &Path, opts: &Options) -> JsonFileResult {
     if opts.output_format == OutputFormat::Text && !opts.quiet {
-        println!(
-            "{}{} {} gain changes on {} file(s)",
-            dry_run_prefix,
-            "mp3rgain".green().bold(),
-            if opts.dry_run {
-                "Would undo"
-            } else {
-                "Undoing"
+        println!("  {} {} (unchanged)", ".".cyan(), get_filename(file));
+    }
+    JsonFileResult {
+        file: get_path(file),
+        status: Some("unchanged".to_string()),
+        gain_applied_steps: Some(0),
+        gain_applied_db: Some(0.0),
+        dry_run: if opts.dry_run { Some(true) } else { None },
+        ..Default::default()
+    }
+}
+
+/// With --skip-unchanged, whether gain of `steps` (left/right) would leave the file as is
+fn is_unchanged_by_gain(file: &Path, left: i32, right: i32, opts: &Options) -> bool {
+    if !opts.skip_unchanged {
+        return false;
+    }
+    // Negative gain can't move frames already at or below --min-floor
+    let would_change = |info: &Mp3Analysis, steps: i32| {
+        !(steps < 0 && info.max_gain <= opts.min_floor)
+            && gain_would_change(info, steps, opts.wrap_gain)
+    };
+    match analyze(file) {
+        Ok(info) => !would_change(&info, left) && !would_change(&info, right),
+        Err(_) => false,
+    }
+}
+
+/// Error result for raw AAC (ADTS) files passed to MP3-only operations
+fn adts_unsupported_result(file: &Path, opts: &Options) -> JsonFileResult {
+    let msg = "raw AAC (ADTS) file has no MP3 global_gain; use -r or -a to write ReplayGain tags";
+    if file_error(
+        opts,
+        opts.output_format == OutputFormat::Text && !opts.quiet,
+    ) {
+        eprintln!("  {} {} - {}", "x".red(), get_filename(file), msg);
+    }
+    JsonFileResult {
+        file: get_path(file),
+        status: Some("error".to_string()),
+        error: Some(msg.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Shift global_gain of every frame by `steps` (--wrap / --min-floor) and
+/// record the undo information, or with -s s / --no-undo-tag only shift it
+fn apply_frame_gain(file: &Path, steps: i32, opts: &Options) -> Result<usize> {
+    if opts.stored_tag_mode == StoredTagMode::Skip {
+        if opts.wrap_gain {
+            apply_with_temp_file(file, |f| apply_gain_wrap(f, steps), opts)
+        } else {
+            apply_with_temp_file(file, |f| apply_gain_floor(f, steps, opts.min_floor), opts)
+        }
+    } else if opts.wrap_gain {
+        apply_with_temp_file(
+            file,
+            |f| store_gain_tags(f, opts, apply_gain_with_undo_wrap(f, steps)),
+            opts,
+        )
+    } else {
+        apply_with_temp_file(
+            file,
+            |f| {
+                let frames = apply_gain_with_undo_floor(f, steps, opts.min_floor);
+                store_gain_tags(f, opts, frames)
             },
-            files.len()
-        );
-        println!();
+            opts,
+        )
     }
+}
 
-    let pb = create_progress_bar(files.len(), opts);
-    let mut json_results: Vec<JsonFileResult> = Vec::new();
-    let mut successful = 0;
-    let mut failed = 0;
+fn process_apply(file: &Path, steps: i32, opts: &Options) -> Result<JsonFileResult> {
+    let filename = get_filename(file);
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
-    for file in files {
-        let filename = get_filename(file);
-        progress_set_message(&pb, filename);
+    if is_adts_file(file) {
+        return Ok(adts_unsupported_result(file, opts));
+    }
 
-        let result = process_undo(file, opts)?;
-        update_counters(&result, &mut successful, &mut failed);
+    // Save original timestamp (and mode/owner) if needed
+    let preserved = PreservedMetadata::capture(file, opts);
+    // The gain below is decided from the file as it is now
+    let snapshot = FileSnapshot::capture(file);
 
-        if opts.output_format == OutputFormat::Json {
-            json_results.push(result);
-        }
+    let (steps, ceiling_msg) = cap_to_ceiling(file, steps, opts);
 
-        progress_inc(&pb);
+    // Check for clipping and possibly prevent it
+    let mut actual_steps = steps;
+    let mut warnings = Warnings::from(ceiling_msg);
+
+    if steps > 0 && !opts.wrap_gain {
+        if let Ok(info) = analyze(file) {
+            if info.headroom_steps == 0 {
+                // Some granule is already at global_gain 255; saturation
+                // leaves it there while the other frames still take the gain
+                let msg = "no positive headroom; frames at global_gain 255 will not change";
+                if opts.output_format == OutputFormat::Text && !opts.quiet {
+                    eprintln!(
+                        "  {} {}{} - {}",
+                        "!".yellow(),
+                        dry_run_prefix,
+                        filename,
+                        msg
+                    );
+                }
+                if info.min_gain == 255 {
+                    // Every granule is at 255: saturation would leave the audio
+                    // untouched while still recording an undo entry
+                    return Ok(JsonFileResult {
+                        file: get_path(file),
+                        status: Some("skipped".to_string()),
+                        headroom_steps: Some(0),
+                        gain_applied_steps: Some(0),
+                        gain_applied_db: Some(0.0),
+                        warnings: Warnings::from(msg.to_string()),
+                        dry_run: if opts.dry_run { Some(true) } else { None },
+                        ..Default::default()
+                    });
+                }
+                warnings.push(msg.to_string());
+            }
+
+            if steps > info.headroom_steps {
+                if opts.prevent_clipping {
+                    // -k: automatically reduce gain to prevent clipping
+                    let original_steps = steps;
+                    actual_steps = info.headroom_steps;
+                    if opts.output_format == OutputFormat::Text && !opts.quiet {
+                        eprintln!(
+                            "  {} {}{} - gain reduced from {} to {} steps to prevent clipping",
+                            "!".yellow(),
+                            dry_run_prefix,
+                            filename,
+                            original_steps,
+                            actual_steps
+                        );
+                    }
+                    warnings.push(format!(
+                        "gain reduced from {} to {} steps to prevent clipping",
+                        original_steps, actual_steps
+                    ));
+                } else if !opts.ignore_clipping && info.headroom_steps > 0 {
+                    // Show warning but continue
+                    if opts.output_format == OutputFormat::Text && !opts.quiet {
+                        eprintln!(
+                            "  {} {}{} - clipping warning: requested {} steps but only {} headroom",
+                            "!".yellow(),
+                            dry_run_prefix,
+                            filename,
+                            steps,
+                            info.headroom_steps
+                        );
+                        eprintln!(
+                            "      Use -c to ignore clipping warnings or -k to prevent clipping"
+                        );
+                    }
+                    warnings.push(format!(
+                        "clipping warning: requested {} steps but only {} headroom",
+                        steps, info.headroom_steps
+                    ));
+                }
+            }
+        }
     }
 
-    progress_finish(pb);
+    if is_unchanged_by_gain(file, actual_steps, actual_steps, opts) {
+        let mut result = unchanged_result(file, opts);
+        result.warnings = warnings;
+        return Ok(result);
+    }
 
-    if opts.output_format == OutputFormat::Json {
-        let output = JsonOutput {
-            files: Some(json_results),
-            album: None,
-            summary: Some(create_json_summary(
-                files.len(),
-                successful,
-                failed,
-                opts.dry_run,
-            )),
+    // Dry run: don't actually modify
+    if opts.dry_run {
+        let tag_changes = if opts.stored_tag_mode == StoredTagMode::Skip || actual_steps == 0 {
+            Vec::new()
+        } else {
+            preview_ape_changes(file, |_| {
+                preview_undo_tag(file, actual_steps, opts.wrap_gain)
+            })
         };
-        println!("{}", serde_json::to_string_pretty(&output)?);
-    } else {
-        print_dry_run_notice(opts);
+
+        // The same pass an actual apply makes, on a copy in memory
+        let projected = preview_gain(file, actual_steps, opts.wrap_gain, opts.min_floor).ok();
+
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!(
+                "  {} [DRY RUN] {} (would apply {} steps)",
+                "~".cyan(),
+                filename,
+                actual_steps
+            );
+            print_tag_changes(&tag_changes);
+        }
+        return Ok(JsonFileResult {
+            file: get_path(file),
+            status: Some("dry_run".to_string()),
+            gain_applied_steps: Some(actual_steps),
+            gain_applied_db: Some(steps_to_db(actual_steps)),
+            projected_min_gain: projected.as_ref().map(|info| info.min_gain),
+            projected_max_gain: projected.as_ref().map(|info| info.max_gain),
+            projected_avg_gain: projected.as_ref().map(|info| info.avg_gain),
+            projected_headroom: projected.as_ref().map(|info| info.headroom_steps),
+            warnings,
+            dry_run: Some(true),
+            tag_changes: json_tag_changes(&tag_changes),
+            ..Default::default()
+        });
+    }
+
+    // Don't apply a gain computed for contents another process replaced
+    let apply_result = snapshot
+        .and_then(|snapshot| snapshot.verify(file))
+        .and_then(|_| apply_frame_gain(file, actual_steps, opts));
+
+    match apply_result {
+        Ok(frames) => {
+            // Restore timestamp (and mode/owner) if needed
+            preserved.restore(file);
+
+            if opts.output_format == OutputFormat::Text && !opts.quiet {
+                println!("  {} {} ({} frames)", "v".green(), filename, frames);
+            }
+
+            Ok(JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                frames: Some(frames),
+                gain_applied_steps: Some(actual_steps),
+                gain_applied_db: Some(steps_to_db(actual_steps)),
+                warnings,
+                ..Default::default()
+            })
+        }
+        Err(e) => Ok(apply_error_result(file, e, opts)),
     }
-
-    Ok(())
 }
 
-fn cmd_track_gain(files: &[PathBuf], opts: &Options) -> Result<()> {
-    if !replaygain::is_available() {
-        eprintln!(
-            "{}: ReplayGain analysis requires the 'replaygain' feature",
-            "error".red().bold()
-        );
-        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
-        std::process::exit(1);
-    }
+/// Rewrite MP3GAIN_UNDO/REDO/MINMAX in mp3gain's own format (--normalize-tags)
+fn process_normalize_tags(file: &Path, opts: &Options) -> JsonFileResult {
+    let filename = get_filename(file);
+    let error_result = |e: anyhow::Error| {
+        if file_error(
+            opts,
+            opts.output_format == OutputFormat::Text && !opts.quiet,
+        ) {
+            eprintln!("  {} {} - {}", "x".red(), filename, e);
+        }
+        JsonFileResult {
+            file: get_path(file),
+            status: Some("error".to_string()),
+            error: Some(e.to_string()),
+            ..Default::default()
+        }
+    };
 
-    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+    let unchanged_msg = match read_gain_tag(file) {
+        Ok(Some(_)) => "gain tags already in mp3gain format",
+        Ok(None) => "no gain tags",
+        Err(e) => return error_result(e),
+    };
+    let tag_changes = preview_ape_changes(file, |mut tag| {
+        tag.normalize_undo();
+        Ok(tag)
+    });
 
-    if opts.output_format == OutputFormat::Text && !opts.quiet {
-        println!(
-            "{}{} Analyzing and {} track gain to {} file(s)",
-            dry_run_prefix,
-            "mp3rgain".green().bold(),
-            if opts.dry_run {
-                "would apply"
-            } else {
-                "applying"
-            },
-            files.len()
-        );
-        println!("  Target: {} dB (ReplayGain 1.0)", REPLAYGAIN_REFERENCE_DB);
-        if opts.gain_modifier != 0 {
-            println!("  Gain modifier: {:+} steps", opts.gain_modifier);
+    if tag_changes.is_empty() {
+        let msg = unchanged_msg.to_string();
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!("  {} {} ({})", ".".cyan(), filename, msg);
         }
-        println!();
+        return JsonFileResult {
+            file: get_path(file),
+            status: no_adjustment_status(opts),
+            warnings: Warnings::from(msg),
+            dry_run: if opts.dry_run { Some(true) } else { None },
+            ..Default::default()
+        };
     }
 
-    let pb = create_progress_bar(files.len(), opts);
-    let mut json_results: Vec<JsonFileResult> = Vec::new();
-    let mut successful = 0;
-    let mut failed = 0;
+    if opts.dry_run {
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!(
+                "  {} [DRY RUN] {} (would normalize gain tags)",
+                "~".cyan(),
+                filename
+            );
+            print_tag_changes(&tag_changes);
+        }
+        return JsonFileResult {
+            file: get_path(file),
+            status: Some("dry_run".to_string()),
+            dry_run: Some(true),
+            tag_changes: json_tag_changes(&tag_changes),
+            ..Default::default()
+        };
+    }
 
-    for file in files {
-        let filename = get_filename(file);
-        progress_set_message(&pb, filename);
+    let preserved = PreservedMetadata::capture(file, opts);
 
-        let result = process_track_gain(file, opts)?;
-        update_counters(&result, &mut successful, &mut failed);
+    match store_gain_tags(file, opts, normalize_undo_tag(file)) {
+        Ok(_) => {
+            preserved.restore(file);
+            if opts.output_format == OutputFormat::Text && !opts.quiet {
+                println!("  {} {} (normalized)", "v".green(), filename);
+                print_tag_changes(&tag_changes);
+            }
+            JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                tag_changes: json_tag_changes(&tag_changes),
+                ..Default::default()
+            }
+        }
+        Err(e) => error_result(e),
+    }
+}
 
-        if opts.output_format == OutputFormat::Json {
-            json_results.push(result);
+/// Record the current min/max global_gain as MP3GAIN_MINMAX (--write-minmax)
+fn process_write_minmax(file: &Path, opts: &Options) -> JsonFileResult {
+    let filename = get_filename(file);
+    let error_result = |e: anyhow::Error| {
+        if file_error(
+            opts,
+            opts.output_format == OutputFormat::Text && !opts.quiet,
+        ) {
+            eprintln!("  {} {} - {}", "x".red(), filename, e);
         }
+        JsonFileResult {
+            file: get_path(file),
+            status: Some("error".to_string()),
+            error: Some(e.to_string()),
+            ..Default::default()
+        }
+    };
 
-        progress_inc(&pb);
-    }
+    let analysis = match analyze(file) {
+        Ok(analysis) => analysis,
+        Err(e) => return error_result(e),
+    };
+    let tag_changes = preview_ape_changes(file, |_| preview_minmax_tag(file));
 
-    progress_finish(pb);
+    if tag_changes.is_empty() {
+        // The existing baseline predates any adjustment; keep it
+        let msg = format!("{} already present", TAG_MP3GAIN_MINMAX);
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!("  {} {} ({})", ".".cyan(), filename, msg);
+        }
+        return JsonFileResult {
+            file: get_path(file),
+            status: no_adjustment_status(opts),
+            warnings: Warnings::from(msg),
+            dry_run: if opts.dry_run { Some(true) } else { None },
+            ..Default::default()
+        };
+    }
 
-    if opts.output_format == OutputFormat::Json {
-        let output = JsonOutput {
-            files: Some(json_results),
-            album: None,
-            summary: Some(create_json_summary(
-                files.len(),
-                successful,
-                failed,
-                opts.dry_run,
-            )),
+    if opts.dry_run {
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!(
+                "  {} [DRY RUN] {} (would record {})",
+                "~".cyan(),
+                filename,
+                TAG_MP3GAIN_MINMAX
+            );
+            print_tag_changes(&tag_changes);
+        }
+        return JsonFileResult {
+            file: get_path(file),
+            status: Some("dry_run".to_string()),
+            min_gain: Some(analysis.min_gain),
+            max_gain: Some(analysis.max_gain),
+            dry_run: Some(true),
+            tag_changes: json_tag_changes(&tag_changes),
+            ..Default::default()
         };
-        println!("{}", serde_json::to_string_pretty(&output)?);
-    } else {
-        print_dry_run_notice(opts);
     }
 
-    Ok(())
+    let preserved = PreservedMetadata::capture(file, opts);
+
+    match store_gain_tags(file, opts, write_minmax_tag(file)) {
+        Ok(_) => {
+            preserved.restore(file);
+            if opts.output_format == OutputFormat::Text && !opts.quiet {
+                println!(
+                    "  {} {} ({}={},{})",
+                    "v".green(),
+                    filename,
+                    TAG_MP3GAIN_MINMAX,
+                    analysis.min_gain,
+                    analysis.max_gain
+                );
+            }
+            JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                min_gain: Some(analysis.min_gain),
+                max_gain: Some(analysis.max_gain),
+                ..Default::default()
+            }
+        }
+        Err(e) => error_result(e),
+    }
 }
 
-fn cmd_album_gain(files: &[PathBuf], opts: &Options) -> Result<()> {
-    if !replaygain::is_available() {
-        eprintln!(
-            "{}: ReplayGain analysis requires the 'replaygain' feature",
-            "error".red().bold()
-        );
-        eprintln!("  Install with: cargo install mp3rgain --features replaygain");
-        std::process::exit(1);
+/// Write the album ReplayGain tags of one file (--album-gain-db)
+///
+/// Track tags and audio are left alone. Without `peak`, a stored album peak
+/// is removed: it came from another analysis than the new gain.
+fn process_set_album_tags(
+    file: &Path,
+    gain_db: f64,
+    peak: Option<f64>,
+    opts: &Options,
+) -> JsonFileResult {
+    enum Planned {
+        Mp4(mp4meta::ReplayGainTags),
+        Ape(ApeTag),
     }
 
-    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+    let filename = get_filename(file);
+    let error_result = |e: anyhow::Error| {
+        if file_error(
+            opts,
+            opts.output_format == OutputFormat::Text && !opts.quiet,
+        ) {
+            eprintln!("  {} {} - {}", "x".red(), filename, e);
+        }
+        JsonFileResult {
+            file: get_path(file),
+            status: Some("error".to_string()),
+            error: Some(e.to_string()),
+            ..Default::default()
+        }
+    };
 
-    if opts.output_format == OutputFormat::Text && !opts.quiet {
-        println!(
-            "{}{} Analyzing album gain for {} file(s)",
-            dry_run_prefix,
-            "mp3rgain".green().bold(),
-            files.len()
-        );
-        println!("  Target: {} dB (ReplayGain 1.0)", REPLAYGAIN_REFERENCE_DB);
-        if opts.gain_modifier != 0 {
-            println!("  Gain modifier: {:+} steps", opts.gain_modifier);
+    let with_album = |mut tag: ApeTag| {
+        tag.set_replaygain_album(gain_db, peak.unwrap_or_default());
+        if peak.is_none() {
+            tag.remove(TAG_REPLAYGAIN_ALBUM_PEAK);
         }
-        println!();
-    }
+        tag
+    };
+    // M4A keeps ReplayGain in iTunes freeform tags, FLAC in Vorbis comments,
+    // MP3 and raw ADTS in APEv2
+    let file_type = detect_audio_type(file);
+    let plan = match file_type {
+        Some(AudioFileType::Aac) => mp4meta::read_replaygain_tags(file).map(|existing| {
+            let mut tags = existing.clone();
+            tags.set_album(gain_db, peak.unwrap_or_default());
+            if peak.is_none() {
+                tags.album_peak = None;
+            }
+            (existing.diff(&tags), Planned::Mp4(tags))
+        }),
+        Some(AudioFileType::Mp3) => read_gain_tag(file).map(|existing| {
+            let existing = existing.unwrap_or_default();
+            let tag = with_album(existing.clone());
+            (existing.diff(&tag), Planned::Ape(tag))
+        }),
+        Some(AudioFileType::AacAdts) => read_ape_tag_from_file(file).map(|existing| {
+            let existing = existing.unwrap_or_default();
+            let tag = with_album(existing.clone());
+            (existing.diff(&tag), Planned::Ape(tag))
+        }),
+        Some(AudioFileType::Flac) => flac::read_replaygain_tag(file).map(|existing| {
+            let existing = existing.unwrap_or_default();
+            let tag = with_album(existing.clone());
+            (existing.diff(&tag), Planned::Ape(tag))
+        }),
+        None => Err(anyhow::anyhow!("not an MP3, M4A, FLAC or AAC file")),
+    };
+    let (tag_changes, planned) = match plan {
+        Ok(plan) => plan,
+        Err(e) => return error_result(e),
+    };
 
-    // First, analyze all tracks
-    if opts.output_format == OutputFormat::Text && !opts.quiet {
-        println!("  {} Analyzing tracks...", "->".cyan());
+    if tag_changes.is_empty() {
+        let msg = "album tags already set".to_string();
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!("  {} {} ({})", ".".cyan(), filename, msg);
+        }
+        return JsonFileResult {
+            file: get_path(file),
+            status: no_adjustment_status(opts),
+            warnings: Warnings::from(msg),
+            dry_run: if opts.dry_run { Some(true) } else { None },
+            ..Default::default()
+        };
     }
 
-    let file_refs: Vec<&std::path::Path> = files.iter().map(|p| p.as_path()).collect();
+    if opts.dry_run {
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!(
+                "  {} [DRY RUN] {} (would write album gain {:+.2} dB)",
+                "~".cyan(),
+                filename,
+                gain_db
+            );
+            print_tag_changes(&tag_changes);
+        }
+        return JsonFileResult {
+            file: get_path(file),
+            status: Some("dry_run".to_string()),
+            dry_run: Some(true),
+            tag_changes: json_tag_changes(&tag_changes),
+            ..Default::default()
+        };
+    }
 
-    match replaygain::analyze_album_with_index(&file_refs, opts.track_index) {
-        Ok(album_result) => {
-            // Apply gain modifier
-            let modified_gain_steps = album_result.album_gain_steps() + opts.gain_modifier;
+    let preserved = PreservedMetadata::capture(file, opts);
+    let written = match planned {
+        Planned::Mp4(tags) => mp4meta::write_replaygain_tags(file, &tags),
+        Planned::Ape(tag) if file_type == Some(AudioFileType::Mp3) => {
+            write_gain_tag(file, &tag, opts.tag_format)
+        }
+        Planned::Ape(tag) if file_type == Some(AudioFileType::Flac) => {
+            flac::write_replaygain_tag(file, &tag)
+        }
+        Planned::Ape(tag) => write_ape_tag(file, &tag),
+    };
 
+    match written {
+        Ok(()) => {
+            preserved.restore(file);
             if opts.output_format == OutputFormat::Text && !opts.quiet {
-                println!();
-                println!("  Album loudness: {:.1} dB", album_result.album_loudness_db);
                 println!(
-                    "  Album gain:     {:+.1} dB ({} steps{})",
-                    album_result.album_gain_db,
-                    album_result.album_gain_steps(),
-                    if opts.gain_modifier != 0 {
-                        format!(" + {} = {}", opts.gain_modifier, modified_gain_steps)
-                    } else {
-                        String::new()
-                    }
+                    "  {} {} (album gain {:+.2} dB written)",
+                    "v".green(),
+                    filename,
+                    gain_db
                 );
-                println!("  Album peak:     {:.4}", album_result.album_peak);
-                println!();
             }
-
-            // Apply album gain to all files
-            let steps = modified_gain_steps;
-
-            if steps == 0 {
-                if opts.output_format == OutputFormat::Json {
-                    let json_results: Vec<JsonFileResult> = files
-                        .iter()
-                        .enumerate()
-                        .map(|(i, file)| {
-                            let track = &album_result.tracks[i];
-                            JsonFileResult {
-                                file: file.display().to_string(),
-                                status: Some("skipped".to_string()),
-                                loudness_db: Some(track.loudness_db),
-                                peak: Some(track.peak),
-                                gain_applied_steps: Some(0),
-                                gain_applied_db: Some(0.0),
-                                ..Default::default()
-                            }
-                        })
-                        .collect();
-
-                    let output = JsonOutput {
-                        files: Some(json_results),
-                        album: Some(JsonAlbumResult {
-                            loudness_db: album_result.album_loudness_db,
-                            gain_db: album_result.album_gain_db,
-                            gain_steps: modified_gain_steps,
-                            peak: album_result.album_peak,
-                        }),
-                        summary: Some(create_json_summary(files.len(), 0, 0, opts.dry_run)),
-                    };
-                    println!("{}", serde_json::to_string_pretty(&output)?);
-                } else if !opts.quiet {
-                    println!("  {} No adjustment needed", ".".cyan());
-                }
-                return Ok(());
+            JsonFileResult {
+                file: get_path(file),
+                status: Some("success".to_string()),
+                ..Default::default()
             }
+        }
+        Err(e) => error_result(e),
+    }
+}
 
-            let pb = create_progress_bar(files.len(), opts);
-            let mut json_results: Vec<JsonFileResult> = Vec::new();
-            let mut successful = 0;
-            let mut failed = 0;
-
-            for (i, file) in files.iter().enumerate() {
-                let filename = get_filename(file);
-                progress_set_message(&pb, filename);
-
-                let track_result = &album_result.tracks[i];
-                let album_info = AacAlbumInfo {
-                    album_gain_db: album_result.album_gain_db,
-                    album_peak: album_result.album_peak,
-                };
-                let result = process_apply_replaygain_with_album(
-                    file,
-                    steps,
-                    track_result,
-                    opts,
-                    Some(&album_info),
-                )?;
-                update_counters(&result, &mut successful, &mut failed);
+/// A gain that changes linearly across a range of frames (--ramp)
+#[derive(Clone, Copy, PartialEq)]
+struct GainRamp {
+    start_frame: usize,
+    end_frame: usize,
+    start_steps: i32,
+    end_steps: i32,
+}
 
-                if opts.output_format == OutputFormat::Json {
-                    json_results.push(result);
-                }
+impl GainRamp {
+    /// Parse `<startf>:<endf>:<startsteps>:<endsteps>`, e.g. `0:38:-10:0`
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(':');
+        let ramp = GainRamp {
+            start_frame: parts.next()?.trim().parse().ok()?,
+            end_frame: parts.next()?.trim().parse().ok()?,
+            start_steps: parts.next()?.trim().parse().ok()?,
+            end_steps: parts.next()?.trim().parse().ok()?,
+        };
+        parts.next().is_none().then_some(ramp)
+    }
 
-                progress_inc(&pb);
-            }
+    fn describe(&self) -> String {
+        format!(
+            "{:+} to {:+} steps over frames {}-{}",
+            self.start_steps, self.end_steps, self.start_frame, self.end_frame
+        )
+    }
+}
 
-            progress_finish(pb);
+/// --ramp: fade the gain across a range of frames of each file
+fn cmd_ramp(files: &[PathBuf], ramp: GainRamp, opts: &Options) -> Result<()> {
+    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
-            if opts.output_format == OutputFormat::Json {
-                let output = JsonOutput {
-                    files: Some(json_results),
-                    album: Some(JsonAlbumResult {
-                        loudness_db: album_result.album_loudness_db,
-                        gain_db: album_result.album_gain_db,
-                        gain_steps: modified_gain_steps,
-                        peak: album_result.album_peak,
-                    }),
-                    summary: Some(create_json_summary(
-                        files.len(),
-                        successful,
-                        failed,
-                        opts.dry_run,
-                    )),
-                };
-                println!("{}", serde_json::to_string_pretty(&output)?);
-            } else {
-                print_dry_run_notice(opts);
-            }
-        }
-        Err(e) => {
-            if opts.output_format == OutputFormat::Json {
-                let output = JsonOutput {
-                    files: None,
-                    album: None,
-                    summary: Some(create_json_summary(
-                        files.len(),
-                        0,
-                        files.len(),
-                        opts.dry_run,
-                    )),
-                };
-                println!("{}", serde_json::to_string_pretty(&output)?);
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "{}{} {} a ramp of {} to {} file(s)",
+            dry_run_prefix,
+            "mp3rgain".green().bold(),
+            if opts.dry_run {
+                "Would apply"
             } else {
-                eprintln!("{}: Failed to analyze album: {}", "error".red().bold(), e);
-            }
-            std::process::exit(1);
-        }
+                "Applying"
+            },
+            ramp.describe(),
+            files.len()
+        );
+        println!();
     }
 
-    Ok(())
-}
+    let pb = create_progress_bar(files.len(), opts);
+    let mut stats = Stats::new(opts);
+    let mut json_results: Vec<JsonFileResult> = Vec::new();
+    let mut successful = 0;
+    let mut failed = 0;
+
+    for file in files {
+        let filename = get_filename(file);
+        progress_set_message(&pb, &filename);
 
-// =============================================================================
-// File processing
-// =============================================================================
+        let timer = stats.begin(file);
+        let mut result = process_ramp(file, ramp, opts);
+        stats.finish(timer, file, opts, &mut result);
+        write_sidecar(file, &mut result, opts);
+        update_counters(&result, &mut successful, &mut failed);
 
-fn apply_with_temp_file<F>(file: &PathBuf, operation: F, opts: &Options) -> Result<usize>
-where
-    F: FnOnce(&Path) -> Result<usize>,
-{
-    if opts.use_temp_file {
-        // Create temp file in the same directory
-        let parent = file.parent().unwrap_or(Path::new("."));
-        let temp_path = parent.join(format!(".mp3rgain_temp_{}.mp3", std::process::id()));
+        if opts.output_format == OutputFormat::Json {
+            json_results.push(result);
+        }
 
-        // Copy original to temp
-        fs::copy(file, &temp_path)?;
+        progress_inc(&pb);
+    }
 
-        // Apply operation to temp file
-        match operation(&temp_path) {
-            Ok(frames) => {
-                // Replace original with temp
-                fs::rename(&temp_path, file)?;
-                Ok(frames)
-            }
-            Err(e) => {
-                // Clean up temp file on error
-                let _ = fs::remove_file(&temp_path);
-                Err(e)
-            }
-        }
+    progress_finish(pb);
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonOutput {
+            files: Some(json_results),
+            album: None,
+            albums: None,
+            summary: Some(create_json_summary(
+                files.len(),
+                successful,
+                failed,
+                opts.dry_run,
+            )),
+            stats: stats.json(),
+        };
+        print_json(&output, opts)?;
     } else {
-        operation(file)
+        stats.print(opts);
+        print_dry_run_notice(opts);
     }
+
+    Ok(())
 }
 
-fn process_apply(file: &PathBuf, steps: i32, opts: &Options) -> Result<JsonFileResult> {
+/// Apply a --ramp to one file; no undo information is stored for it
+fn process_ramp(file: &Path, ramp: GainRamp, opts: &Options) -> JsonFileResult {
     let filename = get_filename(file);
-    let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
-    // Save original timestamp if needed
-    let original_mtime = if opts.preserve_timestamp && !opts.dry_run {
-        std::fs::metadata(file).ok().and_then(|m| m.modified().ok())
-    } else {
-        None
-    };
-
-    // Check for clipping and possibly prevent it
-    let mut actual_steps = steps;
-    let mut warning_msg: Option<String> = None;
-
-    if steps > 0 && !opts.wrap_gain {
-        if let Ok(info) = analyze(file) {
-            if steps > info.headroom_steps {
-                if opts.prevent_clipping {
-                    // -k: automatically reduce gain to prevent clipping
-                    let original_steps = steps;
-                    actual_steps = info.headroom_steps;
-                    if opts.output_format == OutputFormat::Text && !opts.quiet {
-                        eprintln!(
-                            "  {} {}{} - gain reduced from {} to {} steps to prevent clipping",
-                            "!".yellow(),
-                            dry_run_prefix,
-                            filename,
-                            original_steps,
-                            actual_steps
-                        );
-                    }
-                    warning_msg = Some(format!(
-                        "gain reduced from {} to {} steps to prevent clipping",
-                        original_steps, actual_steps
-                    ));
-                } else if !opts.ignore_clipping && !opts.quiet {
-                    // Show warning but continue
-                    if opts.output_format == OutputFormat::Text {
-                        eprintln!(
-                            "  {} {}{} - clipping warning: requested {} steps but only {} headroom",
-                            "!".yellow(),
-                            dry_run_prefix,
-                            filename,
-                            steps,
-                            info.headroom_steps
-                        );
-                        eprintln!(
-                            "      Use -c to ignore clipping warnings or -k to prevent clipping"
-                        );
-                    }
-                    warning_msg = Some(format!(
-                        "clipping warning: requested {} steps but only {} headroom",
-                        steps, info.headroom_steps
-                    ));
-                }
-            }
-        }
+    if is_adts_file(file) {
+        return adts_unsupported_result(file, opts);
     }
 
-    // Dry run: don't actually modify
     if opts.dry_run {
         if opts.output_format == OutputFormat::Text && !opts.quiet {
             println!(
-                "  {} [DRY RUN] {} (would apply {} steps)",
+                "  {} [DRY RUN] {} (would apply {})",
                 "~".cyan(),
                 filename,
-                actual_steps
+                ramp.describe()
             );
         }
-        return Ok(JsonFileResult {
-            file: file.display().to_string(),
+        return JsonFileResult {
+            file: get_path(file),
             status: Some("dry_run".to_string()),
-            gain_applied_steps: Some(actual_steps),
-            gain_applied_db: Some(steps_to_db(actual_steps)),
-            warning: warning_msg,
             dry_run: Some(true),
             ..Default::default()
-        });
+        };
     }
 
-    let apply_result = if opts.stored_tag_mode == StoredTagMode::Skip {
-        // -s s: Skip tag writing, just apply gain
-        if opts.wrap_gain {
-            apply_with_temp_file(file, |f| apply_gain_wrap(f, actual_steps), opts)
-        } else {
-            apply_with_temp_file(file, |f| apply_gain(f, actual_steps), opts)
-        }
-    } else if opts.wrap_gain {
-        apply_with_temp_file(file, |f| apply_gain_with_undo_wrap(f, actual_steps), opts)
-    } else {
-        apply_with_temp_file(file, |f| apply_gain_with_undo(f, actual_steps), opts)
-    };
-
-    match apply_result {
+    let preserved = PreservedMetadata::capture(file, opts);
+    let applied = apply_with_temp_file(
+        file,
+        |f| {
+            apply_gain_ramp(
+                f,
+                ramp.start_frame,
+                ramp.end_frame,
+                ramp.start_steps,
+                ramp.end_steps,
+            )
+        },
+        opts,
+    );
+    match applied {
         Ok(frames) => {
-            // Restore timestamp if needed
-            if let Some(mtime) = original_mtime {
-                restore_timestamp(file, mtime);
-            }
-
+            preserved.restore(file);
+            let msg = "ramps are not recorded in MP3GAIN_UNDO; -u does not reverse them";
             if opts.output_format == OutputFormat::Text && !opts.quiet {
-                println!("  {} {} ({} frames)", "v".green(), filename, frames);
+                println!(
+                    "  {} {} ({} frames, {})",
+                    "v".green(),
+                    filename,
+                    frames,
+                    ramp.describe()
+                );
+                eprintln!("  {} {} - {}", "!".yellow(), filename, msg);
             }
-
-            Ok(JsonFileResult {
-                file: file.display().to_string(),
+            JsonFileResult {
+                file: get_path(file),
                 status: Some("success".to_string()),
                 frames: Some(frames),
-                gain_applied_steps: Some(actual_steps),
-                gain_applied_db: Some(steps_to_db(actual_steps)),
-                warning: warning_msg,
+                warnings: Warnings::from(msg.to_string()),
                 ..Default::default()
-            })
-        }
-        Err(e) => {
-            if opts.output_format == OutputFormat::Text && !opts.quiet {
-                eprintln!("  {} {} - {}", "x".red(), filename, e);
             }
-
-            Ok(JsonFileResult {
-                file: file.display().to_string(),
-                status: Some("error".to_string()),
-                error: Some(e.to_string()),
-                ..Default::default()
-            })
         }
+        Err(e) => apply_error_result(file, e, opts),
     }
 }
 
 fn process_apply_channel(
-    file: &PathBuf,
-    channel: Channel,
-    steps: i32,
+    file: &Path,
+    left: i32,
+    right: i32,
     opts: &Options,
 ) -> Result<JsonFileResult> {
     let filename = get_filename(file);
-    let channel_name = match channel {
-        Channel::Left => "left",
-        Channel::Right => "right",
+    // Single-channel runs keep reporting their gain in gain_applied_*
+    let single_steps = match (left, right) {
+        (steps, 0) | (0, steps) => Some(steps),
+        _ => None,
     };
 
-    // Save original timestamp if needed
-    let original_mtime = if opts.preserve_timestamp && !opts.dry_run {
-        std::fs::metadata(file).ok().and_then(|m| m.modified().ok())
-    } else {
-        None
-    };
+    if is_adts_file(file) {
+        return Ok(adts_unsupported_result(file, opts));
+    }
+
+    // Mid/side and intensity stereo code the channels together, so global_gain
+    // of one granule channel doesn't map to the left or right speaker alone
+    let warning = (left != right && analyze(file).is_ok_and(|a| a.channel_mode == "Joint Stereo"))
+        .then(|| {
+            "joint stereo file: with mid/side or intensity coding, channel gain \
+         does not cleanly separate left and right"
+                .to_string()
+        });
+    if let Some(msg) = &warning {
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            eprintln!("  {} {} - {}", "!".yellow(), filename, msg);
+        }
+    }
+
+    // Save original timestamp (and mode/owner) if needed
+    let preserved = PreservedMetadata::capture(file, opts);
+
+    if is_unchanged_by_gain(file, left, right, opts) {
+        let mut result = unchanged_result(file, opts);
+        result.left_gain_steps = Some(0);
+        result.right_gain_steps = Some(0);
+        return Ok(result);
+    }
 
     // Dry run: don't actually modify
     if opts.dry_run {
         if opts.output_format == OutputFormat::Text && !opts.quiet {
             println!(
-                "  {} [DRY RUN] {} (would apply {} steps to {} channel)",
+                "  {} [DRY RUN] {} (would apply {})",
                 "~".cyan(),
                 filename,
-                steps,
-                channel_name
+                describe_channel_gains(left, right)
             );
         }
         return Ok(JsonFileResult {
-            file: file.display().to_string(),
+            file: get_path(file),
             status: Some("dry_run".to_string()),
-            gain_applied_steps: Some(steps),
-            gain_applied_db: Some(steps_to_db(steps)),
+            gain_applied_steps: single_steps,
+            gain_applied_db: single_steps.map(steps_to_db),
+            left_gain_steps: Some(left),
+            right_gain_steps: Some(right),
             dry_run: Some(true),
+            warnings: warning.into(),
             ..Default::default()
         });
     }
 
-    match apply_gain_channel_with_undo(file, channel, steps) {
+    let apply_result = if opts.stored_tag_mode == StoredTagMode::Skip {
+        apply_with_temp_file(file, |f| apply_gain_channels(f, left, right), opts)
+    } else {
+        apply_with_temp_file(
+            file,
+            |f| store_gain_tags(f, opts, apply_gain_channels_with_undo(f, left, right)),
+            opts,
+        )
+    };
+    match apply_result {
         Ok(frames) => {
-            // Restore timestamp if needed
-            if let Some(mtime) = original_mtime {
-                restore_timestamp(file, mtime);
-            }
+            // Restore timestamp (and mode/owner) if needed
+            preserved.restore(file);
 
             if opts.output_format == OutputFormat::Text && !opts.quiet {
                 println!(
-                    "  {} {} ({} frames, {} channel)",
+                    "  {} {} ({} frames, {})",
                     "v".green(),
                     filename,
                     frames,
-                    channel_name
+                    describe_channel_gains(left, right)
                 );
             }
 
             Ok(JsonFileResult {
-                file: file.display().to_string(),
+                file: get_path(file),
                 status: Some("success".to_string()),
                 frames: Some(frames),
-                gain_applied_steps: Some(steps),
-                gain_applied_db: Some(steps_to_db(steps)),
+                gain_applied_steps: single_steps,
+                gain_applied_db: single_steps.map(steps_to_db),
+                left_gain_steps: Some(left),
+                right_gain_steps: Some(right),
+                warnings: warning.into(),
                 ..Default::default()
             })
         }
         Err(e) => {
-            if opts.output_format == OutputFormat::Text && !opts.quiet {
+            if file_error(
+                opts,
+                opts.output_format == OutputFormat::Text && !opts.quiet,
+            ) {
                 eprintln!("  {} {} - {}", "x".red(), filename, e);
             }
 
             Ok(JsonFileResult {
-                file: file.display().to_string(),
+                file: get_path(file),
                 status: Some("error".to_string()),
                 error: Some(e.to_string()),
                 ..Default::default()
@@ -1708,8 +8051,9 @@ fn process_info(file: &Path, opts: &Options) -> Result<JsonFileResult> {
                     find_max_amplitude(file).unwrap_or((1.0, 255, 0));
 
                 // Calculate gain with modifier (mp3gain compatible: -d modifies suggested gain)
-                let gain_db = rg_result.gain_db + opts.gain_modifier_db;
-                let gain_steps = db_to_steps(gain_db);
+                let target_db = replaygain_target_db(opts);
+                let gain_db = rg_result.gain_for_target(target_db);
+                let gain_steps = rg_result.gain_steps_for_target(target_db);
 
                 // Max Amplitude scaled to 32768 (mp3gain format for beets)
                 // beets divides by 32768, so we output peak * 32768
@@ -1722,7 +8066,7 @@ fn process_info(file: &Path, opts: &Options) -> Result<JsonFileResult> {
                 );
 
                 return Ok(JsonFileResult {
-                    file: file.display().to_string(),
+                    file: get_path(file),
                     loudness_db: Some(rg_result.loudness_db),
                     gain_applied_db: Some(gain_db),
                     gain_applied_steps: Some(gain_steps),
@@ -1734,9 +8078,11 @@ fn process_info(file: &Path, opts: &Options) -> Result<JsonFileResult> {
                 });
             }
             Err(e) => {
-                eprintln!("{} - {}", filename.red(), e);
+                if file_error(opts, true) {
+                    eprintln!("{} - {}", filename.red(), e);
+                }
                 return Ok(JsonFileResult {
-                    file: file.display().to_string(),
+                    file: get_path(file),
                     status: Some("error".to_string()),
                     error: Some(e.to_string()),
                     ..Default::default()
@@ -1745,33 +8091,15 @@ fn process_info(file: &Path, opts: &Options) -> Result<JsonFileResult> {
         }
     }
 
-    // Check if this is an M4A/AAC file - if so, show appropriate message
-    if mp4meta::is_mp4_file(file) {
-        match opts.output_format {
-            OutputFormat::Text => {
-                if opts.quiet {
-                    println!("{}\tM4A/AAC\t-\t-\t-\t-\t-", filename);
-                } else {
-                    println!("{}", filename.cyan().bold());
-                    println!("  Format:      M4A/AAC");
-                    println!(
-                        "  {}",
-                        "Note: Use -r or -a for ReplayGain analysis".yellow()
-                    );
-                    println!();
-                }
-            }
-            OutputFormat::Tsv => {
-                println!("{}\t-\t-\t-\t-\t-", filename);
-            }
-            OutputFormat::Json => {}
-        }
-
-        return Ok(JsonFileResult {
-            file: file.display().to_string(),
-            status: Some("info".to_string()),
-            ..Default::default()
-        });
+    // Check if this is an M4A/AAC or FLAC file - if so, show appropriate message
+    let aac_format = match detect_audio_type(file) {
+        Some(AudioFileType::Aac) => Some("M4A/AAC"),
+        Some(AudioFileType::AacAdts) => Some("AAC (ADTS)"),
+        Some(AudioFileType::Flac) => Some("FLAC"),
+        _ => None,
+    };
+    if let Some(format) = aac_format {
+        return Ok(process_aac_info(file, format, opts));
     }
 
     // MP3 file: use basic analysis
@@ -1807,6 +8135,36 @@ fn process_info(file: &Path, opts: &Options) -> Result<JsonFileResult> {
                             info.headroom_steps.to_string().green(),
                             info.headroom_db
                         );
+                        if info.leading_bytes > 0 {
+                            println!(
+                                "  Leading:     {} junk bytes before audio (ignored)",
+                                info.leading_bytes.to_string().yellow()
+                            );
+                        }
+                        if info.id3v2_gap > 0 {
+                            println!(
+                                "  Gap:         {} bytes before the first frame (ignored)",
+                                info.id3v2_gap
+                            );
+                        }
+                        if info.trailing_bytes > 0 {
+                            println!(
+                                "  Trailing:    {} bytes after last frame (ignored)",
+                                info.trailing_bytes.to_string().yellow()
+                            );
+                        }
+                        if info.bad_frames > 0 {
+                            let offsets: Vec<String> = info
+                                .bad_frame_offsets
+                                .iter()
+                                .map(|offset| offset.to_string())
+                                .collect();
+                            println!(
+                                "  Bad frames:  {} skipped at byte {} (left unchanged)",
+                                info.bad_frames.to_string().yellow(),
+                                offsets.join(", ")
+                            );
+                        }
                         println!();
                     }
                 }
@@ -1826,7 +8184,7 @@ fn process_info(file: &Path, opts: &Options) -> Result<JsonFileResult> {
             }
 
             Ok(JsonFileResult {
-                file: file.display().to_string(),
+                file: get_path(file),
                 mpeg_version: Some(info.mpeg_version),
                 channel_mode: Some(info.channel_mode),
                 frames: Some(info.frame_count),
@@ -1835,16 +8193,21 @@ fn process_info(file: &Path, opts: &Options) -> Result<JsonFileResult> {
                 avg_gain: Some(info.avg_gain),
                 headroom_steps: Some(info.headroom_steps),
                 headroom_db: Some(info.headroom_db),
+                leading_bytes: (info.leading_bytes > 0).then_some(info.leading_bytes),
+                id3v2_gap: (info.id3v2_gap > 0).then_some(info.id3v2_gap),
+                trailing_bytes: (info.trailing_bytes > 0).then_some(info.trailing_bytes),
+                bad_frames: (info.bad_frames > 0).then_some(info.bad_frames),
+                bad_frame_offsets: (info.bad_frames > 0).then_some(info.bad_frame_offsets),
                 ..Default::default()
             })
         }
         Err(e) => {
-            if opts.output_format != OutputFormat::Json {
+            if file_error(opts, opts.output_format != OutputFormat::Json) {
                 eprintln!("{} - {}", filename.red(), e);
             }
 
             Ok(JsonFileResult {
-                file: file.display().to_string(),
+                file: get_path(file),
                 status: Some("error".to_string()),
                 error: Some(e.to_string()),
                 ..Default::default()
@@ -1853,66 +8216,83 @@ fn process_info(file: &Path, opts: &Options) -> Result<JsonFileResult> {
     }
 }
 
-fn process_undo(file: &PathBuf, opts: &Options) -> Result<JsonFileResult> {
+fn process_undo(file: &Path, opts: &Options) -> Result<JsonFileResult> {
     let filename = get_filename(file);
     let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
+    let action = if opts.redo { "redo" } else { "undo" };
 
-    // Save original timestamp if needed
-    let original_mtime = if opts.preserve_timestamp && !opts.dry_run {
-        std::fs::metadata(file).ok().and_then(|m| m.modified().ok())
-    } else {
-        None
-    };
+    // aacgain changed the AAC audio itself, which mp3rgain doesn't edit
+    if let Some(undo) = aacgain_undo(file) {
+        let e = anyhow::anyhow!(
+            "adjusted by aacgain (undo {}); AAC audio can't be changed by mp3rgain, use aacgain -u",
+            undo
+        );
+        return Ok(apply_error_result(file, e, opts));
+    }
+
+    // Save original timestamp (and mode/owner) if needed
+    let preserved = PreservedMetadata::capture(file, opts);
 
     // Dry run: just analyze what would be done
     if opts.dry_run {
         // Try to read the undo tag to see what would happen
         if opts.output_format == OutputFormat::Text && !opts.quiet {
-            println!("  {} [DRY RUN] {} (would undo)", "~".cyan(), filename);
+            println!("  {} [DRY RUN] {} (would {})", "~".cyan(), filename, action);
         }
         return Ok(JsonFileResult {
-            file: file.display().to_string(),
+            file: get_path(file),
             status: Some("dry_run".to_string()),
             dry_run: Some(true),
             ..Default::default()
         });
     }
 
-    match undo_gain(file) {
+    // The redone gain is written to APEv2 like any other adjustment, and so
+    // are the adjustments that --last leaves in place
+    let result = if opts.redo {
+        store_gain_tags(file, opts, redo_gain(file))
+    } else if opts.undo_last {
+        store_gain_tags(file, opts, undo_last_gain(file))
+    } else if opts.keep_redo {
+        undo_gain_keep_redo(file)
+    } else {
+        undo_gain(file)
+    };
+    match result {
         Ok(frames) => {
             if frames == 0 {
                 if opts.output_format == OutputFormat::Text && !opts.quiet {
                     println!(
-                        "  {} {}{} (no changes to undo)",
+                        "  {} {}{} (no changes to {})",
                         ".".cyan(),
                         dry_run_prefix,
-                        filename
+                        filename,
+                        action
                     );
                 }
 
                 Ok(JsonFileResult {
-                    file: file.display().to_string(),
+                    file: get_path(file),
                     status: Some("skipped".to_string()),
                     frames: Some(0),
                     ..Default::default()
                 })
             } else {
-                // Restore timestamp if needed
-                if let Some(mtime) = original_mtime {
-                    restore_timestamp(file, mtime);
-                }
+                // Restore timestamp (and mode/owner) if needed
+                preserved.restore(file);
 
                 if opts.output_format == OutputFormat::Text && !opts.quiet {
                     println!(
-                        "  {} {} ({} frames restored)",
+                        "  {} {} ({} frames {})",
                         "v".green(),
                         filename,
-                        frames
+                        frames,
+                        if opts.redo { "re-applied" } else { "restored" }
                     );
                 }
 
                 Ok(JsonFileResult {
-                    file: file.display().to_string(),
+                    file: get_path(file),
                     status: Some("success".to_string()),
                     frames: Some(frames),
                     ..Default::default()
@@ -1920,12 +8300,15 @@ fn process_undo(file: &PathBuf, opts: &Options) -> Result<JsonFileResult> {
             }
         }
         Err(e) => {
-            if opts.output_format == OutputFormat::Text && !opts.quiet {
+            if file_error(
+                opts,
+                opts.output_format == OutputFormat::Text && !opts.quiet,
+            ) {
                 eprintln!("  {} {} - {}", "x".red(), filename, e);
             }
 
             Ok(JsonFileResult {
-                file: file.display().to_string(),
+                file: get_path(file),
                 status: Some("error".to_string()),
                 error: Some(e.to_string()),
                 ..Default::default()
@@ -1934,7 +8317,24 @@ fn process_undo(file: &PathBuf, opts: &Options) -> Result<JsonFileResult> {
     }
 }
 
-fn process_track_gain(file: &PathBuf, opts: &Options) -> Result<JsonFileResult> {
+/// aacgain's undo information in an M4A file, if it has any
+fn aacgain_undo(file: &Path) -> Option<String> {
+    if !mp4meta::is_mp4_file(file) {
+        return None;
+    }
+    mp4meta::read_replaygain_tags(file).ok()?.mp3gain_undo
+}
+
+/// The gain tag of `file` as [`read_gain_tag`] finds it; for M4A, the
+/// ReplayGain and aacgain freeform tags under the same key names
+fn read_stored_gain_tag(file: &Path) -> Result<Option<ApeTag>> {
+    if mp4meta::is_mp4_file(file) {
+        return Ok(mp4meta::read_replaygain_tags(file)?.to_gain_tag());
+    }
+    read_gain_tag(file)
+}
+
+fn process_track_gain(file: &Path, opts: &Options) -> Result<JsonFileResult> {
     let filename = get_filename(file);
     let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
@@ -1947,61 +8347,166 @@ fn process_track_gain(file: &PathBuf, opts: &Options) -> Result<JsonFileResult>
         );
     }
 
-    match replaygain::analyze_track_with_index(file, opts.track_index) {
-        Ok(result) => {
-            // Apply gain modifier
-            let base_steps = result.gain_steps();
-            let modified_steps = base_steps + opts.gain_modifier;
-
-            if opts.output_format == OutputFormat::Text && !opts.quiet {
-                println!(
-                    "      Loudness: {:.1} dB, Gain: {:+.1} dB ({} steps{}), Peak: {:.4}",
-                    result.loudness_db,
-                    result.gain_db,
-                    base_steps,
-                    if opts.gain_modifier != 0 {
-                        format!(" + {} = {}", opts.gain_modifier, modified_steps)
-                    } else {
-                        String::new()
-                    },
-                    result.peak
-                );
+    match analyze_track(file, opts) {
+        Ok(result) => process_track_result(file, &result, opts),
+        Err(e) => {
+            if file_error(
+                opts,
+                opts.output_format == OutputFormat::Text && !opts.quiet,
+            ) {
+                eprintln!("  {} {} - {}", "x".red(), filename, e);
             }
 
-            if modified_steps == 0 {
-                if opts.output_format == OutputFormat::Text && !opts.quiet {
-                    println!("  {} {} (no adjustment needed)", ".".cyan(), filename);
-                }
-                return Ok(JsonFileResult {
-                    file: file.display().to_string(),
-                    status: Some("skipped".to_string()),
-                    loudness_db: Some(result.loudness_db),
-                    peak: Some(result.peak),
-                    gain_applied_steps: Some(0),
-                    gain_applied_db: Some(0.0),
-                    ..Default::default()
-                });
-            }
+            Ok(JsonFileResult {
+                file: get_path(file),
+                status: Some("error".to_string()),
+                error: Some(e.to_string()),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+/// Report and apply the track gain of an analyzed file
+fn process_track_result(
+    file: &Path,
+    result: &ReplayGainResult,
+    opts: &Options,
+) -> Result<JsonFileResult> {
+    let filename = get_filename(file);
+
+    // Apply gain modifiers: -d moves the target, -m adds whole steps
+    let requested_db = result.gain_for_target(replaygain_target_db(opts));
+    let base_steps = track_gain_steps(result, opts);
+    let modified_steps = base_steps + opts.gain_modifier;
+
+    if opts.output_format == OutputFormat::Text && !opts.quiet {
+        println!(
+            "      Loudness: {:.1} dB, Gain: {:+.1} dB ({} steps{}), Peak: {:.4}",
+            result.loudness_db,
+            requested_db,
+            base_steps,
+            if opts.gain_modifier != 0 {
+                format!(" + {} = {}", opts.gain_modifier, modified_steps)
+            } else {
+                String::new()
+            },
+            result.peak
+        );
+        let tags_only = opts.tags_only || !result.file_type.is_some_and(|t| t.has_global_gain());
+        if tags_only && opts.show_continuous {
+            println!(
+                "      Exact gain: {:+.2} dB (stored in tags as is, no quantization)",
+                result.gain_db
+            );
+        } else {
+            print_continuous_gain("      Exact gain:", requested_db, opts);
+        }
+        if result.channel_layout == replaygain::ChannelLayout::DualChannel {
+            println!(
+                "      {}: dual channel file, both programs analyzed together",
+                "note".yellow()
+            );
+        }
+    }
+    print_partial_warning(file, result, opts);
+
+    // A frame-domain estimate is only reported, never applied
+    if result.method == AnalysisMethod::Heuristic {
+        let msg = "could not decode audio; gain is an approximate estimate from \
+                   global_gain values and was not applied";
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            eprintln!("  {} {} - {}", "!".yellow(), filename, msg);
+        }
+        return Ok(JsonFileResult {
+            file: get_path(file),
+            status: Some("skipped".to_string()),
+            loudness_db: Some(result.loudness_db),
+            peak: Some(result.peak),
+            gain_applied_steps: Some(0),
+            gain_applied_db: Some(0.0),
+            suggested_gain_steps: Some(modified_steps),
+            method: Some(result.method.as_str()),
+            warnings: Warnings::from(msg.to_string()),
+            ..Default::default()
+        });
+    }
+
+    if modified_steps == 0 && !opts.tags_only && !opts.tag_even_if_zero {
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!("  {} {} (no adjustment needed)", ".".cyan(), filename);
+        }
+        let mut json = JsonFileResult {
+            file: get_path(file),
+            status: no_adjustment_status(opts),
+            loudness_db: Some(result.loudness_db),
+            peak: Some(result.peak),
+            gain_applied_steps: Some(0),
+            gain_applied_db: Some(0.0),
+            method: Some(result.method.as_str()),
+            partial: result.partial.then_some(true),
+            ..Default::default()
+        };
+        add_continuous_gain(&mut json, requested_db, opts);
+        return Ok(json);
+    }
+
+    let mut json = process_apply_replaygain(file, modified_steps, result, opts)?;
+    json.method = Some(result.method.as_str());
+    json.partial = result.partial.then_some(true);
+    add_continuous_gain(&mut json, requested_db, opts);
+    Ok(json)
+}
+
+/// Track gain for -r in whole steps, before -m
+///
+/// Rounds down where rounding to nearest would push the peak past full scale
+/// (see [`ReplayGainResult::clip_safe_gain_steps`]); with -d, which asks for a specific
+/// level, the gain rounds to nearest.
+fn track_gain_steps(result: &ReplayGainResult, opts: &Options) -> i32 {
+    let target_db = replaygain_target_db(opts);
+    if opts.gain_modifier_db != 0.0 {
+        result.gain_steps_for_target(target_db)
+    } else {
+        result.clip_safe_gain_steps(target_db)
+    }
+}
+
+/// Loudness -r/-a level files to: the 89 dB reference moved by -d
+fn replaygain_target_db(opts: &Options) -> f64 {
+    REPLAYGAIN_REFERENCE_DB + opts.gain_modifier_db
+}
 
-            process_apply_replaygain(file, modified_steps, &result, opts)
-        }
-        Err(e) => {
-            if opts.output_format == OutputFormat::Text && !opts.quiet {
-                eprintln!("  {} {} - {}", "x".red(), filename, e);
-            }
+/// --show-continuous: print the exact gain, the whole steps it rounds to and
+/// the rounding error (text output only)
+fn print_continuous_gain(label: &str, gain_db: f64, opts: &Options) {
+    if !opts.show_continuous || opts.output_format != OutputFormat::Text || opts.quiet {
+        return;
+    }
+    let steps = db_to_steps(gain_db);
+    println!(
+        "{} {:+.2} dB, rounded to {} steps = {:+.2} dB (quantization error {:+.2} dB)",
+        label,
+        gain_db,
+        steps,
+        steps_to_db(steps),
+        quantization_error_db(gain_db)
+    );
+}
 
-            Ok(JsonFileResult {
-                file: file.display().to_string(),
-                status: Some("error".to_string()),
-                error: Some(e.to_string()),
-                ..Default::default()
-            })
-        }
+/// --show-continuous: record the exact gain and how far the applied gain is from it
+///
+/// Besides rounding to whole steps, the difference includes -m and any -k
+/// reduction.
+fn add_continuous_gain(json: &mut JsonFileResult, requested_db: f64, opts: &Options) {
+    if let (true, Some(applied_db)) = (opts.show_continuous, json.gain_applied_db) {
+        json.requested_gain_db = Some(requested_db);
+        json.quantization_error_db = Some(applied_db - requested_db);
     }
 }
 
 fn process_apply_replaygain(
-    file: &PathBuf,
+    file: &Path,
     steps: i32,
     result: &ReplayGainResult,
     opts: &Options,
@@ -2010,7 +8515,7 @@ fn process_apply_replaygain(
 }
 
 fn process_apply_replaygain_with_album(
-    file: &PathBuf,
+    file: &Path,
     steps: i32,
     result: &ReplayGainResult,
     opts: &Options,
@@ -2019,45 +8524,48 @@ fn process_apply_replaygain_with_album(
     let filename = get_filename(file);
     let dry_run_prefix = if opts.dry_run { "[DRY RUN] " } else { "" };
 
-    // Save original timestamp if needed
-    let original_mtime = if opts.preserve_timestamp && !opts.dry_run {
-        std::fs::metadata(file).ok().and_then(|m| m.modified().ok())
-    } else {
-        None
-    };
+    // Save original timestamp (and mode/owner) if needed
+    let preserved = PreservedMetadata::capture(file, opts);
+
+    let (steps, ceiling_msg) = cap_to_ceiling(file, steps, opts);
 
     // Check for clipping if not ignored
     let mut actual_steps = steps;
-    let mut warning_msg: Option<String> = None;
+    let mut warnings = Warnings::from(ceiling_msg);
 
     if steps > 0 && !opts.wrap_gain {
         // Check if applying this gain would cause clipping
-        let gain_linear = 10.0_f64.powf(result.gain_db / 20.0);
-        let new_peak = result.peak * gain_linear;
-        if new_peak > 1.0 {
-            if opts.prevent_clipping {
-                // Calculate the maximum safe gain
-                let max_safe_db = -20.0 * result.peak.log10();
-                let max_safe_steps = db_to_steps(max_safe_db);
-                actual_steps = max_safe_steps.max(0);
-
+        let gain_db = steps_to_db(steps);
+        if replaygain::would_clip(result.peak, gain_db) {
+            if opts.prevent_clipping || opts.album_clip_safe {
+                // Largest gain keeping the peak at full scale; `steps` already
+                // include -m, so they are passed on as the loudness target
+                let target_db = REPLAYGAIN_REFERENCE_DB + gain_db - result.gain_db;
+                actual_steps = result.suggested_gain_steps(target_db, 0.0).max(0);
+
+                // --album-clip-safe: only this track leaves the album level
+                let below_album = if opts.album_clip_safe {
+                    ", below the album gain,"
+                } else {
+                    ""
+                };
+                let msg = format!(
+                    "gain reduced from {} to {} steps{} to prevent clipping (peak: {:.4})",
+                    steps, actual_steps, below_album, result.peak
+                );
                 if opts.output_format == OutputFormat::Text && !opts.quiet {
                     eprintln!(
-                        "  {} {}{} - gain reduced from {} to {} steps to prevent clipping (peak: {:.4})",
+                        "  {} {}{} - {}",
                         "!".yellow(),
                         dry_run_prefix,
                         filename,
-                        steps,
-                        actual_steps,
-                        result.peak
+                        msg
                     );
                 }
-                warning_msg = Some(format!(
-                    "gain reduced from {} to {} steps to prevent clipping (peak: {:.4})",
-                    steps, actual_steps, result.peak
-                ));
-            } else if !opts.ignore_clipping && !opts.quiet {
-                if opts.output_format == OutputFormat::Text {
+                warnings.push(msg);
+            } else if !opts.ignore_clipping {
+                let new_peak = result.peak * 10.0_f64.powf(gain_db / 20.0);
+                if opts.output_format == OutputFormat::Text && !opts.quiet {
                     eprintln!(
                         "  {} {}{} - clipping warning: peak would be {:.2} (>{:.2})",
                         "!".yellow(),
@@ -2068,7 +8576,7 @@ fn process_apply_replaygain_with_album(
                     );
                     eprintln!("      Use -c to ignore clipping warnings or -k to prevent clipping");
                 }
-                warning_msg = Some(format!(
+                warnings.push(format!(
                     "clipping warning: peak would be {:.2} (>1.00)",
                     new_peak
                 ));
@@ -2076,13 +8584,51 @@ fn process_apply_replaygain_with_album(
         }
     }
 
+    // --tag-even-if-zero: a file already at the target still gets its tags
+    let tags_only = opts.tags_only
+        || (opts.tag_even_if_zero && actual_steps == 0)
+        || !result.file_type.is_some_and(|t| t.has_global_gain());
+    // -s s writes no tags, which leaves nothing to do where the gain only
+    // goes into tags
+    if tags_only && opts.stored_tag_mode == StoredTagMode::Skip {
+        let msg = "-s s: ReplayGain tags are not written";
+        if opts.output_format == OutputFormat::Text && !opts.quiet {
+            println!("  {} {} ({})", ".".cyan(), filename, msg);
+        }
+        warnings.push(msg);
+        return Ok(JsonFileResult {
+            file: get_path(file),
+            status: Some("skipped".to_string()),
+            loudness_db: Some(result.loudness_db),
+            peak: Some(result.peak),
+            gain_applied_steps: Some(0),
+            gain_applied_db: Some(0.0),
+            warnings,
+            dry_run: if opts.dry_run { Some(true) } else { None },
+            ..Default::default()
+        });
+    }
+    if opts.skip_unchanged {
+        let unchanged = if tags_only {
+            planned_tag_changes(file, actual_steps, result, opts, album_info).is_empty()
+        } else {
+            is_unchanged_by_gain(file, actual_steps, actual_steps, opts)
+        };
+        if unchanged {
+            let mut json = unchanged_result(file, opts);
+            json.loudness_db = Some(result.loudness_db);
+            json.peak = Some(result.peak);
+            json.warnings = warnings;
+            return Ok(json);
+        }
+    }
+
     // Dry run: don't actually modify
     if opts.dry_run {
+        let tag_changes = planned_tag_changes(file, actual_steps, result, opts, album_info);
+
         if opts.output_format == OutputFormat::Text && !opts.quiet {
-            let format_info = match result.file_type {
-                AudioFileType::Aac => " (tags only)",
-                AudioFileType::Mp3 => "",
-            };
+            let format_info = if tags_only { " (tags only)" } else { "" };
             println!(
                 "  {} [DRY RUN] {} (would apply {:+.1} dB, {} steps{})",
                 "~".cyan(),
@@ -2091,47 +8637,56 @@ fn process_apply_replaygain_with_album(
                 actual_steps,
                 format_info
             );
+            print_tag_changes(&tag_changes);
         }
         return Ok(JsonFileResult {
-            file: file.display().to_string(),
+            file: get_path(file),
             status: Some("dry_run".to_string()),
             loudness_db: Some(result.loudness_db),
             peak: Some(result.peak),
             gain_applied_steps: Some(actual_steps),
             gain_applied_db: Some(steps_to_db(actual_steps)),
-            warning: warning_msg,
+            warnings,
             dry_run: Some(true),
+            tag_changes: json_tag_changes(&tag_changes),
             ..Default::default()
         });
     }
 
-    // Handle AAC/M4A files differently - only write ReplayGain tags
-    if result.file_type == AudioFileType::Aac {
-        return process_apply_replaygain_aac_with_album(
+    // Handle AAC/M4A files (and MP3 with --tags-only) differently - only write ReplayGain tags
+    if tags_only {
+        return process_apply_replaygain_tags_with_album(
             file,
             actual_steps,
             result,
             opts,
-            warning_msg,
-            original_mtime,
+            warnings,
+            &preserved,
             album_info,
         );
     }
 
     // MP3: Apply gain to audio frames
-    let apply_result = if opts.wrap_gain {
-        apply_with_temp_file(file, |f| apply_gain_with_undo_wrap(f, actual_steps), opts)
-    } else {
-        apply_with_temp_file(file, |f| apply_gain_with_undo(f, actual_steps), opts)
-    };
+    let apply_result = apply_frame_gain(file, actual_steps, opts);
 
     match apply_result {
         Ok(frames) => {
-            // Restore timestamp if needed
-            if let Some(mtime) = original_mtime {
-                restore_timestamp(file, mtime);
+            // Album gain: tag the track and album gain left after the shift
+            if let Some(album) = album_info {
+                if opts.stored_tag_mode != StoredTagMode::Skip {
+                    if let Err(e) = write_album_mp3_tags(file, actual_steps, result, album, opts) {
+                        let msg = format!("failed to write ReplayGain tags: {}", e);
+                        if opts.output_format == OutputFormat::Text && !opts.quiet {
+                            eprintln!("  {} {} - {}", "!".yellow(), filename, msg);
+                        }
+                        warnings.push(msg);
+                    }
+                }
             }
 
+            // Restore timestamp (and mode/owner) if needed
+            preserved.restore(file);
+
             if opts.output_format == OutputFormat::Text && !opts.quiet {
                 println!(
                     "  {} {} ({} frames, {:+.1} dB)",
@@ -2143,60 +8698,225 @@ fn process_apply_replaygain_with_album(
             }
 
             Ok(JsonFileResult {
-                file: file.display().to_string(),
+                file: get_path(file),
                 status: Some("success".to_string()),
                 frames: Some(frames),
                 loudness_db: Some(result.loudness_db),
                 peak: Some(result.peak),
                 gain_applied_steps: Some(actual_steps),
                 gain_applied_db: Some(steps_to_db(actual_steps)),
-                warning: warning_msg,
+                warnings,
                 ..Default::default()
             })
         }
-        Err(e) => {
-            if opts.output_format == OutputFormat::Text && !opts.quiet {
-                eprintln!("  {} {} - {}", "x".red(), filename, e);
+        Err(e) => Ok(apply_error_result(file, e, opts)),
+    }
+}
+
+/// Move the gain tags an APEv2-writing library call stored to --tag-format
+///
+/// Passes the call's result through; the tags are left alone if it failed
+/// or the format is APEv2.
+fn store_gain_tags<T>(file: &Path, opts: &Options, written: Result<T>) -> Result<T> {
+    let value = written?;
+    if opts.tag_format != TagFormat::Ape {
+        convert_gain_tag(file, opts.tag_format)?;
+    }
+    Ok(value)
+}
+
+/// Gain metadata that -s d removes from a file
+struct PlannedDelete {
+    changes: Vec<TagChange>,
+    /// The APEv2 tag holds nothing else and is removed as a whole
+    drops_ape_tag: bool,
+}
+
+/// The APEv2 tag of `file` without its [`GAIN_TAG_KEYS`], if it has a tag
+fn ape_tag_without_gain_keys(file: &Path) -> Result<Option<(ApeTag, ApeTag)>> {
+    Ok(read_ape_tag_from_file(file)?.map(|tag| {
+        let mut kept = tag.clone();
+        for key in GAIN_TAG_KEYS {
+            kept.remove(key);
+        }
+        (tag, kept)
+    }))
+}
+
+/// What -s d would remove from `file`, without changing it
+///
+/// Only gain keys go: MP4 ReplayGain freeform tags for M4A, ReplayGain
+/// Vorbis comments for FLAC, otherwise the [`GAIN_TAG_KEYS`] of the formats
+/// chosen with --tag-format. Artist, title and other items stay; an APEv2 tag
+/// with nothing else in it is dropped.
+fn plan_delete_gain_tags(file: &Path, opts: &Options) -> Result<PlannedDelete> {
+    if detect_audio_type(file) == Some(AudioFileType::Aac) {
+        let existing = mp4meta::read_replaygain_tags(file)?;
+        return Ok(PlannedDelete {
+            changes: existing.diff(&mp4meta::ReplayGainTags::new()),
+            drops_ape_tag: false,
+        });
+    }
+    if detect_audio_type(file) == Some(AudioFileType::Flac) {
+        let existing = flac::read_replaygain_tag(file)?.unwrap_or_default();
+        return Ok(PlannedDelete {
+            changes: existing.diff(&ApeTag::new()),
+            drops_ape_tag: false,
+        });
+    }
+
+    let mut plan = PlannedDelete {
+        changes: Vec::new(),
+        drops_ape_tag: false,
+    };
+    if opts.tag_format != TagFormat::Id3v2 {
+        if let Some((tag, kept)) = ape_tag_without_gain_keys(file)? {
+            plan.changes.extend(tag.diff(&kept));
+            plan.drops_ape_tag = kept.is_empty();
+        }
+    }
+    if opts.tag_format != TagFormat::Ape {
+        let items: Vec<(String, String)> = id3v2::read_txxx_from_file(file)?
+            .into_iter()
+            .filter(|(key, _)| is_gain_key(key))
+            .collect();
+        plan.changes.extend(diff_tag_items(&items, &[]));
+    }
+    Ok(plan)
+}
+
+/// Remove what [`plan_delete_gain_tags`] lists
+fn delete_gain_tags(file: &Path, opts: &Options) -> Result<()> {
+    if detect_audio_type(file) == Some(AudioFileType::Aac) {
+        return mp4meta::delete_replaygain_tags(file);
+    }
+    if detect_audio_type(file) == Some(AudioFileType::Flac) {
+        return flac::delete_replaygain_tags(file);
+    }
+
+    if opts.tag_format != TagFormat::Id3v2 {
+        match ape_tag_without_gain_keys(file)? {
+            Some((_, kept)) if kept.is_empty() => delete_ape_tag(file)?,
+            Some((tag, kept)) if kept.items().len() != tag.items().len() => {
+                write_ape_tag(file, &kept)?
             }
+            _ => {}
+        }
+    }
+    if opts.tag_format != TagFormat::Ape {
+        id3v2::write_txxx(file, &GAIN_TAG_KEYS, &[])?;
+    }
+    Ok(())
+}
 
-            Ok(JsonFileResult {
-                file: file.display().to_string(),
-                status: Some("error".to_string()),
-                error: Some(e.to_string()),
-                ..Default::default()
+/// Tag changes that applying `steps` (or writing ReplayGain tags) would make
+fn planned_tag_changes(
+    file: &Path,
+    steps: i32,
+    result: &ReplayGainResult,
+    opts: &Options,
+    album_info: Option<&AacAlbumInfo>,
+) -> Vec<TagChange> {
+    let tagged = tag_peak_result(file, result, opts).unwrap_or_else(|_| result.clone());
+    let result = &tagged;
+    match result.file_type {
+        Some(AudioFileType::Aac) => {
+            let planned = planned_mp4_tags(result, album_info, opts);
+            mp4meta::read_replaygain_tags(file)
+                .map(|existing| existing.diff(&planned))
+                .unwrap_or_default()
+        }
+        Some(AudioFileType::AacAdts) => preview_ape_changes(file, |tag| {
+            Ok(planned_ape_tag(tag, result, album_info, opts))
+        }),
+        Some(AudioFileType::Flac) => flac::read_replaygain_tag(file)
+            .map(|existing| {
+                let existing = existing.unwrap_or_default();
+                existing.diff(&planned_ape_tag(existing.clone(), result, album_info, opts))
+            })
+            .unwrap_or_default(),
+        Some(AudioFileType::Mp3) if opts.rva2 => {
+            preview_rva2_changes(file, &planned_rva2_frames(result, album_info))
+        }
+        Some(AudioFileType::Mp3) if opts.tags_only => preview_ape_changes(file, |tag| {
+            Ok(planned_ape_tag(tag, result, album_info, opts))
+        }),
+        Some(AudioFileType::Mp3) if steps != 0 && opts.stored_tag_mode != StoredTagMode::Skip => {
+            preview_ape_changes(file, |_| {
+                let tag = preview_undo_tag(file, steps, opts.wrap_gain)?;
+                Ok(match album_info {
+                    Some(album) => {
+                        let (residual, album) = album_residual(result, album, steps);
+                        planned_ape_tag(tag, &residual, Some(&album), opts)
+                    }
+                    None => tag,
+                })
             })
         }
+        _ => Vec::new(),
     }
 }
 
-/// Apply ReplayGain to AAC/M4A files with optional album info
-fn process_apply_replaygain_aac_with_album(
+/// Write ReplayGain tags (AAC/M4A, FLAC, or MP3 with --tags-only) with optional album info
+fn process_apply_replaygain_tags_with_album(
     file: &Path,
     _actual_steps: i32,
     result: &ReplayGainResult,
     opts: &Options,
-    warning_msg: Option<String>,
-    original_mtime: Option<std::time::SystemTime>,
+    mut warnings: Warnings,
+    preserved: &PreservedMetadata,
     album_info: Option<&AacAlbumInfo>,
 ) -> Result<JsonFileResult> {
     let filename = get_filename(file);
 
-    // Create ReplayGain tags for AAC
-    let mut tags = mp4meta::ReplayGainTags::new();
-    tags.set_track(result.gain_db, result.peak);
+    // --true-peak-tags: peak tags get the true peak, the rest is unchanged
+    let write_result = tag_peak_result(file, result, opts).and_then(|tagged| {
+        let result = &tagged;
+        if result.file_type == Some(AudioFileType::Aac) {
+            mp4meta::write_replaygain_tags(file, &planned_mp4_tags(result, album_info, opts))
+        } else if result.file_type == Some(AudioFileType::Flac) {
+            flac::read_replaygain_tag(file).and_then(|tag| {
+                flac::write_replaygain_tag(
+                    file,
+                    &planned_ape_tag(tag.unwrap_or_default(), result, album_info, opts),
+                )
+            })
+        } else if result.file_type == Some(AudioFileType::Mp3) && opts.rva2 {
+            id3v2::write_rva2(file, &planned_rva2_frames(result, album_info))
+        } else {
+            if result.file_type == Some(AudioFileType::AacAdts) {
+                // Raw ADTS has no container for metadata; fall back to an APEv2 tag,
+                // which some players (but not all) read from .aac files
+                let note =
+                    "raw AAC (ADTS) has no standard ReplayGain tag container, writing APEv2 tag";
+                if opts.output_format == OutputFormat::Text && !opts.quiet {
+                    eprintln!("  {} {} - {}", "!".yellow(), filename, note);
+                }
+                warnings.push(note);
+            }
 
-    // Add album tags if available
-    if let Some(album) = album_info {
-        tags.set_album(album.album_gain_db, album.album_peak);
-    }
+            if result.file_type == Some(AudioFileType::Mp3) {
+                read_gain_tag(file).and_then(|tag| {
+                    let planned =
+                        planned_ape_tag(tag.unwrap_or_default(), result, album_info, opts);
+                    write_gain_tag(file, &planned, opts.tag_format)
+                })
+            } else {
+                read_ape_tag_from_file(file).and_then(|tag| {
+                    write_ape_tag(
+                        file,
+                        &planned_ape_tag(tag.unwrap_or_default(), result, album_info, opts),
+                    )
+                })
+            }
+        }
+    });
 
     // Write tags to file
-    match mp4meta::write_replaygain_tags(file, &tags) {
+    match write_result {
         Ok(()) => {
-            // Restore timestamp if needed
-            if let Some(mtime) = original_mtime {
-                restore_timestamp(file, mtime);
-            }
+            // Restore timestamp (and mode/owner) if needed
+            preserved.restore(file);
 
             let tag_type = if album_info.is_some() {
                 "track+album tags"
@@ -2215,23 +8935,26 @@ fn process_apply_replaygain_aac_with_album(
             }
 
             Ok(JsonFileResult {
-                file: file.display().to_string(),
+                file: get_path(file),
                 status: Some("success".to_string()),
                 loudness_db: Some(result.loudness_db),
                 peak: Some(result.peak),
                 gain_applied_steps: Some(result.gain_steps()),
                 gain_applied_db: Some(result.gain_db),
-                warning: warning_msg,
+                warnings,
                 ..Default::default()
             })
         }
         Err(e) => {
-            if opts.output_format == OutputFormat::Text && !opts.quiet {
+            if file_error(
+                opts,
+                opts.output_format == OutputFormat::Text && !opts.quiet,
+            ) {
                 eprintln!("  {} {} - {}", "x".red(), filename, e);
             }
 
             Ok(JsonFileResult {
-                file: file.display().to_string(),
+                file: get_path(file),
                 status: Some("error".to_string()),
                 error: Some(e.to_string()),
                 ..Default::default()
@@ -2240,6 +8963,269 @@ fn process_apply_replaygain_aac_with_album(
     }
 }
 
+/// `result` with the peak to write into ReplayGain tags: the true peak with
+/// --true-peak-tags (ReplayGain 2.0), otherwise the sample peak it has
+fn tag_peak_result(
+    file: &Path,
+    result: &ReplayGainResult,
+    opts: &Options,
+) -> Result<ReplayGainResult> {
+    let mut result = result.clone();
+    if opts.true_peak_tags {
+        result.peak = replaygain::find_true_peak(file, opts.track_index)?;
+    }
+    Ok(result)
+}
+
+/// ReplayGain tags to write into an MP4/M4A file
+///
+/// Gains are for the -d target, which is recorded as the reference loudness.
+fn planned_mp4_tags(
+    result: &ReplayGainResult,
+    album_info: Option<&AacAlbumInfo>,
+    opts: &Options,
+) -> mp4meta::ReplayGainTags {
+    let target_db = replaygain_target_db(opts);
+    let mut tags = mp4meta::ReplayGainTags::new();
+    tags.set_track(result.gain_for_target(target_db), result.peak);
+    tags.set_reference_loudness(target_db);
+
+    // Add album tags if available
+    if let Some(album) = album_info {
+        tags.set_album(
+            album.album_gain_db + opts.gain_modifier_db,
+            album.album_peak,
+        );
+    }
+
+    tags
+}
+
+/// APEv2 ReplayGain tag to write (raw ADTS, or MP3 with --tags-only), based on the existing tag
+///
+/// Like [`planned_mp4_tags`], gains are for the -d target.
+fn planned_ape_tag(
+    mut tag: ApeTag,
+    result: &ReplayGainResult,
+    album_info: Option<&AacAlbumInfo>,
+    opts: &Options,
+) -> ApeTag {
+    let target_db = replaygain_target_db(opts);
+    tag.set_replaygain_track(result.gain_for_target(target_db), result.peak);
+    tag.set_reference_loudness(target_db);
+    if let Some(album) = album_info {
+        tag.set_replaygain_album(
+            album.album_gain_db + opts.gain_modifier_db,
+            album.album_peak,
+        );
+    }
+    tag
+}
+
+/// Track result and album info as they stand after album gain shifted an
+/// MP3's frames by `steps`: the gains still left to apply, and the peaks of
+/// the shifted audio
+fn album_residual(
+    result: &ReplayGainResult,
+    album: &AacAlbumInfo,
+    steps: i32,
+) -> (ReplayGainResult, AacAlbumInfo) {
+    let applied_db = steps_to_db(steps);
+    let scale = 10.0_f64.powf(applied_db / 20.0);
+    let mut residual = result.clone();
+    residual.gain_db -= applied_db;
+    residual.peak *= scale;
+    let album = AacAlbumInfo {
+        album_gain_db: album.album_gain_db - applied_db,
+        album_peak: album.album_peak * scale,
+    };
+    (residual, album)
+}
+
+/// After album gain shifted an MP3's frames by `steps`, write its ReplayGain
+/// track and album tags from [`album_residual`], next to the undo information
+///
+/// Players can then switch between track and album mode without applying
+/// the shift a second time.
+fn write_album_mp3_tags(
+    file: &Path,
+    steps: i32,
+    result: &ReplayGainResult,
+    album: &AacAlbumInfo,
+    opts: &Options,
+) -> Result<()> {
+    let (residual, album) = album_residual(result, album, steps);
+    // A true peak is measured on the shifted audio
+    let residual = tag_peak_result(file, &residual, opts)?;
+    let tag = read_gain_tag(file)?.unwrap_or_default();
+    write_gain_tag(
+        file,
+        &planned_ape_tag(tag, &residual, Some(&album), opts),
+        opts.tag_format,
+    )
+}
+
+/// RVA2 frames to write into an MP3 with --rva2
+fn planned_rva2_frames(
+    result: &ReplayGainResult,
+    album_info: Option<&AacAlbumInfo>,
+) -> Vec<id3v2::Rva2Frame> {
+    let mut frames = vec![id3v2::Rva2Frame::master_only(
+        "track",
+        result.gain_db,
+        Some(result.peak),
+    )];
+    if let Some(album) = album_info {
+        frames.push(id3v2::Rva2Frame::master_only(
+            "album",
+            album.album_gain_db,
+            Some(album.album_peak),
+        ));
+    }
+    frames
+}
+
+/// Diff the file's RVA2 master adjustments against those after writing `frames`
+fn preview_rva2_changes(file: &Path, frames: &[id3v2::Rva2Frame]) -> Vec<TagChange> {
+    let items = |frames: Vec<id3v2::Rva2Frame>| -> Vec<(String, String)> {
+        frames
+            .iter()
+            .filter_map(|f| {
+                let master = f.master()?;
+                let mut value = format!("{:+.2} dB", master.adjustment_db);
+                if let Some(peak) = master.peak {
+                    value.push_str(&format!(", peak {:.6}", peak));
+                }
+                Some((format!("RVA2 ({})", f.identification), value))
+            })
+            .collect()
+    };
+
+    let Ok(data) = fs::read(file) else {
+        return Vec::new();
+    };
+    match id3v2::with_rva2(&data, frames) {
+        Ok(new_data) => diff_tag_items(
+            &items(id3v2::read_rva2(&data)),
+            &items(id3v2::read_rva2(&new_data)),
+        ),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Diff the file's current APEv2 tag against the tag an operation would write
+///
+/// Errors while reading or planning are treated as "no preview available".
+fn preview_ape_changes(file: &Path, plan: impl FnOnce(ApeTag) -> Result<ApeTag>) -> Vec<TagChange> {
+    let existing = read_gain_tag(file).ok().flatten().unwrap_or_default();
+    match plan(existing.clone()) {
+        Ok(planned) => existing.diff(&planned),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Print dry-run tag changes in a unified-diff style (+ added, - removed)
+fn print_tag_changes(changes: &[TagChange]) {
+    for change in changes {
+        match change {
+            TagChange::Added { key, value } => {
+                println!("      {}", format!("+ {}={}", key, value).green());
+            }
+            TagChange::Changed { key, old, new } => {
+                println!("      {}", format!("- {}={}", key, old).red());
+                println!("      {}", format!("+ {}={}", key, new).green());
+            }
+            TagChange::Removed { key, value } => {
+                println!("      {}", format!("- {}={}", key, value).red());
+            }
+        }
+    }
+}
+
+fn json_tag_changes(changes: &[TagChange]) -> Option<Vec<JsonTagChange>> {
+    if changes.is_empty() {
+        return None;
+    }
+
+    Some(
+        changes
+            .iter()
+            .map(|change| match change {
+                TagChange::Added { key, value } => JsonTagChange {
+                    action: "add",
+                    key: key.clone(),
+                    old: None,
+                    new: Some(value.clone()),
+                },
+                TagChange::Changed { key, old, new } => JsonTagChange {
+                    action: "change",
+                    key: key.clone(),
+                    old: Some(old.clone()),
+                    new: Some(new.clone()),
+                },
+                TagChange::Removed { key, value } => JsonTagChange {
+                    action: "remove",
+                    key: key.clone(),
+                    old: Some(value.clone()),
+                    new: None,
+                },
+            })
+            .collect(),
+    )
+}
+
+/// File attributes captured before a write and put back afterwards
+///
+/// `-p` keeps the modification time; `--preserve-all` also keeps the
+/// permission bits and, on Unix, the owner and group. Writing through a temp
+/// file (`-t`) replaces the file, which would otherwise leave it owned by
+/// whoever ran mp3rgain. Restoring is best effort, like the timestamp.
+#[derive(Debug, Default)]
+struct PreservedMetadata {
+    mtime: Option<SystemTime>,
+    permissions: Option<fs::Permissions>,
+    #[cfg(unix)]
+    owner: Option<(u32, u32)>,
+}
+
+impl PreservedMetadata {
+    fn capture(file: &Path, opts: &Options) -> Self {
+        if opts.dry_run || !(opts.preserve_timestamp || opts.preserve_all) {
+            return Self::default();
+        }
+        let Ok(meta) = fs::metadata(file) else {
+            return Self::default();
+        };
+
+        Self {
+            mtime: opts
+                .preserve_timestamp
+                .then(|| meta.modified().ok())
+                .flatten(),
+            permissions: opts.preserve_all.then(|| meta.permissions()),
+            #[cfg(unix)]
+            owner: opts.preserve_all.then(|| {
+                use std::os::unix::fs::MetadataExt;
+                (meta.uid(), meta.gid())
+            }),
+        }
+    }
+
+    fn restore(&self, file: &Path) {
+        // Owner first: changing it may clear setuid/setgid bits
+        #[cfg(unix)]
+        if let Some((uid, gid)) = self.owner {
+            let _ = std::os::unix::fs::chown(file, Some(uid), Some(gid));
+        }
+        if let Some(permissions) = &self.permissions {
+            let _ = fs::set_permissions(file, permissions.clone());
+        }
+        if let Some(mtime) = self.mtime {
+            restore_timestamp(file, mtime);
+        }
+    }
+}
+
 fn restore_timestamp(file: &Path, mtime: SystemTime) {
     let _ = std::fs::File::options()
         .write(true)
@@ -2251,11 +9237,168 @@ fn restore_timestamp(file: &Path, mtime: SystemTime) {
 // Help / Version
 // =============================================================================
 
-fn print_version() {
+fn print_version(verbose: bool) {
     println!("mp3rgain version {}", VERSION);
     println!("A modern mp3gain replacement written in Rust");
     println!();
     println!("Each gain step = {} dB", GAIN_STEP_DB);
+
+    if verbose {
+        let caps = mp3rgain::capabilities();
+        let yes_no = |enabled: bool| if enabled { "yes" } else { "no" };
+        let rates: Vec<String> = caps.sample_rates.iter().map(|r| r.to_string()).collect();
+
+        println!();
+        println!("Capabilities:");
+        println!("  ReplayGain analysis: {}", yes_no(caps.replaygain));
+        println!("  AAC/M4A decoding:    {}", yes_no(caps.aac));
+        println!("  FLAC decoding:       {}", yes_no(caps.flac));
+        println!("  Memory-mapped I/O:   {}", yes_no(caps.mmap));
+        println!("  Parallel processing: {}", yes_no(caps.parallel));
+        println!("  ID3v2 gain tags:     {}", yes_no(caps.id3v2));
+        println!("  MPEG versions:       {}", caps.mpeg_versions.join(", "));
+        if rates.is_empty() {
+            println!("  Sample rates:        (none, ReplayGain disabled)");
+        } else {
+            println!("  Sample rates:        {}", rates.join(", "));
+        }
+    }
+}
+
+/// Print version and build capabilities as JSON (for CI and packagers)
+fn print_version_json() -> Result<()> {
+    let caps = mp3rgain::capabilities();
+    let output = JsonVersion {
+        version: VERSION,
+        features: JsonFeatures {
+            replaygain: caps.replaygain,
+            aac: caps.aac,
+            flac: caps.flac,
+            mmap: caps.mmap,
+            parallel: caps.parallel,
+            id3v2: caps.id3v2,
+        },
+        step_db: GAIN_STEP_DB,
+        mpeg_versions: caps.mpeg_versions,
+        supported_sample_rates: caps.sample_rates,
+    };
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
+
+/// What `-g steps` does, in words
+fn explain_fixed_gain(steps: i32) -> String {
+    let db = global_gain_shift_db(steps);
+    let change = match steps {
+        0 => return "-g 0 leaves the audio unchanged.".to_string(),
+        1.. => format!("raised by {}", steps),
+        _ => format!("lowered by {}", -steps),
+    };
+    format!(
+        "-g {} applies exactly {:+.1} dB ({} x {} dB): every frame's global_gain is {}.\n\
+         This is a uniform scalefactor shift of the whole file, not loudness normalization: \
+         loud and quiet passages change alike, and files that differ in loudness still differ \
+         by as much afterwards. Use -r (track) or -a (album) to bring files to a common loudness.",
+        steps, db, steps, GAIN_STEP_DB, change
+    )
+}
+
+/// What `-d db` does, in words
+fn explain_gain_modifier(db: f64) -> String {
+    format!(
+        "-d {:+.1} moves the ReplayGain target from {} dB to {} dB for -r and -a.\n\
+         Each file's gain is its analyzed gain {:+.1} dB, rounded to whole {} dB steps, \
+         so the dB applied depends on the file; -d on its own applies nothing. \
+         Use -g for a fixed change in dB.",
+        db,
+        REPLAYGAIN_REFERENCE_DB,
+        REPLAYGAIN_REFERENCE_DB + db,
+        db,
+        GAIN_STEP_DB
+    )
+}
+
+/// --explain: describe what -g and -d would do, without touching any files
+fn cmd_explain(opts: &Options) -> Result<()> {
+    let mut explanations = Vec::new();
+    if let Some(steps) = opts.gain_steps {
+        explanations.push(JsonExplanation {
+            option: format!("-g {}", steps),
+            applied_db: Some(global_gain_shift_db(steps)),
+            target_db: None,
+            explanation: explain_fixed_gain(steps),
+        });
+    }
+    if opts.gain_modifier_db != 0.0 {
+        explanations.push(JsonExplanation {
+            option: format!("-d {}", opts.gain_modifier_db),
+            applied_db: None,
+            target_db: Some(REPLAYGAIN_REFERENCE_DB + opts.gain_modifier_db),
+            explanation: explain_gain_modifier(opts.gain_modifier_db),
+        });
+    }
+    if explanations.is_empty() {
+        eprintln!("{}: --explain requires -g or -d", "error".red().bold());
+        std::process::exit(1);
+    }
+
+    if opts.output_format == OutputFormat::Json {
+        return print_json(&explanations, opts);
+    }
+    for (i, explanation) in explanations.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("{}", explanation.explanation);
+    }
+    Ok(())
+}
+
+/// --list-formats: the operations supported for each audio format
+fn cmd_list_formats(opts: &Options) -> Result<()> {
+    let formats = mp3rgain::format_support();
+    let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+
+    if opts.output_format == OutputFormat::Json {
+        let output = JsonFormatList {
+            formats: formats
+                .iter()
+                .map(|format| JsonFormat {
+                    format: format.name,
+                    extensions: format.extensions,
+                    frame_gain: format.frame_gain,
+                    tag_only: !format.frame_gain,
+                    tag_gain: format.tag_gain,
+                    replaygain_analysis: format.replaygain_analysis,
+                    undo: format.undo,
+                    channel_gain: format.channel_gain,
+                })
+                .collect(),
+        };
+        return print_json(&output, opts);
+    }
+
+    println!(
+        "{:<12}{:<12}{:<12}{:<24}{:<10}{:<6}CHANNEL GAIN",
+        "FORMAT", "EXTENSIONS", "FRAME GAIN", "TAG GAIN", "ANALYSIS", "UNDO"
+    );
+    for format in &formats {
+        println!(
+            "{:<12}{:<12}{:<12}{:<24}{:<10}{:<6}{}",
+            format.name,
+            format.extensions.join(", "),
+            if format.frame_gain { "yes" } else { "tag-only" },
+            format.tag_gain,
+            yes_no(format.replaygain_analysis),
+            yes_no(format.undo),
+            yes_no(format.channel_gain)
+        );
+    }
+    if !replaygain::is_available() {
+        println!();
+        println!("ReplayGain analysis requires the 'replaygain' feature");
+    }
+    Ok(())
 }
 
 fn print_usage() {
@@ -2264,40 +9407,157 @@ fn print_usage() {
     println!();
     println!("{}", "USAGE:".cyan().bold());
     println!("    mp3rgain [OPTIONS] <FILES>...");
+    println!("    mp3rgain <COMMAND> [OPTIONS] <FILES>...");
+    println!("    mp3rgain [OPTIONS] -- <FILES>...  (names after -- are always files)");
+    println!();
+    println!("{}", "COMMANDS:".cyan().bold());
+    println!("    Optional; each refuses options that pick a different action");
+    println!("    apply       Change gain (needs -g, -l, -r, -a, --match or the like)");
+    println!("    analyze     Show gain information only (the default; also -x, -s c)");
+    println!("    undo        Same as -u");
+    println!("    redo        Same as --redo");
+    println!("    measure     Same as --measure");
+    println!("    strip       Same as -s d");
+    println!("    help        Show this help");
     println!();
     println!("{}", "OPTIONS:".cyan().bold());
     println!(
         "    -g <i>      Apply gain of i steps (each step = {} dB)",
         GAIN_STEP_DB
     );
-    println!("    -d <n>      Apply gain of n dB (rounded to nearest step)");
+    println!("    -d <n>      Move the target level n dB from 89 dB (analysis, -r, -a)");
     println!("    -l <c> <g>  Apply gain to left (0) or right (1) channel only");
+    println!("                (repeat for both channels: -l 0 3 -l 1 -2; on joint stereo");
+    println!("                files mid/side coding mixes the channels)");
     println!("    -m <i>      Modify suggested gain by integer i");
+    println!("    --avg-target <n>  Shift average global_gain to n (0-255, no decoding)");
+    println!("    --peak-normalize-heuristic <dbfs>  Bring the peak estimated from global_gain");
+    println!("                  to dbfs (approximate, no decoding)");
+    println!("    --apply-rva2  Apply the ID3v2 RVA2 track adjustment to global_gain");
+    println!("    --ramp <startf>:<endf>:<s1>:<s2>  Fade the gain from s1 to s2 steps across");
+    println!("                frames startf to endf (not recorded for undo)");
+    println!("    --apply-replaygain-tag  Apply the stored REPLAYGAIN_TRACK_GAIN to global_gain");
     println!("    -r          Apply Track gain (ReplayGain analysis)");
     println!("    -a          Apply Album gain (ReplayGain analysis)");
+    println!("    --group <files>  With -a, files up to the next --group form one album;");
+    println!("                files before the first --group get track gain");
+    println!("    --album-depth <n>  With -a -R, one album per directory n levels below");
+    println!("                each given directory (1 merges Album/Disc 1, Album/Disc 2)");
+    println!("    --album-gain-db <n>  Write album gain n dB into the tags of each file");
+    println!("                without analysis (audio is not changed)");
+    println!("    --album-peak <p>  Album peak to write with --album-gain-db");
     println!("    -e          Skip album analysis (even with multiple files)");
+    println!("    --tags-only With -r/-a, write ReplayGain tags without modifying MP3 audio");
+    println!("    --rva2      Like --tags-only, but write ID3v2 RVA2 frames to MP3 files");
+    println!("    --true-peak-tags  Write true peaks (4x oversampled) into peak tags");
+    println!("    --tag-even-if-zero  With -r/-a, write ReplayGain tags for files needing no gain");
+    println!("    --match <m> Bring every file to the loudness of the quietest, loudest or");
+    println!("                median one (relative levels are not kept, unlike -a)");
+    println!("    --trim-silence  Leave windows below -60 dBFS (silent intros/outros) out");
+    println!("                of the loudness measured by -r/-a");
+    println!("    --skip-intro <s>  Leave the first s seconds (e.g. a loud jingle) out of");
+    println!("                the loudness measured by -r/-a; the peak still covers them");
+    println!("    --skip-outro <s>  Same for the last s seconds");
+    println!("    --downmix <m>  Measure the loudness for -r/-a as mono (channels averaged),");
+    println!("                stereo or none (as decoded, default)");
+    println!("    --as-one    With -r, analyze all files as one continuous stream (as if");
+    println!("                joined end to end) and apply that one gain to each file");
+    println!("    --best-effort  Measure damaged files from the audio that still decodes");
+    println!("                instead of failing (results are marked partial)");
+    println!("    --show-continuous  With -r/-a, also show the exact dB gain and the error");
+    println!("                of rounding it to 1.5 dB steps");
     println!("    -i <n>      Specify which audio track to process (default: 0)");
-    println!("    -u          Undo gain changes (restore from APEv2 tag)");
+    println!("    -u          Undo gain changes (restore from APEv2 or ID3v2 tag)");
+    println!("    --last      With -u, undo only the most recent adjustment");
+    println!("    --keep-redo  With -u, keep the undone gain as MP3GAIN_REDO for --redo");
+    println!("    --redo      Apply again the gain the last -u --keep-redo took back");
+    println!("    --write-minmax  Only record MP3GAIN_MINMAX (undo baseline), leave audio alone");
+    println!("    --normalize-tags  Rewrite MP3GAIN_UNDO/REDO from other taggers as +002,+002,N");
+    println!("                and MP3GAIN_MINMAX as min,max (alias --canonicalize-tags)");
     println!("    -x          Only find max amplitude of file");
+    println!("    --measure   Only print loudness, peak and sample rate (no gain or target)");
+    println!("                  ('-' reads standard input, buffered in memory)");
+    println!("    --decode    With -x, fail instead of estimating if audio can't be decoded");
+    println!("    --dump-frames  Print per-frame global_gain, current and with -g (TSV)");
+    println!("    --list-frames-json  Print the header fields and global_gain of every frame");
+    println!("                of one file as JSON; --limit <n> lists only the first n");
+    println!("    --gain-histogram  Count each file's global_gain values (text bars, TSV, JSON)");
+    println!("    --compare-tools  Frame-based peak/loudness estimate vs decoded analysis");
+    println!("    --check-clipping  List files whose track gain (-d, -m) would clip; writes");
+    println!("                nothing, exit status 2 if any would (for CI)");
+    println!("    --rg-debug  Print the filter rate, RMS window size and count, and the");
+    println!("                95th percentile bucket behind each file's loudness, and the");
+    println!("                algorithm, constants and version it was measured with");
+    println!("    --doctor    Run diagnostic checks on files (for bug reports; -o json)");
     println!("    -s <mode>   Stored tag handling:");
     println!("                  c = check/show stored tag info");
-    println!("                  d = delete stored tag info");
-    println!("                  s = skip (ignore) stored tag info");
-    println!("                  r = force recalculation");
-    println!("                  i = use ID3v2 tags (not fully supported)");
+    println!("                  d = delete stored tag info (gain keys only; -n lists them)");
+    println!("                  s = skip (ignore) stored tags and cached analyses,");
+    println!("                      write no tags (--no-undo-tag)");
+    println!("                  r = force recalculation, refreshing cached analyses");
+    println!("                  i = use ID3v2 tags (--tag-format id3v2)");
     println!("                  a = use APEv2 tags (default)");
+    println!("    --tag-format <f>  Where MP3 gain/undo/ReplayGain tags go: ape (default),");
+    println!("                  id3v2 (TXXX frames) or both");
     println!("    -p          Preserve original file timestamp");
+    println!("    --preserve-all  Like -p, and also keep file mode and owner");
     println!("    -c          Ignore clipping warnings");
     println!("    -k          Prevent clipping (automatically limit gain)");
+    println!("    --album-clip-safe  With -a, lower only the tracks the album gain would clip");
+    println!("    --album-clip-check  With -a, lower the album gain until no track clips");
     println!("    -w          Wrap gain values (instead of clamping)");
+    println!("    --min-floor <n>  Stop negative gain at global_gain n instead of 0");
+    println!(
+        "    --max-steps <n>  Refuse -g/-d/-l/-m beyond +/-n steps (default: {})",
+        DEFAULT_MAX_STEPS
+    );
+    println!("    --force     Apply gains beyond --max-steps");
+    println!("    --ceiling-from <file>  Cap each file's gain at the steps listed for it");
+    println!("                  (lines of \"<steps> <file>\", relative to <file>)");
     println!("    -t          Use temp file for writing (safer, required for some ops)");
+    println!("    --temp-dir <dir>  Put -t's temp files in dir (implies -t); by default they");
+    println!("                go next to each file, or to the system temp dir if that fails");
     println!("    -f          Assume MPEG 2 Layer III (compatibility, no effect)");
     println!("    -q          Quiet mode (less output)");
+    println!("    --quiet-errors  Don't print per-file errors; report their count at the");
+    println!("                  end and exit with status 1 if there were any");
+    println!("    --progress <m>  Progress bar: auto (default), always or never (-q: never)");
+    println!("    --progress-threshold <n>  Files needed for an auto progress bar (default: 5)");
+    println!("    --retry <n>  Retry a busy or locked file up to n (max 20) times with backoff");
+    println!("    --skip-locked  Skip files still locked after retries; count them separately");
     println!("    -R          Process directories recursively");
+    println!("    --follow-symlinks  With -R, follow symlinked directories (skipped by default)");
+    println!("    --since <time>  With -R, only process files modified after <time>");
+    println!("                  (e.g. 2024-05-01T12:00:00Z, 2024-05-01 or 7d)");
+    println!("    --max-file-size <size>  Skip files larger than <size> (bytes, or e.g. 500MB)");
+    println!("    --sort <o>  Process files by name, path, mtime (oldest first) or none (as");
+    println!("                given); default path with -R or -a, otherwise as given");
+    println!("    --natural-sort  Compare numbers in names by value (track2 before track10);");
+    println!("                always on with -a");
+    println!("    --in-place-truncate  With -t, copy result back to preserve hardlinks");
+    println!("    --verify    Re-parse each written file and fail if any frame was damaged");
     println!("    -n          Dry-run mode (show what would be done)");
+    println!("    --estimate  Time a few files and project the batch's total run time");
     println!("    --dry-run   Same as -n");
-    println!("    -o <fmt>    Output format: 'text' (default), 'json', or 'tsv'");
+    println!("    --cache <dir>  Reuse -r/-a analyses of unchanged files from <dir>");
+    println!("    --stats     Print frames, bytes, time and throughput per file and total");
+    println!("    --skip-unchanged  Don't write files the operation would not alter");
+    println!("    --sidecar   Write <file>.rgain.json with each file's result (not with -n)");
+    println!("    --journal <f>  Append one NDJSON audit record per file to f: operation,");
+    println!("                result, size and SHA-256 before and after (not with -n)");
+    println!("    --hash      Print each file's SHA-256 before and after (-n: before only)");
+    println!("    -o <fmt>    Output format: 'text' (default), 'json', 'json-compact', or 'tsv'");
+    println!("    --json-compact  Same as -o json-compact: one minified JSON document per line");
+    println!("    --path-style <s>  Name files as 'full', 'relative' or 'basename' in all output");
+    println!("                  (default: full for JSON, basename for text and TSV)");
+    println!("    --legacy-output  Print analysis in the original mp3gain text layout");
     println!("    -v          Show version");
+    println!("    -v --verbose  Show version and build capabilities");
+    println!("    --version-json  Show version and build capabilities as JSON");
+    println!("    --list-formats  Show the operations supported for each audio format");
+    println!("    --explain   With -g or -d, explain the exact dB they apply instead of");
+    println!("                applying them (-g is a uniform shift, not normalization)");
+    println!("                (text, or JSON with -o json)");
     println!("    -h          Show this help");
     println!();
     println!("{}", "EXAMPLES:".cyan().bold());
@@ -2307,9 +9567,13 @@ fn print_usage() {
     println!("    mp3rgain -d 4.5 song.mp3       Apply +4.5 dB (rounds to +3 steps)");
     println!("    mp3rgain -r song.mp3           Analyze and apply track gain");
     println!("    mp3rgain -a *.mp3              Analyze and apply album gain");
+    println!("    mp3rgain -a set.m3u8           Album gain for the tracks of a playlist");
+    println!("    mp3rgain -a --group d1/*.mp3 --group d2/*.mp3  Album gain per disc");
     println!("    mp3rgain -r -m 2 *.mp3         Apply track gain + 2 steps");
     println!("    mp3rgain -e *.mp3              Track gain only (skip album calc)");
+    println!("    mp3rgain --match quietest *.mp3  Level all files to the quietest one");
     println!("    mp3rgain -u song.mp3           Undo previous gain changes");
+    println!("    mp3rgain apply -r *.mp3        Same as -r, with the action named");
     println!("    mp3rgain -x song.mp3           Show max amplitude only");
     println!("    mp3rgain -s c *.mp3            Check stored tag info");
     println!("    mp3rgain -s d *.mp3            Delete stored tag info");
@@ -2323,6 +9587,9 @@ fn print_usage() {
     println!("    mp3rgain -o tsv *.mp3          Output in tab-separated format");
     println!("    mp3rgain -l 0 3 song.mp3       Apply +3 steps to left channel");
     println!("    mp3rgain -l 1 -2 song.mp3      Apply -2 steps to right channel");
+    println!("    mp3rgain -l 0 1 -l 1 -1 song.mp3  Shift balance toward the left");
+    println!("    mp3rgain --avg-target 210 *.mp3  Roughly level files by average gain");
+    println!("    mp3rgain --apply-rva2 *.mp3    Make RVA2 volume adjustments lossless");
     println!();
     println!("{}", "NOTES:".cyan().bold());
     println!(
@@ -2332,6 +9599,11 @@ fn print_usage() {
     println!("    - Changes are lossless and reversible");
     println!("    - Gain changes are stored in APEv2 tags for undo support");
     println!("    - Progress bar shown automatically for 5+ files");
+    println!("    - Mutually exclusive: -r/-a, -g with -d/-r/-a/-l, -u or --redo with any");
+    println!("      gain option (-g/-d/-m/-l/-r/-a/--match) or each other, --match with");
+    println!("      -r/-a/-g, and -w/-k");
+    println!("    - Files are rewritten in place, keeping hardlinks; -t replaces the file");
+    println!("      (detaching hardlinks) unless --in-place-truncate is given");
     if replaygain::is_available() {
         println!(
             "    - ReplayGain analysis is {} (target: {} dB)",
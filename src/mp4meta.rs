@@ -16,17 +16,37 @@
 //!               └── ---- (freeform tags for ReplayGain)
 //! mdat (media data)
 //! ```
+//!
+//! A `free` or `skip` box right after ilst or after moov is used as padding:
+//! it takes up tag growth so the media data doesn't move (see
+//! [`mp4_edit_cost`]).
+//!
+//! Fragmented MP4 (moov with `mvex`, samples in `moof` fragments) is
+//! detected and refused for writing: fragments locate their samples with
+//! offsets this module does not rewrite.
 
 use anyhow::{Context, Result};
 use std::fs;
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
-/// ReplayGain tag keys (iTunes freeform format)
+use crate::tag_keys;
+use crate::write_error;
+
+/// ReplayGain tag keys (iTunes freeform format): the names in
+/// [`tag_keys::REPLAYGAIN_KEYS`], lowercase as other taggers write them
 pub const RG_TRACK_GAIN: &str = "replaygain_track_gain";
 pub const RG_TRACK_PEAK: &str = "replaygain_track_peak";
 pub const RG_ALBUM_GAIN: &str = "replaygain_album_gain";
 pub const RG_ALBUM_PEAK: &str = "replaygain_album_peak";
+pub const RG_REFERENCE_LOUDNESS: &str = "replaygain_reference_loudness";
+
+/// Undo information aacgain stores in freeform tags: the names of
+/// [`tag_keys::MP3GAIN_UNDO`], [`tag_keys::MP3GAIN_MINMAX`] and
+/// [`tag_keys::MP3GAIN_ALBUM_MINMAX`], lowercase, with the same values
+pub const AACGAIN_UNDO: &str = "mp3gain_undo";
+pub const AACGAIN_MINMAX: &str = "mp3gain_minmax";
+pub const AACGAIN_ALBUM_MINMAX: &str = "mp3gain_album_minmax";
 
 /// iTunes namespace for freeform tags
 const ITUNES_NAMESPACE: &str = "com.apple.iTunes";
@@ -38,9 +58,11 @@ const MOOV: u32 = u32::from_be_bytes(*b"moov");
 const UDTA: u32 = u32::from_be_bytes(*b"udta");
 const META: u32 = u32::from_be_bytes(*b"meta");
 const ILST: u32 = u32::from_be_bytes(*b"ilst");
-#[allow(dead_code)]
 const FREE: u32 = u32::from_be_bytes(*b"free");
-const MDAT: u32 = u32::from_be_bytes(*b"mdat");
+const MOOF: u32 = u32::from_be_bytes(*b"moof");
+const MFRA: u32 = u32::from_be_bytes(*b"mfra");
+const MVEX: u32 = u32::from_be_bytes(*b"mvex");
+const SKIP: u32 = u32::from_be_bytes(*b"skip");
 #[allow(dead_code)]
 const HDLR: u32 = u32::from_be_bytes(*b"hdlr");
 const FREEFORM: u32 = u32::from_be_bytes(*b"----");
@@ -110,12 +132,22 @@ pub struct FreeformTag {
 }
 
 /// Collection of ReplayGain tags
+///
+/// The aacgain fields are only read: writing or deleting ReplayGain tags
+/// leaves aacgain's freeform tags as they are.
 #[derive(Debug, Clone, Default)]
 pub struct ReplayGainTags {
     pub track_gain: Option<String>,
     pub track_peak: Option<String>,
     pub album_gain: Option<String>,
     pub album_peak: Option<String>,
+    pub reference_loudness: Option<String>,
+    /// aacgain's undo information ([`AACGAIN_UNDO`])
+    pub mp3gain_undo: Option<String>,
+    /// aacgain's original global_gain range ([`AACGAIN_MINMAX`])
+    pub mp3gain_minmax: Option<String>,
+    /// aacgain's original album global_gain range ([`AACGAIN_ALBUM_MINMAX`])
+    pub mp3gain_album_minmax: Option<String>,
 }
 
 impl ReplayGainTags {
@@ -126,6 +158,26 @@ impl ReplayGainTags {
     pub fn set_track(&mut self, gain_db: f64, peak: f64) {
         self.track_gain = Some(format!("{:+.2} dB", gain_db));
         self.track_peak = Some(format!("{:.6}", peak));
+        self.set_reference_loudness(crate::replaygain::REPLAYGAIN_REFERENCE_DB);
+    }
+
+    /// Set the loudness the gains bring the track to (e.g. "89.0 dB")
+    ///
+    /// Players assume the 89 dB reference without it, so gains computed for
+    /// another target must come with this tag.
+    pub fn set_reference_loudness(&mut self, reference_db: f64) {
+        self.reference_loudness = Some(format!("{:.1} dB", reference_db));
+    }
+
+    /// The reference loudness in dB, if the tag is present and parses
+    pub fn reference_loudness_db(&self) -> Option<f64> {
+        self.reference_loudness
+            .as_deref()?
+            .trim()
+            .trim_end_matches("dB")
+            .trim()
+            .parse()
+            .ok()
     }
 
     pub fn set_album(&mut self, gain_db: f64, peak: f64) {
@@ -138,6 +190,52 @@ impl ReplayGainTags {
             && self.track_peak.is_none()
             && self.album_gain.is_none()
             && self.album_peak.is_none()
+            && self.reference_loudness.is_none()
+    }
+
+    /// Tag key/value pairs that are set
+    fn items(&self) -> Vec<(String, String)> {
+        [
+            (RG_TRACK_GAIN, &self.track_gain),
+            (RG_TRACK_PEAK, &self.track_peak),
+            (RG_ALBUM_GAIN, &self.album_gain),
+            (RG_ALBUM_PEAK, &self.album_peak),
+            (RG_REFERENCE_LOUDNESS, &self.reference_loudness),
+        ]
+        .iter()
+        .filter_map(|(key, value)| value.as_ref().map(|v| (key.to_string(), v.clone())))
+        .collect()
+    }
+
+    /// All tags that are set as an APEv2-style gain tag, under the
+    /// [`tag_keys`] names, including aacgain's undo information
+    ///
+    /// Lets M4A files be shown like MP3 gain tags; `None` if nothing is set.
+    pub fn to_gain_tag(&self) -> Option<crate::ApeTag> {
+        let mut tag = crate::ApeTag::new();
+        for (key, value) in [
+            (tag_keys::MP3GAIN_UNDO, &self.mp3gain_undo),
+            (tag_keys::MP3GAIN_MINMAX, &self.mp3gain_minmax),
+            (tag_keys::MP3GAIN_ALBUM_MINMAX, &self.mp3gain_album_minmax),
+            (tag_keys::REPLAYGAIN_TRACK_GAIN, &self.track_gain),
+            (tag_keys::REPLAYGAIN_TRACK_PEAK, &self.track_peak),
+            (tag_keys::REPLAYGAIN_ALBUM_GAIN, &self.album_gain),
+            (tag_keys::REPLAYGAIN_ALBUM_PEAK, &self.album_peak),
+            (
+                tag_keys::REPLAYGAIN_REFERENCE_LOUDNESS,
+                &self.reference_loudness,
+            ),
+        ] {
+            if let Some(value) = value {
+                tag.set(key, value);
+            }
+        }
+        (!tag.is_empty()).then_some(tag)
+    }
+
+    /// Compute the per-key changes needed to turn these tags into `new`
+    pub fn diff(&self, new: &ReplayGainTags) -> Vec<crate::TagChange> {
+        crate::diff_tag_items(&self.items(), &new.items())
     }
 
     fn to_freeform_tags(&self) -> Vec<FreeformTag> {
@@ -171,6 +269,13 @@ impl ReplayGainTags {
                 value: v.clone(),
             });
         }
+        if let Some(ref v) = self.reference_loudness {
+            tags.push(FreeformTag {
+                namespace: ITUNES_NAMESPACE.to_string(),
+                name: RG_REFERENCE_LOUDNESS.to_string(),
+                value: v.clone(),
+            });
+        }
 
         tags
     }
@@ -214,12 +319,18 @@ fn find_box_in_container(
 
     while pos + 8 <= container_end {
         let mut cursor = Cursor::new(&data[pos..]);
-        if let Ok(Some(header)) = BoxHeader::read(&mut cursor) {
+        if let Ok(Some(mut header)) = BoxHeader::read(&mut cursor) {
+            // Size 0: the box runs to the end of its container, so it is the
+            // last one and gets that size for the caller
+            if header.size == 0 {
+                header.size = (container_end - pos) as u64;
+            }
+
             if header.box_type == box_type {
                 return Some((pos, header));
             }
 
-            if header.size == 0 {
+            if header.size < header.header_size as u64 {
                 break;
             }
 
@@ -329,12 +440,103 @@ fn serialize_freeform_tag(tag: &FreeformTag) -> Vec<u8> {
     freeform
 }
 
+/// Position, type and size of each top-level box, reading only their headers
+fn top_level_boxes(file: &mut fs::File) -> Result<Vec<(u64, u32, u64)>> {
+    let len = file.metadata()?.len();
+
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= len {
+        let head = crate::read_range(file, pos, 16)?;
+        let Some(header) = BoxHeader::read(&mut Cursor::new(&head))? else {
+            break;
+        };
+        let size = if header.size == 0 {
+            len - pos // Extends to EOF
+        } else {
+            header.size
+        };
+        if size < header.header_size as u64 {
+            break;
+        }
+        boxes.push((pos, header.box_type, size));
+        pos += size;
+    }
+
+    Ok(boxes)
+}
+
+/// Read the top-level moov box of an MP4 file, seeking past the others
+///
+/// Only box headers and moov itself are read, so a large mdat costs nothing.
+fn read_moov(file_path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+
+    match top_level_boxes(&mut file)?
+        .into_iter()
+        .find(|&(_, box_type, _)| box_type == MOOV)
+    {
+        Some((pos, _, size)) => Ok(Some(crate::read_range(&mut file, pos, size)?)),
+        None => Ok(None),
+    }
+}
+
+/// How much of an MP4 file writing ReplayGain tags rewrites, see [`mp4_edit_cost`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditCost {
+    /// Nothing but padding follows moov, so only moov is rewritten
+    Cheap,
+    /// A `free` or `skip` box of this many bytes, after ilst or after moov,
+    /// takes up tag growth that fits in it; only larger growth moves the
+    /// media data after moov
+    Padded(u64),
+    /// Media data follows moov: if the tags grow, everything after moov is
+    /// rewritten and the chunk offsets pointing there are updated
+    FullRewrite,
+}
+
+/// Check how costly writing ReplayGain tags into an MP4 file would be
+///
+/// Only box headers are read. Files with moov in front of a large mdat
+/// ("fast start") take as long to tag as to copy, so callers can warn before
+/// starting.
+pub fn mp4_edit_cost(file_path: &Path) -> Result<EditCost> {
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    let boxes = top_level_boxes(&mut file)?;
+
+    let moov = boxes
+        .iter()
+        .position(|&(_, box_type, _)| box_type == MOOV)
+        .ok_or_else(|| anyhow::anyhow!("No moov box found in MP4 file"))?;
+    let after = &boxes[moov + 1..];
+    if after
+        .iter()
+        .all(|&(_, box_type, _)| matches!(box_type, FREE | SKIP))
+    {
+        return Ok(EditCost::Cheap);
+    }
+    if let (_, FREE | SKIP, size) = after[0] {
+        return Ok(EditCost::Padded(size));
+    }
+
+    let (pos, _, size) = boxes[moov];
+    let moov = crate::read_range(&mut file, pos, size)?;
+    Ok(match ilst_padding(&moov) {
+        Some(size) => EditCost::Padded(size as u64),
+        None => EditCost::FullRewrite,
+    })
+}
+
 /// Read ReplayGain tags from MP4/M4A file
+///
+/// Only the moov box is read (see [`read_moov`]).
 pub fn read_replaygain_tags(file_path: &Path) -> Result<ReplayGainTags> {
-    let data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
-
     let mut tags = ReplayGainTags::new();
+    let Some(data) = read_moov(file_path)? else {
+        return Ok(tags); // No moov, no metadata
+    };
 
     // Find moov box
     let (moov_pos, moov_header) = match find_box(&data, MOOV) {
@@ -398,6 +600,18 @@ pub fn read_replaygain_tags(file_path: &Path) -> Result<ReplayGainTags> {
                             x if x.eq_ignore_ascii_case(RG_ALBUM_PEAK) => {
                                 tags.album_peak = Some(tag.value);
                             }
+                            x if x.eq_ignore_ascii_case(RG_REFERENCE_LOUDNESS) => {
+                                tags.reference_loudness = Some(tag.value);
+                            }
+                            x if x.eq_ignore_ascii_case(AACGAIN_UNDO) => {
+                                tags.mp3gain_undo = Some(tag.value);
+                            }
+                            x if x.eq_ignore_ascii_case(AACGAIN_MINMAX) => {
+                                tags.mp3gain_minmax = Some(tag.value);
+                            }
+                            x if x.eq_ignore_ascii_case(AACGAIN_ALBUM_MINMAX) => {
+                                tags.mp3gain_album_minmax = Some(tag.value);
+                            }
                             _ => {}
                         }
                     }
@@ -417,14 +631,40 @@ pub fn read_replaygain_tags(file_path: &Path) -> Result<ReplayGainTags> {
 }
 
 /// Write ReplayGain tags to MP4/M4A file
+///
+/// Only the changed bytes are written back: unless the tags move the media
+/// data (see [`mp4_edit_cost`]), it stays untouched on disk.
 pub fn write_replaygain_tags(file_path: &Path, tags: &ReplayGainTags) -> Result<()> {
-    let data =
-        fs::read(file_path).with_context(|| format!("Failed to read: {}", file_path.display()))?;
+    let data = crate::read_file(file_path)?;
 
     let new_data = update_mp4_metadata(&data, tags)?;
 
-    fs::write(file_path, &new_data)
-        .with_context(|| format!("Failed to write: {}", file_path.display()))?;
+    let start = data
+        .iter()
+        .zip(&new_data)
+        .position(|(old, new)| old != new)
+        .unwrap_or(data.len().min(new_data.len()));
+    let end = if data.len() == new_data.len() {
+        let unchanged_tail = data[start..]
+            .iter()
+            .rev()
+            .zip(new_data[start..].iter().rev())
+            .take_while(|(old, new)| old == new)
+            .count();
+        new_data.len() - unchanged_tail
+    } else {
+        new_data.len()
+    };
+
+    fs::OpenOptions::new()
+        .write(true)
+        .open(file_path)
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(start as u64))?;
+            file.write_all(&new_data[start..end])?;
+            file.set_len(new_data.len() as u64)
+        })
+        .map_err(|e| write_error(e, file_path))?;
 
     Ok(())
 }
@@ -435,6 +675,15 @@ fn update_mp4_metadata(data: &[u8], tags: &ReplayGainTags) -> Result<Vec<u8>> {
     let (moov_pos, moov_header) =
         find_box(data, MOOV).ok_or_else(|| anyhow::anyhow!("No moov box found in MP4 file"))?;
 
+    // tfhd base offsets and the mfra index point at absolute positions, which
+    // a bigger moov would shift
+    if is_fragmented_data(data) {
+        anyhow::bail!(
+            "fragmented MP4 (moof fragments) is not supported: \
+             writing tags could break its fragment offsets"
+        );
+    }
+
     let moov_content_start = moov_pos + moov_header.header_size as usize;
     let moov_content_size = moov_header.content_size() as usize;
     let moov_end = moov_pos + moov_header.size as usize;
@@ -451,6 +700,7 @@ fn update_mp4_metadata(data: &[u8], tags: &ReplayGainTags) -> Result<Vec<u8>> {
             ilst_pos,
             ilst_size,
             meta_pos,
+            meta_size,
             udta_pos,
         } => {
             // Calculate size differences
@@ -467,10 +717,16 @@ fn update_mp4_metadata(data: &[u8], tags: &ReplayGainTags) -> Result<Vec<u8>> {
             // Write data after old ilst
             result.extend_from_slice(&data[ilst_pos + old_ilst_size..]);
 
-            // Update sizes in headers
-            update_box_size(&mut result, moov_pos, size_diff);
-            update_box_size(&mut result, udta_pos, size_diff);
-            update_box_size(&mut result, meta_pos, size_diff);
+            // Padding after ilst takes up the change if it can, leaving the
+            // containers as big as they were
+            let meta_end = (meta_pos + meta_size) as i64 + size_diff;
+            let ilst_end = ilst_pos + new_ilst_size;
+            if absorb_in_padding(&mut result, ilst_end, meta_end as usize, size_diff) != 0 {
+                // Update sizes in headers
+                update_box_size(&mut result, moov_pos, size_diff);
+                update_box_size(&mut result, udta_pos, size_diff);
+                update_box_size(&mut result, meta_pos, size_diff);
+            }
         }
         IlstLocation::NeedsMeta {
             udta_pos,
@@ -515,27 +771,107 @@ fn update_mp4_metadata(data: &[u8], tags: &ReplayGainTags) -> Result<Vec<u8>> {
         }
     }
 
-    // Update mdat offset if needed (stco/co64 atoms)
-    // For simplicity, we'll handle this by checking if moov comes before mdat
-    if let Some((mdat_pos, _)) = find_box(data, MDAT) {
-        if mdat_pos > moov_pos {
-            // moov is before mdat, need to update chunk offsets
-            let size_diff = result.len() as i64 - data.len() as i64;
-            if size_diff != 0 {
-                update_chunk_offsets(&mut result, moov_pos, size_diff)?;
-            }
-        }
+    // Padding after moov takes up the change in its size; failing that, room
+    // moov gave up becomes new padding, so the data after it stays in place
+    let mut size_diff = result.len() as i64 - data.len() as i64;
+    let next_pos = (moov_end as i64 + size_diff) as usize;
+    let file_end = result.len();
+    size_diff = absorb_in_padding(&mut result, next_pos, file_end, size_diff);
+    if size_diff <= -8 && next_pos < result.len() {
+        let mut padding = (-size_diff as u32).to_be_bytes().to_vec();
+        padding.extend_from_slice(&FREE.to_be_bytes());
+        padding.resize(-size_diff as usize, 0);
+        result.splice(next_pos..next_pos, padding);
+        size_diff = 0;
+    }
+
+    // Everything after moov moved, so chunk offsets (stco/co64) pointing
+    // there must follow; data before moov, such as an mdat written ahead of
+    // it, stays where it was
+    if size_diff != 0 {
+        update_chunk_offsets(&mut result, moov_pos, moov_end as u64, size_diff)?;
     }
 
     Ok(result)
 }
 
+/// Size of the `free` or `skip` padding box at `pos`, if one starts there
+/// and ends by `end`
+fn padding_at(data: &[u8], pos: usize, end: usize) -> Option<usize> {
+    let header = data.get(pos..pos + 8)?;
+    let box_type = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    (matches!(box_type, FREE | SKIP) && size >= 8 && pos + size <= end).then_some(size)
+}
+
+/// Let a padding box take up the change in size of the box before it
+///
+/// `next_pos` is where the box after the changed one now starts in `result`,
+/// and `end` where their container ends. A `free` or `skip` box there shrinks
+/// by the growth, if it has the room, or grows by the shrinkage. Returns how
+/// far the data after the padding still moves: 0 once the padding took up
+/// the change, otherwise `size_diff`.
+fn absorb_in_padding(result: &mut Vec<u8>, next_pos: usize, end: usize, size_diff: i64) -> i64 {
+    let Some(size) = padding_at(result, next_pos, end) else {
+        return size_diff;
+    };
+    let size = size as i64;
+    let mut header = [0u8; 8];
+    header[4..].copy_from_slice(&result[next_pos + 4..next_pos + 8]);
+
+    if size_diff > 0 && (size == size_diff || size - size_diff >= 8) {
+        result.drain(next_pos..next_pos + size_diff as usize);
+        if size > size_diff {
+            header[..4].copy_from_slice(&((size - size_diff) as u32).to_be_bytes());
+            result[next_pos..next_pos + 8].copy_from_slice(&header);
+        }
+        0
+    } else if size_diff < 0 {
+        header[..4].copy_from_slice(&((size - size_diff) as u32).to_be_bytes());
+        let mut grown = header.to_vec();
+        grown.resize((8 - size_diff) as usize, 0);
+        result.splice(next_pos..next_pos + 8, grown);
+        0
+    } else {
+        size_diff
+    }
+}
+
+/// Size of the padding box right after ilst in the meta box of `moov`
+fn ilst_padding(moov: &[u8]) -> Option<usize> {
+    let (moov_pos, moov_header) = find_box(moov, MOOV)?;
+    let (udta_pos, udta_header) = find_box_in_container(
+        moov,
+        moov_pos + moov_header.header_size as usize,
+        moov_header.content_size() as usize,
+        UDTA,
+    )?;
+    let (meta_pos, meta_header) = find_box_in_container(
+        moov,
+        udta_pos + udta_header.header_size as usize,
+        udta_header.content_size() as usize,
+        META,
+    )?;
+    let (ilst_pos, ilst_header) = find_box_in_container(
+        moov,
+        meta_pos + meta_header.header_size as usize + 4,
+        (meta_header.content_size() as usize).checked_sub(4)?,
+        ILST,
+    )?;
+    padding_at(
+        moov,
+        ilst_pos + ilst_header.size as usize,
+        meta_pos + meta_header.size as usize,
+    )
+}
+
 #[derive(Debug)]
 enum IlstLocation {
     Existing {
         ilst_pos: usize,
         ilst_size: usize,
         meta_pos: usize,
+        meta_size: usize,
         udta_pos: usize,
     },
     NeedsMeta {
@@ -613,6 +949,7 @@ fn create_or_update_ilst(
             ilst_pos,
             ilst_size: ilst_header.size as usize,
             meta_pos,
+            meta_size: meta_header.size as usize,
             udta_pos,
         },
     ))
@@ -637,11 +974,7 @@ fn create_ilst_box(tags: &ReplayGainTags, existing_content: &[u8]) -> Vec<u8> {
                 let inner_data = &existing_content
                     [pos + header.header_size as usize..pos + header.size as usize];
                 if let Some(tag) = parse_freeform_tag(inner_data) {
-                    tag.namespace == ITUNES_NAMESPACE
-                        && (tag.name.eq_ignore_ascii_case(RG_TRACK_GAIN)
-                            || tag.name.eq_ignore_ascii_case(RG_TRACK_PEAK)
-                            || tag.name.eq_ignore_ascii_case(RG_ALBUM_GAIN)
-                            || tag.name.eq_ignore_ascii_case(RG_ALBUM_PEAK))
+                    tag.namespace == ITUNES_NAMESPACE && tag_keys::is_replaygain_key(&tag.name)
                 } else {
                     false
                 }
@@ -747,7 +1080,15 @@ fn update_box_size(data: &mut [u8], box_pos: usize, size_diff: i64) {
 }
 
 /// Update stco/co64 chunk offsets after modifying moov size
-fn update_chunk_offsets(data: &mut [u8], moov_pos: usize, size_diff: i64) -> Result<()> {
+///
+/// Offsets at or past `moved_from` (the original end of moov) are shifted
+/// by `size_diff`; earlier ones are left alone.
+fn update_chunk_offsets(
+    data: &mut [u8],
+    moov_pos: usize,
+    moved_from: u64,
+    size_diff: i64,
+) -> Result<()> {
     // Find moov box again in the modified data
     let (_, moov_header) = match find_box(data, MOOV) {
         Some(x) => x,
@@ -757,7 +1098,7 @@ fn update_chunk_offsets(data: &mut [u8], moov_pos: usize, size_diff: i64) -> Res
     let moov_end = moov_pos + moov_header.size as usize;
 
     // Recursively find and update stco/co64 boxes within moov
-    update_offsets_recursive(data, moov_pos + 8, moov_end, size_diff)?;
+    update_offsets_recursive(data, moov_pos + 8, moov_end, moved_from, size_diff)?;
 
     Ok(())
 }
@@ -773,6 +1114,7 @@ fn update_offsets_recursive(
     data: &mut [u8],
     start: usize,
     end: usize,
+    moved_from: u64,
     size_diff: i64,
 ) -> Result<()> {
     let mut pos = start;
@@ -810,8 +1152,11 @@ fn update_offsets_recursive(
                             data[offset_pos + 2],
                             data[offset_pos + 3],
                         ]);
-                        let new_offset = (offset as i64 + size_diff) as u32;
-                        data[offset_pos..offset_pos + 4].copy_from_slice(&new_offset.to_be_bytes());
+                        if offset as u64 >= moved_from {
+                            let new_offset = (offset as i64 + size_diff) as u32;
+                            data[offset_pos..offset_pos + 4]
+                                .copy_from_slice(&new_offset.to_be_bytes());
+                        }
                         offset_pos += 4;
                     }
                 }
@@ -843,15 +1188,24 @@ fn update_offsets_recursive(
                             data[offset_pos + 6],
                             data[offset_pos + 7],
                         ]);
-                        let new_offset = (offset as i64 + size_diff) as u64;
-                        data[offset_pos..offset_pos + 8].copy_from_slice(&new_offset.to_be_bytes());
+                        if offset >= moved_from {
+                            let new_offset = (offset as i64 + size_diff) as u64;
+                            data[offset_pos..offset_pos + 8]
+                                .copy_from_slice(&new_offset.to_be_bytes());
+                        }
                         offset_pos += 8;
                     }
                 }
             }
             TRAK | MDIA | MINF | STBL | MOOV | UDTA => {
                 // Container boxes - recurse into them
-                update_offsets_recursive(data, pos + 8, pos + size as usize, size_diff)?;
+                update_offsets_recursive(
+                    data,
+                    pos + 8,
+                    pos + size as usize,
+                    moved_from,
+                    size_diff,
+                )?;
             }
             _ => {}
         }
@@ -868,21 +1222,64 @@ pub fn delete_replaygain_tags(file_path: &Path) -> Result<()> {
     write_replaygain_tags(file_path, &empty_tags)
 }
 
+/// Check if an MP4 file is fragmented (fMP4)
+///
+/// Only the moov box is read, like [`read_replaygain_tags`].
+pub fn is_fragmented_mp4(file_path: &Path) -> Result<bool> {
+    Ok(read_moov(file_path)?.is_some_and(|moov| has_mvex(&moov)))
+}
+
+/// Fragmented MP4: an `mvex` box announcing fragments in moov, or top-level
+/// `moof`/`mfra` boxes
+fn is_fragmented_data(data: &[u8]) -> bool {
+    find_box(data, MOOF).is_some()
+        || find_box(data, MFRA).is_some()
+        || find_box(data, MOOV).is_some_and(|(pos, _)| has_mvex(&data[pos..]))
+}
+
+/// Whether the moov box at the start of `moov` holds an `mvex` box
+fn has_mvex(moov: &[u8]) -> bool {
+    let Some((_, header)) = find_box(moov, MOOV) else {
+        return false;
+    };
+    let content_start = header.header_size as usize;
+    let content_size = (header.content_size() as usize).min(moov.len() - content_start);
+    find_box_in_container(moov, content_start, content_size, MVEX).is_some()
+}
+
 /// Check if file is an MP4/M4A file
 pub fn is_mp4_file(file_path: &Path) -> bool {
-    if let Ok(data) = fs::read(file_path) {
-        if data.len() >= 12 {
-            // Check for ftyp box
-            let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-            let box_type = &data[4..8];
-            if box_type == b"ftyp" && size >= 12 {
-                // Check compatible brands
-                let brand = &data[8..12];
-                return matches!(
-                    brand,
-                    b"M4A " | b"M4B " | b"M4P " | b"M4V " | b"mp41" | b"mp42" | b"isom" | b"iso2"
-                );
-            }
+    let mut head = Vec::with_capacity(12);
+    if let Ok(file) = fs::File::open(file_path) {
+        let _ = file.take(12).read_to_end(&mut head);
+    }
+    is_mp4_data(&head)
+}
+
+/// Check if data starts with an MP4 `ftyp` box of an audio-capable brand
+pub(crate) fn is_mp4_data(data: &[u8]) -> bool {
+    if data.len() >= 12 {
+        // Check for ftyp box
+        let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let box_type = &data[4..8];
+        if box_type == b"ftyp" && size >= 12 {
+            // Check compatible brands
+            let brand = &data[8..12];
+            return matches!(
+                brand,
+                b"M4A "
+                    | b"M4B "
+                    | b"M4P "
+                    | b"M4V "
+                    | b"mp41"
+                    | b"mp42"
+                    | b"isom"
+                    | b"iso2"
+                    // Fragmented MP4 brands
+                    | b"iso5"
+                    | b"iso6"
+                    | b"dash"
+            );
         }
     }
     false
@@ -922,9 +1319,174 @@ mod tests {
         assert_eq!(tags.track_peak, Some("0.987650".to_string()));
         assert_eq!(tags.album_gain, Some("+2.00 dB".to_string()));
         assert_eq!(tags.album_peak, Some("0.999990".to_string()));
+        assert_eq!(tags.reference_loudness, Some("89.0 dB".to_string()));
 
         let freeform_tags = tags.to_freeform_tags();
-        assert_eq!(freeform_tags.len(), 4);
+        assert_eq!(freeform_tags.len(), 5);
+    }
+
+    #[test]
+    fn test_replaygain_tags_diff() {
+        use crate::TagChange;
+
+        let mut old = ReplayGainTags::new();
+        old.set_track(-2.0, 0.5);
+        old.album_gain = Some("-1.00 dB".to_string());
+
+        let mut new = ReplayGainTags::new();
+        new.set_track(-3.0, 0.5);
+
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            vec![
+                TagChange::Changed {
+                    key: RG_TRACK_GAIN.to_string(),
+                    old: "-2.00 dB".to_string(),
+                    new: "-3.00 dB".to_string(),
+                },
+                TagChange::Removed {
+                    key: RG_ALBUM_GAIN.to_string(),
+                    value: "-1.00 dB".to_string(),
+                },
+            ]
+        );
+
+        let added = ReplayGainTags::new().diff(&new);
+        assert_eq!(added.len(), 3);
+        assert!(added.iter().all(|c| matches!(c, TagChange::Added { .. })));
+    }
+
+    #[test]
+    fn test_reference_loudness_round_trip() {
+        // ftyp + empty moov + mdat
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0, 0, 20]);
+        data.extend_from_slice(b"ftypM4A \0\0\0\0M4A ");
+        data.extend_from_slice(&[0, 0, 0, 8]);
+        data.extend_from_slice(b"moov");
+        data.extend_from_slice(&[0, 0, 0, 12]);
+        data.extend_from_slice(b"mdat\0\0\0\0");
+
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_mp4meta_reference_{}.m4a",
+            std::process::id()
+        ));
+        fs::write(&path, &data).unwrap();
+
+        let mut tags = ReplayGainTags::new();
+        tags.set_track(-4.25, 0.5);
+        write_replaygain_tags(&path, &tags).unwrap();
+
+        let read = read_replaygain_tags(&path).unwrap();
+        assert_eq!(read.track_gain, Some("-4.25 dB".to_string()));
+        assert_eq!(read.reference_loudness, Some("89.0 dB".to_string()));
+
+        // A custom target replaces the default reference
+        tags.set_reference_loudness(92.0);
+        write_replaygain_tags(&path, &tags).unwrap();
+        let read = read_replaygain_tags(&path).unwrap();
+        assert_eq!(read.reference_loudness, Some("92.0 dB".to_string()));
+        assert_eq!(read.reference_loudness_db(), Some(92.0));
+
+        delete_replaygain_tags(&path).unwrap();
+        assert!(read_replaygain_tags(&path).unwrap().is_empty());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_read_tags_skips_mdat() {
+        // ftyp + large mdat + moov at the end, as some encoders write it
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0, 0, 20]);
+        data.extend_from_slice(b"ftypM4A \0\0\0\0M4A ");
+        data.extend_from_slice(&(1_000_008u32).to_be_bytes());
+        data.extend_from_slice(b"mdat");
+        data.resize(data.len() + 1_000_000, 0);
+        data.extend_from_slice(&[0, 0, 0, 8]);
+        data.extend_from_slice(b"moov");
+
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_mp4meta_mdat_first_{}.m4a",
+            std::process::id()
+        ));
+        fs::write(&path, &data).unwrap();
+        let mut tags = ReplayGainTags::new();
+        tags.set_track(-2.5, 0.75);
+        write_replaygain_tags(&path, &tags).unwrap();
+
+        let bytes_read = || crate::BYTES_READ.with(|bytes| bytes.get());
+        let before = bytes_read();
+        let read = read_replaygain_tags(&path).unwrap();
+        assert!(
+            bytes_read() - before < 4096,
+            "{} bytes",
+            bytes_read() - before
+        );
+        assert_eq!(read.track_gain, Some("-2.50 dB".to_string()));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_size_zero_box_runs_to_container_end() {
+        let mut tags = ReplayGainTags::new();
+        tags.set_track(-1.5, 0.5);
+        let items: Vec<u8> = tags
+            .to_freeform_tags()
+            .iter()
+            .flat_map(serialize_freeform_tag)
+            .collect();
+        let mut ilst = mp4_box(b"ilst", &items);
+        ilst[..4].copy_from_slice(&[0; 4]);
+
+        // An ordinary box, then ilst as the last box with size 0
+        let mut meta = vec![0; 4];
+        meta.extend_from_slice(&mp4_box(b"free", &[0; 8]));
+        meta.extend_from_slice(&ilst);
+        let moov = mp4_box(b"moov", &mp4_box(b"udta", &mp4_box(b"meta", &meta)));
+
+        let meta_content = 8 + 8 + 8 + 4;
+        let (pos, header) =
+            find_box_in_container(&moov, meta_content, moov.len() - meta_content, ILST).unwrap();
+        assert_eq!(pos, meta_content + 16);
+        assert_eq!(header.size as usize, ilst.len());
+
+        let data = [mp4_box(b"ftyp", b"M4A \0\0\0\0M4A "), moov].concat();
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_mp4meta_size_zero_{}.m4a",
+            std::process::id()
+        ));
+        fs::write(&path, &data).unwrap();
+        let read = read_replaygain_tags(&path).unwrap();
+        assert_eq!(read.track_gain.as_deref(), Some("-1.50 dB"));
+
+        // Rewriting replaces the whole box rather than inserting another
+        tags.set_track(2.0, 0.25);
+        write_replaygain_tags(&path, &tags).unwrap();
+        let read = read_replaygain_tags(&path).unwrap();
+        assert_eq!(read.track_gain.as_deref(), Some("+2.00 dB"));
+        assert_eq!(fs::read(&path).unwrap().len(), data.len());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_read_aacgain_undo_tags() {
+        let path = Path::new("tests/fixtures/test_aacgain.m4a");
+        let tags = read_replaygain_tags(path).unwrap();
+        assert_eq!(tags.mp3gain_undo.as_deref(), Some("+003,+003,N"));
+        assert_eq!(tags.mp3gain_minmax.as_deref(), Some("120,200"));
+        assert_eq!(tags.mp3gain_album_minmax.as_deref(), Some("110,210"));
+        assert!(tags.track_gain.is_some());
+
+        let tag = tags.to_gain_tag().unwrap();
+        assert_eq!(tag.get(tag_keys::MP3GAIN_UNDO), Some("+003,+003,N"));
+        assert_eq!(tag.get(tag_keys::MP3GAIN_ALBUM_MINMAX), Some("110,210"));
+        assert!(ReplayGainTags::new().to_gain_tag().is_none());
+
+        // Only the three ReplayGain tags count as changes
+        assert_eq!(tags.diff(&ReplayGainTags::new()).len(), 3);
     }
 
     #[test]
@@ -938,7 +1500,157 @@ mod tests {
             b'M', b'4', b'A', b' ', // compatible brand
         ];
 
-        // This test would need a temp file, but we can verify the logic
-        assert!(matches!(&m4a_header[8..12], b"M4A "));
+        assert!(is_mp4_data(&m4a_header));
+        assert!(!is_mp4_data(&m4a_header[..11]));
+
+        let mut other_brand = m4a_header.clone();
+        other_brand[8..12].copy_from_slice(b"qt  ");
+        assert!(!is_mp4_data(&other_brand));
+    }
+
+    fn mp4_box(box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut data = ((8 + content.len()) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(content);
+        data
+    }
+
+    /// First chunk offset of each trak's stco
+    fn chunk_offsets(data: &[u8]) -> Vec<u32> {
+        data.windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == b"stco")
+            .map(|(i, _)| u32::from_be_bytes(data[i + 12..i + 16].try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_offsets_follow_moved_data_only() {
+        // ftyp, an mdat before moov, moov with two traks, and an mdat after
+        // it: one trak's chunk lies in each mdat
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0M4A ");
+        let early = mp4_box(b"mdat", b"early audio");
+        let trak = |offset: u32| {
+            let mut stco = vec![0, 0, 0, 0, 0, 0, 0, 1];
+            stco.extend_from_slice(&offset.to_be_bytes());
+            let stbl = mp4_box(b"stbl", &mp4_box(b"stco", &stco));
+            let minf = mp4_box(b"minf", &stbl);
+            mp4_box(b"trak", &mp4_box(b"mdia", &minf))
+        };
+        let early_offset = (ftyp.len() + 8) as u32;
+        let moov_len = 8 + 2 * trak(0).len();
+        let late_offset = (ftyp.len() + early.len() + moov_len + 8) as u32;
+        let moov = mp4_box(b"moov", &[trak(early_offset), trak(late_offset)].concat());
+        let late = mp4_box(b"mdat", b"late audio");
+        let data = [ftyp, early, moov, late].concat();
+        assert_eq!(&data[late_offset as usize..], b"late audio");
+
+        let mut tags = ReplayGainTags::new();
+        tags.set_track(-4.25, 0.5);
+        let updated = update_mp4_metadata(&data, &tags).unwrap();
+        let offsets = chunk_offsets(&updated);
+        assert_eq!(offsets[0], early_offset);
+        assert_eq!(&updated[offsets[0] as usize..][..11], b"early audio");
+        assert_eq!(&updated[offsets[1] as usize..], b"late audio");
+    }
+
+    #[test]
+    fn test_padding_after_ilst_keeps_mdat_in_place() {
+        // ftyp, moov (a trak whose chunk is in the mdat after it, and an
+        // empty ilst followed by padding), mdat
+        let layout = |padding: usize| {
+            let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0M4A ");
+            let trak = |offset: u32| {
+                let mut stco = vec![0, 0, 0, 0, 0, 0, 0, 1];
+                stco.extend_from_slice(&offset.to_be_bytes());
+                let stbl = mp4_box(b"stbl", &mp4_box(b"stco", &stco));
+                mp4_box(b"trak", &mp4_box(b"mdia", &mp4_box(b"minf", &stbl)))
+            };
+            let mut meta = vec![0; 4];
+            meta.extend_from_slice(&mp4_box(b"ilst", &[]));
+            meta.extend_from_slice(&mp4_box(b"free", &vec![0; padding - 8]));
+            let udta = mp4_box(b"udta", &mp4_box(b"meta", &meta));
+            let moov_len = 8 + trak(0).len() + udta.len();
+            let offset = (ftyp.len() + moov_len + 8) as u32;
+            let moov = mp4_box(b"moov", &[trak(offset), udta].concat());
+            [ftyp, moov, mp4_box(b"mdat", b"audio")].concat()
+        };
+        let mut tags = ReplayGainTags::new();
+        tags.set_track(-4.25, 0.5);
+
+        let data = layout(1024);
+        let offset = chunk_offsets(&data)[0];
+        let updated = update_mp4_metadata(&data, &tags).unwrap();
+        assert_eq!(updated.len(), data.len());
+        assert_eq!(chunk_offsets(&updated), vec![offset]);
+        assert_eq!(&updated[offset as usize..], b"audio");
+        assert_eq!(updated[..32], data[..32]);
+
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_mp4meta_ilst_padding_{}.m4a",
+            std::process::id()
+        ));
+        fs::write(&path, &data).unwrap();
+        assert_eq!(mp4_edit_cost(&path).unwrap(), EditCost::Padded(1024));
+        write_replaygain_tags(&path, &tags).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), updated);
+        assert_eq!(
+            read_replaygain_tags(&path).unwrap().track_gain.as_deref(),
+            Some("-4.25 dB")
+        );
+        let _ = fs::remove_file(&path);
+
+        // Too little padding: mdat moves and the offset follows it
+        let data = layout(16);
+        let updated = update_mp4_metadata(&data, &tags).unwrap();
+        assert!(updated.len() > data.len());
+        let moved = chunk_offsets(&updated)[0];
+        assert!(moved > chunk_offsets(&data)[0]);
+        assert_eq!(&updated[moved as usize..], b"audio");
+    }
+
+    #[test]
+    fn test_edit_cost_by_layout() {
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0M4A ");
+        let moov = mp4_box(b"moov", &[]);
+        let free = mp4_box(b"free", &[0; 1016]);
+        let mdat = mp4_box(b"mdat", b"audio");
+
+        let path = std::env::temp_dir().join(format!(
+            "mp3rgain_mp4meta_edit_cost_{}.m4a",
+            std::process::id()
+        ));
+        let cost = |layout: &[&[u8]]| {
+            fs::write(&path, layout.concat()).unwrap();
+            mp4_edit_cost(&path).unwrap()
+        };
+        assert_eq!(cost(&[&ftyp, &mdat, &moov]), EditCost::Cheap);
+        assert_eq!(cost(&[&ftyp, &mdat, &moov, &free]), EditCost::Cheap);
+        assert_eq!(cost(&[&ftyp, &moov, &mdat]), EditCost::FullRewrite);
+        assert_eq!(cost(&[&ftyp, &moov, &free, &mdat]), EditCost::Padded(1024));
+
+        // The padding takes up the new tags and gets the room back when
+        // they are deleted, so mdat stays where it is
+        let mut tags = ReplayGainTags::new();
+        tags.set_track(-4.25, 0.5);
+        write_replaygain_tags(&path, &tags).unwrap();
+        let tagged = fs::read(&path).unwrap();
+        assert_eq!(
+            tagged.len(),
+            ftyp.len() + moov.len() + free.len() + mdat.len()
+        );
+        assert!(tagged.ends_with(&mdat));
+        assert_eq!(
+            read_replaygain_tags(&path).unwrap().track_gain.as_deref(),
+            Some("-4.25 dB")
+        );
+        assert!(matches!(mp4_edit_cost(&path).unwrap(), EditCost::Padded(size) if size < 1024));
+
+        delete_replaygain_tags(&path).unwrap();
+        let cleared = fs::read(&path).unwrap();
+        assert_eq!(cleared.len(), tagged.len());
+        assert!(cleared.ends_with(&mdat));
+
+        let _ = fs::remove_file(&path);
     }
 }
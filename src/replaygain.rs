@@ -14,23 +14,31 @@
 #[cfg(feature = "replaygain")]
 use anyhow::Context;
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "replaygain")]
+use std::collections::VecDeque;
 use std::path::Path;
 
-#[cfg(feature = "replaygain")]
-use crate::mp4meta;
+use crate::CancellationToken;
 
 #[cfg(feature = "replaygain")]
-use symphonia::core::audio::{AudioBufferRef, Signal};
+use std::sync::Arc;
+#[cfg(feature = "replaygain")]
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Signal};
 #[cfg(feature = "replaygain")]
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
 #[cfg(feature = "replaygain")]
-use symphonia::core::formats::FormatOptions;
+use symphonia::core::conv::IntoSample;
+#[cfg(feature = "replaygain")]
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
 #[cfg(feature = "replaygain")]
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 #[cfg(feature = "replaygain")]
 use symphonia::core::meta::MetadataOptions;
 #[cfg(feature = "replaygain")]
 use symphonia::core::probe::Hint;
+#[cfg(feature = "replaygain")]
+use symphonia::core::sample::Sample;
 
 /// ReplayGain reference level in dB SPL
 /// Original mp3gain uses 89 dB (ReplayGain 1.0)
@@ -43,13 +51,58 @@ pub const REPLAYGAIN_REFERENCE_DB: f64 = 89.0;
 /// Source: https://replaygain.hydrogenaud.io/calibration.html
 const PINK_REF: f64 = 64.82;
 
+/// Window level below which `--trim-silence` leaves a window out, in dB
+/// relative to digital full scale
+pub const SILENCE_GATE_DBFS: f64 = -60.0;
+
+/// Level of a full-scale 16-bit square wave on the histogram's dB scale,
+/// where the analysis measures 16-bit-scaled samples
+const FULL_SCALE_DB: f64 = 90.308_998_699_194_36; // 20 * log10(32768)
+
 /// Audio file type
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum AudioFileType {
     /// MP3 file
     Mp3,
     /// AAC/M4A file
     Aac,
+    /// Raw AAC stream (ADTS, usually `.aac`) without an MP4 container
+    AacAdts,
+    /// FLAC file (lossless; ReplayGain goes into Vorbis comments)
+    Flac,
+}
+
+impl AudioFileType {
+    /// Whether the audio has MP3 global_gain fields that gain changes
+    /// (and their undo) can adjust; other formats only get ReplayGain tags
+    pub fn has_global_gain(&self) -> bool {
+        *self == AudioFileType::Mp3
+    }
+}
+
+/// Channel layout of the analyzed track
+///
+/// Dual Channel MP3 files carry two independent mono programs. Like the
+/// original mp3gain, they are analyzed as a stereo pair, so the reported
+/// loudness is that of both programs played together.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    /// Two independent mono programs (MP3 Dual Channel mode)
+    DualChannel,
+    /// More than two channels; only the first two are analyzed
+    Multichannel(usize),
+}
+
+impl ChannelLayout {
+    fn from_channel_count(channels: usize) -> Self {
+        match channels {
+            0 | 1 => ChannelLayout::Mono,
+            2 => ChannelLayout::Stereo,
+            n => ChannelLayout::Multichannel(n),
+        }
+    }
 }
 
 /// Result of ReplayGain analysis for a single track
@@ -63,8 +116,34 @@ pub struct ReplayGainResult {
     pub peak: f64,
     /// Sample rate of the audio
     pub sample_rate: u32,
-    /// File type (MP3 or AAC)
-    pub file_type: AudioFileType,
+    /// File type, or `None` for samples fed to a [`PcmAnalyzer`]
+    pub file_type: Option<AudioFileType>,
+    /// Channel layout reported by the decoder (and MP3 frame headers)
+    pub channel_layout: ChannelLayout,
+    /// Whether the values come from decoding or a frame-domain estimate
+    pub method: AnalysisMethod,
+    /// Some packets failed to read or decode and were left out, so the values
+    /// describe only the decodable part (see [`analyze_track_best_effort`])
+    pub partial: bool,
+}
+
+/// How a [`ReplayGainResult`] (or [`crate::MaxAmplitude`]) was obtained
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnalysisMethod {
+    /// Audio was decoded and analyzed with the ReplayGain algorithm
+    Decoded,
+    /// Decoding failed; peak and gain are estimated from MP3 global_gain
+    /// values and are only approximate
+    Heuristic,
+}
+
+impl AnalysisMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnalysisMethod::Decoded => "decoded",
+            AnalysisMethod::Heuristic => "heuristic",
+        }
+    }
 }
 
 impl ReplayGainResult {
@@ -72,10 +151,228 @@ impl ReplayGainResult {
     pub fn gain_steps(&self) -> i32 {
         (self.gain_db / crate::GAIN_STEP_DB).round() as i32
     }
+
+    /// Gain in dB needed to reach `target_db` instead of the 89 dB reference
+    pub fn gain_for_target(&self, target_db: f64) -> f64 {
+        self.gain_db + (target_db - REPLAYGAIN_REFERENCE_DB)
+    }
+
+    /// [`gain_for_target`](Self::gain_for_target) rounded to MP3 gain steps
+    pub fn gain_steps_for_target(&self, target_db: f64) -> i32 {
+        crate::db_to_steps(self.gain_for_target(target_db))
+    }
+
+    /// [`gain_for_target`](Self::gain_for_target) rounded to MP3 gain steps,
+    /// rounding down where rounding up would clip (see
+    /// [`db_to_steps_clip_safe`](crate::db_to_steps_clip_safe))
+    pub fn clip_safe_gain_steps(&self, target_db: f64) -> i32 {
+        crate::db_to_steps_clip_safe(self.gain_for_target(target_db), self.peak)
+    }
+
+    /// Gain in dB reaching `target_db` without the peak exceeding `max_peak_dbfs`
+    ///
+    /// The smaller of the loudness gain ([`gain_for_target`](Self::gain_for_target))
+    /// and the headroom between the peak and the ceiling. A 0 dBFS ceiling is
+    /// the `-k` limit. Silence (peak 0) has no peak limit.
+    pub fn suggested_gain(&self, target_db: f64, max_peak_dbfs: f64) -> f64 {
+        self.gain_for_target(target_db)
+            .min(self.peak_headroom_db(max_peak_dbfs))
+    }
+
+    /// [`suggested_gain`](Self::suggested_gain) in MP3 gain steps
+    ///
+    /// A loudness-driven gain is rounded like [`gain_steps_for_target`](Self::gain_steps_for_target);
+    /// a peak-limited one is rounded down so the peak stays under the ceiling.
+    pub fn suggested_gain_steps(&self, target_db: f64, max_peak_dbfs: f64) -> i32 {
+        let peak_steps = (self.peak_headroom_db(max_peak_dbfs) / crate::GAIN_STEP_DB).floor();
+        // Infinite headroom (silence) saturates to i32::MAX
+        self.gain_steps_for_target(target_db).min(peak_steps as i32)
+    }
+
+    /// Gain in dB that puts the peak exactly at `max_peak_dbfs`
+    fn peak_headroom_db(&self, max_peak_dbfs: f64) -> f64 {
+        clip_margin_db(self.peak, 0.0) + max_peak_dbfs
+    }
 }
 
-/// Result of album gain analysis
+/// A track's ReplayGain result together with its loudness histogram
+///
+/// The histogram is what album analysis sums, so a track analyzed once can be
+/// added to an album later (or cached) without decoding it again.
 #[derive(Debug, Clone)]
+pub struct TrackAnalysis {
+    pub result: ReplayGainResult,
+    pub histogram: LoudnessHistogram,
+}
+
+impl TrackAnalysis {
+    /// Recompute loudness and gain leaving out windows quieter than `gate_dbfs`
+    ///
+    /// Long silent intros and outros still count as windows at the bottom of
+    /// the histogram; gating them takes the percentile over the music only.
+    /// The histogram itself is kept whole, so it can still join an album.
+    pub fn with_silence_gate(mut self, gate_dbfs: f64) -> Self {
+        let loudness_db = self.histogram.get_loudness_gated(Some(gate_dbfs));
+        // Unchanged for a short clip's single window, which keeps its capped gain
+        if self.result.method == AnalysisMethod::Decoded && loudness_db != self.result.loudness_db {
+            self.result.loudness_db = loudness_db;
+            self.result.gain_db = PINK_REF - loudness_db;
+        }
+        self
+    }
+
+    /// Analysis parameters behind the loudness, with windows quieter than
+    /// `gate_dbfs` left out of the percentile as in [`with_silence_gate`](Self::with_silence_gate)
+    pub fn params(&self, gate_dbfs: Option<f64>) -> AnalysisParams {
+        AnalysisParams {
+            filter_sample_rate: self.result.sample_rate,
+            window_samples: window_samples(self.result.sample_rate),
+            windows: self.histogram.window_count(),
+            percentile_bucket: self.histogram.percentile_bucket(gate_dbfs),
+        }
+    }
+}
+
+/// How a track's loudness was measured, for comparing with other tools (`--rg-debug`)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AnalysisParams {
+    /// Sample rate whose equal-loudness filter coefficients were used
+    pub filter_sample_rate: u32,
+    /// Samples per RMS window (50ms, rounded up)
+    pub window_samples: usize,
+    /// RMS windows in the loudness histogram
+    pub windows: u64,
+    /// Histogram bucket of the 95th percentile window; the loudness is the
+    /// bucket / 100 dB. `None` for an empty histogram
+    pub percentile_bucket: Option<usize>,
+}
+
+/// Name of the loudness algorithm the analysis implements
+pub const ALGORITHM: &str = "ReplayGain 1.0";
+
+/// Algorithm and constants a gain was derived with, so stored results can be
+/// reproduced and checked against a later version (`--rg-debug`)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AlgorithmInfo {
+    /// [`ALGORITHM`]
+    pub algorithm: &'static str,
+    /// Loudness the algorithm measures for the -14 dBFS pink noise reference
+    pub pink_ref: f64,
+    /// [`REPLAYGAIN_REFERENCE_DB`]
+    pub reference_db: f64,
+    /// Loudness the gain levels to, the reference moved by any modifier
+    pub target_db: f64,
+    /// Version of this library
+    pub version: &'static str,
+}
+
+impl AlgorithmInfo {
+    /// The algorithm in this version, leveling to `target_db`
+    pub fn new(target_db: f64) -> Self {
+        AlgorithmInfo {
+            algorithm: ALGORITHM,
+            pink_ref: PINK_REF,
+            reference_db: REPLAYGAIN_REFERENCE_DB,
+            target_db,
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+/// Parts of a track left out of its loudness
+///
+/// For podcasts and the like with a loud fixed intro or outro, so the
+/// loudness describes the body. The skipped audio still runs through the
+/// filters and counts toward the peak, which is about clipping the whole
+/// file. Durations are converted to samples at the decoded sample rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SkipRegions {
+    /// Seconds at the start
+    pub intro_secs: f64,
+    /// Seconds at the end
+    pub outro_secs: f64,
+}
+
+impl SkipRegions {
+    pub fn is_empty(&self) -> bool {
+        self.intro_secs <= 0.0 && self.outro_secs <= 0.0
+    }
+}
+
+/// Channel layout the loudness is measured in, whatever the file's own
+///
+/// Only the first two channels of a file are analyzed in any case. Since
+/// ReplayGain averages the mean squares of the channels, a mono source
+/// analyzed as stereo measures the same as analyzed as mono; `Stereo` only
+/// changes the result of files whose channel count is wrong.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Downmix {
+    /// Analyze the channels as decoded
+    #[default]
+    None,
+    /// Average left and right into one channel before filtering, as a mono
+    /// playback system would hear it
+    Mono,
+    /// Analyze as two channels, taking a mono source as both
+    Stereo,
+}
+
+impl Downmix {
+    /// Parse `mono`, `stereo` or `none`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Some(Downmix::None),
+            "mono" => Some(Downmix::Mono),
+            "stereo" => Some(Downmix::Stereo),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "replaygain")]
+impl Downmix {
+    /// Channels the analyzer gets for a stream of `channels`
+    fn analyzed_channels(self, channels: usize) -> usize {
+        match self {
+            Downmix::None => channels,
+            Downmix::Mono => 1,
+            Downmix::Stereo => 2,
+        }
+    }
+
+    /// Left and (if any) right sample as analyzed
+    fn apply(self, left: f64, right: Option<f64>) -> (f64, Option<f64>) {
+        match self {
+            Downmix::None => (left, right),
+            Downmix::Mono => (right.map_or(left, |right| (left + right) / 2.0), None),
+            Downmix::Stereo => (left, Some(right.unwrap_or(left))),
+        }
+    }
+}
+
+/// Error returned when a track decodes to no audio samples at all
+///
+/// There is no loudness to measure, so no gain is suggested. The original
+/// reports GAIN_NOT_ENOUGH_SAMPLES for anything shorter than one 50ms
+/// window; such short clips are measured from the samples they have instead
+/// (a single MP3 frame is only 26ms). Use
+/// `err.downcast_ref::<NotEnoughSamples>()` to detect it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NotEnoughSamples;
+
+impl std::fmt::Display for NotEnoughSamples {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not enough samples to do analysis")
+    }
+}
+
+impl std::error::Error for NotEnoughSamples {}
+
+/// Result of album gain analysis
+///
+/// More tracks can be folded in later with [`add_to_album`], which only
+/// decodes the new files.
+#[derive(Debug, Clone, Default)]
 pub struct AlbumGainResult {
     /// Individual track results
     pub tracks: Vec<ReplayGainResult>,
@@ -85,13 +382,92 @@ pub struct AlbumGainResult {
     pub album_gain_db: f64,
     /// Album peak amplitude
     pub album_peak: f64,
+    /// Loudness histogram of all tracks, which the album loudness is taken from
+    pub histogram: LoudnessHistogram,
+    /// Leave out windows quieter than this (dBFS) from track and album
+    /// loudness; set before adding tracks. See [`TrackAnalysis::with_silence_gate`].
+    pub silence_gate_dbfs: Option<f64>,
 }
 
 impl AlbumGainResult {
+    /// Add an analyzed track and recompute the album loudness and gain
+    pub fn add_track(&mut self, mut track: TrackAnalysis) {
+        if let Some(gate) = self.silence_gate_dbfs {
+            track = track.with_silence_gate(gate);
+        }
+        self.album_peak = self.album_peak.max(track.result.peak);
+        // Album histogram accumulates all track histograms (like B[] in original mp3gain)
+        self.histogram.accumulate(&track.histogram);
+        self.tracks.push(track.result);
+
+        // Calculate album loudness from combined histogram (95th percentile)
+        self.album_loudness_db = self.histogram.get_loudness_gated(self.silence_gate_dbfs);
+        self.album_gain_db = PINK_REF - self.album_loudness_db;
+    }
+
     /// Convert album gain in dB to MP3 gain steps
     pub fn album_gain_steps(&self) -> i32 {
         (self.album_gain_db / crate::GAIN_STEP_DB).round() as i32
     }
+
+    /// Album gain in dB needed to reach `target_db` instead of the 89 dB reference
+    pub fn album_gain_for_target(&self, target_db: f64) -> f64 {
+        self.album_gain_db + (target_db - REPLAYGAIN_REFERENCE_DB)
+    }
+
+    /// [`album_gain_for_target`](Self::album_gain_for_target) rounded to MP3 gain steps
+    pub fn album_gain_steps_for_target(&self, target_db: f64) -> i32 {
+        crate::db_to_steps(self.album_gain_for_target(target_db))
+    }
+}
+
+/// Whether applying `gain_db` to audio peaking at `peak` would push it above full scale
+pub fn would_clip(peak: f64, gain_db: f64) -> bool {
+    peak * 10.0_f64.powf(gain_db / 20.0) > 1.0
+}
+
+/// Headroom in dB left below full scale after applying `gain_db`
+///
+/// Negative when the gain would clip; infinite for silence (peak 0).
+pub fn clip_margin_db(peak: f64, gain_db: f64) -> f64 {
+    if peak > 0.0 {
+        -20.0 * peak.log10() - gain_db
+    } else {
+        f64::INFINITY
+    }
+}
+
+/// Outcome of [`clip_free_album_steps`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlbumClipCheck {
+    /// Album gain in steps after any reductions
+    pub steps: i32,
+    /// Number of passes over the track peaks, the final check included
+    pub iterations: u32,
+    /// Whether no track clips at `steps`; false if the floor was reached first
+    pub clip_free: bool,
+}
+
+/// Lower an album gain a step at a time until no track clips
+///
+/// Each pass scales every track's peak by the gain and checks it against full
+/// scale, so the peaks measured during analysis are reused and nothing is
+/// decoded again. The gain is not lowered below `floor_steps`.
+pub fn clip_free_album_steps(track_peaks: &[f64], steps: i32, floor_steps: i32) -> AlbumClipCheck {
+    let mut check = AlbumClipCheck {
+        steps,
+        iterations: 0,
+        clip_free: false,
+    };
+    loop {
+        check.iterations += 1;
+        let gain_db = crate::steps_to_db(check.steps);
+        check.clip_free = !track_peaks.iter().any(|&peak| would_clip(peak, gain_db));
+        if check.clip_free || check.steps <= floor_steps {
+            return check;
+        }
+        check.steps -= 1;
+    }
 }
 
 // =============================================================================
@@ -623,65 +999,131 @@ impl EqualLoudnessFilter {
 /// Steps per dB for histogram resolution (matches original mp3gain)
 const STEPS_PER_DB: f64 = 100.0;
 
-/// Maximum histogram size
-/// For 16-bit samples: mean_square ranges from ~0 to ~1B (32768²)
-/// 10*log10(1B) ≈ 90 dB, so we need coverage from about 0 to 100 dB
-/// Size = 100 dB * 100 steps/dB = 10000, plus margin = 12000
+/// Histogram size: STEPS_per_dB * MAX_dB (120 dB) in the original
+/// Bucket i holds windows whose mean square is i / 100 dB; windows below 0 dB
+/// (including digital silence) land in bucket 0 and louder ones in the last.
 const HISTOGRAM_SIZE: usize = 12000;
 
-/// Histogram offset to map dB values to array indices
-/// For 16-bit samples, typical values are 40-90 dB (10*log10 of mean_square)
-/// Offset of 2000 allows coverage from -20 dB to +100 dB
-const HISTOGRAM_OFFSET: i32 = 2000;
-
 /// RMS percentile for loudness calculation (95th percentile)
 const RMS_PERCENTILE: f64 = 0.95;
 
 /// Histogram data for ReplayGain analysis
 /// This can be accumulated across multiple tracks for album gain calculation
-#[cfg(feature = "replaygain")]
-#[derive(Clone)]
-struct LoudnessHistogram {
+#[derive(Clone, PartialEq)]
+pub struct LoudnessHistogram {
     /// Histogram of loudness values (RMS windows bucketed by dB)
     data: Vec<u32>,
 }
 
-#[cfg(feature = "replaygain")]
+impl Default for LoudnessHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for LoudnessHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoudnessHistogram")
+            .field("windows", &self.window_count())
+            .finish()
+    }
+}
+
 impl LoudnessHistogram {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             data: vec![0; HISTOGRAM_SIZE],
         }
     }
 
+    /// Number of 50ms windows counted
+    pub fn window_count(&self) -> u64 {
+        self.data.iter().map(|&x| x as u64).sum()
+    }
+
+    /// Non-empty buckets as (bucket index, window count)
+    pub(crate) fn buckets(&self) -> impl Iterator<Item = (usize, u32)> + '_ {
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(i, &count)| (i, count))
+    }
+
+    /// Rebuild a histogram from [`buckets`](Self::buckets); `None` if an
+    /// index is out of range
+    pub(crate) fn from_buckets(buckets: impl IntoIterator<Item = (usize, u32)>) -> Option<Self> {
+        let mut histogram = Self::new();
+        for (i, count) in buckets {
+            *histogram.data.get_mut(i)? += count;
+        }
+        Some(histogram)
+    }
+
     /// Accumulate another histogram into this one (for album gain calculation)
-    fn accumulate(&mut self, other: &LoudnessHistogram) {
+    pub fn accumulate(&mut self, other: &LoudnessHistogram) {
         for (i, &count) in other.data.iter().enumerate() {
             self.data[i] += count;
         }
     }
 
     /// Calculate loudness from histogram using 95th percentile
-    fn get_loudness(&self) -> f64 {
-        let total: u64 = self.data.iter().map(|&x| x as u64).sum();
+    ///
+    /// Matches analyzeResult() in gain_analysis.c: the threshold is
+    /// `ceil(elems * (1 - 0.95))` evaluated in floating point, so it inherits
+    /// the original's rounding (100 windows give 6, not 5), and buckets are
+    /// walked from the top until that many windows have been counted.
+    ///
+    /// An empty histogram returns -20 dB; track analysis reports
+    /// [`NotEnoughSamples`] before it gets here. A histogram of one window
+    /// (a clip under 50ms) gives that window's level.
+    pub fn get_loudness(&self) -> f64 {
+        self.get_loudness_gated(None)
+    }
+
+    /// [`get_loudness`](Self::get_loudness) over the windows at or above
+    /// `gate_dbfs` only
+    ///
+    /// If every window is below the gate (a silent track), the whole
+    /// histogram is used, as without a gate.
+    pub fn get_loudness_gated(&self, gate_dbfs: Option<f64>) -> f64 {
+        self.percentile_bucket(gate_dbfs)
+            .map_or(-20.0, |i| i as f64 / STEPS_PER_DB) // -20 dB for an empty histogram
+    }
+
+    /// Bucket the loudness is read from: the one holding the 95th percentile
+    /// window, among those at or above `gate_dbfs`; `None` if empty
+    pub fn percentile_bucket(&self, gate_dbfs: Option<f64>) -> Option<usize> {
+        let first = gate_dbfs
+            .map(|gate| ((FULL_SCALE_DB + gate) * STEPS_PER_DB).ceil().max(0.0) as usize)
+            .map(|i| i.min(HISTOGRAM_SIZE - 1))
+            .filter(|&i| self.data[i..].iter().any(|&count| count > 0))
+            .unwrap_or(0);
+
+        let total: u64 = self.data[first..].iter().map(|&x| x as u64).sum();
         if total == 0 {
-            return -20.0; // Default for empty histogram
+            return None;
         }
 
         let threshold = ((total as f64) * (1.0 - RMS_PERCENTILE)).ceil() as u64;
         let mut count = 0u64;
 
-        for i in (0..HISTOGRAM_SIZE).rev() {
+        for i in (first..HISTOGRAM_SIZE).rev() {
             count += self.data[i] as u64;
             if count >= threshold {
-                return (i as i32 - HISTOGRAM_OFFSET) as f64 / STEPS_PER_DB;
+                return Some(i);
             }
         }
 
-        -20.0 // Default for lowest values
+        Some(0) // Unreachable: the threshold never exceeds the total
     }
 }
 
+/// Samples in a 50ms RMS window, rounded up as in the original (552 at 11025 Hz)
+pub fn window_samples(sample_rate: u32) -> usize {
+    (sample_rate as usize * 50).div_ceil(1000)
+}
+
 /// Analyzer state for accumulating samples across buffers
 #[cfg(feature = "replaygain")]
 struct ReplayGainAnalyzer {
@@ -695,22 +1137,38 @@ struct ReplayGainAnalyzer {
     window_samples: usize,
     /// Histogram of loudness values
     histogram: LoudnessHistogram,
+    /// Samples still to leave out at the start ([`SkipRegions::intro_secs`])
+    intro_left: usize,
+    /// Samples to leave out at the end ([`SkipRegions::outro_secs`])
+    outro_samples: usize,
+    /// Histogram buckets of the last windows, held back from the histogram
+    /// until later windows show they end before the outro
+    held: VecDeque<usize>,
 }
 
 #[cfg(feature = "replaygain")]
 impl ReplayGainAnalyzer {
     fn new(sample_rate: u32) -> Self {
-        // 50ms window
-        let window_samples = (sample_rate as usize * 50) / 1000;
+        let window_samples = window_samples(sample_rate);
         Self {
             lsum: 0.0,
             rsum: 0.0,
             totsamp: 0,
             window_samples,
             histogram: LoudnessHistogram::new(),
+            intro_left: 0,
+            outro_samples: 0,
+            held: VecDeque::new(),
         }
     }
 
+    /// Leave `skip` out of the histogram from here on
+    fn skip(&mut self, skip: SkipRegions, sample_rate: u32) {
+        let to_samples = |secs: f64| (secs.max(0.0) * sample_rate as f64).round() as usize;
+        self.intro_left = to_samples(skip.intro_secs);
+        self.outro_samples = to_samples(skip.outro_secs);
+    }
+
     /// Get a reference to the histogram for accumulation
     fn get_histogram(&self) -> &LoudnessHistogram {
         &self.histogram
@@ -718,6 +1176,10 @@ impl ReplayGainAnalyzer {
 
     /// Add a stereo sample pair (already filtered)
     fn add_sample(&mut self, left: f64, right: f64) {
+        if self.intro_left > 0 {
+            self.intro_left -= 1;
+            return;
+        }
         self.lsum += left * left;
         self.rsum += right * right;
         self.totsamp += 1;
@@ -729,6 +1191,10 @@ impl ReplayGainAnalyzer {
 
     /// Add a mono sample (already filtered)
     fn add_mono_sample(&mut self, sample: f64) {
+        if self.intro_left > 0 {
+            self.intro_left -= 1;
+            return;
+        }
         let sq = sample * sample;
         self.lsum += sq;
         self.rsum += sq;
@@ -749,12 +1215,19 @@ impl ReplayGainAnalyzer {
         // Original: (lsum + rsum) / totsamp * 0.5
         let mean_square = (self.lsum + self.rsum) / self.totsamp as f64 * 0.5;
 
-        // Convert to histogram index
+        // Convert to histogram index, clamped so every window is counted
         // Original: STEPS_per_dB * 10.0 * log10(mean_square + 1e-37)
+        //           ival = (val <= 0) ? 0 : (int) val, capped at the last bucket
         let val = STEPS_PER_DB * 10.0 * (mean_square + 1e-37).log10();
-        let idx = (val as i32 + HISTOGRAM_OFFSET) as usize;
-
-        if idx < HISTOGRAM_SIZE {
+        let idx = if val <= 0.0 { 0 } else { val as usize };
+        let idx = idx.min(HISTOGRAM_SIZE - 1);
+        if self.outro_samples > 0 {
+            self.held.push_back(idx);
+            if self.held.len() > self.outro_samples.div_ceil(self.window_samples) {
+                let idx = self.held.pop_front().unwrap();
+                self.histogram.data[idx] += 1;
+            }
+        } else {
             self.histogram.data[idx] += 1;
         }
 
@@ -768,48 +1241,165 @@ impl ReplayGainAnalyzer {
     fn get_loudness(&self) -> f64 {
         self.histogram.get_loudness()
     }
+
+    /// Loudness and suggested gain at the end of a stream with this `peak`
+    ///
+    /// A partial last window is discarded, as GetTitleGain() does in the
+    /// original, so results match mp3gain for anything 50ms or longer. A
+    /// shorter clip has no complete window, so its samples form the only one
+    /// instead, averaged over the samples it has. One short window says
+    /// little about loudness, so for such a clip the gain is also capped where
+    /// its peak reaches full scale. Returns [`NotEnoughSamples`] if nothing
+    /// is left to measure.
+    fn finish(&mut self, peak: f64) -> Result<(f64, f64)> {
+        // The partial last window is the end of the outro, so only the held
+        // windows that reach into the rest of it are dropped
+        let outro_windows = self
+            .outro_samples
+            .saturating_sub(self.totsamp)
+            .div_ceil(self.window_samples);
+        let keep = self.held.len().saturating_sub(outro_windows);
+        for idx in self.held.drain(..).take(keep) {
+            self.histogram.data[idx] += 1;
+        }
+
+        let short_clip = self.histogram.window_count() == 0;
+        if short_clip {
+            if self.totsamp == 0 || self.outro_samples > 0 {
+                return Err(NotEnoughSamples.into());
+            }
+            self.finish_window();
+        }
+
+        let loudness_db = self.get_loudness();
+        let mut gain_db = PINK_REF - loudness_db;
+        if short_clip && peak > 0.0 {
+            gain_db = gain_db.min(-20.0 * peak.log10());
+        }
+        Ok((loudness_db, gain_db))
+    }
 }
 
 // =============================================================================
 // Main analysis functions
 // =============================================================================
 
-/// Detect file type from path
+/// Detect file type from its contents, taking anything unrecognized as MP3
 #[cfg(feature = "replaygain")]
 fn detect_file_type(file_path: &Path) -> AudioFileType {
-    if mp4meta::is_mp4_file(file_path) {
-        AudioFileType::Aac
-    } else {
-        AudioFileType::Mp3
-    }
+    crate::detect_audio_type(file_path).unwrap_or(AudioFileType::Mp3)
 }
 
-/// Internal result containing both ReplayGainResult and histogram for album calculation
+/// Bytes searched past an ID3v2 tag for the first MP3 frame
 #[cfg(feature = "replaygain")]
-struct TrackAnalysisInternal {
-    result: ReplayGainResult,
-    histogram: LoudnessHistogram,
+const FIRST_FRAME_SCAN: u64 = 64 * 1024;
+
+/// Channel mode of an MP3 from its first frame header
+///
+/// Unlike [`crate::analyze`], which scans every frame, this reads only the
+/// ID3v2 tag header and at most [`FIRST_FRAME_SCAN`] bytes after it.
+#[cfg(feature = "replaygain")]
+fn mp3_file_channel_mode(file_path: &Path) -> Option<&'static str> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = std::fs::File::open(file_path).ok()?;
+    let mut header = Vec::new();
+    (&mut file).take(10).read_to_end(&mut header).ok()?;
+    file.seek(SeekFrom::Start(crate::skip_id3v2(&header) as u64))
+        .ok()?;
+    let mut data = Vec::new();
+    file.take(FIRST_FRAME_SCAN).read_to_end(&mut data).ok()?;
+    first_frame_channel_mode(&data)
 }
 
-/// Internal function to analyze a track and return both result and histogram
+/// Channel mode of the first frame in MP3 data
 #[cfg(feature = "replaygain")]
-fn analyze_track_internal(
-    file_path: &Path,
-    track_index: Option<u32>,
-) -> Result<TrackAnalysisInternal> {
-    // Detect file type
-    let file_type = detect_file_type(file_path);
+fn first_frame_channel_mode(data: &[u8]) -> Option<&'static str> {
+    let mut mode = None;
+    let _ = crate::iterate_frames(data, |_, header, _| {
+        mode.get_or_insert(header.channel_mode.as_str());
+    });
+    mode
+}
 
-    // Open the media source
-    let file = std::fs::File::open(file_path)
-        .with_context(|| format!("Failed to open: {}", file_path.display()))?;
+/// Audio to decode: a file, or a whole stream held in memory
+#[cfg(feature = "replaygain")]
+enum AudioInput<'a> {
+    File(&'a Path),
+    /// Standard input and other streams that can't seek, read in full first
+    Memory(Arc<[u8]>),
+}
 
-    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+#[cfg(feature = "replaygain")]
+impl AudioInput<'_> {
+    /// Format detected from the contents
+    fn detected_type(&self) -> Option<AudioFileType> {
+        match self {
+            AudioInput::File(path) => crate::detect_audio_type(path),
+            AudioInput::Memory(data) => crate::detect_audio_type_data(data),
+        }
+    }
+
+    /// Name for error messages
+    fn name(&self) -> std::borrow::Cow<'_, str> {
+        match self {
+            AudioInput::File(path) => path.to_string_lossy(),
+            AudioInput::Memory(_) => "<stdin>".into(),
+        }
+    }
+
+    fn media_source(&self) -> Result<Box<dyn MediaSource>> {
+        match self {
+            AudioInput::File(path) => {
+                let file = std::fs::File::open(path)
+                    .with_context(|| format!("Failed to open: {}", path.display()))?;
+                Ok(Box::new(file))
+            }
+            AudioInput::Memory(data) => Ok(Box::new(std::io::Cursor::new(Arc::clone(data)))),
+        }
+    }
+
+    /// Channel mode of an MP3 from its first frame header
+    fn mp3_channel_mode(&self) -> Option<&'static str> {
+        match self {
+            AudioInput::File(path) => mp3_file_channel_mode(path),
+            AudioInput::Memory(data) => first_frame_channel_mode(data),
+        }
+    }
+}
+
+/// Open a file with symphonia and select an audio track by index (default: first)
+#[cfg(feature = "replaygain")]
+fn open_audio_track(
+    input: &AudioInput,
+    track_index: Option<u32>,
+) -> Result<(Box<dyn FormatReader>, Track)> {
+    // Open the media source
+    let mss = MediaSourceStream::new(input.media_source()?, Default::default());
 
-    // Probe the format
+    // Probe the format, hinting at what the contents are rather than what
+    // the extension claims
     let mut hint = Hint::new();
-    if let Some(ext) = file_path.extension().and_then(|e| e.to_str()) {
-        hint.with_extension(ext);
+    match input.detected_type() {
+        Some(AudioFileType::Mp3) => {
+            hint.with_extension("mp3");
+        }
+        Some(AudioFileType::Aac) => {
+            hint.with_extension("m4a");
+        }
+        Some(AudioFileType::AacAdts) => {
+            hint.with_extension("aac");
+        }
+        Some(AudioFileType::Flac) => {
+            hint.with_extension("flac");
+        }
+        None => {
+            if let AudioInput::File(path) = input {
+                if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+                    hint.with_extension(ext);
+                }
+            }
+        }
     }
 
     let probed = symphonia::default::get_probe()
@@ -819,9 +1409,9 @@ fn analyze_track_internal(
             &FormatOptions::default(),
             &MetadataOptions::default(),
         )
-        .with_context(|| format!("Failed to probe format: {}", file_path.display()))?;
+        .with_context(|| format!("Failed to probe format: {}", input.name()))?;
 
-    let mut format = probed.format;
+    let format = probed.format;
 
     // Find audio tracks
     let audio_tracks: Vec<_> = format
@@ -849,81 +1439,240 @@ fn analyze_track_internal(
         }
         None => audio_tracks[0],
     };
+    let track = track.clone();
 
-    let track_id = track.id;
-    let sample_rate = track
-        .codec_params
+    Ok((format, track))
+}
+
+/// Basic stream properties, read from container/codec parameters without decoding
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    /// Detected file type
+    pub file_type: AudioFileType,
+    /// Sample rate in Hz
+    pub sample_rate: u32,
+    /// Number of channels
+    pub channels: usize,
+    /// Duration in seconds, if the container reports the frame count
+    pub duration_secs: Option<f64>,
+}
+
+/// Read sample rate, channel count and duration of an audio track
+#[cfg(feature = "replaygain")]
+pub fn stream_info(file_path: &Path, track_index: Option<u32>) -> Result<StreamInfo> {
+    let (_, track) = open_audio_track(&AudioInput::File(file_path), track_index)?;
+    let params = &track.codec_params;
+    let sample_rate = params
         .sample_rate
         .ok_or_else(|| anyhow::anyhow!("Unknown sample rate"))?;
-    let channels = track.codec_params.channels.map(|c| c.count()).unwrap_or(2);
 
-    // Create decoder
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &DecoderOptions::default())
-        .with_context(|| "Failed to create decoder")?;
+    Ok(StreamInfo {
+        file_type: detect_file_type(file_path),
+        sample_rate,
+        channels: params.channels.map(|c| c.count()).unwrap_or(2),
+        duration_secs: params
+            .n_frames
+            .map(|frames| frames as f64 / sample_rate as f64),
+    })
+}
 
-    // Create filter for each channel
-    let mut filters: Vec<EqualLoudnessFilter> = (0..channels)
-        .map(|_| {
-            EqualLoudnessFilter::new(sample_rate).ok_or_else(|| {
-                anyhow::anyhow!(
-                    "Unsupported sample rate: {} Hz. Supported rates: 96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000",
-                    sample_rate
-                )
-            })
-        })
-        .collect::<Result<Vec<_>>>()?;
+/// Analyze a track and return both result and histogram
+#[cfg(feature = "replaygain")]
+fn analyze_track_internal(
+    input: &AudioInput,
+    track_index: Option<u32>,
+    best_effort: bool,
+    skip: SkipRegions,
+    downmix: Downmix,
+    cancel: &CancellationToken,
+    progress: &mut dyn FnMut(f32),
+) -> Result<TrackAnalysis> {
+    analyze_stream_internal(
+        std::slice::from_ref(input),
+        track_index,
+        best_effort,
+        skip,
+        downmix,
+        cancel,
+        progress,
+    )
+}
 
-    let mut analyzer = ReplayGainAnalyzer::new(sample_rate);
-    let mut peak: f64 = 0.0;
+/// Analyze `inputs` one after another as a single stream
+///
+/// The filters and analyzer carry over from one input to the next, as they
+/// would through the inputs joined end to end; the result (file type and
+/// channel layout included) is described from the first input.
+#[cfg(feature = "replaygain")]
+fn analyze_stream_internal(
+    inputs: &[AudioInput],
+    track_index: Option<u32>,
+    best_effort: bool,
+    skip: SkipRegions,
+    downmix: Downmix,
+    cancel: &CancellationToken,
+    progress: &mut dyn FnMut(f32),
+) -> Result<TrackAnalysis> {
+    let Some(first) = inputs.first() else {
+        return Err(NotEnoughSamples.into());
+    };
+    // Detect file type, taking anything unrecognized as MP3
+    let file_type = first.detected_type().unwrap_or(AudioFileType::Mp3);
 
-    // Process all packets
-    loop {
-        let packet = match format.next_packet() {
-            Ok(p) => p,
-            Err(symphonia::core::errors::Error::IoError(e))
-                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
-            {
-                break;
-            }
-            Err(e) => return Err(e.into()),
-        };
+    let mut state: Option<(Vec<EqualLoudnessFilter>, ReplayGainAnalyzer)> = None;
+    let mut stream_params: (Option<u32>, Option<usize>) = (None, None);
+    let mut peak: f64 = 0.0;
+    let mut partial = false;
 
-        if packet.track_id() != track_id {
-            continue;
-        }
+    for (index, input) in inputs.iter().enumerate() {
+        let (mut format, track) = open_audio_track(input, track_index)?;
 
-        let decoded = match decoder.decode(&packet) {
-            Ok(d) => d,
-            Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
-            Err(e) => return Err(e.into()),
-        };
+        let track_id = track.id;
+        let total_frames = track.codec_params.n_frames.filter(|&n| n > 0);
 
-        // Process audio buffer
-        process_audio_buffer(&decoded, &mut filters, &mut analyzer, &mut peak);
-    }
+        // Create decoder
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .with_context(|| "Failed to create decoder")?;
 
-    // Finish any remaining samples in the last window
-    analyzer.finish_window();
+        // Some containers only reveal the sample rate or channel count in the
+        // decoded audio, so the filters are set up with the first buffer when the
+        // codec parameters leave them out
+        let mut params = (
+            track.codec_params.sample_rate,
+            track.codec_params.channels.map(|c| c.count()),
+        );
+        let mut checked = false;
+
+        // Process all packets
+        loop {
+            cancel.check()?;
+
+            let packet = match format.next_packet() {
+                Ok(p) => p,
+                Err(symphonia::core::errors::Error::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                // The reader can't be trusted to find the next packet, so a best
+                // effort ends with what was decoded so far
+                Err(_) if best_effort => {
+                    partial = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
 
-    // Calculate loudness and gain
-    let loudness_db = analyzer.get_loudness();
-    let gain_db = PINK_REF - loudness_db;
+            if packet.track_id() != track_id {
+                continue;
+            }
+            if let Some(total) = total_frames {
+                let done = (packet.ts() + packet.dur()).min(total);
+                progress((index as f32 + done as f32 / total as f32) / inputs.len() as f32);
+            }
 
-    let result = ReplayGainResult {
+            let decoded = match decoder.decode(&packet) {
+                Ok(d) => d,
+                Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
+                Err(_) if best_effort => {
+                    partial = true;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if !checked {
+                let spec = decoded.spec();
+                let sample_rate = *params.0.get_or_insert(spec.rate);
+                let channels = *params.1.get_or_insert(spec.channels.count());
+                match stream_params {
+                    (Some(rate), Some(count)) if (rate, count) != (sample_rate, channels) => {
+                        anyhow::bail!(
+                            "{} is {} Hz with {} channel(s), but the stream so far is {} Hz with {} channel(s)",
+                            input.name(),
+                            sample_rate,
+                            channels,
+                            rate,
+                            count
+                        );
+                    }
+                    (Some(_), Some(_)) => {}
+                    _ => {
+                        let (filters, mut analyzer) =
+                            analysis_state(sample_rate, downmix.analyzed_channels(channels))?;
+                        analyzer.skip(skip, sample_rate);
+                        state = Some((filters, analyzer));
+                        stream_params = (Some(sample_rate), Some(channels));
+                    }
+                }
+                checked = true;
+            }
+            let (filters, analyzer) = state.as_mut().unwrap();
+
+            // Process audio buffer
+            process_audio_buffer(&decoded, downmix, filters, analyzer, &mut peak);
+        }
+    }
+
+    let (Some(sample_rate), Some(channels), Some((_, mut analyzer))) =
+        (stream_params.0, stream_params.1, state)
+    else {
+        return Err(NotEnoughSamples.into());
+    };
+    let (loudness_db, gain_db) = analyzer.finish(peak)?;
+
+    // The decoder reports Dual Channel MP3 as plain stereo, so check the frame headers
+    let mut channel_layout = ChannelLayout::from_channel_count(channels);
+    if file_type == AudioFileType::Mp3
+        && channel_layout == ChannelLayout::Stereo
+        && first.mp3_channel_mode() == Some("Dual Channel")
+    {
+        channel_layout = ChannelLayout::DualChannel;
+    }
+
+    let result = ReplayGainResult {
         loudness_db,
         gain_db,
         peak,
         sample_rate,
-        file_type,
+        file_type: Some(file_type),
+        channel_layout,
+        method: AnalysisMethod::Decoded,
+        partial,
     };
 
-    Ok(TrackAnalysisInternal {
+    Ok(TrackAnalysis {
         result,
         histogram: analyzer.get_histogram().clone(),
     })
 }
 
+/// Equal-loudness filters (one per channel) and the analyzer for a stream
+#[cfg(feature = "replaygain")]
+fn analysis_state(
+    sample_rate: u32,
+    channels: usize,
+) -> Result<(Vec<EqualLoudnessFilter>, ReplayGainAnalyzer)> {
+    let filters = (0..channels.max(1))
+        .map(|_| {
+            EqualLoudnessFilter::new(sample_rate).ok_or_else(|| {
+                let rates: Vec<String> = supported_sample_rates()
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect();
+                anyhow::anyhow!(
+                    "Unsupported sample rate: {} Hz. Supported rates: {}",
+                    sample_rate,
+                    rates.join(", ")
+                )
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((filters, ReplayGainAnalyzer::new(sample_rate)))
+}
+
 /// Analyze a single track and calculate ReplayGain
 #[cfg(feature = "replaygain")]
 pub fn analyze_track(file_path: &Path) -> Result<ReplayGainResult> {
@@ -936,10 +1685,220 @@ pub fn analyze_track_with_index(
     file_path: &Path,
     track_index: Option<u32>,
 ) -> Result<ReplayGainResult> {
-    let internal = analyze_track_internal(file_path, track_index)?;
+    analyze_track_cancellable(file_path, track_index, &CancellationToken::new(), |_| {})
+}
+
+/// Analyze a single track with cancellation and progress reporting
+///
+/// `cancel` is checked between decoded packets; when set, an
+/// [`Interrupted`](crate::Interrupted) error is returned. `progress` is called
+/// as decoding advances with the fraction of the track done (0.0 to 1.0), if
+/// the track length is known.
+#[cfg(feature = "replaygain")]
+pub fn analyze_track_cancellable(
+    file_path: &Path,
+    track_index: Option<u32>,
+    cancel: &CancellationToken,
+    mut progress: impl FnMut(f32),
+) -> Result<ReplayGainResult> {
+    let internal = analyze_track_internal(
+        &AudioInput::File(file_path),
+        track_index,
+        false,
+        SkipRegions::default(),
+        Downmix::None,
+        cancel,
+        &mut progress,
+    )?;
+    Ok(internal.result)
+}
+
+/// Analyze a single track held in memory, such as audio read from standard input
+///
+/// Decoding needs to seek, which a pipe can't, so the caller buffers the
+/// whole stream first: memory use grows with the length of the audio, about
+/// 1 MB per minute at 128 kbps.
+#[cfg(feature = "replaygain")]
+pub fn analyze_track_data(data: Vec<u8>, track_index: Option<u32>) -> Result<ReplayGainResult> {
+    let internal = analyze_track_internal(
+        &AudioInput::Memory(data.into()),
+        track_index,
+        false,
+        SkipRegions::default(),
+        Downmix::None,
+        &CancellationToken::new(),
+        &mut |_| {},
+    )?;
     Ok(internal.result)
 }
 
+/// Analyze a single track, keeping its loudness histogram
+///
+/// Like [`analyze_track_with_index`]; the histogram lets the track join an
+/// album with [`AlbumGainResult::add_track`] without decoding it again.
+#[cfg(feature = "replaygain")]
+pub fn analyze_track_histogram(
+    file_path: &Path,
+    track_index: Option<u32>,
+) -> Result<TrackAnalysis> {
+    analyze_track_internal(
+        &AudioInput::File(file_path),
+        track_index,
+        false,
+        SkipRegions::default(),
+        Downmix::None,
+        &CancellationToken::new(),
+        &mut |_| {},
+    )
+}
+
+/// Analyze a single track, leaving out packets that fail to read or decode
+///
+/// Like [`analyze_track_histogram`], but a damaged stream gives a result from
+/// its decodable portion instead of an error: packets the decoder rejects for
+/// any reason are skipped, and a read error ends the analysis early. Such
+/// results have `partial` set. Malformed packets are skipped in every mode;
+/// only the failures that otherwise abort the analysis count as partial.
+#[cfg(feature = "replaygain")]
+pub fn analyze_track_best_effort(
+    file_path: &Path,
+    track_index: Option<u32>,
+) -> Result<TrackAnalysis> {
+    analyze_track_internal(
+        &AudioInput::File(file_path),
+        track_index,
+        true,
+        SkipRegions::default(),
+        Downmix::None,
+        &CancellationToken::new(),
+        &mut |_| {},
+    )
+}
+
+/// Analyze a single track, leaving `skip` out of its loudness
+///
+/// Like [`analyze_track_histogram`], or [`analyze_track_best_effort`] with
+/// `best_effort`. The histogram only holds the windows outside `skip`, so
+/// an album built from such tracks skips the same regions.
+#[cfg(feature = "replaygain")]
+pub fn analyze_track_skipping(
+    file_path: &Path,
+    track_index: Option<u32>,
+    skip: SkipRegions,
+    best_effort: bool,
+) -> Result<TrackAnalysis> {
+    analyze_track_downmixed(file_path, track_index, skip, Downmix::None, best_effort)
+}
+
+/// Analyze a single track in the channel layout `downmix`, leaving `skip`
+/// out of its loudness
+///
+/// Like [`analyze_track_skipping`]. The peak is still that of the channels
+/// as stored. Tracks of an album should all use the same `downmix`.
+#[cfg(feature = "replaygain")]
+pub fn analyze_track_downmixed(
+    file_path: &Path,
+    track_index: Option<u32>,
+    skip: SkipRegions,
+    downmix: Downmix,
+    best_effort: bool,
+) -> Result<TrackAnalysis> {
+    analyze_track_internal(
+        &AudioInput::File(file_path),
+        track_index,
+        best_effort,
+        skip,
+        downmix,
+        &CancellationToken::new(),
+        &mut |_| {},
+    )
+}
+
+/// Analyze several files as one continuous stream, as if joined end to end
+///
+/// Unlike album analysis, which pools the loudness windows of separately
+/// analyzed tracks, the samples of all files go through a single analyzer.
+/// The equal-loudness filter state and the partly filled 50 ms window carry
+/// over from each file into the next rather than being reset, so the result
+/// matches analyzing the concatenated audio: resetting would add a filter
+/// transient at every join and drop the last window of each file. All files
+/// must share the first one's sample rate and channel count. The peak is the
+/// highest of all files; `skip` applies to the start of the first file and the
+/// end of the last.
+#[cfg(feature = "replaygain")]
+pub fn analyze_stream(
+    files: &[&Path],
+    track_index: Option<u32>,
+    skip: SkipRegions,
+    downmix: Downmix,
+) -> Result<TrackAnalysis> {
+    let inputs: Vec<AudioInput> = files.iter().map(|file| AudioInput::File(file)).collect();
+    analyze_stream_internal(
+        &inputs,
+        track_index,
+        false,
+        skip,
+        downmix,
+        &CancellationToken::new(),
+        &mut |_| {},
+    )
+}
+
+/// Analyze a single track, estimating from MP3 frames if it can't be decoded
+///
+/// Some unusual MP3s that the frame parser handles fail symphonia's decoder.
+/// For those, the peak is estimated from the maximum global_gain and the gain
+/// suggested is the headroom that leaves that peak at full scale. Such results
+/// have `method` set to [`AnalysisMethod::Heuristic`] and are only approximate.
+/// Other errors (and non-MP3 files) are returned unchanged, including
+/// [`NotEnoughSamples`] for a track that decodes to no samples.
+#[cfg(feature = "replaygain")]
+pub fn analyze_track_or_estimate(
+    file_path: &Path,
+    track_index: Option<u32>,
+) -> Result<ReplayGainResult> {
+    let decode_error = match analyze_track_with_index(file_path, track_index) {
+        Ok(result) => return Ok(result),
+        Err(e) => e,
+    };
+    if detect_file_type(file_path) != AudioFileType::Mp3
+        || decode_error.downcast_ref::<NotEnoughSamples>().is_some()
+    {
+        return Err(decode_error);
+    }
+
+    estimate_track(file_path).map_err(|_| decode_error)
+}
+
+/// Estimate an MP3's peak and gain from its frames, without decoding
+///
+/// The peak is estimated from the maximum global_gain and the gain suggested
+/// is the headroom that leaves that peak at full scale, as
+/// [`analyze_track_or_estimate`] does for files that can't be decoded.
+/// Results have `method` set to [`AnalysisMethod::Heuristic`] and are only
+/// approximate; compare them with a decoded analysis to see how far off.
+pub fn estimate_track(file_path: &Path) -> Result<ReplayGainResult> {
+    let analysis = crate::analyze(file_path)?;
+    let peak = crate::estimate_peak_from_gain(analysis.max_gain);
+    let gain_db = -20.0 * peak.log10();
+    let channel_layout = match analysis.channel_mode.as_str() {
+        "Mono" => ChannelLayout::Mono,
+        "Dual Channel" => ChannelLayout::DualChannel,
+        _ => ChannelLayout::Stereo,
+    };
+
+    Ok(ReplayGainResult {
+        loudness_db: PINK_REF - gain_db,
+        gain_db,
+        peak,
+        sample_rate: analysis.sample_rate,
+        file_type: Some(AudioFileType::Mp3),
+        channel_layout,
+        method: AnalysisMethod::Heuristic,
+        partial: false,
+    })
+}
+
 /// Scale factor to convert normalized float samples to 16-bit integer range.
 /// The original ReplayGain algorithm (and its PINK_REF calibration constant of 64.82)
 /// was designed for non-normalized 16-bit integer samples (-32768 to 32767).
@@ -949,82 +1908,212 @@ pub fn analyze_track_with_index(
 const SAMPLE_SCALE_16BIT: f64 = 32768.0;
 
 /// Process an audio buffer and feed filtered samples to the analyzer
+///
+/// Every sample format symphonia can produce is handled, so no decoded
+/// frames are dropped from the analysis.
 #[cfg(feature = "replaygain")]
 fn process_audio_buffer(
     buffer: &AudioBufferRef,
+    downmix: Downmix,
     filters: &mut [EqualLoudnessFilter],
     analyzer: &mut ReplayGainAnalyzer,
     peak: &mut f64,
 ) {
     match buffer {
-        AudioBufferRef::F32(buf) => {
-            let channels = buf.spec().channels.count();
-            let frames = buf.frames();
-
-            for frame in 0..frames {
-                // Get normalized sample and track peak (in normalized range for peak reporting)
-                let left_norm = buf.chan(0)[frame] as f64;
-                *peak = peak.max(left_norm.abs());
-                // Scale to 16-bit range for ReplayGain algorithm compatibility
-                let left_filtered = filters[0].process(left_norm * SAMPLE_SCALE_16BIT);
-
-                if channels >= 2 {
-                    let right_norm = buf.chan(1)[frame] as f64;
-                    *peak = peak.max(right_norm.abs());
-                    let right_filtered = filters[1].process(right_norm * SAMPLE_SCALE_16BIT);
-                    analyzer.add_sample(left_filtered, right_filtered);
-                } else {
-                    analyzer.add_mono_sample(left_filtered);
-                }
-            }
+        AudioBufferRef::U8(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::U16(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::U24(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::U32(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::S8(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::S16(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::S24(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::S32(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::F32(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+        AudioBufferRef::F64(buf) => process_samples(buf, downmix, filters, analyzer, peak),
+    }
+}
+
+/// Feed one buffer of any sample format to the analyzer
+///
+/// Samples are converted to the normalized range (-1.0 to 1.0) for peak
+/// reporting, then scaled to the 16-bit range for the ReplayGain algorithm.
+/// Integer formats convert by power-of-two division, so S16 and S32 input
+/// reaches the filters unchanged from a direct integer scaling.
+#[cfg(feature = "replaygain")]
+fn process_samples<S: Sample + IntoSample<f64>>(
+    buf: &AudioBuffer<S>,
+    downmix: Downmix,
+    filters: &mut [EqualLoudnessFilter],
+    analyzer: &mut ReplayGainAnalyzer,
+    peak: &mut f64,
+) {
+    let channels = buf.spec().channels.count();
+
+    for frame in 0..buf.frames() {
+        let left: f64 = buf.chan(0)[frame].into_sample();
+        let right: Option<f64> = (channels >= 2).then(|| buf.chan(1)[frame].into_sample());
+        // The peak is about clipping the file, so it comes from the samples
+        // as stored rather than the fold-down
+        *peak = peak.max(left.abs()).max(right.map_or(0.0, f64::abs));
+        let (left, right) = downmix.apply(left, right);
+        add_frame(filters, analyzer, peak, left, right);
+    }
+}
+
+/// Filter one frame of normalized samples and add it to the analysis
+#[cfg(feature = "replaygain")]
+fn add_frame(
+    filters: &mut [EqualLoudnessFilter],
+    analyzer: &mut ReplayGainAnalyzer,
+    peak: &mut f64,
+    left: f64,
+    right: Option<f64>,
+) {
+    *peak = peak.max(left.abs());
+    let left_filtered = filters[0].process(left * SAMPLE_SCALE_16BIT);
+
+    match right {
+        Some(right) => {
+            *peak = peak.max(right.abs());
+            let right_filtered = filters[1].process(right * SAMPLE_SCALE_16BIT);
+            analyzer.add_sample(left_filtered, right_filtered);
         }
-        AudioBufferRef::S16(buf) => {
-            let channels = buf.spec().channels.count();
-            let frames = buf.frames();
-
-            for frame in 0..frames {
-                // S16 samples are already in the correct range for ReplayGain algorithm
-                // Convert to f64 directly without normalization for filter processing
-                let left = buf.chan(0)[frame] as f64;
-                // Track peak in normalized range (0.0 to 1.0)
-                *peak = peak.max((left / SAMPLE_SCALE_16BIT).abs());
-                let left_filtered = filters[0].process(left);
-
-                if channels >= 2 {
-                    let right = buf.chan(1)[frame] as f64;
-                    *peak = peak.max((right / SAMPLE_SCALE_16BIT).abs());
-                    let right_filtered = filters[1].process(right);
-                    analyzer.add_sample(left_filtered, right_filtered);
-                } else {
-                    analyzer.add_mono_sample(left_filtered);
-                }
+        None => analyzer.add_mono_sample(left_filtered),
+    }
+}
+
+/// Largest normalized sample magnitude across all channels of a buffer
+#[cfg(feature = "replaygain")]
+fn buffer_peak(buffer: &AudioBufferRef) -> f64 {
+    fn peak_of<S: Sample + IntoSample<f64>>(buf: &AudioBuffer<S>) -> f64 {
+        let mut peak: f64 = 0.0;
+        for ch in 0..buf.spec().channels.count() {
+            for &sample in buf.chan(ch) {
+                let sample: f64 = sample.into_sample();
+                peak = peak.max(sample.abs());
             }
         }
-        AudioBufferRef::S32(buf) => {
-            let channels = buf.spec().channels.count();
-            let frames = buf.frames();
-            // Scale S32 to 16-bit range: divide by 2^16 to go from 32-bit to 16-bit range
-            let scale = SAMPLE_SCALE_16BIT / 2147483648.0;
-
-            for frame in 0..frames {
-                let left = buf.chan(0)[frame] as f64 * scale;
-                // Track peak in normalized range
-                *peak = peak.max((left / SAMPLE_SCALE_16BIT).abs());
-                let left_filtered = filters[0].process(left);
-
-                if channels >= 2 {
-                    let right = buf.chan(1)[frame] as f64 * scale;
-                    *peak = peak.max((right / SAMPLE_SCALE_16BIT).abs());
-                    let right_filtered = filters[1].process(right);
-                    analyzer.add_sample(left_filtered, right_filtered);
-                } else {
-                    analyzer.add_mono_sample(left_filtered);
-                }
+        peak
+    }
+
+    match buffer {
+        AudioBufferRef::U8(buf) => peak_of(buf),
+        AudioBufferRef::U16(buf) => peak_of(buf),
+        AudioBufferRef::U24(buf) => peak_of(buf),
+        AudioBufferRef::U32(buf) => peak_of(buf),
+        AudioBufferRef::S8(buf) => peak_of(buf),
+        AudioBufferRef::S16(buf) => peak_of(buf),
+        AudioBufferRef::S24(buf) => peak_of(buf),
+        AudioBufferRef::S32(buf) => peak_of(buf),
+        AudioBufferRef::F32(buf) => peak_of(buf),
+        AudioBufferRef::F64(buf) => peak_of(buf),
+    }
+}
+
+/// ReplayGain analysis of already-decoded PCM samples
+///
+/// For callers with their own decoder: push interleaved samples in the
+/// normalized range (-1.0 to 1.0), then [`finish`](Self::finish) gives the
+/// values [`analyze_track`] reports for a file holding the same audio. As
+/// for files, only the first two channels are analyzed.
+///
+/// ```
+/// use mp3rgain::replaygain::PcmAnalyzer;
+///
+/// // Two seconds of a 1kHz stereo sine at half scale
+/// let sample_rate = 44100;
+/// let samples: Vec<f32> = (0..2 * sample_rate)
+///     .flat_map(|i| {
+///         let t = i as f32 / sample_rate as f32;
+///         let x = 0.5 * (2.0 * std::f32::consts::PI * 1000.0 * t).sin();
+///         [x, x]
+///     })
+///     .collect();
+///
+/// let mut analyzer = PcmAnalyzer::new();
+/// analyzer.push_samples(&samples, 2, sample_rate).unwrap();
+/// let result = analyzer.finish().unwrap();
+///
+/// assert!((result.peak - 0.5).abs() < 1e-3);
+/// assert!((result.loudness_db - 72.97).abs() < 0.01);
+/// // Louder than the 89 dB reference, so the gain turns it down
+/// assert!((result.gain_db + 8.15).abs() < 0.01);
+/// ```
+#[cfg(feature = "replaygain")]
+#[derive(Default)]
+pub struct PcmAnalyzer {
+    /// Channel count, sample rate and analysis state, set by the first push
+    stream: Option<(usize, u32, Vec<EqualLoudnessFilter>, ReplayGainAnalyzer)>,
+    peak: f64,
+}
+
+#[cfg(feature = "replaygain")]
+impl PcmAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add interleaved samples, `channels` per frame
+    ///
+    /// Every call must use the channel count and sample rate of the first.
+    /// Fails on an unsupported sample rate or a partial last frame.
+    pub fn push_samples(
+        &mut self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<()> {
+        if channels == 0 || !samples.len().is_multiple_of(channels) {
+            anyhow::bail!(
+                "{} samples do not make whole frames of {} channels",
+                samples.len(),
+                channels
+            );
+        }
+        let (stream_channels, stream_rate, filters, analyzer) = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                let (filters, analyzer) = analysis_state(sample_rate, channels)?;
+                self.stream
+                    .insert((channels, sample_rate, filters, analyzer))
             }
+        };
+        if (channels, sample_rate) != (*stream_channels, *stream_rate) {
+            anyhow::bail!(
+                "Stream format changed from {} channels at {} Hz to {} channels at {} Hz",
+                stream_channels,
+                stream_rate,
+                channels,
+                sample_rate
+            );
         }
-        _ => {
-            // Unsupported format, skip
+
+        for frame in samples.chunks_exact(channels) {
+            let right = frame.get(1).map(|&x| x as f64);
+            add_frame(filters, analyzer, &mut self.peak, frame[0] as f64, right);
         }
+        Ok(())
+    }
+
+    /// Loudness, gain and peak of everything pushed
+    ///
+    /// Returns [`NotEnoughSamples`] if nothing was pushed.
+    pub fn finish(self) -> Result<ReplayGainResult> {
+        let Some((channels, sample_rate, _, mut analyzer)) = self.stream else {
+            return Err(NotEnoughSamples.into());
+        };
+        let (loudness_db, gain_db) = analyzer.finish(self.peak)?;
+
+        Ok(ReplayGainResult {
+            loudness_db,
+            gain_db,
+            peak: self.peak,
+            sample_rate,
+            file_type: None,
+            channel_layout: ChannelLayout::from_channel_count(channels),
+            method: AnalysisMethod::Decoded,
+            partial: false,
+        })
     }
 }
 
@@ -1045,38 +2134,94 @@ pub fn analyze_album_with_index(
     files: &[&Path],
     track_index: Option<u32>,
 ) -> Result<AlbumGainResult> {
-    let mut track_results = Vec::with_capacity(files.len());
-    let mut album_peak: f64 = 0.0;
-    // Album histogram accumulates all track histograms (like B[] in original mp3gain)
-    let mut album_histogram = LoudnessHistogram::new();
+    analyze_album_cancellable(files, track_index, &CancellationToken::new(), |_, _| {})
+}
 
-    for file in files {
-        // Analyze each track and get histogram
-        let internal = analyze_track_internal(file, track_index)?;
-        album_peak = album_peak.max(internal.result.peak);
+/// Analyze multiple tracks for album gain with cancellation and progress reporting
+///
+/// `cancel` is checked between files and between decoded packets; when set,
+/// an [`Interrupted`](crate::Interrupted) error is returned. `progress` is
+/// called after each track with the number of analyzed tracks and the total.
+#[cfg(feature = "replaygain")]
+pub fn analyze_album_cancellable(
+    files: &[&Path],
+    track_index: Option<u32>,
+    cancel: &CancellationToken,
+    progress: impl FnMut(usize, usize),
+) -> Result<AlbumGainResult> {
+    let mut album = AlbumGainResult::default();
+    add_to_album_cancellable(&mut album, files, track_index, cancel, progress)?;
+    Ok(album)
+}
 
-        // Accumulate track histogram into album histogram
-        album_histogram.accumulate(&internal.histogram);
+/// Add tracks to an analyzed album and recompute the album gain
+///
+/// The album histogram is a sum over tracks, so only `files` are decoded; the
+/// result is the same as analyzing the old and new tracks together. New track
+/// results are appended to `album.tracks`.
+#[cfg(feature = "replaygain")]
+pub fn add_to_album(
+    album: &mut AlbumGainResult,
+    files: &[&Path],
+    track_index: Option<u32>,
+) -> Result<()> {
+    add_to_album_cancellable(
+        album,
+        files,
+        track_index,
+        &CancellationToken::new(),
+        |_, _| {},
+    )
+}
 
-        track_results.push(internal.result);
-    }
+/// [`add_to_album`] with cancellation and progress reporting
+///
+/// See [`analyze_album_cancellable`]; `progress` counts the new tracks only.
+/// On error `album` is left unchanged.
+#[cfg(feature = "replaygain")]
+pub fn add_to_album_cancellable(
+    album: &mut AlbumGainResult,
+    files: &[&Path],
+    track_index: Option<u32>,
+    cancel: &CancellationToken,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<()> {
+    let mut updated = album.clone();
+    updated.tracks.reserve(files.len());
 
-    // Calculate album loudness from combined histogram (95th percentile)
-    let album_loudness_db = album_histogram.get_loudness();
-    let album_gain_db = PINK_REF - album_loudness_db;
+    for (i, file) in files.iter().enumerate() {
+        cancel.check()?;
 
-    Ok(AlbumGainResult {
-        tracks: track_results,
-        album_loudness_db,
-        album_gain_db,
-        album_peak,
-    })
+        // Analyze each track and get histogram
+        let track = analyze_track_internal(
+            &AudioInput::File(file),
+            track_index,
+            false,
+            SkipRegions::default(),
+            Downmix::None,
+            cancel,
+            &mut |_| {},
+        )?;
+        updated.add_track(track);
+        progress(i + 1, files.len());
+    }
+
+    *album = updated;
+    Ok(())
 }
 
 // =============================================================================
 // Stub implementations when feature is disabled
 // =============================================================================
 
+#[cfg(not(feature = "replaygain"))]
+pub fn stream_info(_file_path: &Path, _track_index: Option<u32>) -> Result<StreamInfo> {
+    anyhow::bail!(
+        "Reading AAC stream info requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
 #[cfg(not(feature = "replaygain"))]
 pub fn analyze_track(_file_path: &Path) -> Result<ReplayGainResult> {
     anyhow::bail!(
@@ -1096,6 +2241,35 @@ pub fn analyze_track_with_index(
     )
 }
 
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_track_data(_data: Vec<u8>, _track_index: Option<u32>) -> Result<ReplayGainResult> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_track_cancellable(
+    _file_path: &Path,
+    _track_index: Option<u32>,
+    _cancel: &CancellationToken,
+    _progress: impl FnMut(f32),
+) -> Result<ReplayGainResult> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_track_or_estimate(
+    file_path: &Path,
+    track_index: Option<u32>,
+) -> Result<ReplayGainResult> {
+    analyze_track_with_index(file_path, track_index)
+}
+
 #[cfg(not(feature = "replaygain"))]
 pub fn analyze_album(_files: &[&Path]) -> Result<AlbumGainResult> {
     anyhow::bail!(
@@ -1115,11 +2289,128 @@ pub fn analyze_album_with_index(
     )
 }
 
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_album_cancellable(
+    _files: &[&Path],
+    _track_index: Option<u32>,
+    _cancel: &CancellationToken,
+    _progress: impl FnMut(usize, usize),
+) -> Result<AlbumGainResult> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_track_histogram(
+    _file_path: &Path,
+    _track_index: Option<u32>,
+) -> Result<TrackAnalysis> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_track_best_effort(
+    _file_path: &Path,
+    _track_index: Option<u32>,
+) -> Result<TrackAnalysis> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_track_skipping(
+    _file_path: &Path,
+    _track_index: Option<u32>,
+    _skip: SkipRegions,
+    _best_effort: bool,
+) -> Result<TrackAnalysis> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_track_downmixed(
+    _file_path: &Path,
+    _track_index: Option<u32>,
+    _skip: SkipRegions,
+    _downmix: Downmix,
+    _best_effort: bool,
+) -> Result<TrackAnalysis> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn analyze_stream(
+    _files: &[&Path],
+    _track_index: Option<u32>,
+    _skip: SkipRegions,
+    _downmix: Downmix,
+) -> Result<TrackAnalysis> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn add_to_album(
+    _album: &mut AlbumGainResult,
+    _files: &[&Path],
+    _track_index: Option<u32>,
+) -> Result<()> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn add_to_album_cancellable(
+    _album: &mut AlbumGainResult,
+    _files: &[&Path],
+    _track_index: Option<u32>,
+    _cancel: &CancellationToken,
+    _progress: impl FnMut(usize, usize),
+) -> Result<()> {
+    anyhow::bail!(
+        "ReplayGain analysis requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
 /// Check if ReplayGain feature is available
 pub fn is_available() -> bool {
     cfg!(feature = "replaygain")
 }
 
+/// Sample rates with equal-loudness filter coefficients (ReplayGain 1.0)
+const SUPPORTED_SAMPLE_RATES: [u32; 12] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000,
+];
+
+/// Sample rates that ReplayGain analysis can handle in this build
+///
+/// Returns an empty slice when the `replaygain` feature is disabled.
+pub fn supported_sample_rates() -> &'static [u32] {
+    if is_available() {
+        &SUPPORTED_SAMPLE_RATES
+    } else {
+        &[]
+    }
+}
+
 /// Result of peak amplitude analysis
 #[derive(Debug, Clone)]
 pub struct PeakAmplitudeResult {
@@ -1166,10 +2457,8 @@ pub fn find_peak_amplitude(file_path: &Path) -> Result<PeakAmplitudeResult> {
         .ok_or_else(|| anyhow::anyhow!("No audio track found"))?;
 
     let track_id = track.id;
-    let sample_rate = track
-        .codec_params
-        .sample_rate
-        .ok_or_else(|| anyhow::anyhow!("Unknown sample rate"))?;
+    // Taken from the first decoded buffer if the container leaves it out
+    let mut sample_rate = track.codec_params.sample_rate;
 
     let mut decoder = symphonia::default::get_codecs()
         .make(&track.codec_params, &DecoderOptions::default())
@@ -1207,47 +2496,188 @@ pub fn find_peak_amplitude(file_path: &Path) -> Result<PeakAmplitudeResult> {
         // The F32 buffer from Symphonia is already normalized and clipped.
         // To detect clipping, we check if the peak is exactly 1.0 (or very close),
         // which indicates the audio may have been clipped by the decoder.
-        match &decoded {
-            AudioBufferRef::F32(buf) => {
-                let channels = buf.spec().channels.count();
-                for frame in 0..buf.frames() {
-                    for ch in 0..channels {
-                        let sample = buf.chan(ch)[frame].abs() as f64;
-                        max_peak = max_peak.max(sample);
-                    }
-                }
-            }
-            AudioBufferRef::S16(buf) => {
-                let channels = buf.spec().channels.count();
-                for frame in 0..buf.frames() {
-                    for ch in 0..channels {
-                        // S16 samples: convert to normalized range
-                        // This can exceed 1.0 if sample is at max (32767/32768 ≈ 0.99997)
-                        let sample = (buf.chan(ch)[frame] as f64).abs() / SAMPLE_SCALE_16BIT;
-                        max_peak = max_peak.max(sample);
-                    }
-                }
-            }
-            AudioBufferRef::S32(buf) => {
-                let channels = buf.spec().channels.count();
-                for frame in 0..buf.frames() {
-                    for ch in 0..channels {
-                        let sample = (buf.chan(ch)[frame] as f64).abs() / 2147483648.0;
-                        max_peak = max_peak.max(sample);
-                    }
-                }
-            }
-            _ => {}
-        }
+        max_peak = max_peak.max(buffer_peak(&decoded));
+        sample_rate.get_or_insert(decoded.spec().rate);
     }
 
     Ok(PeakAmplitudeResult {
         peak: max_peak,
         peak_pcm: max_peak * SAMPLE_SCALE_16BIT,
-        sample_rate,
+        sample_rate: sample_rate.ok_or_else(|| anyhow::anyhow!("Unknown sample rate"))?,
     })
 }
 
+/// ITU-R BS.1770-4 Annex 2 interpolation filter: one row of 12 taps for
+/// each of the 4 phases of 4x oversampling
+const TRUE_PEAK_FILTER: [[f64; 12]; 4] = [
+    [
+        0.0017089843750,
+        0.0109863281250,
+        -0.0196533203125,
+        0.0332031250000,
+        -0.0594482421875,
+        0.1373291015625,
+        0.9721679687500,
+        -0.1022949218750,
+        0.0476074218750,
+        -0.0266113281250,
+        0.0148925781250,
+        -0.0083007812500,
+    ],
+    [
+        -0.0291748046875,
+        0.0292968750000,
+        -0.0517578125000,
+        0.0891113281250,
+        -0.1665039062500,
+        0.4650878906250,
+        0.7797851562500,
+        -0.2003173828125,
+        0.1015625000000,
+        -0.0582275390625,
+        0.0330810546875,
+        -0.0189208984375,
+    ],
+    [
+        -0.0189208984375,
+        0.0330810546875,
+        -0.0582275390625,
+        0.1015625000000,
+        -0.2003173828125,
+        0.7797851562500,
+        0.4650878906250,
+        -0.1665039062500,
+        0.0891113281250,
+        -0.0517578125000,
+        0.0292968750000,
+        -0.0291748046875,
+    ],
+    [
+        -0.0083007812500,
+        0.0148925781250,
+        -0.0266113281250,
+        0.0476074218750,
+        -0.1022949218750,
+        0.9721679687500,
+        0.1373291015625,
+        -0.0594482421875,
+        0.0332031250000,
+        -0.0196533203125,
+        0.0109863281250,
+        0.0017089843750,
+    ],
+];
+
+/// True-peak meter (ITU-R BS.1770-4 Annex 2)
+///
+/// Each channel is oversampled 4x to find the peaks between samples that a
+/// DAC or resampler reconstructs, which can exceed the sample peak. The
+/// result is never below the sample peak.
+#[derive(Debug, Clone, Default)]
+pub struct TruePeakDetector {
+    /// Last 12 samples of each channel, newest first
+    history: Vec<[f64; 12]>,
+    peak: f64,
+}
+
+impl TruePeakDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the next normalized sample (-1.0 to 1.0) of `channel`
+    pub fn push(&mut self, channel: usize, sample: f64) {
+        if channel >= self.history.len() {
+            self.history.resize(channel + 1, [0.0; 12]);
+        }
+        let history = &mut self.history[channel];
+        history.copy_within(0..11, 1);
+        history[0] = sample;
+
+        self.peak = self.peak.max(sample.abs());
+        for taps in &TRUE_PEAK_FILTER {
+            let interpolated: f64 = taps.iter().zip(history.iter()).map(|(t, x)| t * x).sum();
+            self.peak = self.peak.max(interpolated.abs());
+        }
+    }
+
+    /// True peak of everything pushed, including the filter's ring-out
+    /// after the last samples
+    pub fn finish(mut self) -> f64 {
+        for channel in 0..self.history.len() {
+            for _ in 0..11 {
+                self.push(channel, 0.0);
+            }
+        }
+        self.peak
+    }
+}
+
+/// Decode a track and measure its true peak (see [`TruePeakDetector`])
+///
+/// The ReplayGain 2.0 convention for peak tags, as opposed to the sample
+/// peak [`analyze_track`] reports. Costs a full decode of its own.
+#[cfg(feature = "replaygain")]
+pub fn find_true_peak(file_path: &Path, track_index: Option<u32>) -> Result<f64> {
+    fn push_all<S: Sample + IntoSample<f64>>(buf: &AudioBuffer<S>, meter: &mut TruePeakDetector) {
+        for ch in 0..buf.spec().channels.count() {
+            for &sample in buf.chan(ch) {
+                meter.push(ch, sample.into_sample());
+            }
+        }
+    }
+
+    let (mut format, track) = open_audio_track(&AudioInput::File(file_path), track_index)?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .with_context(|| "Failed to create decoder")?;
+
+    let mut meter = TruePeakDetector::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            Err(symphonia::core::errors::Error::IoError(e))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match &decoded {
+            AudioBufferRef::U8(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::U16(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::U24(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::U32(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::S8(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::S16(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::S24(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::S32(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::F32(buf) => push_all(buf, &mut meter),
+            AudioBufferRef::F64(buf) => push_all(buf, &mut meter),
+        }
+    }
+
+    Ok(meter.finish())
+}
+
+#[cfg(not(feature = "replaygain"))]
+pub fn find_true_peak(_file_path: &Path, _track_index: Option<u32>) -> Result<f64> {
+    anyhow::bail!(
+        "True peak measurement requires the 'replaygain' feature.\n\
+        Install with: cargo install mp3rgain --features replaygain"
+    )
+}
+
 #[cfg(not(feature = "replaygain"))]
 pub fn find_peak_amplitude(_file_path: &Path) -> Result<PeakAmplitudeResult> {
     anyhow::bail!(
@@ -1270,14 +2700,141 @@ mod tests {
         assert!(!available);
     }
 
+    #[test]
+    fn test_true_peak_between_samples() {
+        // A quarter-rate sine sampled 45 degrees off its crests: every sample
+        // is at 0.707, while the waveform reaches 1.0 in between
+        let mut meter = TruePeakDetector::new();
+        for n in 0..400 {
+            let sample =
+                (std::f64::consts::FRAC_PI_2 * n as f64 + std::f64::consts::FRAC_PI_4).sin();
+            meter.push(0, sample);
+            meter.push(1, 0.5 * sample);
+        }
+        let peak = meter.finish();
+        assert!((peak - 1.0).abs() < 0.02, "{}", peak);
+
+        // Never below the sample peak
+        let mut meter = TruePeakDetector::new();
+        meter.push(0, -0.25);
+        assert!(meter.finish() >= 0.25);
+    }
+
+    #[test]
+    fn test_would_clip_boundaries() {
+        // A full-scale peak with no gain sits exactly at the limit
+        assert!(!would_clip(1.0, 0.0));
+        assert_eq!(clip_margin_db(1.0, 0.0), 0.0);
+
+        assert!(would_clip(1.0, 0.1));
+        assert!((clip_margin_db(1.0, 0.1) + 0.1).abs() < 1e-12);
+        assert!(!would_clip(1.0, -0.1));
+
+        assert!(!would_clip(0.5, 0.0));
+        assert!((clip_margin_db(0.5, 0.0) - 6.0206).abs() < 1e-4);
+        assert!(!would_clip(0.5, 6.0));
+        assert!(would_clip(0.5, 6.1));
+
+        // Silence never clips
+        assert!(!would_clip(0.0, 100.0));
+        assert_eq!(clip_margin_db(0.0, 100.0), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_clip_free_album_steps() {
+        // The 0.5 peak clips above +6.02 dB: 8 steps come down to 4 in 5 passes
+        let peaks = [0.2, 0.5, 0.35];
+        let check = clip_free_album_steps(&peaks, 8, 0);
+        assert_eq!(
+            check,
+            AlbumClipCheck {
+                steps: 4,
+                iterations: 5,
+                clip_free: true
+            }
+        );
+        assert_eq!(clip_free_album_steps(&peaks, 4, 0).iterations, 1);
+
+        // A peak over full scale still clips at the floor
+        let check = clip_free_album_steps(&[0.3, 1.2], 2, 0);
+        assert_eq!((check.steps, check.iterations), (0, 3));
+        assert!(!check.clip_free);
+    }
+
+    #[test]
+    fn test_gain_for_target() {
+        let result = ReplayGainResult {
+            loudness_db: 70.0,
+            gain_db: -5.18,
+            peak: 0.5,
+            sample_rate: 44100,
+            file_type: Some(AudioFileType::Mp3),
+            channel_layout: ChannelLayout::Stereo,
+            method: AnalysisMethod::Decoded,
+            partial: false,
+        };
+
+        // Reference target leaves the gain unchanged
+        assert!((result.gain_for_target(89.0) - -5.18).abs() < 1e-9);
+        assert_eq!(result.gain_steps_for_target(89.0), result.gain_steps());
+
+        // Louder/quieter targets shift the gain by the difference
+        assert!((result.gain_for_target(92.0) - -2.18).abs() < 1e-9);
+        assert!((result.gain_for_target(83.0) - -11.18).abs() < 1e-9);
+        assert_eq!(result.gain_steps_for_target(92.0), -1);
+        assert_eq!(result.gain_steps_for_target(83.0), -7);
+
+        let peak_db = 20.0 * 0.5f64.log10(); // -6.02 dBFS
+
+        // Loudness-driven: -2.18 dB keeps the peak well under 0 dBFS
+        assert!((result.suggested_gain(92.0, 0.0) - -2.18).abs() < 1e-9);
+        assert_eq!(result.suggested_gain_steps(92.0, 0.0), -1);
+
+        // Peak-driven: +10.82 dB would clip, so the 6.02 dB headroom wins and
+        // its steps round down (4 steps = 6.0 dB, not 4.01 rounded up)
+        assert!((result.suggested_gain(105.0, 0.0) + peak_db).abs() < 1e-9);
+        assert_eq!(result.gain_steps_for_target(105.0), 7);
+        assert_eq!(result.suggested_gain_steps(105.0, 0.0), 4);
+        assert!(!would_clip(result.peak, 4.0 * crate::GAIN_STEP_DB));
+
+        // +6.9 dB rounds to 5 steps = 7.5 dB, past the 6.02 dB of headroom;
+        // clip-safe rounding settles on 4 steps instead
+        assert_eq!(result.gain_steps_for_target(101.08), 5);
+        assert_eq!(result.clip_safe_gain_steps(101.08), 4);
+        assert_eq!(result.clip_safe_gain_steps(92.0), -1);
+
+        // A lower ceiling takes over from a smaller loudness gain
+        assert!((result.suggested_gain(92.0, -9.0) - (-9.0 - peak_db)).abs() < 1e-9);
+        assert_eq!(result.suggested_gain_steps(92.0, -9.0), -2);
+
+        // Silence is only loudness-limited
+        let silent = ReplayGainResult {
+            peak: 0.0,
+            ..result.clone()
+        };
+        assert!((silent.suggested_gain(105.0, 0.0) - 10.82).abs() < 1e-9);
+        assert_eq!(silent.suggested_gain_steps(105.0, 0.0), 7);
+
+        let album = AlbumGainResult {
+            tracks: vec![result],
+            album_loudness_db: 72.0,
+            album_gain_db: 1.0,
+            album_peak: 0.5,
+            histogram: LoudnessHistogram::new(),
+            silence_gate_dbfs: None,
+        };
+        assert!((album.album_gain_for_target(95.0) - 7.0).abs() < 1e-9);
+        assert_eq!(album.album_gain_steps_for_target(95.0), 5);
+        assert_eq!(album.album_gain_steps_for_target(89.0), 1);
+    }
+
     #[cfg(feature = "replaygain")]
     #[test]
     fn test_filter_creation() {
         // Test all supported sample rates
-        let supported_rates = [
-            96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000,
-        ];
-        for rate in supported_rates {
+        let supported_rates = supported_sample_rates();
+        assert_eq!(supported_rates.len(), 12);
+        for &rate in supported_rates {
             let filter = EqualLoudnessFilter::new(rate);
             assert!(filter.is_some(), "Sample rate {} should be supported", rate);
             let filter = filter.unwrap();
@@ -1363,4 +2920,333 @@ mod tests {
             loudness
         );
     }
+
+    #[cfg(feature = "replaygain")]
+    #[test]
+    fn test_histogram_percentile_matches_analyze_result() {
+        // Expected values follow analyzeResult() in gain_analysis.c step by step
+        let histogram = |buckets: &[(usize, u32)]| {
+            let mut h = LoudnessHistogram::new();
+            for &(idx, count) in buckets {
+                h.data[idx] += count;
+            }
+            h
+        };
+
+        // 100 windows: ceil(100 * (1 - 0.95)) is 6 in floating point, so the
+        // 5 loud windows are not enough and the quieter bucket is reported
+        assert_eq!(histogram(&[(5000, 95), (7000, 5)]).get_loudness(), 50.0);
+        assert_eq!(histogram(&[(5000, 94), (7000, 6)]).get_loudness(), 70.0);
+        // 20 windows (one second): threshold 2, the second loudest window
+        assert_eq!(histogram(&[(6000, 19), (8000, 1)]).get_loudness(), 60.0);
+        assert_eq!(histogram(&[(6000, 18), (8000, 2)]).get_loudness(), 80.0);
+        assert_eq!(histogram(&[(4321, 1)]).get_loudness(), 43.21);
+    }
+
+    #[cfg(feature = "replaygain")]
+    #[test]
+    fn test_silent_and_partial_windows() {
+        let sample_rate = 44100u32;
+        let window = ReplayGainAnalyzer::new(sample_rate).window_samples;
+        assert_eq!(window, 2205);
+        assert_eq!(ReplayGainAnalyzer::new(11025).window_samples, 552);
+        assert_eq!(ReplayGainAnalyzer::new(22050).window_samples, 1103);
+
+        // 18 silent windows, 2 loud ones and half a window left over
+        let mut analyzer = ReplayGainAnalyzer::new(sample_rate);
+        for _ in 0..18 * window {
+            analyzer.add_mono_sample(0.0);
+        }
+        for _ in 0..2 * window + window / 2 {
+            analyzer.add_mono_sample(10000.0);
+        }
+        let histogram = analyzer.get_histogram();
+        let total: u32 = histogram.data.iter().sum();
+        assert_eq!(total, 20, "partial window must not be counted");
+        assert_eq!(histogram.data[0], 18, "silence must land in bucket 0");
+        // 10*log10(10000²) = 80 dB; two loud windows meet the threshold of 2
+        assert_eq!(analyzer.get_loudness(), 80.0);
+
+        // With a single loud window, the second loudest is silence
+        let mut analyzer = ReplayGainAnalyzer::new(sample_rate);
+        for _ in 0..19 * window {
+            analyzer.add_mono_sample(0.0);
+        }
+        for _ in 0..window {
+            analyzer.add_mono_sample(10000.0);
+        }
+        assert_eq!(analyzer.get_loudness(), 0.0);
+    }
+
+    /// Analyze one second of a 1kHz sine at half scale, built by `to_sample`
+    /// from normalized values into a mono buffer of the given format
+    #[cfg(feature = "replaygain")]
+    fn analyze_sine_buffer<S: Sample>(
+        to_sample: impl Fn(f64) -> S,
+        wrap: impl Fn(&AudioBuffer<S>) -> AudioBufferRef<'_>,
+    ) -> (f64, f64) {
+        use symphonia::core::audio::{Channels, SignalSpec};
+
+        let sample_rate = 44100u32;
+        let spec = SignalSpec::new(sample_rate, Channels::FRONT_LEFT);
+        let mut buf = AudioBuffer::<S>::new(sample_rate as u64, spec);
+        buf.render_reserved(Some(sample_rate as usize));
+        for (i, sample) in buf.chan_mut(0).iter_mut().enumerate() {
+            let t = i as f64 / sample_rate as f64;
+            *sample = to_sample(0.5 * (2.0 * std::f64::consts::PI * 1000.0 * t).sin());
+        }
+
+        let mut filters = vec![EqualLoudnessFilter::new(sample_rate).unwrap()];
+        let mut analyzer = ReplayGainAnalyzer::new(sample_rate);
+        let mut peak = 0.0;
+        process_audio_buffer(
+            &wrap(&buf),
+            Downmix::None,
+            &mut filters,
+            &mut analyzer,
+            &mut peak,
+        );
+        (analyzer.get_loudness(), peak)
+    }
+
+    #[cfg(feature = "replaygain")]
+    #[test]
+    fn test_s24_and_u8_buffers_are_analyzed() {
+        use std::borrow::Cow;
+        use symphonia::core::sample::i24;
+
+        let (reference, reference_peak) =
+            analyze_sine_buffer(|x| x as f32, |buf| AudioBufferRef::F32(Cow::Borrowed(buf)));
+        let (s24, s24_peak) = analyze_sine_buffer(
+            |x| i24::from((x * 8_388_608.0).round() as i32),
+            |buf| AudioBufferRef::S24(Cow::Borrowed(buf)),
+        );
+        let (u8_loudness, u8_peak) = analyze_sine_buffer(
+            |x| (128.0 + x * 128.0).round() as u8,
+            |buf| AudioBufferRef::U8(Cow::Borrowed(buf)),
+        );
+
+        assert!(reference > 50.0, "reference loudness {}", reference);
+        assert!(
+            (s24 - reference).abs() < 0.01,
+            "S24 loudness {} vs F32 {}",
+            s24,
+            reference
+        );
+        assert!((s24_peak - reference_peak).abs() < 1e-6);
+        // 8-bit quantization adds noise but stays close to the reference
+        assert!(
+            (u8_loudness - reference).abs() < 0.5,
+            "U8 loudness {} vs F32 {}",
+            u8_loudness,
+            reference
+        );
+        assert!((u8_peak - 0.5).abs() < 0.01, "U8 peak {}", u8_peak);
+    }
+
+    #[cfg(feature = "replaygain")]
+    #[test]
+    fn test_pcm_analyzer_matches_decoded_buffers() {
+        use std::borrow::Cow;
+
+        let (reference, reference_peak) =
+            analyze_sine_buffer(|x| x as f32, |buf| AudioBufferRef::F32(Cow::Borrowed(buf)));
+        let samples: Vec<f32> = (0..44100)
+            .map(|i| {
+                let t = i as f64 / 44100.0;
+                (0.5 * (2.0 * std::f64::consts::PI * 1000.0 * t).sin()) as f32
+            })
+            .collect();
+
+        // Pushed in uneven pieces, as a streaming decoder would
+        let mut analyzer = PcmAnalyzer::new();
+        for chunk in samples.chunks(1000) {
+            analyzer.push_samples(chunk, 1, 44100).unwrap();
+        }
+        let result = analyzer.finish().unwrap();
+        assert!((result.loudness_db - reference).abs() < 1e-9);
+        assert!((result.peak - reference_peak).abs() < 1e-9);
+        assert_eq!(result.file_type, None);
+        assert_eq!(result.channel_layout, ChannelLayout::Mono);
+
+        let mut analyzer = PcmAnalyzer::new();
+        analyzer.push_samples(&samples, 1, 44100).unwrap();
+        assert!(analyzer.push_samples(&samples, 2, 44100).is_err());
+        assert!(analyzer.push_samples(&samples, 1, 48000).is_err());
+        assert!(analyzer.push_samples(&samples[..3], 2, 44100).is_err());
+        assert!(PcmAnalyzer::new().push_samples(&samples, 1, 12345).is_err());
+
+        // Nothing to measure
+        let err = PcmAnalyzer::new().finish().unwrap_err();
+        assert!(err.downcast_ref::<NotEnoughSamples>().is_some());
+    }
+
+    #[cfg(feature = "replaygain")]
+    #[test]
+    fn test_short_clip_is_measured() {
+        let sine = |i: usize| {
+            let t = i as f64 / 44100.0;
+            (0.5 * (2.0 * std::f64::consts::PI * 1000.0 * t).sin()) as f32
+        };
+        let analyze = |samples: &[f32]| {
+            let mut analyzer = PcmAnalyzer::new();
+            analyzer.push_samples(samples, 1, 44100).unwrap();
+            analyzer.finish().unwrap()
+        };
+        let long: Vec<f32> = (0..44100).map(sine).collect();
+        let reference = analyze(&long);
+
+        // 10ms of the same tone: under one 50ms window, still about as loud
+        let clip = analyze(&long[..441]);
+        assert!(
+            (clip.loudness_db - reference.loudness_db).abs() < 3.0,
+            "10ms clip {} dB vs 1s {} dB",
+            clip.loudness_db,
+            reference.loudness_db
+        );
+        assert!((clip.gain_db - (PINK_REF - clip.loudness_db)).abs() < 1e-9);
+
+        // A lone click measures as quiet; the gain stops where its peak
+        // reaches full scale instead of pushing the click into clipping
+        let mut click = vec![0.0f32; 2000];
+        click[0] = 0.5;
+        let click = analyze(&click);
+        assert!(
+            PINK_REF - click.loudness_db > 10.0,
+            "click {} dB",
+            click.loudness_db
+        );
+        assert!((click.gain_db - 20.0 * 2f64.log10()).abs() < 1e-9);
+        assert!(!would_clip(click.peak, click.gain_db));
+    }
+
+    #[cfg(feature = "replaygain")]
+    #[test]
+    fn test_skip_regions_leave_out_loud_intro_and_outro() {
+        // 10 seconds (and a partial window) of a quiet 1kHz tone, with two
+        // seconds of the same tone 19 dB louder before or after it
+        let sample_rate = 44100u32;
+        let tone = |amplitude: f64, seconds: usize| {
+            (0..seconds * sample_rate as usize).map(move |i| {
+                let t = i as f64 / sample_rate as f64;
+                amplitude * (2.0 * std::f64::consts::PI * 1000.0 * t).sin()
+            })
+        };
+        let body: Vec<f64> = tone(0.1, 10).chain(tone(0.1, 1).take(1000)).collect();
+        let jingle: Vec<f64> = tone(0.9, 2).collect();
+        let analyze = |parts: &[&[f64]], skip: SkipRegions| {
+            let (mut filters, mut analyzer) = analysis_state(sample_rate, 1).unwrap();
+            analyzer.skip(skip, sample_rate);
+            let mut peak = 0.0;
+            for &x in parts.concat().iter() {
+                add_frame(&mut filters, &mut analyzer, &mut peak, x, None);
+            }
+            let (loudness, _) = analyzer.finish(peak).unwrap();
+            (loudness, analyzer.get_histogram().window_count(), peak)
+        };
+
+        let (body_loudness, body_windows, _) = analyze(&[&body], SkipRegions::default());
+        assert_eq!(body_windows, 200);
+
+        let intro = SkipRegions {
+            intro_secs: 2.0,
+            ..Default::default()
+        };
+        let (unskipped, _, _) = analyze(&[&jingle, &body], SkipRegions::default());
+        assert!(unskipped > body_loudness + 15.0);
+        let (loudness, windows, peak) = analyze(&[&jingle, &body], intro);
+        assert!(
+            (loudness - body_loudness).abs() < 0.1,
+            "{} vs {}",
+            loudness,
+            body_loudness
+        );
+        assert_eq!(windows, body_windows);
+        // The skipped intro still sets the peak
+        assert!((peak - 0.9).abs() < 1e-3);
+
+        let outro = SkipRegions {
+            outro_secs: 2.0,
+            ..Default::default()
+        };
+        let (unskipped, _, _) = analyze(&[&body, &jingle], SkipRegions::default());
+        assert!(unskipped > body_loudness + 15.0);
+        let (loudness, windows, _) = analyze(&[&body, &jingle], outro);
+        assert!((loudness - body_loudness).abs() < 0.1);
+        // The window straddling the body's end and the outro is dropped too
+        assert_eq!(windows, body_windows);
+    }
+
+    #[cfg(feature = "replaygain")]
+    #[test]
+    fn test_silence_gate_ignores_padding() {
+        // One second of a 1kHz tone at -6 dBFS, then 30 seconds of silence:
+        // under 5% of the windows are loud, so the percentile lands in silence
+        let sample_rate = 44100;
+        let mut filter = EqualLoudnessFilter::new(sample_rate).unwrap();
+        let mut analyzer = ReplayGainAnalyzer::new(sample_rate);
+        let mut tone = ReplayGainAnalyzer::new(sample_rate);
+        for i in 0..31 * sample_rate as usize {
+            let x = if i < sample_rate as usize {
+                let t = i as f64 / sample_rate as f64;
+                0.5 * (2.0 * std::f64::consts::PI * 1000.0 * t).sin()
+            } else {
+                0.0
+            };
+            let filtered = filter.process(x * SAMPLE_SCALE_16BIT);
+            analyzer.add_mono_sample(filtered);
+            if i < sample_rate as usize {
+                tone.add_mono_sample(filtered);
+            }
+        }
+
+        let track = TrackAnalysis {
+            result: ReplayGainResult {
+                loudness_db: analyzer.get_loudness(),
+                gain_db: PINK_REF - analyzer.get_loudness(),
+                peak: 0.5,
+                sample_rate,
+                file_type: Some(AudioFileType::Mp3),
+                channel_layout: ChannelLayout::Mono,
+                method: AnalysisMethod::Decoded,
+                partial: false,
+            },
+            histogram: analyzer.get_histogram().clone(),
+        };
+        assert!(track.result.loudness_db < 10.0);
+
+        let gated = track.clone().with_silence_gate(SILENCE_GATE_DBFS);
+        assert!(
+            (gated.result.loudness_db - tone.get_loudness()).abs() < 0.01,
+            "gated {} vs tone alone {}",
+            gated.result.loudness_db,
+            tone.get_loudness()
+        );
+        assert!((gated.result.gain_db - (PINK_REF - tone.get_loudness())).abs() < 0.01);
+        // Gating doesn't touch the histogram an album would sum
+        assert_eq!(gated.histogram, track.histogram);
+
+        // A histogram of silence only falls back to the ungated value
+        let silent = LoudnessHistogram::from_buckets([(0, 100)]).unwrap();
+        assert_eq!(
+            silent.get_loudness_gated(Some(SILENCE_GATE_DBFS)),
+            silent.get_loudness()
+        );
+    }
+
+    #[cfg(feature = "replaygain")]
+    #[test]
+    fn test_analysis_state_for_late_stream_params() {
+        // What analyze_track_internal builds from the first decoded buffer when
+        // the container leaves out the sample rate and channel count
+        let (filters, analyzer) = analysis_state(48000, 2).unwrap();
+        assert_eq!(filters.len(), 2);
+        assert_eq!(analyzer.window_samples, 2400);
+        assert_eq!(analysis_state(22050, 0).unwrap().0.len(), 1);
+
+        let err = analysis_state(99999, 2).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("Unsupported sample rate: 99999 Hz"));
+    }
 }
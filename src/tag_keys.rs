@@ -0,0 +1,127 @@
+//! Keys of the gain metadata mp3rgain reads and writes
+//!
+//! The same names are used as APEv2 item keys and as ID3v2 TXXX frame
+//! descriptions (see [`crate::TagFormat`]). MP4 files carry the ReplayGain
+//! values as iTunes freeform tags under the lowercase names in
+//! [`crate::mp4meta`]. Keys are compared case-insensitively, since taggers
+//! disagree on case.
+
+/// Gain applied by mp3rgain/mp3gain so far, as `left,right,wrap`
+///
+/// Steps are signed three-digit numbers and the wrap flag is `W` (wrapped
+/// around) or `N` (clamped), e.g. `+004,+004,N`. Undo subtracts these.
+pub const MP3GAIN_UNDO: &str = "MP3GAIN_UNDO";
+
+/// Each adjustment that makes up [`MP3GAIN_UNDO`], oldest first
+///
+/// Entries use the [`MP3GAIN_UNDO`] format and are separated by `;`, e.g.
+/// `+002,+002,N;+003,+003,N`. It is only written once there are two, and
+/// undoing only the last adjustment pops one.
+pub const MP3GAIN_UNDO_STACK: &str = "MP3GAIN_UNDO_STACK";
+
+/// Gain taken back by the last undo, in the [`MP3GAIN_UNDO`] format
+///
+/// Redo applies it again and moves it back to [`MP3GAIN_UNDO`]; any other
+/// adjustment drops it.
+pub const MP3GAIN_REDO: &str = "MP3GAIN_REDO";
+
+/// Smallest and largest global_gain before the first adjustment, as `min,max`
+pub const MP3GAIN_MINMAX: &str = "MP3GAIN_MINMAX";
+
+/// [`MP3GAIN_MINMAX`] over the whole album, as `min,max`
+pub const MP3GAIN_ALBUM_MINMAX: &str = "MP3GAIN_ALBUM_MINMAX";
+
+/// Track gain relative to the reference loudness, e.g. `-6.50 dB`
+pub const REPLAYGAIN_TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
+
+/// Track peak as a linear sample value (1.0 = full scale), e.g. `0.988312`
+pub const REPLAYGAIN_TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
+
+/// Album gain relative to the reference loudness, e.g. `-5.00 dB`
+pub const REPLAYGAIN_ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
+
+/// Album peak as a linear sample value (1.0 = full scale)
+pub const REPLAYGAIN_ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
+
+/// Loudness the gains aim for, e.g. `89.0 dB`
+pub const REPLAYGAIN_REFERENCE_LOUDNESS: &str = "REPLAYGAIN_REFERENCE_LOUDNESS";
+
+/// mp3gain's own keys: undo/redo information and the original gain range
+pub const MP3GAIN_KEYS: [&str; 5] = [
+    MP3GAIN_UNDO,
+    MP3GAIN_UNDO_STACK,
+    MP3GAIN_REDO,
+    MP3GAIN_MINMAX,
+    MP3GAIN_ALBUM_MINMAX,
+];
+
+/// ReplayGain keys, track and album
+pub const REPLAYGAIN_KEYS: [&str; 5] = [
+    REPLAYGAIN_TRACK_GAIN,
+    REPLAYGAIN_TRACK_PEAK,
+    REPLAYGAIN_ALBUM_GAIN,
+    REPLAYGAIN_ALBUM_PEAK,
+    REPLAYGAIN_REFERENCE_LOUDNESS,
+];
+
+/// Every key in this module: [`MP3GAIN_KEYS`] followed by [`REPLAYGAIN_KEYS`]
+pub const GAIN_KEYS: [&str; 10] = [
+    MP3GAIN_UNDO,
+    MP3GAIN_UNDO_STACK,
+    MP3GAIN_REDO,
+    MP3GAIN_MINMAX,
+    MP3GAIN_ALBUM_MINMAX,
+    REPLAYGAIN_TRACK_GAIN,
+    REPLAYGAIN_TRACK_PEAK,
+    REPLAYGAIN_ALBUM_GAIN,
+    REPLAYGAIN_ALBUM_PEAK,
+    REPLAYGAIN_REFERENCE_LOUDNESS,
+];
+
+/// Check if `key` is one of the [`GAIN_KEYS`] (ignoring case)
+///
+/// Everything else in a tag (artist, title, cover art, ...) belongs to the
+/// user and is never touched when gain metadata is removed.
+pub fn is_gain_key(key: &str) -> bool {
+    GAIN_KEYS.iter().any(|k| k.eq_ignore_ascii_case(key))
+}
+
+/// Check if `key` is one of the [`REPLAYGAIN_KEYS`] (ignoring case)
+pub fn is_replaygain_key(key: &str) -> bool {
+    REPLAYGAIN_KEYS.iter().any(|k| k.eq_ignore_ascii_case(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_gain_key() {
+        for key in GAIN_KEYS {
+            assert!(is_gain_key(key), "{}", key);
+            assert!(is_gain_key(&key.to_lowercase()), "{}", key);
+        }
+        assert_eq!(
+            [&MP3GAIN_KEYS[..], &REPLAYGAIN_KEYS[..]].concat(),
+            GAIN_KEYS
+        );
+        for key in REPLAYGAIN_KEYS {
+            assert!(is_replaygain_key(key), "{}", key);
+        }
+        for key in MP3GAIN_KEYS {
+            assert!(!is_replaygain_key(key), "{}", key);
+        }
+
+        for key in [
+            "",
+            "ARTIST",
+            "REPLAYGAIN",
+            "REPLAYGAIN_TRACK_GAIN ",
+            "MP3GAIN_UNDO_OLD",
+            "replaygain_track_gain_db",
+            "R128_TRACK_GAIN",
+        ] {
+            assert!(!is_gain_key(key), "{:?}", key);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Deterministic MP3 byte streams for unit tests
+//!
+//! Frames are laid out from the Layer III spec rather than from the parser
+//! under test, so bit-offset math can be checked against independently placed
+//! global_gain values. Every other side-info bit is set to 1 (except
+//! main_data_begin), which makes an off-by-one gain offset visible. Main data
+//! is zeroed and no Xing/Info frame is emitted.
+
+use super::{ChannelMode, MpegVersion};
+
+/// Build `frames` consecutive Layer III frames with every global_gain set to `gain`
+///
+/// Frames use the middle sample rate (48/24/12 kHz), bitrate index 9
+/// (128 kbps for MPEG1, 80 kbps otherwise), no CRC and no padding.
+pub(crate) fn mp3_frames(
+    version: MpegVersion,
+    mode: ChannelMode,
+    frames: usize,
+    gain: u8,
+) -> Vec<u8> {
+    let frame = mp3_frame(version, mode, gain);
+    frame.repeat(frames)
+}
+
+/// Build a single frame (see [`mp3_frames`])
+fn mp3_frame(version: MpegVersion, mode: ChannelMode, gain: u8) -> Vec<u8> {
+    let (version_bits, frame_size, granules) = match version {
+        // 1152 samples * 128 kbps / 48 kHz
+        MpegVersion::Mpeg1 => (0b11u8, 384, 2),
+        // 576 samples * 80 kbps / 24 kHz
+        MpegVersion::Mpeg2 => (0b10, 240, 1),
+        // 576 samples * 80 kbps / 12 kHz
+        MpegVersion::Mpeg25 => (0b00, 480, 1),
+    };
+    let (mode_bits, channels) = match mode {
+        ChannelMode::Stereo => (0b00u8, 2),
+        ChannelMode::JointStereo => (0b01, 2),
+        ChannelMode::DualChannel => (0b10, 2),
+        ChannelMode::Mono => (0b11, 1),
+    };
+
+    let mut frame = vec![0u8; frame_size];
+    frame[0] = 0xFF;
+    frame[1] = 0xE0 | (version_bits << 3) | (0b01 << 1) | 0x01;
+    frame[2] = (9 << 4) | (1 << 2);
+    frame[3] = mode_bits << 6;
+
+    // Side info: main_data_begin, private bits, scfsi (MPEG1), then one
+    // block per granule/channel with global_gain 21 bits into it
+    let is_mpeg1 = version == MpegVersion::Mpeg1;
+    let (side_info_len, prefix_bits, block_bits) = match (is_mpeg1, channels) {
+        (true, 1) => (17, 9 + 5 + 4, 59),
+        (true, _) => (32, 9 + 3 + 8, 59),
+        (false, 1) => (9, 8 + 1, 63),
+        (false, _) => (17, 8 + 2, 63),
+    };
+    let main_data_begin_bits = if is_mpeg1 { 9 } else { 8 };
+
+    let side_info = &mut frame[4..4 + side_info_len];
+    side_info.fill(0xFF);
+    put_bits(side_info, 0, main_data_begin_bits, 0);
+    for block in 0..granules * channels {
+        put_bits(
+            side_info,
+            prefix_bits + block * block_bits + 21,
+            8,
+            gain as u32,
+        );
+    }
+
+    frame
+}
+
+/// Write the low `count` bits of `value` at `bit_pos` (MSB first)
+fn put_bits(buf: &mut [u8], bit_pos: usize, count: usize, value: u32) {
+    for i in 0..count {
+        let bit = (value >> (count - 1 - i)) & 1;
+        let pos = bit_pos + i;
+        let mask = 0x80 >> (pos % 8);
+        if bit == 1 {
+            buf[pos / 8] |= mask;
+        } else {
+            buf[pos / 8] &= !mask;
+        }
+    }
+}
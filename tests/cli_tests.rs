@@ -0,0 +1,3357 @@
+//! Command-line tests for the mp3rgain binary
+//!
+//! These run the built executable against copies of the fixtures in
+//! tests/fixtures/ and check its output and the resulting files.
+
+use mp3rgain::{
+    analyze, apply_gain, delete_ape_tag, estimate_applied_gain, frame_gains, frame_headers,
+    global_gain_shift_db, read_ape_tag_from_file, write_ape_tag, ApeTag, GAIN_STEP_DB,
+    TAG_MP3GAIN_MINMAX, TAG_MP3GAIN_REDO, TAG_MP3GAIN_UNDO, TAG_REPLAYGAIN_TRACK_GAIN,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Global counter for unique file names
+static TEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Helper to copy a test file to a temp location for testing with unique name
+fn copy_test_file(name: &str) -> PathBuf {
+    let id = TEST_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let src = Path::new("tests/fixtures").join(name);
+    let dst = std::env::temp_dir().join(format!("mp3rgain_cli_test_{}_{}", id, name));
+    fs::copy(&src, &dst).expect("Failed to copy test file");
+    dst
+}
+
+/// Helper to cleanup temp file
+fn cleanup(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+/// Run mp3rgain with the given arguments
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mp3rgain"))
+        .args(args)
+        .output()
+        .expect("Failed to run mp3rgain")
+}
+
+/// Run mp3rgain with `-o json` and parse stdout
+fn run_json(args: &[&str]) -> serde_json::Value {
+    let mut full = vec!["-o", "json"];
+    full.extend_from_slice(args);
+    let output = run(&full);
+    serde_json::from_slice(&output.stdout).unwrap_or_else(|e| {
+        panic!(
+            "Invalid JSON ({}): {}",
+            e,
+            String::from_utf8_lossy(&output.stdout)
+        )
+    })
+}
+
+// =============================================================================
+// Gain Application
+// =============================================================================
+
+#[test]
+fn test_json_clipping_warning_keeps_stderr_empty() {
+    let path = copy_test_file("test_stereo.mp3");
+    apply_gain(&path, -4).unwrap();
+    let headroom = analyze(&path).unwrap().headroom_steps;
+    let steps = (headroom + 4).to_string();
+
+    for quiet in [false, true] {
+        let mut args = vec!["-o", "json", "-n", "-g", &steps];
+        if quiet {
+            args.push("-q");
+        }
+        args.push(path.to_str().unwrap());
+        let output = run(&args);
+        assert!(
+            output.stderr.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        assert!(json["files"][0]["warning"]
+            .as_str()
+            .unwrap()
+            .contains("clipping warning"));
+    }
+
+    // Text mode still prints it
+    let output = run(&["-n", "-g", &steps, path.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("clipping warning"));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_positive_gain_without_headroom_warns() {
+    // Raise the loudest granule to 255 while the others keep headroom
+    let path = copy_test_file("test_joint_stereo.mp3");
+    let info = analyze(&path).unwrap();
+    apply_gain(&path, info.headroom_steps).unwrap();
+    let before = analyze(&path).unwrap();
+    assert_eq!(before.headroom_steps, 0);
+    assert!(before.min_gain < 255);
+
+    let json = run_json(&["-g", "1", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["status"], "success");
+    assert_eq!(file["gain_applied_steps"], 1);
+    assert!(file["warning"]
+        .as_str()
+        .unwrap()
+        .contains("no positive headroom"));
+
+    // The frames below 255 took the gain; those at 255 saturated
+    let after = analyze(&path).unwrap();
+    assert_eq!(after.min_gain, before.min_gain + 1);
+    assert_eq!(after.max_gain, 255);
+
+    let output = run(&["-g", "1", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no positive headroom"));
+
+    cleanup(&path);
+
+    // With every granule at 255 there is nothing to raise: neither the audio
+    // nor the tags are touched
+    let path = copy_test_file("test_mono.mp3");
+    apply_gain(&path, 255).unwrap();
+    assert_eq!(analyze(&path).unwrap().min_gain, 255);
+    let before = fs::read(&path).unwrap();
+
+    let json = run_json(&["-g", "1", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "skipped");
+    assert!(json["files"][0]["warning"]
+        .as_str()
+        .unwrap()
+        .contains("no positive headroom"));
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_gain_on_adts_file_is_rejected() {
+    let path = copy_test_file("test_adts.aac");
+    let before = fs::read(&path).unwrap();
+
+    let json = run_json(&["-g", "1", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["status"], "error");
+    assert!(file["error"].as_str().unwrap().contains("ADTS"));
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_id3_tagged_adts_gets_aac_tags() {
+    let path = copy_test_file("test_id3_adts.aac");
+    let before = fs::read(&path).unwrap();
+
+    let json = run_json(&["-r", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    let tag = read_ape_tag_from_file(&path).unwrap().unwrap();
+    assert!(tag.get("REPLAYGAIN_TRACK_GAIN").is_some());
+    // ID3v2 tag and ADTS frames are kept, the APEv2 tag is appended
+    assert_eq!(fs::read(&path).unwrap()[..before.len()], before[..]);
+
+    let json = run_json(&["-g", "1", path.to_str().unwrap()]);
+    assert!(json["files"][0]["error"].as_str().unwrap().contains("ADTS"));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_retry_count_is_limited() {
+    let path = copy_test_file("test_mono.mp3");
+
+    let output = run(&["--retry", "1000", "-g", "1", path.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid retry count"));
+    assert!(run(&["--retry", "20", "-g", "1", path.to_str().unwrap()])
+        .status
+        .success());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_max_steps_guard() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = fs::read(&path).unwrap();
+
+    // Beyond the default limit of 64 steps: refused, file untouched
+    let output = run(&["-g", "-100", path.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("limit of 64 steps"));
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    // A lower custom limit also applies to -l
+    let output = run(&["--max-steps", "2", "-l", "0", "-3", path.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("limit of 2 steps"));
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    // --force overrides it
+    let output = run(&["--force", "-g", "-100", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(analyze(&path).unwrap().min_gain, 15);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_ceiling_from_caps_track_gain() {
+    // -r suggests +4 steps for the joint stereo file and +2 for the mono one
+    let capped = copy_test_file("test_joint_stereo.mp3");
+    let free = copy_test_file("test_mono.mp3");
+    let original = fs::read(&capped).unwrap();
+    let capped_gain = analyze(&capped).unwrap().max_gain;
+    let free_gain = analyze(&free).unwrap().max_gain;
+
+    let map = capped.with_extension("ceilings.txt");
+    let name = capped.file_name().unwrap().to_str().unwrap();
+    fs::write(&map, format!("# steps file\n1 {}\n", name)).unwrap();
+
+    let json = run_json(&[
+        "-r",
+        "-c",
+        "--ceiling-from",
+        map.to_str().unwrap(),
+        capped.to_str().unwrap(),
+        free.to_str().unwrap(),
+    ]);
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files[0]["gain_applied_steps"], 1);
+    assert!(files[0]["warning"]
+        .as_str()
+        .unwrap()
+        .contains("capped from 4 to 1 steps"));
+    assert_eq!(files[1]["gain_applied_steps"], 2);
+    assert_eq!(analyze(&capped).unwrap().max_gain, capped_gain + 1);
+    assert_eq!(analyze(&free).unwrap().max_gain, free_gain + 2);
+
+    // The undo information records the capped gain
+    let output = run(&["-u", capped.to_str().unwrap()]);
+    assert!(output.status.success());
+    delete_ape_tag(&capped).unwrap();
+    assert_eq!(fs::read(&capped).unwrap(), original);
+
+    // A malformed line is an error
+    fs::write(&map, "lots song.mp3\n").unwrap();
+    let output = run(&["-r", "--ceiling-from", map.to_str().unwrap(), name]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(":1: expected <steps> <file>"));
+
+    cleanup(&capped);
+    cleanup(&free);
+    cleanup(&map);
+}
+
+#[test]
+fn test_json_lists_every_warning() {
+    // test_mono.mp3 has 45 steps of frame headroom: the ceiling caps +60 to
+    // +50, then -k lowers that to +45
+    let path = copy_test_file("test_mono.mp3");
+    let map = path.with_extension("ceilings.txt");
+    let name = path.file_name().unwrap().to_str().unwrap();
+    fs::write(&map, format!("50 {}\n", name)).unwrap();
+
+    let json = run_json(&[
+        "-k",
+        "-g",
+        "60",
+        "--ceiling-from",
+        map.to_str().unwrap(),
+        path.to_str().unwrap(),
+    ]);
+    let file = &json["files"][0];
+    assert_eq!(file["gain_applied_steps"], 45);
+    let warnings = file["warnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0]
+        .as_str()
+        .unwrap()
+        .contains("capped from 60 to 50 steps"));
+    assert!(warnings[1]
+        .as_str()
+        .unwrap()
+        .contains("gain reduced from 50 to 45 steps"));
+    // The single-string field still carries both
+    assert_eq!(
+        file["warning"].as_str().unwrap(),
+        format!(
+            "{}; {}",
+            warnings[0].as_str().unwrap(),
+            warnings[1].as_str().unwrap()
+        )
+    );
+
+    cleanup(&path);
+    cleanup(&map);
+}
+
+#[test]
+fn test_no_undo_tag_only_shifts_global_gain() {
+    let path = copy_test_file("test_mono.mp3");
+    let reference = copy_test_file("test_mono.mp3");
+
+    let output = run(&["--no-undo-tag", "-g", "2", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    apply_gain(&reference, 2).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), fs::read(&reference).unwrap());
+    assert!(read_ape_tag_from_file(&path).unwrap().is_none());
+
+    // -r shifts the frames just the same, without undo or ReplayGain tags
+    let original = copy_test_file("test_mono.mp3");
+    let max_gain = analyze(&original).unwrap().max_gain;
+    let json = run_json(&["--no-undo-tag", "-r", "-c", original.to_str().unwrap()]);
+    let steps = json["files"][0]["gain_applied_steps"].as_i64().unwrap();
+    assert_ne!(steps, 0);
+    assert_eq!(
+        analyze(&original).unwrap().max_gain as i64,
+        max_gain as i64 + steps
+    );
+    assert!(read_ape_tag_from_file(&original).unwrap().is_none());
+
+    cleanup(&path);
+    cleanup(&reference);
+    cleanup(&original);
+}
+
+#[test]
+fn test_skip_stored_tags_reads_and_writes_none() {
+    // Undo tags already in the file are neither extended nor rewritten
+    let path = copy_test_file("test_mono.mp3");
+    let mut tag = ApeTag::new();
+    tag.set(TAG_MP3GAIN_UNDO, "+005,+005,N");
+    write_ape_tag(&path, &tag).unwrap();
+    let reference = copy_test_file("test_mono.mp3");
+    fs::copy(&path, &reference).unwrap();
+
+    assert!(run(&["-s", "s", "-g", "1", path.to_str().unwrap()])
+        .status
+        .success());
+    apply_gain(&reference, 1).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), fs::read(&reference).unwrap());
+
+    // Files whose gain only goes into tags are left alone
+    let m4a = copy_test_file("test_aac.m4a");
+    let before = fs::read(&m4a).unwrap();
+    let json = run_json(&["-s", "s", "-r", m4a.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "skipped");
+    assert_eq!(fs::read(&m4a).unwrap(), before);
+
+    let output = run(&["-s", "s", "-u", path.to_str().unwrap()]);
+    assert!(!output.status.success());
+
+    cleanup(&path);
+    cleanup(&reference);
+    cleanup(&m4a);
+}
+
+#[test]
+fn test_aacgain_undo_info_in_m4a() {
+    // test_aacgain.m4a carries aacgain's mp3gain_undo/minmax freeform tags
+    let m4a = copy_test_file("test_aacgain.m4a");
+
+    let output = run(&["-s", "c", m4a.to_str().unwrap()]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("MP3GAIN_UNDO:         +003,+003,N"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("MP3GAIN_MINMAX:       120,200"));
+    assert!(stdout.contains("MP3GAIN_ALBUM_MINMAX: 110,210"));
+
+    // The AAC audio can't be restored here, so undo says why instead of
+    // reporting nothing to undo
+    let before = fs::read(&m4a).unwrap();
+    let json = run_json(&["-u", m4a.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "error");
+    assert!(json["files"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("aacgain"));
+    assert_eq!(fs::read(&m4a).unwrap(), before);
+
+    // Writing ReplayGain tags keeps aacgain's
+    assert!(run(&["-r", m4a.to_str().unwrap()]).status.success());
+    let output = run(&["-s", "c", m4a.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("+003,+003,N"));
+
+    cleanup(&m4a);
+}
+
+#[test]
+fn test_skip_and_recalc_ignore_cached_analyses() {
+    let dir = temp_dir("stored_analysis");
+    let cache = dir.join("cache");
+    let path = dir.join("song.mp3");
+    fs::copy("tests/fixtures/test_stereo.mp3", &path).unwrap();
+    let file = path.to_str().unwrap();
+    let loudness = |extra: &[&str]| {
+        let mut args = vec!["-r", "-n", "--cache", cache.to_str().unwrap()];
+        args.extend_from_slice(extra);
+        args.push(file);
+        run_json(&args)["files"][0]["loudness_db"].as_f64().unwrap()
+    };
+    let measured = loudness(&[]);
+
+    // Make the cached entry lie about the loudness
+    let entry = fs::read_dir(&cache)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&entry).unwrap()).unwrap();
+    json["loudness_db"] = serde_json::json!(measured + 10.0);
+    fs::write(&entry, serde_json::to_vec(&json).unwrap()).unwrap();
+    assert_eq!(loudness(&[]), measured + 10.0);
+
+    // -s s decodes afresh and leaves the entry as it was; -s r refreshes it
+    assert_eq!(loudness(&["-s", "s"]), measured);
+    assert_eq!(loudness(&[]), measured + 10.0);
+    assert_eq!(loudness(&["-s", "r"]), measured);
+    assert_eq!(loudness(&[]), measured);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_verify_accepts_intact_writes() {
+    let path = copy_test_file("test_joint_stereo.mp3");
+    let frames = analyze(&path).unwrap().frame_count;
+
+    for args in [
+        &["-g", "2"][..],
+        &["-t", "-g", "-2"],
+        &["-l", "0", "1"],
+        &["-u"],
+    ] {
+        let mut args = args.to_vec();
+        args.extend(["--verify", path.to_str().unwrap()]);
+        let output = run(&args);
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(analyze(&path).unwrap().frame_count, frames);
+    }
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_journal_appends_one_record_per_file() {
+    use mp3rgain::journal::FileState;
+
+    let files = [
+        copy_test_file("test_mono.mp3"),
+        copy_test_file("test_vbr.mp3"),
+    ];
+    let journal = files[0].with_extension("journal.ndjson");
+    let originals: Vec<Vec<u8>> = files.iter().map(|f| fs::read(f).unwrap()).collect();
+    let paths: Vec<&str> = files.iter().map(|f| f.to_str().unwrap()).collect();
+
+    let mut args = vec!["--journal", journal.to_str().unwrap(), "-g", "1"];
+    args.extend(&paths);
+    assert!(run(&args).status.success());
+
+    // A dry run modifies nothing and records nothing
+    args.push("-n");
+    assert!(run(&args).status.success());
+
+    let text = fs::read_to_string(&journal).unwrap();
+    let records: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    for ((record, file), original) in records.iter().zip(&files).zip(&originals) {
+        assert_eq!(record["file"], file.to_str().unwrap());
+        assert_eq!(record["operation"], "-g");
+        assert_eq!(record["status"], "success");
+        assert_eq!(record["gain_applied_steps"], 1);
+        assert!(record["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(record["tool_version"], env!("CARGO_PKG_VERSION"));
+
+        let before = FileState::of(original);
+        let after = FileState::of(&fs::read(file).unwrap());
+        assert_eq!(record["before"]["size"], before.size);
+        assert_eq!(record["before"]["sha256"], before.sha256);
+        assert_eq!(record["after"]["size"], after.size);
+        assert_eq!(record["after"]["sha256"], after.sha256);
+        assert_ne!(before.sha256, after.sha256);
+    }
+
+    // Later runs append
+    assert!(
+        run(&["--journal", journal.to_str().unwrap(), "-u", paths[0]])
+            .status
+            .success()
+    );
+    let text = fs::read_to_string(&journal).unwrap();
+    assert_eq!(text.lines().count(), 3);
+    let undo: serde_json::Value = serde_json::from_str(text.lines().last().unwrap()).unwrap();
+    assert_eq!(undo["operation"], "-u");
+    let after = FileState::of(&fs::read(&files[0]).unwrap());
+    assert_eq!(undo["after"]["sha256"], after.sha256);
+    assert_eq!(after, FileState::of(&originals[0]));
+
+    for file in &files {
+        cleanup(file);
+    }
+    cleanup(&journal);
+}
+
+#[test]
+fn test_hash_reports_before_and_after() {
+    use mp3rgain::journal::FileState;
+
+    let path = copy_test_file("test_mono.mp3");
+    let original = FileState::of(&fs::read(&path).unwrap());
+
+    // A dry run only has the "before" hash
+    let json = run_json(&["--hash", "-n", "-g", "1", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["sha256_before"], original.sha256);
+    assert!(file.get("sha256_after").is_none());
+
+    let json = run_json(&["--hash", "-g", "1", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    let modified = FileState::of(&fs::read(&path).unwrap());
+    assert_eq!(file["sha256_before"], original.sha256);
+    assert_eq!(file["sha256_after"], modified.sha256);
+    assert_ne!(original.sha256, modified.sha256);
+
+    let output = run(&["--hash", "-g", "-1", path.to_str().unwrap()]);
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(text.contains(&format!("SHA-256 before: {}", modified.sha256)));
+
+    // Through a temp file, and for an undo that drops the tag again
+    let before = FileState::of(&fs::read(&path).unwrap());
+    let json = run_json(&["--hash", "-t", "-g", "2", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["sha256_before"], before.sha256);
+    assert_eq!(
+        file["sha256_after"],
+        FileState::of(&fs::read(&path).unwrap()).sha256
+    );
+    let json = run_json(&["--hash", "-u", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["sha256_after"], original.sha256);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_stats_object() {
+    let path = copy_test_file("test_mono.mp3");
+
+    let json = run_json(&["--stats", "-g", "-1", path.to_str().unwrap()]);
+    let stats = &json["stats"];
+    assert_eq!(stats["files"], 1);
+    assert!(stats["frames"].as_u64().unwrap() > 0);
+    assert!(stats["bytes_read"].as_u64().unwrap() > 0);
+    assert!(stats["bytes_written"].as_u64().unwrap() > 0);
+    assert!(stats["seconds"].as_f64().unwrap() >= 0.0);
+    assert_eq!(json["files"][0]["stats"]["frames"], stats["frames"]);
+
+    // Without --stats there is no stats object
+    let json = run_json(&["-g", "1", path.to_str().unwrap()]);
+    assert!(json.get("stats").is_none());
+    assert!(json["files"][0].get("stats").is_none());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_skip_unchanged() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = fs::read(&path).unwrap();
+    let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+
+    // Suggested +3.7 dB (2 steps) lowered by -m -2: nothing to apply
+    let json = run_json(&["--skip-unchanged", "-r", "-m", "-2", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "unchanged");
+    assert_eq!(json["files"][0]["gain_applied_steps"], 0);
+
+    // A fixed gain of 0 steps is reported per file as well
+    let json = run_json(&["--skip-unchanged", "-g", "0", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "unchanged");
+    assert_eq!(fs::read(&path).unwrap(), before);
+    assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+    // Writing the same ReplayGain tags again is a no-op too
+    run(&["-r", "--tags-only", path.to_str().unwrap()]);
+    let tagged = fs::read(&path).unwrap();
+    let json = run_json(&[
+        "--skip-unchanged",
+        "-r",
+        "--tags-only",
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(json["files"][0]["status"], "unchanged");
+    assert_eq!(fs::read(&path).unwrap(), tagged);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_sidecar_records_result() {
+    let file = copy_test_file("test_mono.mp3");
+    let sidecar = PathBuf::from(format!("{}.rgain.json", file.display()));
+
+    run_json(&["-n", "-r", "--sidecar", file.to_str().unwrap()]);
+    assert!(!sidecar.exists(), "dry run must not write a sidecar");
+
+    let json = run_json(&["-r", "--sidecar", file.to_str().unwrap()]);
+    let result = &json["files"][0];
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(&sidecar).expect("sidecar written")).unwrap();
+
+    assert_eq!(report["schema_version"], 1);
+    assert_eq!(report["tool_version"], env!("CARGO_PKG_VERSION"));
+    let timestamp = report["timestamp"].as_str().unwrap();
+    assert_eq!(timestamp.len(), 20);
+    assert!(timestamp.ends_with('Z') && timestamp.as_bytes()[10] == b'T');
+    for key in [
+        "file",
+        "status",
+        "gain_applied_steps",
+        "loudness_db",
+        "peak",
+    ] {
+        assert_eq!(report[key], result[key], "{}", key);
+    }
+    assert_eq!(report["gain_applied_steps"], 2);
+
+    cleanup(&sidecar);
+    cleanup(&file);
+}
+
+#[test]
+fn test_write_minmax_only_tags() {
+    let file = copy_test_file("test_mono.mp3");
+    let original = fs::read(&file).unwrap();
+
+    let json = run_json(&["--write-minmax", file.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+
+    // Only an APE tag is appended; the audio is untouched
+    let tagged = fs::read(&file).unwrap();
+    assert_eq!(&tagged[..original.len()], &original[..]);
+    let tag = read_ape_tag_from_file(&file).unwrap().unwrap();
+    assert_eq!(tag.get("MP3GAIN_MINMAX"), Some("115,210"));
+    assert_eq!(tag.get("MP3GAIN_UNDO"), None);
+
+    // Gain applied by a tool that doesn't tag is measured against the baseline,
+    // which a second run keeps
+    apply_gain(&file, 2).unwrap();
+    let json = run_json(&["--write-minmax", file.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "skipped");
+    assert_eq!(estimate_applied_gain(&file), Some(2));
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_undo_last_reverses_only_latest_adjustment() {
+    let file = copy_test_file("test_mono.mp3");
+    let path = file.to_str().unwrap();
+    let original = fs::read(&file).unwrap();
+    let original_max = analyze(&file).unwrap().max_gain;
+
+    assert!(run(&["-g", "2", path]).status.success());
+    assert!(run(&["-g", "3", path]).status.success());
+    let tag = read_ape_tag_from_file(&file).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+005,+005,N"));
+
+    // Only the +3 goes; the +2 stays applied and recorded
+    let json = run_json(&["-u", "--last", path]);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert_eq!(analyze(&file).unwrap().max_gain, original_max + 2);
+    let tag = read_ape_tag_from_file(&file).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+002,+002,N"));
+    assert_eq!(tag.get(TAG_MP3GAIN_REDO), Some("+003,+003,N"));
+
+    // A plain -u reverses the rest
+    assert!(run(&["-u", path]).status.success());
+    assert_eq!(analyze(&file).unwrap().max_gain, original_max);
+    let undone = fs::read(&file).unwrap();
+    assert_eq!(&undone[..original.len()], &original[..]);
+
+    assert!(!run(&["--last", path]).status.success());
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_canonicalize_tags_keeps_values() {
+    let file = copy_test_file("test_mono.mp3");
+    let original = fs::read(&file).unwrap();
+    let mut tag = ApeTag::new();
+    tag.set(TAG_MP3GAIN_UNDO, "-3, -3 ,w");
+    tag.set(TAG_MP3GAIN_MINMAX, " 95, +210");
+    write_ape_tag(&file, &tag).unwrap();
+    let before = read_ape_tag_from_file(&file).unwrap().unwrap();
+
+    let json = run_json(&["--canonicalize-tags", file.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    let tag = read_ape_tag_from_file(&file).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("-003,-003,W"));
+    assert_eq!(tag.get(TAG_MP3GAIN_MINMAX), Some("95,210"));
+    assert_eq!(tag.get_undo_stack(), before.get_undo_stack());
+    assert_eq!(tag.get_minmax(), before.get_minmax());
+    // Only the tag changed
+    assert_eq!(&fs::read(&file).unwrap()[..original.len()], &original[..]);
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_normalize_tags_rewrites_foreign_undo() {
+    let file = copy_test_file("test_mono.mp3");
+    let original = fs::read(&file).unwrap();
+    apply_gain(&file, 2).unwrap();
+    let mut tag = ApeTag::new();
+    tag.set(TAG_MP3GAIN_UNDO, " 2,2,n");
+    write_ape_tag(&file, &tag).unwrap();
+
+    let json = run_json(&["-s", "c", file.to_str().unwrap()]);
+    assert!(json["files"][0]["warning"]
+        .as_str()
+        .unwrap()
+        .contains("--normalize-tags"));
+
+    let json = run_json(&["-n", "--normalize-tags", file.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "dry_run");
+    let tag = read_ape_tag_from_file(&file).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some(" 2,2,n"));
+
+    let json = run_json(&["--normalize-tags", file.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    let tag = read_ape_tag_from_file(&file).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+002,+002,N"));
+
+    let json = run_json(&["--normalize-tags", file.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "skipped");
+
+    // The rewritten tag still undoes the adjustment
+    assert!(run(&["-u", file.to_str().unwrap()]).status.success());
+    let undone = fs::read(&file).unwrap();
+    assert_eq!(&undone[..original.len()], &original[..]);
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_tag_format_id3v2_check_and_undo() {
+    let file = copy_test_file("test_mono.mp3");
+    let path = file.to_str().unwrap();
+    let before = analyze(&file).unwrap();
+
+    // -s i is mp3gain's spelling of the same thing; undo values accumulate
+    run(&["-g", "2", "--tag-format", "id3v2", path]);
+    run(&["-g", "1", "-s", "i", path]);
+    assert!(read_ape_tag_from_file(&file).unwrap().is_none());
+    assert_eq!(analyze(&file).unwrap().max_gain, before.max_gain + 3);
+
+    let json = run_json(&["-s", "c", path]);
+    assert_eq!(json["files"][0]["tag_format"], "id3v2");
+
+    // The redo information stays in ID3v2, where the undo came from
+    let json = run_json(&["-u", "--keep-redo", path]);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert_eq!(analyze(&file).unwrap().max_gain, before.max_gain);
+    assert!(read_ape_tag_from_file(&file).unwrap().is_none());
+    let json = run_json(&["-s", "c", path]);
+    assert_eq!(json["files"][0]["tag_format"], "id3v2");
+
+    // --redo re-applies the gain and stores it in ID3v2 again
+    let json = run_json(&["--redo", path]);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert_eq!(analyze(&file).unwrap().max_gain, before.max_gain + 3);
+    assert!(read_ape_tag_from_file(&file).unwrap().is_none());
+    let json = run_json(&["-s", "c", path]);
+    assert_eq!(json["files"][0]["tag_format"], "id3v2");
+
+    let json = run_json(&["-u", path]);
+    assert_eq!(json["files"][0]["status"], "success");
+    let json = run_json(&["-s", "c", path]);
+    assert_eq!(json["files"][0]["status"], "no_tag");
+
+    let output = run(&["-g", "1", "--tag-format", "id3", path]);
+    assert!(!output.status.success());
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_batch_undo_skips_files_without_undo_tag() {
+    let gained = copy_test_file("test_mono.mp3");
+    let untouched = copy_test_file("test_mono.mp3");
+    run(&["-g", "2", gained.to_str().unwrap()]);
+
+    let json = run_json(&["-u", gained.to_str().unwrap(), untouched.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert_eq!(json["files"][1]["status"], "skipped");
+    assert_eq!(json["summary"]["successful"], 1);
+    assert_eq!(json["summary"]["failed"], 0);
+
+    cleanup(&gained);
+    cleanup(&untouched);
+}
+
+// =============================================================================
+// Dry Run
+// =============================================================================
+
+#[test]
+fn test_dry_run_lists_tag_changes() {
+    let path = copy_test_file("test_mono.mp3");
+
+    // Fresh file: undo and min/max tags would be added
+    let json = run_json(&["-n", "-g", "-1", path.to_str().unwrap()]);
+    let changes = json["files"][0]["tag_changes"].as_array().unwrap();
+    let added: Vec<&str> = changes
+        .iter()
+        .filter(|c| c["action"] == "add")
+        .map(|c| c["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(added, vec!["MP3GAIN_UNDO", "MP3GAIN_MINMAX"]);
+
+    // After a real apply the undo value changes and the second adjustment
+    // starts the undo stack
+    run(&["-g", "-1", path.to_str().unwrap()]);
+    let json = run_json(&["-n", "-g", "-1", path.to_str().unwrap()]);
+    let changes = json["files"][0]["tag_changes"].as_array().unwrap();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0]["action"], "change");
+    assert_eq!(changes[0]["old"], "-001,-001,N");
+    assert_eq!(changes[0]["new"], "-002,-002,N");
+    assert_eq!(changes[1]["action"], "add");
+    assert_eq!(changes[1]["key"], "MP3GAIN_UNDO_STACK");
+    assert_eq!(changes[1]["new"], "-001,-001,N;-001,-001,N");
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_dry_run_projects_gain_statistics() {
+    // Plain, saturating past the headroom, wrapping, and stopping at a floor
+    let cases: [&[&str]; 4] = [
+        &["-g", "3"],
+        &["-g", "50", "-c"],
+        &["-g", "-120", "-w", "--force"],
+        &["-g", "-20", "--min-floor", "100", "-s", "s"],
+    ];
+    for args in cases {
+        let path = copy_test_file("test_joint_stereo.mp3");
+        let file = path.to_str().unwrap();
+
+        let mut dry = vec!["-n"];
+        dry.extend_from_slice(args);
+        dry.push(file);
+        let json = run_json(&dry);
+        let projected = &json["files"][0];
+
+        let mut apply = args.to_vec();
+        apply.push(file);
+        assert!(run(&apply).status.success());
+        let after = analyze(&path).unwrap();
+
+        assert_eq!(
+            projected["projected_min_gain"], after.min_gain,
+            "{:?}",
+            args
+        );
+        assert_eq!(
+            projected["projected_max_gain"], after.max_gain,
+            "{:?}",
+            args
+        );
+        assert_eq!(
+            projected["projected_avg_gain"], after.avg_gain,
+            "{:?}",
+            args
+        );
+        assert_eq!(
+            projected["projected_headroom"], after.headroom_steps,
+            "{:?}",
+            args
+        );
+
+        cleanup(&path);
+    }
+}
+
+#[test]
+fn test_delete_dry_run_lists_only_gain_keys() {
+    let path = copy_test_file("test_mono.mp3");
+    run(&["-g", "-1", path.to_str().unwrap()]);
+    let mut tag = read_ape_tag_from_file(&path).unwrap().unwrap();
+    tag.set("ARTIST", "Somebody");
+    tag.set("REPLAYGAIN_TRACK_GAIN", "-1.50 dB");
+    write_ape_tag(&path, &tag).unwrap();
+    let before = fs::read(&path).unwrap();
+
+    let json = run_json(&["-n", "-s", "d", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    let removed: Vec<&str> = file["tag_changes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .inspect(|c| assert_eq!(c["action"], "remove"))
+        .map(|c| c["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        removed,
+        vec!["MP3GAIN_UNDO", "MP3GAIN_MINMAX", "REPLAYGAIN_TRACK_GAIN"]
+    );
+    assert!(file["ape_tag_removed"].is_null());
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    // The real delete removes just those and keeps the artist
+    run(&["-s", "d", path.to_str().unwrap()]);
+    let tag = read_ape_tag_from_file(&path).unwrap().unwrap();
+    assert_eq!(tag.get("ARTIST"), Some("Somebody"));
+    assert_eq!(tag.items().len(), 1);
+
+    // Gain keys alone: the whole APEv2 tag would go
+    let fresh = copy_test_file("test_mono.mp3");
+    run(&["-g", "-1", fresh.to_str().unwrap()]);
+    let json = run_json(&["-n", "-s", "d", fresh.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["ape_tag_removed"], true);
+
+    // M4A: the ReplayGain freeform tags
+    let m4a = copy_test_file("test_aac.m4a");
+    run(&["-r", m4a.to_str().unwrap()]);
+    let json = run_json(&["-n", "-s", "d", m4a.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["tag_changes"].as_array().unwrap().len(), 3);
+    run(&["-s", "d", m4a.to_str().unwrap()]);
+    let tags = mp3rgain::mp4meta::read_replaygain_tags(&m4a).unwrap();
+    assert!(tags.track_gain.is_none());
+
+    for path in [path, fresh, m4a] {
+        cleanup(&path);
+    }
+}
+
+// =============================================================================
+// ReplayGain
+// =============================================================================
+
+#[test]
+fn test_tags_only_leaves_audio_untouched() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = fs::read(&path).unwrap();
+    assert!(read_ape_tag_from_file(&path).unwrap().is_none());
+
+    let json = run_json(&["-r", "--tags-only", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    let gain_db = json["files"][0]["gain_applied_db"].as_f64().unwrap();
+    let peak = json["files"][0]["peak"].as_f64().unwrap();
+
+    // Audio is byte-for-byte identical; only a tag was appended
+    let after = fs::read(&path).unwrap();
+    assert!(after.len() > before.len());
+    assert_eq!(&after[..before.len()], &before[..]);
+
+    let tag = read_ape_tag_from_file(&path).unwrap().unwrap();
+    assert_eq!(
+        tag.get("REPLAYGAIN_TRACK_GAIN"),
+        Some(format!("{:+.2} dB", gain_db).as_str())
+    );
+    assert_eq!(
+        tag.get("REPLAYGAIN_TRACK_PEAK"),
+        Some(format!("{:.6}", peak).as_str())
+    );
+    assert!(tag.get("MP3GAIN_UNDO").is_none());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_true_peak_tags() {
+    use mp3rgain::replaygain::{analyze_track, find_true_peak};
+
+    let mono = copy_test_file("test_mono.mp3");
+    let vbr = copy_test_file("test_vbr.mp3");
+    let true_peaks: Vec<f64> = [&mono, &vbr]
+        .iter()
+        .map(|p| find_true_peak(p, None).unwrap())
+        .collect();
+    for (path, true_peak) in [&mono, &vbr].iter().zip(&true_peaks) {
+        assert!(*true_peak >= analyze_track(path).unwrap().peak);
+    }
+
+    let args = ["-a", "--tags-only", "--true-peak-tags"];
+    let mut full = args.to_vec();
+    full.extend([mono.to_str().unwrap(), vbr.to_str().unwrap()]);
+    assert!(run(&full).status.success());
+
+    let album_peak = format!("{:.6}", true_peaks[0].max(true_peaks[1]));
+    for (path, true_peak) in [&mono, &vbr].iter().zip(&true_peaks) {
+        let tag = read_ape_tag_from_file(path).unwrap().unwrap();
+        assert_eq!(
+            tag.get("REPLAYGAIN_TRACK_PEAK"),
+            Some(format!("{:.6}", true_peak).as_str())
+        );
+        assert_eq!(tag.get("REPLAYGAIN_ALBUM_PEAK"), Some(album_peak.as_str()));
+    }
+
+    cleanup(&mono);
+    cleanup(&vbr);
+}
+
+#[test]
+fn test_tag_even_if_zero() {
+    use mp3rgain::replaygain::analyze_track;
+
+    // -d puts the target right at the file's loudness, so the gain is 0 steps
+    let file = copy_test_file("test_mono.mp3");
+    let path = file.to_str().unwrap();
+    let target = format!("{}", -analyze_track(&file).unwrap().gain_db);
+    let original = fs::read(&file).unwrap();
+
+    assert!(run(&["-r", "-d", &target, path]).status.success());
+    assert!(read_ape_tag_from_file(&file).unwrap().is_none());
+
+    let json = run_json(&["-r", "-d", &target, "--tag-even-if-zero", path]);
+    assert_eq!(json["files"][0]["status"], "success");
+    let tag = read_ape_tag_from_file(&file).unwrap().unwrap();
+    assert_eq!(tag.get("REPLAYGAIN_TRACK_GAIN"), Some("+0.00 dB"));
+    assert!(tag.get("REPLAYGAIN_TRACK_PEAK").is_some());
+
+    // Only the tag was added; the audio is as it was
+    let data = fs::read(&file).unwrap();
+    assert_eq!(&data[..original.len()], &original[..]);
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_album_gain_clipping_prevention() {
+    // Album gain for these two is +2 steps; -m 12 pushes it past what the
+    // louder track (test_mono.mp3, peak ~0.119) can take without clipping
+    let mono = copy_test_file("test_mono.mp3");
+    let vbr = copy_test_file("test_vbr.mp3");
+    let before: Vec<u8> = [&mono, &vbr]
+        .iter()
+        .map(|p| analyze(p).unwrap().max_gain)
+        .collect();
+
+    let json = run_json(&[
+        "-a",
+        "-k",
+        "-m",
+        "12",
+        mono.to_str().unwrap(),
+        vbr.to_str().unwrap(),
+    ]);
+    let album = &json["album"];
+    assert_eq!(album["gain_steps"], 12);
+    assert!(album["warning"]
+        .as_str()
+        .unwrap()
+        .contains("from 14 to 12 steps"));
+
+    for (path, max_gain) in [&mono, &vbr].iter().zip(before) {
+        let file = json["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|f| f["file"] == path.to_str().unwrap())
+            .unwrap();
+        assert_eq!(file["gain_applied_steps"], 12);
+        assert_eq!(analyze(path).unwrap().max_gain, max_gain + 12);
+    }
+
+    cleanup(&mono);
+    cleanup(&vbr);
+}
+
+#[test]
+fn test_album_gain_writes_track_and_album_tags() {
+    let mono = copy_test_file("test_mono.mp3");
+    let vbr = copy_test_file("test_vbr.mp3");
+    let files = [mono.to_str().unwrap(), vbr.to_str().unwrap()];
+
+    let json = run_json(&["-a", files[0], files[1]]);
+    let album = &json["album"];
+    let applied_db = album["gain_steps"].as_f64().unwrap() * GAIN_STEP_DB;
+    assert_ne!(applied_db, 0.0);
+
+    let gain = |path: &PathBuf, key: &str| -> f64 {
+        let tag = read_ape_tag_from_file(path).unwrap().unwrap();
+        assert!(tag.get(TAG_MP3GAIN_UNDO).is_some());
+        tag.get(key)
+            .unwrap()
+            .trim_end_matches(" dB")
+            .parse()
+            .unwrap()
+    };
+    let loudness = |path: &PathBuf| -> f64 {
+        json["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|f| f["file"] == path.to_str().unwrap())
+            .unwrap()["loudness_db"]
+            .as_f64()
+            .unwrap()
+    };
+
+    // Both files share the album gain left after the shift...
+    let album_gain = album["gain_db"].as_f64().unwrap() - applied_db;
+    for path in [&mono, &vbr] {
+        assert!((gain(path, "REPLAYGAIN_ALBUM_GAIN") - album_gain).abs() < 0.01);
+    }
+    // ...while the track gains keep the difference in loudness
+    let track_difference =
+        gain(&vbr, "REPLAYGAIN_TRACK_GAIN") - gain(&mono, "REPLAYGAIN_TRACK_GAIN");
+    assert!(track_difference > 1.0);
+    assert!((track_difference - (loudness(&mono) - loudness(&vbr))).abs() < 0.02);
+
+    cleanup(&mono);
+    cleanup(&vbr);
+}
+
+#[test]
+fn test_album_clip_check_converges() {
+    // Same album as above: the check walks 14 steps down to 12 in 3 passes
+    let mono = copy_test_file("test_mono.mp3");
+    let vbr = copy_test_file("test_vbr.mp3");
+    let (mono_path, vbr_path) = (mono.to_str().unwrap(), vbr.to_str().unwrap());
+
+    // The plain +2 step album gain clips nothing and passes the first check
+    let json = run_json(&["-a", "-n", "--album-clip-check", mono_path, vbr_path]);
+    assert_eq!(json["album"]["clip_check"]["iterations"], 1);
+    assert!(json["album"]["warning"].is_null());
+
+    let json = run_json(&["-a", "--album-clip-check", "-m", "12", mono_path, vbr_path]);
+    let album = &json["album"];
+    assert_eq!(album["gain_steps"], 12);
+    assert_eq!(album["clip_check"]["iterations"], 3);
+    assert_eq!(album["clip_check"]["gain_steps"], 12);
+    assert_eq!(album["clip_check"]["clip_free"], true);
+    assert!(album["warning"]
+        .as_str()
+        .unwrap()
+        .contains("from 14 to 12 steps after 3 clipping checks"));
+    for file in json["files"].as_array().unwrap() {
+        assert_eq!(file["gain_applied_steps"], 12);
+    }
+
+    assert!(!run(&["-r", "--album-clip-check", mono_path])
+        .status
+        .success());
+
+    cleanup(&mono);
+    cleanup(&vbr);
+}
+
+#[test]
+fn test_album_dry_run_reports_clipping_tracks() {
+    // +14 steps (21 dB) clips test_mono.mp3 (peak ~0.119) but not
+    // test_vbr.mp3 (peak ~0.089); 12 steps would clip neither
+    let mono = copy_test_file("test_mono.mp3");
+    let vbr = copy_test_file("test_vbr.mp3");
+    let originals = [fs::read(&mono).unwrap(), fs::read(&vbr).unwrap()];
+
+    let json = run_json(&[
+        "-a",
+        "-n",
+        "-m",
+        "12",
+        mono.to_str().unwrap(),
+        vbr.to_str().unwrap(),
+    ]);
+    let clipping = &json["album"]["clipping"];
+    let tracks = clipping["tracks"].as_array().unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0]["file"], mono.to_str().unwrap());
+    let over_db = tracks[0]["over_db"].as_f64().unwrap();
+    assert!(over_db > 0.0 && over_db < GAIN_STEP_DB * 2.0, "{}", over_db);
+    assert_eq!(clipping["safe_gain_steps"], 12);
+
+    let output = run(&[
+        "-a",
+        "-n",
+        "-m",
+        "12",
+        mono.to_str().unwrap(),
+        vbr.to_str().unwrap(),
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("1 track(s) would clip at +21.0 dB"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Largest album gain without clipping: +18.0 dB"));
+
+    assert_eq!(fs::read(&mono).unwrap(), originals[0]);
+    assert_eq!(fs::read(&vbr).unwrap(), originals[1]);
+
+    cleanup(&mono);
+    cleanup(&vbr);
+}
+
+#[test]
+fn test_downmix_mono_on_stereo_file() {
+    let path = copy_test_file("test_stereo.mp3");
+    let file = path.to_str().unwrap();
+    let analyzed = |extra: &[&str]| {
+        let mut args = vec!["-r", "-n"];
+        args.extend_from_slice(extra);
+        args.push(file);
+        run_json(&args)["files"][0].clone()
+    };
+    let stereo = analyzed(&[]);
+    let mono = analyzed(&["--downmix", "mono"]);
+    let loudness = |result: &serde_json::Value| result["loudness_db"].as_f64().unwrap();
+
+    // Averaging the channels can only cancel energy, never add it
+    assert!(loudness(&mono) <= loudness(&stereo) + 0.01);
+    // The peak is that of the file either way
+    assert_eq!(mono["peak"], stereo["peak"]);
+    assert_eq!(
+        loudness(&analyzed(&["--downmix", "stereo"])),
+        loudness(&stereo)
+    );
+    assert!(!run(&["-r", "--downmix", "surround", file]).status.success());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_as_one_matches_concatenation() {
+    let first = copy_test_file("test_stereo.mp3");
+    let second = copy_test_file("test_joint_stereo.mp3");
+    let joined = copy_test_file("test_stereo.mp3");
+
+    // The concatenation: all of the first file, then the second one's audio
+    // frames (without its ID3v2 tag and Info frame)
+    let audio_start = frame_headers(&second, usize::MAX)
+        .unwrap()
+        .into_iter()
+        .find(|frame| !frame.is_info_frame)
+        .unwrap()
+        .offset;
+    let mut data = fs::read(&first).unwrap();
+    data.extend_from_slice(&fs::read(&second).unwrap()[audio_start..]);
+    fs::write(&joined, data).unwrap();
+
+    let files = [first.to_str().unwrap(), second.to_str().unwrap()];
+    let json = run_json(&["-r", "-n", "--as-one", files[0], files[1]]);
+    let concatenated = run_json(&["-r", "-n", joined.to_str().unwrap()]);
+    let loudness = |result: &serde_json::Value| result["loudness_db"].as_f64().unwrap();
+
+    // One gain for both files; the decoder restarting at the join is the
+    // only difference from the concatenation
+    assert_eq!(
+        json["files"][0]["loudness_db"],
+        json["files"][1]["loudness_db"]
+    );
+    assert_eq!(
+        json["files"][0]["gain_applied_steps"],
+        json["files"][1]["gain_applied_steps"]
+    );
+    assert!((loudness(&json["files"][0]) - loudness(&concatenated["files"][0])).abs() < 0.1);
+    assert_eq!(json["files"][0]["peak"], concatenated["files"][0]["peak"]);
+
+    assert!(!run(&["-a", "--as-one", files[0], files[1]])
+        .status
+        .success());
+    let mono = copy_test_file("test_mono.mp3");
+    assert!(!run(&["-r", "--as-one", files[0], mono.to_str().unwrap()])
+        .status
+        .success());
+
+    for path in [&first, &second, &joined, &mono] {
+        cleanup(path);
+    }
+}
+
+#[test]
+fn test_track_gain_clipping_prevention() {
+    // +14 steps would take test_mono.mp3 (peak ~0.119, ~18.5 dB of headroom)
+    // past full scale; -k must settle on the largest gain that still fits
+    let mono = copy_test_file("test_mono.mp3");
+    let max_gain = analyze(&mono).unwrap().max_gain;
+
+    let json = run_json(&["-r", "-k", "-m", "12", mono.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["gain_applied_steps"], 12);
+    assert!(file["warning"]
+        .as_str()
+        .unwrap()
+        .contains("from 14 to 12 steps"));
+    assert_eq!(analyze(&mono).unwrap().max_gain, max_gain + 12);
+
+    cleanup(&mono);
+}
+
+#[test]
+fn test_track_gain_rounds_down_instead_of_clipping() {
+    // Raising frame 0 by 11 steps and frames 1-2 by one leaves test_mono.mp3
+    // wanting +2.47 dB at peak ~0.805. Nearest is 2 steps (peak 1.14); -r
+    // applies 1 (peak 0.96), while the info output still reports nearest.
+    let mono = copy_test_file("test_mono.mp3");
+    let path = mono.to_str().unwrap();
+    for ramp in ["0:0:11:11", "1:2:1:1"] {
+        assert!(run(&["--ramp", ramp, "-s", "s", path]).status.success());
+    }
+    let max_gain = analyze(&mono).unwrap().max_gain;
+
+    let output = run(&["-o", "tsv", "-s", "s", path]);
+    let tsv = String::from_utf8_lossy(&output.stdout);
+    let row: Vec<&str> = tsv.lines().nth(1).unwrap().split('\t').collect();
+    assert_eq!(row[1], "2");
+
+    let json = run_json(&["-r", "-s", "s", path]);
+    assert_eq!(json["files"][0]["gain_applied_steps"], 1);
+    assert_eq!(analyze(&mono).unwrap().max_gain, max_gain + 1);
+
+    cleanup(&mono);
+}
+
+#[test]
+fn test_album_gain_per_group() {
+    // Together test_mono.mp3 and test_vbr.mp3 get +2 steps; on its own
+    // test_vbr.mp3 gets +4. The ungrouped file gets its track gain (+2).
+    let mono = copy_test_file("test_mono.mp3");
+    let vbr = copy_test_file("test_vbr.mp3");
+    let vbr_alone = copy_test_file("test_vbr.mp3");
+    let ungrouped = copy_test_file("test_mono.mp3");
+    let before = |p: &Path| analyze(p).unwrap().max_gain;
+    let expected = [
+        (&mono, before(&mono) + 2),
+        (&vbr, before(&vbr) + 2),
+        (&vbr_alone, before(&vbr_alone) + 4),
+        (&ungrouped, before(&ungrouped) + 2),
+    ];
+
+    let json = run_json(&[
+        "-a",
+        ungrouped.to_str().unwrap(),
+        "--group",
+        mono.to_str().unwrap(),
+        vbr.to_str().unwrap(),
+        "--group",
+        vbr_alone.to_str().unwrap(),
+    ]);
+    assert!(json["album"].is_null());
+
+    let albums = json["albums"].as_array().unwrap();
+    assert_eq!(albums.len(), 2);
+    assert_eq!(albums[0]["gain_steps"], 2);
+    assert_eq!(albums[0]["files"].as_array().unwrap().len(), 2);
+    assert_eq!(albums[1]["gain_steps"], 4);
+    assert_eq!(albums[1]["files"][0]["file"], vbr_alone.to_str().unwrap());
+
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0]["file"], ungrouped.to_str().unwrap());
+    assert_eq!(json["summary"]["total_files"], 4);
+    assert_eq!(json["summary"]["successful"], 4);
+
+    for (path, max_gain) in expected {
+        assert_eq!(analyze(path).unwrap().max_gain, max_gain);
+        cleanup(path);
+    }
+}
+
+#[test]
+fn test_cache_reuses_album_analysis() {
+    let dir = temp_dir("cache");
+    let cache = dir.join("cache");
+    let a = dir.join("a.mp3");
+    let b = dir.join("b.mp3");
+    fs::copy("tests/fixtures/test_stereo.mp3", &a).unwrap();
+    fs::copy("tests/fixtures/test_mono.mp3", &b).unwrap();
+    let args = [
+        "-a",
+        "-n",
+        "--cache",
+        cache.to_str().unwrap(),
+        a.to_str().unwrap(),
+        b.to_str().unwrap(),
+    ];
+
+    // Nothing is analyzed without -r/-a, so the directory isn't created
+    run_json(&[
+        "-n",
+        "--cache",
+        cache.to_str().unwrap(),
+        a.to_str().unwrap(),
+    ]);
+    assert!(!cache.exists());
+
+    let plain = run_json(&["-a", "-n", a.to_str().unwrap(), b.to_str().unwrap()]);
+    let first = run_json(&args);
+    assert_eq!(first, plain);
+
+    // Backdate the entries; one that gets rewritten (after decoding) moves on
+    let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    let entries = || -> Vec<std::time::SystemTime> {
+        let mut times: Vec<_> = fs::read_dir(&cache)
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().modified().unwrap())
+            .collect();
+        times.sort();
+        times
+    };
+    for entry in fs::read_dir(&cache).unwrap() {
+        let file = fs::File::options()
+            .write(true)
+            .open(entry.unwrap().path())
+            .unwrap();
+        file.set_modified(old).unwrap();
+    }
+    assert_eq!(entries(), vec![old, old]);
+
+    // Unchanged files: same gains, nothing decoded
+    assert_eq!(run_json(&args), first);
+    assert_eq!(entries(), vec![old, old]);
+
+    // A modified file is analyzed again, the other still comes from the cache
+    fs::File::options()
+        .write(true)
+        .open(&a)
+        .unwrap()
+        .set_modified(old)
+        .unwrap();
+    assert_eq!(run_json(&args), first);
+    let times = entries();
+    assert_eq!(times[0], old);
+    assert_ne!(times[1], old);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_group_requires_album_gain() {
+    let output = run(&["-r", "--group", "tests/fixtures/test_mono.mp3"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--group requires -a"));
+}
+
+#[test]
+fn test_conflicting_options_are_rejected() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = fs::read(&path).unwrap();
+    let file = path.to_str().unwrap();
+
+    let cases: [(&[&str], &str, &str); 12] = [
+        (&["-r", "-a"], "-r", "-a"),
+        (&["-ar"], "-r", "-a"),
+        (&["-g", "2", "-d", "4.5"], "-g", "-d"),
+        (&["-g", "2", "-r"], "-g", "-r"),
+        (&["-g", "2", "-a"], "-g", "-a"),
+        (&["-g", "2", "-l", "0", "1"], "-g", "-l"),
+        (&["-u", "-g", "2"], "-u", "-g"),
+        (&["-u", "-d", "3"], "-u", "-d"),
+        (&["-u", "-m", "1"], "-u", "-m"),
+        (&["-u", "-l", "1", "2"], "-u", "-l"),
+        (&["-u", "-r"], "-u", "-r"),
+        (&["-w", "-k", "-g", "2"], "-w", "-k"),
+    ];
+    for (args, first, second) in cases {
+        let mut full = args.to_vec();
+        full.push(file);
+        let output = run(&full);
+        assert!(!output.status.success(), "{:?} should fail", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!("{} and {} are mutually exclusive", first, second)),
+            "{:?}: {}",
+            args,
+            stderr
+        );
+    }
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_album_depth_merges_disc_folders() {
+    // Music/Album/Disc 1, Music/Album/Disc 2 and Music/Other
+    let dir = temp_dir("album_depth");
+    let tracks = [
+        ("Album/Disc 1/01.mp3", "test_mono.mp3"),
+        ("Album/Disc 1/02.mp3", "test_vbr.mp3"),
+        ("Album/Disc 2/01.mp3", "test_joint_stereo.mp3"),
+        ("Other/01.mp3", "test_stereo.mp3"),
+    ];
+    for (path, fixture) in tracks {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::copy(Path::new("tests/fixtures").join(fixture), path).unwrap();
+    }
+    let file = |n: usize| dir.join(tracks[n].0).to_str().unwrap().to_string();
+    let album_files = |album: &serde_json::Value| -> Vec<String> {
+        album["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["file"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    // Depth 1: both discs form one album, Other another
+    let json = run_json(&[
+        "-a",
+        "-R",
+        "--album-depth",
+        "1",
+        "-n",
+        dir.to_str().unwrap(),
+    ]);
+    let albums = json["albums"].as_array().unwrap();
+    assert_eq!(albums.len(), 2);
+    assert_eq!(album_files(&albums[0]), vec![file(0), file(1), file(2)]);
+    assert_eq!(album_files(&albums[1]), vec![file(3)]);
+
+    // ...with the gain of an album over all three tracks
+    let whole = run_json(&["-a", "-n", &file(0), &file(1), &file(2)]);
+    assert_eq!(albums[0]["gain_steps"], whole["album"]["gain_steps"]);
+    assert_eq!(albums[0]["loudness_db"], whole["album"]["loudness_db"]);
+
+    // Depth 2 splits the discs again
+    let json = run_json(&[
+        "-a",
+        "-R",
+        "--album-depth",
+        "2",
+        "-n",
+        dir.to_str().unwrap(),
+    ]);
+    let albums = json["albums"].as_array().unwrap();
+    assert_eq!(albums.len(), 3);
+    assert_eq!(album_files(&albums[0]), vec![file(0), file(1)]);
+
+    let output = run(&["-a", "--album-depth", "1", dir.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("requires -a and -R"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_album_clip_safe_lowers_only_clipping_track() {
+    // Album gain is 2 steps; +11 more clips test_mono.mp3 (12 steps of
+    // headroom) but not the other two (14 steps each)
+    let files: Vec<PathBuf> = ["test_mono.mp3", "test_joint_stereo.mp3", "test_vbr.mp3"]
+        .iter()
+        .map(|name| copy_test_file(name))
+        .collect();
+    let paths: Vec<&str> = files.iter().map(|f| f.to_str().unwrap()).collect();
+
+    let json = run_json(&[&["-a", "-m", "11", "--album-clip-safe"], &paths[..]].concat());
+    assert_eq!(json["album"]["gain_steps"], 13);
+    let results = json["files"].as_array().unwrap();
+    let applied: Vec<i64> = results
+        .iter()
+        .map(|r| r["gain_applied_steps"].as_i64().unwrap())
+        .collect();
+    assert_eq!(applied, [12, 13, 13]);
+    assert!(results[0]["warning"]
+        .as_str()
+        .unwrap()
+        .contains("below the album gain"));
+    assert!(results[1].get("warning").is_none());
+    assert!(results[2].get("warning").is_none());
+
+    // -k would lower the whole album instead; the two don't combine
+    let output = run(&[&["-a", "-k", "--album-clip-safe"], &paths[..]].concat());
+    assert!(!output.status.success());
+    let output = run(&["-r", "--album-clip-safe", paths[0]]);
+    assert!(!output.status.success());
+
+    for file in &files {
+        cleanup(file);
+    }
+}
+
+// =============================================================================
+// Debugging
+// =============================================================================
+
+/// Parse `--dump-frames` output into (offset, current, projected) rows
+fn parse_frame_dump(output: &Output) -> Vec<(usize, Vec<u8>, Vec<u8>)> {
+    let parse_gains = |s: &str| s.split(',').map(|g| g.parse().unwrap()).collect();
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let cols: Vec<&str> = line.split('\t').collect();
+            assert_eq!(cols.len(), 3, "bad line: {}", line);
+            (
+                cols[0].parse().unwrap(),
+                parse_gains(cols[1]),
+                parse_gains(cols[2]),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_ramp_option() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = frame_gains(&path, 0, false).unwrap();
+
+    let json = run_json(&["--ramp", "0:4:-4:0", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert_eq!(json["files"][0]["frames"], 5);
+    let after = frame_gains(&path, 0, false).unwrap();
+    let deltas: Vec<i32> = before
+        .iter()
+        .zip(&after)
+        .map(|(old, new)| i32::from(new.current[0]) - i32::from(old.current[0]))
+        .take(6)
+        .collect();
+    assert_eq!(deltas, [-4, -3, -2, -1, 0, 0]);
+
+    for bad in ["0:4:-4", "0:4:-4:0:1", "a:4:-4:0"] {
+        assert!(!run(&["--ramp", bad, path.to_str().unwrap()])
+            .status
+            .success());
+    }
+    assert!(
+        !run(&["--ramp", "0:4:-4:0", "-g", "1", path.to_str().unwrap()])
+            .status
+            .success()
+    );
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_list_frames_json_schema() {
+    let path = "tests/fixtures/test_stereo.mp3";
+    let info = analyze(Path::new(path)).unwrap();
+    let gains = frame_gains(Path::new(path), 0, false).unwrap();
+
+    let json = run_json(&["--list-frames-json", path]);
+    assert_eq!(json["file"], path);
+    let frames = json["frames"].as_array().unwrap();
+    assert_eq!(json["total_frames"], frames.len());
+    let audio: Vec<_> = frames
+        .iter()
+        .filter(|frame| frame["info_frame"] == false)
+        .collect();
+    assert_eq!(audio.len(), info.frame_count);
+
+    for (frame, expected) in audio.iter().zip(&gains) {
+        assert_eq!(frame["offset"], expected.offset);
+        assert_eq!(frame["mpeg_version"], "MPEG1");
+        assert_eq!(frame["sample_rate"], info.sample_rate);
+        assert!(frame["bitrate_kbps"].as_u64().unwrap() > 0);
+        assert!(frame["frame_size"].as_u64().unwrap() > 4);
+        assert!(frame["channel_mode"].is_string());
+        assert!(frame["crc"].is_boolean());
+        // 2 granules x 2 channels, in side-info order
+        let granules = frame["global_gain"].as_array().unwrap();
+        assert_eq!(granules.len(), 2);
+        let values: Vec<u8> = granules
+            .iter()
+            .flat_map(|granule| granule.as_array().unwrap())
+            .map(|value| value.as_u64().unwrap() as u8)
+            .collect();
+        assert_eq!(values, expected.current);
+    }
+
+    let json = run_json(&["--list-frames-json", "--limit", "3", path]);
+    assert_eq!(json["frames"].as_array().unwrap().len(), 3);
+    assert_eq!(json["total_frames"], frames.len());
+    assert!(
+        !run(&["--list-frames-json", path, "tests/fixtures/test_mono.mp3"])
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn test_dump_frames_projection_matches_apply() {
+    let path = copy_test_file("test_stereo.mp3");
+    let info = analyze(&path).unwrap();
+
+    let dump = parse_frame_dump(&run(&["--dump-frames", "-g", "-2", path.to_str().unwrap()]));
+    assert_eq!(dump.len(), info.frame_count);
+    for (_, current, projected) in &dump {
+        // MPEG1 stereo: 2 granules x 2 channels
+        assert_eq!(current.len(), 4);
+        let expected: Vec<u8> = current.iter().map(|g| g.saturating_sub(2)).collect();
+        assert_eq!(projected, &expected);
+    }
+
+    // Applying the gain produces exactly the projected values
+    apply_gain(&path, -2).unwrap();
+    let after = parse_frame_dump(&run(&["--dump-frames", path.to_str().unwrap()]));
+    assert_eq!(after.len(), dump.len());
+    for ((offset, _, projected), (new_offset, current, unchanged)) in dump.iter().zip(&after) {
+        assert_eq!(offset, new_offset);
+        assert_eq!(projected, current);
+        assert_eq!(current, unchanged);
+    }
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_peak_normalize_heuristic_reaches_estimated_target() {
+    // test_mono peaks at global_gain 210, an estimated -67.5 dBFS; -6 dBFS
+    // is reached at 251 (-6.0 dBFS)
+    let path = copy_test_file("test_mono.mp3");
+    let json = run_json(&["--peak-normalize-heuristic", "-6", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["gain_applied_steps"], 41);
+    assert_eq!(analyze(&path).unwrap().max_gain, 251);
+
+    // Between steps the estimate stays below the target: -4 dBFS gives
+    // 252 (-4.5 dBFS), and asking for exactly that changes nothing
+    let json = run_json(&["--peak-normalize-heuristic", "-4", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["gain_applied_steps"], 1);
+    let output = run(&["--peak-normalize-heuristic", "-4.5", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(analyze(&path).unwrap().max_gain, 252);
+
+    let output = run(&["--peak-normalize-heuristic", "3", path.to_str().unwrap()]);
+    assert!(!output.status.success());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_gain_histogram_counts_every_gain_location() {
+    let path = "tests/fixtures/test_vbr.mp3";
+    let info = analyze(Path::new(path)).unwrap();
+
+    let json = run_json(&["--gain-histogram", path]);
+    let file = &json["files"][0];
+    let histogram: Vec<u64> = file["gain_histogram"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|count| count.as_u64().unwrap())
+        .collect();
+    assert_eq!(histogram.len(), 256);
+    assert_eq!(histogram.iter().sum::<u64>(), info.gain_locations as u64);
+    assert_eq!(file["gain_locations"], info.gain_locations);
+    assert!(histogram[info.min_gain as usize] > 0);
+    assert!(histogram[info.max_gain as usize] > 0);
+    assert!(histogram[..info.min_gain as usize].iter().all(|&c| c == 0));
+
+    // TSV: a header, then one line per value with the same counts
+    let output = run(&["-o", "tsv", "--gain-histogram", path]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines[0], "File\tglobal_gain\tCount");
+    let counts: Vec<u64> = lines[1..]
+        .iter()
+        .map(|line| line.rsplit('\t').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(counts, histogram);
+}
+
+#[test]
+fn test_doctor_reports_checks_without_modifying() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = fs::read(&path).unwrap();
+
+    let json = run_json(&["--doctor", path.to_str().unwrap()]);
+    let report = &json["files"][0];
+    let status = |name: &str| {
+        report["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["name"] == name)
+            .map(|c| c["status"].as_str().unwrap().to_string())
+    };
+    assert_eq!(status("format").as_deref(), Some("ok"));
+    assert_eq!(status("id3v2").as_deref(), Some("ok"));
+    assert_eq!(status("apev2").as_deref(), Some("skipped"));
+    assert_eq!(status("frames").as_deref(), Some("ok"));
+    assert_eq!(status("gain_preview").as_deref(), Some("ok"));
+    assert!(status("decode").is_some());
+
+    let frames = report["frames"].as_array().unwrap();
+    assert_eq!(frames.len(), 5);
+    assert_eq!(frames[0]["info_frame"], true);
+    assert_eq!(frames[1]["channel_mode"], "Mono");
+
+    assert_eq!(fs::read(&path).unwrap(), before);
+    cleanup(&path);
+}
+
+#[test]
+fn test_version_json() {
+    let output = run(&["--version-json"]);
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(json["step_db"], 1.5);
+    let caps = mp3rgain::capabilities();
+    assert_eq!(json["features"]["replaygain"], caps.replaygain);
+    assert_eq!(json["features"]["aac"], caps.aac);
+    assert_eq!(json["features"]["flac"], caps.flac);
+    assert_eq!(json["features"]["parallel"], caps.parallel);
+    assert_eq!(json["features"]["id3v2"], true);
+    let rates: Vec<u64> = json["supported_sample_rates"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r.as_u64().unwrap())
+        .collect();
+    assert_eq!(rates.len(), caps.sample_rates.len());
+    assert!(caps
+        .sample_rates
+        .iter()
+        .all(|r| rates.contains(&(*r as u64))));
+}
+
+#[test]
+fn test_explain_gain() {
+    let explain = |args: &[&str]| {
+        let mut full = vec!["--explain"];
+        full.extend_from_slice(args);
+        let output = run(&full);
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    for (steps, db) in [("2", "+3.0"), ("-3", "-4.5"), ("10", "+15.0")] {
+        let text = explain(&["-g", steps]);
+        assert!(
+            text.contains(&format!("-g {} applies exactly {} dB", steps, db)),
+            "{}",
+            text
+        );
+        assert!(text.contains("not loudness normalization"));
+    }
+    assert!(explain(&["-g", "0"]).contains("leaves the audio unchanged"));
+    let text = explain(&["-d", "3"]);
+    assert!(text.contains("from 89 dB to 92 dB"));
+    assert!(text.contains("depends on the file"));
+
+    let json = run_json(&["--explain", "-g", "-2"]);
+    assert_eq!(json[0]["applied_db"], -3.0);
+    assert_eq!(json[0]["applied_db"], global_gain_shift_db(-2));
+    assert!(!run(&["--explain"]).status.success());
+}
+
+#[test]
+fn test_list_formats() {
+    let json = run_json(&["--list-formats"]);
+    let formats = json["formats"].as_array().unwrap();
+    let format = |name: &str| {
+        formats
+            .iter()
+            .find(|f| f["format"] == name)
+            .unwrap_or_else(|| panic!("{} not listed", name))
+    };
+
+    let mp3 = format("MP3");
+    assert_eq!(mp3["frame_gain"], true);
+    assert_eq!(mp3["tag_only"], false);
+    assert_eq!(mp3["undo"], true);
+    assert_eq!(mp3["channel_gain"], true);
+    for name in ["M4A/AAC", "AAC (ADTS)", "FLAC"] {
+        assert_eq!(format(name)["frame_gain"], false);
+        assert_eq!(format(name)["tag_only"], true);
+        assert_eq!(format(name)["channel_gain"], false);
+    }
+    assert_eq!(
+        mp3["replaygain_analysis"],
+        mp3rgain::capabilities().replaygain
+    );
+
+    let text = run(&["--list-formats"]);
+    assert!(text.status.success());
+    let stdout = String::from_utf8_lossy(&text.stdout);
+    assert!(stdout
+        .lines()
+        .any(|line| line.starts_with("M4A/AAC") && line.contains("tag-only")));
+}
+
+#[test]
+fn test_json_compact_prints_one_line_per_document() {
+    let mono = "tests/fixtures/test_mono.mp3";
+    let stereo = "tests/fixtures/test_stereo.mp3";
+    let pretty = run_json(&[mono, stereo]);
+
+    for flag in [&["--json-compact"][..], &["-o", "json-compact"][..]] {
+        let output = run(&[flag, &[mono, stereo]].concat());
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+        assert!(stdout.ends_with('\n'));
+        let compact: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+        assert_eq!(compact, pretty);
+    }
+}
+
+#[test]
+fn test_estimate_projects_batch_without_processing() {
+    let files: Vec<PathBuf> = [
+        "test_mono.mp3",
+        "test_stereo.mp3",
+        "test_vbr.mp3",
+        "test_joint_stereo.mp3",
+    ]
+    .iter()
+    .map(|name| copy_test_file(name))
+    .collect();
+    let paths: Vec<&str> = files.iter().map(|f| f.to_str().unwrap()).collect();
+    let originals: Vec<Vec<u8>> = files.iter().map(|f| fs::read(f).unwrap()).collect();
+
+    let frames = run_json(&[&["--estimate", "-g", "2"], &paths[..]].concat());
+    assert_eq!(frames["action"], "-g");
+    assert_eq!(frames["decodes"], false);
+    let decoded = run_json(&[&["--estimate", "-r"], &paths[..]].concat());
+    assert_eq!(decoded["action"], "-r");
+    assert_eq!(decoded["decodes"], true);
+
+    for json in [&frames, &decoded] {
+        assert_eq!(json["sampled_files"], 3);
+        let estimates = json["files"].as_array().unwrap();
+        assert_eq!(estimates.len(), files.len());
+        let sampled = estimates.iter().filter(|e| e["sampled"] == true).count();
+        assert_eq!(sampled, 3);
+        let total: f64 = estimates
+            .iter()
+            .map(|e| e["seconds"].as_f64().unwrap())
+            .sum();
+        assert!((json["total_seconds"].as_f64().unwrap() - total).abs() < 1e-9);
+        assert!(total > 0.0);
+    }
+
+    for (file, original) in files.iter().zip(&originals) {
+        assert_eq!(&fs::read(file).unwrap(), original);
+        cleanup(file);
+    }
+}
+
+// =============================================================================
+// Playlists
+// =============================================================================
+
+#[test]
+fn test_playlist_as_album() {
+    let dir = temp_dir("playlist");
+    fs::create_dir(dir.join("set")).unwrap();
+    fs::copy("tests/fixtures/test_mono.mp3", dir.join("set/a.mp3")).unwrap();
+    let vbr = copy_test_file("test_vbr.mp3");
+
+    // BOM, CRLF, #EXTINF lines, a relative, an absolute and a missing entry
+    let playlist = dir.join("set.m3u8");
+    fs::write(
+        &playlist,
+        format!(
+            "\u{feff}#EXTM3U\r\n#EXTINF:1,Mono\r\nset/a.mp3\r\n\r\n\
+             #EXTINF:1,VBR\r\n{}\r\nset/missing.mp3\r\n",
+            vbr.display()
+        ),
+    )
+    .unwrap();
+
+    let output = run(&["-o", "json", "-a", "-n", playlist.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("skipping missing entry (set/missing.mp3)"));
+
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files.len(), 2);
+    assert!(files[0]["file"].as_str().unwrap().ends_with("a.mp3"));
+    assert_eq!(files[1]["file"], vbr.to_str().unwrap());
+    assert_eq!(json["album"]["gain_steps"], 2);
+
+    cleanup(&vbr);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// =============================================================================
+// Subcommands
+// =============================================================================
+
+/// Parse the JSON a run printed, whatever comes first on its command line
+fn stdout_json(output: &Output) -> serde_json::Value {
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn test_subcommands_match_legacy_flags() {
+    let sub = copy_test_file("test_mono.mp3");
+    let legacy = copy_test_file("test_mono.mp3");
+    let original = fs::read(&sub).unwrap();
+    let (sub_path, legacy_path) = (sub.to_str().unwrap(), legacy.to_str().unwrap());
+
+    // The file name differs, everything else is the same
+    let analyze = stdout_json(&run(&["analyze", "-o", "json", sub_path]));
+    let info = run_json(&[legacy_path]);
+    assert_eq!(
+        analyze["files"][0]["max_gain"],
+        info["files"][0]["max_gain"]
+    );
+    assert_eq!(analyze["files"][0]["frames"], info["files"][0]["frames"]);
+
+    let measure = stdout_json(&run(&["measure", "-o", "json", sub_path]));
+    let measured = run_json(&["--measure", legacy_path]);
+    assert_eq!(
+        measure["files"][0]["loudness_db"],
+        measured["files"][0]["loudness_db"]
+    );
+
+    assert!(run(&["apply", "-g", "2", sub_path]).status.success());
+    assert!(run(&["-g", "2", legacy_path]).status.success());
+    assert_eq!(fs::read(&sub).unwrap(), fs::read(&legacy).unwrap());
+    assert_ne!(fs::read(&sub).unwrap(), original);
+
+    assert!(run(&["apply", "-r", "-c", sub_path]).status.success());
+    assert!(run(&["-r", "-c", legacy_path]).status.success());
+    assert_eq!(fs::read(&sub).unwrap(), fs::read(&legacy).unwrap());
+
+    assert!(run(&["undo", "--keep-redo", sub_path]).status.success());
+    assert!(run(&["-u", "--keep-redo", legacy_path]).status.success());
+    assert_eq!(fs::read(&sub).unwrap(), fs::read(&legacy).unwrap());
+
+    assert!(run(&["redo", sub_path]).status.success());
+    assert!(run(&["--redo", legacy_path]).status.success());
+    assert_eq!(fs::read(&sub).unwrap(), fs::read(&legacy).unwrap());
+
+    assert!(run(&["strip", sub_path]).status.success());
+    assert!(run(&["-s", "d", legacy_path]).status.success());
+    assert_eq!(fs::read(&sub).unwrap(), fs::read(&legacy).unwrap());
+    assert!(read_ape_tag_from_file(&sub).unwrap().is_none());
+
+    cleanup(&sub);
+    cleanup(&legacy);
+}
+
+#[test]
+fn test_subcommand_rejects_other_actions() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = fs::read(&path).unwrap();
+
+    for (args, message) in [
+        (
+            &["undo", "-g", "2"][..],
+            "`mp3rgain undo` cannot be combined with -g",
+        ),
+        (
+            &["analyze", "-r"][..],
+            "`mp3rgain analyze` cannot be combined with -r",
+        ),
+        (
+            &["measure", "-u"][..],
+            "`mp3rgain measure` cannot be combined with -u",
+        ),
+        (
+            &["apply", "-x"][..],
+            "`mp3rgain apply` cannot be combined with -x",
+        ),
+        (
+            &["apply", "-d", "3"][..],
+            "`mp3rgain apply` needs a gain option",
+        ),
+    ] {
+        let mut args = args.to_vec();
+        args.push(path.to_str().unwrap());
+        let output = run(&args);
+        assert!(!output.status.success(), "{:?}", args);
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains(message),
+            "{:?}: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    // Options that agree with the subcommand are fine
+    assert!(run(&["undo", "-u", path.to_str().unwrap()])
+        .status
+        .success());
+    assert!(run(&["analyze", "-x", path.to_str().unwrap()])
+        .status
+        .success());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_file_named_like_subcommand() {
+    let dir = temp_dir("subcommand_names");
+    for name in ["apply", "undo"] {
+        fs::copy("tests/fixtures/test_mono.mp3", dir.join(name)).unwrap();
+    }
+    let run_in_dir = |args: &[&str]| -> Output {
+        Command::new(env!("CARGO_BIN_EXE_mp3rgain"))
+            .args(args)
+            .current_dir(&dir)
+            .output()
+            .unwrap()
+    };
+    let min_gain = |name: &str| -> serde_json::Value {
+        let output = run_in_dir(&["-o", "json", "--", name]);
+        assert!(output.status.success());
+        stdout_json(&output)["files"][0]["min_gain"].clone()
+    };
+    let before = min_gain("apply");
+
+    // After an option, a subcommand name is just a file
+    assert!(run_in_dir(&["-g", "1", "apply"]).status.success());
+    assert_eq!(min_gain("apply"), before.as_u64().unwrap() + 1);
+    assert!(run_in_dir(&["-g", "1", "undo"]).status.success());
+    assert_eq!(min_gain("undo"), before.as_u64().unwrap() + 1);
+
+    // After `--`, even the first argument is a file
+    assert!(run_in_dir(&["apply", "-g", "1", "--", "apply"])
+        .status
+        .success());
+    assert_eq!(min_gain("apply"), before.as_u64().unwrap() + 2);
+    let json = stdout_json(&run_in_dir(&["-o", "json", "--", "undo", "-g"]));
+    assert_eq!(json["files"][0]["file"], "undo");
+    assert_eq!(json["files"][1]["file"], "-g");
+    assert_eq!(json["files"][1]["status"], "error");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// =============================================================================
+// Links
+// =============================================================================
+
+/// Helper to create an empty temp directory with a unique name
+fn temp_dir(name: &str) -> PathBuf {
+    let id = TEST_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let dir = std::env::temp_dir().join(format!("mp3rgain_cli_test_{}_{}", id, name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_natural_sort_orders_track2_before_track10() {
+    let dir = temp_dir("natural_sort");
+    for name in ["track10.mp3", "track2.mp3", "track1.mp3"] {
+        fs::copy("tests/fixtures/test_mono.mp3", dir.join(name)).unwrap();
+    }
+    let names = |json: &serde_json::Value| -> Vec<String> {
+        json["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| {
+                let file = Path::new(f["file"].as_str().unwrap());
+                file.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect()
+    };
+    let dir_arg = dir.to_str().unwrap();
+
+    // Plain path order puts track10 first, natural order by value
+    assert_eq!(
+        names(&run_json(&["-R", dir_arg])),
+        ["track1.mp3", "track10.mp3", "track2.mp3"]
+    );
+    assert_eq!(
+        names(&run_json(&["-R", "--natural-sort", dir_arg])),
+        ["track1.mp3", "track2.mp3", "track10.mp3"]
+    );
+    // Album mode sorts naturally by default, even files given out of order
+    let files: Vec<String> = ["track10.mp3", "track2.mp3", "track1.mp3"]
+        .iter()
+        .map(|name| dir.join(name).to_string_lossy().into_owned())
+        .collect();
+    let mut args = vec!["-a", "-n"];
+    args.extend(files.iter().map(|f| f.as_str()));
+    assert_eq!(
+        names(&run_json(&args)),
+        ["track1.mp3", "track2.mp3", "track10.mp3"]
+    );
+    // Without -R or -a, files keep the order given unless --sort asks
+    assert_eq!(
+        names(&run_json(&[&files[0], &files[1]])),
+        ["track10.mp3", "track2.mp3"]
+    );
+    assert_eq!(
+        names(&run_json(&[
+            "--sort",
+            "name",
+            "--natural-sort",
+            &files[0],
+            &files[1]
+        ])),
+        ["track2.mp3", "track10.mp3"]
+    );
+    assert!(!run(&["--sort", "size", &files[0]]).status.success());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_recursion_handles_symlink_loop() {
+    let dir = temp_dir("symlink_loop");
+    fs::copy("tests/fixtures/test_mono.mp3", dir.join("song.mp3")).unwrap();
+    std::os::unix::fs::symlink(&dir, dir.join("loop")).unwrap();
+    std::os::unix::fs::symlink(dir.join("song.mp3"), dir.join("alias.mp3")).unwrap();
+
+    // The symlinked directory is skipped by default, and the symlinked file
+    // is the same file as song.mp3
+    let json = run_json(&["-R", dir.to_str().unwrap()]);
+    assert_eq!(json["files"].as_array().unwrap().len(), 1);
+
+    // Following them still visits each file once and terminates
+    let json = run_json(&["-R", "--follow-symlinks", dir.to_str().unwrap()]);
+    assert_eq!(json["files"].as_array().unwrap().len(), 1);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_recursion_collects_symlinked_files() {
+    let dir = temp_dir("symlinked_file");
+    let target = copy_test_file("test_mono.mp3");
+    std::os::unix::fs::symlink(&target, dir.join("linked.mp3")).unwrap();
+
+    // Only symlinked directories need --follow-symlinks
+    let json = run_json(&["-R", dir.to_str().unwrap()]);
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1);
+    assert!(files[0]["file"].as_str().unwrap().ends_with("linked.mp3"));
+
+    let _ = fs::remove_dir_all(&dir);
+    cleanup(&target);
+}
+
+#[test]
+fn test_since_selects_recently_modified_files() {
+    use std::time::{Duration, SystemTime};
+
+    let dir = temp_dir("since");
+    fs::create_dir(dir.join("old_album")).unwrap();
+    let set_mtime = |name: &str, time: SystemTime| {
+        let path = dir.join(name);
+        fs::copy("tests/fixtures/test_mono.mp3", &path).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    };
+    let year_2000 = SystemTime::UNIX_EPOCH + Duration::from_secs(946_684_800);
+    set_mtime("old_album/old.mp3", year_2000);
+    set_mtime(
+        "week_old.mp3",
+        SystemTime::now() - Duration::from_secs(7 * 86400),
+    );
+    set_mtime("new.mp3", SystemTime::now());
+
+    let selected = |since: &str| -> Vec<String> {
+        let json = run_json(&["-R", "--since", since, dir.to_str().unwrap()]);
+        json["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| {
+                let file = f["file"].as_str().unwrap();
+                Path::new(file)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into()
+            })
+            .collect()
+    };
+    assert_eq!(
+        selected("2000-01-01T00:00:00Z"),
+        ["new.mp3", "week_old.mp3"]
+    );
+    assert_eq!(
+        selected("2000-01-01T00:30:00+01:00"),
+        ["new.mp3", "old.mp3", "week_old.mp3"]
+    );
+    assert_eq!(selected("1d"), ["new.mp3"]);
+
+    // Nothing new is not an error
+    let output = run(&[
+        "-R",
+        "--since",
+        "1h",
+        dir.join("old_album").to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no audio files modified since"));
+
+    let output = run(&["-R", "--since", "yesterday", dir.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid time: yesterday"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_max_file_size_skips_large_files() {
+    let dir = temp_dir("max_file_size");
+    fs::copy("tests/fixtures/test_mono.mp3", dir.join("small.mp3")).unwrap();
+    fs::copy("tests/fixtures/test_stereo.mp3", dir.join("large.mp3")).unwrap();
+    let large = fs::read(dir.join("large.mp3")).unwrap();
+
+    // test_mono.mp3 is under 10 kB, test_stereo.mp3 over
+    let output = run(&[
+        "-R",
+        "-g",
+        "1",
+        "--max-file-size",
+        "10K",
+        dir.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("large.mp3: skipped"), "{}", stderr);
+    assert!(!stderr.contains("small.mp3"));
+    assert_eq!(fs::read(dir.join("large.mp3")).unwrap(), large);
+
+    let json = run_json(&["-R", "--max-file-size", "10000", dir.to_str().unwrap()]);
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1);
+    assert!(files[0]["file"].as_str().unwrap().ends_with("small.mp3"));
+
+    let output = run(&["--max-file-size", "lots", dir.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid size: lots"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_quiet_errors_counts_without_printing() {
+    let dir = temp_dir("quiet_errors");
+    fs::copy("tests/fixtures/test_mono.mp3", dir.join("song.mp3")).unwrap();
+    fs::write(dir.join("notes.mp3"), "not audio").unwrap();
+    fs::write(dir.join("cover.mp3"), "not audio either").unwrap();
+
+    // By default each failure is printed and the run still succeeds
+    let output = run(&["-R", dir.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("No valid MP3 frames").count(), 2);
+    assert!(output.status.success());
+
+    for args in [&["-R"][..], &["-R", "-g", "1"], &["-R", "-o", "json"]] {
+        let mut args = args.to_vec();
+        args.extend(["--quiet-errors", dir.to_str().unwrap()]);
+        let output = run(&args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            !stderr.contains("No valid MP3 frames"),
+            "{:?}: {}",
+            args,
+            stderr
+        );
+        // JSON output carries the errors itself and leaves stderr empty
+        if args.contains(&"json") {
+            assert!(stderr.is_empty(), "{:?}: {}", args, stderr);
+        } else {
+            assert!(
+                stderr.contains("2 file(s) failed"),
+                "{:?}: {}",
+                args,
+                stderr
+            );
+        }
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&output.stdout).contains("song.mp3"));
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hardlinks_with_temp_file() {
+    let dir = temp_dir("hardlinks");
+    let original = dir.join("a.mp3");
+    let link = dir.join("b.mp3");
+    fs::copy("tests/fixtures/test_mono.mp3", &original).unwrap();
+    fs::hard_link(&original, &link).unwrap();
+
+    // In-place write keeps the link
+    run(&["-g", "-1", original.to_str().unwrap()]);
+    assert_eq!(fs::read(&original).unwrap(), fs::read(&link).unwrap());
+
+    // -t with --in-place-truncate keeps the link
+    run(&[
+        "-t",
+        "--in-place-truncate",
+        "-g",
+        "-1",
+        original.to_str().unwrap(),
+    ]);
+    assert_eq!(fs::read(&original).unwrap(), fs::read(&link).unwrap());
+    assert_eq!(analyze(&link).unwrap().min_gain, 113);
+
+    // Plain -t renames over the original, detaching the link
+    run(&["-t", "-g", "-1", original.to_str().unwrap()]);
+    assert_ne!(fs::read(&original).unwrap(), fs::read(&link).unwrap());
+    assert_eq!(analyze(&link).unwrap().min_gain, 113);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_temp_dir_on_another_filesystem() {
+    // /dev/shm is a tmpfs where available, so the temp file has to cross
+    // filesystems to replace the original; elsewhere a separate directory
+    // still takes the path that doesn't rename within one directory
+    let shm = Path::new("/dev/shm");
+    let temp = if shm.is_dir() && fs::metadata(shm).is_ok_and(|m| !m.permissions().readonly()) {
+        let dir = shm.join(format!(
+            "mp3rgain_cli_test_{}",
+            TEST_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    } else {
+        temp_dir("temp_dir")
+    };
+    let path = copy_test_file("test_mono.mp3");
+    let expected = copy_test_file("test_mono.mp3");
+    run(&["-g", "2", expected.to_str().unwrap()]);
+
+    let output = run(&[
+        "--temp-dir",
+        temp.to_str().unwrap(),
+        "-g",
+        "2",
+        path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(fs::read(&path).unwrap(), fs::read(&expected).unwrap());
+    // The temp file is gone
+    assert_eq!(fs::read_dir(&temp).unwrap().count(), 0);
+
+    let missing = temp.join("missing");
+    assert!(!run(&["--temp-dir", missing.to_str().unwrap(), "-g", "1"])
+        .status
+        .success());
+
+    cleanup(&path);
+    cleanup(&expected);
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_preserve_all_keeps_mode_owner_and_mtime() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let path = copy_test_file("test_mono.mp3");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    let before = fs::metadata(&path).unwrap();
+
+    for extra in [&[][..], &["-t"][..]] {
+        let mut args = vec!["--preserve-all", "-g", "-1"];
+        args.extend_from_slice(extra);
+        args.push(path.to_str().unwrap());
+        assert!(run(&args).status.success());
+
+        let after = fs::metadata(&path).unwrap();
+        assert_eq!(after.permissions().mode() & 0o7777, 0o644, "{:?}", extra);
+        assert_eq!((after.uid(), after.gid()), (before.uid(), before.gid()));
+        assert_eq!(after.modified().unwrap(), mtime);
+    }
+    assert_eq!(analyze(&path).unwrap().min_gain, 113);
+
+    cleanup(&path);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_read_only_file_reports_clear_error() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = copy_test_file("test_mono.mp3");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o444)).unwrap();
+    if fs::File::options().write(true).open(&path).is_ok() {
+        // Running as root: permissions aren't enforced
+        cleanup(&path);
+        return;
+    }
+    let before = analyze(&path).unwrap().min_gain;
+
+    for extra in [&[][..], &["-t", "--in-place-truncate"][..]] {
+        let mut args = vec!["-g", "1"];
+        args.extend_from_slice(extra);
+        args.push(path.to_str().unwrap());
+        let json = run_json(&args);
+        let file = &json["files"][0];
+        assert_eq!(file["status"], "error", "{:?}", extra);
+        assert!(file["error"]
+            .as_str()
+            .unwrap()
+            .contains("file is read-only"));
+    }
+    assert_eq!(analyze(&path).unwrap().min_gain, before);
+
+    // -t replaces the file through its (writable) directory, keeping the mode
+    let json = run_json(&["-g", "1", "-t", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert_eq!(analyze(&path).unwrap().min_gain, before + 1);
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o444
+    );
+
+    cleanup(&path);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_album_gain_continues_past_read_only_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let first = copy_test_file("test_mono.mp3");
+    let locked = copy_test_file("test_vbr.mp3");
+    let last = copy_test_file("test_mono.mp3");
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o444)).unwrap();
+    if fs::File::options().write(true).open(&locked).is_ok() {
+        // Running as root: permissions aren't enforced
+        for path in [&first, &locked, &last] {
+            cleanup(path);
+        }
+        return;
+    }
+    let before = analyze(&first).unwrap().min_gain;
+    let locked_before = fs::read(&locked).unwrap();
+
+    let json = run_json(&[
+        "-a",
+        first.to_str().unwrap(),
+        locked.to_str().unwrap(),
+        last.to_str().unwrap(),
+    ]);
+    let steps = json["album"]["gain_steps"].as_i64().unwrap();
+    assert!(steps > 0);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert_eq!(json["files"][1]["status"], "error");
+    assert_eq!(json["files"][2]["status"], "success");
+    assert_eq!(json["summary"]["failed"], 1);
+    assert!(json["album"]["warning"]
+        .as_str()
+        .unwrap()
+        .contains("1 of 3 file(s) failed"));
+
+    for path in [&first, &last] {
+        assert_eq!(
+            analyze(path).unwrap().min_gain as i64,
+            before as i64 + steps
+        );
+    }
+    assert_eq!(fs::read(&locked).unwrap(), locked_before);
+
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o644)).unwrap();
+    for path in [&first, &locked, &last] {
+        cleanup(path);
+    }
+}
+
+#[test]
+fn test_concurrent_temp_file_applies_in_one_directory() {
+    let dir = temp_dir("concurrent_temp");
+    let names = ["a.mp3", "b.mp3", "c.mp3", "d.mp3"];
+    for name in names {
+        fs::copy("tests/fixtures/test_mono.mp3", dir.join(name)).unwrap();
+    }
+    let paths: Vec<String> = names
+        .iter()
+        .map(|n| dir.join(n).to_str().unwrap().to_string())
+        .collect();
+
+    // Two runs at once, each writing two files through -t temp files
+    let children: Vec<_> = paths
+        .chunks(2)
+        .map(|pair| {
+            Command::new(env!("CARGO_BIN_EXE_mp3rgain"))
+                .args(["-t", "-g", "-1", &pair[0], &pair[1]])
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+
+    for path in &paths {
+        assert_eq!(analyze(Path::new(path)).unwrap().min_gain, 114);
+    }
+    let leftovers: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            e.file_name()
+                .to_string_lossy()
+                .starts_with(".mp3rgain_temp")
+        })
+        .collect();
+    assert!(leftovers.is_empty(), "temp files left behind");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_channel_gain_both_channels_in_one_run() {
+    let path = copy_test_file("test_stereo.mp3");
+    apply_gain(&path, -5).unwrap();
+    let before = fs::read(&path).unwrap();
+
+    let json = run_json(&["-l", "0", "3", "-l", "1", "-2", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert_eq!(json["files"][0]["left_gain_steps"], 3);
+    assert_eq!(json["files"][0]["right_gain_steps"], -2);
+
+    // Left and right differ now; undo restores both exactly
+    let dump = String::from_utf8(run(&["--dump-frames", path.to_str().unwrap()]).stdout).unwrap();
+    let first = dump.lines().nth(1).unwrap();
+    assert_eq!(first.split('\t').nth(1).unwrap(), "253,248,253,248");
+
+    run(&["-u", path.to_str().unwrap()]);
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    cleanup(&path);
+}
+
+// =============================================================================
+// RVA2
+// =============================================================================
+
+#[test]
+fn test_check_tags_reports_rva2() {
+    let json = run_json(&["-s", "c", "tests/fixtures/test_rva2.mp3"]);
+    let file = &json["files"][0];
+    assert_eq!(file["rva2_gain_db"], -3.5);
+    assert_eq!(file["rva2_peak"], 0.75);
+}
+
+#[test]
+fn test_apply_rva2_keeps_remainder_in_frame() {
+    let path = copy_test_file("test_rva2.mp3");
+
+    // -3.5 dB rounds to -2 steps (-3.0 dB), leaving -0.5 dB in the frame
+    let json = run_json(&["--apply-rva2", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["status"], "success");
+    assert_eq!(file["gain_applied_steps"], -2);
+    assert_eq!(analyze(&path).unwrap().min_gain, 113);
+
+    let frames = mp3rgain::id3v2::read_rva2_from_file(&path).unwrap();
+    assert_eq!(
+        mp3rgain::id3v2::track_master(&frames)
+            .unwrap()
+            .adjustment_db,
+        -0.5
+    );
+    assert_eq!(frames[1].master().unwrap().adjustment_db, -5.0);
+
+    // Nothing left to apply at step resolution
+    let json = run_json(&["--apply-rva2", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "skipped");
+    assert_eq!(analyze(&path).unwrap().min_gain, 113);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_apply_replaygain_tag() {
+    let path = copy_test_file("test_vbr.mp3");
+    let untagged = copy_test_file("test_mono.mp3");
+    let before = analyze(&path).unwrap();
+
+    let mut tag = ApeTag::new();
+    tag.set(TAG_REPLAYGAIN_TRACK_GAIN, "+4.50 dB");
+    write_ape_tag(&path, &tag).unwrap();
+
+    // +4.5 dB rounds to +3 steps, leaving +0.00 dB in the tag
+    let json = run_json(&[
+        "--apply-replaygain-tag",
+        path.to_str().unwrap(),
+        untagged.to_str().unwrap(),
+    ]);
+    let file = &json["files"][0];
+    assert_eq!(file["status"], "success");
+    assert_eq!(file["gain_applied_steps"], 3);
+    assert_eq!(file["replaygain_track_gain"], "+4.50 dB");
+    assert_eq!(analyze(&path).unwrap().max_gain, before.max_gain + 3);
+
+    let tag = read_ape_tag_from_file(&path).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+003,+003,N"));
+    assert_eq!(tag.get(TAG_REPLAYGAIN_TRACK_GAIN), Some("+0.00 dB"));
+
+    // Without a track gain there is nothing to apply
+    assert_eq!(json["files"][1]["status"], "error");
+
+    cleanup(&path);
+    cleanup(&untagged);
+}
+
+#[test]
+fn test_rva2_mode_writes_frames_instead_of_gain() {
+    let mono = copy_test_file("test_mono.mp3");
+    let vbr = copy_test_file("test_vbr.mp3");
+    let before = analyze(&mono).unwrap();
+
+    let json = run_json(&[
+        "-a",
+        "--rva2",
+        mono.to_str().unwrap(),
+        vbr.to_str().unwrap(),
+    ]);
+    let album_db = json["album"]["gain_db"].as_f64().unwrap();
+    let file = json["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|f| f["file"] == mono.to_str().unwrap())
+        .unwrap();
+    assert_eq!(file["status"], "success");
+    let track_db = file["gain_applied_db"].as_f64().unwrap();
+
+    // Audio and APEv2 tags are untouched
+    let after = analyze(&mono).unwrap();
+    assert_eq!(
+        (after.min_gain, after.max_gain, after.frame_count),
+        (before.min_gain, before.max_gain, before.frame_count)
+    );
+    assert!(read_ape_tag_from_file(&mono).unwrap().is_none());
+
+    // Adjustments round-trip within the 1/512 dB field resolution
+    let frames = mp3rgain::id3v2::read_rva2_from_file(&mono).unwrap();
+    let value = |id: &str| {
+        let frame = frames.iter().find(|f| f.identification == id).unwrap();
+        frame.master().unwrap().adjustment_db
+    };
+    assert!((value("track") - track_db).abs() <= 1.0 / 1024.0);
+    assert!((value("album") - album_db).abs() <= 1.0 / 1024.0);
+
+    cleanup(&mono);
+    cleanup(&vbr);
+}
+
+// =============================================================================
+// AAC / M4A
+// =============================================================================
+
+#[test]
+fn test_info_on_m4a_file() {
+    let path = copy_test_file("test_aac.m4a");
+
+    let json = run_json(&[path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["status"], "info");
+    assert_eq!(file["format"], "M4A/AAC");
+    assert_eq!(file["sample_rate"], 44100);
+    assert_eq!(file["channels"], 2);
+    // 64 AAC frames of 1024 samples
+    let duration = file["duration_secs"].as_f64().unwrap();
+    assert!((duration - 64.0 * 1024.0 / 44100.0).abs() < 1e-6);
+    assert!(file["replaygain_track_gain"].is_null());
+
+    // Tags written by -r show up in the info output
+    run(&["-r", path.to_str().unwrap()]);
+    let tags = mp3rgain::mp4meta::read_replaygain_tags(&path).unwrap();
+    let json = run_json(&[path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(
+        file["replaygain_track_gain"].as_str(),
+        tags.track_gain.as_deref()
+    );
+    assert!(file["replaygain_track_peak"].is_string());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_custom_target_writes_reference_loudness_to_m4a() {
+    let default_path = copy_test_file("test_aac.m4a");
+    let custom_path = copy_test_file("test_aac.m4a");
+    assert!(run(&["-r", default_path.to_str().unwrap()])
+        .status
+        .success());
+    assert!(run(&["-r", "-d", "3", custom_path.to_str().unwrap()])
+        .status
+        .success());
+
+    let default = mp3rgain::mp4meta::read_replaygain_tags(&default_path).unwrap();
+    let custom = mp3rgain::mp4meta::read_replaygain_tags(&custom_path).unwrap();
+    assert_eq!(default.reference_loudness_db(), Some(89.0));
+    assert_eq!(custom.reference_loudness_db(), Some(92.0));
+
+    let gain = |tags: &mp3rgain::mp4meta::ReplayGainTags| -> f64 {
+        let value = tags.track_gain.as_deref().unwrap();
+        value.trim_end_matches(" dB").parse().unwrap()
+    };
+    assert!((gain(&custom) - gain(&default) - 3.0).abs() < 0.011);
+
+    cleanup(&default_path);
+    cleanup(&custom_path);
+}
+
+#[test]
+fn test_album_gain_db_writes_known_album_tags() {
+    let path = copy_test_file("test_aac.m4a");
+    let mut tags = mp3rgain::mp4meta::ReplayGainTags::default();
+    tags.set_track(-3.0, 0.5);
+    tags.set_album(-1.0, 0.7);
+    mp3rgain::mp4meta::write_replaygain_tags(&path, &tags).unwrap();
+    let original = fs::read(&path).unwrap();
+
+    let output = run(&[
+        "--album-gain-db",
+        "-5.5",
+        "--album-peak",
+        "0.9",
+        "-n",
+        path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    run(&[
+        "--album-gain-db",
+        "-5.5",
+        "--album-peak",
+        "0.9",
+        path.to_str().unwrap(),
+    ]);
+    let written = mp3rgain::mp4meta::read_replaygain_tags(&path).unwrap();
+    assert_eq!(written.album_gain.as_deref(), Some("-5.50 dB"));
+    assert_eq!(written.album_peak.as_deref(), Some("0.900000"));
+    assert_eq!(written.track_gain, tags.track_gain);
+    assert_eq!(written.track_peak, tags.track_peak);
+
+    // Without --album-peak the old peak doesn't outlive its gain
+    run(&["--album-gain-db", "-4", path.to_str().unwrap()]);
+    let written = mp3rgain::mp4meta::read_replaygain_tags(&path).unwrap();
+    assert_eq!(written.album_gain.as_deref(), Some("-4.00 dB"));
+    assert_eq!(written.album_peak, None);
+
+    let output = run(&["--album-peak", "0.9", path.to_str().unwrap()]);
+    assert!(!output.status.success());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_track_gain_on_flac_writes_vorbis_comments() {
+    let path = copy_test_file("test_flac.flac");
+
+    let json = run_json(&["-r", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["status"], "success");
+    let tag = mp3rgain::flac::read_replaygain_tag(&path).unwrap().unwrap();
+    let gain = format!("{:+.2} dB", file["gain_applied_db"].as_f64().unwrap());
+    assert_eq!(tag.get("REPLAYGAIN_TRACK_GAIN"), Some(gain.as_str()));
+    assert!(read_ape_tag_from_file(&path).unwrap().is_none());
+
+    let json = run_json(&["-g", "1", path.to_str().unwrap()]);
+    assert!(json["files"][0]["error"].as_str().unwrap().contains("FLAC"));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_track_gain_estimate_is_not_applied() {
+    // test_mono.mp3 behind more zero padding than symphonia's probe searches
+    let path = copy_test_file("test_mono.mp3");
+    let mut data = vec![0u8; 1100 * 1024];
+    data.extend_from_slice(&fs::read(&path).unwrap());
+    fs::write(&path, &data).unwrap();
+
+    let json = run_json(&["-r", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    assert_eq!(file["method"], "heuristic");
+    assert_eq!(file["status"], "skipped");
+    assert!(file["suggested_gain_steps"].as_i64().unwrap() > 0);
+    assert!(file["warning"].as_str().unwrap().contains("approximate"));
+    assert_eq!(fs::read(&path).unwrap(), data);
+
+    let json = run_json(&["-r", "-n", "tests/fixtures/test_mono.mp3"]);
+    assert_eq!(json["files"][0]["method"], "decoded");
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_show_continuous_reports_quantization_error() {
+    let json = run_json(&[
+        "-r",
+        "-n",
+        "--show-continuous",
+        "tests/fixtures/test_stereo.mp3",
+    ]);
+    let file = &json["files"][0];
+    let requested = file["requested_gain_db"].as_f64().unwrap();
+    let applied = file["gain_applied_db"].as_f64().unwrap();
+    let error = file["quantization_error_db"].as_f64().unwrap();
+    assert!((error - (applied - requested)).abs() < 1e-9);
+    assert!(error.abs() <= 0.75);
+
+    // -d moves the target, so it adds to the requested gain before rounding
+    let json = run_json(&[
+        "-r",
+        "-n",
+        "-d",
+        "4",
+        "--show-continuous",
+        "tests/fixtures/test_stereo.mp3",
+    ]);
+    let file = &json["files"][0];
+    let raised = file["requested_gain_db"].as_f64().unwrap();
+    assert!((raised - (requested + 4.0)).abs() < 1e-9);
+    assert_eq!(
+        file["gain_applied_steps"].as_f64().unwrap(),
+        (raised / GAIN_STEP_DB).round()
+    );
+    assert!(
+        (file["quantization_error_db"].as_f64().unwrap() - mp3rgain::quantization_error_db(raised))
+            .abs()
+            < 1e-9
+    );
+
+    // Only requested with the flag
+    let json = run_json(&["-r", "-n", "tests/fixtures/test_stereo.mp3"]);
+    assert!(json["files"][0].get("quantization_error_db").is_none());
+}
+
+#[test]
+fn test_channel_gain_on_joint_stereo_warns() {
+    let path = copy_test_file("test_joint_stereo.mp3");
+
+    let output = run(&["-l", "0", "2", "-n", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("joint stereo"));
+
+    let json = run_json(&["-l", "0", "2", path.to_str().unwrap()]);
+    assert_eq!(json["files"][0]["status"], "success");
+    assert!(json["files"][0]["warning"]
+        .as_str()
+        .unwrap()
+        .contains("mid/side"));
+
+    // Equal gains on both channels are unaffected by the stereo coding
+    let json = run_json(&["-l", "0", "1", "-l", "1", "1", path.to_str().unwrap()]);
+    assert!(json["files"][0].get("warning").is_none());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_measure_reports_loudness_without_modifying() {
+    let path = copy_test_file("test_stereo.mp3");
+    let original = fs::read(&path).unwrap();
+
+    // A target change must not matter: nothing but the measurement is reported
+    let json = run_json(&["--measure", "-d", "5", path.to_str().unwrap()]);
+    let file = &json["files"][0];
+    let expected = mp3rgain::replaygain::analyze_track(&path).unwrap();
+    assert!((file["loudness_db"].as_f64().unwrap() - expected.loudness_db).abs() < 1e-9);
+    assert!((file["peak"].as_f64().unwrap() - expected.peak).abs() < 1e-9);
+    assert_eq!(file["sample_rate"], 44100);
+    assert!(file.get("gain_applied_steps").is_none());
+    assert!(file.get("suggested_gain_steps").is_none());
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_measure_reads_stdin() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    for name in ["test_stereo.mp3", "test_mono.mp3"] {
+        let fixture = Path::new("tests/fixtures").join(name);
+        let mut child = Command::new(env!("CARGO_BIN_EXE_mp3rgain"))
+            .args(["-o", "json", "-r", "--measure", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let data = fs::read(&fixture).unwrap();
+        child.stdin.take().unwrap().write_all(&data).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let file = &json["files"][0];
+        assert_eq!(file["file"], "-");
+        let expected = mp3rgain::replaygain::analyze_track(&fixture).unwrap();
+        assert!((file["loudness_db"].as_f64().unwrap() - expected.loudness_db).abs() < 1e-9);
+        assert!((file["peak"].as_f64().unwrap() - expected.peak).abs() < 1e-9);
+        assert_eq!(file["sample_rate"], expected.sample_rate);
+    }
+}
+
+#[test]
+fn test_compare_tools_reports_both_methods() {
+    let fixture = Path::new("tests/fixtures/test_mono.mp3");
+    let json = run_json(&["--compare-tools", fixture.to_str().unwrap()]);
+    let compared = &json["files"][0]["compare_tools"];
+
+    // min/max global_gain 115/210: the frame estimate puts the peak 45 steps down
+    let heuristic = &compared["heuristic"];
+    assert_eq!(heuristic["headroom_steps"], 45);
+    assert!((heuristic["peak_dbfs"].as_f64().unwrap() + 67.5).abs() < 1e-9);
+
+    let decoded = &compared["decoded"];
+    let expected = mp3rgain::replaygain::analyze_track(fixture).unwrap();
+    assert!((decoded["peak"].as_f64().unwrap() - expected.peak).abs() < 1e-9);
+    assert!((decoded["loudness_db"].as_f64().unwrap() - expected.loudness_db).abs() < 1e-9);
+
+    let delta = &compared["delta"];
+    for key in ["peak_dbfs", "loudness_db"] {
+        let difference = heuristic[key].as_f64().unwrap() - decoded[key].as_f64().unwrap();
+        assert!(
+            (delta[key].as_f64().unwrap() - difference).abs() < 1e-9,
+            "{}",
+            key
+        );
+    }
+    assert_eq!(
+        delta["headroom_steps"].as_i64().unwrap(),
+        45 - decoded["headroom_steps"].as_i64().unwrap()
+    );
+
+    let text = run(&["--compare-tools", fixture.to_str().unwrap()]);
+    let text = String::from_utf8_lossy(&text.stdout);
+    assert!(text.contains("Heuristic") && text.contains("Decoded") && text.contains("Delta"));
+}
+
+#[test]
+fn test_check_clipping_exit_status() {
+    let stereo = copy_test_file("test_stereo.mp3");
+    let mono = copy_test_file("test_mono.mp3");
+    let before = fs::read(&mono).unwrap();
+    let files = [stereo.to_str().unwrap(), mono.to_str().unwrap()];
+
+    // At 89 dB neither file needs enough gain to clip
+    let output = run(&["--check-clipping", files[0], files[1]]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("No file would clip"));
+
+    // At 105 dB the mono file (peak -18.5 dBFS) needs +13 steps and clips,
+    // while the full-scale stereo one still gets a cut
+    let output = run(&["--check-clipping", "-d", "16", files[0], files[1]]);
+    assert_eq!(output.status.code(), Some(2));
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(text.contains(&*mono.file_name().unwrap().to_string_lossy()));
+    assert!(!text.contains(&*stereo.file_name().unwrap().to_string_lossy()));
+    assert!(text.contains("1 of 2 file(s) would clip"));
+
+    let json = run_json(&["--check-clipping", "-d", "16", files[0], files[1]]);
+    assert_eq!(json["files"][0]["clips"], false);
+    assert_eq!(json["files"][1]["clips"], true);
+    assert_eq!(json["files"][1]["suggested_gain_steps"], 13);
+
+    // Nothing is written
+    assert_eq!(fs::read(&mono).unwrap(), before);
+    assert!(read_ape_tag_from_file(&mono).unwrap().is_none());
+
+    cleanup(&stereo);
+    cleanup(&mono);
+}
+
+#[test]
+fn test_match_levels_files_to_quietest() {
+    let files: Vec<PathBuf> = ["test_mono.mp3", "test_joint_stereo.mp3", "test_vbr.mp3"]
+        .iter()
+        .map(|name| copy_test_file(name))
+        .collect();
+    apply_gain(&files[2], -4).unwrap();
+    let paths: Vec<&str> = files.iter().map(|f| f.to_str().unwrap()).collect();
+    let loudness = |f: &Path| mp3rgain::replaygain::analyze_track(f).unwrap().loudness_db;
+    let before: Vec<f64> = files.iter().map(|f| loudness(f)).collect();
+    assert!(before[0] - before[2] > 6.0 && before[1] - before[2] > 3.0);
+
+    let mut args = vec!["--match", "quietest"];
+    args.extend(&paths);
+    let json = run_json(&args);
+    assert_eq!(json["target"]["mode"], "quietest");
+    let target = json["target"]["loudness_db"].as_f64().unwrap();
+    assert!((target - before[2]).abs() < 1e-9);
+    assert_eq!(json["summary"]["failed"], 0);
+    assert_eq!(json["files"][2]["gain_applied_steps"], 0);
+
+    for file in &files {
+        assert!(
+            (loudness(file) - target).abs() < 0.75,
+            "{} vs {}",
+            loudness(file),
+            target
+        );
+    }
+
+    for file in &files {
+        cleanup(file);
+    }
+}
+
+/// Analysis of test_mono.mp3 and test_vbr.mp3 in the layout of the original
+/// mp3gain (1.6, no options)
+const LEGACY_OUTPUT: &str = "\
+tests/fixtures/test_mono.mp3
+Recommended \"Track\" dB change: 3.700000
+Recommended \"Track\" mp3 gain change: 2
+Max PCM sample at current gain: 3896.272461
+Max mp3 global gain field: 210
+Min mp3 global gain field: 115
+
+tests/fixtures/test_vbr.mp3
+Recommended \"Track\" dB change: 6.260000
+Recommended \"Track\" mp3 gain change: 4
+Max PCM sample at current gain: 2906.312744
+Max mp3 global gain field: 210
+Min mp3 global gain field: 136
+
+Recommended \"Album\" dB change for all files: 3.700000
+Recommended \"Album\" mp3 gain change for all files: 2
+";
+
+#[test]
+fn test_legacy_output_matches_mp3gain_layout() {
+    let output = run(&[
+        "--legacy-output",
+        "tests/fixtures/test_mono.mp3",
+        "tests/fixtures/test_vbr.mp3",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), LEGACY_OUTPUT);
+
+    // A single file has no album recommendation; -m adds to the mp3 gain change
+    let output = run(&[
+        "--legacy-output",
+        "-m",
+        "14",
+        "tests/fixtures/test_mono.mp3",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Recommended \"Track\" mp3 gain change: 16\n"));
+    assert!(stdout.contains("WARNING: some clipping may occur with this gain change!\n"));
+    assert!(!stdout.contains("Album"));
+}
+
+#[test]
+fn test_best_effort_measures_damaged_file() {
+    // Corrupt an AAC packet in the middle of the mdat box
+    let path = copy_test_file("test_aac.m4a");
+    let mut data = fs::read(&path).unwrap();
+    let mdat = data.windows(4).position(|w| w == b"mdat").unwrap();
+    let size = u32::from_be_bytes(data[mdat - 4..mdat].try_into().unwrap()) as usize;
+    let corrupt = mdat + 4 + size / 4;
+    data[corrupt..corrupt + 3].fill(0xFF);
+    fs::write(&path, &data).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let json = run_json(&["--measure", path_str]);
+    assert_eq!(json["files"][0]["status"], "error");
+
+    let json = run_json(&["--measure", "--best-effort", path_str]);
+    assert_eq!(json["files"][0]["partial"], true);
+    assert!(json["files"][0]["loudness_db"].as_f64().is_some());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_duplicate_file_is_processed_once() {
+    let file = copy_test_file("test_mono.mp3");
+    let path = file.to_str().unwrap();
+    // The same file again, spelled differently
+    let tmp = std::env::temp_dir();
+    let dir_name = tmp.file_name().unwrap().to_str().unwrap();
+    let other = tmp
+        .join("..")
+        .join(dir_name)
+        .join(file.file_name().unwrap());
+    let other = other.to_str().unwrap();
+
+    let json = run_json(&["-g", "2", path, other, path]);
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1, "{}", json);
+
+    let after = run_json(&[path]);
+    assert_eq!(after["files"][0]["max_gain"], 212);
+    assert_eq!(after["files"][0]["min_gain"], 117);
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_path_style_applies_to_text_and_json() {
+    let dir = temp_dir("path_style");
+    fs::create_dir(dir.join("album")).unwrap();
+    let file = dir.join("album").join("track.mp3");
+    fs::copy("tests/fixtures/test_mono.mp3", &file).unwrap();
+    let path = file.to_str().unwrap();
+
+    let run_in_dir = |args: &[&str]| -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_mp3rgain"))
+            .args(args)
+            .current_dir(&dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let json_file = |style: Option<&str>| -> String {
+        let mut args = vec!["-o", "json"];
+        if let Some(style) = style {
+            args.extend(["--path-style", style]);
+        }
+        args.push(path);
+        let json: serde_json::Value = serde_json::from_str(&run_in_dir(&args)).unwrap();
+        json["files"][0]["file"].as_str().unwrap().to_string()
+    };
+
+    // Defaults: JSON has the path as given, text the file name
+    assert_eq!(json_file(None), path);
+    assert_eq!(json_file(Some("full")), path);
+    assert_eq!(json_file(Some("basename")), "track.mp3");
+    let relative = Path::new("album").join("track.mp3");
+    assert_eq!(json_file(Some("relative")), relative.to_str().unwrap());
+
+    let text = run_in_dir(&[path]);
+    assert!(
+        text.contains("track.mp3") && !text.contains(path),
+        "{}",
+        text
+    );
+    assert!(run_in_dir(&["--path-style", "full", path]).contains(path));
+    let text = run_in_dir(&["--path-style", "relative", path]);
+    assert!(text.contains(relative.to_str().unwrap()), "{}", text);
+
+    let output = run(&["--path-style", "short", path]);
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_progress_modes() {
+    // The bar only draws on a terminal, so this checks that every mode is
+    // accepted and leaves the output alone; bad values are rejected
+    let file = copy_test_file("test_mono.mp3");
+    let path = file.to_str().unwrap();
+    let plain = run(&[path]);
+    assert!(plain.status.success());
+
+    for args in [
+        &["--progress", "always"][..],
+        &["--progress", "never"],
+        &["--progress", "auto", "--progress-threshold", "1"],
+        &["--progress", "always", "-q"],
+    ] {
+        let mut full = args.to_vec();
+        full.push(path);
+        let output = run(&full);
+        assert!(output.status.success(), "{:?}", args);
+        if !args.contains(&"-q") {
+            assert_eq!(output.stdout, plain.stdout, "{:?}", args);
+        }
+    }
+
+    assert!(!run(&["--progress", "sometimes", path]).status.success());
+    assert!(!run(&["--progress-threshold", "few", path]).status.success());
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_info_reports_bad_frames() {
+    let file = copy_test_file("test_stereo.mp3");
+    let path = file.to_str().unwrap();
+    let frames = frame_gains(&file, 0, false).unwrap();
+    let bad_offset = frames[frames.len() / 2].offset;
+    let mut data = fs::read(&file).unwrap();
+    data[bad_offset..bad_offset + 4].fill(0);
+    fs::write(&file, &data).unwrap();
+
+    let json = run_json(&[path]);
+    let result = &json["files"][0];
+    assert_eq!(result["bad_frames"], 1);
+    assert_eq!(result["bad_frame_offsets"][0], bad_offset);
+
+    let output = run(&[path]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Bad frames:"));
+
+    // The gain still reaches the frames on both sides of the corrupt one
+    let before = frame_gains(&file, 1, false).unwrap();
+    assert!(run(&["-g", "1", path]).status.success());
+    let after = frame_gains(&file, 0, false).unwrap();
+    assert_eq!(after.len(), frames.len() - 1);
+    assert!(before
+        .iter()
+        .zip(&after)
+        .all(|(before, after)| before.projected == after.current));
+
+    cleanup(&file);
+}
+
+#[test]
+fn test_rg_debug_window_count() {
+    use mp3rgain::replaygain::{stream_info, window_samples};
+
+    // Every complete 50ms window of the decoded track is counted; the
+    // partial last one is dropped
+    let path = "tests/fixtures/test_stereo.mp3";
+    let info = stream_info(Path::new(path), None).unwrap();
+    let samples = (info.duration_secs.unwrap() * info.sample_rate as f64).round() as u64;
+    let window = window_samples(info.sample_rate);
+    assert_eq!(window, 2205);
+
+    let json = run_json(&["--rg-debug", path]);
+    let params = &json["files"][0]["analysis_params"];
+    assert_eq!(params["filter_sample_rate"], info.sample_rate);
+    assert_eq!(params["window_samples"], window);
+    assert_eq!(params["windows"], samples / window as u64);
+    let bucket = params["percentile_bucket"].as_f64().unwrap();
+    let loudness = json["files"][0]["loudness_db"].as_f64().unwrap();
+    assert!((bucket / 100.0 - loudness).abs() < 1e-9);
+
+    let meta = &json["files"][0]["replaygain_meta"];
+    assert_eq!(meta["algorithm"], "ReplayGain 1.0");
+    assert_eq!(meta["pink_ref"], 64.82);
+    assert_eq!(meta["reference_db"], 89.0);
+    assert_eq!(meta["target_db"], 89.0);
+    assert_eq!(meta["version"], env!("CARGO_PKG_VERSION"));
+    let json = run_json(&["--rg-debug", "-d", "2", path]);
+    assert_eq!(json["files"][0]["replaygain_meta"]["target_db"], 91.0);
+
+    let tsv = run(&["--rg-debug", "-o", "tsv", path]);
+    let stdout = String::from_utf8_lossy(&tsv.stdout);
+    assert!(stdout.starts_with("File\tFilter rate\tWindow samples\tWindows"));
+}
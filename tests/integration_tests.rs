@@ -3,7 +3,22 @@
 //! These tests use real MP3 files in tests/fixtures/ to verify
 //! the correctness of gain application, undo, and channel-specific operations.
 
-use mp3rgain::{analyze, apply_gain, apply_gain_channel, apply_gain_with_undo, undo_gain, Channel};
+use mp3rgain::id3v2;
+use mp3rgain::replaygain;
+use mp3rgain::{
+    analyze, analyze_file, analyze_many, apply_gain, apply_gain_bytes, apply_gain_channel,
+    apply_gain_channel_with_undo, apply_gain_channels_with_undo, apply_gain_checked,
+    apply_gain_file, apply_gain_floor, apply_gain_granule, apply_gain_many, apply_gain_ramp,
+    apply_gain_safe, apply_gain_to_avg_target, apply_gain_with_undo, apply_gain_with_undo_bytes,
+    apply_gain_with_undo_floor, apply_gain_with_undo_wrap, compare_gains, convert_gain_tag,
+    detect_audio_type, estimate_applied_gain, frame_gains, frame_headers, gain_would_change,
+    has_supported_extension, is_adts_file, measure_max_amplitude, read_ape_tag,
+    read_ape_tag_from_file, read_gain_tag, read_info_header, redo_gain, repair_ape_tag,
+    stored_gain_tag_format, supported_extensions, undo_gain, undo_gain_keep_redo, write_ape_tag,
+    ApeItemType, ApeTag, CancellationToken, Channel, ClipPolicy, FileModifiedDuringOperation,
+    FileSnapshot, GainOp, Interrupted, TagFormat, GAIN_TAG_KEYS, TAG_MP3GAIN_MINMAX,
+    TAG_MP3GAIN_REDO, TAG_MP3GAIN_UNDO, TAG_REPLAYGAIN_TRACK_GAIN,
+};
 use std::fs;
 use std::path::Path;
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -63,6 +78,64 @@ fn test_analyze_mono_file() {
     assert_eq!(info.channel_mode, "Mono");
 }
 
+/// Bit positions of every global_gain in the MPEG2.5 fixtures, worked out
+/// from the spec: 288-byte frames with one granule, whose side info starts
+/// after the 4-byte header with main_data_begin (8 bits) and the private bits
+/// (1 for mono, 2 for stereo), followed by a 63-bit block per channel with
+/// global_gain 21 bits in
+fn mpeg25_gain_bits(frames: usize, channels: usize) -> Vec<usize> {
+    (0..frames)
+        .flat_map(|frame| {
+            (0..channels).map(move |ch| (frame * 288 + 4) * 8 + 8 + channels + ch * 63 + 21)
+        })
+        .collect()
+}
+
+fn read_bits_at(data: &[u8], bit: usize) -> u8 {
+    let word = u16::from_be_bytes([data[bit / 8], data[bit / 8 + 1]]);
+    (word >> (8 - bit % 8)) as u8
+}
+
+#[test]
+fn test_mpeg25_analyze_apply_and_undo() {
+    for (name, channels, min_gain, max_gain) in [
+        ("test_mpeg25_mono.mp3", 1, 150, 156),
+        ("test_mpeg25_stereo.mp3", 2, 140, 156),
+    ] {
+        let path = copy_test_file(name);
+        let original = fs::read(&path).unwrap();
+        let bits = mpeg25_gain_bits(24, channels);
+
+        let info = analyze(&path).unwrap();
+        assert_eq!(info.mpeg_version, "MPEG2.5");
+        assert_eq!(info.sample_rate, 8000);
+        assert_eq!(info.frame_count, 24);
+        assert_eq!(info.gain_locations, bits.len());
+        assert_eq!((info.min_gain, info.max_gain), (min_gain, max_gain));
+        let gains: Vec<u8> = bits.iter().map(|&b| read_bits_at(&original, b)).collect();
+        assert_eq!(gains.iter().min(), Some(&min_gain), "{}", name);
+        assert_eq!(gains.iter().max(), Some(&max_gain), "{}", name);
+
+        // Exactly the gain fields move; every other bit stays
+        assert_eq!(apply_gain_with_undo(&path, 3).unwrap(), 24);
+        let gained = fs::read(&path).unwrap();
+        let mut expected = original.clone();
+        for (&bit, &gain) in bits.iter().zip(&gains) {
+            let shift = 8 - bit % 8;
+            let mask = !(0xFF_u16 << shift);
+            let word = u16::from_be_bytes([expected[bit / 8], expected[bit / 8 + 1]]);
+            let word = (word & mask) | (u16::from(gain + 3) << shift);
+            expected[bit / 8..bit / 8 + 2].copy_from_slice(&word.to_be_bytes());
+        }
+        assert_eq!(&gained[..original.len()], &expected[..], "{}", name);
+
+        assert_eq!(undo_gain(&path).unwrap(), 24);
+        assert_eq!(&fs::read(&path).unwrap()[..original.len()], &original[..]);
+
+        cleanup(&path);
+    }
+}
+
 #[test]
 fn test_analyze_vbr_file() {
     let path = Path::new("tests/fixtures/test_vbr.mp3");
@@ -77,6 +150,258 @@ fn test_analyze_vbr_file() {
     assert!(info.frame_count > 0, "Should have frames");
 }
 
+#[test]
+fn test_analyze_dual_channel_file() {
+    let path = Path::new("tests/fixtures/test_dual_channel.mp3");
+    let info = analyze(path).unwrap();
+    assert!(info.frame_count > 0, "Should have frames");
+    assert_eq!(info.channel_mode, "Dual Channel");
+}
+
+#[test]
+fn test_replaygain_reports_channel_layout() {
+    if !replaygain::is_available() {
+        return;
+    }
+
+    let dual =
+        replaygain::analyze_track(Path::new("tests/fixtures/test_dual_channel.mp3")).unwrap();
+    assert_eq!(dual.channel_layout, replaygain::ChannelLayout::DualChannel);
+
+    let stereo = replaygain::analyze_track(Path::new("tests/fixtures/test_stereo.mp3")).unwrap();
+    assert_eq!(stereo.channel_layout, replaygain::ChannelLayout::Stereo);
+
+    let mono = replaygain::analyze_track(Path::new("tests/fixtures/test_mono.mp3")).unwrap();
+    assert_eq!(mono.channel_layout, replaygain::ChannelLayout::Mono);
+}
+
+/// Write a copy of test_mono.mp3 that symphonia can't probe: its frames are
+/// preceded by more zero bytes than the probe searches, but the frame parser
+/// still finds them
+fn write_undecodable_mp3() -> std::path::PathBuf {
+    let path = copy_test_file("test_mono.mp3");
+    let mut data = fs::read(&path).unwrap();
+    if &data[0..3] == b"ID3" {
+        let size = ((data[6] as usize) << 21)
+            | ((data[7] as usize) << 14)
+            | ((data[8] as usize) << 7)
+            | data[9] as usize;
+        data.drain(..10 + size);
+    }
+    let mut padded = vec![0u8; 1100 * 1024];
+    padded.extend_from_slice(&data);
+    fs::write(&path, &padded).unwrap();
+    path
+}
+
+#[test]
+fn test_replaygain_falls_back_to_estimate() {
+    if !replaygain::is_available() {
+        return;
+    }
+
+    let path = write_undecodable_mp3();
+    assert!(replaygain::analyze_track(&path).is_err());
+
+    let info = analyze(&path).unwrap();
+    let result = replaygain::analyze_track_or_estimate(&path, None).unwrap();
+    assert_eq!(result.method, replaygain::AnalysisMethod::Heuristic);
+    assert_eq!(result.sample_rate, info.sample_rate);
+    assert_eq!(result.channel_layout, replaygain::ChannelLayout::Mono);
+    // Estimated peak sits exactly at the global_gain headroom
+    assert!((result.gain_db - info.headroom_db).abs() < 1e-9);
+
+    // Decodable files are analyzed normally
+    let decoded =
+        replaygain::analyze_track_or_estimate(Path::new("tests/fixtures/test_mono.mp3"), None)
+            .unwrap();
+    assert_eq!(decoded.method, replaygain::AnalysisMethod::Decoded);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_max_amplitude_decoded_vs_heuristic() {
+    let path = write_undecodable_mp3();
+
+    // Without decodable audio, -x falls back to the global_gain estimate
+    // (max global_gain 210 = 45 steps of headroom) unless decoding is required
+    let estimated = measure_max_amplitude(&path, false).unwrap();
+    assert_eq!(estimated.method, replaygain::AnalysisMethod::Heuristic);
+    assert_eq!(estimated.max_gain, 210);
+    assert!((estimated.peak - 10f64.powf(-67.5 / 20.0)).abs() < 1e-12);
+    assert!(measure_max_amplitude(&path, true).is_err());
+
+    if replaygain::is_available() {
+        // test_mono.mp3 decodes to a known peak of ~0.119
+        let decoded =
+            measure_max_amplitude(Path::new("tests/fixtures/test_mono.mp3"), true).unwrap();
+        assert_eq!(decoded.method, replaygain::AnalysisMethod::Decoded);
+        assert!((decoded.peak - 0.1189).abs() < 0.001);
+        assert!(decoded.peak > estimated.peak);
+    }
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_adts_detection() {
+    assert!(is_adts_file(Path::new("tests/fixtures/test_adts.aac")));
+    assert!(!is_adts_file(Path::new("tests/fixtures/test_stereo.mp3")));
+    assert!(!is_adts_file(Path::new("tests/fixtures/test_mono.mp3")));
+
+    if replaygain::is_available() {
+        let result = replaygain::analyze_track(Path::new("tests/fixtures/test_adts.aac")).unwrap();
+        assert_eq!(result.file_type, Some(replaygain::AudioFileType::AacAdts));
+        assert_eq!(result.sample_rate, 44100);
+    }
+}
+
+#[test]
+fn test_id3_tagged_adts_is_not_mp3() {
+    // test_adts.aac behind a 128-byte ID3v2.3 tag (title, encoder, padding)
+    let path = copy_test_file("test_id3_adts.aac");
+    let before = fs::read(&path).unwrap();
+    assert!(is_adts_file(&path));
+
+    // The MP3 paths name the stream instead of reporting a broken MP3
+    for err in [
+        analyze(&path).unwrap_err(),
+        apply_gain(&path, 1).unwrap_err(),
+        apply_gain_with_undo(&path, 1).unwrap_err(),
+    ] {
+        assert!(err.to_string().contains("ADTS"), "{}", err);
+    }
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    if replaygain::is_available() {
+        let result = replaygain::analyze_track(&path).unwrap();
+        assert_eq!(result.file_type, Some(replaygain::AudioFileType::AacAdts));
+        let tagged = replaygain::stream_info(&path, None).unwrap();
+        let plain =
+            replaygain::stream_info(Path::new("tests/fixtures/test_adts.aac"), None).unwrap();
+        assert_eq!(tagged.sample_rate, 44100);
+        assert_eq!(tagged.duration_secs, plain.duration_secs);
+    }
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_detect_audio_type_ignores_extension() {
+    use replaygain::AudioFileType;
+
+    let fixtures = [
+        ("test_stereo.mp3", AudioFileType::Mp3),
+        ("test_leading_junk.mp3", AudioFileType::Mp3),
+        ("test_aac.m4a", AudioFileType::Aac),
+        ("test_adts.aac", AudioFileType::AacAdts),
+        ("test_id3_adts.aac", AudioFileType::AacAdts),
+        ("test_flac.flac", AudioFileType::Flac),
+    ];
+    for (name, file_type) in fixtures {
+        let path = Path::new("tests/fixtures").join(name);
+        assert_eq!(detect_audio_type(&path), Some(file_type), "{}", name);
+    }
+
+    // Copy `data` to a temp file with the given (misleading) name
+    let mislabeled = |data: &[u8], name: &str| {
+        let id = TEST_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("mp3rgain_test_{}_{}", id, name));
+        fs::write(&path, data).unwrap();
+        path
+    };
+    let mp3 = fs::read("tests/fixtures/test_mono.mp3").unwrap();
+    let m4a = fs::read("tests/fixtures/test_aac.m4a").unwrap();
+    let adts = fs::read("tests/fixtures/test_adts.aac").unwrap();
+
+    let m4a_as_mp3 = mislabeled(&m4a, "m4a.mp3");
+    assert_eq!(detect_audio_type(&m4a_as_mp3), Some(AudioFileType::Aac));
+    let mp3_as_m4a = mislabeled(&mp3, "mp3.m4a");
+    assert_eq!(detect_audio_type(&mp3_as_m4a), Some(AudioFileType::Mp3));
+
+    // ADTS behind an ID3v2 tag (the 44-byte tag of test_mono.mp3)
+    let tagged_adts = mislabeled(&[&mp3[..44], &adts[..]].concat(), "adts.mp3");
+    assert_eq!(
+        detect_audio_type(&tagged_adts),
+        Some(AudioFileType::AacAdts)
+    );
+    assert!(is_adts_file(&tagged_adts));
+
+    let text = mislabeled(b"not audio at all, just a text file", "text.mp3");
+    assert_eq!(detect_audio_type(&text), None);
+    assert_eq!(
+        detect_audio_type(Path::new("tests/fixtures/missing.mp3")),
+        None
+    );
+
+    if replaygain::is_available() {
+        let result = replaygain::analyze_track(&m4a_as_mp3).unwrap();
+        assert_eq!(result.file_type, Some(AudioFileType::Aac));
+        let result = replaygain::analyze_track(&mp3_as_m4a).unwrap();
+        assert_eq!(result.file_type, Some(AudioFileType::Mp3));
+    }
+
+    for path in [m4a_as_mp3, mp3_as_m4a, tagged_adts, text] {
+        cleanup(&path);
+    }
+}
+
+#[test]
+fn test_supported_extensions() {
+    for ext in ["mp3", "m4a", "mp4", "aac", "flac"] {
+        assert!(supported_extensions().contains(&ext));
+    }
+    assert!(has_supported_extension(Path::new("album/01 Track.MP3")));
+    assert!(has_supported_extension(Path::new("video.mp4")));
+    assert!(!has_supported_extension(Path::new("cover.jpg")));
+    assert!(!has_supported_extension(Path::new("mp3")));
+}
+
+#[test]
+fn test_frame_headers_and_tag_summary() {
+    let path = Path::new("tests/fixtures/test_stereo.mp3");
+    let info = analyze(path).unwrap();
+
+    let headers = frame_headers(path, 3).unwrap();
+    assert_eq!(headers.len(), 3);
+    assert_eq!(headers[0].offset, 44);
+    assert!(headers[0].is_info_frame);
+    assert!(!headers[1].is_info_frame);
+    assert_eq!(headers[1].offset, headers[0].offset + headers[0].frame_size);
+    for header in &headers {
+        assert_eq!(header.mpeg_version, info.mpeg_version);
+        assert_eq!(header.sample_rate, info.sample_rate);
+        assert_eq!(header.bitrate_kbps, 128);
+    }
+
+    let tag = id3v2::read_tag_summary(path).unwrap().unwrap();
+    assert_eq!(tag.major_version, 4);
+    assert_eq!(tag.size, 34);
+    assert_eq!(tag.frame_ids, vec!["TSSE".to_string()]);
+}
+
+#[test]
+fn test_compare_gains_against_regained_copy() {
+    let a = copy_test_file("test_vbr.mp3");
+    let b = copy_test_file("test_vbr.mp3");
+    apply_gain(&b, 3).unwrap();
+
+    let diff = compare_gains(&a, &b).unwrap();
+    assert!(diff.frame_counts_match());
+    assert!((diff.avg_steps - 3.0).abs() < 1e-9);
+    assert_eq!((diff.min_steps, diff.max_steps), (3, 3));
+    assert_eq!(compare_gains(&b, &a).unwrap().min_steps, -3);
+
+    // A different stream is reported, not rejected (39 frames against 40)
+    let diff = compare_gains(&a, Path::new("tests/fixtures/test_stereo.mp3")).unwrap();
+    assert!(!diff.frame_counts_match());
+    assert_eq!((diff.frames_a, diff.frames_b), (40, 39));
+
+    cleanup(&a);
+    cleanup(&b);
+}
+
 #[test]
 fn test_analyze_nonexistent_file() {
     let path = Path::new("tests/fixtures/nonexistent.mp3");
@@ -165,6 +490,40 @@ fn test_apply_zero_gain() {
     cleanup(&path);
 }
 
+#[test]
+fn test_apply_gain_to_avg_target() {
+    let path = copy_test_file("test_stereo.mp3");
+    let before = analyze(&path).unwrap();
+    let target = (before.avg_gain.round() as u8).saturating_sub(5);
+
+    let frames = apply_gain_to_avg_target(&path, target).unwrap();
+    assert!(frames > 0);
+
+    let after = analyze(&path).unwrap();
+    assert!(
+        (after.avg_gain - target as f64).abs() <= 1.0,
+        "average {} should be near target {}",
+        after.avg_gain,
+        target
+    );
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_gain_would_change() {
+    // Every global_gain of this file is already 255
+    let stereo = analyze(Path::new("tests/fixtures/test_stereo.mp3")).unwrap();
+    assert!(!gain_would_change(&stereo, 0, false));
+    assert!(!gain_would_change(&stereo, 1, false));
+    assert!(gain_would_change(&stereo, -1, false));
+    assert!(gain_would_change(&stereo, 1, true));
+    assert!(!gain_would_change(&stereo, 256, true));
+
+    let mono = analyze(Path::new("tests/fixtures/test_mono.mp3")).unwrap();
+    assert!(gain_would_change(&mono, 1, false));
+}
+
 #[test]
 fn test_apply_gain_saturates_at_max() {
     let path = copy_test_file("test_stereo.mp3");
@@ -195,6 +554,52 @@ fn test_apply_gain_saturates_at_min() {
     cleanup(&path);
 }
 
+#[test]
+fn test_apply_gain_stops_at_floor() {
+    let path = copy_test_file("test_mono.mp3");
+
+    // Spread the gains (115-210) to 15-110, so some are already below the floor
+    apply_gain(&path, -100).unwrap();
+    let before = frame_gains(&path, 0, false).unwrap();
+
+    apply_gain_floor(&path, -20, 50).unwrap();
+    let after = frame_gains(&path, 0, false).unwrap();
+
+    let mut floored = 0;
+    for (old, new) in before.iter().zip(&after) {
+        for (&old, &new) in old.current.iter().zip(&new.current) {
+            if old <= 50 {
+                assert_eq!(new, old, "values at or below the floor stay");
+            } else {
+                assert_eq!(new, old.saturating_sub(20).max(50));
+                floored += usize::from(new == 50);
+            }
+        }
+    }
+    assert!(floored > 0, "some frames should stop at the floor");
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_floor_with_undo_is_exactly_undone() {
+    let path = copy_test_file("test_mono.mp3");
+    let original = fs::read(&path).unwrap();
+
+    // Gains span 115-210: -20 with a floor of 100 would hold some frames back,
+    // which MP3GAIN_UNDO can't restore
+    assert!(apply_gain_with_undo_floor(&path, -20, 100).is_err());
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    // A floor nothing reaches shifts every frame, so undo restores the audio
+    apply_gain_with_undo_floor(&path, -20, 50).unwrap();
+    undo_gain(&path).unwrap();
+    let undone = fs::read(&path).unwrap();
+    assert_eq!(undone[..original.len()], original[..]);
+
+    cleanup(&path);
+}
+
 // =============================================================================
 // Undo Tests
 // =============================================================================
@@ -241,13 +646,461 @@ fn test_apply_and_undo_gain() {
     cleanup(&path);
 }
 
+/// Write a file holding only the first audio frame of test_mono.mp3,
+/// followed by `padding` zero bytes
+fn single_frame_file(padding: usize) -> std::path::PathBuf {
+    let src = Path::new("tests/fixtures/test_mono.mp3");
+    let frame = frame_headers(src, 2)
+        .unwrap()
+        .into_iter()
+        .find(|frame| !frame.is_info_frame)
+        .unwrap();
+    let mut data = fs::read(src).unwrap()[frame.offset..frame.offset + frame.frame_size].to_vec();
+    data.resize(data.len() + padding, 0);
+
+    let id = TEST_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let path = std::env::temp_dir().join(format!("mp3rgain_test_{}_single_frame.mp3", id));
+    fs::write(&path, &data).unwrap();
+    path
+}
+
+#[test]
+fn test_single_frame_apply_and_undo() {
+    for padding in [0, 2, 100] {
+        let path = single_frame_file(padding);
+        let original = fs::read(&path).unwrap();
+
+        let info = analyze(&path).unwrap();
+        assert_eq!(info.frame_count, 1, "padding {}", padding);
+        assert_eq!(info.trailing_bytes, padding);
+
+        assert_eq!(apply_gain_with_undo(&path, 2).unwrap(), 1);
+        let after = analyze(&path).unwrap();
+        assert_eq!(after.min_gain, info.min_gain + 2);
+        assert_eq!(after.max_gain, info.max_gain + 2);
+
+        assert_eq!(undo_gain(&path).unwrap(), 1);
+        assert_eq!(fs::read(&path).unwrap(), original, "padding {}", padding);
+
+        cleanup(&path);
+    }
+}
+
+#[test]
+fn test_single_frame_is_measured_for_replaygain() {
+    if !replaygain::is_available() {
+        return;
+    }
+
+    // ID3v2 tag, Info frame and one audio frame: decodable, and measured
+    // although 26ms is shorter than one 50ms analysis window
+    let src = Path::new("tests/fixtures/test_mono.mp3");
+    let frame = frame_headers(src, 2)
+        .unwrap()
+        .into_iter()
+        .find(|frame| !frame.is_info_frame)
+        .unwrap();
+    let path = copy_test_file("test_mono.mp3");
+    let mut data = fs::read(&path).unwrap();
+    data.truncate(frame.offset + frame.frame_size);
+    fs::write(&path, &data).unwrap();
+
+    let result = replaygain::analyze_track(&path).unwrap();
+    assert!(result.loudness_db > 0.0, "loudness {}", result.loudness_db);
+    // Capped where the peak reaches full scale
+    assert!(replaygain::clip_margin_db(result.peak, result.gain_db) > -1e-9);
+    // Decoded, not a frame-domain estimate
+    let result = replaygain::analyze_track_or_estimate(&path, None).unwrap();
+    assert_eq!(result.method, replaygain::AnalysisMethod::Decoded);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_best_effort_analysis_skips_corrupt_packet() {
+    if !replaygain::is_available() {
+        return;
+    }
+
+    // Overwrite bytes inside an AAC packet in the middle of the mdat box, which
+    // the AAC decoder rejects with an error other than a plain DecodeError
+    let path = copy_test_file("test_aac.m4a");
+    let mut data = fs::read(&path).unwrap();
+    let mdat = data.windows(4).position(|w| w == b"mdat").unwrap();
+    let size = u32::from_be_bytes(data[mdat - 4..mdat].try_into().unwrap()) as usize;
+    let corrupt = mdat + 4 + size / 4;
+    data[corrupt..corrupt + 3].fill(0xFF);
+    fs::write(&path, &data).unwrap();
+
+    assert!(replaygain::analyze_track(&path).is_err());
+
+    let track = replaygain::analyze_track_best_effort(&path, None).unwrap();
+    assert!(track.result.partial);
+    assert_eq!(track.result.method, replaygain::AnalysisMethod::Decoded);
+    assert!(track.result.loudness_db.is_finite());
+
+    // Intact files aren't flagged
+    let intact =
+        replaygain::analyze_track_best_effort(Path::new("tests/fixtures/test_aac.m4a"), None)
+            .unwrap();
+    assert!(!intact.result.partial);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_gain_tag_formats_round_trip() {
+    let txxx_keys = |path: &Path| -> Vec<String> {
+        id3v2::read_txxx_from_file(path)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    };
+
+    for format in [TagFormat::Ape, TagFormat::Id3v2, TagFormat::Both] {
+        let path = copy_test_file("test_mono.mp3");
+        let original = analyze(&path).unwrap();
+
+        apply_gain_with_undo(&path, 2).unwrap();
+        convert_gain_tag(&path, format).unwrap();
+
+        let ape = read_ape_tag_from_file(&path).unwrap();
+        let txxx = txxx_keys(&path);
+        match format {
+            TagFormat::Ape => assert!(txxx.is_empty()),
+            TagFormat::Id3v2 => assert!(ape.is_none(), "APEv2 gain keys must be moved"),
+            TagFormat::Both => {
+                assert_eq!(ape.unwrap().get(TAG_MP3GAIN_UNDO), Some("+002,+002,N"))
+            }
+        }
+        if format != TagFormat::Ape {
+            assert_eq!(txxx, vec![TAG_MP3GAIN_UNDO, TAG_MP3GAIN_MINMAX]);
+        }
+        assert_eq!(stored_gain_tag_format(&path).unwrap(), Some(format));
+
+        // Undo finds the tag in either format and clears both
+        let tag = read_gain_tag(&path).unwrap().unwrap();
+        assert_eq!(tag.get_undo_gain(), Some(2));
+        assert_eq!(estimate_applied_gain(&path), Some(2));
+        assert_eq!(undo_gain(&path).unwrap(), original.frame_count);
+        let after = analyze(&path).unwrap();
+        assert_eq!(
+            (after.min_gain, after.max_gain),
+            (original.min_gain, original.max_gain)
+        );
+        assert_eq!(stored_gain_tag_format(&path).unwrap(), None);
+
+        cleanup(&path);
+    }
+}
+
+#[test]
+fn test_read_gain_tag_reconciles_ape_and_id3v2() {
+    let path = copy_test_file("test_mono.mp3");
+
+    // ID3v2 holds an undo value and a track gain, APEv2 a different undo value
+    let data = fs::read(&path).unwrap();
+    let data = id3v2::with_txxx(
+        &data,
+        &GAIN_TAG_KEYS,
+        &[
+            (TAG_MP3GAIN_UNDO, "+003,+003,N"),
+            (TAG_REPLAYGAIN_TRACK_GAIN, "-4.00 dB"),
+        ],
+    )
+    .unwrap();
+    fs::write(&path, data).unwrap();
+    let mut ape = ApeTag::new();
+    ape.set_undo_gain(1, 1, false);
+    write_ape_tag(&path, &ape).unwrap();
+    assert_eq!(
+        stored_gain_tag_format(&path).unwrap(),
+        Some(TagFormat::Both)
+    );
+
+    // APEv2 wins for keys in both, ID3v2-only keys are added
+    let tag = read_gain_tag(&path).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+001,+001,N"));
+    assert_eq!(tag.get(TAG_REPLAYGAIN_TRACK_GAIN), Some("-4.00 dB"));
+
+    // Moving to ID3v2 stores the reconciled values there
+    convert_gain_tag(&path, TagFormat::Id3v2).unwrap();
+    assert!(read_ape_tag_from_file(&path).unwrap().is_none());
+    let tag = read_gain_tag(&path).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), Some("+001,+001,N"));
+    assert_eq!(tag.get(TAG_REPLAYGAIN_TRACK_GAIN), Some("-4.00 dB"));
+
+    // Undo removes the undo item but keeps the track gain
+    let before = analyze(&path).unwrap();
+    undo_gain(&path).unwrap();
+    assert_eq!(analyze(&path).unwrap().max_gain, before.max_gain - 1);
+    let tag = read_gain_tag(&path).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), None);
+    assert_eq!(tag.get(TAG_REPLAYGAIN_TRACK_GAIN), Some("-4.00 dB"));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_gain_provenance() {
+    use mp3rgain::mp4meta::{self, ReplayGainTags};
+    use mp3rgain::{flac, gain_provenance, GainSource, TagContainer};
+
+    let provenance = |path: &Path| {
+        let p = gain_provenance(path).unwrap();
+        (p.source, p.container)
+    };
+    let mp3 = |format| Some(TagContainer::Mp3(format));
+
+    // Untouched
+    let path = copy_test_file("test_mono.mp3");
+    assert_eq!(provenance(&path), (GainSource::Untagged, None));
+
+    // A single adjustment looks like mp3gain's tags...
+    apply_gain_with_undo(&path, 1).unwrap();
+    assert_eq!(
+        provenance(&path),
+        (GainSource::Mp3gain, mp3(TagFormat::Ape))
+    );
+
+    // ...a second one leaves mp3rgain's undo stack
+    apply_gain_with_undo(&path, 1).unwrap();
+    convert_gain_tag(&path, TagFormat::Id3v2).unwrap();
+    assert_eq!(
+        provenance(&path),
+        (GainSource::Mp3rgain, mp3(TagFormat::Id3v2))
+    );
+    cleanup(&path);
+
+    // ReplayGain only, in MP3, FLAC and M4A files
+    let path = copy_test_file("test_mono.mp3");
+    let mut tag = ApeTag::new();
+    tag.set_replaygain_track(-6.5, 0.75);
+    write_ape_tag(&path, &tag).unwrap();
+    assert_eq!(
+        provenance(&path),
+        (GainSource::ReplayGain, mp3(TagFormat::Ape))
+    );
+    cleanup(&path);
+
+    let path = copy_test_file("test_flac.flac");
+    assert_eq!(provenance(&path), (GainSource::Untagged, None));
+    flac::write_replaygain_tag(&path, &tag).unwrap();
+    assert_eq!(
+        provenance(&path),
+        (GainSource::ReplayGain, Some(TagContainer::Vorbis))
+    );
+    cleanup(&path);
+
+    let path = copy_test_file("test_aac.m4a");
+    assert_eq!(provenance(&path), (GainSource::Untagged, None));
+    let mut tags = ReplayGainTags::new();
+    tags.set_track(-6.5, 0.75);
+    mp4meta::write_replaygain_tags(&path, &tags).unwrap();
+    assert_eq!(
+        provenance(&path),
+        (GainSource::ReplayGain, Some(TagContainer::Mp4))
+    );
+    cleanup(&path);
+}
+
 #[test]
 fn test_undo_without_previous_gain() {
     let path = copy_test_file("test_stereo.mp3");
 
-    // Try to undo without any previous gain application
+    // Undo without any previous gain application: nothing to undo
+    let original = fs::read(&path).unwrap();
     let result = undo_gain(&path);
-    assert!(result.is_err(), "Should fail to undo without APE tag");
+    assert_eq!(result.unwrap(), 0, "Nothing to undo without APE tag");
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_gain_with_undo_in_memory_roundtrip() {
+    let path = copy_test_file("test_stereo.mp3");
+    let original = fs::read(&path).unwrap();
+
+    // Same bytes as the file-based pipeline, undo tag included
+    let mut data = original.clone();
+    let frames = apply_gain_with_undo_bytes(&mut data, -3).unwrap();
+    assert_eq!(frames, apply_gain_with_undo(&path, -3).unwrap());
+    assert_eq!(data, fs::read(&path).unwrap());
+
+    // A second adjustment accumulates in MP3GAIN_UNDO
+    apply_gain_with_undo_bytes(&mut data, 1).unwrap();
+    let tag = read_ape_tag(&data).unwrap();
+    assert_eq!(tag.get_undo_gain(), Some(-2));
+    assert!(tag.get(TAG_MP3GAIN_MINMAX).is_some());
+
+    // Undoing the buffer's tag restores the original file
+    fs::write(&path, &data).unwrap();
+    undo_gain(&path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    // Plain gain leaves the tags alone
+    let mut plain = original.clone();
+    assert_eq!(apply_gain_bytes(&mut plain, 2).unwrap(), frames);
+    assert!(read_ape_tag(&plain).is_none());
+    assert_eq!(plain.len(), original.len());
+
+    let mut flac = fs::read("tests/fixtures/test_flac.flac").unwrap();
+    assert!(apply_gain_with_undo_bytes(&mut flac, 1).is_err());
+
+    cleanup(&path);
+}
+
+/// Build an APEv2 tag (footer only) from raw `(key, flags, value)` items
+fn ape_tag_bytes(items: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (key, flags, value) in items {
+        body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        body.extend_from_slice(&flags.to_le_bytes());
+        body.extend_from_slice(key.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value);
+    }
+    let mut footer = b"APETAGEX".to_vec();
+    footer.extend_from_slice(&2000u32.to_le_bytes());
+    footer.extend_from_slice(&((body.len() + 32) as u32).to_le_bytes());
+    footer.extend_from_slice(&(items.len() as u32).to_le_bytes());
+    footer.extend_from_slice(&0u32.to_le_bytes());
+    footer.extend_from_slice(&[0u8; 8]);
+    body.extend_from_slice(&footer);
+    body
+}
+
+#[test]
+fn test_gain_write_preserves_non_utf8_ape_items() {
+    let path = copy_test_file("test_mono.mp3");
+    let latin1: &[u8] = b"Caf\xe9 del Mar \xa9 1999";
+    let binary: &[u8] = &[0x00, 0xff, 0xfe, 0x80];
+    let mut data = fs::read(&path).unwrap();
+    data.extend_from_slice(&ape_tag_bytes(&[
+        ("Comment", 0, latin1),
+        ("Cover Art (Front)", 0x2, binary),
+    ]));
+    fs::write(&path, &data).unwrap();
+
+    apply_gain_with_undo(&path, 1).unwrap();
+
+    let tag = mp3rgain::read_ape_tag_from_file(&path).unwrap().unwrap();
+    let comment = tag.items().iter().find(|i| i.key == "Comment").unwrap();
+    assert_eq!(comment.raw, latin1);
+    assert_eq!(comment.flags, 0);
+    let cover = tag
+        .items()
+        .iter()
+        .find(|i| i.key == "Cover Art (Front)")
+        .unwrap();
+    assert_eq!(cover.raw, binary);
+    assert_eq!(cover.flags, 0x2);
+    assert!(tag.get("MP3GAIN_UNDO").is_some());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_binary_ape_item_survives_gain_tag_rewrites() {
+    let path = copy_test_file("test_mono.mp3");
+    // A read-only binary cover whose bytes aren't valid UTF-8
+    let cover: Vec<u8> = b"\x89PNG\r\n\x1a\n\xff\xd8"
+        .iter()
+        .copied()
+        .cycle()
+        .take(300)
+        .collect();
+    let mut data = fs::read(&path).unwrap();
+    data.extend_from_slice(&ape_tag_bytes(&[
+        ("Cover Art (Front)", 0x3, &cover),
+        ("Title", 0, b"Song"),
+    ]));
+    fs::write(&path, &data).unwrap();
+
+    apply_gain_with_undo(&path, 2).unwrap();
+    apply_gain_with_undo(&path, -1).unwrap();
+    undo_gain(&path).unwrap();
+
+    let tag = read_ape_tag_from_file(&path).unwrap().unwrap();
+    let item = tag
+        .items()
+        .iter()
+        .find(|i| i.key == "Cover Art (Front)")
+        .unwrap();
+    assert_eq!(item.raw, cover);
+    assert_eq!(item.flags, 0x3);
+    assert_eq!(item.item_type(), ApeItemType::Binary);
+    assert!(item.is_read_only());
+    assert!(item.value.is_empty());
+    // Binary items have no text value
+    assert_eq!(tag.get("Cover Art (Front)"), None);
+    assert_eq!(tag.get("Title"), Some("Song"));
+
+    // Setting a text value makes it a writable text item
+    let mut tag = tag;
+    tag.set("Cover Art (Front)", "none");
+    let item = &tag.items()[0];
+    assert_eq!(item.item_type(), ApeItemType::Text);
+    assert!(!item.is_read_only());
+
+    cleanup(&path);
+}
+
+fn count_ape_preambles(data: &[u8]) -> usize {
+    data.windows(8).filter(|w| w == b"APETAGEX").count()
+}
+
+#[test]
+fn test_repair_truncated_ape_tag() {
+    let path = copy_test_file("test_mono.mp3");
+    apply_gain_with_undo(&path, 2).unwrap();
+    let tagged = fs::read(&path).unwrap();
+
+    // Interrupted write: the footer is cut off
+    fs::write(&path, &tagged[..tagged.len() - 10]).unwrap();
+    assert!(mp3rgain::read_ape_tag_from_file(&path).unwrap().is_none());
+
+    assert!(repair_ape_tag(&path).unwrap());
+    let repaired = fs::read(&path).unwrap();
+    assert_eq!(repaired, tagged);
+    assert_eq!(count_ape_preambles(&repaired), 2);
+    let tag = mp3rgain::read_ape_tag_from_file(&path).unwrap().unwrap();
+    assert_eq!(tag.get_undo_gain(), Some(2));
+
+    // Already consistent: nothing to do
+    assert!(!repair_ape_tag(&path).unwrap());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_write_removes_duplicate_and_partial_ape_tags() {
+    let path = copy_test_file("test_mono.mp3");
+    apply_gain_with_undo(&path, 1).unwrap();
+    let tagged = fs::read(&path).unwrap();
+    let tag_len = tagged.len() - 8611;
+
+    // The same tag appended twice
+    let mut data = tagged.clone();
+    data.extend_from_slice(&tagged[tagged.len() - tag_len..]);
+    fs::write(&path, &data).unwrap();
+    apply_gain_with_undo(&path, 1).unwrap();
+    let data = fs::read(&path).unwrap();
+    assert_eq!(count_ape_preambles(&data), 2);
+    assert_eq!(
+        mp3rgain::read_ape_tag_from_file(&path)
+            .unwrap()
+            .unwrap()
+            .get_undo_gain(),
+        Some(2)
+    );
+
+    // A stray header left by an interrupted write
+    fs::write(&path, &data[..data.len() - 40]).unwrap();
+    apply_gain_with_undo(&path, 1).unwrap();
+    assert_eq!(count_ape_preambles(&fs::read(&path).unwrap()), 2);
 
     cleanup(&path);
 }
@@ -282,6 +1135,24 @@ fn test_cumulative_gain_undo() {
     cleanup(&path);
 }
 
+#[test]
+fn test_estimate_applied_gain_without_undo_tag() {
+    let path = copy_test_file("test_mono.mp3");
+    assert_eq!(estimate_applied_gain(&path), None);
+
+    apply_gain_with_undo(&path, 3).unwrap();
+
+    // Drop MP3GAIN_UNDO, keeping MP3GAIN_MINMAX
+    let mut tag = mp3rgain::read_ape_tag_from_file(&path).unwrap().unwrap();
+    tag.remove("MP3GAIN_UNDO");
+    mp3rgain::write_ape_tag(&path, &tag).unwrap();
+    assert_eq!(undo_gain(&path).unwrap(), 0);
+
+    assert_eq!(estimate_applied_gain(&path), Some(3));
+
+    cleanup(&path);
+}
+
 // =============================================================================
 // Channel-Specific Gain Tests
 // =============================================================================
@@ -318,6 +1189,142 @@ fn test_apply_gain_right_channel() {
     cleanup(&path);
 }
 
+#[test]
+fn test_apply_gain_checked_counts_saturation_per_channel() {
+    let path = copy_test_file("test_joint_stereo.mp3");
+
+    // Left values range over 110-157, right values are all 210: -130 drives
+    // the quieter left values to 0, +46 pushes every right value past 255
+    let before = frame_gains(&path, 0, false).unwrap();
+    let hits = |channel: usize, limit: fn(u8) -> bool| {
+        before
+            .iter()
+            .filter(|f| f.current.iter().skip(channel).step_by(2).any(|&g| limit(g)))
+            .count()
+    };
+    let left_expected = hits(0, |g| g < 130);
+    assert!(left_expected > 0 && left_expected < before.len());
+    assert_eq!(hits(1, |g| g > 209), before.len());
+
+    let report = apply_gain_checked(&path, -130, 46).unwrap();
+    assert_eq!(report.frames, before.len());
+    assert_eq!(report.left_saturated, left_expected);
+    assert_eq!(report.right_saturated, before.len());
+    assert!(report.clipped);
+    assert_eq!(report.steps_applied, (-130, 46));
+
+    // Negative gain alone never counts as clipping
+    let report = apply_gain_checked(&path, -1, -1).unwrap();
+    assert!(!report.clipped);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_corrupt_frame_is_skipped_and_reported() {
+    let path = copy_test_file("test_stereo.mp3");
+    let clean = analyze(&path).unwrap();
+    assert_eq!(clean.bad_frames, 0);
+
+    // Wipe the header of a frame in the middle of the stream
+    let frames = frame_gains(&path, 0, false).unwrap();
+    let bad_offset = frames[frames.len() / 2].offset;
+    let mut data = fs::read(&path).unwrap();
+    data[bad_offset..bad_offset + 4].fill(0);
+    fs::write(&path, &data).unwrap();
+
+    let info = analyze(&path).unwrap();
+    assert_eq!(info.bad_frames, 1);
+    assert_eq!(info.bad_frame_offsets, vec![bad_offset]);
+    assert_eq!(info.frame_count, clean.frame_count - 1);
+
+    // Every remaining frame still gets the gain
+    let report = apply_gain_checked(&path, 2, 2).unwrap();
+    assert_eq!(report.frames, info.frame_count);
+    assert_eq!(report.bad_frames, 1);
+    let after = frame_gains(&path, 0, false).unwrap();
+    let expected: Vec<_> = frames
+        .iter()
+        .filter(|f| f.offset != bad_offset)
+        .map(|f| {
+            f.current
+                .iter()
+                .map(|g| g.saturating_add(2))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let actual: Vec<_> = after.into_iter().map(|f| f.current).collect();
+    assert_eq!(actual, expected);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_analyze_and_apply_through_open_file() {
+    use std::io::{Seek, SeekFrom};
+
+    let path = copy_test_file("test_stereo.mp3");
+    let original = fs::read(&path).unwrap();
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+
+    // The handle's position doesn't matter
+    file.seek(SeekFrom::End(0)).unwrap();
+    let before = analyze_file(&mut file).unwrap();
+    assert_eq!(before.frame_count, analyze(&path).unwrap().frame_count);
+
+    let frames = apply_gain_file(&mut file, -2).unwrap();
+    assert_eq!(frames, before.frame_count);
+    let after = analyze_file(&mut file).unwrap();
+    assert_eq!(after.max_gain, before.max_gain - 2);
+    assert_eq!(after.min_gain, before.min_gain - 2);
+    drop(file);
+
+    // Same bytes as going through the path
+    let reference = copy_test_file("test_stereo.mp3");
+    apply_gain(&reference, -2).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), fs::read(&reference).unwrap());
+    assert_ne!(fs::read(&path).unwrap(), original);
+
+    // A handle opened read-only can be analyzed but not written
+    let mut read_only = fs::File::open(&path).unwrap();
+    assert!(analyze_file(&mut read_only).is_ok());
+    assert!(apply_gain_file(&mut read_only, 1).is_err());
+
+    cleanup(&path);
+    cleanup(&reference);
+}
+
+#[test]
+fn test_apply_gain_safe_reports_irreversible_frames() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = frame_gains(&path, 0, false).unwrap();
+
+    // Values range over 115-210, so +2 stays within 0-255 everywhere
+    let report = apply_gain_safe(&path, 2).unwrap();
+    assert_eq!(report.frames, before.len());
+    assert!(report.is_reversible());
+
+    // +50 then pins every value above 205 at 255; the gain stays applied
+    let current = frame_gains(&path, 0, false).unwrap();
+    let expected: Vec<usize> = current
+        .iter()
+        .filter(|f| f.current.iter().any(|&g| g > 205))
+        .map(|f| f.offset)
+        .collect();
+    assert!(!expected.is_empty() && expected.len() < before.len());
+
+    let report = apply_gain_safe(&path, 50).unwrap();
+    assert!(!report.is_reversible());
+    assert_eq!(report.irreversible_frames, expected);
+    assert_eq!(analyze(&path).unwrap().max_gain, 255);
+
+    cleanup(&path);
+}
+
 #[test]
 fn test_channel_gain_fails_on_mono() {
     let path = copy_test_file("test_mono.mp3");
@@ -344,6 +1351,195 @@ fn test_channel_zero_gain() {
     cleanup(&path);
 }
 
+#[test]
+fn test_apply_gain_granule_leaves_other_granule() {
+    let path = copy_test_file("test_stereo.mp3");
+    let before = frame_gains(&path, 0, false).unwrap();
+
+    let frames = apply_gain_granule(&path, 1, None, 2).unwrap();
+    assert_eq!(frames, before.len());
+
+    // Locations are [gr0_ch0, gr0_ch1, gr1_ch0, gr1_ch1]
+    let after = frame_gains(&path, 0, false).unwrap();
+    for (old, new) in before.iter().zip(&after) {
+        assert_eq!(new.current[..2], old.current[..2]);
+        for (o, n) in old.current[2..].iter().zip(&new.current[2..]) {
+            assert_eq!(*n, o.saturating_add(2));
+        }
+    }
+
+    // Only one channel of the granule
+    apply_gain_granule(&path, 0, Some(Channel::Right), -1).unwrap();
+    let right = frame_gains(&path, 0, false).unwrap();
+    for (old, new) in after.iter().zip(&right) {
+        assert_eq!(new.current[0], old.current[0]);
+        assert_eq!(new.current[1], old.current[1].saturating_sub(1));
+        assert_eq!(new.current[2..], old.current[2..]);
+    }
+
+    assert!(apply_gain_granule(&path, 2, None, 1).is_err());
+    cleanup(&path);
+
+    // MPEG2.5 frames have a single granule
+    let path = copy_test_file("test_mpeg25_stereo.mp3");
+    let error = apply_gain_granule(&path, 1, None, 1).unwrap_err();
+    assert!(error.to_string().contains("MPEG2.5"));
+    assert!(apply_gain_granule(&path, 0, None, 1).unwrap() > 0);
+    cleanup(&path);
+}
+
+#[test]
+fn test_apply_gain_ramp_interpolates_linearly() {
+    let path = copy_test_file("test_mono.mp3");
+    let before = frame_gains(&path, 0, false).unwrap();
+
+    assert_eq!(apply_gain_ramp(&path, 10, 30, -8, 4).unwrap(), 21);
+
+    let after = frame_gains(&path, 0, false).unwrap();
+    assert_eq!(after.len(), before.len());
+    for (frame, (old, new)) in before.iter().zip(&after).enumerate() {
+        let expected = if (10..=30).contains(&frame) {
+            -8 + (12.0 * (frame - 10) as f64 / 20.0).round() as i32
+        } else {
+            0
+        };
+        for (o, n) in old.current.iter().zip(&new.current) {
+            assert_eq!(i32::from(*n) - i32::from(*o), expected, "frame {}", frame);
+        }
+    }
+    // The ramp is not recorded for undo
+    assert!(read_ape_tag_from_file(&path).unwrap().is_none());
+
+    // The opposite ramp restores the file
+    apply_gain_ramp(&path, 10, 30, 8, -4).unwrap();
+    assert_eq!(frame_gains(&path, 0, false).unwrap(), before);
+
+    assert!(apply_gain_ramp(&path, 30, 10, 1, 1).is_err());
+    assert!(apply_gain_ramp(&path, 0, before.len(), 1, 1).is_err());
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_undo_then_redo_restores_applied_state() {
+    let path = copy_test_file("test_mono.mp3");
+
+    apply_gain_with_undo(&path, 3).unwrap();
+    let applied = fs::read(&path).unwrap();
+
+    undo_gain_keep_redo(&path).unwrap();
+    let tag = read_gain_tag(&path).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_UNDO), None);
+    assert_eq!(tag.get(TAG_MP3GAIN_REDO), Some("+003,+003,N"));
+
+    assert!(redo_gain(&path).unwrap() > 0);
+    assert_eq!(fs::read(&path).unwrap(), applied);
+
+    // Nothing left to redo, and a new adjustment drops an undone one
+    assert_eq!(redo_gain(&path).unwrap(), 0);
+    undo_gain_keep_redo(&path).unwrap();
+    apply_gain_with_undo(&path, 1).unwrap();
+    let tag = read_gain_tag(&path).unwrap().unwrap();
+    assert_eq!(tag.get(TAG_MP3GAIN_REDO), None);
+    assert_eq!(redo_gain(&path).unwrap(), 0);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_apply_gain_both_channels_and_undo() {
+    let path = copy_test_file("test_stereo.mp3");
+
+    // Make headroom first (the fixture is at max gain), without an undo record
+    apply_gain(&path, -5).unwrap();
+    let before = fs::read(&path).unwrap();
+
+    let frames = apply_gain_channels_with_undo(&path, 3, -2).unwrap();
+    assert!(frames > 0);
+    let tag = mp3rgain::read_ape_tag_from_file(&path).unwrap().unwrap();
+    assert_eq!(tag.get("MP3GAIN_UNDO"), Some("+003,-002,N"));
+
+    undo_gain(&path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_gain_op_matches_dedicated_functions() {
+    type Dedicated = fn(&Path) -> anyhow::Result<usize>;
+    let cases: [(GainOp, Dedicated); 4] = [
+        (GainOp::new().steps(-3), |p| apply_gain(p, -3)),
+        (GainOp::new().steps(-3).with_undo(true), |p| {
+            apply_gain_with_undo(p, -3)
+        }),
+        (GainOp::new().steps(10).wrap(true).with_undo(true), |p| {
+            apply_gain_with_undo_wrap(p, 10)
+        }),
+        (
+            GainOp::new()
+                .steps(-2)
+                .channel(Channel::Right)
+                .with_undo(true),
+            |p| apply_gain_channel_with_undo(p, Channel::Right, -2),
+        ),
+    ];
+
+    for (op, dedicated) in cases {
+        let built = copy_test_file("test_stereo.mp3");
+        let expected = copy_test_file("test_stereo.mp3");
+
+        let report = op.apply(&built).unwrap();
+        let frames = dedicated(&expected).unwrap();
+        assert_eq!(report.frames, frames, "{:?}", op);
+        assert_eq!(
+            fs::read(&built).unwrap(),
+            fs::read(&expected).unwrap(),
+            "{:?}",
+            op
+        );
+
+        cleanup(&built);
+        cleanup(&expected);
+    }
+}
+
+#[test]
+fn test_gain_op_prevents_clipping() {
+    let path = copy_test_file("test_stereo.mp3");
+    apply_gain(&path, -5).unwrap();
+    let headroom = analyze(&path).unwrap().headroom_steps;
+    assert!(headroom > 0);
+
+    let report = GainOp::new()
+        .steps(headroom + 4)
+        .clip_policy(ClipPolicy::Prevent)
+        .apply(&path)
+        .unwrap();
+    assert_eq!(report.steps_applied, (headroom, headroom));
+    assert!(!report.clipped);
+    assert_eq!(analyze(&path).unwrap().headroom_steps, 0);
+
+    // No headroom left: nothing to apply
+    let before = fs::read(&path).unwrap();
+    let report = GainOp::new()
+        .steps(2)
+        .clip_policy(ClipPolicy::Prevent)
+        .apply(&path)
+        .unwrap();
+    assert_eq!(report.frames, 0);
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    assert!(GainOp::new()
+        .steps(-1)
+        .wrap(true)
+        .channel(Channel::Left)
+        .apply(&path)
+        .is_err());
+
+    cleanup(&path);
+}
+
 // =============================================================================
 // Format Compatibility Tests
 // =============================================================================
@@ -410,6 +1606,399 @@ fn test_mono_gain_application() {
     cleanup(&path);
 }
 
+#[test]
+fn test_read_lame_tag_replaygain() {
+    let info = read_info_header(Path::new("tests/fixtures/test_lame_replaygain.mp3"))
+        .unwrap()
+        .expect("Info header");
+    assert_eq!(info.marker, "Info");
+    assert_eq!(info.encoder.as_deref(), Some("LAME3.100"));
+    assert_eq!(info.track_gain_db, Some(-6.3));
+    assert_eq!(info.album_gain_db, Some(-7.0));
+    assert_eq!(info.peak, Some(0.5));
+
+    // ffmpeg writes a LAME tag without ReplayGain
+    let info = read_info_header(Path::new("tests/fixtures/test_vbr.mp3"))
+        .unwrap()
+        .expect("Xing header");
+    assert_eq!(info.marker, "Xing");
+    assert_eq!(info.encoder.as_deref(), Some("Lavc62.11"));
+    assert_eq!(info.track_gain_db, None);
+    assert_eq!(info.album_gain_db, None);
+    assert_eq!(info.peak, None);
+
+    // No Xing/Info header at all
+    let path = copy_test_file("test_stereo.mp3");
+    let mut data = fs::read(&path).unwrap();
+    let pos = data.windows(4).position(|w| w == b"Info").unwrap();
+    data[pos..pos + 4].copy_from_slice(b"\0\0\0\0");
+    fs::write(&path, &data).unwrap();
+    assert_eq!(read_info_header(&path).unwrap(), None);
+    cleanup(&path);
+}
+
+#[test]
+fn test_read_rva2_from_id3v2_tag() {
+    // test_mono.mp3 with RVA2 "track" (master -3.5 dB, peak 0.75) and
+    // "album" (master -5.0 dB, no peak) frames after its TSSE frame
+    let path = Path::new("tests/fixtures/test_rva2.mp3");
+    let frames = id3v2::read_rva2_from_file(path).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[1].identification, "album");
+    assert_eq!(frames[1].master().unwrap().adjustment_db, -5.0);
+
+    let track = id3v2::track_master(&frames).unwrap();
+    assert_eq!(track.adjustment_db, -3.5);
+    assert_eq!(track.peak, Some(0.75));
+
+    // The audio behind the tag is untouched
+    assert_eq!(
+        analyze(path).unwrap().frame_count,
+        analyze(Path::new("tests/fixtures/test_mono.mp3"))
+            .unwrap()
+            .frame_count
+    );
+    assert!(
+        id3v2::read_rva2_from_file(Path::new("tests/fixtures/test_mono.mp3"))
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_m4a_tag_writes_keep_chunk_offsets() {
+    use mp3rgain::mp4meta::{self, ReplayGainTags};
+
+    // test_aac.m4a is ffmpeg output with moov before mdat and no udta, so
+    // every write moves the audio and all chunk offsets must follow it
+    let path = copy_test_file("test_aac.m4a");
+    let find =
+        |data: &[u8], box_type: &[u8]| data.windows(4).position(|w| w == box_type).unwrap() - 4;
+    let first_chunk = |data: &[u8]| {
+        let stco = find(data, b"stco");
+        u32::from_be_bytes(data[stco + 16..stco + 20].try_into().unwrap()) as usize
+    };
+    let original = fs::read(&path).unwrap();
+    let audio = &original[first_chunk(&original)..];
+    assert_eq!(first_chunk(&original), find(&original, b"mdat") + 8);
+    let decoded = replaygain::is_available().then(|| replaygain::analyze_track(&path).unwrap());
+
+    let mut track = ReplayGainTags::new();
+    track.set_track(-4.25, 0.5);
+    let mut album = track.clone();
+    album.set_album(-3.5, 0.75);
+
+    // New udta, then a growing ilst, then removal
+    for tags in [&track, &album, &ReplayGainTags::new()] {
+        mp4meta::write_replaygain_tags(&path, tags).unwrap();
+        let data = fs::read(&path).unwrap();
+        assert!(data.len() >= original.len());
+        let chunk = first_chunk(&data);
+        assert_eq!(chunk, find(&data, b"mdat") + 8);
+        assert_eq!(&data[chunk..], audio);
+
+        if let Some(before) = &decoded {
+            let after = replaygain::analyze_track(&path).unwrap();
+            assert_eq!(after.loudness_db, before.loudness_db);
+            assert_eq!(after.peak, before.peak);
+        }
+    }
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_fragmented_m4a_is_detected_and_left_alone() {
+    use mp3rgain::mp4meta::{self, ReplayGainTags};
+
+    // test_fragmented.m4a holds the audio of test_aac.m4a in a moof fragment,
+    // announced by mvex in an otherwise empty moov
+    let path = copy_test_file("test_fragmented.m4a");
+    assert_eq!(
+        detect_audio_type(&path),
+        Some(replaygain::AudioFileType::Aac)
+    );
+    assert!(mp4meta::is_fragmented_mp4(&path).unwrap());
+    assert!(!mp4meta::is_fragmented_mp4(Path::new("tests/fixtures/test_aac.m4a")).unwrap());
+
+    // Reading works, writing is refused without touching the file
+    let original = fs::read(&path).unwrap();
+    assert!(mp4meta::read_replaygain_tags(&path).unwrap().is_empty());
+    let mut tags = ReplayGainTags::new();
+    tags.set_track(-4.25, 0.5);
+    let err = mp4meta::write_replaygain_tags(&path, &tags).unwrap_err();
+    assert!(err.to_string().contains("fragmented MP4"));
+    assert!(mp4meta::delete_replaygain_tags(&path).is_err());
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_flac_replaygain_roundtrip() {
+    use mp3rgain::flac;
+
+    // 0.2 s of a 440 Hz sine at -12 dBFS, 16-bit stereo, with a TITLE comment
+    let path = copy_test_file("test_flac.flac");
+    let original = fs::read(&path).unwrap();
+    assert_eq!(
+        detect_audio_type(&path),
+        Some(replaygain::AudioFileType::Flac)
+    );
+    assert!(flac::read_replaygain_tag(&path).unwrap().is_none());
+    assert!(analyze(&path).unwrap_err().to_string().contains("FLAC"));
+
+    let decoded = replaygain::is_available().then(|| {
+        let result = replaygain::analyze_track(&path).unwrap();
+        assert_eq!(result.file_type, Some(replaygain::AudioFileType::Flac));
+        assert!((result.peak - 0.25).abs() < 0.001, "{}", result.peak);
+        result
+    });
+
+    let mut tag = ApeTag::new();
+    tag.set_replaygain_track(-2.75, 0.25);
+    tag.set_replaygain_album(-3.0, 0.5);
+    // Not a ReplayGain key: nothing to store it in for FLAC
+    tag.set(TAG_MP3GAIN_UNDO, "+001,+001,N");
+    flac::write_replaygain_tag(&path, &tag).unwrap();
+
+    let read = flac::read_replaygain_tag(&path).unwrap().unwrap();
+    assert_eq!(read.get(TAG_REPLAYGAIN_TRACK_GAIN), Some("-2.75 dB"));
+    assert_eq!(read.get("REPLAYGAIN_TRACK_PEAK"), Some("0.250000"));
+    assert_eq!(read.get("REPLAYGAIN_ALBUM_GAIN"), Some("-3.00 dB"));
+    assert_eq!(read.get(TAG_MP3GAIN_UNDO), None);
+    let comments = flac::read_vorbis_comments(&path).unwrap();
+    assert_eq!(
+        comments[0],
+        ("TITLE".to_string(), "440 Hz sine".to_string())
+    );
+
+    // Rewriting replaces the values instead of adding more
+    tag.set_replaygain_track(-1.5, 0.25);
+    flac::write_replaygain_tag(&path, &tag).unwrap();
+    let comments = flac::read_vorbis_comments(&path).unwrap();
+    assert_eq!(
+        comments
+            .iter()
+            .filter(|(key, _)| key == TAG_REPLAYGAIN_TRACK_GAIN)
+            .map(|(_, value)| value.as_str())
+            .collect::<Vec<_>>(),
+        vec!["-1.50 dB"]
+    );
+
+    // The audio frames are untouched and still decode the same
+    let data = fs::read(&path).unwrap();
+    let frames = |data: &[u8]| data.windows(2).position(|w| w == [0xFF, 0xF8]).unwrap();
+    assert_eq!(&data[frames(&data)..], &original[frames(&original)..]);
+    if let Some(before) = &decoded {
+        let after = replaygain::analyze_track(&path).unwrap();
+        assert_eq!(after.loudness_db, before.loudness_db);
+    }
+
+    flac::delete_replaygain_tags(&path).unwrap();
+    assert!(flac::read_replaygain_tag(&path).unwrap().is_none());
+    assert_eq!(flac::read_vorbis_comments(&path).unwrap().len(), 1);
+
+    cleanup(&path);
+}
+
+// =============================================================================
+// Batch / Cancellation Tests
+// =============================================================================
+
+#[test]
+fn test_analyze_many_isolates_errors() {
+    let valid = copy_test_file("test_mono.mp3");
+    let missing = std::env::temp_dir().join("mp3rgain_test_missing_file.mp3");
+    let garbage = copy_test_file("test_mono.mp3");
+    fs::write(&garbage, b"not an mp3 file at all").unwrap();
+    let before = fs::read(&valid).unwrap();
+
+    let mut progress = Vec::new();
+    let results = analyze_many(
+        &[valid.as_path(), missing.as_path(), garbage.as_path()],
+        &CancellationToken::new(),
+        |done, total| progress.push((done, total)),
+    )
+    .unwrap();
+
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].0, valid);
+    assert_eq!(results[0].1.as_ref().unwrap().min_gain, 115);
+    assert_eq!(results[1].0, missing);
+    assert!(results[1].1.is_err());
+    assert_eq!(results[2].0, garbage);
+    assert!(results[2].1.is_err());
+    assert_eq!(progress, vec![(1, 3), (2, 3), (3, 3)]);
+    // Analysis has no side effects
+    assert_eq!(fs::read(&valid).unwrap(), before);
+
+    cleanup(&valid);
+    cleanup(&garbage);
+}
+
+#[test]
+fn test_apply_gain_many() {
+    let path1 = copy_test_file("test_stereo.mp3");
+    let path2 = copy_test_file("test_mono.mp3");
+    let before1 = analyze(&path1).unwrap();
+    let before2 = analyze(&path2).unwrap();
+
+    let mut progress = Vec::new();
+    let results = apply_gain_many(
+        &[path1.as_path(), path2.as_path()],
+        -1,
+        &CancellationToken::new(),
+        |done, total| progress.push((done, total)),
+    )
+    .unwrap();
+
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|r| r.is_ok()));
+    assert_eq!(progress, vec![(1, 2), (2, 2)]);
+    assert_eq!(analyze(&path1).unwrap().min_gain, before1.min_gain - 1);
+    assert_eq!(analyze(&path2).unwrap().min_gain, before2.min_gain - 1);
+
+    cleanup(&path1);
+    cleanup(&path2);
+}
+
+#[test]
+fn test_apply_gain_many_cancelled_mid_batch() {
+    let path1 = copy_test_file("test_stereo.mp3");
+    let path2 = copy_test_file("test_stereo.mp3");
+    let original = fs::read(&path2).unwrap();
+
+    let token = CancellationToken::new();
+    let result = apply_gain_many(
+        &[path1.as_path(), path2.as_path()],
+        -1,
+        &token,
+        |done, _| {
+            if done == 1 {
+                token.cancel();
+            }
+        },
+    );
+
+    let err = result.expect_err("batch should stop after cancellation");
+    assert!(err.downcast_ref::<Interrupted>().is_some());
+    // First file was already written, second must be untouched
+    assert_ne!(fs::read(&path1).unwrap(), original);
+    assert_eq!(fs::read(&path2).unwrap(), original);
+
+    cleanup(&path1);
+    cleanup(&path2);
+}
+
+#[test]
+fn test_file_snapshot_detects_change_after_analysis() {
+    let path = copy_test_file("test_stereo.mp3");
+
+    let snapshot = FileSnapshot::capture(&path).unwrap();
+    analyze(&path).unwrap();
+    snapshot.verify(&path).unwrap();
+
+    // Another process rewrites the file between analysis and apply
+    let mut data = fs::read(&path).unwrap();
+    data.extend_from_slice(b"appended by another tagger");
+    fs::write(&path, &data).unwrap();
+
+    let err = snapshot.verify(&path).unwrap_err();
+    assert!(err.downcast_ref::<FileModifiedDuringOperation>().is_some());
+
+    // A file that disappeared counts as changed too
+    cleanup(&path);
+    let err = snapshot.verify(&path).unwrap_err();
+    assert!(err.downcast_ref::<FileModifiedDuringOperation>().is_some());
+}
+
+#[test]
+fn test_analyze_album_cancelled() {
+    if !replaygain::is_available() {
+        return;
+    }
+
+    let path = Path::new("tests/fixtures/test_stereo.mp3");
+    let token = CancellationToken::new();
+
+    let mut analyzed = 0;
+    let result =
+        replaygain::analyze_album_cancellable(&[path, path, path], None, &token, |done, _| {
+            analyzed = done;
+            token.cancel();
+        });
+
+    let err = result.expect_err("analysis should stop after cancellation");
+    assert!(err.downcast_ref::<Interrupted>().is_some());
+    assert_eq!(analyzed, 1);
+}
+
+#[test]
+fn test_add_to_album_matches_full_analysis() {
+    if !replaygain::is_available() {
+        return;
+    }
+
+    let fixtures = Path::new("tests/fixtures");
+    let files: Vec<_> = ["test_stereo.mp3", "test_mono.mp3", "test_vbr.mp3"]
+        .iter()
+        .map(|name| fixtures.join(name))
+        .collect();
+    let refs: Vec<&Path> = files.iter().map(|p| p.as_path()).collect();
+    let full = replaygain::analyze_album(&refs).unwrap();
+
+    // One track at a time, starting from an empty album
+    let mut album = replaygain::AlbumGainResult::default();
+    for file in &refs {
+        replaygain::add_to_album(&mut album, &[file], None).unwrap();
+    }
+    assert_eq!(album.histogram, full.histogram);
+    assert_eq!(album.album_loudness_db, full.album_loudness_db);
+    assert_eq!(album.album_gain_db, full.album_gain_db);
+    assert_eq!(album.album_peak, full.album_peak);
+    let gains = |a: &replaygain::AlbumGainResult| -> Vec<f64> {
+        a.tracks.iter().map(|t| t.gain_db).collect()
+    };
+    assert_eq!(gains(&album), gains(&full));
+
+    // Adding to an analyzed album only moves the album values
+    let mut album = replaygain::analyze_album(&refs[..2]).unwrap();
+    let before = album.histogram.window_count();
+    replaygain::add_to_album(&mut album, &refs[2..], None).unwrap();
+    assert!(album.histogram.window_count() > before);
+    assert_eq!(album.album_gain_db, full.album_gain_db);
+
+    // A failed addition leaves the album as it was
+    let missing = fixtures.join("missing.mp3");
+    let snapshot = album.clone();
+    assert!(replaygain::add_to_album(&mut album, &[&missing], None).is_err());
+    assert_eq!(album.histogram, snapshot.histogram);
+    assert_eq!(album.tracks.len(), 3);
+}
+
+#[test]
+fn test_analyze_track_reports_file_progress() {
+    if !replaygain::is_available() {
+        return;
+    }
+
+    let path = Path::new("tests/fixtures/test_mono.mp3");
+    let mut fractions = Vec::new();
+    let result =
+        replaygain::analyze_track_cancellable(path, None, &CancellationToken::new(), |fraction| {
+            fractions.push(fraction)
+        })
+        .unwrap();
+
+    assert!(fractions.len() > 1);
+    assert!(fractions.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(*fractions.last().unwrap(), 1.0);
+    // Same result as without progress reporting
+    let plain = replaygain::analyze_track(path).unwrap();
+    assert_eq!(result.gain_db, plain.gain_db);
+}
+
 // =============================================================================
 // Edge Case Tests
 // =============================================================================
@@ -446,3 +2035,120 @@ fn test_file_not_modified_on_zero_gain() {
 
     cleanup(&path);
 }
+
+#[test]
+fn test_id3v24_footer_is_skipped() {
+    let path = copy_test_file("test_stereo.mp3");
+    let original = analyze(&path).unwrap();
+
+    // Rewrite the fixture's ID3v2.4 tag with the footer flag and a "3DI" footer
+    let mut data = fs::read(&path).unwrap();
+    assert_eq!(&data[0..4], b"ID3\x04");
+    let tag_end = 10
+        + (((data[6] as usize) << 21)
+            | ((data[7] as usize) << 14)
+            | ((data[8] as usize) << 7)
+            | data[9] as usize);
+    data[5] |= 0x10;
+    let mut footer = data[0..10].to_vec();
+    footer[0..3].copy_from_slice(b"3DI");
+    data.splice(tag_end..tag_end, footer.iter().copied());
+    fs::write(&path, &data).unwrap();
+
+    let info = analyze(&path).unwrap();
+    assert_eq!(info.frame_count, original.frame_count);
+    assert_eq!(info.max_gain, original.max_gain);
+
+    // Gain changes must not touch the footer
+    apply_gain(&path, -1).unwrap();
+    let after = fs::read(&path).unwrap();
+    assert_eq!(&after[tag_end..tag_end + 10], footer.as_slice());
+    assert_eq!(analyze(&path).unwrap().min_gain, original.min_gain - 1);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_trailing_junk_after_last_frame() {
+    let path = copy_test_file("test_stereo.mp3");
+    let original = analyze(&path).unwrap();
+
+    // Drop the fixture's partial final frame so the junk directly follows a
+    // complete frame, then append 10KB of pseudo-random bytes (xorshift,
+    // fixed seed)
+    let mut data = fs::read(&path).unwrap();
+    data.truncate(data.len() - original.trailing_bytes);
+    let audio_len = data.len();
+    let mut state: u32 = 0x2545_F491;
+    for _ in 0..10 * 1024 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        data.push(state as u8);
+    }
+    fs::write(&path, &data).unwrap();
+
+    let info = analyze(&path).unwrap();
+    assert_eq!(info.frame_count, original.frame_count);
+    assert_eq!(info.trailing_bytes, 10 * 1024);
+
+    // Gain changes leave the junk untouched
+    apply_gain(&path, -1).unwrap();
+    let after = fs::read(&path).unwrap();
+    assert_eq!(&after[audio_len..], &data[audio_len..]);
+    assert_eq!(analyze(&path).unwrap().frame_count, original.frame_count);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_junk_before_id3v2_tag() {
+    // test_stereo.mp3 with 4 junk bytes in front of its ID3v2 tag
+    let path = copy_test_file("test_leading_junk.mp3");
+    let reference = analyze(Path::new("tests/fixtures/test_stereo.mp3")).unwrap();
+
+    let info = analyze(&path).unwrap();
+    assert_eq!(info.leading_bytes, 4);
+    assert_eq!(info.frame_count, reference.frame_count);
+    assert_eq!(info.min_gain, reference.min_gain);
+    assert_eq!(info.max_gain, reference.max_gain);
+
+    // Junk and tag must come through a gain change untouched
+    let original = fs::read(&path).unwrap();
+    let tag_end = 4
+        + 10
+        + (((original[10] as usize) << 21)
+            | ((original[11] as usize) << 14)
+            | ((original[12] as usize) << 7)
+            | original[13] as usize);
+    apply_gain(&path, -1).unwrap();
+    let after = fs::read(&path).unwrap();
+    assert_eq!(&after[..tag_end], &original[..tag_end]);
+    assert_eq!(analyze(&path).unwrap().min_gain, reference.min_gain - 1);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_gap_after_id3v2_tag() {
+    // test_stereo.mp3 with 1 KiB of padding between its 44-byte ID3v2 tag and
+    // the first frame; the padding holds a stray sync word no frame follows
+    let path = copy_test_file("test_id3v2_gap.mp3");
+    let reference = analyze(Path::new("tests/fixtures/test_stereo.mp3")).unwrap();
+
+    let info = analyze(&path).unwrap();
+    assert_eq!(info.id3v2_gap, 1024);
+    assert_eq!(info.leading_bytes, 0);
+    assert_eq!(info.frame_count, reference.frame_count);
+    assert_eq!(info.gain_locations, reference.gain_locations);
+    assert_eq!(reference.id3v2_gap, 0);
+
+    // The padding comes through a gain change untouched
+    let original = fs::read(&path).unwrap();
+    apply_gain(&path, -1).unwrap();
+    let after = fs::read(&path).unwrap();
+    assert_eq!(&after[..44 + 1024], &original[..44 + 1024]);
+    assert_eq!(analyze(&path).unwrap().min_gain, reference.min_gain - 1);
+
+    cleanup(&path);
+}